@@ -5,6 +5,7 @@
  * header here, and whitelist the struct in the build.rs file.
  */
 #include "c.h"
+#include "catalog/catversion.h"
 #include "catalog/pg_control.h"
 #include "access/xlog_internal.h"
 
@@ -126,6 +126,8 @@ fn main() -> anyhow::Result<()> {
             .allowlist_var("XLOG_PAGE_MAGIC")
             .allowlist_var("PG_CONTROL_FILE_SIZE")
             .allowlist_var("PG_CONTROLFILEDATA_OFFSETOF_CRC")
+            .allowlist_var("PG_CONTROL_VERSION")
+            .allowlist_var("CATALOG_VERSION_NO")
             .allowlist_type("PageHeaderData")
             .allowlist_type("DBState")
             // Because structs are used for serialization, tell bindgen to emit
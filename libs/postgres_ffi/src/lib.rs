@@ -10,6 +10,7 @@
 #![allow(clippy::duplicate_mod)]
 
 use bytes::Bytes;
+use std::path::Path;
 use utils::bin_ser::SerializeError;
 use utils::lsn::Lsn;
 
@@ -48,8 +49,12 @@ macro_rules! postgres_ffi {
 postgres_ffi!(v14);
 postgres_ffi!(v15);
 
+pub mod major_version;
+pub mod page_utils;
 pub mod pg_constants;
 pub mod relfile_utils;
+pub mod version_utils;
+pub mod vm_fsm_utils;
 
 // Export some widely used datatypes that are unlikely to change across Postgres versions
 pub use v14::bindings::{uint32, uint64, Oid};
@@ -78,9 +83,18 @@ pub use v14::xlog_utils::encode_logical_message;
 pub use v14::xlog_utils::get_current_timestamp;
 pub use v14::xlog_utils::to_pg_timestamp;
 pub use v14::xlog_utils::XLogFileName;
+pub use v14::xlog_utils::{
+    decode_commit_timestamp, decode_logical_message, decode_replorigin_record,
+};
+pub use v14::xlog_utils::{from_pg_timestamp, parse_pg_timestamp, to_pg_text};
+pub use v14::xlog_utils::{generate_timeline_history, TLHistoryFileName};
+pub use v14::xlog_utils::{LogicalMessage, ReplicationOriginRecord};
+pub use v14::xlog_utils::{SegmentReader, DEFAULT_SEGMENT_READAHEAD};
 
 pub use v14::bindings::DBState_DB_SHUTDOWNED;
 
+pub use major_version::{get_pg_major_version, PgMajorVersion};
+
 pub fn bkpimage_is_compressed(bimg_info: u8, version: u32) -> anyhow::Result<bool> {
     match version {
         14 => Ok(bimg_info & v14::bindings::BKPIMAGE_IS_COMPRESSED != 0),
@@ -92,13 +106,14 @@ pub fn bkpimage_is_compressed(bimg_info: u8, version: u32) -> anyhow::Result<boo
 }
 
 pub fn generate_wal_segment(
-    segno: u64,
+    lsn: Lsn,
     system_id: u64,
+    tli: TimeLineID,
     pg_version: u32,
-) -> Result<Bytes, SerializeError> {
+) -> Result<(String, Bytes), SerializeError> {
     match pg_version {
-        14 => v14::xlog_utils::generate_wal_segment(segno, system_id),
-        15 => v15::xlog_utils::generate_wal_segment(segno, system_id),
+        14 => v14::xlog_utils::generate_wal_segment(lsn, system_id, tli),
+        15 => v15::xlog_utils::generate_wal_segment(lsn, system_id, tli),
         _ => Err(SerializeError::BadInput),
     }
 }
@@ -122,7 +137,8 @@ pub fn generate_pg_control(
 //
 // It's a shaky assumption, that it's always 1. We might import a
 // PostgreSQL data directory that has gone through timeline bumps,
-// for example. FIXME later.
+// for example. generate_wal_segment() and generate_timeline_history()
+// accept other timelines, but the rest of the code still assumes this one.
 pub const PG_TLI: u32 = 1;
 
 //  See TransactionIdIsNormal in transam.h
@@ -145,6 +161,48 @@ pub const fn transaction_id_precedes(id1: TransactionId, id2: TransactionId) ->
     diff < 0
 }
 
+/// Find the end of valid WAL in `data_dir`, see `xlog_utils::find_end_of_wal`.
+pub fn find_end_of_wal(
+    data_dir: &Path,
+    wal_seg_size: usize,
+    start_lsn: Lsn,
+    pg_version: u32,
+) -> anyhow::Result<Lsn> {
+    match pg_version {
+        14 => v14::xlog_utils::find_end_of_wal(data_dir, wal_seg_size, start_lsn),
+        15 => v15::xlog_utils::find_end_of_wal(data_dir, wal_seg_size, start_lsn),
+        _ => anyhow::bail!("Unknown version {}", pg_version),
+    }
+}
+
+/// Find the first record starting in the segment, see
+/// `xlog_utils::find_first_record_in_segment`.
+pub fn find_first_record_in_segment(
+    data_dir: &Path,
+    wal_seg_size: usize,
+    segno: XLogSegNo,
+    pg_version: u32,
+) -> anyhow::Result<Option<Lsn>> {
+    match pg_version {
+        14 => v14::xlog_utils::find_first_record_in_segment(data_dir, wal_seg_size, segno),
+        15 => v15::xlog_utils::find_first_record_in_segment(data_dir, wal_seg_size, segno),
+        _ => anyhow::bail!("Unknown version {}", pg_version),
+    }
+}
+
+/// Encode logical message record in the WAL format of the given version.
+pub fn encode_logical_message_for_version(
+    prefix: &str,
+    message: &str,
+    pg_version: u32,
+) -> anyhow::Result<Vec<u8>> {
+    match pg_version {
+        14 => Ok(v14::xlog_utils::encode_logical_message(prefix, message)),
+        15 => Ok(v15::xlog_utils::encode_logical_message(prefix, message)),
+        _ => anyhow::bail!("Unknown version {}", pg_version),
+    }
+}
+
 // Check if page is not yet initialized (port of Postgres PageIsInit() macro)
 pub fn page_is_new(pg: &[u8]) -> bool {
     pg[14] == 0 && pg[15] == 0 // pg_upper == 0
@@ -167,28 +225,18 @@ pub fn page_set_lsn(pg: &mut [u8], lsn: Lsn) {
 // The only difference is that it does not have "level" parameter because XLogRecordPageWithFreeSpace
 // always call it with level=FSM_BOTTOM_LEVEL
 pub fn fsm_logical_to_physical(addr: BlockNumber) -> BlockNumber {
-    let mut leafno = addr;
-    const FSM_TREE_DEPTH: u32 = if pg_constants::SLOTS_PER_FSM_PAGE >= 1626 {
-        3
-    } else {
-        4
-    };
-
-    /* Count upper level nodes required to address the leaf page */
-    let mut pages: BlockNumber = 0;
-    for _l in 0..FSM_TREE_DEPTH {
-        pages += leafno + 1;
-        leafno /= pg_constants::SLOTS_PER_FSM_PAGE;
-    }
-    /* Turn the page count into 0-based block number */
-    pages - 1
+    vm_fsm_utils::fsm_logical_to_physical(vm_fsm_utils::FSMAddress {
+        level: vm_fsm_utils::FSM_BOTTOM_LEVEL,
+        logpageno: addr,
+    })
 }
 
 pub mod waldecoder {
 
-    use crate::{v14, v15};
+    use crate::{v14, v15, WAL_SEGMENT_SIZE};
     use bytes::{Buf, Bytes, BytesMut};
     use std::num::NonZeroU32;
+    use std::ops::Range;
     use thiserror::Error;
     use utils::lsn::Lsn;
 
@@ -205,7 +253,12 @@ pub mod waldecoder {
 
     pub struct WalStreamDecoder {
         pub lsn: Lsn,
+        /// Start of the last record returned by poll_decode(), after the page
+        /// header if the record begins a page.
+        pub record_start_lsn: Lsn,
         pub pg_version: u32,
+        /// Long page headers are expected at multiples of it.
+        pub wal_seg_size: usize,
         pub inputbuf: BytesMut,
         pub state: State,
     }
@@ -219,9 +272,16 @@ pub mod waldecoder {
 
     impl WalStreamDecoder {
         pub fn new(lsn: Lsn, pg_version: u32) -> WalStreamDecoder {
+            Self::with_seg_size(lsn, pg_version, WAL_SEGMENT_SIZE)
+        }
+
+        /// Decoder of WAL with non-default segment size.
+        pub fn with_seg_size(lsn: Lsn, pg_version: u32, wal_seg_size: usize) -> WalStreamDecoder {
             WalStreamDecoder {
                 lsn,
+                record_start_lsn: lsn,
                 pg_version,
+                wal_seg_size,
                 inputbuf: BytesMut::new(),
                 state: State::WaitingForRecord,
             }
@@ -255,4 +315,31 @@ pub mod waldecoder {
             }
         }
     }
+
+    /// Split a buffer of WAL starting at `start_lsn`, which must be a record
+    /// boundary, into ranges each holding one complete record (with page
+    /// headers and alignment padding it spans). Trailing bytes of a record that
+    /// is not complete in `buf` are not covered by any range.
+    ///
+    /// This allows senders to cut WAL into messages without splitting records.
+    pub fn split_on_record_boundaries(
+        buf: &[u8],
+        start_lsn: Lsn,
+        pg_version: u32,
+        wal_seg_size: usize,
+    ) -> Result<Vec<Range<usize>>, WalDecodeError> {
+        let mut decoder = WalStreamDecoder::with_seg_size(start_lsn, pg_version, wal_seg_size);
+        decoder.feed_bytes(buf);
+
+        let mut ranges = Vec::new();
+        let mut start = 0;
+        while let Some((next_lsn, _)) = decoder.poll_decode()? {
+            // XLOG_SWITCH record "spans" until the end of the segment, which
+            // may be beyond the buffer.
+            let end = std::cmp::min((next_lsn - start_lsn) as usize, buf.len());
+            ranges.push(start..end);
+            start = end;
+        }
+        Ok(ranges)
+    }
 }
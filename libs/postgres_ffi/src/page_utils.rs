@@ -0,0 +1,448 @@
+//!
+//! Read-only inspection of PostgreSQL heap and B-tree pages.
+//!
+//! This is roughly what the pageinspect extension provides: decoding of the
+//! page header, line pointers, heap tuple headers and the B-tree special
+//! space, plus data checksum calculation. It is used to sanity-check pages
+//! reconstructed by WAL redo, and to explain their contents in debug tools.
+//!
+//! See src/include/storage/bufpage.h, src/include/storage/itemid.h,
+//! src/include/access/htup_details.h, src/include/access/nbtree.h and
+//! src/include/storage/checksum_impl.h in the PostgreSQL sources.
+//!
+use crate::{BlockNumber, OffsetNumber, TransactionId, BLCKSZ};
+use anyhow::{bail, ensure, Result};
+use utils::lsn::Lsn;
+
+/// Size of PageHeaderData without the line pointer array
+pub const SIZE_OF_PAGE_HEADER_DATA: usize = 24;
+/// Size of ItemIdData
+pub const SIZE_OF_ITEM_ID: usize = 4;
+/// Size of HeapTupleHeaderData without the null bitmap
+pub const SIZE_OF_HEAP_TUPLE_HEADER: usize = 23;
+/// Size of BTPageOpaqueData
+pub const SIZE_OF_BT_PAGE_OPAQUE: usize = 16;
+
+const PD_CHECKSUM_OFFSET: usize = 8;
+
+// Line pointer states, from itemid.h
+pub const LP_UNUSED: u8 = 0;
+pub const LP_NORMAL: u8 = 1;
+pub const LP_REDIRECT: u8 = 2;
+pub const LP_DEAD: u8 = 3;
+
+// From htup_details.h
+pub const HEAP_NATTS_MASK: u16 = 0x07FF;
+pub const HEAP_HASNULL: u16 = 0x0001;
+pub const HEAP_XMIN_COMMITTED: u16 = 0x0100;
+pub const HEAP_XMIN_INVALID: u16 = 0x0200;
+pub const HEAP_XMAX_COMMITTED: u16 = 0x0400;
+pub const HEAP_XMAX_INVALID: u16 = 0x0800;
+pub const HEAP_XMAX_IS_MULTI: u16 = 0x1000;
+pub const HEAP_HOT_UPDATED: u16 = 0x4000;
+pub const HEAP_ONLY_TUPLE: u16 = 0x8000;
+
+// btpo_flags, from nbtree.h
+pub const BTP_LEAF: u16 = 1 << 0;
+pub const BTP_ROOT: u16 = 1 << 1;
+pub const BTP_DELETED: u16 = 1 << 2;
+pub const BTP_META: u16 = 1 << 3;
+pub const BTP_HALF_DEAD: u16 = 1 << 4;
+pub const BTP_SPLIT_END: u16 = 1 << 5;
+pub const BTP_HAS_GARBAGE: u16 = 1 << 6;
+pub const BTP_INCOMPLETE_SPLIT: u16 = 1 << 7;
+pub const BTP_HAS_FULLXID: u16 = 1 << 8;
+
+/// Decoded PageHeaderData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageHeader {
+    pub lsn: Lsn,
+    pub checksum: u16,
+    pub flags: u16,
+    pub lower: u16,
+    pub upper: u16,
+    pub special: u16,
+    pub pagesize_version: u16,
+    pub prune_xid: TransactionId,
+}
+
+impl PageHeader {
+    pub fn decode(page: &[u8]) -> Result<PageHeader> {
+        ensure!(
+            page.len() == BLCKSZ as usize,
+            "unexpected page size {}",
+            page.len()
+        );
+        let hdr = PageHeader {
+            lsn: crate::page_get_lsn(page),
+            checksum: get_u16(page, PD_CHECKSUM_OFFSET),
+            flags: get_u16(page, 10),
+            lower: get_u16(page, 12),
+            upper: get_u16(page, 14),
+            special: get_u16(page, 16),
+            pagesize_version: get_u16(page, 18),
+            prune_xid: get_u32(page, 20),
+        };
+
+        // New (all-zeros) pages don't have anything to validate
+        if hdr.upper == 0 {
+            return Ok(hdr);
+        }
+        // See PageIsVerifiedExtended() in bufpage.c
+        if hdr.lower as usize >= SIZE_OF_PAGE_HEADER_DATA
+            && hdr.lower <= hdr.upper
+            && hdr.upper <= hdr.special
+            && hdr.special <= BLCKSZ
+            && hdr.special % 8 == 0
+        {
+            Ok(hdr)
+        } else {
+            bail!(
+                "corrupted page pointers: lower = {}, upper = {}, special = {}",
+                hdr.lower,
+                hdr.upper,
+                hdr.special
+            )
+        }
+    }
+
+    pub fn is_new(&self) -> bool {
+        self.upper == 0
+    }
+
+    pub fn page_size(&self) -> u16 {
+        self.pagesize_version & 0xFF00
+    }
+
+    pub fn layout_version(&self) -> u8 {
+        (self.pagesize_version & 0x00FF) as u8
+    }
+
+    /// Number of line pointers on the page, PageGetMaxOffsetNumber() in C
+    pub fn max_offset_number(&self) -> OffsetNumber {
+        if (self.lower as usize) <= SIZE_OF_PAGE_HEADER_DATA {
+            0
+        } else {
+            ((self.lower as usize - SIZE_OF_PAGE_HEADER_DATA) / SIZE_OF_ITEM_ID) as OffsetNumber
+        }
+    }
+
+    /// Size of the special space at the end of the page
+    pub fn special_size(&self) -> usize {
+        BLCKSZ as usize - self.special as usize
+    }
+}
+
+/// Decoded ItemIdData (line pointer)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ItemId {
+    pub off: u16,
+    pub flags: u8,
+    pub len: u16,
+}
+
+impl ItemId {
+    pub fn decode(raw: u32) -> ItemId {
+        ItemId {
+            off: (raw & 0x7FFF) as u16,
+            flags: ((raw >> 15) & 0x03) as u8,
+            len: (raw >> 17) as u16,
+        }
+    }
+
+    pub fn is_normal(&self) -> bool {
+        self.flags == LP_NORMAL
+    }
+
+    pub fn has_storage(&self) -> bool {
+        self.len != 0
+    }
+}
+
+/// Iterate over line pointers of the page, yielding (offset number, line
+/// pointer) pairs. Offset numbers are 1-based, like in PostgreSQL.
+pub fn page_line_pointers(
+    page: &[u8],
+) -> Result<impl Iterator<Item = (OffsetNumber, ItemId)> + '_> {
+    let hdr = PageHeader::decode(page)?;
+    Ok((0..hdr.max_offset_number()).map(move |i| {
+        let pos = SIZE_OF_PAGE_HEADER_DATA + i as usize * SIZE_OF_ITEM_ID;
+        (i + 1, ItemId::decode(get_u32(page, pos)))
+    }))
+}
+
+/// Get contents of the item that the line pointer points to.
+pub fn page_get_item<'a>(page: &'a [u8], item_id: &ItemId) -> Result<&'a [u8]> {
+    let start = item_id.off as usize;
+    let end = start + item_id.len as usize;
+    ensure!(
+        item_id.has_storage() && start >= SIZE_OF_PAGE_HEADER_DATA && end <= page.len(),
+        "line pointer {:?} is out of page bounds",
+        item_id
+    );
+    Ok(&page[start..end])
+}
+
+/// Decoded HeapTupleHeaderData
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapTupleHeader {
+    pub xmin: TransactionId,
+    pub xmax: TransactionId,
+    /// t_cid or t_xvac, depending on infomask
+    pub field3: u32,
+    pub ctid: (BlockNumber, OffsetNumber),
+    pub infomask2: u16,
+    pub infomask: u16,
+    pub hoff: u8,
+}
+
+impl HeapTupleHeader {
+    pub fn decode(item: &[u8]) -> Result<HeapTupleHeader> {
+        ensure!(
+            item.len() >= SIZE_OF_HEAP_TUPLE_HEADER,
+            "heap tuple is too short: {} bytes",
+            item.len()
+        );
+        let blkno = ((get_u16(item, 12) as u32) << 16) | get_u16(item, 14) as u32;
+        let hdr = HeapTupleHeader {
+            xmin: get_u32(item, 0),
+            xmax: get_u32(item, 4),
+            field3: get_u32(item, 8),
+            ctid: (blkno, get_u16(item, 16)),
+            infomask2: get_u16(item, 18),
+            infomask: get_u16(item, 20),
+            hoff: item[22],
+        };
+        ensure!(
+            hdr.hoff as usize >= SIZE_OF_HEAP_TUPLE_HEADER && hdr.hoff as usize <= item.len(),
+            "invalid t_hoff {}",
+            hdr.hoff
+        );
+        Ok(hdr)
+    }
+
+    pub fn natts(&self) -> u16 {
+        self.infomask2 & HEAP_NATTS_MASK
+    }
+
+    pub fn has_nulls(&self) -> bool {
+        self.infomask & HEAP_HASNULL != 0
+    }
+}
+
+/// Decoded BTPageOpaqueData, stored in the special space of B-tree pages
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BTPageOpaque {
+    pub prev: BlockNumber,
+    pub next: BlockNumber,
+    pub level: u32,
+    pub flags: u16,
+    pub cycleid: u16,
+}
+
+impl BTPageOpaque {
+    pub fn decode(page: &[u8]) -> Result<BTPageOpaque> {
+        let hdr = PageHeader::decode(page)?;
+        ensure!(
+            hdr.special_size() == SIZE_OF_BT_PAGE_OPAQUE,
+            "unexpected special space size {} for a btree page",
+            hdr.special_size()
+        );
+        let off = hdr.special as usize;
+        Ok(BTPageOpaque {
+            prev: get_u32(page, off),
+            next: get_u32(page, off + 4),
+            level: get_u32(page, off + 8),
+            flags: get_u16(page, off + 12),
+            cycleid: get_u16(page, off + 14),
+        })
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.flags & BTP_LEAF != 0
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.flags & BTP_ROOT != 0
+    }
+
+    pub fn is_meta(&self) -> bool {
+        self.flags & BTP_META != 0
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.flags & BTP_DELETED != 0
+    }
+}
+
+//
+// Data checksums, port of checksum_impl.h
+//
+const N_SUMS: usize = 32;
+const FNV_PRIME: u32 = 16777619;
+
+const CHECKSUM_BASE_OFFSETS: [u32; N_SUMS] = [
+    0x5B1F36E9, 0xB8525960, 0x02AB50AA, 0x1DE66D2A, 0x79FF467A, 0x9BB9F8A3, 0x217E7CD2, 0x83E13D2C,
+    0xF8D4474F, 0xE39EB970, 0x42C6AE16, 0x993216FA, 0x7B093B5D, 0x98DAFF3C, 0xF718902A, 0x0B1C9CDB,
+    0xE58F764B, 0x187636BC, 0x5D7B3BB1, 0xE73DE7DE, 0x92BEC979, 0xCCA6C0B2, 0x304A0979, 0x85AA43D4,
+    0x783125BB, 0x6CA8EAA2, 0xE407EAC6, 0x4B5CFC3E, 0x9FBF8C76, 0x15CA20BE, 0xF2CA9FFF, 0x3ED2B1D3,
+];
+
+fn checksum_comp(checksum: u32, value: u32) -> u32 {
+    let tmp = checksum ^ value;
+    tmp.wrapping_mul(FNV_PRIME) ^ (tmp >> 17)
+}
+
+/// Compute the data checksum of a page, pg_checksum_page() in C. The
+/// pd_checksum field itself is excluded from the calculation.
+pub fn page_checksum(page: &[u8], blkno: BlockNumber) -> u16 {
+    assert_eq!(page.len(), BLCKSZ as usize);
+
+    let mut sums = CHECKSUM_BASE_OFFSETS;
+    for (i, chunk) in page.chunks_exact(4 * N_SUMS).enumerate() {
+        for (j, sum) in sums.iter_mut().enumerate() {
+            let pos = j * 4;
+            let mut value = u32::from_le_bytes(chunk[pos..pos + 4].try_into().unwrap());
+            if i == 0 && pos == PD_CHECKSUM_OFFSET {
+                // pd_checksum is the lower half of the third word
+                value &= 0xFFFF0000;
+            }
+            *sum = checksum_comp(*sum, value);
+        }
+    }
+    // Two rounds of zeroes for additional mixing
+    for _ in 0..2 {
+        for sum in sums.iter_mut() {
+            *sum = checksum_comp(*sum, 0);
+        }
+    }
+    let checksum = sums.iter().fold(0u32, |acc, sum| acc ^ sum) ^ blkno;
+
+    // Reduce to a uint16 with an offset of one, so that the checksum is never zero
+    ((checksum % 65535) + 1) as u16
+}
+
+/// Check that pd_checksum matches the page contents. New pages don't have a
+/// checksum and are accepted if they are all-zeros.
+pub fn page_verify_checksum(page: &[u8], blkno: BlockNumber) -> Result<bool> {
+    let hdr = PageHeader::decode(page)?;
+    if hdr.is_new() {
+        return Ok(page.iter().all(|b| *b == 0));
+    }
+    Ok(hdr.checksum == page_checksum(page, blkno))
+}
+
+pub fn page_set_checksum(page: &mut [u8], blkno: BlockNumber) {
+    let checksum = page_checksum(page, blkno);
+    page[PD_CHECKSUM_OFFSET..PD_CHECKSUM_OFFSET + 2].copy_from_slice(&checksum.to_le_bytes());
+}
+
+fn get_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap())
+}
+
+fn get_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a heap page with a single tuple and a B-tree-sized special space
+    fn make_page(special_size: usize) -> Vec<u8> {
+        let mut page = vec![0u8; BLCKSZ as usize];
+        let special = BLCKSZ as usize - special_size;
+        let tuple_len = SIZE_OF_HEAP_TUPLE_HEADER + 1 + 4; // header, padding, one int4
+        let upper = special - 32;
+
+        crate::page_set_lsn(&mut page, Lsn(0x0100_0028));
+        page[12..14]
+            .copy_from_slice(&((SIZE_OF_PAGE_HEADER_DATA + SIZE_OF_ITEM_ID) as u16).to_le_bytes());
+        page[14..16].copy_from_slice(&(upper as u16).to_le_bytes());
+        page[16..18].copy_from_slice(&(special as u16).to_le_bytes());
+        page[18..20].copy_from_slice(&(BLCKSZ | 4).to_le_bytes());
+
+        let lp = (upper as u32) | ((LP_NORMAL as u32) << 15) | ((tuple_len as u32) << 17);
+        page[24..28].copy_from_slice(&lp.to_le_bytes());
+
+        let tuple = &mut page[upper..upper + tuple_len];
+        tuple[0..4].copy_from_slice(&1000u32.to_le_bytes()); // xmin
+        tuple[14..16].copy_from_slice(&7u16.to_le_bytes()); // ctid block (low half)
+        tuple[16..18].copy_from_slice(&1u16.to_le_bytes()); // ctid offset
+        tuple[18..20].copy_from_slice(&1u16.to_le_bytes()); // natts
+        tuple[20..22].copy_from_slice(&HEAP_XMAX_INVALID.to_le_bytes());
+        tuple[22] = 24;
+        page
+    }
+
+    #[test]
+    fn test_heap_page() {
+        let page = make_page(0);
+        let hdr = PageHeader::decode(&page).unwrap();
+        assert_eq!(hdr.lsn, Lsn(0x0100_0028));
+        assert_eq!(hdr.page_size(), BLCKSZ);
+        assert_eq!(hdr.layout_version(), 4);
+        assert_eq!(hdr.max_offset_number(), 1);
+
+        let items: Vec<_> = page_line_pointers(&page).unwrap().collect();
+        assert_eq!(items.len(), 1);
+        let (offnum, item_id) = items[0];
+        assert_eq!(offnum, 1);
+        assert!(item_id.is_normal());
+
+        let tuple = HeapTupleHeader::decode(page_get_item(&page, &item_id).unwrap()).unwrap();
+        assert_eq!(tuple.xmin, 1000);
+        assert_eq!(tuple.ctid, (7, 1));
+        assert_eq!(tuple.natts(), 1);
+        assert!(!tuple.has_nulls());
+        assert_eq!(tuple.infomask & HEAP_XMAX_INVALID, HEAP_XMAX_INVALID);
+    }
+
+    #[test]
+    fn test_btree_special() {
+        let mut page = make_page(SIZE_OF_BT_PAGE_OPAQUE);
+        let off = BLCKSZ as usize - SIZE_OF_BT_PAGE_OPAQUE;
+        page[off..off + 4].copy_from_slice(&3u32.to_le_bytes());
+        page[off + 4..off + 8].copy_from_slice(&5u32.to_le_bytes());
+        page[off + 12..off + 14].copy_from_slice(&(BTP_LEAF | BTP_ROOT).to_le_bytes());
+
+        let opaque = BTPageOpaque::decode(&page).unwrap();
+        assert_eq!(opaque.prev, 3);
+        assert_eq!(opaque.next, 5);
+        assert_eq!(opaque.level, 0);
+        assert!(opaque.is_leaf() && opaque.is_root());
+        assert!(!opaque.is_meta() && !opaque.is_deleted());
+
+        // heap pages have no special space
+        assert!(BTPageOpaque::decode(&make_page(0)).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_header() {
+        let mut page = make_page(0);
+        page[12..14].copy_from_slice(&9000u16.to_le_bytes());
+        assert!(PageHeader::decode(&page).is_err());
+        assert!(PageHeader::decode(&page[..100]).is_err());
+    }
+
+    #[test]
+    fn test_page_checksum() {
+        let mut page = make_page(0);
+        page_set_checksum(&mut page, 10);
+        assert_ne!(PageHeader::decode(&page).unwrap().checksum, 0);
+        assert!(page_verify_checksum(&page, 10).unwrap());
+
+        // Checksum covers block number and page contents, but not itself
+        assert!(!page_verify_checksum(&page, 11).unwrap());
+        let checksum = page_checksum(&page, 10);
+        page[8] ^= 0xFF;
+        assert_eq!(page_checksum(&page, 10), checksum);
+        page[8] ^= 0xFF;
+        page[1000] = 1;
+        assert!(!page_verify_checksum(&page, 10).unwrap());
+
+        // All-zeros page is valid
+        assert!(page_verify_checksum(&[0u8; BLCKSZ as usize], 1).unwrap());
+    }
+}
@@ -157,6 +157,16 @@ pub const RM_RELMAP_ID: u8 = 7;
 pub const RM_STANDBY_ID: u8 = 8;
 pub const RM_HEAP2_ID: u8 = 9;
 pub const RM_HEAP_ID: u8 = 10;
+pub const RM_REPLORIGIN_ID: u8 = 19;
+pub const RM_LOGICALMSG_ID: u8 = 21;
+
+// From message.h
+pub const XLOG_LOGICAL_MESSAGE: u8 = 0x00;
+
+// From origin.h
+pub const XLOG_REPLORIGIN_SET: u8 = 0x00;
+pub const XLOG_REPLORIGIN_DROP: u8 = 0x10;
+pub const INVALID_REP_ORIGIN_ID: u16 = 0;
 
 // from xlogreader.h
 pub const XLR_INFO_MASK: u8 = 0x0F;
@@ -1,8 +1,13 @@
 //!
 //! Common utilities for dealing with PostgreSQL relation files.
 //!
+use crate::pg_constants::{DEFAULTTABLESPACE_OID, GLOBALTABLESPACE_OID};
+use crate::Oid;
 use once_cell::sync::OnceCell;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
 
 //
 // Fork numbers, from relpath.h
@@ -88,6 +93,138 @@ pub fn parse_relfilename(fname: &str) -> Result<(u32, u8, u32), FilePathError> {
     Ok((relnode, forknum, segno))
 }
 
+///
+/// Relation data file segment id throughout the Postgres cluster.
+///
+/// Every data file in Postgres is uniquely identified by 4 numbers:
+/// - relation id / node (`relnode`)
+/// - database id (`dbnode`)
+/// - tablespace id (`spcnode`), in short this is a unique id of a separate
+///   directory to store data files.
+/// - forknumber (`forknum`) is used to split different kinds of data of the same relation
+///   between some set of files (`relnode`, `relnode_fsm`, `relnode_vm`).
+///
+/// In native Postgres code `RelFileNode` structure and individual `ForkNumber` value
+/// are used for the same purpose.
+/// [See more related comments here](https:///github.com/postgres/postgres/blob/99c5852e20a0987eca1c38ba0c09329d4076b6a0/src/include/storage/relfilenode.h#L57).
+///
+// FIXME: should move 'forknum' as last field to keep this consistent with Postgres.
+// Then we could replace the custo Ord and PartialOrd implementations below with
+// deriving them.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+pub struct RelTag {
+    pub forknum: u8,
+    pub spcnode: Oid,
+    pub dbnode: Oid,
+    pub relnode: Oid,
+}
+
+impl PartialOrd for RelTag {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for RelTag {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let mut cmp = self.spcnode.cmp(&other.spcnode);
+        if cmp != Ordering::Equal {
+            return cmp;
+        }
+        cmp = self.dbnode.cmp(&other.dbnode);
+        if cmp != Ordering::Equal {
+            return cmp;
+        }
+        cmp = self.relnode.cmp(&other.relnode);
+        if cmp != Ordering::Equal {
+            return cmp;
+        }
+        cmp = self.forknum.cmp(&other.forknum);
+
+        cmp
+    }
+}
+
+/// Display RelTag in the same format that's used in most PostgreSQL debug messages:
+///
+/// <spcnode>/<dbnode>/<relnode>[_fsm|_vm|_init]
+///
+impl fmt::Display for RelTag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(forkname) = forknumber_to_name(self.forknum) {
+            write!(
+                f,
+                "{}/{}/{}_{}",
+                self.spcnode, self.dbnode, self.relnode, forkname
+            )
+        } else {
+            write!(f, "{}/{}/{}", self.spcnode, self.dbnode, self.relnode)
+        }
+    }
+}
+
+impl RelTag {
+    pub fn to_segfile_name(&self, segno: u32) -> String {
+        let mut name = if self.spcnode == GLOBALTABLESPACE_OID {
+            "global/".to_string()
+        } else {
+            format!("base/{}/", self.dbnode)
+        };
+
+        name += &self.relnode.to_string();
+
+        if let Some(fork_name) = forknumber_to_name(self.forknum) {
+            name += "_";
+            name += fork_name;
+        }
+
+        if segno != 0 {
+            name += ".";
+            name += &segno.to_string();
+        }
+
+        name
+    }
+
+    ///
+    /// Parse a relation file path relative to the data directory, as produced
+    /// by to_segfile_name(). Returns (RelTag, segno) tuple.
+    ///
+    /// Formats:
+    /// global/<relation file name>
+    /// base/<dboid>/<relation file name>
+    ///
+    /// Relations in non-default tablespaces (pg_tblspc) are not supported.
+    ///
+    pub fn from_segfile_name(path: &str) -> Result<(RelTag, u32), FilePathError> {
+        let (spcnode, dbnode, fname) = match path.split('/').collect::<Vec<_>>()[..] {
+            ["global", fname] => (GLOBALTABLESPACE_OID, 0, fname),
+            ["base", dbnode, fname] => (DEFAULTTABLESPACE_OID, dbnode.parse::<u32>()?, fname),
+            _ => return Err(FilePathError::InvalidFileName),
+        };
+        let (relnode, forknum, segno) = parse_relfilename(fname)?;
+
+        Ok((
+            RelTag {
+                forknum,
+                spcnode,
+                dbnode,
+                relnode,
+            },
+            segno,
+        ))
+    }
+
+    pub fn with_forknum(&self, forknum: u8) -> Self {
+        RelTag {
+            forknum,
+            spcnode: self.spcnode,
+            dbnode: self.dbnode,
+            relnode: self.relnode,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -148,4 +285,44 @@ mod tests {
         // currently.
         assert_eq!(parse_relfilename("1.123456"), Ok((1, 0, 123456)));
     }
+
+    #[test]
+    fn test_segfile_name_roundtrip() {
+        let rel = RelTag {
+            forknum: VISIBILITYMAP_FORKNUM,
+            spcnode: DEFAULTTABLESPACE_OID,
+            dbnode: 13010,
+            relnode: 16384,
+        };
+        assert_eq!(rel.to_segfile_name(0), "base/13010/16384_vm");
+        assert_eq!(rel.to_segfile_name(3), "base/13010/16384_vm.3");
+        assert_eq!(
+            RelTag::from_segfile_name("base/13010/16384_vm.3"),
+            Ok((rel, 3))
+        );
+
+        let rel = RelTag {
+            forknum: MAIN_FORKNUM,
+            spcnode: GLOBALTABLESPACE_OID,
+            dbnode: 0,
+            relnode: 1262,
+        };
+        assert_eq!(rel.to_segfile_name(0), "global/1262");
+        assert_eq!(RelTag::from_segfile_name("global/1262"), Ok((rel, 0)));
+        assert_eq!(rel.to_string(), "1664/0/1262");
+        assert_eq!(rel.with_forknum(FSM_FORKNUM).to_string(), "1664/0/1262_fsm");
+
+        assert_eq!(
+            RelTag::from_segfile_name("pg_tblspc/16385/PG_15_202209061/5/16386"),
+            Err(FilePathError::InvalidFileName)
+        );
+        assert_eq!(
+            RelTag::from_segfile_name("base/x/1234"),
+            Err(FilePathError::InvalidFileName)
+        );
+        assert_eq!(
+            RelTag::from_segfile_name("base/5/1234_foo"),
+            Err(FilePathError::InvalidForkName)
+        );
+    }
 }
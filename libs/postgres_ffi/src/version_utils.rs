@@ -0,0 +1,166 @@
+//!
+//! Utilities for detecting the PostgreSQL version of a data directory.
+//!
+//! The major version is stored in the PG_VERSION file, and the control file
+//! carries the control file format version and the catalog version, which
+//! must match the binaries exactly. All of these need to be known before
+//! anything version-specific, like WAL, can be parsed.
+//!
+use crate::{v14, v15};
+use anyhow::{bail, ensure, Context, Result};
+use std::path::Path;
+
+/// Offset of pg_control_version in ControlFileData. It follows the 8-byte
+/// system_identifier, and is at the same place in all versions, so that
+/// incompatible control files can be recognized.
+const PG_CONTROL_VERSION_OFFSET: usize = 8;
+/// Offset of catalog_version_no in ControlFileData
+const CATALOG_VERSION_NO_OFFSET: usize = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PgVersionInfo {
+    /// Major version, from PG_VERSION file
+    pub major_version: u32,
+    /// pg_control_version from the control file
+    pub pg_control_version: u32,
+    /// catalog_version_no from the control file
+    pub catalog_version_no: u32,
+}
+
+impl PgVersionInfo {
+    ///
+    /// Determine the version from the contents of PG_VERSION and
+    /// global/pg_control files, e.g. extracted from a basebackup.
+    ///
+    pub fn from_files(pg_version: &[u8], pg_control: &[u8]) -> Result<PgVersionInfo> {
+        let pg_version = std::str::from_utf8(pg_version).context("PG_VERSION is not UTF-8")?;
+        let major_version = pg_version
+            .trim()
+            .parse::<u32>()
+            .with_context(|| format!("invalid PG_VERSION contents {:?}", pg_version))?;
+
+        ensure!(
+            pg_control.len() >= CATALOG_VERSION_NO_OFFSET + 4,
+            "control file is too short"
+        );
+        let get_u32 = |off: usize| u32::from_le_bytes(pg_control[off..off + 4].try_into().unwrap());
+
+        Ok(PgVersionInfo {
+            major_version,
+            pg_control_version: get_u32(PG_CONTROL_VERSION_OFFSET),
+            catalog_version_no: get_u32(CATALOG_VERSION_NO_OFFSET),
+        })
+    }
+
+    ///
+    /// Determine the version of a PostgreSQL data directory.
+    ///
+    pub fn probe_datadir(datadir: &Path) -> Result<PgVersionInfo> {
+        let pg_version_path = datadir.join("PG_VERSION");
+        let pg_version = std::fs::read(&pg_version_path)
+            .with_context(|| format!("failed to read {}", pg_version_path.display()))?;
+        let pg_control_path = datadir.join("global").join("pg_control");
+        let pg_control = std::fs::read(&pg_control_path)
+            .with_context(|| format!("failed to read {}", pg_control_path.display()))?;
+
+        let info = Self::from_files(&pg_version, &pg_control)?;
+        info.check_compatible()?;
+        // All fields are known now, verify the CRC of the whole control file
+        // with the version-specific layout.
+        match info.major_version {
+            14 => {
+                v14::ControlFileData::decode(&pg_control)?;
+            }
+            15 => {
+                v15::ControlFileData::decode(&pg_control)?;
+            }
+            _ => unreachable!("checked by check_compatible"),
+        }
+        Ok(info)
+    }
+
+    ///
+    /// Check that the data directory can be handled by the bindings of its
+    /// major version: the control file and catalog versions must match.
+    ///
+    pub fn check_compatible(&self) -> Result<()> {
+        let (pg_control_version, catalog_version_no) = match self.major_version {
+            14 => (
+                v14::bindings::PG_CONTROL_VERSION,
+                v14::bindings::CATALOG_VERSION_NO,
+            ),
+            15 => (
+                v15::bindings::PG_CONTROL_VERSION,
+                v15::bindings::CATALOG_VERSION_NO,
+            ),
+            _ => bail!("Unknown version {}", self.major_version),
+        };
+        if self.pg_control_version != pg_control_version {
+            bail!(
+                "incompatible control file version {} for PostgreSQL {}, expected {}",
+                self.pg_control_version,
+                self.major_version,
+                pg_control_version
+            );
+        }
+        if self.catalog_version_no != catalog_version_no {
+            bail!(
+                "incompatible catalog version {} for PostgreSQL {}, expected {}",
+                self.catalog_version_no,
+                self.major_version,
+                catalog_version_no
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v15_control_file() -> Vec<u8> {
+        v15::ControlFileData {
+            system_identifier: 0x1234,
+            pg_control_version: v15::bindings::PG_CONTROL_VERSION,
+            catalog_version_no: v15::bindings::CATALOG_VERSION_NO,
+            ..Default::default()
+        }
+        .encode()
+        .to_vec()
+    }
+
+    #[test]
+    fn test_from_files() {
+        let pg_control = v15_control_file();
+        let info = PgVersionInfo::from_files(b"15\n", &pg_control).unwrap();
+        assert_eq!(info.major_version, 15);
+        assert_eq!(info.pg_control_version, v15::bindings::PG_CONTROL_VERSION);
+        assert_eq!(info.catalog_version_no, v15::bindings::CATALOG_VERSION_NO);
+        info.check_compatible().unwrap();
+
+        // v15 catalog doesn't match v14 binaries
+        let info = PgVersionInfo::from_files(b"14\n", &pg_control).unwrap();
+        assert!(info.check_compatible().is_err());
+
+        assert!(PgVersionInfo::from_files(b"fifteen", &pg_control).is_err());
+        assert!(PgVersionInfo::from_files(b"15", &pg_control[..10]).is_err());
+    }
+
+    #[test]
+    fn test_probe_datadir() {
+        let datadir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(datadir.path().join("global")).unwrap();
+        std::fs::write(datadir.path().join("PG_VERSION"), "15\n").unwrap();
+
+        let mut pg_control = v15_control_file();
+        std::fs::write(datadir.path().join("global/pg_control"), &pg_control).unwrap();
+        let info = PgVersionInfo::probe_datadir(datadir.path()).unwrap();
+        assert_eq!(info.major_version, 15);
+
+        // Corrupted control file is detected
+        pg_control[100] ^= 0xFF;
+        std::fs::write(datadir.path().join("global/pg_control"), &pg_control).unwrap();
+        assert!(PgVersionInfo::probe_datadir(datadir.path()).is_err());
+    }
+}
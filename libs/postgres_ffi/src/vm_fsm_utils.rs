@@ -0,0 +1,247 @@
+//!
+//! Utilities for dealing with visibility map and free space map pages.
+//!
+//! The visibility map stores two bits per heap block (all-visible and
+//! all-frozen), see src/backend/access/heap/visibilitymap.c. The free space
+//! map is a tree of pages, with one byte per heap block on the leaf level,
+//! see src/backend/storage/freespace/README in the PostgreSQL sources.
+//!
+use crate::pg_constants;
+use crate::BlockNumber;
+
+//
+// Visibility map
+//
+
+/// Location of the bits for a heap block in the visibility map:
+/// (map block, byte within the map, bit offset within the byte)
+pub fn vm_heap_block_location(heap_blkno: BlockNumber) -> (BlockNumber, usize, u8) {
+    (
+        pg_constants::HEAPBLK_TO_MAPBLOCK(heap_blkno),
+        pg_constants::HEAPBLK_TO_MAPBYTE(heap_blkno) as usize,
+        pg_constants::HEAPBLK_TO_OFFSET(heap_blkno) as u8,
+    )
+}
+
+// equivalent to PageGetContents(page)
+fn vm_map(page: &[u8]) -> &[u8] {
+    &page[pg_constants::MAXALIGN_SIZE_OF_PAGE_HEADER_DATA..]
+}
+
+fn vm_map_mut(page: &mut [u8]) -> &mut [u8] {
+    &mut page[pg_constants::MAXALIGN_SIZE_OF_PAGE_HEADER_DATA..]
+}
+
+/// Get VISIBILITYMAP_ALL_VISIBLE and VISIBILITYMAP_ALL_FROZEN bits of the
+/// heap block from the VM page containing it. See visibilitymap_get_status().
+pub fn vm_get_status(page: &[u8], heap_blkno: BlockNumber) -> u8 {
+    let (_, map_byte, map_offset) = vm_heap_block_location(heap_blkno);
+    (vm_map(page)[map_byte] >> map_offset) & pg_constants::VISIBILITYMAP_VALID_BITS
+}
+
+/// Set the given bits of the heap block in the VM page containing it.
+///
+/// Returns 'true' if the page was modified.
+pub fn vm_set_flags(page: &mut [u8], heap_blkno: BlockNumber, flags: u8) -> bool {
+    let (_, map_byte, map_offset) = vm_heap_block_location(heap_blkno);
+    let flags = flags & pg_constants::VISIBILITYMAP_VALID_BITS;
+    let map = vm_map_mut(page);
+    let old = map[map_byte];
+    map[map_byte] |= flags << map_offset;
+    map[map_byte] != old
+}
+
+/// Clear the given bits of the heap block in the VM page containing it.
+///
+/// Returns 'true' if the page was modified.
+pub fn vm_clear_flags(page: &mut [u8], heap_blkno: BlockNumber, flags: u8) -> bool {
+    let (_, map_byte, map_offset) = vm_heap_block_location(heap_blkno);
+    let flags = flags & pg_constants::VISIBILITYMAP_VALID_BITS;
+    let map = vm_map_mut(page);
+    let old = map[map_byte];
+    map[map_byte] &= !(flags << map_offset);
+    map[map_byte] != old
+}
+
+//
+// Free space map
+//
+
+/// Depth of the FSM tree, enough to address 2^32 heap blocks
+pub const FSM_TREE_DEPTH: u32 = if pg_constants::SLOTS_PER_FSM_PAGE >= 1626 {
+    3
+} else {
+    4
+};
+pub const FSM_ROOT_LEVEL: u32 = FSM_TREE_DEPTH - 1;
+pub const FSM_BOTTOM_LEVEL: u32 = 0;
+
+/// Logical address of a FSM page: level in the tree (0 is the bottom level),
+/// and the page number among the pages of that level.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FSMAddress {
+    pub level: u32,
+    pub logpageno: u32,
+}
+
+pub const FSM_ROOT_ADDRESS: FSMAddress = FSMAddress {
+    level: FSM_ROOT_LEVEL,
+    logpageno: 0,
+};
+
+/// Find the FSM leaf page and slot for a heap block. See fsm_get_location().
+pub fn fsm_get_location(heap_blkno: BlockNumber) -> (FSMAddress, u16) {
+    (
+        FSMAddress {
+            level: FSM_BOTTOM_LEVEL,
+            logpageno: heap_blkno / pg_constants::SLOTS_PER_FSM_PAGE,
+        },
+        (heap_blkno % pg_constants::SLOTS_PER_FSM_PAGE) as u16,
+    )
+}
+
+/// Find the parent of a FSM page, and the slot of the child in it.
+/// See fsm_get_parent().
+pub fn fsm_get_parent(child: FSMAddress) -> (FSMAddress, u16) {
+    (
+        FSMAddress {
+            level: child.level + 1,
+            logpageno: child.logpageno / pg_constants::SLOTS_PER_FSM_PAGE,
+        },
+        (child.logpageno % pg_constants::SLOTS_PER_FSM_PAGE) as u16,
+    )
+}
+
+/// Physical block number of a FSM page. Port of fsm_logical_to_physical()
+/// in freespace.c: the pages are stored in depth-first order.
+pub fn fsm_logical_to_physical(addr: FSMAddress) -> BlockNumber {
+    // Find the leftmost leaf page below this page
+    let mut leafno = addr.logpageno;
+    for _l in 0..addr.level {
+        leafno *= pg_constants::SLOTS_PER_FSM_PAGE;
+    }
+
+    // Count upper level nodes required to address the leaf page
+    let mut pages: BlockNumber = 0;
+    for _l in 0..FSM_TREE_DEPTH {
+        pages += leafno + 1;
+        leafno /= pg_constants::SLOTS_PER_FSM_PAGE;
+    }
+
+    // If the page we were asked for wasn't at the bottom level, subtract
+    // the additional lower level pages we counted above.
+    pages -= addr.level;
+
+    // Turn the page count into 0-based block number
+    pages - 1
+}
+
+/// Amount of free space recorded for a slot of a FSM page, in the units of
+/// BLCKSZ / 256 bytes. The nodes follow the page header and the fp_next_slot
+/// field, leaf nodes come after the non-leaf ones.
+pub fn fsm_get_avail(page: &[u8], slot: u16) -> u8 {
+    const FSM_NODES_OFFSET: usize = pg_constants::MAXALIGN_SIZE_OF_PAGE_HEADER_DATA + 4;
+    const FSM_NON_LEAF_NODES_PER_PAGE: usize = crate::BLCKSZ as usize / 2 - 1;
+
+    assert!((slot as u32) < pg_constants::SLOTS_PER_FSM_PAGE);
+    page[FSM_NODES_OFFSET + FSM_NON_LEAF_NODES_PER_PAGE + slot as usize]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BLCKSZ;
+
+    #[test]
+    fn test_vm_flags() {
+        let mut page = vec![0u8; BLCKSZ as usize];
+        let heap_blkno = 5;
+        assert_eq!(vm_get_status(&page, heap_blkno), 0);
+
+        assert!(vm_set_flags(
+            &mut page,
+            heap_blkno,
+            pg_constants::VISIBILITYMAP_ALL_VISIBLE
+        ));
+        assert!(!vm_set_flags(
+            &mut page,
+            heap_blkno,
+            pg_constants::VISIBILITYMAP_ALL_VISIBLE
+        ));
+        assert!(vm_set_flags(
+            &mut page,
+            heap_blkno,
+            pg_constants::VISIBILITYMAP_ALL_FROZEN
+        ));
+        assert_eq!(
+            vm_get_status(&page, heap_blkno),
+            pg_constants::VISIBILITYMAP_VALID_BITS
+        );
+        // Neighbours are not affected
+        assert_eq!(vm_get_status(&page, heap_blkno - 1), 0);
+        assert_eq!(vm_get_status(&page, heap_blkno + 1), 0);
+
+        assert!(vm_clear_flags(
+            &mut page,
+            heap_blkno,
+            pg_constants::VISIBILITYMAP_ALL_VISIBLE
+        ));
+        assert_eq!(
+            vm_get_status(&page, heap_blkno),
+            pg_constants::VISIBILITYMAP_ALL_FROZEN
+        );
+
+        // Heap blocks mapping to the next VM page wrap around to the start
+        let (map_block, map_byte, map_offset) =
+            vm_heap_block_location(pg_constants::HEAPBLOCKS_PER_PAGE + 1);
+        assert_eq!((map_block, map_byte, map_offset), (1, 0, 2));
+    }
+
+    #[test]
+    fn test_fsm_addressing() {
+        let slots = pg_constants::SLOTS_PER_FSM_PAGE;
+        assert_eq!(FSM_TREE_DEPTH, 3);
+
+        assert_eq!(fsm_logical_to_physical(FSM_ROOT_ADDRESS), 0);
+        // The first page of each level follows its parent
+        let (leaf, slot) = fsm_get_location(0);
+        assert_eq!(slot, 0);
+        assert_eq!(fsm_logical_to_physical(leaf), 2);
+        let (middle, _) = fsm_get_parent(leaf);
+        assert_eq!(fsm_logical_to_physical(middle), 1);
+        assert_eq!(fsm_get_parent(middle).0, FSM_ROOT_ADDRESS);
+
+        let (leaf, slot) = fsm_get_location(slots + 10);
+        assert_eq!(leaf.logpageno, 1);
+        assert_eq!(slot, 10);
+        assert_eq!(fsm_logical_to_physical(leaf), 3);
+
+        // First leaf of the second middle-level subtree comes after the
+        // middle-level page itself
+        let (leaf, _) = fsm_get_location(slots * slots);
+        assert_eq!(fsm_logical_to_physical(leaf), slots + 3);
+        assert_eq!(fsm_logical_to_physical(fsm_get_parent(leaf).0), slots + 2);
+
+        // Agrees with the bottom-level-only version
+        for logpageno in [0, 1, 100, slots, slots * 3 + 7] {
+            assert_eq!(
+                fsm_logical_to_physical(FSMAddress {
+                    level: FSM_BOTTOM_LEVEL,
+                    logpageno
+                }),
+                crate::fsm_logical_to_physical(logpageno)
+            );
+        }
+    }
+
+    #[test]
+    fn test_fsm_get_avail() {
+        let mut page = vec![0u8; BLCKSZ as usize];
+        page[BLCKSZ as usize - 1] = 42;
+        assert_eq!(
+            fsm_get_avail(&page, (pg_constants::SLOTS_PER_FSM_PAGE - 1) as u16),
+            42
+        );
+        assert_eq!(fsm_get_avail(&page, 0), 0);
+    }
+}
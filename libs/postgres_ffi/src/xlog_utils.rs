@@ -7,12 +7,15 @@
 // have been named the same as the corresponding PostgreSQL functions instead.
 //
 
+use anyhow::Context;
 use crc32c::crc32c_append;
+use fs2::FileExt;
 
 use super::super::waldecoder::WalStreamDecoder;
 use super::bindings::{
-    CheckPoint, ControlFileData, DBState_DB_SHUTDOWNED, FullTransactionId, TimeLineID, TimestampTz,
-    XLogLongPageHeaderData, XLogPageHeaderData, XLogRecPtr, XLogRecord, XLogSegNo, XLOG_PAGE_MAGIC,
+    CheckPoint, ControlFileData, DBState_DB_SHUTDOWNED, FullTransactionId, MultiXactId,
+    MultiXactOffset, TimeLineID, TimestampTz, TransactionId, XLogLongPageHeaderData,
+    XLogPageHeaderData, XLogRecPtr, XLogRecord, XLogSegNo, XLOG_PAGE_MAGIC,
 };
 use super::PG_MAJORVERSION;
 use crate::pg_constants;
@@ -23,6 +26,7 @@ use crate::{WAL_SEGMENT_SIZE, XLOG_BLCKSZ};
 use bytes::BytesMut;
 use bytes::{Buf, Bytes};
 
+use chrono::{DateTime, NaiveDateTime, Utc};
 use log::*;
 
 use serde::Serialize;
@@ -31,7 +35,7 @@ use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use utils::bin_ser::DeserializeError;
 use utils::bin_ser::SerializeError;
 
@@ -132,25 +136,82 @@ pub fn generate_pg_control(
     Ok((pg_control.encode(), pg_control.system_identifier))
 }
 
+const UNIX_EPOCH_JDATE: i64 = 2440588; /* == date2j(1970, 1, 1) */
+const POSTGRES_EPOCH_JDATE: i64 = 2451545; /* == date2j(2000, 1, 1) */
+const SECS_PER_DAY: i64 = 86400;
+const USECS_PER_SEC: i64 = 1000000;
+
+/// Offset of the PostgreSQL epoch, 2000-01-01, from the Unix epoch, in microseconds
+pub const POSTGRES_EPOCH_OFFSET_USECS: i64 =
+    (POSTGRES_EPOCH_JDATE - UNIX_EPOCH_JDATE) * SECS_PER_DAY * USECS_PER_SEC;
+
+/// Special values of TimestampTz, see DT_NOBEGIN and DT_NOEND in datatype/timestamp.h
+pub const DT_NOBEGIN: TimestampTz = i64::MIN;
+pub const DT_NOEND: TimestampTz = i64::MAX;
+
 pub fn get_current_timestamp() -> TimestampTz {
     to_pg_timestamp(SystemTime::now())
 }
 
 pub fn to_pg_timestamp(time: SystemTime) -> TimestampTz {
-    const UNIX_EPOCH_JDATE: u64 = 2440588; /* == date2j(1970, 1, 1) */
-    const POSTGRES_EPOCH_JDATE: u64 = 2451545; /* == date2j(2000, 1, 1) */
-    const SECS_PER_DAY: u64 = 86400;
-    const USECS_PER_SEC: u64 = 1000000;
-    match time.duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(n) => {
-            ((n.as_secs() - ((POSTGRES_EPOCH_JDATE - UNIX_EPOCH_JDATE) * SECS_PER_DAY))
-                * USECS_PER_SEC
-                + n.subsec_micros() as u64) as i64
-        }
-        Err(_) => panic!("SystemTime before UNIX EPOCH!"),
+    let unix_usecs = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(n) => n.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    };
+    unix_usecs - POSTGRES_EPOCH_OFFSET_USECS
+}
+
+/// Inverse of to_pg_timestamp().
+pub fn from_pg_timestamp(ts: TimestampTz) -> SystemTime {
+    let unix_usecs = ts.saturating_add(POSTGRES_EPOCH_OFFSET_USECS);
+    if unix_usecs >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_micros(unix_usecs as u64)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_micros(unix_usecs.unsigned_abs())
     }
 }
 
+///
+/// Format a timestamp the way PostgreSQL prints timestamptz values in the
+/// UTC time zone, e.g. "2022-10-12 08:15:30.25+00".
+///
+pub fn to_pg_text(ts: TimestampTz) -> String {
+    pq_proto::cell::format_timestamptz(ts)
+}
+
+///
+/// Parse a timestamp in the ISO 8601 format used by PostgreSQL, e.g.
+/// "2022-10-12 08:15:30.25+00" or "2022-10-12T08:15:30Z". A timestamp
+/// without a time zone offset is taken to be in UTC.
+///
+pub fn parse_pg_timestamp(s: &str) -> anyhow::Result<TimestampTz> {
+    let s = s.trim();
+    match s {
+        "-infinity" => return Ok(DT_NOBEGIN),
+        "infinity" => return Ok(DT_NOEND),
+        _ => {}
+    }
+    // chrono wants a space between the date and the time, and a numeric offset
+    let mut normalized = s.replacen('T', " ", 1);
+    if normalized.ends_with('Z') {
+        normalized.pop();
+        normalized.push_str("+00");
+    }
+
+    let datetime = match DateTime::parse_from_str(&normalized, "%Y-%m-%d %H:%M:%S%.f%#z") {
+        Ok(datetime) => datetime.with_timezone(&Utc),
+        Err(_) => {
+            let naive = NaiveDateTime::parse_from_str(&normalized, "%Y-%m-%d %H:%M:%S%.f")
+                .with_context(|| format!("invalid timestamp {:?}", s))?;
+            DateTime::<Utc>::from_utc(naive, Utc)
+        }
+    };
+    Ok(
+        datetime.timestamp() * USECS_PER_SEC + datetime.timestamp_subsec_micros() as i64
+            - POSTGRES_EPOCH_OFFSET_USECS,
+    )
+}
+
 // Returns (aligned) end_lsn of the last record in data_dir with WAL segments.
 // start_lsn must point to some previously known record boundary (beginning of
 // the next record). If no valid record after is found, start_lsn is returned
@@ -161,56 +222,174 @@ pub fn find_end_of_wal(
     start_lsn: Lsn, // start reading WAL at this point; must point at record start_lsn.
 ) -> anyhow::Result<Lsn> {
     let mut result = start_lsn;
-    let mut curr_lsn = start_lsn;
-    let mut buf = [0u8; XLOG_BLCKSZ];
     let pg_version = PG_MAJORVERSION[1..3].parse::<u32>().unwrap();
     debug!("find_end_of_wal PG_VERSION: {}", pg_version);
 
     let mut decoder = WalStreamDecoder::new(start_lsn, pg_version);
-
-    // loop over segments
-    loop {
-        let segno = curr_lsn.segment_number(wal_seg_size);
-        let seg_file_name = XLogFileName(PG_TLI, segno, wal_seg_size);
-        let seg_file_path = data_dir.join(seg_file_name);
-        match open_wal_segment(&seg_file_path)? {
-            None => {
-                // no more segments
-                debug!(
-                    "find_end_of_wal reached end at {:?}, segment {:?} doesn't exist",
-                    result, seg_file_path
-                );
-                return Ok(result);
+    let mut reader = SegmentReader::new(data_dir, wal_seg_size, start_lsn);
+
+    while let Some(chunk) = reader.read_chunk()? {
+        decoder.feed_bytes(&chunk);
+
+        // advance result past all completely read records
+        loop {
+            match decoder.poll_decode() {
+                Ok(Some(record)) => result = record.0,
+                Err(e) => {
+                    debug!(
+                        "find_end_of_wal reached end at {:?}, decode error: {:?}",
+                        result, e
+                    );
+                    return Ok(result);
+                }
+                Ok(None) => break, // need more data
             }
-            Some(mut segment) => {
-                let seg_offs = curr_lsn.segment_offset(wal_seg_size);
-                segment.seek(SeekFrom::Start(seg_offs as u64))?;
-                // loop inside segment
-                loop {
-                    let bytes_read = segment.read(&mut buf)?;
-                    if bytes_read == 0 {
-                        break; // EOF
-                    }
-                    curr_lsn += bytes_read as u64;
-                    decoder.feed_bytes(&buf[0..bytes_read]);
-
-                    // advance result past all completely read records
-                    loop {
-                        match decoder.poll_decode() {
-                            Ok(Some(record)) => result = record.0,
-                            Err(e) => {
-                                debug!(
-                                    "find_end_of_wal reached end at {:?}, decode error: {:?}",
-                                    result, e
-                                );
-                                return Ok(result);
-                            }
-                            Ok(None) => break, // need more data
-                        }
-                    }
+        }
+    }
+    debug!(
+        "find_end_of_wal reached end at {:?}, no more WAL after {:?}",
+        result,
+        reader.lsn()
+    );
+    Ok(result)
+}
+
+///
+/// Find the start of the first record beginning in the WAL segment `segno`,
+/// skipping the tail of a record continued from the previous segment, like
+/// XLogFindNextRecord() in Postgres. Returns None if the segment is missing or
+/// no valid page of it has a record start.
+///
+pub fn find_first_record_in_segment(
+    data_dir: &Path,
+    wal_seg_size: usize,
+    segno: XLogSegNo,
+) -> anyhow::Result<Option<Lsn>> {
+    let seg_start = Lsn(XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size));
+    let mut reader =
+        SegmentReader::new(data_dir, wal_seg_size, seg_start).with_readahead(XLOG_BLCKSZ);
+    let mut page_lsn = seg_start;
+    while let Some(page) = reader.read_chunk()? {
+        if page.len() < XLOG_BLCKSZ {
+            break;
+        }
+        let mut buf = &page[..];
+        let hdr = if page_lsn == seg_start {
+            XLogLongPageHeaderData::from_bytes(&mut buf)?.std
+        } else {
+            XLogPageHeaderData::from_bytes(&mut buf)?
+        };
+        if hdr.xlp_magic != XLOG_PAGE_MAGIC as u16 || hdr.xlp_pageaddr != page_lsn.0 {
+            break;
+        }
+        let hdr_size = XLOG_BLCKSZ - buf.len();
+        let rem_len = if hdr.xlp_info & XLP_FIRST_IS_CONTRECORD != 0 {
+            hdr.xlp_rem_len as usize
+        } else {
+            0
+        };
+        // The continuation ends on this page, and the next record starts
+        // right after it.
+        if hdr_size + rem_len < XLOG_BLCKSZ {
+            let offset = (hdr_size + rem_len + 7) & !7; // MAXALIGN
+            return Ok(Some(page_lsn + offset as u64));
+        }
+        page_lsn += XLOG_BLCKSZ as u64;
+    }
+    Ok(None)
+}
+
+/// Default amount of WAL read from a segment at once by SegmentReader
+pub const DEFAULT_SEGMENT_READAHEAD: usize = 1024 * 1024;
+
+///
+/// Sequential reader of the WAL segments in a directory.
+///
+/// Reads the WAL in large chunks, never crossing a segment boundary, so that
+/// scanning a long stretch of WAL doesn't cost a read() call per page. Each
+/// chunk is read into a newly allocated buffer and handed out as `Bytes`.
+/// Prefers the .partial segment file, if present.
+///
+pub struct SegmentReader {
+    data_dir: PathBuf,
+    wal_seg_size: usize,
+    readahead: usize,
+    /// Position of the next chunk
+    lsn: Lsn,
+    /// Segment containing 'lsn', positioned at it
+    segment: Option<File>,
+}
+
+impl SegmentReader {
+    pub fn new(data_dir: &Path, wal_seg_size: usize, start_lsn: Lsn) -> SegmentReader {
+        SegmentReader {
+            data_dir: data_dir.to_owned(),
+            wal_seg_size,
+            readahead: DEFAULT_SEGMENT_READAHEAD,
+            lsn: start_lsn,
+            segment: None,
+        }
+    }
+
+    /// Set the maximum chunk size. It is rounded up to XLOG_BLCKSZ.
+    pub fn with_readahead(mut self, readahead: usize) -> SegmentReader {
+        self.readahead =
+            std::cmp::max((readahead + XLOG_BLCKSZ - 1) / XLOG_BLCKSZ, 1) * XLOG_BLCKSZ;
+        self
+    }
+
+    /// Position of the next chunk to be read.
+    pub fn lsn(&self) -> Lsn {
+        self.lsn
+    }
+
+    ///
+    /// Read the next chunk of WAL. Returns None when there is no more WAL:
+    /// the next segment doesn't exist, or the current one ends prematurely.
+    ///
+    pub fn read_chunk(&mut self) -> anyhow::Result<Option<Bytes>> {
+        let seg_offs = self.lsn.segment_offset(self.wal_seg_size);
+        if self.segment.is_none() {
+            let segno = self.lsn.segment_number(self.wal_seg_size);
+            let seg_file_path = self
+                .data_dir
+                .join(XLogFileName(PG_TLI, segno, self.wal_seg_size));
+            match open_wal_segment(&seg_file_path)? {
+                None => return Ok(None),
+                Some(mut segment) => {
+                    segment
+                        .seek(SeekFrom::Start(seg_offs as u64))
+                        .with_context(|| format!("failed to seek in {:?}", seg_file_path))?;
+                    self.segment = Some(segment);
                 }
             }
         }
+        let segment = self.segment.as_mut().unwrap();
+
+        // Read up to the readahead size, but not past the end of the segment
+        // or the end of the file.
+        let len = std::cmp::min(self.readahead, self.wal_seg_size - seg_offs);
+        let mut buf = BytesMut::zeroed(len);
+        let mut nread = 0;
+        while nread < len {
+            match segment.read(&mut buf[nread..]) {
+                Ok(0) => break,
+                Ok(n) => nread += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if nread == 0 {
+            return Ok(None);
+        }
+        buf.truncate(nread);
+
+        self.lsn += nread as u64;
+        if self.lsn.segment_offset(self.wal_seg_size) == 0 {
+            // move on to the next segment
+            self.segment = None;
+        }
+        Ok(Some(buf.freeze()))
     }
 }
 
@@ -326,22 +505,144 @@ impl CheckPoint {
         }
         false
     }
+
+    /// Update next OID based on the value carried by a XLOG_NEXTOID record.
+    /// Like in xlog_redo(), the value is taken as is: OIDs wrap around
+    /// without an epoch, so an older value can't be told from a newer one.
+    ///
+    /// Returns 'true' if the OID was updated.
+    pub fn update_next_oid(&mut self, next_oid: Oid) -> bool {
+        if self.nextOid != next_oid {
+            self.nextOid = next_oid;
+            return true;
+        }
+        false
+    }
+
+    /// Update next multixact ID and members offset after a multixact with the
+    /// given ID and members was created. This is a port of
+    /// MultiXactAdvanceNextMXact() in multixact.c: both counters wrap around,
+    /// and multixact ID 0 (InvalidMultiXactId) is skipped.
+    ///
+    /// Returns 'true' if any of the counters was updated.
+    pub fn update_next_multixid(
+        &mut self,
+        multi_xid: MultiXactId,
+        multi_offset: MultiXactOffset,
+        nmembers: u32,
+    ) -> bool {
+        let mut modified = false;
+        let mut next_multi = multi_xid.wrapping_add(1);
+        if next_multi < pg_constants::FIRST_MULTIXACT_ID {
+            next_multi = pg_constants::FIRST_MULTIXACT_ID;
+        }
+        if next_multi.wrapping_sub(self.nextMulti) as i32 > 0 {
+            self.nextMulti = next_multi;
+            modified = true;
+        }
+        let next_offset = multi_offset.wrapping_add(nmembers);
+        if next_offset.wrapping_sub(self.nextMultiOffset) as i32 > 0 {
+            self.nextMultiOffset = next_offset;
+            modified = true;
+        }
+        modified
+    }
+
+    /// Update oldest XID (and the database it belongs to), if the provided
+    /// XID follows the stored one. Like in AdvanceOldestClogXid(), the value
+    /// is never moved backwards.
+    ///
+    /// Returns 'true' if the XID was updated.
+    pub fn update_oldest_xid(&mut self, oldest_xid: TransactionId, oldest_xid_db: Oid) -> bool {
+        if oldest_xid.wrapping_sub(self.oldestXid) as i32 > 0 {
+            self.oldestXid = oldest_xid;
+            self.oldestXidDB = oldest_xid_db;
+            return true;
+        }
+        false
+    }
+
+    /// Advance the counters (nextXid, nextOid, nextMulti, nextMultiOffset
+    /// and oldestXid) past the values that a WAL record allocates or reports,
+    /// so that they stay consistent with the WAL applied so far. `main_data`
+    /// is the main data of the record.
+    ///
+    /// Returns 'true' if any of the counters was updated.
+    pub fn advance_from_record(
+        &mut self,
+        xl_rmid: u8,
+        xl_info: u8,
+        xl_xid: TransactionId,
+        main_data: &[u8],
+    ) -> anyhow::Result<bool> {
+        let mut buf = main_data;
+        let mut modified = self.update_next_xid(xl_xid);
+
+        let info = xl_info & pg_constants::XLR_RMGR_INFO_MASK;
+        match xl_rmid {
+            pg_constants::RM_XLOG_ID if info == pg_constants::XLOG_NEXTOID => {
+                anyhow::ensure!(buf.remaining() >= 4, "truncated XLOG_NEXTOID record");
+                modified |= self.update_next_oid(buf.get_u32_le());
+            }
+            pg_constants::RM_XLOG_ID
+                if info == pg_constants::XLOG_CHECKPOINT_ONLINE
+                    || info == pg_constants::XLOG_CHECKPOINT_SHUTDOWN =>
+            {
+                let checkpoint_bytes = buf
+                    .get(..SIZEOF_CHECKPOINT)
+                    .context("truncated checkpoint record")?;
+                let xlog_checkpoint = CheckPoint::decode(checkpoint_bytes)?;
+                modified |=
+                    self.update_oldest_xid(xlog_checkpoint.oldestXid, xlog_checkpoint.oldestXidDB);
+            }
+            pg_constants::RM_MULTIXACT_ID if info == pg_constants::XLOG_MULTIXACT_CREATE_ID => {
+                // Only the counters are needed here, so read the
+                // xl_multixact_create header and the member XIDs in place
+                // instead of decoding the members.
+                anyhow::ensure!(buf.remaining() >= 12, "truncated multixact create record");
+                let mid = buf.get_u32_le();
+                let moff = buf.get_u32_le();
+                let nmembers = buf.get_u32_le();
+                modified |= self.update_next_multixid(mid, moff, nmembers);
+
+                // Each member is a (xid, status) pair.
+                let mut max_mbr_xid = 0u32;
+                for member in buf.chunks_exact(8).take(nmembers as usize) {
+                    let xid = u32::from_le_bytes(member[..4].try_into().unwrap());
+                    if xid.wrapping_sub(max_mbr_xid) as i32 > 0 {
+                        max_mbr_xid = xid;
+                    }
+                }
+                modified |= self.update_next_xid(max_mbr_xid);
+            }
+            _ => {}
+        }
+        Ok(modified)
+    }
 }
 
 //
-// Generate new, empty WAL segment.
-// We need this segment to start compute node.
+// Generate new, empty WAL segment containing the given LSN, on the given
+// timeline. We need this segment to start compute node.
+//
+// Returns the segment file name along with its contents, so that the name
+// and the page header always agree on segment number and timeline.
 //
-pub fn generate_wal_segment(segno: u64, system_id: u64) -> Result<Bytes, SerializeError> {
+pub fn generate_wal_segment(
+    lsn: Lsn,
+    system_id: u64,
+    tli: TimeLineID,
+) -> Result<(String, Bytes), SerializeError> {
     let mut seg_buf = BytesMut::with_capacity(WAL_SEGMENT_SIZE);
 
+    let segno = lsn.segment_number(WAL_SEGMENT_SIZE);
     let pageaddr = XLogSegNoOffsetToRecPtr(segno, 0, WAL_SEGMENT_SIZE);
     let hdr = XLogLongPageHeaderData {
         std: {
             XLogPageHeaderData {
                 xlp_magic: XLOG_PAGE_MAGIC as u16,
                 xlp_info: pg_constants::XLP_LONG_HEADER,
-                xlp_tli: PG_TLI,
+                xlp_tli: tli,
                 xlp_pageaddr: pageaddr,
                 xlp_rem_len: 0,
                 ..Default::default() // Put 0 in padding fields.
@@ -357,7 +658,144 @@ pub fn generate_wal_segment(segno: u64, system_id: u64) -> Result<Bytes, Seriali
 
     //zero out the rest of the file
     seg_buf.resize(WAL_SEGMENT_SIZE, 0);
-    Ok(seg_buf.freeze())
+    Ok((XLogFileName(tli, segno, WAL_SEGMENT_SIZE), seg_buf.freeze()))
+}
+
+pub fn TLHistoryFileName(tli: TimeLineID) -> String {
+    format!("{:>08X}.history", tli)
+}
+
+//
+// Generate timeline history file for timeline 'tli'. 'switchpoints' lists
+// the LSN at which each of the ancestor timelines was switched away from, in
+// the same order as in the history file: oldest timeline first. Postgres
+// requires this file to be present in pg_wal to start on a timeline other
+// than the first one.
+//
+// Returns the history file name along with its contents.
+//
+pub fn generate_timeline_history(
+    tli: TimeLineID,
+    switchpoints: &[(TimeLineID, Lsn)],
+) -> anyhow::Result<(String, Bytes)> {
+    let mut content = String::new();
+    let mut prev: Option<(TimeLineID, Lsn)> = None;
+    for &(parent_tli, switchpoint) in switchpoints {
+        if let Some((prev_tli, prev_switchpoint)) = prev {
+            anyhow::ensure!(
+                parent_tli > prev_tli && switchpoint >= prev_switchpoint,
+                "timeline history is not ordered at timeline {}",
+                parent_tli
+            );
+        }
+        anyhow::ensure!(
+            parent_tli < tli,
+            "ancestor timeline {} is not older than timeline {}",
+            parent_tli,
+            tli
+        );
+        content.push_str(&format!(
+            "{}\t{}\tno recovery target specified\n",
+            parent_tli, switchpoint
+        ));
+        prev = Some((parent_tli, switchpoint));
+    }
+    Ok((TLHistoryFileName(tli), Bytes::from(content)))
+}
+
+//
+// Create a new WAL segment file of 'seg_size' bytes, with all of its blocks
+// allocated up front. Writing WAL into such a file doesn't need to extend it,
+// so fdatasync() after each write doesn't have to flush file metadata.
+//
+pub fn preallocate_segment(path: &Path, seg_size: usize) -> anyhow::Result<File> {
+    let file = File::options()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("failed to create WAL segment {}", path.display()))?;
+    file.allocate(seg_size as u64)
+        .with_context(|| format!("failed to preallocate WAL segment {}", path.display()))?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+//
+// Reuse an old WAL segment file as segment 'new_segno' on timeline 'tli',
+// like RemoveXlogFile() and InstallXLogFileSegment() do in PostgreSQL
+// instead of deleting it. The file is renamed within its directory, and its
+// first page is overwritten with a fresh long page header; the rest of the
+// first page is zeroed. Old contents of the following pages are left in
+// place, WAL readers recognize them as stale by their xlp_pageaddr.
+//
+// Returns the path of the recycled segment.
+//
+pub fn recycle_segment(
+    old_path: &Path,
+    new_segno: XLogSegNo,
+    tli: TimeLineID,
+) -> anyhow::Result<PathBuf> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .open(old_path)
+        .with_context(|| format!("failed to open WAL segment {}", old_path.display()))?;
+
+    let mut page = [0u8; XLOG_BLCKSZ];
+    file.read_exact(&mut page)?;
+    let old_hdr = XLogLongPageHeaderData::from_bytes(&mut &page[..])?;
+    if old_hdr.std.xlp_magic != XLOG_PAGE_MAGIC as u16
+        || old_hdr.std.xlp_info & pg_constants::XLP_LONG_HEADER == 0
+    {
+        anyhow::bail!(
+            "WAL segment {} doesn't start with a long page header",
+            old_path.display()
+        );
+    }
+    let seg_size = old_hdr.xlp_seg_size as usize;
+    anyhow::ensure!(
+        file.metadata()?.len() == seg_size as u64,
+        "size of WAL segment {} doesn't match xlp_seg_size {}",
+        old_path.display(),
+        seg_size
+    );
+
+    let hdr = XLogLongPageHeaderData {
+        std: XLogPageHeaderData {
+            xlp_magic: XLOG_PAGE_MAGIC as u16,
+            xlp_info: pg_constants::XLP_LONG_HEADER,
+            xlp_tli: tli,
+            xlp_pageaddr: XLogSegNoOffsetToRecPtr(new_segno, 0, seg_size),
+            xlp_rem_len: 0,
+            ..Default::default() // Put 0 in padding fields.
+        },
+        xlp_sysid: old_hdr.xlp_sysid,
+        xlp_seg_size: old_hdr.xlp_seg_size,
+        xlp_xlog_blcksz: old_hdr.xlp_xlog_blcksz,
+    };
+    let hdr_bytes = hdr.encode()?;
+
+    // Like durable_rename_excl() in PostgreSQL: link() fails if the target
+    // exists, so a concurrently created segment is never clobbered.
+    let dir = old_path.parent().unwrap_or_else(|| Path::new("."));
+    let new_path = dir.join(XLogFileName(tli, new_segno, seg_size));
+    std::fs::hard_link(old_path, &new_path).with_context(|| {
+        format!(
+            "failed to rename WAL segment {} to {}",
+            old_path.display(),
+            new_path.display()
+        )
+    })?;
+    std::fs::remove_file(old_path)?;
+    utils::crashsafe::fsync(dir)?;
+
+    page.fill(0);
+    page[..hdr_bytes.len()].copy_from_slice(&hdr_bytes);
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&page)?;
+    file.sync_all()?;
+    Ok(new_path)
 }
 
 #[repr(C)]
@@ -413,8 +851,8 @@ pub fn encode_logical_message(prefix: &str, message: &str) -> Vec<u8> {
         xl_tot_len: total_len as u32,
         xl_xid: 0,
         xl_prev: 0,
-        xl_info: 0,
-        xl_rmid: 21,
+        xl_info: pg_constants::XLOG_LOGICAL_MESSAGE,
+        xl_rmid: pg_constants::RM_LOGICALMSG_ID,
         __bindgen_padding_0: [0u8; 2usize],
         xl_crc: 0, // crc will be calculated later
     };
@@ -439,6 +877,183 @@ pub fn encode_logical_message(prefix: &str, message: &str) -> Vec<u8> {
     wal
 }
 
+/// Size of xl_logical_message without the trailing prefix and message bytes.
+const SIZEOF_XL_LOGICAL_MESSAGE: usize = 4 + 4 + 8 + 8;
+
+/// Logical decoding message, as written by pg_logical_emit_message() or
+/// encode_logical_message().
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogicalMessage {
+    pub db_id: Oid,
+    pub transactional: bool,
+    pub prefix: String,
+    pub message: Bytes,
+    /// Replication origin the record was generated by, if any.
+    pub origin_id: Option<u16>,
+}
+
+/// Replication origin records, see xl_replorigin_set and xl_replorigin_drop
+/// in origin.h
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicationOriginRecord {
+    Set {
+        remote_lsn: Lsn,
+        node_id: u16,
+        force: bool,
+    },
+    Drop {
+        node_id: u16,
+    },
+}
+
+/// Split a WAL record without block references into its header, replication
+/// origin id and main data. Records touching data blocks are rejected, this
+/// is only meant for the non-relation records decoded below.
+fn split_main_data(record: &[u8]) -> anyhow::Result<(XLogRecord, Option<u16>, Bytes)> {
+    let mut buf = Bytes::copy_from_slice(record);
+    if buf.remaining() < XLOG_SIZE_OF_XLOG_RECORD {
+        anyhow::bail!("WAL record is too short: {} bytes", record.len());
+    }
+    let xlogrec = XLogRecord::from_bytes(&mut buf)?;
+    let tot_len = xlogrec.xl_tot_len as usize;
+    if tot_len < XLOG_SIZE_OF_XLOG_RECORD || tot_len > record.len() {
+        anyhow::bail!(
+            "invalid xl_tot_len {} for {} bytes of record",
+            tot_len,
+            record.len()
+        );
+    }
+    buf.truncate(tot_len - XLOG_SIZE_OF_XLOG_RECORD);
+
+    let mut origin_id = None;
+    let mut main_data_len = 0;
+    while buf.remaining() > main_data_len {
+        let block_id = buf.get_u8();
+        match block_id {
+            pg_constants::XLR_BLOCK_ID_DATA_SHORT => {
+                anyhow::ensure!(buf.remaining() >= 1, "truncated main data header");
+                main_data_len = buf.get_u8() as usize;
+            }
+            pg_constants::XLR_BLOCK_ID_DATA_LONG => {
+                anyhow::ensure!(buf.remaining() >= 4, "truncated main data header");
+                main_data_len = buf.get_u32_le() as usize;
+            }
+            pg_constants::XLR_BLOCK_ID_ORIGIN => {
+                anyhow::ensure!(buf.remaining() >= 2, "truncated origin header");
+                origin_id = Some(buf.get_u16_le());
+            }
+            pg_constants::XLR_BLOCK_ID_TOPLEVEL_XID => {
+                anyhow::ensure!(buf.remaining() >= 4, "truncated toplevel xid header");
+                buf.advance(4);
+            }
+            _ => anyhow::bail!("unexpected block id {} in WAL record", block_id),
+        }
+    }
+    anyhow::ensure!(
+        buf.remaining() == main_data_len,
+        "main data length {} doesn't match remaining {} bytes of record",
+        main_data_len,
+        buf.remaining()
+    );
+    Ok((xlogrec, origin_id, buf))
+}
+
+/// Decode logical decoding message record, the counterpart of
+/// encode_logical_message(). Returns None if the record is of another type.
+pub fn decode_logical_message(record: &[u8]) -> anyhow::Result<Option<LogicalMessage>> {
+    let (xlogrec, origin_id, mut buf) = split_main_data(record)?;
+    if xlogrec.xl_rmid != pg_constants::RM_LOGICALMSG_ID
+        || xlogrec.xl_info & pg_constants::XLR_RMGR_INFO_MASK != pg_constants::XLOG_LOGICAL_MESSAGE
+    {
+        return Ok(None);
+    }
+
+    anyhow::ensure!(
+        buf.remaining() >= SIZEOF_XL_LOGICAL_MESSAGE,
+        "logical message record is too short"
+    );
+    let db_id = buf.get_u32_le();
+    let transactional = buf.get_u8() != 0;
+    buf.advance(3); // padding
+    let prefix_size = buf.get_u64_le() as usize;
+    let message_size = buf.get_u64_le() as usize;
+    anyhow::ensure!(
+        prefix_size >= 1 && buf.remaining() == prefix_size + message_size,
+        "logical message sizes {}/{} don't match record length",
+        prefix_size,
+        message_size
+    );
+
+    // prefix is stored null-terminated
+    let prefix = buf.split_to(prefix_size);
+    let prefix = std::str::from_utf8(&prefix[..prefix_size - 1])?.to_string();
+
+    Ok(Some(LogicalMessage {
+        db_id,
+        transactional,
+        prefix,
+        message: buf,
+        origin_id: origin_id.filter(|id| *id != pg_constants::INVALID_REP_ORIGIN_ID),
+    }))
+}
+
+/// Decode replication origin record. Returns None if the record is of
+/// another type.
+pub fn decode_replorigin_record(record: &[u8]) -> anyhow::Result<Option<ReplicationOriginRecord>> {
+    let (xlogrec, _, mut buf) = split_main_data(record)?;
+    if xlogrec.xl_rmid != pg_constants::RM_REPLORIGIN_ID {
+        return Ok(None);
+    }
+
+    match xlogrec.xl_info & pg_constants::XLR_RMGR_INFO_MASK {
+        pg_constants::XLOG_REPLORIGIN_SET => {
+            anyhow::ensure!(
+                buf.remaining() >= 8 + 2 + 1,
+                "replorigin set record is too short"
+            );
+            let remote_lsn = Lsn(buf.get_u64_le());
+            let node_id = buf.get_u16_le();
+            let force = buf.get_u8() != 0;
+            Ok(Some(ReplicationOriginRecord::Set {
+                remote_lsn,
+                node_id,
+                force,
+            }))
+        }
+        pg_constants::XLOG_REPLORIGIN_DROP => {
+            anyhow::ensure!(buf.remaining() >= 2, "replorigin drop record is too short");
+            Ok(Some(ReplicationOriginRecord::Drop {
+                node_id: buf.get_u16_le(),
+            }))
+        }
+        info => anyhow::bail!("unknown replorigin record info 0x{:02x}", info),
+    }
+}
+
+/// Decode commit time of a transaction commit record, see xl_xact_commit in
+/// xact.h. Returns None if the record is of another type.
+pub fn decode_commit_timestamp(record: &[u8]) -> anyhow::Result<Option<TimestampTz>> {
+    // Look at the type first, split_main_data() rejects records with block
+    // references, which most of the records are.
+    anyhow::ensure!(
+        record.len() >= XLOG_SIZE_OF_XLOG_RECORD,
+        "WAL record is too short: {} bytes",
+        record.len()
+    );
+    let xlogrec = XLogRecord::from_slice(&record[..XLOG_SIZE_OF_XLOG_RECORD])?;
+    let info = xlogrec.xl_info & pg_constants::XLOG_XACT_OPMASK;
+    if xlogrec.xl_rmid != pg_constants::RM_XACT_ID
+        || (info != pg_constants::XLOG_XACT_COMMIT
+            && info != pg_constants::XLOG_XACT_COMMIT_PREPARED)
+    {
+        return Ok(None);
+    }
+
+    let (_, _, mut buf) = split_main_data(record)?;
+    anyhow::ensure!(buf.remaining() >= 8, "commit record is too short");
+    Ok(Some(buf.get_i64_le()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::PG_MAJORVERSION;
@@ -615,6 +1230,144 @@ mod tests {
         );
     }
 
+    #[test]
+    pub fn test_find_first_record_in_segment() {
+        use utils::bin_ser::LeSer;
+
+        let wal_seg_size = 4 * XLOG_BLCKSZ;
+        let dir = tempfile::tempdir().unwrap();
+        let seg_start = XLogSegNoOffsetToRecPtr(1, 0, wal_seg_size);
+        let page_hdr = |page: usize, rem_len: u32| XLogPageHeaderData {
+            xlp_magic: XLOG_PAGE_MAGIC as u16,
+            xlp_info: if rem_len > 0 {
+                XLP_FIRST_IS_CONTRECORD
+            } else {
+                0
+            },
+            xlp_tli: PG_TLI,
+            xlp_pageaddr: seg_start + (page * XLOG_BLCKSZ) as u64,
+            xlp_rem_len: rem_len,
+            ..Default::default()
+        };
+        let write_segment = |first_rem_len: u32, second_rem_len: u32| {
+            let mut seg = vec![0u8; wal_seg_size];
+            let long_hdr = XLogLongPageHeaderData {
+                std: page_hdr(0, first_rem_len),
+                xlp_sysid: 0,
+                xlp_seg_size: wal_seg_size as u32,
+                xlp_xlog_blcksz: XLOG_BLCKSZ as u32,
+            };
+            let bytes = long_hdr.encode().unwrap();
+            seg[..bytes.len()].copy_from_slice(&bytes);
+            let bytes = page_hdr(1, second_rem_len).ser().unwrap();
+            seg[XLOG_BLCKSZ..XLOG_BLCKSZ + bytes.len()].copy_from_slice(&bytes);
+            fs::write(dir.path().join(XLogFileName(PG_TLI, 1, wal_seg_size)), seg).unwrap();
+        };
+        let find = || find_first_record_in_segment(dir.path(), wal_seg_size, 1).unwrap();
+
+        assert_eq!(find(), None);
+
+        // No continuation record, the first record follows the header.
+        write_segment(0, 0);
+        assert_eq!(
+            find(),
+            Some(Lsn(seg_start + XLOG_SIZE_OF_XLOG_LONG_PHD as u64))
+        );
+
+        // The continuation ends on the first page, and is aligned.
+        write_segment(100, 0);
+        assert_eq!(
+            find(),
+            Some(Lsn(seg_start + XLOG_SIZE_OF_XLOG_LONG_PHD as u64 + 104))
+        );
+
+        // The continuation spans the whole first page.
+        let rem_on_second = 100;
+        write_segment(
+            (XLOG_BLCKSZ - XLOG_SIZE_OF_XLOG_LONG_PHD) as u32 + rem_on_second,
+            rem_on_second,
+        );
+        assert_eq!(
+            find(),
+            Some(Lsn(seg_start
+                + (XLOG_BLCKSZ + XLOG_SIZE_OF_XLOG_SHORT_PHD) as u64
+                + 104))
+        );
+    }
+
+    #[test]
+    pub fn test_segment_reader() {
+        let wal_seg_size = 4 * XLOG_BLCKSZ;
+        let dir = tempfile::tempdir().unwrap();
+        let seg = |segno| dir.path().join(XLogFileName(PG_TLI, segno, wal_seg_size));
+        let fill = |segno: u8| vec![segno; wal_seg_size];
+        fs::write(seg(1), fill(1)).unwrap();
+        // Partial segment is preferred to the full one
+        fs::write(seg(2), fill(0)).unwrap();
+        let mut partial = seg(2);
+        partial.set_extension("partial");
+        fs::write(partial, &fill(2)[..XLOG_BLCKSZ + 100]).unwrap();
+
+        let start_lsn = Lsn((wal_seg_size + 100) as u64);
+        let mut reader =
+            SegmentReader::new(dir.path(), wal_seg_size, start_lsn).with_readahead(1000);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = reader.read_chunk().unwrap() {
+            assert!(chunk.iter().all(|b| *b == chunk[0]));
+            chunks.push((chunk[0], chunk.len()));
+        }
+        assert_eq!(
+            chunks,
+            vec![
+                (1, XLOG_BLCKSZ),
+                (1, XLOG_BLCKSZ),
+                (1, XLOG_BLCKSZ),
+                (1, XLOG_BLCKSZ - 100),
+                (2, XLOG_BLCKSZ),
+                (2, 100),
+            ]
+        );
+        assert_eq!(
+            reader.lsn(),
+            Lsn((2 * wal_seg_size + XLOG_BLCKSZ + 100) as u64)
+        );
+    }
+
+    #[test]
+    pub fn test_pg_timestamp_conversions() {
+        assert_eq!(
+            from_pg_timestamp(0),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(946684800)
+        );
+        assert_eq!(to_pg_timestamp(SystemTime::UNIX_EPOCH), -946684800_000000);
+        for ts in [0, 1, -1, 687_000_123_456, -100_000_000_000_000] {
+            assert_eq!(to_pg_timestamp(from_pg_timestamp(ts)), ts);
+            assert_eq!(parse_pg_timestamp(&to_pg_text(ts)).unwrap(), ts);
+        }
+
+        assert_eq!(to_pg_text(0), "2000-01-01 00:00:00+00");
+        assert_eq!(to_pg_text(-1), "1999-12-31 23:59:59.999999+00");
+        assert_eq!(
+            to_pg_text(parse_pg_timestamp("2024-02-29T13:14:15.25Z").unwrap()),
+            "2024-02-29 13:14:15.25+00"
+        );
+        assert_eq!(to_pg_text(DT_NOEND), "infinity");
+        assert_eq!(parse_pg_timestamp("-infinity").unwrap(), DT_NOBEGIN);
+
+        // Time zone offsets
+        let utc = parse_pg_timestamp("2022-10-12 08:15:30").unwrap();
+        assert_eq!(parse_pg_timestamp("2022-10-12 08:15:30+00").unwrap(), utc);
+        assert_eq!(
+            parse_pg_timestamp("2022-10-12 10:45:30+02:30").unwrap(),
+            utc
+        );
+        assert_eq!(parse_pg_timestamp("2022-10-12 03:15:30-05").unwrap(), utc);
+
+        assert!(parse_pg_timestamp("2023-02-29 00:00:00").is_err());
+        assert!(parse_pg_timestamp("2022-10-12 24:00:00").is_err());
+        assert!(parse_pg_timestamp("yesterday").is_err());
+    }
+
     /// Check the math in update_next_xid
     ///
     /// NOTE: These checks are sensitive to the value of XID_CHECKPOINT_INTERVAL,
@@ -645,6 +1398,241 @@ mod tests {
         assert_eq!(checkpoint.nextXid.value, 2048);
     }
 
+    #[test]
+    pub fn test_update_next_oid() {
+        let checkpoint_buf = [0u8; std::mem::size_of::<CheckPoint>()];
+        let mut checkpoint = CheckPoint::decode(&checkpoint_buf).unwrap();
+
+        checkpoint.nextOid = 16384;
+        assert!(checkpoint.update_next_oid(24576));
+        assert_eq!(checkpoint.nextOid, 24576);
+
+        // Taken as is, there's no telling which of two OIDs is newer
+        assert!(checkpoint.update_next_oid(20000));
+        assert_eq!(checkpoint.nextOid, 20000);
+        assert!(!checkpoint.update_next_oid(20000));
+    }
+
+    #[test]
+    pub fn test_update_next_multixid() {
+        let checkpoint_buf = [0u8; std::mem::size_of::<CheckPoint>()];
+        let mut checkpoint = CheckPoint::decode(&checkpoint_buf).unwrap();
+
+        checkpoint.nextMulti = 1;
+        checkpoint.nextMultiOffset = 0;
+        assert!(checkpoint.update_next_multixid(1, 0, 2));
+        assert_eq!(checkpoint.nextMulti, 2);
+        assert_eq!(checkpoint.nextMultiOffset, 2);
+
+        // Older multixact doesn't change anything
+        assert!(!checkpoint.update_next_multixid(1, 0, 2));
+        assert_eq!(checkpoint.nextMulti, 2);
+        assert_eq!(checkpoint.nextMultiOffset, 2);
+
+        // Multixact ID wraps around past InvalidMultiXactId, the members
+        // offset wraps around to zero.
+        checkpoint.nextMulti = u32::MAX;
+        checkpoint.nextMultiOffset = u32::MAX - 1;
+        assert!(checkpoint.update_next_multixid(u32::MAX, u32::MAX - 1, 3));
+        assert_eq!(checkpoint.nextMulti, pg_constants::FIRST_MULTIXACT_ID);
+        assert_eq!(checkpoint.nextMultiOffset, 1);
+    }
+
+    #[test]
+    pub fn test_advance_from_record() {
+        let checkpoint_buf = [0u8; std::mem::size_of::<CheckPoint>()];
+        let mut checkpoint = CheckPoint::decode(&checkpoint_buf).unwrap();
+        checkpoint.nextMulti = 1;
+
+        let next_oid = 24576u32.to_le_bytes();
+        assert!(checkpoint
+            .advance_from_record(
+                pg_constants::RM_XLOG_ID,
+                pg_constants::XLOG_NEXTOID,
+                0,
+                &next_oid
+            )
+            .unwrap());
+        assert_eq!(checkpoint.nextOid, 24576);
+
+        // multixact 5 at offset 10 with members 700 and 900
+        let mut multixact = Vec::new();
+        for value in [5u32, 10, 2, 900, 0, 700, 0] {
+            multixact.extend_from_slice(&value.to_le_bytes());
+        }
+        assert!(checkpoint
+            .advance_from_record(
+                pg_constants::RM_MULTIXACT_ID,
+                pg_constants::XLOG_MULTIXACT_CREATE_ID,
+                0,
+                &multixact,
+            )
+            .unwrap());
+        assert_eq!(checkpoint.nextMulti, 6);
+        assert_eq!(checkpoint.nextMultiOffset, 12);
+        assert!(checkpoint.nextXid.value > 900);
+
+        assert!(checkpoint
+            .advance_from_record(pg_constants::RM_XLOG_ID, pg_constants::XLOG_NEXTOID, 0, &[])
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_update_oldest_xid() {
+        let checkpoint_buf = [0u8; std::mem::size_of::<CheckPoint>()];
+        let mut checkpoint = CheckPoint::decode(&checkpoint_buf).unwrap();
+
+        checkpoint.oldestXid = 1000;
+        checkpoint.oldestXidDB = 1;
+        assert!(checkpoint.update_oldest_xid(2000, 5));
+        assert_eq!(checkpoint.oldestXid, 2000);
+        assert_eq!(checkpoint.oldestXidDB, 5);
+
+        assert!(!checkpoint.update_oldest_xid(1500, 7));
+        assert_eq!(checkpoint.oldestXid, 2000);
+        assert_eq!(checkpoint.oldestXidDB, 5);
+    }
+
+    #[test]
+    pub fn test_generate_wal_segment() {
+        let lsn = Lsn(0x0300_0028);
+        let (fname, seg) = generate_wal_segment(lsn, 42, 3).unwrap();
+        assert_eq!(fname, "000000030000000000000003");
+        assert_eq!(seg.len(), WAL_SEGMENT_SIZE);
+
+        let hdr = XLogLongPageHeaderData::from_bytes(&mut seg.clone()).unwrap();
+        assert_eq!(hdr.std.xlp_tli, 3);
+        assert_eq!(hdr.std.xlp_pageaddr, 0x0300_0000);
+        assert_eq!(hdr.xlp_sysid, 42);
+    }
+
+    #[test]
+    pub fn test_preallocate_and_recycle_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(XLogFileName(1, 1, WAL_SEGMENT_SIZE));
+        let mut file = preallocate_segment(&path, WAL_SEGMENT_SIZE).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), WAL_SEGMENT_SIZE as u64);
+        // Existing segments are never overwritten
+        assert!(preallocate_segment(&path, WAL_SEGMENT_SIZE).is_err());
+
+        // Preallocated segment without a header cannot be recycled
+        assert!(recycle_segment(&path, 5, 1).is_err());
+
+        let (_, seg) = generate_wal_segment(Lsn(0x0100_0000), 42, 1).unwrap();
+        file.write_all(&seg[..XLOG_BLCKSZ]).unwrap();
+        file.write_all(&[0xAB; XLOG_BLCKSZ]).unwrap();
+        drop(file);
+
+        let new_path = recycle_segment(&path, 5, 2).unwrap();
+        assert!(!path.exists());
+        assert_eq!(
+            new_path.file_name().unwrap(),
+            XLogFileName(2, 5, WAL_SEGMENT_SIZE).as_str()
+        );
+
+        let contents = fs::read(&new_path).unwrap();
+        assert_eq!(contents.len(), WAL_SEGMENT_SIZE);
+        let hdr = XLogLongPageHeaderData::from_bytes(&mut &contents[..]).unwrap();
+        assert_eq!(hdr.std.xlp_tli, 2);
+        assert_eq!(
+            hdr.std.xlp_pageaddr,
+            XLogSegNoOffsetToRecPtr(5, 0, WAL_SEGMENT_SIZE)
+        );
+        assert_eq!(hdr.xlp_sysid, 42);
+        assert!(contents[XLOG_SIZE_OF_XLOG_LONG_PHD..XLOG_BLCKSZ]
+            .iter()
+            .all(|b| *b == 0));
+        // Following pages are left as is
+        assert_eq!(contents[XLOG_BLCKSZ], 0xAB);
+
+        // An existing segment is never clobbered
+        let other_path = dir.path().join(XLogFileName(2, 6, WAL_SEGMENT_SIZE));
+        fs::write(&other_path, b"other").unwrap();
+        assert!(recycle_segment(&new_path, 6, 2).is_err());
+        assert_eq!(fs::read(&other_path).unwrap(), b"other");
+        assert!(new_path.exists());
+    }
+
+    #[test]
+    pub fn test_generate_timeline_history() {
+        let (fname, content) =
+            generate_timeline_history(3, &[(1, Lsn(0x0300_0000)), (2, Lsn(0x1_0500_00A0))])
+                .unwrap();
+        assert_eq!(fname, "00000003.history");
+        assert_eq!(
+            &content[..],
+            b"1\t0/3000000\tno recovery target specified\n\
+              2\t1/50000A0\tno recovery target specified\n"
+        );
+
+        assert!(generate_timeline_history(2, &[(2, Lsn(0x0300_0000))]).is_err());
+        assert!(
+            generate_timeline_history(3, &[(2, Lsn(0x0300_0000)), (1, Lsn(0x0400_0000))]).is_err()
+        );
+    }
+
+    #[test]
+    pub fn test_split_on_record_boundaries() {
+        use crate::waldecoder::split_on_record_boundaries;
+
+        let pg_version = PG_MAJORVERSION[1..3].parse::<u32>().unwrap();
+        let start_lsn = Lsn(0x0100_0000);
+        let (_, seg) = generate_wal_segment(start_lsn, 42, 1).unwrap();
+
+        let mut buf = seg[..XLOG_SIZE_OF_XLOG_LONG_PHD].to_vec();
+        // Message lengths are chosen so that the records are MAXALIGNed
+        let first = encode_logical_message("prefix", "message");
+        let second = encode_logical_message("prefix", "another message");
+        buf.extend_from_slice(&first);
+        buf.extend_from_slice(&second);
+        buf.extend_from_slice(&first[..10]);
+
+        let ranges =
+            split_on_record_boundaries(&buf, start_lsn, pg_version, WAL_SEGMENT_SIZE).unwrap();
+        let first_end = XLOG_SIZE_OF_XLOG_LONG_PHD + first.len();
+        assert_eq!(
+            ranges,
+            vec![0..first_end, first_end..first_end + second.len()]
+        );
+
+        // Incomplete record only
+        let ranges = split_on_record_boundaries(
+            &buf[..first_end - 1],
+            start_lsn,
+            pg_version,
+            WAL_SEGMENT_SIZE,
+        )
+        .unwrap();
+        assert!(ranges.is_empty());
+
+        // Garbage instead of the page header
+        assert!(
+            split_on_record_boundaries(&first, start_lsn, pg_version, WAL_SEGMENT_SIZE).is_err()
+        );
+    }
+
+    #[test]
+    pub fn test_record_start_lsn() {
+        let pg_version = PG_MAJORVERSION[1..3].parse::<u32>().unwrap();
+        let start_lsn = Lsn(0x0100_0000);
+        let (_, seg) = generate_wal_segment(start_lsn, 42, 1).unwrap();
+
+        let mut buf = seg[..XLOG_SIZE_OF_XLOG_LONG_PHD].to_vec();
+        let first = encode_logical_message("prefix", "message");
+        buf.extend_from_slice(&first);
+        buf.extend_from_slice(&first);
+
+        let mut decoder = WalStreamDecoder::new(start_lsn, pg_version);
+        decoder.feed_bytes(&buf);
+        // The record starting the segment begins after the page header.
+        let (end_lsn, _) = decoder.poll_decode().unwrap().unwrap();
+        let first_start = start_lsn + XLOG_SIZE_OF_XLOG_LONG_PHD as u64;
+        assert_eq!(decoder.record_start_lsn, first_start);
+        assert_eq!(end_lsn, first_start + first.len() as u64);
+        decoder.poll_decode().unwrap().unwrap();
+        assert_eq!(decoder.record_start_lsn, end_lsn);
+    }
+
     #[test]
     pub fn test_encode_logical_message() {
         let expected = [
@@ -655,4 +1643,86 @@ mod tests {
         let actual = encode_logical_message("prefix", "message");
         assert_eq!(expected, actual[..]);
     }
+
+    #[test]
+    pub fn test_decode_logical_message() {
+        let record = encode_logical_message("prefix", "message");
+        let msg = decode_logical_message(&record).unwrap().unwrap();
+        assert_eq!(
+            msg,
+            LogicalMessage {
+                db_id: 0,
+                transactional: false,
+                prefix: "prefix".to_string(),
+                message: Bytes::from_static(b"message"),
+                origin_id: None,
+            }
+        );
+
+        // Not a logical message
+        let mut record = record;
+        record[17] = pg_constants::RM_XLOG_ID;
+        assert!(decode_logical_message(&record).unwrap().is_none());
+
+        // Garbage
+        assert!(decode_logical_message(&record[..10]).is_err());
+    }
+
+    #[test]
+    pub fn test_decode_replorigin_record() {
+        let mut data = vec![pg_constants::XLR_BLOCK_ID_DATA_SHORT, 16];
+        data.extend_from_slice(&0x1_0000_0028u64.to_le_bytes());
+        data.extend_from_slice(&7u16.to_le_bytes());
+        data.extend_from_slice(&[1, 0, 0, 0, 0, 0]);
+        let header = XLogRecord {
+            xl_tot_len: (XLOG_SIZE_OF_XLOG_RECORD + data.len()) as u32,
+            xl_xid: 0,
+            xl_prev: 0,
+            xl_info: pg_constants::XLOG_REPLORIGIN_SET,
+            xl_rmid: pg_constants::RM_REPLORIGIN_ID,
+            __bindgen_padding_0: [0u8; 2usize],
+            xl_crc: 0,
+        };
+        let mut record = header.encode().unwrap().to_vec();
+        record.extend_from_slice(&data);
+
+        assert_eq!(
+            decode_replorigin_record(&record).unwrap(),
+            Some(ReplicationOriginRecord::Set {
+                remote_lsn: Lsn(0x1_0000_0028),
+                node_id: 7,
+                force: true,
+            })
+        );
+        assert!(decode_logical_message(&record).unwrap().is_none());
+    }
+
+    #[test]
+    pub fn test_decode_commit_timestamp() {
+        let commit_time: TimestampTz = 0x2_9B5E_8D3A_6C10;
+        let mut data = vec![pg_constants::XLR_BLOCK_ID_DATA_SHORT, 12];
+        data.extend_from_slice(&commit_time.to_le_bytes());
+        // xinfo, not looked at
+        data.extend_from_slice(&0u32.to_le_bytes());
+        let header = XLogRecord {
+            xl_tot_len: (XLOG_SIZE_OF_XLOG_RECORD + data.len()) as u32,
+            xl_xid: 742,
+            xl_prev: 0,
+            xl_info: pg_constants::XLOG_XACT_COMMIT | pg_constants::XLOG_XACT_HAS_INFO,
+            xl_rmid: pg_constants::RM_XACT_ID,
+            __bindgen_padding_0: [0u8; 2usize],
+            xl_crc: 0,
+        };
+        let mut record = header.encode().unwrap().to_vec();
+        record.extend_from_slice(&data);
+
+        assert_eq!(decode_commit_timestamp(&record).unwrap(), Some(commit_time));
+
+        // Not a commit record
+        let logical_message = encode_logical_message("prefix", "message");
+        assert!(decode_commit_timestamp(&logical_message).unwrap().is_none());
+
+        // Garbage
+        assert!(decode_commit_timestamp(&record[..10]).is_err());
+    }
 }
@@ -0,0 +1,221 @@
+//! Encoding of DataRow cells of common types, in the text or binary format
+//! requested by the client. See
+//! <https://www.postgresql.org/docs/devel/protocol-overview.html#PROTOCOL-FORMAT-CODES>.
+
+use std::fmt::Write;
+use std::time::SystemTime;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::NaiveDateTime;
+use postgres_protocol::PG_EPOCH;
+
+use crate::{Oid, BYTEA_OID, INT2_OID, INT4_OID, INT8_OID, TEXT_OID, TIMESTAMPTZ_OID};
+
+/// Format of column values, as sent in RowDescription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatCode {
+    Text,
+    Binary,
+}
+
+impl FormatCode {
+    pub const fn code(self) -> i16 {
+        match self {
+            FormatCode::Text => 0,
+            FormatCode::Binary => 1,
+        }
+    }
+
+    pub fn from_code(code: i16) -> anyhow::Result<Self> {
+        match code {
+            0 => Ok(FormatCode::Text),
+            1 => Ok(FormatCode::Binary),
+            _ => anyhow::bail!("invalid format code {code}"),
+        }
+    }
+
+    /// Format of column `column` given result format codes as sent by the
+    /// client: none means all columns are text, a single one applies to all
+    /// columns.
+    pub fn for_column(formats: &[FormatCode], column: usize) -> FormatCode {
+        match formats {
+            [] => FormatCode::Text,
+            [format] => *format,
+            formats => formats.get(column).copied().unwrap_or(FormatCode::Text),
+        }
+    }
+}
+
+/// Value of a DataRow cell.
+#[derive(Debug, Clone, Copy)]
+pub enum Cell<'a> {
+    Null,
+    Int2(i16),
+    Int4(i32),
+    Int8(i64),
+    Text(&'a str),
+    Bytea(&'a [u8]),
+    TimestampTz(SystemTime),
+    /// LSN, sent as text in the usual `X/X` form.
+    Lsn(u64),
+}
+
+impl Cell<'_> {
+    /// Type of the column holding the value.
+    pub const fn typoid(&self) -> Oid {
+        match self {
+            Cell::Null | Cell::Text(_) | Cell::Lsn(_) => TEXT_OID,
+            Cell::Int2(_) => INT2_OID,
+            Cell::Int4(_) => INT4_OID,
+            Cell::Int8(_) => INT8_OID,
+            Cell::Bytea(_) => BYTEA_OID,
+            Cell::TimestampTz(_) => TIMESTAMPTZ_OID,
+        }
+    }
+
+    /// Encode the value for DataRow, None for NULL.
+    pub fn encode(&self, format: FormatCode) -> Option<Bytes> {
+        let mut buf = BytesMut::new();
+        match (self, format) {
+            (Cell::Null, _) => return None,
+            (Cell::Int2(v), FormatCode::Binary) => buf.put_i16(*v),
+            (Cell::Int4(v), FormatCode::Binary) => buf.put_i32(*v),
+            (Cell::Int8(v), FormatCode::Binary) => buf.put_i64(*v),
+            (Cell::Int2(v), FormatCode::Text) => write!(buf, "{v}").unwrap(),
+            (Cell::Int4(v), FormatCode::Text) => write!(buf, "{v}").unwrap(),
+            (Cell::Int8(v), FormatCode::Text) => write!(buf, "{v}").unwrap(),
+            // text is the same in both formats
+            (Cell::Text(s), _) => buf.put_slice(s.as_bytes()),
+            (Cell::Bytea(v), FormatCode::Binary) => buf.put_slice(v),
+            (Cell::Bytea(v), FormatCode::Text) => {
+                buf.put_slice(b"\\x");
+                for b in v.iter() {
+                    write!(buf, "{b:02x}").unwrap();
+                }
+            }
+            (Cell::TimestampTz(t), FormatCode::Binary) => buf.put_i64(micros_since(*t, *PG_EPOCH)),
+            (Cell::TimestampTz(t), FormatCode::Text) => {
+                buf.put_slice(format_timestamptz(micros_since(*t, *PG_EPOCH)).as_bytes())
+            }
+            (Cell::Lsn(lsn), _) => write!(buf, "{:X}/{:X}", lsn >> 32, *lsn as u32).unwrap(),
+        }
+        Some(buf.freeze())
+    }
+}
+
+/// Encode a row for [`crate::BeMessage::DataRowBytes`], with formats of
+/// the columns given as in [`FormatCode::for_column`].
+pub fn encode_row(cells: &[Cell], formats: &[FormatCode]) -> Vec<Option<Bytes>> {
+    cells
+        .iter()
+        .enumerate()
+        .map(|(i, cell)| cell.encode(FormatCode::for_column(formats, i)))
+        .collect()
+}
+
+fn micros_since(t: SystemTime, epoch: SystemTime) -> i64 {
+    match t.duration_since(epoch) {
+        Ok(d) => d.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+/// Offset of the Postgres epoch, 2000-01-01, from the Unix epoch.
+const PG_EPOCH_UNIX_USECS: i128 = 946_684_800_000_000;
+const USECS_PER_SEC: i128 = 1_000_000;
+
+/// Format a timestamptz, given in microseconds since the Postgres epoch, in
+/// the ISO output style of Postgres, in UTC: `2000-01-01 00:00:00.5+00`.
+pub fn format_timestamptz(ts: i64) -> String {
+    match ts {
+        // DT_NOBEGIN and DT_NOEND
+        i64::MIN => return "-infinity".to_string(),
+        i64::MAX => return "infinity".to_string(),
+        _ => {}
+    }
+    let unix_usecs = ts as i128 + PG_EPOCH_UNIX_USECS;
+    let secs = unix_usecs.div_euclid(USECS_PER_SEC);
+    let usecs = unix_usecs.rem_euclid(USECS_PER_SEC) as u32;
+    let datetime = match i64::try_from(secs)
+        .ok()
+        .and_then(|secs| NaiveDateTime::from_timestamp_opt(secs, usecs * 1000))
+    {
+        Some(datetime) => datetime,
+        // beyond the years chrono can represent
+        None => return ts.to_string(),
+    };
+
+    let mut result = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+    if usecs != 0 {
+        // like Postgres, omit trailing zeros of the fractional part
+        let fraction = format!("{usecs:06}");
+        result.push('.');
+        result.push_str(fraction.trim_end_matches('0'));
+    }
+    result.push_str("+00");
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn encode(cell: Cell, format: FormatCode) -> Vec<u8> {
+        cell.encode(format).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_encode_cells() {
+        use FormatCode::*;
+
+        assert_eq!(Cell::Null.encode(Binary), None);
+        assert_eq!(encode(Cell::Int2(-2), Text), b"-2");
+        assert_eq!(encode(Cell::Int2(-2), Binary), [0xff, 0xfe]);
+        assert_eq!(encode(Cell::Int4(42), Text), b"42");
+        assert_eq!(encode(Cell::Int4(42), Binary), [0, 0, 0, 42]);
+        assert_eq!(
+            encode(Cell::Int8(1 << 32), Binary),
+            [0, 0, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(encode(Cell::Text("abc"), Binary), b"abc");
+        assert_eq!(encode(Cell::Bytea(&[0xde, 0xad]), Text), b"\\xdead");
+        assert_eq!(encode(Cell::Bytea(&[0xde, 0xad]), Binary), [0xde, 0xad]);
+        assert_eq!(encode(Cell::Lsn(0x1_0000_00A8), Text), b"1/A8");
+
+        let t = *PG_EPOCH + Duration::from_micros(86400 * 1_000_000 + 500_000);
+        assert_eq!(
+            encode(Cell::TimestampTz(t), Text),
+            b"2000-01-02 00:00:00.5+00"
+        );
+        assert_eq!(
+            encode(Cell::TimestampTz(t), Binary),
+            86_400_500_000i64.to_be_bytes()
+        );
+        let t = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(
+            encode(Cell::TimestampTz(t), Text),
+            b"2024-02-29 12:34:56+00"
+        );
+        let t = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(
+            encode(Cell::TimestampTz(t), Text),
+            b"1969-12-31 23:59:59+00"
+        );
+    }
+
+    #[test]
+    fn test_row_formats() {
+        use FormatCode::*;
+
+        let cells = [Cell::Int4(1), Cell::Text("a"), Cell::Null];
+        let row = encode_row(&cells, &[]);
+        assert_eq!(row[0].as_deref(), Some(&b"1"[..]));
+        let row = encode_row(&cells, &[Binary]);
+        assert_eq!(row[0].as_deref(), Some(&[0, 0, 0, 1][..]));
+        let row = encode_row(&cells, &[Text, Binary, Binary]);
+        assert_eq!(row[0].as_deref(), Some(&b"1"[..]));
+        assert_eq!(row[1].as_deref(), Some(&b"a"[..]));
+        assert_eq!(row[2], None);
+    }
+}
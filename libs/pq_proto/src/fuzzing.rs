@@ -0,0 +1,127 @@
+//! Entry points for fuzzing the message parsers with arbitrary input, to be
+//! called from cargo-fuzz targets (which build with `--cfg fuzzing`):
+//!
+//! ```ignore
+//! fuzz_target!(|data: &[u8]| pq_proto::fuzzing::decode_fe_message(data));
+//! ```
+//!
+//! Parse errors are expected; panics, over-long messages slipping through
+//! the length limits and failed roundtrips are bugs.
+
+use bytes::BytesMut;
+use postgres_protocol::message::backend::Message;
+use std::io::Cursor;
+
+use crate::{BeMessage, FeMessage, FeStartupPacket};
+
+/// Small limits, so that both sides of them are reachable by the fuzzer.
+const MAX_MESSAGE_LEN: usize = 1024;
+const MAX_STARTUP_MESSAGE_LEN: usize = 256;
+
+/// Read regular messages from `data` until EOF or the first error.
+pub fn decode_fe_message(data: &[u8]) {
+    let mut stream = Cursor::new(data);
+    loop {
+        let start = stream.position();
+        match FeMessage::read_limited(&mut stream, MAX_MESSAGE_LEN) {
+            Ok(Some(_)) => {
+                // tag byte and the length-prefixed body
+                let len = (stream.position() - start) as usize;
+                assert!(len <= MAX_MESSAGE_LEN + 1, "message of {len} bytes read");
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+}
+
+/// Read the startup packet, and the messages following it, from `data`.
+pub fn decode_startup(data: &[u8]) {
+    let mut stream = Cursor::new(data);
+    match FeStartupPacket::read_limited(&mut stream, MAX_STARTUP_MESSAGE_LEN) {
+        Ok(Some(FeMessage::StartupPacket(_))) => {
+            let len = stream.position() as usize;
+            assert!(
+                len <= MAX_STARTUP_MESSAGE_LEN,
+                "startup packet of {len} bytes read"
+            );
+        }
+        Ok(_) | Err(_) => return,
+    }
+    decode_fe_message(&data[stream.position() as usize..]);
+}
+
+/// Encode backend messages carrying `data` and check that an independent
+/// decoder reads each of them back whole.
+pub fn decode_be_roundtrip(data: &[u8]) {
+    let text = String::from_utf8_lossy(data);
+    let cells: Vec<Option<&[u8]>> = data
+        .split(|&b| b == 0xff)
+        .map(|cell| (!cell.is_empty()).then_some(cell))
+        .collect();
+    let (name, value) = data.split_at(data.len() / 2);
+    let mid = (0..=text.len() / 2)
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap();
+    let (channel, payload) = text.split_at(mid);
+
+    let messages = [
+        BeMessage::DataRow(&cells),
+        BeMessage::CommandComplete(data),
+        BeMessage::CopyData(data),
+        BeMessage::ErrorResponse(&text, None),
+        BeMessage::NoticeResponse(&text),
+        BeMessage::ParameterStatus { name, value },
+        BeMessage::NotificationResponse {
+            process_id: data.len() as i32,
+            channel,
+            payload,
+        },
+    ];
+    for message in &messages {
+        let mut buf = BytesMut::new();
+        // strings with embedded nulls are refused
+        if BeMessage::write(&mut buf, message).is_err() {
+            continue;
+        }
+        let decoded = Message::parse(&mut buf)
+            .unwrap_or_else(|e| panic!("failed to decode {message:?}: {e}"))
+            .unwrap_or_else(|| panic!("{message:?} decoded as incomplete"));
+        assert!(buf.is_empty(), "{message:?} decoded with trailing bytes");
+        let same_kind = matches!(
+            (message, &decoded),
+            (BeMessage::DataRow(_), Message::DataRow(_))
+                | (BeMessage::CommandComplete(_), Message::CommandComplete(_))
+                | (BeMessage::CopyData(_), Message::CopyData(_))
+                | (BeMessage::ErrorResponse(..), Message::ErrorResponse(_))
+                | (BeMessage::NoticeResponse(_), Message::NoticeResponse(_))
+                | (
+                    BeMessage::ParameterStatus { .. },
+                    Message::ParameterStatus(_)
+                )
+                | (
+                    BeMessage::NotificationResponse { .. },
+                    Message::NotificationResponse(_)
+                )
+        );
+        assert!(same_kind, "{message:?} decoded as another message");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuzzing_entry_points() {
+        let query = b"Q\0\0\0\x0dselect 1\0";
+        let startup = b"\0\0\0\x13\0\x03\0\0user\0test\0\0";
+        decode_fe_message(query);
+        // over the limit, and truncated
+        decode_fe_message(b"Q\0\x01\0\0select");
+        decode_startup(&[&startup[..], &query[..]].concat());
+        decode_startup(b"\0\0\x01\0\0\x03\0\0");
+        decode_be_roundtrip(b"");
+        decode_be_roundtrip(b"a\xffb\0c\xff\xff");
+    }
+}
@@ -2,16 +2,30 @@
 //! <https://www.postgresql.org/docs/devel/protocol-message-formats.html>
 //! on message formats.
 
+// Encoding of DataRow cells in text and binary formats.
+pub mod cell;
+// Parser entry points for cargo-fuzz targets.
+#[cfg(any(fuzzing, test))]
+pub mod fuzzing;
+// Key-value pairs of the `options` startup parameter.
+pub mod options;
+// Structured ErrorResponse and NoticeResponse.
+pub mod pg_error;
 // Tools for calling certain async methods in sync contexts.
 pub mod sync;
+// Tracing of every message of a connection, for protocol debugging.
+#[cfg(feature = "trace")]
+pub mod trace;
 
 use anyhow::{ensure, Context, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use options::StartupOptions;
+use pg_error::PgError;
 use postgres_protocol::PG_EPOCH;
 use serde::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt,
     future::Future,
     io::{self, Cursor},
@@ -25,9 +39,12 @@ use tracing::{trace, warn};
 pub type Oid = u32;
 pub type SystemId = u64;
 
+pub const BYTEA_OID: Oid = 17;
 pub const INT8_OID: Oid = 20;
+pub const INT2_OID: Oid = 21;
 pub const INT4_OID: Oid = 23;
 pub const TEXT_OID: Oid = 25;
+pub const TIMESTAMPTZ_OID: Oid = 1184;
 
 #[derive(Debug)]
 pub enum FeMessage {
@@ -41,10 +58,16 @@ pub enum FeMessage {
     Execute(FeExecuteMessage),
     Close(FeCloseMessage),
     Sync,
+    // Request to flush the output buffered during extended query.
+    Flush,
     Terminate,
+    // Fast-path function call, which we don't support but have to parse to
+    // reply with an error.
+    FunctionCall(FeFunctionCallMessage),
     CopyData(Bytes),
     CopyDone,
-    CopyFail,
+    // Error message of the client, null-terminated.
+    CopyFail(Bytes),
     PasswordMessage(Bytes),
 }
 
@@ -60,11 +83,73 @@ pub enum FeStartupPacket {
     },
 }
 
+impl FeStartupPacket {
+    /// Replication mode requested by a startup message; other packets don't
+    /// request any.
+    pub fn replication_mode(&self) -> Result<ReplicationMode> {
+        match self {
+            FeStartupPacket::StartupMessage { params, .. } => params.replication(),
+            _ => Ok(ReplicationMode::Off),
+        }
+    }
+
+    /// User name requested by a startup message; it is mandatory, so errors
+    /// if missing or if the packet is not a startup message.
+    pub fn user(&self) -> Result<&str> {
+        match self {
+            FeStartupPacket::StartupMessage { params, .. } => params
+                .get("user")
+                .context("no user name specified in the startup message"),
+            _ => anyhow::bail!("{self:?} is not a startup message"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct StartupMessageParams {
     params: HashMap<String, String>,
 }
 
+/// Value of the `replication` startup parameter, which turns the session
+/// into a walsender one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationMode {
+    /// Ordinary session, accepting SQL queries only.
+    #[default]
+    Off,
+    /// `replication=true`: physical replication commands only, with no
+    /// database to run SQL against.
+    Physical,
+    /// `replication=database`: replication commands as well as SQL, like
+    /// `pg_basebackup -X stream` and logical replication clients use.
+    Database,
+}
+
+impl str::FromStr for ReplicationMode {
+    type Err = anyhow::Error;
+
+    /// Besides `database`, accepts whatever boolean spelling postgres does,
+    /// see `postgres: parse_bool_with_len`.
+    fn from_str(s: &str) -> Result<Self> {
+        let value = s.to_ascii_lowercase();
+        let is_prefix_of = |word: &str, min_len| value.len() >= min_len && word.starts_with(&value);
+        if value == "database" {
+            Ok(Self::Database)
+        } else if is_prefix_of("true", 1) || is_prefix_of("yes", 1) || value == "on" || value == "1"
+        {
+            Ok(Self::Physical)
+        } else if is_prefix_of("false", 1)
+            || is_prefix_of("no", 1)
+            || is_prefix_of("off", 2)
+            || value == "0"
+        {
+            Ok(Self::Off)
+        } else {
+            anyhow::bail!("invalid value for parameter \"replication\": \"{s}\"")
+        }
+    }
+}
+
 impl StartupMessageParams {
     /// Get parameter's value by its name.
     pub fn get(&self, name: &str) -> Option<&str> {
@@ -85,6 +170,13 @@ impl StartupMessageParams {
         self.get("options").map(Self::parse_options_escaped)
     }
 
+    /// Key-value pairs of the `options` parameter, empty if there is none.
+    pub fn options(&self) -> StartupOptions {
+        self.get("options")
+            .map(StartupOptions::parse)
+            .unwrap_or_default()
+    }
+
     /// Split command-line options according to PostgreSQL's logic,
     /// taking into account all escape sequences but leaving them as-is.
     pub fn parse_options_raw(input: &str) -> impl Iterator<Item = &str> {
@@ -120,6 +212,12 @@ impl StartupMessageParams {
         })
     }
 
+    /// Parsed `replication` parameter, [`ReplicationMode::Off`] if there is none.
+    pub fn replication(&self) -> Result<ReplicationMode> {
+        self.get("replication")
+            .map_or(Ok(ReplicationMode::Off), |value| value.parse())
+    }
+
     /// Iterate through key-value pairs in an arbitrary order.
     pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
         self.params.iter().map(|(k, v)| (k.as_str(), v.as_str()))
@@ -173,7 +271,8 @@ pub struct FeParseMessage {
 #[derive(Debug)]
 pub struct FeDescribeMessage {
     pub kind: u8, // 'S' to describe a prepared statement; or 'P' to describe a portal.
-                  // we only support unnamed prepared stmt or portal
+    /// Name of the prepared statement or portal, empty for the unnamed one.
+    pub name: Bytes,
 }
 
 // we only support unnamed prepared stmt and portal
@@ -187,9 +286,31 @@ pub struct FeExecuteMessage {
     pub maxrows: i32,
 }
 
-// we only support unnamed prepared stmt and portal
 #[derive(Debug)]
-pub struct FeCloseMessage;
+pub struct FeCloseMessage {
+    pub kind: u8, // 'S' to close a prepared statement; or 'P' to close a portal.
+    /// Name of the prepared statement or portal, empty for the unnamed one.
+    pub name: Bytes,
+}
+
+#[derive(Debug)]
+pub struct FeFunctionCallMessage {
+    pub function_oid: Oid,
+    /// Argument format codes, arguments and the result format code, as is.
+    pub args: Bytes,
+}
+
+/// SASLInitialResponse, the first message of the client in SASL
+/// authentication. It comes as [`FeMessage::PasswordMessage`], as the tag is
+/// shared; the following SASLResponse messages are just mechanism-specific
+/// data in the PasswordMessage body.
+#[derive(Debug)]
+pub struct FeSaslInitialResponse {
+    /// Mechanism selected by the client, e.g. SCRAM-SHA-256.
+    pub mechanism: Bytes,
+    /// Mechanism-specific initial response, if any.
+    pub data: Option<Bytes>,
+}
 
 /// Retry a read on EINTR
 ///
@@ -235,6 +356,31 @@ impl ConnectionError {
     }
 }
 
+/// Default limit on the length of the startup packet, as in postgres.
+pub const DEFAULT_MAX_STARTUP_MESSAGE_LEN: usize = 10000;
+/// Default limit on the length of a regular message. Large enough for any
+/// query or CopyData chunk we exchange.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Limits on the length of messages received from the client, including
+/// the length field itself. Longer messages are rejected with a protocol
+/// error before their body is read, so a client can't make us allocate
+/// arbitrary amounts of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageLimits {
+    pub max_startup_message_len: usize,
+    pub max_message_len: usize,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            max_startup_message_len: DEFAULT_MAX_STARTUP_MESSAGE_LEN,
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+        }
+    }
+}
+
 impl FeMessage {
     /// Read one message from the stream.
     /// This function returns `Ok(None)` in case of EOF.
@@ -260,7 +406,15 @@ impl FeMessage {
     pub fn read(
         stream: &mut (impl io::Read + Unpin),
     ) -> Result<Option<FeMessage>, ConnectionError> {
-        Self::read_fut(&mut AsyncishRead(stream)).wait()
+        Self::read_limited(stream, DEFAULT_MAX_MESSAGE_LEN)
+    }
+
+    /// Like `Self::read`, but rejects messages longer than `max_len` bytes.
+    pub fn read_limited(
+        stream: &mut (impl io::Read + Unpin),
+        max_len: usize,
+    ) -> Result<Option<FeMessage>, ConnectionError> {
+        Self::read_fut_limited(&mut AsyncishRead(stream), max_len).wait()
     }
 
     /// Read one message from the stream.
@@ -268,6 +422,17 @@ impl FeMessage {
     pub fn read_fut<Reader>(
         stream: &mut Reader,
     ) -> SyncFuture<Reader, impl Future<Output = Result<Option<FeMessage>, ConnectionError>> + '_>
+    where
+        Reader: tokio::io::AsyncRead + Unpin,
+    {
+        Self::read_fut_limited(stream, DEFAULT_MAX_MESSAGE_LEN)
+    }
+
+    /// Like `Self::read_fut`, but rejects messages longer than `max_len` bytes.
+    pub fn read_fut_limited<Reader>(
+        stream: &mut Reader,
+        max_len: usize,
+    ) -> SyncFuture<Reader, impl Future<Output = Result<Option<FeMessage>, ConnectionError>> + '_>
     where
         Reader: tokio::io::AsyncRead + Unpin,
     {
@@ -285,8 +450,13 @@ impl FeMessage {
             };
 
             // The message length includes itself, so it better be at least 4.
-            let len = retry_read!(stream.read_u32().await)
-                .map_err(ConnectionError::Socket)?
+            let len = retry_read!(stream.read_u32().await).map_err(ConnectionError::Socket)?;
+            if len as usize > max_len {
+                return Err(ConnectionError::Protocol(format!(
+                    "message length {len} exceeds the limit of {max_len} bytes"
+                )));
+            }
+            let len = len
                 .checked_sub(4)
                 .ok_or_else(|| ConnectionError::Protocol("invalid message length".to_string()))?;
 
@@ -307,10 +477,12 @@ impl FeMessage {
                 b'B' => Ok(Some(FeBindMessage::parse(body)?)),
                 b'C' => Ok(Some(FeCloseMessage::parse(body)?)),
                 b'S' => Ok(Some(FeMessage::Sync)),
+                b'H' => Ok(Some(FeMessage::Flush)),
                 b'X' => Ok(Some(FeMessage::Terminate)),
+                b'F' => Ok(Some(FeFunctionCallMessage::parse(body)?)),
                 b'd' => Ok(Some(FeMessage::CopyData(body))),
                 b'c' => Ok(Some(FeMessage::CopyDone)),
-                b'f' => Ok(Some(FeMessage::CopyFail)),
+                b'f' => Ok(Some(FeMessage::CopyFail(body))),
                 b'p' => Ok(Some(FeMessage::PasswordMessage(body))),
                 tag => {
                     return Err(ConnectionError::Protocol(format!(
@@ -329,7 +501,15 @@ impl FeStartupPacket {
     pub fn read(
         stream: &mut (impl io::Read + Unpin),
     ) -> Result<Option<FeMessage>, ConnectionError> {
-        Self::read_fut(&mut AsyncishRead(stream)).wait()
+        Self::read_limited(stream, DEFAULT_MAX_STARTUP_MESSAGE_LEN)
+    }
+
+    /// Like `Self::read`, but rejects packets longer than `max_len` bytes.
+    pub fn read_limited(
+        stream: &mut (impl io::Read + Unpin),
+        max_len: usize,
+    ) -> Result<Option<FeMessage>, ConnectionError> {
+        Self::read_fut_limited(&mut AsyncishRead(stream), max_len).wait()
     }
 
     /// Read startup message from the stream.
@@ -341,7 +521,17 @@ impl FeStartupPacket {
     where
         Reader: tokio::io::AsyncRead + Unpin,
     {
-        const MAX_STARTUP_PACKET_LENGTH: usize = 10000;
+        Self::read_fut_limited(stream, DEFAULT_MAX_STARTUP_MESSAGE_LEN)
+    }
+
+    /// Like `Self::read_fut`, but rejects packets longer than `max_len` bytes.
+    pub fn read_fut_limited<Reader>(
+        stream: &mut Reader,
+        max_len: usize,
+    ) -> SyncFuture<Reader, impl Future<Output = Result<Option<FeMessage>, ConnectionError>> + '_>
+    where
+        Reader: tokio::io::AsyncRead + Unpin,
+    {
         const RESERVED_INVALID_MAJOR_VERSION: u32 = 1234;
         const CANCEL_REQUEST_CODE: u32 = 5678;
         const NEGOTIATE_SSL_CODE: u32 = 5679;
@@ -358,8 +548,9 @@ impl FeStartupPacket {
                 Err(e) => return Err(ConnectionError::Socket(e)),
             };
 
+            // length and request code
             #[allow(clippy::manual_range_contains)]
-            if len < 4 || len > MAX_STARTUP_PACKET_LENGTH {
+            if len < 8 || len > max_len {
                 return Err(ConnectionError::Protocol(format!(
                     "invalid message length {len}"
                 )));
@@ -462,16 +653,15 @@ impl FeParseMessage {
 
 impl FeDescribeMessage {
     fn parse(mut buf: Bytes) -> anyhow::Result<FeMessage> {
+        ensure!(buf.has_remaining(), "missing Describe kind");
         let kind = buf.get_u8();
-        let _pstmt_name = read_cstr(&mut buf)?;
-
-        // FIXME: see FeParseMessage::parse
+        let name = read_cstr(&mut buf)?;
         ensure!(
-            kind == b'S',
-            "only prepared statemement Describe is implemented"
+            kind == b'S' || kind == b'P',
+            "invalid Describe kind {kind:?}"
         );
 
-        Ok(FeMessage::Describe(FeDescribeMessage { kind }))
+        Ok(FeMessage::Describe(FeDescribeMessage { kind, name }))
     }
 }
 
@@ -501,11 +691,54 @@ impl FeBindMessage {
 
 impl FeCloseMessage {
     fn parse(mut buf: Bytes) -> anyhow::Result<FeMessage> {
-        let _kind = buf.get_u8();
-        let _pstmt_or_portal_name = read_cstr(&mut buf)?;
+        ensure!(buf.has_remaining(), "missing Close kind");
+        let kind = buf.get_u8();
+        let name = read_cstr(&mut buf)?;
+        ensure!(kind == b'S' || kind == b'P', "invalid Close kind {kind:?}");
+
+        Ok(FeMessage::Close(FeCloseMessage { kind, name }))
+    }
+}
+
+impl FeFunctionCallMessage {
+    fn parse(mut buf: Bytes) -> anyhow::Result<FeMessage> {
+        ensure!(buf.remaining() >= 4, "missing function OID");
+        let function_oid = buf.get_u32();
 
-        // FIXME: we do nothing with Close
-        Ok(FeMessage::Close(FeCloseMessage))
+        Ok(FeMessage::FunctionCall(FeFunctionCallMessage {
+            function_oid,
+            args: buf,
+        }))
+    }
+}
+
+/// Parse the body of PasswordMessage sent in response to
+/// AuthenticationCleartextPassword or AuthenticationMD5Password: the
+/// password or its md5 hash as a null-terminated string.
+pub fn parse_password_message(buf: &[u8]) -> anyhow::Result<&str> {
+    let password = buf
+        .strip_suffix(&[0])
+        .context("missing password terminator")?;
+    ensure!(!password.contains(&0), "password contains null byte");
+    str::from_utf8(password).context("password is not UTF-8")
+}
+
+impl FeSaslInitialResponse {
+    /// Parse the body of PasswordMessage.
+    pub fn parse(mut buf: Bytes) -> anyhow::Result<Self> {
+        let mechanism = read_cstr(&mut buf)?;
+        ensure!(buf.remaining() >= 4, "missing SASL initial response length");
+        let len = buf.get_i32();
+        let data = if len == -1 {
+            None
+        } else {
+            ensure!(
+                len >= 0 && len as usize == buf.remaining(),
+                "invalid SASL initial response length {len}"
+            );
+            Some(buf)
+        };
+        Ok(FeSaslInitialResponse { mechanism, data })
     }
 }
 
@@ -529,19 +762,32 @@ pub enum BeMessage<'a> {
     CloseComplete,
     // None means column is NULL
     DataRow(&'a [Option<&'a [u8]>]),
+    /// DataRow with cells queued without copying by [`WriteQueue`].
+    DataRowBytes(&'a [Option<Bytes>]),
     ErrorResponse(&'a str, Option<&'a [u8; 5]>),
+    /// ErrorResponse or NoticeResponse, depending on the severity, with all
+    /// the fields set in the error.
+    PgError(&'a PgError),
     /// Single byte - used in response to SSLRequest/GSSENCRequest.
     EncryptionResponse(bool),
     NoData,
+    /// Asynchronous notification, as for LISTEN.
+    NotificationResponse {
+        process_id: i32,
+        channel: &'a str,
+        payload: &'a str,
+    },
     ParameterDescription,
     ParameterStatus {
         name: &'a [u8],
         value: &'a [u8],
     },
     ParseComplete,
-    ReadyForQuery,
+    ReadyForQuery(TransactionStatus),
     RowDescription(&'a [RowDescriptor<'a>]),
     XLogData(XLogDataBody<'a>),
+    /// XLogData with the WAL queued without copying by [`WriteQueue`].
+    XLogDataBytes(XLogDataBytes),
     NoticeResponse(&'a str),
     KeepAlive(WalSndKeepAlive),
 }
@@ -559,11 +805,56 @@ impl<'a> BeMessage<'a> {
         value: b"UTF8",
     };
 
+    /// ReadyForQuery outside of a transaction block.
+    pub const READY_FOR_QUERY: Self = Self::ReadyForQuery(TransactionStatus::Idle);
+
     /// Build a [`BeMessage::ParameterStatus`] holding the server version.
     pub fn server_version(version: &'a str) -> Self {
+        Self::parameter_status("server_version", version)
+    }
+
+    /// Build a [`BeMessage::ParameterStatus`] reporting a run-time parameter.
+    pub fn parameter_status(name: &'a str, value: &'a str) -> Self {
         Self::ParameterStatus {
-            name: b"server_version",
-            value: version.as_bytes(),
+            name: name.as_bytes(),
+            value: value.as_bytes(),
+        }
+    }
+}
+
+/// Server version reported to clients by default. The async python driver
+/// requires a valid one.
+pub const DEFAULT_SERVER_VERSION: &str = "14.1";
+
+/// Run-time parameters which drivers expect to be reported in
+/// ParameterStatus after authentication, as real Postgres does, with the
+/// values we report by default.
+pub const STANDARD_PARAMETERS: [(&str, &str); 5] = [
+    ("server_version", DEFAULT_SERVER_VERSION),
+    ("client_encoding", "UTF8"),
+    ("DateStyle", "ISO, MDY"),
+    ("integer_datetimes", "on"),
+    ("standard_conforming_strings", "on"),
+];
+
+/// Transaction status reported in ReadyForQuery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TransactionStatus {
+    /// Not in a transaction block.
+    #[default]
+    Idle,
+    /// In a transaction block.
+    InTransaction,
+    /// In a failed transaction block, queries are rejected until it ends.
+    Failed,
+}
+
+impl TransactionStatus {
+    pub fn as_byte(self) -> u8 {
+        match self {
+            TransactionStatus::Idle => b'I',
+            TransactionStatus::InTransaction => b'T',
+            TransactionStatus::Failed => b'E',
         }
     }
 }
@@ -607,33 +898,153 @@ impl Default for RowDescriptor<'_> {
     }
 }
 
-impl RowDescriptor<'_> {
+impl<'a> RowDescriptor<'a> {
+    /// Start describing a text column; the type and other attributes can be
+    /// changed with the builder.
+    pub const fn builder(name: &'a [u8]) -> RowDescriptorBuilder<'a> {
+        RowDescriptorBuilder::new(name)
+    }
+
     /// Convenience function to create a RowDescriptor message for an int8 column
-    pub const fn int8_col(name: &[u8]) -> RowDescriptor {
+    pub const fn int8_col(name: &'a [u8]) -> Self {
+        Self::builder(name).typ(PgType::Int8).build()
+    }
+
+    pub const fn text_col(name: &'a [u8]) -> Self {
+        Self::builder(name).build()
+    }
+
+    pub const fn int2_col(name: &'a [u8]) -> Self {
+        Self::builder(name).typ(PgType::Int2).build()
+    }
+
+    pub const fn int4_col(name: &'a [u8]) -> Self {
+        Self::builder(name).typ(PgType::Int4).build()
+    }
+
+    pub const fn bytea_col(name: &'a [u8]) -> Self {
+        Self::builder(name).typ(PgType::Bytea).build()
+    }
+
+    pub const fn timestamptz_col(name: &'a [u8]) -> Self {
+        Self::builder(name).typ(PgType::TimestampTz).build()
+    }
+
+    /// LSNs are sent as text, see [`cell::Cell::Lsn`].
+    pub const fn lsn_col(name: &'a [u8]) -> Self {
+        Self::text_col(name)
+    }
+
+    /// Set the format the column values are sent in.
+    pub const fn with_format(self, format: cell::FormatCode) -> Self {
         RowDescriptor {
-            name,
-            tableoid: 0,
-            attnum: 0,
-            typoid: INT8_OID,
-            typlen: 8,
-            typmod: 0,
-            formatcode: 0,
+            formatcode: format.code(),
+            ..self
         }
     }
+}
 
-    pub const fn text_col(name: &[u8]) -> RowDescriptor {
-        RowDescriptor {
-            name,
-            tableoid: 0,
-            attnum: 0,
-            typoid: TEXT_OID,
-            typlen: -1,
-            typmod: 0,
-            formatcode: 0,
+/// Commonly used column types, with their OIDs and lengths as in pg_type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PgType {
+    Bool,
+    Bytea,
+    Int8,
+    Int2,
+    Int4,
+    Text,
+    Oid,
+    Json,
+    Float8,
+    Varchar,
+    TimestampTz,
+    PgLsn,
+    Jsonb,
+}
+
+impl PgType {
+    pub const fn oid(self) -> Oid {
+        match self {
+            PgType::Bool => 16,
+            PgType::Bytea => BYTEA_OID,
+            PgType::Int8 => INT8_OID,
+            PgType::Int2 => INT2_OID,
+            PgType::Int4 => INT4_OID,
+            PgType::Text => TEXT_OID,
+            PgType::Oid => 26,
+            PgType::Json => 114,
+            PgType::Float8 => 701,
+            PgType::Varchar => 1043,
+            PgType::TimestampTz => TIMESTAMPTZ_OID,
+            PgType::PgLsn => 3220,
+            PgType::Jsonb => 3802,
+        }
+    }
+
+    /// Size of the type in bytes, -1 for variable length types.
+    pub const fn typlen(self) -> i16 {
+        match self {
+            PgType::Bool => 1,
+            PgType::Int2 => 2,
+            PgType::Int4 | PgType::Oid => 4,
+            PgType::Int8 | PgType::Float8 | PgType::TimestampTz | PgType::PgLsn => 8,
+            PgType::Bytea | PgType::Text | PgType::Json | PgType::Varchar | PgType::Jsonb => -1,
         }
     }
 }
 
+/// Builder of [`RowDescriptor`], see [`RowDescriptor::builder`]. Columns
+/// are text, not from a table, without type modifier and sent in text
+/// format unless set otherwise.
+#[derive(Debug)]
+pub struct RowDescriptorBuilder<'a> {
+    desc: RowDescriptor<'a>,
+}
+
+impl<'a> RowDescriptorBuilder<'a> {
+    pub const fn new(name: &'a [u8]) -> Self {
+        RowDescriptorBuilder {
+            desc: RowDescriptor {
+                name,
+                tableoid: 0,
+                attnum: 0,
+                typoid: TEXT_OID,
+                typlen: -1,
+                typmod: -1,
+                formatcode: 0,
+            },
+        }
+    }
+
+    /// Set the type, along with its length.
+    pub const fn typ(mut self, typ: PgType) -> Self {
+        self.desc.typoid = typ.oid();
+        self.desc.typlen = typ.typlen();
+        self
+    }
+
+    pub const fn typmod(mut self, typmod: i32) -> Self {
+        self.desc.typmod = typmod;
+        self
+    }
+
+    /// Set the table and column number the column comes from.
+    pub const fn table(mut self, tableoid: Oid, attnum: i16) -> Self {
+        self.desc.tableoid = tableoid;
+        self.desc.attnum = attnum;
+        self
+    }
+
+    pub const fn format(mut self, format: cell::FormatCode) -> Self {
+        self.desc.formatcode = format.code();
+        self
+    }
+
+    pub const fn build(self) -> RowDescriptor<'a> {
+        self.desc
+    }
+}
+
 #[derive(Debug)]
 pub struct XLogDataBody<'a> {
     pub wal_start: u64,
@@ -642,6 +1053,14 @@ pub struct XLogDataBody<'a> {
     pub data: &'a [u8],
 }
 
+#[derive(Debug)]
+pub struct XLogDataBytes {
+    pub wal_start: u64,
+    pub wal_end: u64,
+    pub timestamp: i64,
+    pub data: Bytes,
+}
+
 #[derive(Debug)]
 pub struct WalSndKeepAlive {
     pub sent_ptr: u64,
@@ -652,15 +1071,124 @@ pub struct WalSndKeepAlive {
 pub static HELLO_WORLD_ROW: BeMessage = BeMessage::DataRow(&[Some(b"hello world")]);
 
 // single text column
-pub static SINGLE_COL_ROWDESC: BeMessage = BeMessage::RowDescription(&[RowDescriptor {
-    name: b"data",
-    tableoid: 0,
-    attnum: 0,
-    typoid: TEXT_OID,
-    typlen: -1,
-    typmod: 0,
-    formatcode: 0,
-}]);
+pub static SINGLE_COL_ROWDESC: BeMessage =
+    BeMessage::RowDescription(&[RowDescriptor::text_col(b"data")]);
+
+/// Payloads shorter than this are copied into the output buffer anyway, as
+/// queueing them separately would cost more than the copy.
+pub const ZERO_COPY_THRESHOLD: usize = 4 * 1024;
+
+/// Serialized messages waiting to be sent. Messages are serialized into a
+/// buffer, except large `Bytes` payloads of XLogDataBytes and DataRowBytes,
+/// which are queued by reference between the buffer chunks. The queue is
+/// drained through the [`Buf`] interface.
+#[derive(Debug, Default)]
+pub struct WriteQueue {
+    /// Chunks ready to be sent, before `buf`.
+    chunks: VecDeque<Bytes>,
+    buf: BytesMut,
+}
+
+impl WriteQueue {
+    pub fn with_capacity(capacity: usize) -> Self {
+        WriteQueue {
+            chunks: VecDeque::new(),
+            buf: BytesMut::with_capacity(capacity),
+        }
+    }
+
+    /// Buffer the messages are serialized into.
+    pub fn buffer_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+
+    /// Capacity of the buffer, not counting payloads queued by reference.
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.has_remaining()
+    }
+
+    /// Serialize the message, see [`BeMessage::write`].
+    pub fn write_message(&mut self, message: &BeMessage) -> io::Result<()> {
+        match message {
+            BeMessage::XLogDataBytes(body) => {
+                self.buf.put_u8(b'd');
+                self.put_len(1 + 8 + 8 + 8 + body.data.len())?;
+                self.buf.put_u8(b'w');
+                self.buf.put_u64(body.wal_start);
+                self.buf.put_u64(body.wal_end);
+                self.buf.put_i64(body.timestamp);
+                self.put_bytes(&body.data);
+            }
+            BeMessage::DataRowBytes(vals) => {
+                let body_len = 2 + vals
+                    .iter()
+                    .map(|val| 4 + val.as_ref().map_or(0, |val| val.len()))
+                    .sum::<usize>();
+                self.buf.put_u8(b'D');
+                self.put_len(body_len)?;
+                self.buf.put_u16(vals.len() as u16); // num of cols
+                for val_opt in vals.iter() {
+                    if let Some(val) = val_opt {
+                        self.buf.put_u32(val.len() as u32);
+                        self.put_bytes(val);
+                    } else {
+                        self.buf.put_i32(-1);
+                    }
+                }
+            }
+            message => BeMessage::write(&mut self.buf, message)?,
+        }
+        Ok(())
+    }
+
+    /// Write length of the message with body of `body_len` bytes.
+    fn put_len(&mut self, body_len: usize) -> io::Result<()> {
+        let len = i32::try_from(4 + body_len)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too big"))?;
+        self.buf.put_i32(len);
+        Ok(())
+    }
+
+    fn put_bytes(&mut self, bytes: &Bytes) {
+        if bytes.len() < ZERO_COPY_THRESHOLD {
+            self.buf.put_slice(bytes);
+        } else {
+            if !self.buf.is_empty() {
+                self.chunks.push_back(self.buf.split().freeze());
+            }
+            self.chunks.push_back(bytes.clone());
+        }
+    }
+}
+
+impl Buf for WriteQueue {
+    fn remaining(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.len()).sum::<usize>() + self.buf.len()
+    }
+
+    fn chunk(&self) -> &[u8] {
+        match self.chunks.front() {
+            Some(chunk) => chunk,
+            None => &self.buf,
+        }
+    }
+
+    fn advance(&mut self, mut cnt: usize) {
+        while let Some(chunk) = self.chunks.front_mut() {
+            if cnt < chunk.len() {
+                chunk.advance(cnt);
+                return;
+            }
+            cnt -= chunk.len();
+            self.chunks.pop_front();
+        }
+        self.buf.advance(cnt);
+    }
+}
 
 /// Call f() to write body of the message and prepend it with 4-byte len as
 /// prescribed by the protocol.
@@ -834,6 +1362,21 @@ impl<'a> BeMessage<'a> {
                 });
             }
 
+            BeMessage::DataRowBytes(vals) => {
+                buf.put_u8(b'D');
+                write_body(buf, |buf| {
+                    buf.put_u16(vals.len() as u16); // num of cols
+                    for val_opt in vals.iter() {
+                        if let Some(val) = val_opt {
+                            buf.put_u32(val.len() as u32);
+                            buf.put_slice(val);
+                        } else {
+                            buf.put_i32(-1);
+                        }
+                    }
+                });
+            }
+
             // ErrorResponse is a zero-terminated array of zero-terminated fields.
             // First byte of each field represents type of this field. Set just enough fields
             // to satisfy rust-postgres client: 'S' -- severity, 'C' -- error, 'M' -- error
@@ -858,27 +1401,23 @@ impl<'a> BeMessage<'a> {
                 })?;
             }
 
+            BeMessage::PgError(error) => {
+                buf.put_u8(if error.severity.is_error() {
+                    b'E'
+                } else {
+                    b'N'
+                });
+                write_body(buf, |buf| error.write_fields(buf))?;
+            }
+
             // NoticeResponse has the same format as ErrorResponse. From doc: "The frontend should display the
             // message but continue listening for ReadyForQuery or ErrorResponse"
-            BeMessage::NoticeResponse(error_msg) => {
-                // For all the errors set Severity to Error and error code to
-                // 'internal error'.
+            BeMessage::NoticeResponse(message) => {
+                let notice = PgError::notice(*message).build();
 
                 // 'N' signalizes NoticeResponse messages
                 buf.put_u8(b'N');
-                write_body(buf, |buf| {
-                    buf.put_u8(b'S'); // severity
-                    buf.put_slice(b"NOTICE\0");
-
-                    buf.put_u8(b'C'); // SQLSTATE error code
-                    buf.put_slice(&terminate_code(SQLSTATE_INTERNAL_ERROR));
-
-                    buf.put_u8(b'M'); // the message
-                    write_cstr(error_msg.as_bytes(), buf)?;
-
-                    buf.put_u8(0); // terminator
-                    Ok::<_, io::Error>(())
-                })?;
+                write_body(buf, |buf| notice.write_fields(buf))?;
             }
 
             BeMessage::NoData => {
@@ -907,15 +1446,28 @@ impl<'a> BeMessage<'a> {
                 });
             }
 
+            BeMessage::NotificationResponse {
+                process_id,
+                channel,
+                payload,
+            } => {
+                buf.put_u8(b'A');
+                write_body(buf, |buf| {
+                    buf.put_i32(*process_id);
+                    write_cstr(channel, buf)?;
+                    write_cstr(payload, buf)
+                })?;
+            }
+
             BeMessage::ParseComplete => {
                 buf.put_u8(b'1');
                 write_body(buf, |_| {});
             }
 
-            BeMessage::ReadyForQuery => {
+            BeMessage::ReadyForQuery(status) => {
                 buf.put_u8(b'Z');
                 write_body(buf, |buf| {
-                    buf.put_u8(b'I');
+                    buf.put_u8(status.as_byte());
                 });
             }
 
@@ -947,6 +1499,17 @@ impl<'a> BeMessage<'a> {
                 });
             }
 
+            BeMessage::XLogDataBytes(body) => {
+                buf.put_u8(b'd');
+                write_body(buf, |buf| {
+                    buf.put_u8(b'w');
+                    buf.put_u64(body.wal_start);
+                    buf.put_u64(body.wal_end);
+                    buf.put_i64(body.timestamp);
+                    buf.put_slice(&body.data);
+                });
+            }
+
             BeMessage::KeepAlive(req) => {
                 buf.put_u8(b'd');
                 write_body(buf, |buf| {
@@ -1083,6 +1646,26 @@ impl ReplicationFeedback {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_row_descriptor_builder() {
+        let desc = RowDescriptor::builder(b"lsn")
+            .typ(PgType::PgLsn)
+            .table(1259, 3)
+            .format(cell::FormatCode::Binary)
+            .build();
+        assert_eq!(desc.typoid, 3220);
+        assert_eq!(desc.typlen, 8);
+        assert_eq!(desc.typmod, -1);
+        assert_eq!((desc.tableoid, desc.attnum), (1259, 3));
+        assert_eq!(desc.formatcode, 1);
+
+        let desc = RowDescriptor::text_col(b"name");
+        assert_eq!(
+            (desc.typoid, desc.typlen, desc.formatcode),
+            (TEXT_OID, -1, 0)
+        );
+    }
+
     #[test]
     fn test_replication_feedback_serialization() {
         let mut rf = ReplicationFeedback::empty();
@@ -1150,6 +1733,218 @@ mod tests {
         assert_eq!(split_options(&params), ["foo bar", " \\", "baz ", "lol"]);
     }
 
+    #[test]
+    fn test_startup_message_params_replication() {
+        let replication = |value| {
+            StartupMessageParams::new([("replication", value)])
+                .replication()
+                .ok()
+        };
+
+        assert_eq!(
+            StartupMessageParams::new([]).replication().unwrap(),
+            ReplicationMode::Off
+        );
+        for value in ["true", "on", "yes", "1", "t", "TRUE", "y"] {
+            assert_eq!(
+                replication(value),
+                Some(ReplicationMode::Physical),
+                "{value}"
+            );
+        }
+        for value in ["false", "off", "no", "0", "f", "Of"] {
+            assert_eq!(replication(value), Some(ReplicationMode::Off), "{value}");
+        }
+        assert_eq!(replication("database"), Some(ReplicationMode::Database));
+        for value in ["", "o", "2", "truee", "databases", "logical"] {
+            assert_eq!(replication(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn test_startup_packet_user() {
+        let startup = |params| FeStartupPacket::StartupMessage {
+            major_version: 3,
+            minor_version: 0,
+            params,
+        };
+        let packet = startup(StartupMessageParams::new([("user", "neon")]));
+        assert_eq!(packet.user().unwrap(), "neon");
+        assert!(startup(StartupMessageParams::new([])).user().is_err());
+        assert!(FeStartupPacket::SslRequest.user().is_err());
+    }
+
+    #[test]
+    fn test_copy_messages() {
+        let mut buf = BytesMut::new();
+        BeMessage::write(&mut buf, &BeMessage::CopyData(b"chunk")).unwrap();
+        BeMessage::write(&mut buf, &BeMessage::CopyDone).unwrap();
+        buf.put_u8(b'f');
+        buf.put_u32(4 + 5);
+        buf.put_slice(b"oops\0");
+
+        let mut stream = &buf[..];
+        match FeMessage::read(&mut stream).unwrap() {
+            Some(FeMessage::CopyData(data)) => assert_eq!(&data[..], b"chunk"),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert!(matches!(
+            FeMessage::read(&mut stream).unwrap(),
+            Some(FeMessage::CopyDone)
+        ));
+        match FeMessage::read(&mut stream).unwrap() {
+            Some(FeMessage::CopyFail(msg)) => assert_eq!(&msg[..], b"oops\0"),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+        assert!(FeMessage::read(&mut stream).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_notification_response() {
+        let mut buf = BytesMut::new();
+        let notification = BeMessage::NotificationResponse {
+            process_id: 7,
+            channel: "wal_backup",
+            payload: "lagging",
+        };
+        BeMessage::write(&mut buf, &notification).unwrap();
+        assert_eq!(&buf[..], b"A\0\0\0\x1b\0\0\0\x07wal_backup\0lagging\0");
+    }
+
+    #[test]
+    fn test_write_queue() {
+        let wal = Bytes::from(vec![7u8; 2 * ZERO_COPY_THRESHOLD]);
+        let cell = Bytes::from_static(b"small");
+        let messages = [
+            BeMessage::XLogDataBytes(XLogDataBytes {
+                wal_start: 1,
+                wal_end: 2,
+                timestamp: 3,
+                data: wal.clone(),
+            }),
+            BeMessage::CopyDone,
+            BeMessage::DataRowBytes(&[Some(wal.clone()), None, Some(cell.clone())]),
+        ];
+        let expected = [
+            BeMessage::XLogData(XLogDataBody {
+                wal_start: 1,
+                wal_end: 2,
+                timestamp: 3,
+                data: &wal,
+            }),
+            BeMessage::CopyDone,
+            BeMessage::DataRow(&[Some(&wal), None, Some(&cell)]),
+        ];
+
+        let mut queue = WriteQueue::default();
+        let mut expected_buf = BytesMut::new();
+        for (message, expected) in messages.iter().zip(expected.iter()) {
+            queue.write_message(message).unwrap();
+            BeMessage::write(&mut expected_buf, expected).unwrap();
+        }
+        assert_eq!(queue.remaining(), expected_buf.len());
+        // large payloads are queued as separate chunks
+        assert!(queue.chunk().len() < wal.len());
+
+        let mut sent = Vec::new();
+        while queue.has_remaining() {
+            // drain in pieces smaller than the chunks
+            let n = queue.chunk().len().min(1000);
+            sent.extend_from_slice(&queue.chunk()[..n]);
+            queue.advance(n);
+        }
+        assert_eq!(sent, expected_buf);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_message_length_limit() {
+        // length of the huge message is read, but its body is never allocated
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'Q');
+        buf.put_u32(u32::MAX);
+        let mut stream = &buf[..];
+        assert!(matches!(
+            FeMessage::read(&mut stream),
+            Err(ConnectionError::Protocol(_))
+        ));
+
+        let mut buf = BytesMut::new();
+        BeMessage::write(&mut buf, &BeMessage::CopyData(b"chunk")).unwrap();
+        let mut stream = &buf[..];
+        // the limit includes the length word: 4 + 5 bytes of payload
+        assert!(matches!(
+            FeMessage::read_limited(&mut stream, 8),
+            Err(ConnectionError::Protocol(_))
+        ));
+        let mut stream = &buf[..];
+        assert!(FeMessage::read_limited(&mut stream, 9).unwrap().is_some());
+
+        // startup packet shorter than its fixed part
+        let mut stream = &[0u8, 0, 0, 5, 0][..];
+        assert!(matches!(
+            FeStartupPacket::read(&mut stream),
+            Err(ConnectionError::Protocol(_))
+        ));
+        let mut buf = BytesMut::new();
+        buf.put_u32(DEFAULT_MAX_STARTUP_MESSAGE_LEN as u32 + 1);
+        let mut stream = &buf[..];
+        assert!(matches!(
+            FeStartupPacket::read(&mut stream),
+            Err(ConnectionError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_extended_query_messages() {
+        fn parse(msg: &[u8]) -> FeMessage {
+            FeMessage::read(&mut &msg[..]).unwrap().unwrap()
+        }
+
+        let msg = parse(b"D\0\0\0\x0aPport\0");
+        assert!(matches!(msg, FeMessage::Describe(m) if m.kind == b'P' && &m.name[..] == b"port"));
+        let msg = parse(b"C\0\0\0\x06S\0");
+        assert!(matches!(msg, FeMessage::Close(m) if m.kind == b'S' && m.name.is_empty()));
+        assert!(matches!(parse(b"H\0\0\0\x04"), FeMessage::Flush));
+        let msg = parse(b"F\0\0\0\x0e\0\0\x04\xd2\0\0\0\0\0\0");
+        assert!(
+            matches!(msg, FeMessage::FunctionCall(m) if m.function_oid == 1234 && m.args.len() == 6)
+        );
+
+        assert!(FeMessage::read(&mut &b"D\0\0\0\x06X\0"[..]).is_err());
+        assert!(FeMessage::read(&mut &b"C\0\0\0\x04"[..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_password_message() {
+        assert_eq!(parse_password_message(b"secret\0").unwrap(), "secret");
+        assert!(parse_password_message(b"secret").is_err());
+        assert!(parse_password_message(b"sec\0ret\0").is_err());
+    }
+
+    #[test]
+    fn test_sasl_initial_response() {
+        let mut buf = BytesMut::new();
+        buf.put_slice(b"SCRAM-SHA-256\0");
+        buf.put_i32(5);
+        buf.put_slice(b"n,,n=");
+        let msg = FeSaslInitialResponse::parse(buf.freeze()).unwrap();
+        assert_eq!(&msg.mechanism[..], b"SCRAM-SHA-256");
+        assert_eq!(msg.data.as_deref(), Some(&b"n,,n="[..]));
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(b"SCRAM-SHA-256\0");
+        buf.put_i32(-1);
+        let msg = FeSaslInitialResponse::parse(buf.freeze()).unwrap();
+        assert!(msg.data.is_none());
+
+        let mut buf = BytesMut::new();
+        buf.put_slice(b"SCRAM-SHA-256\0");
+        buf.put_i32(10);
+        buf.put_slice(b"short");
+        assert!(FeSaslInitialResponse::parse(buf.freeze()).is_err());
+    }
+
     // Make sure that `read` is sync/async callable
     async fn _assert(stream: &mut (impl tokio::io::AsyncRead + Unpin)) {
         let _ = FeMessage::read(&mut [].as_ref());
@@ -0,0 +1,156 @@
+//! Parsing of the `options` startup parameter into key-value pairs.
+//!
+//! libpq passes command-line style options: whitespace separated, with `\ `
+//! and `\\` escapes, see `pg_split_opts`. Each option is either `key=value`,
+//! `-c key=value`, `-ckey=value` or `--key=value`, and the same key may be
+//! repeated, the last value winning as in Postgres.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+use crate::StartupMessageParams;
+
+/// Key of the tenant id, followed by its legacy alias.
+pub const TENANT_ID_KEYS: &[&str] = &["tenant_id", "ztenantid"];
+/// Key of the timeline id, followed by its legacy alias.
+pub const TIMELINE_ID_KEYS: &[&str] = &["timeline_id", "ztimelineid"];
+
+/// Options in the order they were given, keys possibly repeated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StartupOptions {
+    options: Vec<(String, String)>,
+}
+
+impl StartupOptions {
+    pub fn parse(input: &str) -> Self {
+        let mut options = Vec::new();
+        let mut tokens = StartupMessageParams::parse_options_escaped(input);
+        while let Some(token) = tokens.next() {
+            let option = match token.as_ref() {
+                // the value is the next token
+                "-c" => match tokens.next() {
+                    Some(next) => next.into_owned(),
+                    None => break,
+                },
+                token => match token.strip_prefix("--").or(token.strip_prefix("-c")) {
+                    Some(option) => option.to_owned(),
+                    None => token.to_owned(),
+                },
+            };
+            // other command-line switches carry nothing for us
+            if let Some((key, value)) = option.split_once('=') {
+                options.push((key.to_owned(), value.to_owned()));
+            }
+        }
+        StartupOptions { options }
+    }
+
+    /// Last value of the key.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// All values of the key, in the order they were given.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.options
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Last value of the first of `keys` present, to support legacy aliases.
+    pub fn get_any(&self, keys: &[&str]) -> Option<&str> {
+        keys.iter().find_map(|key| self.get(key))
+    }
+
+    /// Parsed last value of the key.
+    pub fn get_parsed<T>(&self, key: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        parse_value(key, self.get(key))
+    }
+
+    /// Tenant id, given as `tenant_id` or legacy `ztenantid`.
+    pub fn tenant_id<T>(&self) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        parse_value("tenant id", self.get_any(TENANT_ID_KEYS))
+    }
+
+    /// Timeline id, given as `timeline_id` or legacy `ztimelineid`.
+    pub fn timeline_id<T>(&self) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        parse_value("timeline id", self.get_any(TIMELINE_ID_KEYS))
+    }
+
+    /// Iterate through key-value pairs in the order they were given.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.options.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+fn parse_value<T>(what: &str, value: Option<&str>) -> anyhow::Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| anyhow::anyhow!("{e}"))
+                .with_context(|| format!("Failed to parse {value} as {what}"))
+        })
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_startup_options() {
+        let options = StartupOptions::parse(
+            "-c tenant_id=a -ctimeline_id=b --app\\ name=x\\ y\\\\z recovery_term=1 -v recovery_term=2",
+        );
+        assert_eq!(
+            options.iter().collect::<Vec<_>>(),
+            [
+                ("tenant_id", "a"),
+                ("timeline_id", "b"),
+                ("app name", "x y\\z"),
+                ("recovery_term", "1"),
+                ("recovery_term", "2"),
+            ]
+        );
+        assert_eq!(options.get("recovery_term"), Some("2"));
+        assert_eq!(
+            options.get_all("recovery_term").collect::<Vec<_>>(),
+            ["1", "2"]
+        );
+        assert_eq!(options.get_parsed::<u64>("recovery_term").unwrap(), Some(2));
+        assert!(options.get_parsed::<u64>("app name").is_err());
+        assert_eq!(options.get_parsed::<u64>("missing").unwrap(), None);
+        assert_eq!(options.tenant_id::<String>().unwrap().as_deref(), Some("a"));
+
+        let options = StartupOptions::parse("ztimelineid=c");
+        assert_eq!(
+            options.timeline_id::<String>().unwrap().as_deref(),
+            Some("c")
+        );
+        assert_eq!(options.tenant_id::<String>().unwrap(), None);
+    }
+}
@@ -0,0 +1,255 @@
+//! Structured errors and notices, sent to the client in ErrorResponse and
+//! NoticeResponse. See
+//! <https://www.postgresql.org/docs/devel/protocol-error-fields.html>.
+
+use std::fmt;
+
+use bytes::{BufMut, BytesMut};
+
+/// Severity of an error or notice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Fatal,
+    Panic,
+    Warning,
+    Notice,
+    Debug,
+    Info,
+    Log,
+}
+
+impl Severity {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Severity::Error => "ERROR",
+            Severity::Fatal => "FATAL",
+            Severity::Panic => "PANIC",
+            Severity::Warning => "WARNING",
+            Severity::Notice => "NOTICE",
+            Severity::Debug => "DEBUG",
+            Severity::Info => "INFO",
+            Severity::Log => "LOG",
+        }
+    }
+
+    /// Whether it is sent in ErrorResponse rather than NoticeResponse.
+    pub const fn is_error(self) -> bool {
+        matches!(self, Severity::Error | Severity::Fatal | Severity::Panic)
+    }
+}
+
+/// Commonly used SQLSTATE codes, see
+/// <https://www.postgresql.org/docs/devel/errcodes-appendix.html>.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlState {
+    SuccessfulCompletion,
+    Warning,
+    ConnectionFailure,
+    ProtocolViolation,
+    FeatureNotSupported,
+    InvalidParameterValue,
+    InvalidAuthorizationSpecification,
+    InvalidPassword,
+    SyntaxError,
+    InsufficientPrivilege,
+    UndefinedObject,
+    DuplicateObject,
+    TooManyConnections,
+    ObjectNotInPrerequisiteState,
+    QueryCanceled,
+    AdminShutdown,
+    CannotConnectNow,
+    InternalError,
+    DataCorrupted,
+}
+
+impl SqlState {
+    pub const fn code(self) -> &'static [u8; 5] {
+        match self {
+            SqlState::SuccessfulCompletion => b"00000",
+            SqlState::Warning => b"01000",
+            SqlState::ConnectionFailure => b"08006",
+            SqlState::ProtocolViolation => b"08P01",
+            SqlState::FeatureNotSupported => b"0A000",
+            SqlState::InvalidParameterValue => b"22023",
+            SqlState::InvalidAuthorizationSpecification => b"28000",
+            SqlState::InvalidPassword => b"28P01",
+            SqlState::SyntaxError => b"42601",
+            SqlState::InsufficientPrivilege => b"42501",
+            SqlState::UndefinedObject => b"42704",
+            SqlState::DuplicateObject => b"42710",
+            SqlState::TooManyConnections => b"53300",
+            SqlState::ObjectNotInPrerequisiteState => b"55000",
+            SqlState::QueryCanceled => b"57014",
+            SqlState::AdminShutdown => b"57P01",
+            SqlState::CannotConnectNow => b"57P03",
+            SqlState::InternalError => b"XX000",
+            SqlState::DataCorrupted => b"XX001",
+        }
+    }
+}
+
+/// Error or notice with the fields sent to the client. Implements
+/// [`std::error::Error`], so handlers can return it inside their errors to
+/// choose what the client gets, see [`PgErrorBuilder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PgError {
+    pub severity: Severity,
+    pub code: [u8; 5],
+    pub message: String,
+    pub detail: Option<String>,
+    pub hint: Option<String>,
+    /// Position in the query string the error refers to, counting
+    /// characters from 1.
+    pub position: Option<u32>,
+}
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)?;
+        if let Some(detail) = &self.detail {
+            write!(f, ": {detail}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for PgError {}
+
+impl PgError {
+    pub fn builder(code: SqlState, message: impl Into<String>) -> PgErrorBuilder {
+        PgErrorBuilder::new(code, message)
+    }
+
+    /// Builder of a WARNING notice, for non-fatal conditions the client
+    /// should know about.
+    pub fn warning(message: impl Into<String>) -> PgErrorBuilder {
+        PgErrorBuilder::new(SqlState::Warning, message).severity(Severity::Warning)
+    }
+
+    /// Builder of a NOTICE, for informational messages.
+    pub fn notice(message: impl Into<String>) -> PgErrorBuilder {
+        PgErrorBuilder::new(SqlState::SuccessfulCompletion, message).severity(Severity::Notice)
+    }
+
+    /// Write the fields of ErrorResponse or NoticeResponse.
+    pub(crate) fn write_fields(&self, buf: &mut BytesMut) -> std::io::Result<()> {
+        let severity = self.severity.as_str();
+        buf.put_u8(b'S'); // severity, possibly localized
+        crate::write_cstr(severity, buf)?;
+        buf.put_u8(b'V'); // severity, never localized
+        crate::write_cstr(severity, buf)?;
+
+        buf.put_u8(b'C'); // SQLSTATE error code
+        crate::write_cstr(self.code, buf)?;
+
+        buf.put_u8(b'M'); // the message
+        crate::write_cstr(&self.message, buf)?;
+
+        if let Some(detail) = &self.detail {
+            buf.put_u8(b'D');
+            crate::write_cstr(detail, buf)?;
+        }
+        if let Some(hint) = &self.hint {
+            buf.put_u8(b'H');
+            crate::write_cstr(hint, buf)?;
+        }
+        if let Some(position) = self.position {
+            buf.put_u8(b'P');
+            crate::write_cstr(position.to_string(), buf)?;
+        }
+
+        buf.put_u8(0); // terminator
+        Ok(())
+    }
+}
+
+/// Builder of [`PgError`]. Errors have ERROR severity unless set otherwise.
+#[derive(Debug)]
+pub struct PgErrorBuilder {
+    error: PgError,
+}
+
+impl PgErrorBuilder {
+    pub fn new(code: SqlState, message: impl Into<String>) -> Self {
+        PgErrorBuilder {
+            error: PgError {
+                severity: Severity::Error,
+                code: *code.code(),
+                message: message.into(),
+                detail: None,
+                hint: None,
+                position: None,
+            },
+        }
+    }
+
+    pub fn severity(mut self, severity: Severity) -> Self {
+        self.error.severity = severity;
+        self
+    }
+
+    /// Set a code which is not in [`SqlState`].
+    pub fn raw_code(mut self, code: &[u8; 5]) -> Self {
+        self.error.code = *code;
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.error.detail = Some(detail.into());
+        self
+    }
+
+    pub fn hint(mut self, hint: impl Into<String>) -> Self {
+        self.error.hint = Some(hint.into());
+        self
+    }
+
+    pub fn position(mut self, position: u32) -> Self {
+        self.error.position = Some(position);
+        self
+    }
+
+    pub fn build(self) -> PgError {
+        self.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BeMessage;
+
+    #[test]
+    fn test_pg_error_serialization() {
+        let error = PgError::builder(SqlState::SyntaxError, "syntax error")
+            .detail("unexpected token")
+            .hint("check the query")
+            .position(7)
+            .build();
+        let mut buf = BytesMut::new();
+        BeMessage::write(&mut buf, &BeMessage::PgError(&error)).unwrap();
+        let body =
+            b"SERROR\0VERROR\0C42601\0Msyntax error\0Dunexpected token\0Hcheck the query\0P7\0\0";
+        assert_eq!(buf[0], b'E');
+        assert_eq!(&buf[1..5], &(body.len() as u32 + 4).to_be_bytes());
+        assert_eq!(&buf[5..], body);
+
+        let notice = PgError::warning("lagging").build();
+        let mut buf = BytesMut::new();
+        BeMessage::write(&mut buf, &BeMessage::PgError(&notice)).unwrap();
+        assert_eq!(buf[0], b'N');
+        assert_eq!(&buf[5..], b"SWARNING\0VWARNING\0C01000\0Mlagging\0\0");
+
+        let notice = PgError::notice("done").build();
+        let mut buf = BytesMut::new();
+        BeMessage::write(&mut buf, &BeMessage::PgError(&notice)).unwrap();
+        assert_eq!(&buf[5..], b"SNOTICE\0VNOTICE\0C00000\0Mdone\0\0");
+
+        // the plain NoticeResponse is the same notice
+        let mut plain = BytesMut::new();
+        BeMessage::write(&mut plain, &BeMessage::NoticeResponse("done")).unwrap();
+        assert_eq!(plain, buf);
+    }
+}
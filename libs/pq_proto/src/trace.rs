@@ -0,0 +1,257 @@
+//! Wire-level tracing of protocol messages, for debugging protocol desync
+//! with clients without capturing traffic. Every message of a connection is
+//! logged as one line with its timestamp, direction, type, length and the
+//! first bytes of its body, similar to libpq's PQtrace.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Buf;
+use tracing::info;
+
+use crate::{BeMessage, FeMessage, FeStartupPacket, WriteQueue};
+
+/// Where trace lines go.
+enum TraceSink {
+    /// `tracing` events with the `pq_trace` target.
+    Log,
+    File(Mutex<File>),
+}
+
+/// Tracer of messages of one connection.
+pub struct MessageTracer {
+    /// Included into every line, e.g. peer address.
+    conn: String,
+    /// How many bytes of the message body to dump.
+    max_bytes: usize,
+    sink: TraceSink,
+}
+
+impl MessageTracer {
+    /// Trace into the log.
+    pub fn new_log(conn: impl Into<String>, max_bytes: usize) -> Self {
+        MessageTracer {
+            conn: conn.into(),
+            max_bytes,
+            sink: TraceSink::Log,
+        }
+    }
+
+    /// Trace into the file, appending to it.
+    pub fn new_file(conn: impl Into<String>, max_bytes: usize, path: &Path) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        Ok(MessageTracer {
+            conn: conn.into(),
+            max_bytes,
+            sink: TraceSink::File(Mutex::new(file)),
+        })
+    }
+
+    /// Trace a message received from the client; `len` is its length on the
+    /// wire, as the decoded message doesn't keep it.
+    pub fn frontend(&self, msg: &FeMessage, len: usize) {
+        let body = match msg {
+            FeMessage::Query(body) | FeMessage::CopyData(body) | FeMessage::CopyFail(body) => {
+                self.preview(body, body.len())
+            }
+            // carries passwords or SASL proofs
+            FeMessage::PasswordMessage(_) => "<redacted>".to_owned(),
+            msg => {
+                let mut debug = format!("{msg:?}");
+                if let Some((end, _)) = debug.char_indices().nth(self.max_bytes) {
+                    debug.truncate(end);
+                    debug.push_str("...");
+                }
+                debug
+            }
+        };
+        self.emit('F', fe_message_name(msg), len, &body);
+    }
+
+    /// Trace a message sent to the client, just serialized into `queue`
+    /// starting at offset `start`. The serialized bytes are traced, so the
+    /// message isn't serialized again.
+    pub fn backend(&self, msg: &BeMessage, queue: &WriteQueue, start: usize) {
+        let len = queue.remaining() - start;
+        // all messages but the single byte encryption response have a type
+        // byte and a length
+        let header_len = len.min(5);
+        let shown = peek(queue, start + header_len, self.max_bytes);
+        let body = self.preview(&shown, len - header_len);
+        self.emit('B', be_message_name(msg), len, &body);
+    }
+
+    /// Quoted start of a message body of `total` bytes.
+    fn preview(&self, body: &[u8], total: usize) -> String {
+        let shown = &body[..body.len().min(self.max_bytes)];
+        let mut preview = format!("\"{}\"", shown.escape_ascii());
+        if shown.len() < total {
+            preview.push_str("...");
+        }
+        preview
+    }
+
+    fn emit(&self, direction: char, name: &str, len: usize, body: &str) {
+        match &self.sink {
+            TraceSink::Log => {
+                info!(target: "pq_trace", "{} {direction} {name} {len} {body}", self.conn)
+            }
+            TraceSink::File(file) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                let mut file = file.lock().unwrap();
+                // tracing must not break the connection
+                let _ = writeln!(
+                    file,
+                    "{}.{:06} {} {direction} {name} {len} {body}",
+                    now.as_secs(),
+                    now.subsec_micros(),
+                    self.conn
+                );
+            }
+        }
+    }
+}
+
+/// Copy of up to `n` queued bytes starting at `offset`; large payloads are
+/// queued by reference, so the bytes might span several chunks.
+fn peek(queue: &WriteQueue, mut offset: usize, n: usize) -> Vec<u8> {
+    let mut peeked = Vec::with_capacity(n);
+    let chunks = queue.chunks.iter().map(|chunk| &chunk[..]);
+    for chunk in chunks.chain(std::iter::once(&queue.buf[..])) {
+        if peeked.len() == n {
+            break;
+        }
+        if offset >= chunk.len() {
+            offset -= chunk.len();
+            continue;
+        }
+        let chunk = &chunk[offset..];
+        offset = 0;
+        peeked.extend_from_slice(&chunk[..chunk.len().min(n - peeked.len())]);
+    }
+    peeked
+}
+
+fn fe_message_name(msg: &FeMessage) -> &'static str {
+    match msg {
+        FeMessage::StartupPacket(packet) => match packet {
+            FeStartupPacket::CancelRequest(_) => "CancelRequest",
+            FeStartupPacket::SslRequest => "SSLRequest",
+            FeStartupPacket::GssEncRequest => "GSSENCRequest",
+            FeStartupPacket::StartupMessage { .. } => "StartupMessage",
+        },
+        FeMessage::Query(_) => "Query",
+        FeMessage::Parse(_) => "Parse",
+        FeMessage::Describe(_) => "Describe",
+        FeMessage::Bind(_) => "Bind",
+        FeMessage::Execute(_) => "Execute",
+        FeMessage::Close(_) => "Close",
+        FeMessage::Sync => "Sync",
+        FeMessage::Flush => "Flush",
+        FeMessage::Terminate => "Terminate",
+        FeMessage::FunctionCall(_) => "FunctionCall",
+        FeMessage::CopyData(_) => "CopyData",
+        FeMessage::CopyDone => "CopyDone",
+        FeMessage::CopyFail(_) => "CopyFail",
+        FeMessage::PasswordMessage(_) => "PasswordMessage",
+    }
+}
+
+fn be_message_name(msg: &BeMessage) -> &'static str {
+    match msg {
+        BeMessage::AuthenticationOk => "AuthenticationOk",
+        BeMessage::AuthenticationMD5Password(_) => "AuthenticationMD5Password",
+        BeMessage::AuthenticationSasl(_) => "AuthenticationSASL",
+        BeMessage::AuthenticationCleartextPassword => "AuthenticationCleartextPassword",
+        BeMessage::BackendKeyData(_) => "BackendKeyData",
+        BeMessage::BindComplete => "BindComplete",
+        BeMessage::CommandComplete(_) => "CommandComplete",
+        BeMessage::CopyData(_) => "CopyData",
+        BeMessage::CopyDone => "CopyDone",
+        BeMessage::CopyFail => "CopyFail",
+        BeMessage::CopyInResponse => "CopyInResponse",
+        BeMessage::CopyOutResponse => "CopyOutResponse",
+        BeMessage::CopyBothResponse => "CopyBothResponse",
+        BeMessage::CloseComplete => "CloseComplete",
+        BeMessage::DataRow(_) | BeMessage::DataRowBytes(_) => "DataRow",
+        BeMessage::ErrorResponse(..) => "ErrorResponse",
+        BeMessage::PgError(error) if error.severity.is_error() => "ErrorResponse",
+        BeMessage::PgError(_) => "NoticeResponse",
+        BeMessage::EncryptionResponse(_) => "EncryptionResponse",
+        BeMessage::NoData => "NoData",
+        BeMessage::NotificationResponse { .. } => "NotificationResponse",
+        BeMessage::ParameterDescription => "ParameterDescription",
+        BeMessage::ParameterStatus { .. } => "ParameterStatus",
+        BeMessage::ParseComplete => "ParseComplete",
+        BeMessage::ReadyForQuery(_) => "ReadyForQuery",
+        BeMessage::RowDescription(_) => "RowDescription",
+        BeMessage::XLogData(_) | BeMessage::XLogDataBytes(_) => "XLogData",
+        BeMessage::NoticeResponse(_) => "NoticeResponse",
+        BeMessage::KeepAlive(_) => "KeepAlive",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{XLogDataBytes, ZERO_COPY_THRESHOLD};
+    use bytes::Bytes;
+
+    #[test]
+    fn test_trace_to_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace");
+        let tracer = MessageTracer::new_file("conn", 4, &path).unwrap();
+
+        tracer.frontend(&FeMessage::Query(Bytes::from_static(b"select 1\0")), 14);
+        tracer.frontend(
+            &FeMessage::PasswordMessage(Bytes::from_static(b"secret\0")),
+            12,
+        );
+
+        let mut queue = WriteQueue::default();
+        let xlog_data = BeMessage::XLogDataBytes(XLogDataBytes {
+            wal_start: 0,
+            wal_end: 0,
+            timestamp: 0,
+            data: Bytes::from(vec![b'x'; ZERO_COPY_THRESHOLD]),
+        });
+        let msgs = [
+            BeMessage::CommandComplete(b"SELECT 1"),
+            BeMessage::EncryptionResponse(false),
+            xlog_data,
+        ];
+        for msg in &msgs {
+            let start = queue.remaining();
+            queue.write_message(msg).unwrap();
+            tracer.backend(msg, &queue, start);
+        }
+        // a message queued by reference after a partially sent one
+        queue.advance(3);
+        let start = queue.remaining();
+        queue.write_message(&msgs[0]).unwrap();
+        tracer.backend(&msgs[0], &queue, start);
+
+        let trace = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = trace
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "conn F Query 14 \"sele\"...",
+                "conn F PasswordMessage 12 <redacted>",
+                "conn B CommandComplete 14 \"SELE\"...",
+                "conn B EncryptionResponse 1 \"\"",
+                "conn B XLogData 4126 \"w\\x00\\x00\\x00\"...",
+                "conn B CommandComplete 14 \"SELE\"...",
+            ]
+        );
+    }
+}
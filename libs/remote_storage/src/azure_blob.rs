@@ -0,0 +1,598 @@
+//! Azure Blob storage wrapper around its REST API, authorized with the storage
+//! account shared key.
+//!
+//! Respects `prefix_in_container` property from [`AzureConfig`],
+//! allowing multiple api users to independently work with the same container, if
+//! their prefixes are both specified and different.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use chrono::Utc;
+use futures::TryStreamExt;
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH},
+    Body, Client, Method, Response, StatusCode,
+};
+use sha2::Sha256;
+use tokio::{io, sync::Semaphore};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::debug;
+use url::Url;
+
+use super::StorageMetadata;
+use crate::s3_bucket::RatelimitedAsyncRead;
+use crate::{
+    AzureConfig, Download, DownloadError, RemotePath, RemoteStorage,
+    REMOTE_STORAGE_PREFIX_SEPARATOR,
+};
+
+/// Version of the Blob service REST API the requests are made against.
+const AZURE_STORAGE_VERSION: &str = "2021-08-06";
+
+/// Environment variable with the base64 encoded storage account key.
+pub const AZURE_STORAGE_ACCESS_KEY_ENV: &str = "AZURE_STORAGE_ACCESS_KEY";
+
+const METADATA_HEADER_PREFIX: &str = "x-ms-meta-";
+
+/// Azure Blob storage.
+pub struct AzureBlobStorage {
+    client: Client,
+    storage_account: String,
+    access_key: Vec<u8>,
+    /// Endpoint URL with the container name appended.
+    container_url: Url,
+    prefix_in_container: Option<String>,
+    // Azure throttles storage accounts exceeding their request rate,
+    // the semaphore helps to stay under the limits.
+    concurrency_limiter: Arc<Semaphore>,
+}
+
+impl AzureBlobStorage {
+    /// Creates the Azure Blob storage, errors if incorrect configuration provided
+    /// or the access key is not set.
+    pub fn new(azure_config: &AzureConfig) -> anyhow::Result<Self> {
+        debug!(
+            "Creating azure remote storage for container {}",
+            azure_config.container_name
+        );
+
+        let access_key = std::env::var(AZURE_STORAGE_ACCESS_KEY_ENV)
+            .with_context(|| format!("{AZURE_STORAGE_ACCESS_KEY_ENV} is not set"))?;
+        let access_key = base64::decode(access_key.trim())
+            .with_context(|| format!("{AZURE_STORAGE_ACCESS_KEY_ENV} is not valid base64"))?;
+
+        let endpoint = match &azure_config.endpoint {
+            Some(endpoint) => endpoint.clone(),
+            None => format!(
+                "https://{}.blob.core.windows.net",
+                azure_config.storage_account
+            ),
+        };
+        let mut container_url = Url::parse(&endpoint)
+            .with_context(|| format!("Failed to parse Azure endpoint '{endpoint}'"))?;
+        container_url
+            .path_segments_mut()
+            .map_err(|()| anyhow!("Azure endpoint '{endpoint}' cannot be a base URL"))?
+            .pop_if_empty()
+            .push(&azure_config.container_name);
+
+        let prefix_in_container = azure_config
+            .prefix_in_container
+            .as_deref()
+            .map(|prefix| prefix.trim_matches(REMOTE_STORAGE_PREFIX_SEPARATOR))
+            .filter(|prefix| !prefix.is_empty())
+            .map(str::to_string);
+
+        Ok(Self {
+            client: Client::new(),
+            storage_account: azure_config.storage_account.clone(),
+            access_key,
+            container_url,
+            prefix_in_container,
+            concurrency_limiter: Arc::new(Semaphore::new(azure_config.concurrency_limit.get())),
+        })
+    }
+
+    fn blob_name_to_relative_path(&self, name: &str) -> RemotePath {
+        let relative_path = match &self.prefix_in_container {
+            // we rely on Azure to return properly prefixed names
+            // for requests with a certain prefix
+            Some(prefix) => name.strip_prefix(prefix.as_str()).unwrap_or_else(|| {
+                panic!("Blob {name} does not start with container prefix {prefix:?}")
+            }),
+            None => name,
+        };
+        RemotePath(
+            relative_path
+                .trim_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+                .split(REMOTE_STORAGE_PREFIX_SEPARATOR)
+                .collect(),
+        )
+    }
+
+    fn relative_path_to_blob_name(&self, path: &RemotePath) -> String {
+        let mut full_path = self.prefix_in_container.clone().unwrap_or_default();
+        for segment in path.0.iter() {
+            if !full_path.is_empty() {
+                full_path.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+            }
+            full_path.push_str(segment.to_str().unwrap_or_default());
+        }
+        full_path
+    }
+
+    fn blob_url(&self, path: &RemotePath) -> Url {
+        let mut url = self.container_url.clone();
+        url.path_segments_mut()
+            .expect("container URL is a base URL")
+            .extend(
+                self.relative_path_to_blob_name(path)
+                    .split(REMOTE_STORAGE_PREFIX_SEPARATOR),
+            );
+        url
+    }
+
+    /// Signs and sends the request, returning the response whatever its status is.
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        mut headers: HeaderMap,
+        body: Option<(Body, usize)>,
+    ) -> anyhow::Result<Response> {
+        let content_length = body.as_ref().map(|(_, len)| *len);
+        if let Some(len) = content_length {
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+        }
+        headers.insert(
+            "x-ms-date",
+            HeaderValue::from_str(&Utc::now().format("%a, %d %b %Y %H:%M:%S GMT").to_string())?,
+        );
+        headers.insert(
+            "x-ms-version",
+            HeaderValue::from_static(AZURE_STORAGE_VERSION),
+        );
+
+        let string_to_sign = string_to_sign(
+            &self.storage_account,
+            &method,
+            &url,
+            &headers,
+            content_length,
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.access_key).expect("bad key size");
+        mac.update(string_to_sign.as_bytes());
+        let signature = base64::encode(mac.finalize().into_bytes());
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("SharedKey {}:{}", self.storage_account, signature))?,
+        );
+
+        let mut request = self.client.request(method, url).headers(headers);
+        if let Some((body, _)) = body {
+            request = request.body(body);
+        }
+        Ok(request.send().await?)
+    }
+
+    /// Lists blobs with names starting with `prefix`, following all the pages.
+    /// With `delimiter`, blobs in "subdirectories" are not listed, their common
+    /// prefixes are returned instead.
+    async fn list_blobs(
+        &self,
+        prefix: Option<&str>,
+        delimiter: bool,
+    ) -> anyhow::Result<ListBlobsPage> {
+        let mut listing = ListBlobsPage::default();
+        let mut marker = None;
+        loop {
+            let _guard = self
+                .concurrency_limiter
+                .acquire()
+                .await
+                .context("Concurrency limiter semaphore got closed during Azure list")?;
+
+            let mut url = self.container_url.clone();
+            {
+                let mut query = url.query_pairs_mut();
+                query.append_pair("restype", "container");
+                query.append_pair("comp", "list");
+                if let Some(prefix) = prefix {
+                    query.append_pair("prefix", prefix);
+                }
+                if delimiter {
+                    query.append_pair("delimiter", &REMOTE_STORAGE_PREFIX_SEPARATOR.to_string());
+                }
+                if let Some(marker) = &marker {
+                    query.append_pair("marker", marker);
+                }
+            }
+
+            let response = self.send(Method::GET, url, HeaderMap::new(), None).await?;
+            let body = check_status(response).await?.text().await?;
+            let page = parse_list_blobs(&body)?;
+            listing.blobs.extend(page.blobs);
+            listing.prefixes.extend(page.prefixes);
+
+            match page.next_marker {
+                Some(next_marker) => marker = Some(next_marker),
+                None => break,
+            }
+        }
+        Ok(listing)
+    }
+
+    async fn download_blob(
+        &self,
+        from: &RemotePath,
+        range: Option<String>,
+    ) -> Result<Download, DownloadError> {
+        let permit = self
+            .concurrency_limiter
+            .clone()
+            .acquire_owned()
+            .await
+            .context("Concurrency limiter semaphore got closed during Azure download")
+            .map_err(DownloadError::Other)?;
+
+        let mut headers = HeaderMap::new();
+        if let Some(range) = range {
+            headers.insert(
+                "x-ms-range",
+                HeaderValue::from_str(&range).map_err(|e| DownloadError::BadInput(e.into()))?,
+            );
+        }
+
+        let response = self
+            .send(Method::GET, self.blob_url(from), headers, None)
+            .await
+            .map_err(DownloadError::Other)?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(DownloadError::NotFound);
+        }
+        let response = check_status(response)
+            .await
+            .context("Failed to download Azure blob")
+            .map_err(DownloadError::Other)?;
+
+        let metadata: HashMap<String, String> = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| {
+                let key = name.as_str().strip_prefix(METADATA_HEADER_PREFIX)?;
+                Some((key.to_string(), value.to_str().ok()?.to_string()))
+            })
+            .collect();
+        let metadata = (!metadata.is_empty()).then_some(StorageMetadata(metadata));
+
+        let stream = Box::pin(
+            response
+                .bytes_stream()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        );
+        Ok(Download {
+            metadata,
+            download_stream: Box::pin(io::BufReader::new(RatelimitedAsyncRead::new(
+                permit,
+                StreamReader::new(stream),
+            ))),
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for AzureBlobStorage {
+    async fn list(&self) -> anyhow::Result<Vec<RemotePath>> {
+        let listing = self
+            .list_blobs(self.prefix_in_container.as_deref(), false)
+            .await
+            .context("Failed to list Azure blobs")?;
+        Ok(listing
+            .blobs
+            .iter()
+            .map(|name| self.blob_name_to_relative_path(name))
+            .collect())
+    }
+
+    /// See the doc for `RemoteStorage::list_prefixes`
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, DownloadError> {
+        // get the passed prefix or if it is not set use prefix_in_container value
+        let list_prefix = prefix
+            .map(|p| self.relative_path_to_blob_name(p))
+            .or_else(|| self.prefix_in_container.clone())
+            .map(|mut p| {
+                // required to end with a separator
+                // otherwise request will return only the entry of a prefix
+                if !p.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
+                    p.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+                }
+                p
+            });
+
+        let listing = self
+            .list_blobs(list_prefix.as_deref(), true)
+            .await
+            .context("Failed to list Azure prefixes")
+            .map_err(DownloadError::Other)?;
+        Ok(listing
+            .prefixes
+            .iter()
+            .map(|name| self.blob_name_to_relative_path(name))
+            .collect())
+    }
+
+    async fn list_files(&self, prefix: &RemotePath) -> anyhow::Result<Vec<RemotePath>> {
+        let mut list_prefix = self.relative_path_to_blob_name(prefix);
+        if !list_prefix.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
+            list_prefix.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+        }
+
+        let listing = self
+            .list_blobs(Some(&list_prefix), true)
+            .await
+            .context("Failed to list Azure files")?;
+        Ok(listing
+            .blobs
+            .iter()
+            .map(|name| self.blob_name_to_relative_path(name))
+            .collect())
+    }
+
+    async fn upload(
+        &self,
+        from: Box<(dyn io::AsyncRead + Unpin + Send + Sync + 'static)>,
+        from_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        let _guard = self
+            .concurrency_limiter
+            .acquire()
+            .await
+            .context("Concurrency limiter semaphore got closed during Azure upload")?;
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ms-blob-type", HeaderValue::from_static("BlockBlob"));
+        for (key, value) in metadata.map(|m| m.0).unwrap_or_default() {
+            headers.insert(
+                HeaderName::from_bytes(format!("{METADATA_HEADER_PREFIX}{key}").as_bytes())?,
+                HeaderValue::from_str(&value)?,
+            );
+        }
+
+        let body = Body::wrap_stream(ReaderStream::new(from));
+        let response = self
+            .send(
+                Method::PUT,
+                self.blob_url(to),
+                headers,
+                Some((body, from_size_bytes)),
+            )
+            .await?;
+        check_status(response)
+            .await
+            .context("Failed to upload Azure blob")?;
+        Ok(())
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
+        self.download_blob(from, None).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, DownloadError> {
+        // Same as for S3, the range end is inclusive
+        let end_inclusive = end_exclusive.map(|end| end.saturating_sub(1));
+        let range = match end_inclusive {
+            Some(end_inclusive) => format!("bytes={start_inclusive}-{end_inclusive}"),
+            None => format!("bytes={start_inclusive}-"),
+        };
+        self.download_blob(from, Some(range)).await
+    }
+
+    async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
+        let _guard = self
+            .concurrency_limiter
+            .acquire()
+            .await
+            .context("Concurrency limiter semaphore got closed during Azure delete")?;
+
+        let response = self
+            .send(Method::DELETE, self.blob_url(path), HeaderMap::new(), None)
+            .await?;
+        // deleting a missing blob succeeds, same as for S3
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(());
+        }
+        check_status(response)
+            .await
+            .context("Failed to delete Azure blob")?;
+        Ok(())
+    }
+}
+
+async fn check_status(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body = response.text().await.unwrap_or_default();
+    bail!("Azure request failed with status {status}: {body}")
+}
+
+/// Builds the string signed for the Shared Key authorization, see
+/// <https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key>.
+/// The requests don't set the standard headers other than Content-Length,
+/// the date and the range are passed in the x-ms- headers instead.
+fn string_to_sign(
+    storage_account: &str,
+    method: &Method,
+    url: &Url,
+    headers: &HeaderMap,
+    content_length: Option<usize>,
+) -> String {
+    let content_length = content_length
+        .filter(|len| *len > 0)
+        .map(|len| len.to_string())
+        .unwrap_or_default();
+    // Content-Encoding, Content-Language, Content-Length, Content-MD5,
+    // Content-Type, Date, If-Modified-Since, If-Match, If-None-Match,
+    // If-Unmodified-Since and Range
+    let mut result = format!("{method}\n\n\n{content_length}\n\n\n\n\n\n\n\n\n");
+
+    let ms_headers: BTreeMap<&str, &str> = headers
+        .iter()
+        .filter(|(name, _)| name.as_str().starts_with("x-ms-"))
+        .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or_default().trim()))
+        .collect();
+    for (name, value) in ms_headers {
+        result.push_str(&format!("{name}:{value}\n"));
+    }
+
+    result.push_str(&format!("/{storage_account}{}", url.path()));
+    let mut params: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (name, value) in url.query_pairs() {
+        params
+            .entry(name.to_lowercase())
+            .or_default()
+            .push(value.into_owned());
+    }
+    for (name, mut values) in params {
+        values.sort();
+        result.push_str(&format!("\n{name}:{}", values.join(",")));
+    }
+    result
+}
+
+/// Blob names and common prefixes from List Blobs responses.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct ListBlobsPage {
+    blobs: Vec<String>,
+    prefixes: Vec<String>,
+    next_marker: Option<String>,
+}
+
+/// Extracts blob names, prefixes and the continuation marker from a List Blobs
+/// response. Both `Blob` and `BlobPrefix` elements start with their `Name`.
+fn parse_list_blobs(xml: &str) -> anyhow::Result<ListBlobsPage> {
+    let mut page = ListBlobsPage::default();
+    let mut rest = xml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        if let Some(element) = rest.strip_prefix("<Blob>") {
+            let (name, remaining) = element_text(element, "Name")?;
+            page.blobs.push(name);
+            rest = remaining;
+        } else if let Some(element) = rest.strip_prefix("<BlobPrefix>") {
+            let (name, remaining) = element_text(element, "Name")?;
+            page.prefixes.push(name);
+            rest = remaining;
+        } else if rest.starts_with("<NextMarker>") {
+            let (marker, remaining) = element_text(rest, "NextMarker")?;
+            page.next_marker = Some(marker).filter(|marker| !marker.is_empty());
+            rest = remaining;
+        } else {
+            rest = &rest[1..];
+        }
+    }
+    Ok(page)
+}
+
+/// Returns the unescaped text of the first `tag` element in `xml` and the rest
+/// of the document after it.
+fn element_text<'a>(xml: &'a str, tag: &str) -> anyhow::Result<(String, &'a str)> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let start = xml
+        .find(&open)
+        .with_context(|| format!("no {tag} element in the List Blobs response"))?
+        + open.len();
+    let end = xml[start..]
+        .find(&close)
+        .with_context(|| format!("unterminated {tag} element in the List Blobs response"))?
+        + start;
+    let text = xml[start..end]
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&");
+    Ok((text, &xml[end + close.len()..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_list_blobs_response() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+<EnumerationResults ServiceEndpoint="https://account.blob.core.windows.net/" ContainerName="container">
+  <Prefix>prefix/</Prefix>
+  <Delimiter>/</Delimiter>
+  <Blobs>
+    <Blob>
+      <Name>prefix/a&amp;b</Name>
+      <Properties><Content-Length>10</Content-Length></Properties>
+    </Blob>
+    <BlobPrefix>
+      <Name>prefix/dir/</Name>
+    </BlobPrefix>
+  </Blobs>
+  <NextMarker>marker</NextMarker>
+</EnumerationResults>"#;
+        assert_eq!(
+            parse_list_blobs(xml).unwrap(),
+            ListBlobsPage {
+                blobs: vec!["prefix/a&b".to_string()],
+                prefixes: vec!["prefix/dir/".to_string()],
+                next_marker: Some("marker".to_string()),
+            }
+        );
+
+        let xml = "<EnumerationResults><Blobs /><NextMarker /></EnumerationResults>";
+        assert_eq!(parse_list_blobs(xml).unwrap(), ListBlobsPage::default());
+        let xml =
+            "<EnumerationResults><Blobs></Blobs><NextMarker></NextMarker></EnumerationResults>";
+        assert_eq!(parse_list_blobs(xml).unwrap(), ListBlobsPage::default());
+    }
+
+    #[test]
+    fn shared_key_string_to_sign() {
+        let url = Url::parse(
+            "https://account.blob.core.windows.net/container?restype=container&comp=list&prefix=a%2F&delimiter=%2F",
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-ms-date",
+            HeaderValue::from_static("Fri, 26 Jun 2015 23:39:12 GMT"),
+        );
+        headers.insert("x-ms-version", HeaderValue::from_static("2015-02-21"));
+        assert_eq!(
+            string_to_sign("account", &Method::GET, &url, &headers, None),
+            "GET\n\n\n\n\n\n\n\n\n\n\n\n\
+             x-ms-date:Fri, 26 Jun 2015 23:39:12 GMT\n\
+             x-ms-version:2015-02-21\n\
+             /account/container\n\
+             comp:list\n\
+             delimiter:/\n\
+             prefix:a/\n\
+             restype:container"
+        );
+
+        let url = Url::parse("https://account.blob.core.windows.net/container/a/b").unwrap();
+        let signed = string_to_sign("account", &Method::PUT, &url, &HeaderMap::new(), Some(7));
+        assert_eq!(signed, "PUT\n\n\n7\n\n\n\n\n\n\n\n\n/account/container/a/b");
+    }
+}
@@ -3,8 +3,11 @@
 //!
 //! [`RemoteStorage`] trait a CRUD-like generic abstraction to use for adapting external storages with a few implementations:
 //!   * [`local_fs`] allows to use local file system as an external storage
-//!   * [`s3_bucket`] uses AWS S3 bucket as an external storage
+//!   * [`s3_bucket`] uses AWS S3 bucket as an external storage; Google Cloud Storage is accessed with it too,
+//!     through the S3 compatible XML API
+//!   * [`azure_blob`] uses Azure Blob storage container as an external storage
 //!
+mod azure_blob;
 mod local_fs;
 mod s3_bucket;
 mod simulate_failures;
@@ -17,6 +20,7 @@ use std::{
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{bail, Context};
@@ -25,7 +29,12 @@ use tokio::io;
 use toml_edit::Item;
 use tracing::info;
 
-pub use self::{local_fs::LocalFs, s3_bucket::S3Bucket, simulate_failures::UnreliableWrapper};
+pub use self::{
+    azure_blob::{AzureBlobStorage, AZURE_STORAGE_ACCESS_KEY_ENV},
+    local_fs::LocalFs,
+    s3_bucket::S3Bucket,
+    simulate_failures::UnreliableWrapper,
+};
 
 /// How many different timelines can be processed simultaneously when synchronizing layers with the remote storage.
 /// During regular work, pageserver produces one layer file per timeline checkpoint, with bursts of concurrency
@@ -40,6 +49,9 @@ pub const DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS: u32 = 10;
 /// https://aws.amazon.com/premiumsupport/knowledge-center/s3-request-limit-avoid-throttling/
 pub const DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT: usize = 100;
 
+/// Endpoint of the S3 compatible XML API of Google Cloud Storage.
+pub const GCS_ENDPOINT: &str = "https://storage.googleapis.com";
+
 const REMOTE_STORAGE_PREFIX_SEPARATOR: char = '/';
 
 /// Path on the remote storage, relative to some inner prefix.
@@ -83,6 +95,11 @@ pub trait RemoteStorage: Send + Sync + 'static {
         prefix: Option<&RemotePath>,
     ) -> Result<Vec<RemotePath>, DownloadError>;
 
+    /// Lists all files directly under the given prefix, without descending
+    /// into subdirectories. Same as for `list_prefixes`, the prefix is
+    /// expected to already account for the global prefix.
+    async fn list_files(&self, prefix: &RemotePath) -> anyhow::Result<Vec<RemotePath>>;
+
     /// Streams the local file contents into remote into the remote storage entry.
     async fn upload(
         &self,
@@ -154,6 +171,7 @@ impl std::error::Error for DownloadError {}
 pub enum GenericRemoteStorage {
     LocalFs(LocalFs),
     AwsS3(Arc<S3Bucket>),
+    AzureBlob(Arc<AzureBlobStorage>),
     Unreliable(Arc<UnreliableWrapper>),
 }
 
@@ -164,6 +182,7 @@ impl Deref for GenericRemoteStorage {
         match self {
             GenericRemoteStorage::LocalFs(local_fs) => local_fs,
             GenericRemoteStorage::AwsS3(s3_bucket) => s3_bucket.as_ref(),
+            GenericRemoteStorage::AzureBlob(azure_blob) => azure_blob.as_ref(),
             GenericRemoteStorage::Unreliable(s) => s.as_ref(),
         }
     }
@@ -181,6 +200,16 @@ impl GenericRemoteStorage {
                       s3_config.bucket_name, s3_config.bucket_region, s3_config.prefix_in_bucket, s3_config.endpoint);
                 Self::AwsS3(Arc::new(S3Bucket::new(s3_config)?))
             }
+            RemoteStorageKind::Gcs(s3_config) => {
+                info!("Using gcs bucket '{}' as a remote storage, prefix in bucket: '{:?}', bucket endpoint: '{:?}'",
+                      s3_config.bucket_name, s3_config.prefix_in_bucket, s3_config.endpoint);
+                Self::AwsS3(Arc::new(S3Bucket::new(s3_config)?))
+            }
+            RemoteStorageKind::AzureBlob(azure_config) => {
+                info!("Using azure container '{}' in account '{}' as a remote storage, prefix in container: '{:?}', endpoint: '{:?}'",
+                      azure_config.container_name, azure_config.storage_account, azure_config.prefix_in_container, azure_config.endpoint);
+                Self::AzureBlob(Arc::new(AzureBlobStorage::new(azure_config)?))
+            }
         })
     }
 
@@ -234,6 +263,46 @@ pub struct RemoteStorageConfig {
     pub max_sync_errors: NonZeroU32,
     /// The storage connection configuration.
     pub storage: RemoteStorageKind,
+    /// Backoff between retries of failed operations, if it differs from the
+    /// default of the storage kind.
+    pub retry: Option<RetryConfig>,
+}
+
+/// Exponential backoff between retries of failed remote storage operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// Delay before the first retry, doubled on every subsequent one.
+    pub min_backoff: Duration,
+    /// Upper bound of the delay.
+    pub max_backoff: Duration,
+}
+
+impl RetryConfig {
+    /// Default backoff of the storage kind. Local FS errors are unlikely to be
+    /// transient, GCS throttles more aggressively than S3 and Azure and asks
+    /// clients to back off for at least a second.
+    pub fn default_for(storage: &RemoteStorageKind) -> Self {
+        let (min_ms, max_ms) = match storage {
+            RemoteStorageKind::LocalFs(_) => (10, 1000),
+            RemoteStorageKind::AwsS3(_) | RemoteStorageKind::AzureBlob(_) => (10, 5000),
+            RemoteStorageKind::Gcs(_) => (1000, 32000),
+        };
+        RetryConfig {
+            min_backoff: Duration::from_millis(min_ms),
+            max_backoff: Duration::from_millis(max_ms),
+        }
+    }
+
+    /// Delay before the retry following `attempt` failed ones.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        if attempt == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX);
+        self.min_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
 }
 
 /// A kind of a remote storage to connect to, with its connection configuration.
@@ -245,6 +314,12 @@ pub enum RemoteStorageKind {
     /// AWS S3 based storage, storing all files in the S3 bucket
     /// specified by the config
     AwsS3(S3Config),
+    /// Google Cloud Storage bucket, accessed through its S3 compatible API
+    /// with HMAC keys passed as AWS credentials.
+    Gcs(S3Config),
+    /// Azure Blob storage container, accessed with the storage account key
+    /// from the `AZURE_STORAGE_ACCESS_KEY` environment variable.
+    AzureBlob(AzureConfig),
 }
 
 /// AWS S3 bucket coordinates and access credentials to manage the bucket contents (read and write).
@@ -279,11 +354,41 @@ impl Debug for S3Config {
     }
 }
 
+/// Azure Blob storage container coordinates.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AzureConfig {
+    /// Name of the container to connect to.
+    pub container_name: String,
+    /// Name of the storage account the container belongs to.
+    pub storage_account: String,
+    /// A "subfolder" in the container, to use the same container separately by multiple remote storage users at once.
+    pub prefix_in_container: Option<String>,
+    /// A base URL to send requests to, `https://<storage_account>.blob.core.windows.net` by default.
+    ///
+    /// Example: `http://127.0.0.1:10000/devstoreaccount1`
+    pub endpoint: Option<String>,
+    /// Limit of concurrent requests, to avoid getting throttled.
+    pub concurrency_limit: NonZeroUsize,
+}
+
+impl Debug for AzureConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureConfig")
+            .field("container_name", &self.container_name)
+            .field("storage_account", &self.storage_account)
+            .field("prefix_in_container", &self.prefix_in_container)
+            .field("concurrency_limit", &self.concurrency_limit)
+            .finish()
+    }
+}
+
 impl RemoteStorageConfig {
     pub fn from_toml(toml: &toml_edit::Item) -> anyhow::Result<Option<RemoteStorageConfig>> {
         let local_path = toml.get("local_path");
         let bucket_name = toml.get("bucket_name");
         let bucket_region = toml.get("bucket_region");
+        let gcs_bucket_name = toml.get("gcs_bucket_name");
+        let container_name = toml.get("container_name");
 
         let max_concurrent_syncs = NonZeroUsize::new(
             parse_optional_integer("max_concurrent_syncs", toml)?
@@ -303,6 +408,67 @@ impl RemoteStorageConfig {
         )
         .context("Failed to parse 'concurrency_limit' as a positive integer")?;
 
+        let prefix_in_bucket = toml
+            .get("prefix_in_bucket")
+            .map(|prefix_in_bucket| parse_toml_string("prefix_in_bucket", prefix_in_bucket))
+            .transpose()?;
+        let endpoint = toml
+            .get("endpoint")
+            .map(|endpoint| parse_toml_string("endpoint", endpoint))
+            .transpose()?;
+
+        if let Some(gcs_bucket_name) = gcs_bucket_name {
+            if local_path.is_some()
+                || bucket_name.is_some()
+                || bucket_region.is_some()
+                || container_name.is_some()
+            {
+                bail!("gcs_bucket_name is mutually exclusive with local_path, bucket_name, bucket_region and container_name");
+            }
+            let storage = RemoteStorageKind::Gcs(S3Config {
+                bucket_name: parse_toml_string("gcs_bucket_name", gcs_bucket_name)?,
+                // required by the client, but ignored by GCS
+                bucket_region: "auto".to_string(),
+                prefix_in_bucket,
+                endpoint: Some(endpoint.unwrap_or_else(|| GCS_ENDPOINT.to_string())),
+                concurrency_limit,
+            });
+            let retry = parse_retry_config(toml, &storage)?;
+            return Ok(Some(RemoteStorageConfig {
+                max_concurrent_syncs,
+                max_sync_errors,
+                storage,
+                retry,
+            }));
+        }
+
+        if let Some(container_name) = container_name {
+            if local_path.is_some() || bucket_name.is_some() || bucket_region.is_some() {
+                bail!("container_name is mutually exclusive with local_path, bucket_name and bucket_region");
+            }
+            let storage_account = toml
+                .get("storage_account")
+                .context("'storage_account' option is mandatory if 'container_name' is given")?;
+            let prefix_in_container = toml
+                .get("prefix_in_container")
+                .map(|prefix| parse_toml_string("prefix_in_container", prefix))
+                .transpose()?;
+            let storage = RemoteStorageKind::AzureBlob(AzureConfig {
+                container_name: parse_toml_string("container_name", container_name)?,
+                storage_account: parse_toml_string("storage_account", storage_account)?,
+                prefix_in_container,
+                endpoint,
+                concurrency_limit,
+            });
+            let retry = parse_retry_config(toml, &storage)?;
+            return Ok(Some(RemoteStorageConfig {
+                max_concurrent_syncs,
+                max_sync_errors,
+                storage,
+                retry,
+            }));
+        }
+
         let storage = match (local_path, bucket_name, bucket_region) {
             // no 'local_path' nor 'bucket_name' options are provided, consider this remote storage disabled
             (None, None, None) => return Ok(None),
@@ -315,14 +481,8 @@ impl RemoteStorageConfig {
             (None, Some(bucket_name), Some(bucket_region)) => RemoteStorageKind::AwsS3(S3Config {
                 bucket_name: parse_toml_string("bucket_name", bucket_name)?,
                 bucket_region: parse_toml_string("bucket_region", bucket_region)?,
-                prefix_in_bucket: toml
-                    .get("prefix_in_bucket")
-                    .map(|prefix_in_bucket| parse_toml_string("prefix_in_bucket", prefix_in_bucket))
-                    .transpose()?,
-                endpoint: toml
-                    .get("endpoint")
-                    .map(|endpoint| parse_toml_string("endpoint", endpoint))
-                    .transpose()?,
+                prefix_in_bucket,
+                endpoint,
                 concurrency_limit,
             }),
             (Some(local_path), None, None) => RemoteStorageKind::LocalFs(PathBuf::from(
@@ -331,12 +491,44 @@ impl RemoteStorageConfig {
             (Some(_), Some(_), _) => bail!("local_path and bucket_name are mutually exclusive"),
         };
 
+        let retry = parse_retry_config(toml, &storage)?;
         Ok(Some(RemoteStorageConfig {
             max_concurrent_syncs,
             max_sync_errors,
             storage,
+            retry,
         }))
     }
+
+    /// Backoff between retries of failed operations to use with this storage.
+    pub fn retry_config(&self) -> RetryConfig {
+        self.retry
+            .unwrap_or_else(|| RetryConfig::default_for(&self.storage))
+    }
+}
+
+/// Parse 'retry_min_backoff_ms' and 'retry_max_backoff_ms', taking the missing
+/// one from the default of the storage kind.
+fn parse_retry_config(
+    toml: &toml_edit::Item,
+    storage: &RemoteStorageKind,
+) -> anyhow::Result<Option<RetryConfig>> {
+    let min_backoff_ms: Option<u64> = parse_optional_integer("retry_min_backoff_ms", toml)?;
+    let max_backoff_ms: Option<u64> = parse_optional_integer("retry_max_backoff_ms", toml)?;
+    if min_backoff_ms.is_none() && max_backoff_ms.is_none() {
+        return Ok(None);
+    }
+    let default = RetryConfig::default_for(storage);
+    let retry = RetryConfig {
+        min_backoff: min_backoff_ms.map_or(default.min_backoff, Duration::from_millis),
+        max_backoff: max_backoff_ms.map_or(default.max_backoff, Duration::from_millis),
+    };
+    if retry.min_backoff.is_zero() || retry.min_backoff > retry.max_backoff {
+        bail!(
+            "'retry_min_backoff_ms' must be positive and not greater than 'retry_max_backoff_ms'"
+        );
+    }
+    Ok(Some(retry))
 }
 
 // Helper functions to parse a toml Item
@@ -384,6 +576,63 @@ mod tests {
         assert_eq!(k.object_name(), None);
     }
 
+    #[test]
+    fn parse_gcs_config_with_retry() {
+        let toml: toml_edit::Document =
+            "storage = { gcs_bucket_name = 'bucket', retry_max_backoff_ms = 60000 }"
+                .parse()
+                .unwrap();
+        let config = RemoteStorageConfig::from_toml(&toml["storage"])
+            .unwrap()
+            .unwrap();
+        match &config.storage {
+            RemoteStorageKind::Gcs(s3_config) => {
+                assert_eq!(s3_config.bucket_name, "bucket");
+                assert_eq!(s3_config.endpoint.as_deref(), Some(GCS_ENDPOINT));
+            }
+            other => panic!("unexpected storage kind {other:?}"),
+        }
+        let retry = config.retry_config();
+        assert_eq!(retry.min_backoff, Duration::from_secs(1));
+        assert_eq!(retry.max_backoff, Duration::from_secs(60));
+
+        assert_eq!(retry.backoff(0), Duration::ZERO);
+        assert_eq!(retry.backoff(1), Duration::from_secs(1));
+        assert_eq!(retry.backoff(3), Duration::from_secs(4));
+        assert_eq!(retry.backoff(100), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn parse_azure_config() {
+        let toml: toml_edit::Document = "storage = { container_name = 'container', storage_account = 'account', prefix_in_container = 'prefix/' }"
+            .parse()
+            .unwrap();
+        let config = RemoteStorageConfig::from_toml(&toml["storage"])
+            .unwrap()
+            .unwrap();
+        match &config.storage {
+            RemoteStorageKind::AzureBlob(azure_config) => {
+                assert_eq!(azure_config.container_name, "container");
+                assert_eq!(azure_config.storage_account, "account");
+                assert_eq!(azure_config.prefix_in_container.as_deref(), Some("prefix/"));
+                assert_eq!(azure_config.endpoint, None);
+            }
+            other => panic!("unexpected storage kind {other:?}"),
+        }
+        assert_eq!(config.retry_config().max_backoff, Duration::from_secs(5));
+
+        let toml: toml_edit::Document = "storage = { container_name = 'container' }"
+            .parse()
+            .unwrap();
+        assert!(RemoteStorageConfig::from_toml(&toml["storage"]).is_err());
+
+        let toml: toml_edit::Document =
+            "storage = { container_name = 'container', storage_account = 'account', bucket_name = 'bucket' }"
+                .parse()
+                .unwrap();
+        assert!(RemoteStorageConfig::from_toml(&toml["storage"]).is_err());
+    }
+
     #[test]
     fn rempte_path_cannot_be_created_from_absolute_ones() {
         let err = RemotePath::new(Path::new("/")).expect_err("Should fail on absolute paths");
@@ -115,6 +115,23 @@ impl RemoteStorage for LocalFs {
             .collect())
     }
 
+    async fn list_files(&self, prefix: &RemotePath) -> anyhow::Result<Vec<RemotePath>> {
+        let mut files = Vec::new();
+        for path in get_all_files(prefix.with_base(&self.storage_root), false).await? {
+            let is_temp = path.extension().map_or(false, |ext| {
+                ext.to_string_lossy().ends_with(LOCAL_FS_TEMP_FILE_SUFFIX)
+            });
+            if path.is_file() && !is_temp {
+                files.push(
+                    path.strip_prefix(&self.storage_root)
+                        .context("Failed to strip storage root prefix")
+                        .and_then(RemotePath::new)?,
+                );
+            }
+        }
+        Ok(files)
+    }
+
     async fn upload(
         &self,
         data: Box<(dyn io::AsyncRead + Unpin + Send + Sync + 'static)>,
@@ -411,6 +428,27 @@ mod fs_tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn list_files() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+
+        let target_path_1 = upload_dummy_file(&storage, "upload_1", None).await?;
+        let target_path_2 = upload_dummy_file(&storage, "upload_2", None).await?;
+        upload_dummy_file(&storage, "nested/upload_3", None).await?;
+
+        let mut files = storage
+            .list_files(&RemotePath::new(Path::new("timelines/some_timeline"))?)
+            .await?;
+        files.sort();
+        assert_eq!(
+            files,
+            vec![target_path_1, target_path_2],
+            "Should list only files directly under the prefix"
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn upload_file_negatives() -> anyhow::Result<()> {
         let storage = create_storage()?;
@@ -22,6 +22,7 @@ pub struct UnreliableWrapper {
 enum RemoteOp {
     List,
     ListPrefixes(Option<RemotePath>),
+    ListFiles(RemotePath),
     Upload(RemotePath),
     Download(RemotePath),
     Delete(RemotePath),
@@ -89,6 +90,11 @@ impl RemoteStorage for UnreliableWrapper {
         self.inner.list_prefixes(prefix).await
     }
 
+    async fn list_files(&self, prefix: &RemotePath) -> anyhow::Result<Vec<RemotePath>> {
+        self.attempt(RemoteOp::ListFiles(prefix.clone()))?;
+        self.inner.list_files(prefix).await
+    }
+
     async fn upload(
         &self,
         data: Box<(dyn tokio::io::AsyncRead + Unpin + Send + Sync + 'static)>,
@@ -21,6 +21,48 @@ pub struct TimelineCreateRequest {
     pub commit_lsn: Lsn,
     // If not passed, it is assigned to the beginning of commit_lsn segment.
     pub local_start_lsn: Option<Lsn>,
+    // Where WAL of the timeline begins globally. If not passed, the timeline
+    // is uninitialized until the first walproposer is elected.
+    pub timeline_start_lsn: Option<Lsn>,
+}
+
+/// Request to fence the timeline by bumping its term, so that walproposers
+/// with an older term can't append anymore.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TimelineTermBumpRequest {
+    /// Term to bump to; if not passed, the current term is incremented.
+    pub term: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineTermBumpResponse {
+    pub previous_term: u64,
+    pub current_term: u64,
+}
+
+/// Request to hold WAL of the timeline starting at `lsn` under a name.
+#[serde_as]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineRetentionPinRequest {
+    #[serde_as(as = "DisplayFromStr")]
+    pub lsn: Lsn,
+    /// Drop the pin after this many seconds; if not passed, it is held until
+    /// released.
+    pub ttl_secs: Option<u64>,
+}
+
+pub type ConfigureFailpointsRequest = Vec<FailpointConfig>;
+
+/// Information for configuring a single fail point
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FailpointConfig {
+    /// Name of the fail point
+    pub name: String,
+    /// List of actions to take, using the format described in `fail::cfg`,
+    /// e.g. "panic", "sleep(1000)", "return" or "off" to disable.
+    ///
+    /// We also support `actions = "exit"` to cause the fail point to immediately exit.
+    pub actions: String,
 }
 
 fn lsn_invalid() -> Lsn {
@@ -31,6 +73,8 @@ fn lsn_invalid() -> Lsn {
 #[serde_as]
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct SkTimelineInfo {
+    /// Current term of the safekeeper.
+    pub term: Option<u64>,
     /// Term of the last entry.
     pub last_log_term: Option<u64>,
     /// LSN of the last record.
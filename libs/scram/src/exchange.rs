@@ -41,14 +41,14 @@ enum ExchangeState {
 /// Server's side of SCRAM auth algorithm.
 pub struct Exchange<'a> {
     state: ExchangeState,
-    secret: &'a ServerSecret,
+    secret: ServerSecret,
     nonce: fn() -> [u8; SCRAM_RAW_NONCE_LEN],
     cert_digest: Option<&'a [u8]>,
 }
 
 impl<'a> Exchange<'a> {
     pub fn new(
-        secret: &'a ServerSecret,
+        secret: ServerSecret,
         nonce: fn() -> [u8; SCRAM_RAW_NONCE_LEN],
         cert_digest: Option<&'a [u8]>,
     ) -> Self {
@@ -1,4 +1,5 @@
-//! Salted Challenge Response Authentication Mechanism.
+//! Salted Challenge Response Authentication Mechanism, server side. Shared by
+//! the proxy and the postgres backends of `utils`.
 //!
 //! RFC: <https://datatracker.ietf.org/doc/html/rfc5802>.
 //!
@@ -10,13 +11,14 @@ mod exchange;
 mod key;
 mod messages;
 mod secret;
-mod signature;
 
-#[cfg(test)]
-mod password;
+pub mod password;
+pub mod sasl;
+pub mod signature;
 
 pub use exchange::Exchange;
 pub use key::ScramKey;
+pub use messages::SCRAM_RAW_NONCE_LEN;
 pub use secret::ServerSecret;
 pub use secret::*;
 
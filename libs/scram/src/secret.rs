@@ -5,6 +5,7 @@ use super::key::ScramKey;
 
 /// Server secret is produced from [password](super::password::SaltedPassword)
 /// and is used throughout the authentication process.
+#[derive(Clone)]
 pub struct ServerSecret {
     /// Number of iterations for `PBKDF2` function.
     pub iterations: u32,
@@ -56,8 +57,7 @@ impl ServerSecret {
     }
 
     /// Build a new server secret from the prerequisites.
-    /// XXX: We only use this function in tests.
-    #[cfg(test)]
+    /// XXX: We only use this function in tests, here and in dependent crates.
     pub fn build(password: &str, salt: &[u8], iterations: u32) -> Option<Self> {
         // TODO: implement proper password normalization required by the RFC
         if !password.is_ascii() {
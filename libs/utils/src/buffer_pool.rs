@@ -0,0 +1,196 @@
+//! Output buffers of postgres backends.
+//!
+//! A buffer grows to fit the largest message written into it, so after a
+//! large message has been flushed the buffer is shrunk back unless it is
+//! below the high watermark. Connections may also share a [`BufferPool`]:
+//! then the buffer is returned to the pool after every flush, and idle
+//! connections don't hold any output memory at all.
+//!
+//! Buffer sizes are set per connection with [`BufferConfig`], e.g. small for
+//! many mostly idle control connections and large for streaming WAL.
+
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+/// Initial capacity of an output buffer.
+pub const DEFAULT_BUFFER_CAPACITY: usize = 10 * 1024;
+/// Capacity of an input buffer, as of std and tokio `BufReader`.
+pub const DEFAULT_READ_BUFFER_CAPACITY: usize = 8 * 1024;
+/// Buffers larger than this are dropped after flush instead of being kept
+/// by the connection or the pool.
+pub const BUFFER_HIGH_WATERMARK: usize = 1024 * 1024;
+
+/// Buffer sizes of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferConfig {
+    /// Capacity of the input buffer; larger reads bypass it.
+    pub read_capacity: usize,
+    /// Initial capacity of the output buffer, also of buffers taken from a
+    /// pool.
+    pub write_capacity: usize,
+    /// Output buffer grown above this is shrunk back to `write_capacity`
+    /// after flush, or freed instead of being returned to a pool.
+    pub high_watermark: usize,
+}
+
+impl BufferConfig {
+    /// For connections exchanging a few small messages.
+    pub const SMALL: Self = BufferConfig {
+        read_capacity: 1024,
+        write_capacity: 1024,
+        high_watermark: 64 * 1024,
+    };
+
+    /// For connections streaming WAL or other bulk data.
+    pub const LARGE: Self = BufferConfig {
+        read_capacity: 128 * 1024,
+        write_capacity: 128 * 1024,
+        high_watermark: 16 * 1024 * 1024,
+    };
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            read_capacity: DEFAULT_READ_BUFFER_CAPACITY,
+            write_capacity: DEFAULT_BUFFER_CAPACITY,
+            high_watermark: BUFFER_HIGH_WATERMARK,
+        }
+    }
+}
+
+/// Bounded set of free buffers shared by connections.
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Create a pool keeping at most `max_buffers` free buffers.
+    pub fn new(max_buffers: usize) -> Self {
+        BufferPool {
+            buffers: Mutex::new(Vec::new()),
+            max_buffers,
+        }
+    }
+
+    /// Take a free buffer, or allocate a new one if there is none, with at
+    /// least `capacity`.
+    pub fn get(&self, capacity: usize) -> BytesMut {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buf.reserve(capacity);
+        buf
+    }
+
+    /// Return a buffer to the pool. Oversized buffers and buffers beyond
+    /// the pool size are freed.
+    pub fn put(&self, mut buf: BytesMut) {
+        if buf.capacity() == 0 || buf.capacity() > BUFFER_HIGH_WATERMARK {
+            return;
+        }
+        buf.clear();
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buf);
+        }
+    }
+
+    /// Number of free buffers in the pool.
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Make sure the buffer has memory before writing into it: a buffer
+/// released to the pool is replaced by one from the pool.
+pub(crate) fn acquire_buffer(buf: &mut BytesMut, pool: Option<&BufferPool>, config: &BufferConfig) {
+    if let Some(pool) = pool {
+        if buf.capacity() == 0 {
+            *buf = pool.get(config.write_capacity);
+        }
+    }
+}
+
+/// Called once the buffer has been written out: give it back to the pool,
+/// or shrink it if it has grown above the high watermark.
+pub(crate) fn release_buffer(buf: &mut BytesMut, pool: Option<&BufferPool>, config: &BufferConfig) {
+    match pool {
+        Some(pool) => {
+            let buf = std::mem::take(buf);
+            if buf.capacity() <= config.high_watermark {
+                pool.put(buf);
+            }
+        }
+        None => {
+            if buf.capacity() > config.high_watermark {
+                *buf = BytesMut::with_capacity(config.write_capacity);
+            } else {
+                buf.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shrink_after_large_message() {
+        let config = BufferConfig::default();
+        let mut buf = BytesMut::with_capacity(DEFAULT_BUFFER_CAPACITY);
+        buf.resize(4 * BUFFER_HIGH_WATERMARK, 0);
+        release_buffer(&mut buf, None, &config);
+        assert!(buf.is_empty());
+        assert_eq!(buf.capacity(), DEFAULT_BUFFER_CAPACITY);
+
+        buf.resize(100, 0);
+        release_buffer(&mut buf, None, &config);
+        assert!(buf.is_empty());
+        assert_eq!(buf.capacity(), DEFAULT_BUFFER_CAPACITY);
+
+        let config = BufferConfig::SMALL;
+        buf.resize(2 * config.high_watermark, 0);
+        release_buffer(&mut buf, None, &config);
+        assert_eq!(buf.capacity(), config.write_capacity);
+    }
+
+    #[test]
+    fn test_pool() {
+        let pool = BufferPool::new(1);
+        let config = BufferConfig::default();
+        let mut buf = BytesMut::new();
+        acquire_buffer(&mut buf, Some(&pool), &config);
+        assert!(buf.capacity() >= DEFAULT_BUFFER_CAPACITY);
+        buf.extend_from_slice(b"message");
+
+        release_buffer(&mut buf, Some(&pool), &config);
+        assert_eq!(buf.capacity(), 0);
+        assert_eq!(pool.len(), 1);
+
+        // reused buffer comes back empty, and grown to the config
+        acquire_buffer(&mut buf, Some(&pool), &BufferConfig::LARGE);
+        assert!(buf.is_empty());
+        assert!(buf.capacity() >= BufferConfig::LARGE.write_capacity);
+        assert!(pool.is_empty());
+
+        // buffers above the high watermark of the config are freed
+        let small = BufferConfig::SMALL;
+        buf.resize(2 * small.high_watermark, 0);
+        release_buffer(&mut buf, Some(&pool), &small);
+        assert!(pool.is_empty());
+
+        // pool is bounded and doesn't keep oversized buffers
+        pool.put(BytesMut::with_capacity(100));
+        pool.put(BytesMut::with_capacity(100));
+        assert_eq!(pool.len(), 1);
+        pool.get(0);
+        pool.put(BytesMut::with_capacity(2 * BUFFER_HIGH_WATERMARK));
+        assert!(pool.is_empty());
+    }
+}
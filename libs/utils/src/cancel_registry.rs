@@ -0,0 +1,128 @@
+//! Query cancellation for postgres backends.
+//!
+//! After authentication each connection is given a random [`CancelKeyData`]
+//! which the client learns from BackendKeyData. To cancel the running
+//! command, the client opens a new connection and sends CancelRequest with
+//! that key; the registry maps the key to the [`CancellationToken`] of the
+//! original connection, which its handler polls while processing the query.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
+use pq_proto::CancelKeyData;
+use tokio::sync::watch;
+
+/// Cancellation flag of the command running on a connection, usable both
+/// from sync and async code.
+#[derive(Clone)]
+pub struct CancellationToken(Arc<watch::Sender<bool>>);
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken(Arc::new(watch::channel(false).0))
+    }
+
+    pub fn cancel(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the token is cancelled.
+    pub async fn cancelled(&self) {
+        let mut rx = self.0.subscribe();
+        while !*rx.borrow() {
+            // can't fail, we hold the sender
+            let _ = rx.changed().await;
+        }
+    }
+
+    /// Forget about cancellation which arrived for the previous command.
+    pub(crate) fn reset(&self) {
+        self.0.send_replace(false);
+    }
+}
+
+static REGISTRY: Lazy<Mutex<HashMap<CancelKeyData, CancellationToken>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Registration of a connection, removed from the registry on drop.
+pub struct CancelRegistration {
+    key: CancelKeyData,
+    token: CancellationToken,
+}
+
+impl CancelRegistration {
+    pub fn key(&self) -> CancelKeyData {
+        self.key
+    }
+
+    pub fn token(&self) -> &CancellationToken {
+        &self.token
+    }
+}
+
+impl Drop for CancelRegistration {
+    fn drop(&mut self) {
+        REGISTRY.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Register a connection under a new random key.
+pub fn register() -> CancelRegistration {
+    let token = CancellationToken::new();
+    let mut registry = REGISTRY.lock().unwrap();
+    loop {
+        let key: CancelKeyData = rand::random();
+        if let std::collections::hash_map::Entry::Vacant(e) = registry.entry(key) {
+            e.insert(token.clone());
+            return CancelRegistration { key, token };
+        }
+    }
+}
+
+/// Cancel the command running on the connection with the key. Returns
+/// whether such a connection exists.
+pub fn cancel(key: &CancelKeyData) -> bool {
+    match REGISTRY.lock().unwrap().get(key) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let registration = register();
+        let other = register();
+        assert_ne!(registration.key(), other.key());
+
+        assert!(!registration.token().is_cancelled());
+        assert!(cancel(&registration.key()));
+        assert!(registration.token().is_cancelled());
+        assert!(!other.token().is_cancelled());
+        registration.token().cancelled().await;
+
+        registration.token().reset();
+        assert!(!registration.token().is_cancelled());
+
+        let key = registration.key();
+        drop(registration);
+        assert!(!cancel(&key));
+    }
+}
@@ -0,0 +1,234 @@
+//! Detection of clients disconnecting while their query is processed.
+//!
+//! Handlers of long-running commands often don't read from the client, so
+//! they would notice it is gone only on their next write. Instead, a single
+//! monitor thread polls sockets of the running queries for `POLLRDHUP`,
+//! which is reported when the peer closes the connection regardless of any
+//! unread data, and cancels their [`CancellationToken`]. Optionally it also
+//! cancels them once the service starts shutting down.
+//!
+//! `POLLRDHUP` is Linux specific; elsewhere only shutdown is detected.
+
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag, OFlag};
+use nix::poll::{poll, PollFd, PollFlags};
+use once_cell::sync::Lazy;
+use tracing::error;
+
+use crate::cancel_registry::CancellationToken;
+
+/// How often sockets are polled when some query also waits for shutdown.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the service is shutting down.
+pub type ShutdownCheck = fn() -> bool;
+
+struct Entry {
+    id: u64,
+    fd: RawFd,
+    token: CancellationToken,
+    shutdown: Option<ShutdownCheck>,
+    disconnected: Arc<AtomicBool>,
+}
+
+struct Monitor {
+    entries: Mutex<Vec<Entry>>,
+    /// Pipe to wake the monitor thread up on new entries.
+    wake_rx: RawFd,
+    wake_tx: RawFd,
+}
+
+static MONITOR: Lazy<Option<Monitor>> = Lazy::new(|| match start() {
+    Ok(monitor) => Some(monitor),
+    Err(e) => {
+        error!("failed to start client disconnect monitor: {e}");
+        None
+    }
+});
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn start() -> anyhow::Result<Monitor> {
+    let (wake_rx, wake_tx) = nix::unistd::pipe()?;
+    for fd in [wake_rx, wake_tx] {
+        fcntl(fd, FcntlArg::F_SETFL(OFlag::O_NONBLOCK))?;
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    thread::Builder::new()
+        .name("disconnect monitor".into())
+        .spawn(|| monitor_main(MONITOR.as_ref().unwrap()))?;
+    Ok(Monitor {
+        entries: Mutex::new(Vec::new()),
+        wake_rx,
+        wake_tx,
+    })
+}
+
+/// Watch of the socket while a query runs, stopped on drop.
+pub(crate) struct Watch {
+    id: Option<u64>,
+    disconnected: Arc<AtomicBool>,
+}
+
+impl Watch {
+    /// Whether the token was cancelled because the client disconnected.
+    pub(crate) fn disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if let (Some(id), Some(monitor)) = (self.id, MONITOR.as_ref()) {
+            monitor
+                .entries
+                .lock()
+                .unwrap()
+                .retain(|entry| entry.id != id);
+        }
+    }
+}
+
+/// Cancel `token` when the peer of the socket `fd` disconnects, or when
+/// `shutdown` reports shutdown, until the returned watch is dropped. The
+/// socket must stay open until then.
+pub(crate) fn watch(fd: RawFd, token: CancellationToken, shutdown: Option<ShutdownCheck>) -> Watch {
+    let disconnected = Arc::new(AtomicBool::new(false));
+    let Some(monitor) = MONITOR.as_ref() else {
+        return Watch {
+            id: None,
+            disconnected,
+        };
+    };
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    monitor.entries.lock().unwrap().push(Entry {
+        id,
+        fd,
+        token,
+        shutdown,
+        disconnected: Arc::clone(&disconnected),
+    });
+    // the thread may sleep in poll() without this socket
+    let _ = nix::unistd::write(monitor.wake_tx, &[0]);
+    Watch {
+        id: Some(id),
+        disconnected,
+    }
+}
+
+fn monitor_main(monitor: &Monitor) {
+    let mut fds = Vec::new();
+    let mut ids = Vec::new();
+    loop {
+        fds.clear();
+        ids.clear();
+        fds.push(PollFd::new(monitor.wake_rx, PollFlags::POLLIN));
+        let mut timeout = -1;
+        {
+            let mut entries = monitor.entries.lock().unwrap();
+            entries.retain(|entry| {
+                if entry.shutdown.map_or(false, |is_shutdown| is_shutdown()) {
+                    entry.token.cancel();
+                    return false;
+                }
+                true
+            });
+            for entry in entries.iter() {
+                #[cfg(target_os = "linux")]
+                fds.push(PollFd::new(entry.fd, PollFlags::POLLRDHUP));
+                #[cfg(not(target_os = "linux"))]
+                fds.push(PollFd::new(entry.fd, PollFlags::empty()));
+                ids.push(entry.id);
+                if entry.shutdown.is_some() {
+                    timeout = SHUTDOWN_POLL_INTERVAL.as_millis() as i32;
+                }
+            }
+        }
+
+        if let Err(e) = poll(&mut fds, timeout) {
+            if e != nix::errno::Errno::EINTR {
+                error!("disconnect monitor poll failed: {e}");
+                thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            }
+            continue;
+        }
+
+        if fds[0].revents().map_or(false, |r| !r.is_empty()) {
+            let mut buf = [0; 64];
+            while matches!(nix::unistd::read(monitor.wake_rx, &mut buf), Ok(n) if n > 0) {}
+        }
+        // POLLHUP and POLLERR are reported even if not asked for
+        let gone: Vec<u64> = fds[1..]
+            .iter()
+            .zip(&ids)
+            .filter(|(fd, _)| fd.revents().map_or(false, |r| !r.is_empty()))
+            .map(|(_, id)| *id)
+            .collect();
+        if !gone.is_empty() {
+            // entries removed meanwhile are skipped, their fds may be reused
+            monitor.entries.lock().unwrap().retain(|entry| {
+                if gone.contains(&entry.id) {
+                    entry.disconnected.store(true, Ordering::Relaxed);
+                    entry.token.cancel();
+                    return false;
+                }
+                true
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    fn wait_cancelled(token: &CancellationToken) {
+        let started = Instant::now();
+        while !token.is_cancelled() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_disconnect() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+
+        let token = CancellationToken::new();
+        let watch = watch(socket.as_raw_fd(), token.clone(), None);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!token.is_cancelled());
+        drop(client);
+        wait_cancelled(&token);
+        assert!(watch.disconnected());
+    }
+
+    #[test]
+    fn test_shutdown() {
+        static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+
+        let token = CancellationToken::new();
+        let watch = watch(
+            socket.as_raw_fd(),
+            token.clone(),
+            Some(|| SHUTDOWN.load(Ordering::Relaxed)),
+        );
+        SHUTDOWN.store(true, Ordering::Relaxed);
+        wait_cancelled(&token);
+        assert!(!watch.disconnected());
+    }
+}
@@ -0,0 +1,109 @@
+//! Hooks for observing traffic of postgres backend connections, e.g. to
+//! export per-connection or per-tenant network metrics.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use pq_proto::{BeMessage, FeMessage};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Observer of a connection, set with `PostgresBackend::set_io_observer`.
+/// Called inline on every read, write and message, so implementations
+/// should be cheap, e.g. bump counters.
+pub trait IoObserver: Send + Sync {
+    /// `n` bytes were read from the client.
+    fn bytes_read(&self, _n: usize) {}
+
+    /// `n` bytes were written to the client.
+    fn bytes_written(&self, _n: usize) {}
+
+    /// A message from the client was decoded.
+    fn message_read(&self, _msg: &FeMessage) {}
+
+    /// A message to the client was encoded into the output buffer.
+    fn message_written(&self, _msg: &BeMessage) {}
+}
+
+/// Reader reporting the number of bytes read to the observer.
+pub(crate) struct ObservedRead<'a, R> {
+    inner: &'a mut R,
+    observer: Option<&'a dyn IoObserver>,
+    bytes_read: usize,
+}
+
+impl<'a, R> ObservedRead<'a, R> {
+    pub(crate) fn new(inner: &'a mut R, observer: Option<&'a dyn IoObserver>) -> Self {
+        ObservedRead {
+            inner,
+            observer,
+            bytes_read: 0,
+        }
+    }
+
+    /// Total number of bytes read through this reader.
+    pub(crate) fn bytes_read(&self) -> usize {
+        self.bytes_read
+    }
+}
+
+impl<R: io::Read> io::Read for ObservedRead<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes_read += n;
+        if let Some(observer) = self.observer {
+            observer.bytes_read(n);
+        }
+        Ok(n)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ObservedRead<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let n = buf.filled().len() - filled;
+            this.bytes_read += n;
+            if let Some(observer) = this.observer {
+                observer.bytes_read(n);
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl IoObserver for Counter {
+        fn bytes_read(&self, n: usize) {
+            self.0.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_observed_read() {
+        let counter = Counter::default();
+        let mut data = &b"hello world"[..];
+        let mut reader = ObservedRead::new(&mut data, Some(&counter));
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 5);
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 11);
+        assert_eq!(reader.bytes_read(), 11);
+    }
+}
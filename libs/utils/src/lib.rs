@@ -15,6 +15,18 @@ pub mod vec_map;
 pub mod bin_ser;
 pub mod postgres_backend;
 pub mod postgres_backend_async;
+// output buffer shrinking and pooling for postgres backends
+pub mod buffer_pool;
+// traffic observation hooks for postgres backends
+pub mod io_observer;
+// server side of SCRAM password authentication for postgres backends
+pub mod scram_auth;
+// server side of md5 password authentication for postgres backends
+pub mod md5_auth;
+// query cancellation through CancelRequest for postgres backends
+pub mod cancel_registry;
+// cancellation of queries of disconnected clients for postgres backends
+pub mod disconnect_monitor;
 
 // helper functions for creating and fsyncing
 pub mod crashsafe;
@@ -40,7 +52,7 @@ pub mod pid_file;
 pub mod accum;
 pub mod shutdown;
 
-// Utility for binding TcpListeners with proper socket options.
+// Utility for binding TcpListeners and setting options of accepted sockets.
 pub mod tcp_listener;
 
 // Utility for putting a raw file descriptor into non-blocking mode
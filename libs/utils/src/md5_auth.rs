@@ -0,0 +1,125 @@
+//! Server side of md5 password authentication, for clients which can't do
+//! SCRAM or JWT.
+//!
+//! Secrets are in the format Postgres keeps in `pg_authid`: `md5` followed
+//! by the hex md5 of the password concatenated with the user name. The
+//! client proves it knows the password by sending `md5` followed by the hex
+//! md5 of the secret's hash concatenated with the random salt of the
+//! connection.
+
+use anyhow::{ensure, Context};
+use rand::RngCore;
+
+const MD5_PREFIX: &str = "md5";
+
+/// Compute the secret of the user's password.
+pub fn md5_secret(user: &str, password: &str) -> String {
+    format!(
+        "{MD5_PREFIX}{}",
+        md5_hex(&[password.as_bytes(), user.as_bytes()])
+    )
+}
+
+/// Decode the hash of an `md5<32 hex digits>` secret.
+pub fn parse_md5(input: &str) -> Option<[u8; 16]> {
+    let text = input.strip_prefix(MD5_PREFIX)?;
+
+    let mut bytes = [0u8; 16];
+    hex::decode_to_slice(text, &mut bytes).ok()?;
+
+    Some(bytes)
+}
+
+/// Check that the secret is in the `md5<32 hex digits>` format.
+pub fn parse_md5_secret(secret: &str) -> anyhow::Result<&str> {
+    parse_md5(secret).context("invalid md5 secret")?;
+    Ok(secret)
+}
+
+/// Response the client sends to AuthenticationMD5Password with `salt`.
+pub fn md5_response(secret: &str, salt: &[u8; 4]) -> String {
+    let hash = secret.strip_prefix(MD5_PREFIX).unwrap_or(secret);
+    format!("{MD5_PREFIX}{}", md5_hex(&[hash.as_bytes(), salt]))
+}
+
+/// Salt for a new connection.
+pub fn generate_salt() -> [u8; 4] {
+    let mut salt = [0; 4];
+    rand::thread_rng().fill_bytes(&mut salt);
+    salt
+}
+
+/// md5 authentication of one connection.
+pub struct Md5Exchange {
+    salt: [u8; 4],
+    /// None if there is no such user; authentication fails then, but only
+    /// after the client has sent the password, to not reveal that.
+    secret: Option<String>,
+}
+
+impl Md5Exchange {
+    pub fn new(secret: Option<String>) -> Self {
+        Md5Exchange {
+            salt: generate_salt(),
+            secret,
+        }
+    }
+
+    /// Salt to send in AuthenticationMD5Password.
+    pub fn salt(&self) -> [u8; 4] {
+        self.salt
+    }
+
+    /// Check the response of the client, the PasswordMessage body without
+    /// the null terminator.
+    pub fn check(&self, response: &str) -> anyhow::Result<()> {
+        let secret = self.secret.as_deref().context("no such user")?;
+        let expected = md5_response(secret, &self.salt);
+        ensure!(
+            constant_time_eq(expected.as_bytes(), response.as_bytes()),
+            "wrong password"
+        );
+        Ok(())
+    }
+}
+
+fn md5_hex(parts: &[&[u8]]) -> String {
+    let mut context = md5::Context::new();
+    for part in parts {
+        context.consume(part);
+    }
+    format!("{:x}", context.compute())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_md5_exchange() {
+        // as computed by Postgres
+        let secret = md5_secret("postgres", "secret");
+        assert_eq!(secret, "md553f48b7c4b76a86ce72276c5755f217d");
+        assert_eq!(parse_md5_secret(&secret).unwrap(), secret);
+        assert!(parse_md5_secret("md5xyz").is_err());
+
+        let exchange = Md5Exchange::new(Some(secret.clone()));
+        let response = md5_response(&secret, &exchange.salt());
+        exchange.check(&response).unwrap();
+        assert!(exchange
+            .check(&md5_response(
+                &md5_secret("postgres", "wrong"),
+                &exchange.salt()
+            ))
+            .is_err());
+
+        let exchange = Md5Exchange::new(None);
+        assert!(exchange
+            .check(&md5_response(&secret, &exchange.salt()))
+            .is_err());
+    }
+}
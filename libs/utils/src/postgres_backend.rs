@@ -3,18 +3,38 @@
 //! implementation determining how to process the queries. Currently its API
 //! is rather narrow, but we can extend it once required.
 
-use crate::postgres_backend_async::{log_query_error, short_error, QueryError};
+use crate::buffer_pool::{
+    acquire_buffer, release_buffer, BufferConfig, BufferPool, DEFAULT_BUFFER_CAPACITY,
+};
+use crate::cancel_registry::{self, CancelRegistration, CancellationToken};
+use crate::disconnect_monitor::{self, ShutdownCheck};
+use crate::io_observer::{IoObserver, ObservedRead};
+use crate::md5_auth::Md5Exchange;
+use crate::postgres_backend_async::{
+    copy_disconnected_error, copy_fail_error, idle_timed_out_error, log_query_error,
+    query_disconnected_error, shutdown_error, write_timed_out_error, CopyMode, QueryError,
+    DEFAULT_FLUSH_THRESHOLD, SHUTDOWN_FLUSH_TIMEOUT, SQLSTATE_INVALID_PASSWORD,
+};
+use crate::scram_auth::{ScramExchange, ScramStep, ServerSecret, METHODS as SCRAM_METHODS};
 use crate::sock_split::{BidiStream, ReadStream, WriteStream};
 use anyhow::Context;
-use bytes::{Bytes, BytesMut};
-use pq_proto::{BeMessage, FeMessage, FeStartupPacket};
+use bytes::{Buf, Bytes, BytesMut};
+#[cfg(feature = "pq-trace")]
+use pq_proto::trace::MessageTracer;
+use pq_proto::{
+    parse_password_message,
+    pg_error::{PgError, Severity, SqlState},
+    BeAuthenticationSaslMessage, BeMessage, CancelKeyData, FeMessage, FeStartupPacket,
+    MessageLimits, ReplicationMode, TransactionStatus, WriteQueue, STANDARD_PARAMETERS,
+};
 use serde::{Deserialize, Serialize};
 use std::fmt;
 use std::io::{self, Write};
 use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::*;
 
 pub trait Handler {
@@ -22,10 +42,20 @@ pub trait Handler {
     /// postgres_backend will issue ReadyForQuery after calling this (this
     /// might be not what we want after CopyData streaming, but currently we don't
     /// care).
+    ///
+    /// `cancel` is cancelled when the client sends CancelRequest for the
+    /// query, disconnects or, with [`PostgresBackend::set_shutdown_check`],
+    /// the service shuts down; long-running handlers should poll it and
+    /// return [`QueryError::Cancelled`].
+    ///
+    /// ReadyForQuery reports the status set with
+    /// [`PostgresBackend::set_transaction_status`] by handlers implementing
+    /// transaction blocks; an error inside a block marks it failed.
     fn process_query(
         &mut self,
         pgb: &mut PostgresBackend,
         query_string: &str,
+        cancel: &CancellationToken,
     ) -> Result<(), QueryError>;
 
     /// Called on startup packet receival, allows to process params.
@@ -33,6 +63,9 @@ pub trait Handler {
     /// If Ok(false) is returned postgres_backend will skip auth -- that is needed for new users
     /// creation is the proxy code. That is quite hacky and ad-hoc solution, may be we could allow
     /// to override whole init logic in implementations.
+    ///
+    /// The `replication` parameter has already been validated, see
+    /// [`PostgresBackend::replication_mode`].
     fn startup(
         &mut self,
         _pgb: &mut PostgresBackend,
@@ -50,11 +83,53 @@ pub trait Handler {
         Err(QueryError::Other(anyhow::anyhow!("JWT auth failed")))
     }
 
+    /// Get SCRAM secret of the user for password auth, None if there is no
+    /// such user.
+    fn get_scram_secret(
+        &mut self,
+        _pgb: &mut PostgresBackend,
+        _user: &str,
+    ) -> Result<Option<ServerSecret>, QueryError> {
+        Ok(None)
+    }
+
+    /// Get md5 secret of the user for md5 password auth, see
+    /// [`crate::md5_auth`]; None if there is no such user.
+    fn get_md5_secret(
+        &mut self,
+        _pgb: &mut PostgresBackend,
+        _user: &str,
+    ) -> Result<Option<String>, QueryError> {
+        Ok(None)
+    }
+
     fn is_shutdown_requested(&self) -> bool {
         false
     }
 }
 
+/// ALPN protocol clients must negotiate with direct TLS.
+pub const PG_ALPN_PROTOCOL: &[u8] = b"postgresql";
+
+/// Type of TLS handshake records, the first byte of ClientHello. It can't
+/// start a startup packet: that would mean length of over 350MB.
+pub(crate) const TLS_HANDSHAKE_RECORD_TYPE: u8 = 0x16;
+
+/// Server config for direct TLS, advertising the postgres ALPN protocol.
+/// The listener config is reused if it advertises the protocol already.
+pub(crate) fn direct_tls_config(config: &Arc<rustls::ServerConfig>) -> Arc<rustls::ServerConfig> {
+    if config
+        .alpn_protocols
+        .iter()
+        .any(|protocol| protocol == PG_ALPN_PROTOCOL)
+    {
+        return Arc::clone(config);
+    }
+    let mut config = rustls::ServerConfig::clone(config);
+    config.alpn_protocols.push(PG_ALPN_PROTOCOL.to_vec());
+    Arc::new(config)
+}
+
 /// PostgresBackend protocol state.
 /// XXX: The order of the constructors matters.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd)]
@@ -70,6 +145,10 @@ pub enum AuthType {
     Trust,
     // This mimics postgres's AuthenticationCleartextPassword but instead of password expects JWT
     NeonJWT,
+    // SCRAM-SHA-256 password auth, the password is never sent
+    Scram,
+    // md5 password auth, for clients which can do neither JWT nor SCRAM
+    Md5,
 }
 
 impl FromStr for AuthType {
@@ -79,6 +158,8 @@ impl FromStr for AuthType {
         match s {
             "Trust" => Ok(Self::Trust),
             "NeonJWT" => Ok(Self::NeonJWT),
+            "Scram" => Ok(Self::Scram),
+            "Md5" => Ok(Self::Md5),
             _ => anyhow::bail!("invalid value \"{s}\" for auth type"),
         }
     }
@@ -89,6 +170,8 @@ impl fmt::Display for AuthType {
         f.write_str(match self {
             AuthType::Trust => "Trust",
             AuthType::NeonJWT => "NeonJWT",
+            AuthType::Scram => "Scram",
+            AuthType::Md5 => "Md5",
         })
     }
 }
@@ -133,8 +216,31 @@ impl io::Write for Stream {
 
 pub struct PostgresBackend {
     stream: Option<Stream>,
-    // Output buffer. c.f. BeMessage::write why we are using BytesMut here.
-    buf_out: BytesMut,
+    /// The socket of `stream`, to set write timeouts on.
+    socket: TcpStream,
+    /// Writes fail if the peer doesn't read anything for this long.
+    write_stall_timeout: Option<Duration>,
+    /// The connection is closed if the client sends no message for this long
+    /// while the backend waits for one.
+    idle_timeout: Option<Duration>,
+    /// How often to check for shutdown while waiting for a message.
+    shutdown_poll_interval: Option<Duration>,
+    /// Cancels running queries on shutdown.
+    shutdown_check: Option<ShutdownCheck>,
+    /// Buffered output above this is flushed before writing more.
+    flush_threshold: Option<usize>,
+    /// Whether `write_message` only buffers, set while a simple query runs
+    /// so that its small result and ReadyForQuery go out in one write.
+    defer_flush: bool,
+    // Output buffer, large payloads are queued in it without copying.
+    buf_out: WriteQueue,
+    /// Pool to return `buf_out` to after flush, if shared with other connections.
+    buffer_pool: Option<Arc<BufferPool>>,
+    /// Buffer sizes, with the read capacity in effect.
+    buffer_config: BufferConfig,
+    io_observer: Option<Arc<dyn IoObserver>>,
+    #[cfg(feature = "pq-trace")]
+    tracer: Option<MessageTracer>,
 
     pub state: ProtoState,
 
@@ -142,6 +248,27 @@ pub struct PostgresBackend {
 
     peer_addr: SocketAddr,
     pub tls_config: Option<Arc<rustls::ServerConfig>>,
+    /// Accept TLS started right away with ClientHello (Postgres 17 direct
+    /// SSL negotiation), in addition to SSLRequest.
+    pub allow_direct_tls: bool,
+    /// Messages longer than these limits close the connection.
+    pub message_limits: MessageLimits,
+
+    /// SCRAM exchange in progress, if authenticating with it.
+    scram: Option<ScramExchange>,
+    /// md5 password exchange in progress, if authenticating with it.
+    md5: Option<Md5Exchange>,
+
+    /// Registration for CancelRequest, made once the connection is established.
+    cancel: Option<CancelRegistration>,
+    /// Reported in ReadyForQuery.
+    transaction_status: TransactionStatus,
+    /// Requested with the `replication` startup parameter.
+    replication_mode: ReplicationMode,
+
+    /// Run-time parameters reported in ParameterStatus in addition to or
+    /// instead of the standard ones.
+    parameters: Vec<(String, String)>,
 }
 
 pub fn query_from_cstring(query_string: Bytes) -> Vec<u8> {
@@ -187,12 +314,32 @@ impl PostgresBackend {
         }
 
         Ok(Self {
+            socket: socket.try_clone()?,
+            write_stall_timeout: None,
+            idle_timeout: None,
+            shutdown_poll_interval: None,
+            shutdown_check: None,
+            flush_threshold: Some(DEFAULT_FLUSH_THRESHOLD),
+            defer_flush: false,
             stream: Some(Stream::Bidirectional(BidiStream::from_tcp(socket))),
-            buf_out: BytesMut::with_capacity(10 * 1024),
+            buf_out: WriteQueue::with_capacity(DEFAULT_BUFFER_CAPACITY),
+            buffer_pool: None,
+            buffer_config: BufferConfig::default(),
+            io_observer: None,
+            #[cfg(feature = "pq-trace")]
+            tracer: None,
             state: ProtoState::Initialization,
             auth_type,
             tls_config,
+            allow_direct_tls: false,
+            message_limits: MessageLimits::default(),
             peer_addr,
+            scram: None,
+            md5: None,
+            cancel: None,
+            transaction_status: TransactionStatus::Idle,
+            replication_mode: ReplicationMode::Off,
+            parameters: Vec::new(),
         })
     }
 
@@ -208,10 +355,183 @@ impl PostgresBackend {
         }
     }
 
+    /// Set buffer sizes of the connection. The read buffer is resized only
+    /// before TLS is started, keeping data the client already sent; the
+    /// output buffer while empty.
+    /// With a shared buffer pool, buffers taken from it get the write capacity.
+    pub fn set_buffer_config(&mut self, config: BufferConfig) {
+        let resized = match &mut self.stream {
+            Some(Stream::Bidirectional(stream)) => stream.set_read_capacity(config.read_capacity),
+            _ => false,
+        };
+        let read_capacity = if resized {
+            config.read_capacity
+        } else {
+            self.buffer_config.read_capacity
+        };
+        if self.buffer_pool.is_none() && self.buf_out.is_empty() {
+            *self.buf_out.buffer_mut() = BytesMut::with_capacity(config.write_capacity);
+        }
+        self.buffer_config = BufferConfig {
+            read_capacity,
+            ..config
+        };
+    }
+
+    /// Current capacities of the read and the output buffer, for metrics.
+    /// The output buffer grows to fit the largest message until flushed.
+    pub fn buffer_capacities(&self) -> (usize, usize) {
+        (self.buffer_config.read_capacity, self.buf_out.capacity())
+    }
+
+    /// Share output buffers with other connections through the pool.
+    pub fn set_buffer_pool(&mut self, pool: Arc<BufferPool>) {
+        if self.buf_out.is_empty() {
+            release_buffer(self.buf_out.buffer_mut(), Some(&pool), &self.buffer_config);
+        }
+        self.buffer_pool = Some(pool);
+    }
+
+    /// Report traffic of the connection to the observer.
+    pub fn set_io_observer(&mut self, observer: Arc<dyn IoObserver>) {
+        self.io_observer = Some(observer);
+    }
+
+    /// Trace every message of the connection, see [`MessageTracer`].
+    #[cfg(feature = "pq-trace")]
+    pub fn set_message_tracer(&mut self, tracer: MessageTracer) {
+        self.tracer = Some(tracer);
+    }
+
+    #[cfg(feature = "pq-trace")]
+    fn trace_frontend(&self, msg: &FeMessage, len: usize) {
+        if let Some(tracer) = &self.tracer {
+            tracer.frontend(msg, len);
+        }
+    }
+
+    #[cfg(not(feature = "pq-trace"))]
+    fn trace_frontend(&self, _msg: &FeMessage, _len: usize) {}
+
+    #[cfg(feature = "pq-trace")]
+    fn trace_backend(&self, msg: &BeMessage, start: usize) {
+        if let Some(tracer) = &self.tracer {
+            tracer.backend(msg, &self.buf_out, start);
+        }
+    }
+
+    #[cfg(not(feature = "pq-trace"))]
+    fn trace_backend(&self, _msg: &BeMessage, _start: usize) {}
+
     pub fn get_peer_addr(&self) -> &SocketAddr {
         &self.peer_addr
     }
 
+    /// Token cancelled when the client sends CancelRequest for the command
+    /// being processed. It is the token passed to `Handler::process_query`,
+    /// also cancelled on disconnect and shutdown.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        match &self.cancel {
+            Some(registration) => registration.token().clone(),
+            None => CancellationToken::new(),
+        }
+    }
+
+    /// Report a run-time parameter to the client in ParameterStatus, e.g.
+    /// `server_version` to override the default one. Parameters set before
+    /// the connection is established, e.g. in `Handler::startup`, are
+    /// reported along with the standard ones right after AuthenticationOk.
+    pub fn set_parameter_status(&mut self, name: &str, value: &str) -> io::Result<()> {
+        if self.state == ProtoState::Established {
+            self.write_message_noflush(&BeMessage::parameter_status(name, value))?;
+        }
+        match self.parameters.iter_mut().find(|(set, _)| set == name) {
+            Some((_, old_value)) => *old_value = value.to_owned(),
+            None => self.parameters.push((name.to_owned(), value.to_owned())),
+        }
+        Ok(())
+    }
+
+    /// Write ParameterStatus of the standard and set parameters, which
+    /// follows AuthenticationOk.
+    fn write_parameter_statuses(&mut self) -> io::Result<()> {
+        let parameters = std::mem::take(&mut self.parameters);
+        for (name, value) in STANDARD_PARAMETERS {
+            if !parameters.iter().any(|(set, _)| set == name) {
+                self.write_message_noflush(&BeMessage::parameter_status(name, value))?;
+            }
+        }
+        for (name, value) in &parameters {
+            self.write_message_noflush(&BeMessage::parameter_status(name, value))?;
+        }
+        self.parameters = parameters;
+        Ok(())
+    }
+
+    /// Register the connection for cancellation and return the key to be
+    /// reported to the client in BackendKeyData.
+    fn register_cancel_key(&mut self) -> CancelKeyData {
+        let registration = cancel_registry::register();
+        let key = registration.key();
+        self.cancel = Some(registration);
+        key
+    }
+
+    /// Forget about CancelRequest which arrived for the previous command.
+    fn reset_cancellation(&self) {
+        if let Some(registration) = &self.cancel {
+            registration.token().reset();
+        }
+    }
+
+    /// Run the query, with a cancellation token also cancelled when the
+    /// client disconnects or the service shuts down. Fails if the client
+    /// disconnected, otherwise returns the result of the handler.
+    fn run_query(
+        &mut self,
+        handler: &mut impl Handler,
+        query_string: &str,
+    ) -> Result<Result<(), QueryError>, QueryError> {
+        self.reset_cancellation();
+        let cancel = self.cancellation_token();
+        let watch =
+            disconnect_monitor::watch(self.socket.as_raw_fd(), cancel.clone(), self.shutdown_check);
+        let res = handler.process_query(self, query_string, &cancel);
+        if watch.disconnected() {
+            return Err(query_disconnected_error());
+        }
+        if res.is_err() {
+            self.fail_transaction();
+        }
+        Ok(res)
+    }
+
+    /// Replication mode the client asked for in the startup packet: handlers
+    /// should accept replication commands only if it is not
+    /// [`ReplicationMode::Off`], and SQL only if it is not
+    /// [`ReplicationMode::Physical`].
+    pub fn replication_mode(&self) -> ReplicationMode {
+        self.replication_mode
+    }
+
+    /// Transaction status reported in the next ReadyForQuery.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    /// Set the transaction status, e.g. on BEGIN, COMMIT or when the
+    /// transaction block fails without the query returning an error.
+    pub fn set_transaction_status(&mut self, status: TransactionStatus) {
+        self.transaction_status = status;
+    }
+
+    /// A query failed: a transaction block it was part of is aborted.
+    fn fail_transaction(&mut self) {
+        if self.transaction_status == TransactionStatus::InTransaction {
+            self.transaction_status = TransactionStatus::Failed;
+        }
+    }
+
     pub fn take_stream_in(&mut self) -> Option<ReadStream> {
         let stream = self.stream.take();
         match stream {
@@ -229,36 +549,239 @@ impl PostgresBackend {
 
     /// Read full message or return None if connection is closed.
     pub fn read_message(&mut self) -> Result<Option<FeMessage>, QueryError> {
-        let (state, stream) = (self.state, self.get_stream_in()?);
+        // the client may wait for the deferred output before sending more
+        if self.defer_flush {
+            self.flush()?;
+        }
+        let (state, limits) = (self.state, self.message_limits);
+        let observer = self.io_observer.clone();
+        let stream = &mut ObservedRead::new(self.get_stream_in()?, observer.as_deref());
 
         use ProtoState::*;
-        match state {
-            Initialization | Encrypted => FeStartupPacket::read(stream),
-            Authentication | Established => FeMessage::read(stream),
+        let msg = match state {
+            Initialization | Encrypted => {
+                FeStartupPacket::read_limited(stream, limits.max_startup_message_len)
+            }
+            Authentication | Established => FeMessage::read_limited(stream, limits.max_message_len),
+        }?;
+        let len = stream.bytes_read();
+        if let Some(msg) = &msg {
+            if let Some(observer) = &observer {
+                observer.message_read(msg);
+            }
+            self.trace_frontend(msg, len);
+        }
+        Ok(msg)
+    }
+
+    /// Flush output once this much of it is buffered, see
+    /// [`Self::write_message_noflush`]. None lets the buffer grow until
+    /// explicit flush.
+    pub fn set_flush_threshold(&mut self, threshold: Option<usize>) {
+        self.flush_threshold = threshold;
+    }
+
+    /// Whether the output buffer has grown above the flush threshold.
+    pub fn needs_flush(&self) -> bool {
+        match self.flush_threshold {
+            Some(threshold) => self.buf_out.remaining() >= threshold,
+            None => false,
         }
-        .map_err(QueryError::from)
     }
 
-    /// Write message into internal output buffer.
+    /// Write message into internal output buffer. The buffer is flushed
+    /// first if it has grown above the flush threshold, so that writing
+    /// many messages before flush doesn't buffer all of them.
+    ///
+    /// Note that this means it can write to the socket, blocking on a client
+    /// which doesn't read and failing with socket errors, which it never did
+    /// before the threshold was added. Callers which must not touch the
+    /// socket should disable the threshold with
+    /// [`Self::set_flush_threshold`]`(None)`.
     pub fn write_message_noflush(&mut self, message: &BeMessage) -> io::Result<&mut Self> {
-        BeMessage::write(&mut self.buf_out, message)?;
+        if self.needs_flush() {
+            self.flush()?;
+        }
+        acquire_buffer(
+            self.buf_out.buffer_mut(),
+            self.buffer_pool.as_deref(),
+            &self.buffer_config,
+        );
+        let start = self.buf_out.remaining();
+        self.buf_out.write_message(message)?;
+        if let Some(observer) = &self.io_observer {
+            observer.message_written(message);
+        }
+        self.trace_backend(message, start);
         Ok(self)
     }
 
+    /// Make writes fail with `TimedOut` when the peer doesn't read anything
+    /// for `timeout`, instead of blocking until it does. None disables the
+    /// timeout.
+    pub fn set_write_stall_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.socket.set_write_timeout(timeout)?;
+        self.write_stall_timeout = timeout;
+        Ok(())
+    }
+
+    /// Close the connection when the client sends no message for `timeout`
+    /// while the message loop waits for one. Reads done by the handler
+    /// during a query are not affected. None disables the timeout.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Check [`Handler::is_shutdown_requested`] at least this often while
+    /// the message loop waits for a message, instead of only when one
+    /// arrives. On shutdown the client gets a FATAL admin_shutdown error
+    /// after the message being processed.
+    pub fn set_shutdown_poll_interval(&mut self, interval: Option<Duration>) {
+        self.shutdown_poll_interval = interval;
+    }
+
+    /// Cancel running queries once `is_shutdown` returns true, which is
+    /// checked periodically; usually the same check as
+    /// [`Handler::is_shutdown_requested`].
+    pub fn set_shutdown_check(&mut self, is_shutdown: Option<ShutdownCheck>) {
+        self.shutdown_check = is_shutdown;
+    }
+
     /// Flush output buffer into the socket.
     pub fn flush(&mut self) -> io::Result<&mut Self> {
+        self.flush_until(None)
+    }
+
+    /// Flush output buffer into the socket, failing with `TimedOut` if it
+    /// isn't written out within `timeout`.
+    pub fn flush_with_timeout(&mut self, timeout: Duration) -> io::Result<&mut Self> {
+        let res = self.flush_until(Some(Instant::now() + timeout));
+        self.socket.set_write_timeout(self.write_stall_timeout)?;
+        res
+    }
+
+    fn flush_until(&mut self, deadline: Option<Instant>) -> io::Result<&mut Self> {
         let stream = self.stream.as_mut().unwrap();
-        stream.write_all(&self.buf_out)?;
-        self.buf_out.clear();
+        while self.buf_out.has_remaining() {
+            if let Some(deadline) = deadline {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Err(write_timed_out_error());
+                }
+                let timeout = self.write_stall_timeout.map_or(left, |t| t.min(left));
+                self.socket.set_write_timeout(Some(timeout))?;
+            }
+            match stream.write(self.buf_out.chunk()) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(bytes_written) => {
+                    self.buf_out.advance(bytes_written);
+                    if let Some(observer) = &self.io_observer {
+                        observer.bytes_written(bytes_written);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // socket write timeout is reported as EAGAIN
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    return Err(write_timed_out_error());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        release_buffer(
+            self.buf_out.buffer_mut(),
+            self.buffer_pool.as_deref(),
+            &self.buffer_config,
+        );
         Ok(self)
     }
 
-    /// Write message into internal buffer and flush it.
+    /// Write message into internal buffer and flush it. While a simple
+    /// query is processed the flush is deferred until ReadyForQuery, unless
+    /// the query switches to COPY mode.
     pub fn write_message(&mut self, message: &BeMessage) -> io::Result<&mut Self> {
         self.write_message_noflush(message)?;
+        self.flush_unless_deferred()
+    }
+
+    /// Write messages into internal buffer and flush them at once, e.g. a
+    /// whole small result set.
+    pub fn write_messages(&mut self, messages: &[BeMessage]) -> io::Result<&mut Self> {
+        for message in messages {
+            self.write_message_noflush(message)?;
+        }
+        self.flush_unless_deferred()
+    }
+
+    fn flush_unless_deferred(&mut self) -> io::Result<&mut Self> {
+        if self.defer_flush {
+            return Ok(self);
+        }
+        self.flush()
+    }
+
+    /// Send a NoticeResponse, e.g. about a non-fatal condition during a long
+    /// command. It is flushed right away, also in COPY mode: clients accept
+    /// notices between any messages.
+    pub fn send_notice(&mut self, message: &str) -> io::Result<&mut Self> {
+        self.write_message_noflush(&BeMessage::NoticeResponse(message))?;
+        self.flush()
+    }
+
+    /// Send a NotificationResponse on `channel`, flushed right away like
+    /// [`Self::send_notice`].
+    pub fn send_notification(&mut self, channel: &str, payload: &str) -> io::Result<&mut Self> {
+        let process_id = self
+            .cancel
+            .as_ref()
+            .map_or(0, |registration| registration.key().backend_pid);
+        self.write_message_noflush(&BeMessage::NotificationResponse {
+            process_id,
+            channel,
+            payload,
+        })?;
         self.flush()
     }
 
+    /// Switch the connection to COPY mode.
+    pub fn start_copy(&mut self, mode: CopyMode) -> io::Result<&mut Self> {
+        // COPY data is streamed, don't hold it until the query finishes
+        self.defer_flush = false;
+        self.write_message(&mode.response())
+    }
+
+    /// End COPY OUT (or the server side of COPY BOTH) with CopyDone.
+    pub fn finish_copy_out(&mut self) -> io::Result<&mut Self> {
+        self.write_message(&BeMessage::CopyDone)
+    }
+
+    /// Read the next chunk of data in COPY IN or COPY BOTH mode. Returns
+    /// None once the client sends CopyDone; CopyFail from the client and
+    /// messages not allowed during COPY are errors.
+    pub fn read_copy_data(&mut self) -> Result<Option<Bytes>, QueryError> {
+        loop {
+            match self.read_message()? {
+                Some(FeMessage::CopyData(data)) => return Ok(Some(data)),
+                Some(FeMessage::CopyDone) => return Ok(None),
+                Some(FeMessage::CopyFail(msg)) => return Err(copy_fail_error(&msg)),
+                // Allowed by the protocol and ignored.
+                Some(FeMessage::Sync | FeMessage::Flush) => continue,
+                Some(FeMessage::Terminate) | None => {
+                    return Err(copy_disconnected_error(
+                        "client closed connection during COPY",
+                    ))
+                }
+                Some(msg) => {
+                    return Err(QueryError::Other(anyhow::anyhow!(
+                        "unexpected message {msg:?} during COPY"
+                    )))
+                }
+            }
+        }
+    }
+
     // Wrapper for run_message_loop() that shuts down socket when we are done
     pub fn run(mut self, handler: &mut impl Handler) -> Result<(), QueryError> {
         let ret = self.run_message_loop(handler);
@@ -271,13 +794,58 @@ impl PostgresBackend {
     fn run_message_loop(&mut self, handler: &mut impl Handler) -> Result<(), QueryError> {
         trace!("postgres backend to {:?} started", self.peer_addr);
 
+        if self.allow_direct_tls
+            && self.tls_config.is_some()
+            && self.state == ProtoState::Initialization
+            && self.starts_with_tls_handshake()?
+        {
+            self.start_direct_tls()?;
+        }
+
         let mut unnamed_query_string = Bytes::new();
 
+        // read timeout of the socket, to restore after waiting for a message
+        // with the idle timeout or shutdown polling
+        let read_timeout = self.socket.read_timeout()?;
+        let mut idle_since = Instant::now();
+
         while !handler.is_shutdown_requested() {
-            match self.read_message() {
+            // wake up in time, but keep polling as often as the socket read
+            // timeout asks
+            let mut wait = read_timeout;
+            if let Some(timeout) = self.idle_timeout {
+                let left = timeout.saturating_sub(idle_since.elapsed());
+                if left.is_zero() {
+                    return Err(idle_timed_out_error(timeout));
+                }
+                wait = Some(wait.map_or(left, |t| t.min(left)));
+            }
+            if let Some(interval) = self.shutdown_poll_interval {
+                wait = Some(wait.map_or(interval, |t| t.min(interval)));
+            }
+            if wait != read_timeout {
+                // wait for the message without reading it, as a read timing
+                // out in the middle of the message would lose its start
+                self.socket.set_read_timeout(wait)?;
+                let res = self.get_stream_in()?.wait_readable();
+                self.socket.set_read_timeout(read_timeout)?;
+                match res {
+                    Ok(()) => {}
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        continue
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            let message = self.read_message();
+            match message {
                 Ok(message) => {
                     if let Some(msg) = message {
                         trace!("got message {msg:?}");
+                        idle_since = Instant::now();
 
                         match self.process_message(handler, msg, &mut unnamed_query_string)? {
                             ProcessMsgResult::Continue => continue,
@@ -297,17 +865,64 @@ impl PostgresBackend {
                 }
             }
         }
+        if handler.is_shutdown_requested() {
+            info!("shutdown request received in run_message_loop");
+            self.send_shutdown_error();
+        }
 
         trace!("postgres backend to {:?} exited", self.peer_addr);
         Ok(())
     }
 
+    /// Tell the client that the connection is closed because of shutdown.
+    /// Failures are ignored, the connection is closed anyway.
+    fn send_shutdown_error(&mut self) {
+        let error = shutdown_error();
+        if self
+            .write_message_noflush(&BeMessage::PgError(&error))
+            .is_ok()
+        {
+            let _ = self.flush_with_timeout(SHUTDOWN_FLUSH_TIMEOUT);
+        }
+    }
+
     pub fn start_tls(&mut self) -> anyhow::Result<()> {
+        self.accept_tls(self.tls_config.clone().unwrap())?;
+        Ok(())
+    }
+
+    /// Whether the client started with TLS ClientHello instead of a
+    /// startup packet.
+    fn starts_with_tls_handshake(&mut self) -> io::Result<bool> {
+        match &mut self.stream {
+            Some(Stream::Bidirectional(stream)) => {
+                Ok(stream.peek_byte()? == Some(TLS_HANDSHAKE_RECORD_TYPE))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Accept direct TLS, which requires the postgres ALPN protocol.
+    fn start_direct_tls(&mut self) -> anyhow::Result<()> {
+        debug!("direct TLS requested");
+        let config = direct_tls_config(self.tls_config.as_ref().unwrap());
+        let alpn = self.accept_tls(config)?;
+        if alpn.as_deref() != Some(PG_ALPN_PROTOCOL) {
+            anyhow::bail!("direct TLS connection did not negotiate postgresql ALPN");
+        }
+        self.state = ProtoState::Encrypted;
+        Ok(())
+    }
+
+    /// Do TLS handshake, returning the negotiated ALPN protocol.
+    fn accept_tls(&mut self, config: Arc<rustls::ServerConfig>) -> anyhow::Result<Option<Vec<u8>>> {
         match self.stream.take() {
             Some(Stream::Bidirectional(bidi_stream)) => {
-                let conn = rustls::ServerConnection::new(self.tls_config.clone().unwrap())?;
-                self.stream = Some(Stream::Bidirectional(bidi_stream.start_tls(conn)?));
-                Ok(())
+                let conn = rustls::ServerConnection::new(config)?;
+                let stream = bidi_stream.start_tls(conn)?;
+                let alpn = stream.alpn_protocol().map(|p| p.to_vec());
+                self.stream = Some(Stream::Bidirectional(stream));
+                Ok(alpn)
             }
             stream => {
                 self.stream = stream;
@@ -363,26 +978,64 @@ impl PostgresBackend {
                             )));
                         }
 
+                        self.replication_mode = match m.replication_mode() {
+                            Ok(mode) => mode,
+                            Err(e) => {
+                                let error = PgError::builder(
+                                    SqlState::InvalidParameterValue,
+                                    e.to_string(),
+                                )
+                                .severity(Severity::Fatal)
+                                .build();
+                                self.write_message(&BeMessage::PgError(&error))?;
+                                return Err(QueryError::Other(e));
+                            }
+                        };
+
                         // NB: startup() may change self.auth_type -- we are using that in proxy code
                         // to bypass auth for new users.
                         handler.startup(self, &m)?;
 
                         match self.auth_type {
                             AuthType::Trust => {
-                                self.write_message_noflush(&BeMessage::AuthenticationOk)?
-                                    .write_message_noflush(&BeMessage::CLIENT_ENCODING)?
-                                    // The async python driver requires a valid server_version
-                                    .write_message_noflush(&BeMessage::server_version("14.1"))?
-                                    .write_message(&BeMessage::ReadyForQuery)?;
+                                let key = self.register_cancel_key();
+                                self.write_message_noflush(&BeMessage::AuthenticationOk)?;
+                                self.write_parameter_statuses()?;
+                                self.write_message_noflush(&BeMessage::BackendKeyData(key))?
+                                    .write_message(&BeMessage::READY_FOR_QUERY)?;
                                 self.state = ProtoState::Established;
                             }
                             AuthType::NeonJWT => {
                                 self.write_message(&BeMessage::AuthenticationCleartextPassword)?;
                                 self.state = ProtoState::Authentication;
                             }
+                            AuthType::Scram => {
+                                let user = m.user()?;
+                                let secret = handler.get_scram_secret(self, user)?;
+                                self.scram = Some(ScramExchange::new(user, secret));
+                                self.write_message(&BeMessage::AuthenticationSasl(
+                                    BeAuthenticationSaslMessage::Methods(SCRAM_METHODS),
+                                ))?;
+                                self.state = ProtoState::Authentication;
+                            }
+                            AuthType::Md5 => {
+                                let user = m.user()?;
+                                let secret = handler.get_md5_secret(self, user)?;
+                                let exchange = Md5Exchange::new(secret);
+                                self.write_message(&BeMessage::AuthenticationMD5Password(
+                                    exchange.salt(),
+                                ))?;
+                                self.md5 = Some(exchange);
+                                self.state = ProtoState::Authentication;
+                            }
                         }
                     }
-                    FeStartupPacket::CancelRequest { .. } => {
+                    FeStartupPacket::CancelRequest(key) => {
+                        if cancel_registry::cancel(&key) {
+                            info!("cancelling query on request from {}", self.peer_addr);
+                        } else {
+                            debug!("CancelRequest for unknown connection");
+                        }
                         return Ok(ProcessMsgResult::Break);
                     }
                 }
@@ -399,17 +1052,56 @@ impl PostgresBackend {
                         let (_, jwt_response) = m.split_last().context("protocol violation")?;
 
                         if let Err(e) = handler.check_auth_jwt(self, jwt_response) {
+                            self.write_message(&BeMessage::PgError(&e.to_pg_error()))?;
+                            return Err(e);
+                        }
+                    }
+                    AuthType::Scram => {
+                        let exchange = self.scram.as_mut().context("protocol violation")?;
+                        match exchange.step(m) {
+                            Ok(ScramStep::Continue(msg)) => {
+                                self.write_message(&BeMessage::AuthenticationSasl(
+                                    BeAuthenticationSaslMessage::Continue(msg.as_bytes()),
+                                ))?;
+                                return Ok(ProcessMsgResult::Continue);
+                            }
+                            Ok(ScramStep::Success(msg)) => {
+                                self.scram = None;
+                                self.write_message_noflush(&BeMessage::AuthenticationSasl(
+                                    BeAuthenticationSaslMessage::Final(msg.as_bytes()),
+                                ))?;
+                            }
+                            Err(e) => {
+                                self.write_message(&BeMessage::ErrorResponse(
+                                    "password authentication failed",
+                                    Some(SQLSTATE_INVALID_PASSWORD),
+                                ))?;
+                                return Err(QueryError::Other(
+                                    e.context("password authentication failed"),
+                                ));
+                            }
+                        }
+                    }
+                    AuthType::Md5 => {
+                        let exchange = self.md5.take().context("protocol violation")?;
+                        if let Err(e) =
+                            parse_password_message(&m).and_then(|response| exchange.check(response))
+                        {
                             self.write_message(&BeMessage::ErrorResponse(
-                                &e.to_string(),
-                                Some(e.pg_error_code()),
+                                "password authentication failed",
+                                Some(SQLSTATE_INVALID_PASSWORD),
                             ))?;
-                            return Err(e);
+                            return Err(QueryError::Other(
+                                e.context("password authentication failed"),
+                            ));
                         }
                     }
                 }
-                self.write_message_noflush(&BeMessage::AuthenticationOk)?
-                    .write_message_noflush(&BeMessage::CLIENT_ENCODING)?
-                    .write_message(&BeMessage::ReadyForQuery)?;
+                let key = self.register_cancel_key();
+                self.write_message_noflush(&BeMessage::AuthenticationOk)?;
+                self.write_parameter_statuses()?;
+                self.write_message_noflush(&BeMessage::BackendKeyData(key))?
+                    .write_message(&BeMessage::READY_FOR_QUERY)?;
                 self.state = ProtoState::Established;
             }
 
@@ -418,15 +1110,14 @@ impl PostgresBackend {
                 let query_string = cstr_to_str(&body)?;
 
                 trace!("got query {query_string:?}");
-                if let Err(e) = handler.process_query(self, query_string) {
+                self.defer_flush = true;
+                let result = self.run_query(handler, query_string);
+                self.defer_flush = false;
+                if let Err(e) = result? {
                     log_query_error(query_string, &e);
-                    let short_error = short_error(&e);
-                    self.write_message_noflush(&BeMessage::ErrorResponse(
-                        &short_error,
-                        Some(e.pg_error_code()),
-                    ))?;
+                    self.write_message_noflush(&BeMessage::PgError(&e.to_pg_error()))?;
                 }
-                self.write_message(&BeMessage::ReadyForQuery)?;
+                self.write_message(&BeMessage::ReadyForQuery(self.transaction_status))?;
             }
 
             FeMessage::Parse(m) => {
@@ -434,9 +1125,13 @@ impl PostgresBackend {
                 self.write_message(&BeMessage::ParseComplete)?;
             }
 
-            FeMessage::Describe(_) => {
-                self.write_message_noflush(&BeMessage::ParameterDescription)?
-                    .write_message(&BeMessage::NoData)?;
+            FeMessage::Describe(m) => {
+                // The unnamed statement has no parameters, and we don't know
+                // what the query returns before running it.
+                if m.kind == b'S' {
+                    self.write_message_noflush(&BeMessage::ParameterDescription)?;
+                }
+                self.write_message(&BeMessage::NoData)?;
             }
 
             FeMessage::Bind(_) => {
@@ -450,12 +1145,9 @@ impl PostgresBackend {
             FeMessage::Execute(_) => {
                 let query_string = cstr_to_str(unnamed_query_string)?;
                 trace!("got execute {query_string:?}");
-                if let Err(e) = handler.process_query(self, query_string) {
+                if let Err(e) = self.run_query(handler, query_string)? {
                     log_query_error(query_string, &e);
-                    self.write_message(&BeMessage::ErrorResponse(
-                        &e.to_string(),
-                        Some(e.pg_error_code()),
-                    ))?;
+                    self.write_message(&BeMessage::PgError(&e.to_pg_error()))?;
                 }
                 // NOTE there is no ReadyForQuery message. This handler is used
                 // for basebackup and it uses CopyOut which doesn't require
@@ -464,7 +1156,23 @@ impl PostgresBackend {
             }
 
             FeMessage::Sync => {
-                self.write_message(&BeMessage::ReadyForQuery)?;
+                self.write_message(&BeMessage::ReadyForQuery(self.transaction_status))?;
+            }
+
+            FeMessage::Flush => {
+                self.flush()?;
+            }
+
+            FeMessage::FunctionCall(m) => {
+                let error = PgError::builder(
+                    SqlState::FeatureNotSupported,
+                    "fast-path function calls are not supported",
+                )
+                .detail(format!("function OID {}", m.function_oid))
+                .build();
+                self.fail_transaction();
+                self.write_message_noflush(&BeMessage::PgError(&error))?;
+                self.write_message(&BeMessage::ReadyForQuery(self.transaction_status))?;
             }
 
             FeMessage::Terminate => {
@@ -473,7 +1181,7 @@ impl PostgresBackend {
 
             // We prefer explicit pattern matching to wildcards, because
             // this helps us spot the places where new variants are missing
-            FeMessage::CopyData(_) | FeMessage::CopyDone | FeMessage::CopyFail => {
+            FeMessage::CopyData(_) | FeMessage::CopyDone | FeMessage::CopyFail(_) => {
                 return Err(QueryError::Other(anyhow::anyhow!(
                     "unexpected message type: {msg:?}"
                 )));
@@ -483,3 +1191,299 @@ impl PostgresBackend {
         Ok(ProcessMsgResult::Continue)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+    use pq_proto::RowDescriptor;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Backend connected to a client socket.
+    fn connect() -> (PostgresBackend, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        let pgb = PostgresBackend::new(socket, AuthType::Trust, None, false).unwrap();
+        (pgb, client)
+    }
+
+    fn startup_message(params: &[u8]) -> BytesMut {
+        let mut msg = BytesMut::new();
+        msg.put_u32(8 + params.len() as u32);
+        msg.put_u32(196608); // protocol 3.0
+        msg.put_slice(params);
+        msg
+    }
+
+    fn query(query: &str) -> BytesMut {
+        let mut msg = BytesMut::new();
+        msg.put_u8(b'Q');
+        msg.put_u32(4 + query.len() as u32 + 1);
+        msg.put_slice(query.as_bytes());
+        msg.put_u8(0);
+        msg
+    }
+
+    /// Split the response into the types and bodies of the messages.
+    fn split_messages(mut buf: &[u8]) -> Vec<(u8, &[u8])> {
+        let mut messages = Vec::new();
+        while !buf.is_empty() {
+            let len = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+            messages.push((buf[0], &buf[5..1 + len]));
+            buf = &buf[1 + len..];
+        }
+        messages
+    }
+
+    #[test]
+    fn test_flush_threshold() {
+        let (mut pgb, mut client) = connect();
+        pgb.set_flush_threshold(Some(10));
+
+        let row = BeMessage::DataRow(&[Some(b"0123456789")]);
+        pgb.write_message_noflush(&row).unwrap();
+        assert!(pgb.needs_flush());
+        // flushes the first row
+        pgb.write_message_noflush(&row).unwrap();
+        let mut response = [0; 21];
+        client.read_exact(&mut response).unwrap();
+        assert_eq!(&response, b"D\0\0\0\x14\0\x01\0\0\0\x0a0123456789");
+
+        // without the threshold, nothing is written until flush
+        pgb.set_flush_threshold(None);
+        for _ in 0..10 {
+            pgb.write_message_noflush(&row).unwrap();
+        }
+        assert!(!pgb.needs_flush());
+        client.set_nonblocking(true).unwrap();
+        let mut buf = [0; 1];
+        let err = client.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        client.set_nonblocking(false).unwrap();
+        pgb.flush().unwrap();
+        let mut response = [0; 21 * 11];
+        client.read_exact(&mut response).unwrap();
+    }
+
+    #[test]
+    fn test_defer_flush() {
+        /// Counts writes to the socket.
+        #[derive(Default)]
+        struct Writes(AtomicUsize);
+        impl IoObserver for Writes {
+            fn bytes_written(&self, _n: usize) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        struct Rows(Arc<Writes>);
+        impl Handler for Rows {
+            fn process_query(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                let writes = || self.0 .0.load(Ordering::Relaxed);
+                let before = writes();
+                if query_string == "copy" {
+                    // COPY data is streamed
+                    pgb.start_copy(CopyMode::Out)?;
+                    assert_eq!(writes(), before + 1);
+                    pgb.write_message(&BeMessage::CopyData(b"1"))?;
+                    assert_eq!(writes(), before + 2);
+                    pgb.finish_copy_out()?;
+                } else {
+                    pgb.write_message(&BeMessage::RowDescription(&[RowDescriptor::text_col(
+                        b"a",
+                    )]))?
+                    .write_message(&BeMessage::DataRow(&[Some(b"1")]))?;
+                    assert_eq!(writes(), before);
+                }
+                pgb.write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
+                Ok(())
+            }
+        }
+
+        let (mut pgb, mut client) = connect();
+        pgb.state = ProtoState::Established;
+        let writes = Arc::new(Writes::default());
+        pgb.set_io_observer(writes.clone());
+        client.write_all(&query("select")).unwrap();
+        client.write_all(&query("copy")).unwrap();
+        client.write_all(b"X\0\0\0\x04").unwrap();
+        pgb.run(&mut Rows(writes.clone())).unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let types: Vec<u8> = split_messages(&response)
+            .into_iter()
+            .map(|(typ, _)| typ)
+            .collect();
+        assert_eq!(types, b"TDCZHdcCZ");
+        // the result of the simple query and ReadyForQuery go out in one
+        // write, the 3 COPY messages, CommandComplete and ReadyForQuery in 5
+        assert_eq!(writes.0.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn test_buffer_config_pipelined() {
+        let (mut pgb, mut client) = connect();
+        pgb.state = ProtoState::Established;
+        pgb.set_buffer_pool(Arc::new(BufferPool::new(1)));
+        // the client sends the next message right after the query, as the
+        // walproposer does after START_WAL_PUSH
+        let mut messages = query("first");
+        messages.extend_from_slice(&query("second"));
+        client.write_all(&messages).unwrap();
+        assert!(matches!(pgb.read_message(), Ok(Some(FeMessage::Query(_)))));
+
+        // resizing keeps the buffered message
+        pgb.set_buffer_config(BufferConfig::LARGE);
+        assert_eq!(pgb.buffer_capacities().0, BufferConfig::LARGE.read_capacity);
+        match pgb.read_message() {
+            Ok(Some(FeMessage::Query(body))) => assert_eq!(cstr_to_str(&body).unwrap(), "second"),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+
+        // output buffers taken from the pool get the configured capacity
+        pgb.write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))
+            .unwrap();
+        assert!(pgb.buffer_capacities().1 >= BufferConfig::LARGE.write_capacity);
+    }
+
+    #[test]
+    fn test_send_notice() {
+        /// Holds the client, to check the notice arrives during the query.
+        struct Notice(TcpStream);
+        impl Handler for Notice {
+            fn process_query(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                _query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                pgb.write_message(&BeMessage::CommandComplete(b"SELECT 0"))?;
+                pgb.send_notice("lagging")?;
+                // query output is held back until the query ends, but the
+                // notice is flushed right away, with the output before it
+                let mut response = [0; 52];
+                self.0.read_exact(&mut response).unwrap();
+                let messages = split_messages(&response);
+                assert_eq!(messages[0], (b'C', &b"SELECT 0\0"[..]));
+                assert_eq!(
+                    messages[1],
+                    (b'N', &b"SNOTICE\0VNOTICE\0C00000\0Mlagging\0\0"[..])
+                );
+                Ok(())
+            }
+        }
+
+        let (mut pgb, mut client) = connect();
+        pgb.state = ProtoState::Established;
+        client.write_all(&query("select")).unwrap();
+        client.write_all(b"X\0\0\0\x04").unwrap();
+        let mut handler = Notice(client);
+        pgb.run(&mut handler).unwrap();
+
+        let mut response = Vec::new();
+        handler.0.read_to_end(&mut response).unwrap();
+        assert_eq!(split_messages(&response), [(b'Z', &b"I"[..])]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_disconnect_cancels_query() {
+        struct WaitCancel(bool);
+        impl Handler for WaitCancel {
+            fn process_query(
+                &mut self,
+                _pgb: &mut PostgresBackend,
+                _query_string: &str,
+                cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                let started = Instant::now();
+                while !cancel.is_cancelled() {
+                    if started.elapsed() > Duration::from_secs(10) {
+                        return Err(QueryError::Other(anyhow::anyhow!("not cancelled")));
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                self.0 = true;
+                Err(QueryError::Cancelled)
+            }
+        }
+
+        let (mut pgb, mut client) = connect();
+        pgb.state = ProtoState::Established;
+        // the client is gone while the query runs
+        client.write_all(&query("select")).unwrap();
+        drop(client);
+        let mut handler = WaitCancel(false);
+        let res = pgb.run(&mut handler);
+        assert!(handler.0);
+        assert!(
+            matches!(res, Err(QueryError::Disconnected(_))),
+            "unexpected result {res:?}"
+        );
+    }
+
+    #[test]
+    fn test_parameter_status() {
+        struct Params;
+        impl Handler for Params {
+            fn process_query(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                pgb.set_parameter_status("application_name", query_string)?;
+                Ok(())
+            }
+
+            fn startup(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                _sm: &FeStartupPacket,
+            ) -> Result<(), QueryError> {
+                pgb.set_parameter_status("server_version", "15.2")?;
+                pgb.set_parameter_status("application_name", "test")?;
+                Ok(())
+            }
+        }
+
+        let (pgb, mut client) = connect();
+        client
+            .write_all(&startup_message(b"user\0test\0\0"))
+            .unwrap();
+        client.write_all(&query("app")).unwrap();
+        client.write_all(b"X\0\0\0\x04").unwrap();
+        pgb.run(&mut Params).unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+        let statuses: Vec<&[u8]> = split_messages(&response)
+            .into_iter()
+            .filter(|(typ, _)| *typ == b'S')
+            .map(|(_, body)| body)
+            .collect();
+        // overridden standard parameters are reported with the set ones,
+        // and changes while the connection is established right away
+        assert_eq!(
+            statuses,
+            [
+                &b"client_encoding\0UTF8\0"[..],
+                b"DateStyle\0ISO, MDY\0",
+                b"integer_datetimes\0on\0",
+                b"standard_conforming_strings\0on\0",
+                b"server_version\x0015.2\0",
+                b"application_name\0test\0",
+                b"application_name\0app\0",
+            ]
+        );
+    }
+}
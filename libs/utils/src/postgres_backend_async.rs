@@ -3,19 +3,40 @@
 //! implementation determining how to process the queries. Currently its API
 //! is rather narrow, but we can extend it once required.
 
-use crate::postgres_backend::AuthType;
+use crate::buffer_pool::{
+    acquire_buffer, release_buffer, BufferConfig, BufferPool, DEFAULT_BUFFER_CAPACITY,
+};
+use crate::cancel_registry::{self, CancelRegistration, CancellationToken};
+use crate::disconnect_monitor;
+use crate::io_observer::{IoObserver, ObservedRead};
+use crate::md5_auth::Md5Exchange;
+use crate::postgres_backend::{
+    direct_tls_config, AuthType, PG_ALPN_PROTOCOL, TLS_HANDSHAKE_RECORD_TYPE,
+};
+use crate::scram_auth::{ScramExchange, ScramStep, ServerSecret, METHODS as SCRAM_METHODS};
 use anyhow::Context;
 use bytes::{Buf, Bytes, BytesMut};
-use pq_proto::{BeMessage, ConnectionError, FeMessage, FeStartupPacket, SQLSTATE_INTERNAL_ERROR};
+use futures::{Sink, Stream};
+#[cfg(feature = "pq-trace")]
+use pq_proto::trace::MessageTracer;
+use pq_proto::{
+    parse_password_message,
+    pg_error::{PgError, Severity, SqlState},
+    BeAuthenticationSaslMessage, BeMessage, CancelKeyData, ConnectionError, FeMessage,
+    FeStartupPacket, MessageLimits, ReplicationMode, TransactionStatus, WriteQueue,
+    STANDARD_PARAMETERS,
+};
 use std::io;
 use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
+use std::time::Duration;
 use std::{future::Future, task::ready};
 use tracing::{debug, error, info, trace};
 
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio_rustls::TlsAcceptor;
 
 pub fn is_expected_io_error(e: &io::Error) -> bool {
@@ -33,6 +54,9 @@ pub enum QueryError {
     /// The connection was lost while processing the query.
     #[error(transparent)]
     Disconnected(#[from] ConnectionError),
+    /// The query was cancelled by CancelRequest from the client.
+    #[error("canceling statement due to user request")]
+    Cancelled,
     /// Some other error
     #[error(transparent)]
     Other(#[from] anyhow::Error),
@@ -45,24 +69,162 @@ impl From<io::Error> for QueryError {
 }
 
 impl QueryError {
-    pub fn pg_error_code(&self) -> &'static [u8; 5] {
+    pub fn pg_error_code(&self) -> &[u8; 5] {
         match self {
-            Self::Disconnected(_) => b"08006",         // connection failure
-            Self::Other(_) => SQLSTATE_INTERNAL_ERROR, // internal error
+            Self::Disconnected(_) => SqlState::ConnectionFailure.code(),
+            Self::Cancelled => SqlState::QueryCanceled.code(),
+            Self::Other(e) => match find_pg_error(e) {
+                Some(pg_error) => &pg_error.code,
+                None => SqlState::InternalError.code(),
+            },
+        }
+    }
+
+    /// The error as reported to the client. Handlers choose the SQLSTATE
+    /// and other fields by returning a [`PgError`] inside the error, e.g.
+    /// `Err(PgError::builder(SqlState::FeatureNotSupported, "...").build().into())`.
+    /// The contexts added on top of such an error go into the detail field.
+    /// Otherwise the message is the whole error chain, as clients often show
+    /// only the message.
+    pub fn to_pg_error(&self) -> PgError {
+        match self {
+            Self::Other(e) => {
+                let Some(pg_error) = find_pg_error(e) else {
+                    return PgError::builder(SqlState::InternalError, format!("{e:#}")).build();
+                };
+                let mut pg_error = pg_error.clone();
+                let contexts = e
+                    .chain()
+                    .take_while(|c| c.downcast_ref::<PgError>().is_none())
+                    .map(|c| c.to_string())
+                    .collect::<Vec<_>>();
+                if !contexts.is_empty() {
+                    let contexts = contexts.join(": ");
+                    pg_error.detail = Some(match pg_error.detail {
+                        Some(detail) => format!("{detail}; {contexts}"),
+                        None => contexts,
+                    });
+                }
+                pg_error
+            }
+            _ => PgError::builder(SqlState::InternalError, short_error(self))
+                .raw_code(self.pg_error_code())
+                .build(),
+        }
+    }
+}
+
+fn find_pg_error(e: &anyhow::Error) -> Option<&PgError> {
+    e.chain().find_map(|e| e.downcast_ref::<PgError>())
+}
+
+/// Default amount of buffered output above which writing messages flushes
+/// them, see [`PostgresBackend::set_flush_threshold`].
+pub const DEFAULT_FLUSH_THRESHOLD: usize = 64 * 1024;
+
+/// Default max size of the CopyData messages [`CopyDataWriter`] cuts the
+/// written data into.
+pub const DEFAULT_COPY_DATA_CHUNK_SIZE: usize = 128 * 1024;
+
+/// invalid_password, reported when password authentication fails.
+pub const SQLSTATE_INVALID_PASSWORD: &[u8; 5] = SqlState::InvalidPassword.code();
+
+/// How long to try sending [`shutdown_error`] to a client which may not be
+/// reading, to not hold up the shutdown.
+pub(crate) const SHUTDOWN_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Error sent to clients when their connection is closed because the
+/// service shuts down, as Postgres does on smart shutdown.
+pub fn shutdown_error() -> PgError {
+    PgError::builder(
+        SqlState::AdminShutdown,
+        "terminating connection due to administrator command",
+    )
+    .severity(Severity::Fatal)
+    .build()
+}
+
+/// Direction of data in the COPY sub-protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMode {
+    /// Client sends data, e.g. basebackup import.
+    In,
+    /// Server sends data, e.g. basebackup.
+    Out,
+    /// Both sides send data, used for replication.
+    Both,
+}
+
+impl CopyMode {
+    pub(crate) fn response(&self) -> BeMessage<'static> {
+        match self {
+            CopyMode::In => BeMessage::CopyInResponse,
+            CopyMode::Out => BeMessage::CopyOutResponse,
+            CopyMode::Both => BeMessage::CopyBothResponse,
         }
     }
 }
 
+/// Error for a COPY aborted by the client with CopyFail.
+pub(crate) fn copy_fail_error(msg: &[u8]) -> QueryError {
+    let msg = msg.strip_suffix(&[0]).unwrap_or(msg);
+    QueryError::Other(anyhow::anyhow!(
+        "client failed COPY: {}",
+        String::from_utf8_lossy(msg)
+    ))
+}
+
+/// Error of a write the peer didn't accept in time.
+pub(crate) fn write_timed_out_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "timed out writing to the socket, peer stopped reading",
+    )
+}
+
+/// Error for a client that sent nothing for longer than the idle timeout.
+pub(crate) fn idle_timed_out_error(timeout: Duration) -> QueryError {
+    QueryError::Disconnected(ConnectionError::Socket(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("client sent nothing for {timeout:?}"),
+    )))
+}
+
+/// Error for a client that disconnected while its query was running.
+pub(crate) fn query_disconnected_error() -> QueryError {
+    QueryError::Disconnected(ConnectionError::Socket(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "client disconnected during query",
+    )))
+}
+
+/// Error for a connection closed in the middle of COPY.
+pub(crate) fn copy_disconnected_error(msg: &str) -> QueryError {
+    QueryError::Disconnected(ConnectionError::Socket(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        msg.to_owned(),
+    )))
+}
+
 #[async_trait::async_trait]
 pub trait Handler {
     /// Handle single query.
     /// postgres_backend will issue ReadyForQuery after calling this (this
     /// might be not what we want after CopyData streaming, but currently we don't
     /// care).
+    ///
+    /// `cancel` is cancelled when the client sends CancelRequest for the
+    /// query, disconnects or the service shuts down; long-running handlers
+    /// should poll it and return [`QueryError::Cancelled`].
+    ///
+    /// ReadyForQuery reports the status set with
+    /// [`PostgresBackend::set_transaction_status`] by handlers implementing
+    /// transaction blocks; an error inside a block marks it failed.
     async fn process_query(
         &mut self,
         pgb: &mut PostgresBackend,
         query_string: &str,
+        cancel: &CancellationToken,
     ) -> Result<(), QueryError>;
 
     /// Called on startup packet receival, allows to process params.
@@ -70,6 +232,9 @@ pub trait Handler {
     /// If Ok(false) is returned postgres_backend will skip auth -- that is needed for new users
     /// creation is the proxy code. That is quite hacky and ad-hoc solution, may be we could allow
     /// to override whole init logic in implementations.
+    ///
+    /// The `replication` parameter has already been validated, see
+    /// [`PostgresBackend::replication_mode`].
     fn startup(
         &mut self,
         _pgb: &mut PostgresBackend,
@@ -86,6 +251,26 @@ pub trait Handler {
     ) -> Result<(), QueryError> {
         Err(QueryError::Other(anyhow::anyhow!("JWT auth failed")))
     }
+
+    /// Get SCRAM secret of the user for password auth, None if there is no
+    /// such user.
+    fn get_scram_secret(
+        &mut self,
+        _pgb: &mut PostgresBackend,
+        _user: &str,
+    ) -> Result<Option<ServerSecret>, QueryError> {
+        Ok(None)
+    }
+
+    /// Get md5 secret of the user for md5 password auth, see
+    /// [`crate::md5_auth`]; None if there is no such user.
+    fn get_md5_secret(
+        &mut self,
+        _pgb: &mut PostgresBackend,
+        _user: &str,
+    ) -> Result<Option<String>, QueryError> {
+        Ok(None)
+    }
 }
 
 /// PostgresBackend protocol state.
@@ -159,11 +344,27 @@ impl AsyncRead for Stream {
 
 pub struct PostgresBackend {
     stream: Stream,
-
-    // Output buffer. c.f. BeMessage::write why we are using BytesMut here.
-    // The data between 0 and "current position" as tracked by the bytes::Buf
-    // implementation of BytesMut, have already been written.
-    buf_out: BytesMut,
+    /// The socket of `stream`, watched for disconnects during queries.
+    fd: RawFd,
+
+    // Output buffer, large payloads are queued in it without copying.
+    // Data consumed through the bytes::Buf implementation of WriteQueue has
+    // already been written.
+    buf_out: WriteQueue,
+    /// Pool to return `buf_out` to after flush, if shared with other connections.
+    buffer_pool: Option<Arc<BufferPool>>,
+    /// Buffer sizes, with the read capacity in effect.
+    buffer_config: BufferConfig,
+    io_observer: Option<Arc<dyn IoObserver>>,
+    #[cfg(feature = "pq-trace")]
+    tracer: Option<MessageTracer>,
+    /// Writes fail if the peer doesn't read anything for this long.
+    write_stall_timeout: Option<Duration>,
+    /// The connection is closed if the client sends no message for this long
+    /// while the backend waits for one.
+    idle_timeout: Option<Duration>,
+    /// Buffered output above this is flushed before writing more.
+    flush_threshold: Option<usize>,
 
     pub state: ProtoState,
 
@@ -171,6 +372,27 @@ pub struct PostgresBackend {
 
     peer_addr: SocketAddr,
     pub tls_config: Option<Arc<rustls::ServerConfig>>,
+    /// Accept TLS started right away with ClientHello (Postgres 17 direct
+    /// SSL negotiation), in addition to SSLRequest.
+    pub allow_direct_tls: bool,
+    /// Messages longer than these limits close the connection.
+    pub message_limits: MessageLimits,
+
+    /// SCRAM exchange in progress, if authenticating with it.
+    scram: Option<ScramExchange>,
+    /// md5 password exchange in progress, if authenticating with it.
+    md5: Option<Md5Exchange>,
+
+    /// Registration for CancelRequest, made once the connection is established.
+    cancel: Option<CancelRegistration>,
+    /// Reported in ReadyForQuery.
+    transaction_status: TransactionStatus,
+    /// Requested with the `replication` startup parameter.
+    replication_mode: ReplicationMode,
+
+    /// Run-time parameters reported in ParameterStatus in addition to or
+    /// instead of the standard ones.
+    parameters: Vec<(String, String)>,
 }
 
 pub fn query_from_cstring(query_string: Bytes) -> Vec<u8> {
@@ -196,54 +418,443 @@ impl PostgresBackend {
         tls_config: Option<Arc<rustls::ServerConfig>>,
     ) -> io::Result<Self> {
         let peer_addr = socket.peer_addr()?;
+        let fd = socket.as_raw_fd();
 
         Ok(Self {
+            fd,
             stream: Stream::Unencrypted(BufReader::new(socket)),
-            buf_out: BytesMut::with_capacity(10 * 1024),
+            buf_out: WriteQueue::with_capacity(DEFAULT_BUFFER_CAPACITY),
+            buffer_pool: None,
+            buffer_config: BufferConfig::default(),
+            io_observer: None,
+            #[cfg(feature = "pq-trace")]
+            tracer: None,
+            write_stall_timeout: None,
+            idle_timeout: None,
+            flush_threshold: Some(DEFAULT_FLUSH_THRESHOLD),
             state: ProtoState::Initialization,
             auth_type,
             tls_config,
+            allow_direct_tls: false,
+            message_limits: MessageLimits::default(),
             peer_addr,
+            scram: None,
+            md5: None,
+            cancel: None,
+            transaction_status: TransactionStatus::Idle,
+            replication_mode: ReplicationMode::Off,
+            parameters: Vec::new(),
         })
     }
 
+    /// Set buffer sizes of the connection. The read buffer is resized only
+    /// while nothing is buffered in it and before TLS is started, e.g. right
+    /// after creation or between queries; the output buffer while empty.
+    /// With a shared buffer pool, buffers taken from it get the write capacity.
+    pub fn set_buffer_config(&mut self, config: BufferConfig) {
+        // the input buffer can be replaced only while nothing is buffered
+        let resized = match &self.stream {
+            Stream::Unencrypted(reader) => reader.buffer().is_empty(),
+            _ => false,
+        };
+        if resized {
+            if let Stream::Unencrypted(reader) = std::mem::replace(&mut self.stream, Stream::Broken)
+            {
+                self.stream = Stream::Unencrypted(BufReader::with_capacity(
+                    config.read_capacity,
+                    reader.into_inner(),
+                ));
+            }
+        }
+        let read_capacity = if resized {
+            config.read_capacity
+        } else {
+            self.buffer_config.read_capacity
+        };
+        if self.buffer_pool.is_none() && self.buf_out.is_empty() {
+            *self.buf_out.buffer_mut() = BytesMut::with_capacity(config.write_capacity);
+        }
+        self.buffer_config = BufferConfig {
+            read_capacity,
+            ..config
+        };
+    }
+
+    /// Current capacities of the read and the output buffer, for metrics.
+    /// The output buffer grows to fit the largest message until flushed.
+    pub fn buffer_capacities(&self) -> (usize, usize) {
+        (self.buffer_config.read_capacity, self.buf_out.capacity())
+    }
+
+    /// Share output buffers with other connections through the pool.
+    pub fn set_buffer_pool(&mut self, pool: Arc<BufferPool>) {
+        if self.buf_out.is_empty() {
+            release_buffer(self.buf_out.buffer_mut(), Some(&pool), &self.buffer_config);
+        }
+        self.buffer_pool = Some(pool);
+    }
+
+    /// Report traffic of the connection to the observer.
+    pub fn set_io_observer(&mut self, observer: Arc<dyn IoObserver>) {
+        self.io_observer = Some(observer);
+    }
+
+    /// Trace every message of the connection, see [`MessageTracer`].
+    #[cfg(feature = "pq-trace")]
+    pub fn set_message_tracer(&mut self, tracer: MessageTracer) {
+        self.tracer = Some(tracer);
+    }
+
+    #[cfg(feature = "pq-trace")]
+    fn trace_frontend(&self, msg: &FeMessage, len: usize) {
+        if let Some(tracer) = &self.tracer {
+            tracer.frontend(msg, len);
+        }
+    }
+
+    #[cfg(not(feature = "pq-trace"))]
+    fn trace_frontend(&self, _msg: &FeMessage, _len: usize) {}
+
+    #[cfg(feature = "pq-trace")]
+    fn trace_backend(&self, msg: &BeMessage, start: usize) {
+        if let Some(tracer) = &self.tracer {
+            tracer.backend(msg, &self.buf_out, start);
+        }
+    }
+
+    #[cfg(not(feature = "pq-trace"))]
+    fn trace_backend(&self, _msg: &BeMessage, _start: usize) {}
+
     pub fn get_peer_addr(&self) -> &SocketAddr {
         &self.peer_addr
     }
 
+    /// Token cancelled when the client sends CancelRequest for the command
+    /// being processed. It is the token passed to `Handler::process_query`,
+    /// also cancelled on disconnect and shutdown.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        match &self.cancel {
+            Some(registration) => registration.token().clone(),
+            None => CancellationToken::new(),
+        }
+    }
+
+    /// Report a run-time parameter to the client in ParameterStatus, e.g.
+    /// `server_version` to override the default one. Parameters set before
+    /// the connection is established, e.g. in `Handler::startup`, are
+    /// reported along with the standard ones right after AuthenticationOk.
+    pub fn set_parameter_status(&mut self, name: &str, value: &str) -> io::Result<()> {
+        if self.state == ProtoState::Established {
+            self.write_message(&BeMessage::parameter_status(name, value))?;
+        }
+        match self.parameters.iter_mut().find(|(set, _)| set == name) {
+            Some((_, old_value)) => *old_value = value.to_owned(),
+            None => self.parameters.push((name.to_owned(), value.to_owned())),
+        }
+        Ok(())
+    }
+
+    /// Write ParameterStatus of the standard and set parameters, which
+    /// follows AuthenticationOk.
+    fn write_parameter_statuses(&mut self) -> io::Result<()> {
+        let parameters = std::mem::take(&mut self.parameters);
+        for (name, value) in STANDARD_PARAMETERS {
+            if !parameters.iter().any(|(set, _)| set == name) {
+                self.write_message(&BeMessage::parameter_status(name, value))?;
+            }
+        }
+        for (name, value) in &parameters {
+            self.write_message(&BeMessage::parameter_status(name, value))?;
+        }
+        self.parameters = parameters;
+        Ok(())
+    }
+
+    /// Register the connection for cancellation and return the key to be
+    /// reported to the client in BackendKeyData.
+    fn register_cancel_key(&mut self) -> CancelKeyData {
+        let registration = cancel_registry::register();
+        let key = registration.key();
+        self.cancel = Some(registration);
+        key
+    }
+
+    /// Forget about CancelRequest which arrived for the previous command.
+    fn reset_cancellation(&self) {
+        if let Some(registration) = &self.cancel {
+            registration.token().reset();
+        }
+    }
+
+    /// Replication mode the client asked for in the startup packet: handlers
+    /// should accept replication commands only if it is not
+    /// [`ReplicationMode::Off`], and SQL only if it is not
+    /// [`ReplicationMode::Physical`].
+    pub fn replication_mode(&self) -> ReplicationMode {
+        self.replication_mode
+    }
+
+    /// Transaction status reported in the next ReadyForQuery.
+    pub fn transaction_status(&self) -> TransactionStatus {
+        self.transaction_status
+    }
+
+    /// Set the transaction status, e.g. on BEGIN, COMMIT or when the
+    /// transaction block fails without the query returning an error.
+    pub fn set_transaction_status(&mut self, status: TransactionStatus) {
+        self.transaction_status = status;
+    }
+
+    /// A query failed: a transaction block it was part of is aborted.
+    fn fail_transaction(&mut self) {
+        if self.transaction_status == TransactionStatus::InTransaction {
+            self.transaction_status = TransactionStatus::Failed;
+        }
+    }
+
+    /// Run the query, with a cancellation token also cancelled when the
+    /// client disconnects or the service shuts down. Fails if the client
+    /// disconnected, otherwise returns the result of the handler.
+    async fn run_query(
+        &mut self,
+        handler: &mut impl Handler,
+        query_string: &str,
+    ) -> Result<Result<(), QueryError>, QueryError> {
+        self.reset_cancellation();
+        let cancel = self.cancellation_token();
+        let watch = disconnect_monitor::watch(self.fd, cancel.clone(), None);
+        let res = handler.process_query(self, query_string, &cancel).await;
+        if watch.disconnected() {
+            return Err(query_disconnected_error());
+        }
+        if res.is_err() {
+            self.fail_transaction();
+        }
+        Ok(res)
+    }
+
     /// Read full message or return None if connection is closed.
     pub async fn read_message(&mut self) -> Result<Option<FeMessage>, QueryError> {
+        let observer = self.io_observer.as_deref();
+        let stream = &mut ObservedRead::new(&mut self.stream, observer);
+
         use ProtoState::*;
-        match self.state {
-            Initialization | Encrypted => FeStartupPacket::read_fut(&mut self.stream).await,
-            Authentication | Established => FeMessage::read_fut(&mut self.stream).await,
+        let msg = match self.state {
+            Initialization | Encrypted => {
+                FeStartupPacket::read_fut_limited(
+                    stream,
+                    self.message_limits.max_startup_message_len,
+                )
+                .await
+            }
+            Authentication | Established => {
+                FeMessage::read_fut_limited(stream, self.message_limits.max_message_len).await
+            }
             Closed => Ok(None),
+        }?;
+        let len = stream.bytes_read();
+        if let Some(msg) = &msg {
+            if let Some(observer) = observer {
+                observer.message_read(msg);
+            }
+            self.trace_frontend(msg, len);
+        }
+        Ok(msg)
+    }
+
+    /// Make flushes fail with `TimedOut` when the peer doesn't read
+    /// anything for `timeout`, instead of waiting until it does. None
+    /// disables the timeout.
+    pub fn set_write_stall_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_stall_timeout = timeout;
+    }
+
+    /// Close the connection when the client sends no message for `timeout`
+    /// while the message loop waits for one. Reads done by the handler
+    /// during a query are not affected. None disables the timeout.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.idle_timeout = timeout;
+    }
+
+    /// Read the next message of the message loop, subject to the idle
+    /// timeout.
+    async fn read_message_idle(&mut self) -> Result<Option<FeMessage>, QueryError> {
+        match self.idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, self.read_message())
+                .await
+                .map_err(|_| idle_timed_out_error(timeout))?,
+            None => self.read_message().await,
         }
-        .map_err(QueryError::from)
     }
 
     /// Flush output buffer into the socket.
     pub async fn flush(&mut self) -> io::Result<()> {
         while self.buf_out.has_remaining() {
-            let bytes_written = self.stream.write(self.buf_out.chunk()).await?;
+            let write = self.stream.write(self.buf_out.chunk());
+            let bytes_written = match self.write_stall_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, write)
+                    .await
+                    .map_err(|_| write_timed_out_error())??,
+                None => write.await?,
+            };
             self.buf_out.advance(bytes_written);
+            if let Some(observer) = &self.io_observer {
+                observer.bytes_written(bytes_written);
+            }
         }
-        self.buf_out.clear();
+        release_buffer(
+            self.buf_out.buffer_mut(),
+            self.buffer_pool.as_deref(),
+            &self.buffer_config,
+        );
         Ok(())
     }
 
+    /// Flush output buffer into the socket, failing with `TimedOut` if it
+    /// isn't written out within `timeout`. Unsent data stays in the buffer.
+    pub async fn flush_with_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        tokio::time::timeout(timeout, self.flush())
+            .await
+            .map_err(|_| write_timed_out_error())?
+    }
+
+    /// Flush output once this much of it is buffered when writing with
+    /// [`Self::feed_message`] or as a [`Sink`]. None lets the buffer grow
+    /// until explicit flush.
+    pub fn set_flush_threshold(&mut self, threshold: Option<usize>) {
+        self.flush_threshold = threshold;
+    }
+
+    /// Whether the output buffer has grown above the flush threshold.
+    pub fn needs_flush(&self) -> bool {
+        match self.flush_threshold {
+            Some(threshold) => self.buf_out.remaining() >= threshold,
+            None => false,
+        }
+    }
+
+    /// Write message into internal output buffer, flushing it first if it
+    /// has grown above the flush threshold. Use this instead of
+    /// [`Self::write_message`] when writing many messages, e.g. DataRows,
+    /// before flush.
+    pub async fn feed_message(&mut self, message: &BeMessage<'_>) -> io::Result<&mut Self> {
+        if self.needs_flush() {
+            self.flush().await?;
+        }
+        self.write_message(message)
+    }
+
     /// Write message into internal output buffer.
     pub fn write_message(&mut self, message: &BeMessage<'_>) -> io::Result<&mut Self> {
-        BeMessage::write(&mut self.buf_out, message)?;
+        acquire_buffer(
+            self.buf_out.buffer_mut(),
+            self.buffer_pool.as_deref(),
+            &self.buffer_config,
+        );
+        let start = self.buf_out.remaining();
+        self.buf_out.write_message(message)?;
+        if let Some(observer) = &self.io_observer {
+            observer.message_written(message);
+        }
+        self.trace_backend(message, start);
         Ok(self)
     }
 
+    /// Write messages into internal output buffer and flush them at once,
+    /// e.g. a whole small result set.
+    pub async fn write_messages(&mut self, messages: &[BeMessage<'_>]) -> io::Result<()> {
+        for message in messages {
+            self.write_message(message)?;
+        }
+        self.flush().await
+    }
+
+    /// Send a NoticeResponse, e.g. about a non-fatal condition during a long
+    /// command. It is flushed right away, also in COPY mode: clients accept
+    /// notices between any messages.
+    pub async fn send_notice(&mut self, message: &str) -> io::Result<()> {
+        self.write_message(&BeMessage::NoticeResponse(message))?;
+        self.flush().await
+    }
+
+    /// Send a NotificationResponse on `channel`, flushed right away like
+    /// [`Self::send_notice`].
+    pub async fn send_notification(&mut self, channel: &str, payload: &str) -> io::Result<()> {
+        let process_id = self
+            .cancel
+            .as_ref()
+            .map_or(0, |registration| registration.key().backend_pid);
+        self.write_message(&BeMessage::NotificationResponse {
+            process_id,
+            channel,
+            payload,
+        })?;
+        self.flush().await
+    }
+
+    /// Switch the connection to COPY mode.
+    pub async fn start_copy(&mut self, mode: CopyMode) -> io::Result<()> {
+        self.write_message(&mode.response())?;
+        self.flush().await
+    }
+
+    /// End COPY OUT (or the server side of COPY BOTH) with CopyDone.
+    pub async fn finish_copy_out(&mut self) -> io::Result<()> {
+        self.write_message(&BeMessage::CopyDone)?;
+        self.flush().await
+    }
+
+    /// Read the next chunk of data in COPY IN or COPY BOTH mode. Returns
+    /// None once the client sends CopyDone; CopyFail from the client and
+    /// messages not allowed during COPY are errors.
+    pub async fn read_copy_data(&mut self) -> Result<Option<Bytes>, QueryError> {
+        loop {
+            match self.read_message().await? {
+                Some(FeMessage::CopyData(data)) => return Ok(Some(data)),
+                Some(FeMessage::CopyDone) => return Ok(None),
+                Some(FeMessage::CopyFail(msg)) => return Err(copy_fail_error(&msg)),
+                // Allowed by the protocol and ignored.
+                Some(FeMessage::Sync | FeMessage::Flush) => continue,
+                Some(FeMessage::Terminate) | None => {
+                    return Err(copy_disconnected_error(
+                        "client closed connection during COPY",
+                    ))
+                }
+                Some(msg) => {
+                    return Err(QueryError::Other(anyhow::anyhow!(
+                        "unexpected message {msg:?} during COPY"
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Stream of messages from the client, ending when the connection is
+    /// closed. Allows to use stream combinators and `select!` instead of
+    /// calling [`Self::read_message`] in a loop.
+    pub fn messages(&mut self) -> impl Stream<Item = Result<FeMessage, QueryError>> + '_ {
+        futures::stream::unfold(self, |pgb| async move {
+            match pgb.read_message().await {
+                Ok(Some(msg)) => Some((Ok(msg), pgb)),
+                Ok(None) => None,
+                // The stream is in an unknown state after an error.
+                Err(e) => {
+                    pgb.state = ProtoState::Closed;
+                    Some((Err(e), pgb))
+                }
+            }
+        })
+    }
+
     /// Returns an AsyncWrite implementation that wraps all the data written
     /// to it in CopyData messages, and writes them to the connection
     ///
     /// The caller is responsible for sending CopyOutResponse and CopyDone messages.
     pub fn copyout_writer(&mut self) -> CopyDataWriter {
-        CopyDataWriter { pgb: self }
+        CopyDataWriter {
+            pgb: self,
+            max_chunk_size: DEFAULT_COPY_DATA_CHUNK_SIZE,
+        }
     }
 
     /// A polling function that tries to write all the data from 'buf_out' to the
@@ -254,10 +865,20 @@ impl PostgresBackend {
     ) -> Poll<Result<(), std::io::Error>> {
         while self.buf_out.has_remaining() {
             match ready!(Pin::new(&mut self.stream).poll_write(cx, self.buf_out.chunk())) {
-                Ok(bytes_written) => self.buf_out.advance(bytes_written),
+                Ok(bytes_written) => {
+                    self.buf_out.advance(bytes_written);
+                    if let Some(observer) = &self.io_observer {
+                        observer.bytes_written(bytes_written);
+                    }
+                }
                 Err(err) => return Poll::Ready(Err(err)),
             }
         }
+        release_buffer(
+            self.buf_out.buffer_mut(),
+            self.buffer_pool.as_deref(),
+            &self.buffer_config,
+        );
         Poll::Ready(Ok(()))
     }
 
@@ -291,18 +912,22 @@ impl PostgresBackend {
     {
         trace!("postgres backend to {:?} started", self.peer_addr);
 
-        tokio::select!(
+        let shutdown = tokio::select!(
             biased;
 
-            _ = shutdown_watcher() => {
-                // We were requested to shut down.
-                tracing::info!("shutdown request received during handshake");
-                return Ok(())
-            },
+            _ = shutdown_watcher() => true,
 
             result = async {
+                if self.allow_direct_tls
+                    && self.tls_config.is_some()
+                    && self.state == ProtoState::Initialization
+                    && self.starts_with_tls_handshake().await?
+                {
+                    self.start_direct_tls().await?;
+                }
+
                 while self.state < ProtoState::Established {
-                    if let Some(msg) = self.read_message().await? {
+                    if let Some(msg) = self.read_message_idle().await? {
                         trace!("got message {msg:?} during handshake");
 
                         match self.process_handshake_message(handler, msg).await? {
@@ -324,23 +949,53 @@ impl PostgresBackend {
             } => {
                 // Handshake complete.
                 result?;
+                false
             }
         );
+        if shutdown {
+            // We were requested to shut down.
+            tracing::info!("shutdown request received during handshake");
+            self.send_shutdown_error().await;
+            return Ok(());
+        }
 
         // Authentication completed
         let mut query_string = Bytes::new();
-        while let Some(msg) = tokio::select!(
-            biased;
-            _ = shutdown_watcher() => {
+        loop {
+            let msg = tokio::select!(
+                biased;
+                _ = shutdown_watcher() => None,
+                msg = self.read_message_idle() => Some(msg),
+            );
+            let Some(msg) = msg else {
                 // We were requested to shut down.
                 tracing::info!("shutdown request received in run_message_loop");
-                Ok(None)
-            },
-            msg = self.read_message() => { msg },
-        )? {
+                self.send_shutdown_error().await;
+                break;
+            };
+            let Some(msg) = msg? else {
+                break;
+            };
             trace!("got message {:?}", msg);
 
-            let result = self.process_message(handler, msg, &mut query_string).await;
+            // on shutdown, cancel the running query but let it finish
+            let cancel = self.cancellation_token();
+            let result = {
+                let process = self.process_message(handler, msg, &mut query_string);
+                tokio::pin!(process);
+                let done = tokio::select!(
+                    biased;
+                    result = &mut process => Some(result),
+                    _ = shutdown_watcher() => None,
+                );
+                match done {
+                    Some(result) => result,
+                    None => {
+                        cancel.cancel();
+                        process.await
+                    }
+                }
+            };
             self.flush().await?;
             match result? {
                 ProcessMsgResult::Continue => {
@@ -355,15 +1010,57 @@ impl PostgresBackend {
         Ok(())
     }
 
+    /// Tell the client that the connection is closed because of shutdown.
+    /// Failures are ignored, the connection is closed anyway.
+    async fn send_shutdown_error(&mut self) {
+        let error = shutdown_error();
+        if self.write_message(&BeMessage::PgError(&error)).is_ok() {
+            let _ = tokio::time::timeout(SHUTDOWN_FLUSH_TIMEOUT, self.flush()).await;
+        }
+    }
+
     async fn start_tls(&mut self) -> anyhow::Result<()> {
+        self.accept_tls(self.tls_config.clone().unwrap()).await?;
+        Ok(())
+    }
+
+    /// Whether the client started with TLS ClientHello instead of a
+    /// startup packet.
+    async fn starts_with_tls_handshake(&mut self) -> io::Result<bool> {
+        match &mut self.stream {
+            Stream::Unencrypted(stream) => {
+                Ok(stream.fill_buf().await?.first() == Some(&TLS_HANDSHAKE_RECORD_TYPE))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Accept direct TLS, which requires the postgres ALPN protocol.
+    async fn start_direct_tls(&mut self) -> anyhow::Result<()> {
+        debug!("direct TLS requested");
+        let config = direct_tls_config(self.tls_config.as_ref().unwrap());
+        let alpn = self.accept_tls(config).await?;
+        if alpn.as_deref() != Some(PG_ALPN_PROTOCOL) {
+            anyhow::bail!("direct TLS connection did not negotiate postgresql ALPN");
+        }
+        self.state = ProtoState::Encrypted;
+        Ok(())
+    }
+
+    /// Do TLS handshake, returning the negotiated ALPN protocol.
+    async fn accept_tls(
+        &mut self,
+        config: Arc<rustls::ServerConfig>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
         if let Stream::Unencrypted(plain_stream) =
             std::mem::replace(&mut self.stream, Stream::Broken)
         {
-            let acceptor = TlsAcceptor::from(self.tls_config.clone().unwrap());
+            let acceptor = TlsAcceptor::from(config);
             let tls_stream = acceptor.accept(plain_stream).await?;
+            let alpn = tls_stream.get_ref().1.alpn_protocol().map(|p| p.to_vec());
 
             self.stream = Stream::Tls(Box::new(tls_stream));
-            return Ok(());
+            return Ok(alpn);
         };
         anyhow::bail!("TLS already started");
     }
@@ -404,26 +1101,64 @@ impl PostgresBackend {
                             )));
                         }
 
+                        self.replication_mode = match m.replication_mode() {
+                            Ok(mode) => mode,
+                            Err(e) => {
+                                let error = PgError::builder(
+                                    SqlState::InvalidParameterValue,
+                                    e.to_string(),
+                                )
+                                .severity(Severity::Fatal)
+                                .build();
+                                self.write_message(&BeMessage::PgError(&error))?;
+                                return Err(QueryError::Other(e));
+                            }
+                        };
+
                         // NB: startup() may change self.auth_type -- we are using that in proxy code
                         // to bypass auth for new users.
                         handler.startup(self, &m)?;
 
                         match self.auth_type {
                             AuthType::Trust => {
-                                self.write_message(&BeMessage::AuthenticationOk)?
-                                    .write_message(&BeMessage::CLIENT_ENCODING)?
-                                    // The async python driver requires a valid server_version
-                                    .write_message(&BeMessage::server_version("14.1"))?
-                                    .write_message(&BeMessage::ReadyForQuery)?;
+                                let key = self.register_cancel_key();
+                                self.write_message(&BeMessage::AuthenticationOk)?;
+                                self.write_parameter_statuses()?;
+                                self.write_message(&BeMessage::BackendKeyData(key))?
+                                    .write_message(&BeMessage::READY_FOR_QUERY)?;
                                 self.state = ProtoState::Established;
                             }
                             AuthType::NeonJWT => {
                                 self.write_message(&BeMessage::AuthenticationCleartextPassword)?;
                                 self.state = ProtoState::Authentication;
                             }
+                            AuthType::Scram => {
+                                let user = m.user()?;
+                                let secret = handler.get_scram_secret(self, user)?;
+                                self.scram = Some(ScramExchange::new(user, secret));
+                                self.write_message(&BeMessage::AuthenticationSasl(
+                                    BeAuthenticationSaslMessage::Methods(SCRAM_METHODS),
+                                ))?;
+                                self.state = ProtoState::Authentication;
+                            }
+                            AuthType::Md5 => {
+                                let user = m.user()?;
+                                let secret = handler.get_md5_secret(self, user)?;
+                                let exchange = Md5Exchange::new(secret);
+                                self.write_message(&BeMessage::AuthenticationMD5Password(
+                                    exchange.salt(),
+                                ))?;
+                                self.md5 = Some(exchange);
+                                self.state = ProtoState::Authentication;
+                            }
                         }
                     }
-                    FeStartupPacket::CancelRequest { .. } => {
+                    FeStartupPacket::CancelRequest(key) => {
+                        if cancel_registry::cancel(&key) {
+                            info!("cancelling query on request from {}", self.peer_addr);
+                        } else {
+                            debug!("CancelRequest for unknown connection");
+                        }
                         self.state = ProtoState::Closed;
                         return Ok(ProcessMsgResult::Break);
                     }
@@ -441,17 +1176,58 @@ impl PostgresBackend {
                         let (_, jwt_response) = m.split_last().context("protocol violation")?;
 
                         if let Err(e) = handler.check_auth_jwt(self, jwt_response) {
+                            self.write_message(&BeMessage::PgError(&e.to_pg_error()))?;
+                            return Err(e);
+                        }
+                    }
+                    AuthType::Scram => {
+                        let exchange = self.scram.as_mut().context("protocol violation")?;
+                        match exchange.step(m) {
+                            Ok(ScramStep::Continue(msg)) => {
+                                self.write_message(&BeMessage::AuthenticationSasl(
+                                    BeAuthenticationSaslMessage::Continue(msg.as_bytes()),
+                                ))?;
+                                return Ok(ProcessMsgResult::Continue);
+                            }
+                            Ok(ScramStep::Success(msg)) => {
+                                self.scram = None;
+                                self.write_message(&BeMessage::AuthenticationSasl(
+                                    BeAuthenticationSaslMessage::Final(msg.as_bytes()),
+                                ))?;
+                            }
+                            Err(e) => {
+                                self.write_message(&BeMessage::ErrorResponse(
+                                    "password authentication failed",
+                                    Some(SQLSTATE_INVALID_PASSWORD),
+                                ))?;
+                                self.flush().await?;
+                                return Err(QueryError::Other(
+                                    e.context("password authentication failed"),
+                                ));
+                            }
+                        }
+                    }
+                    AuthType::Md5 => {
+                        let exchange = self.md5.take().context("protocol violation")?;
+                        if let Err(e) =
+                            parse_password_message(&m).and_then(|response| exchange.check(response))
+                        {
                             self.write_message(&BeMessage::ErrorResponse(
-                                &e.to_string(),
-                                Some(e.pg_error_code()),
+                                "password authentication failed",
+                                Some(SQLSTATE_INVALID_PASSWORD),
                             ))?;
-                            return Err(e);
+                            self.flush().await?;
+                            return Err(QueryError::Other(
+                                e.context("password authentication failed"),
+                            ));
                         }
                     }
                 }
-                self.write_message(&BeMessage::AuthenticationOk)?
-                    .write_message(&BeMessage::CLIENT_ENCODING)?
-                    .write_message(&BeMessage::ReadyForQuery)?;
+                let key = self.register_cancel_key();
+                self.write_message(&BeMessage::AuthenticationOk)?;
+                self.write_parameter_statuses()?;
+                self.write_message(&BeMessage::BackendKeyData(key))?
+                    .write_message(&BeMessage::READY_FOR_QUERY)?;
                 self.state = ProtoState::Established;
             }
 
@@ -483,15 +1259,11 @@ impl PostgresBackend {
                 let query_string = cstr_to_str(&body)?;
 
                 trace!("got query {query_string:?}");
-                if let Err(e) = handler.process_query(self, query_string).await {
+                if let Err(e) = self.run_query(handler, query_string).await? {
                     log_query_error(query_string, &e);
-                    let short_error = short_error(&e);
-                    self.write_message(&BeMessage::ErrorResponse(
-                        &short_error,
-                        Some(e.pg_error_code()),
-                    ))?;
+                    self.write_message(&BeMessage::PgError(&e.to_pg_error()))?;
                 }
-                self.write_message(&BeMessage::ReadyForQuery)?;
+                self.write_message(&BeMessage::ReadyForQuery(self.transaction_status))?;
             }
 
             FeMessage::Parse(m) => {
@@ -499,9 +1271,13 @@ impl PostgresBackend {
                 self.write_message(&BeMessage::ParseComplete)?;
             }
 
-            FeMessage::Describe(_) => {
-                self.write_message(&BeMessage::ParameterDescription)?
-                    .write_message(&BeMessage::NoData)?;
+            FeMessage::Describe(m) => {
+                // The unnamed statement has no parameters, and we don't know
+                // what the query returns before running it.
+                if m.kind == b'S' {
+                    self.write_message(&BeMessage::ParameterDescription)?;
+                }
+                self.write_message(&BeMessage::NoData)?;
             }
 
             FeMessage::Bind(_) => {
@@ -515,12 +1291,9 @@ impl PostgresBackend {
             FeMessage::Execute(_) => {
                 let query_string = cstr_to_str(unnamed_query_string)?;
                 trace!("got execute {query_string:?}");
-                if let Err(e) = handler.process_query(self, query_string).await {
+                if let Err(e) = self.run_query(handler, query_string).await? {
                     log_query_error(query_string, &e);
-                    self.write_message(&BeMessage::ErrorResponse(
-                        &e.to_string(),
-                        Some(e.pg_error_code()),
-                    ))?;
+                    self.write_message(&BeMessage::PgError(&e.to_pg_error()))?;
                 }
                 // NOTE there is no ReadyForQuery message. This handler is used
                 // for basebackup and it uses CopyOut which doesn't require
@@ -529,7 +1302,23 @@ impl PostgresBackend {
             }
 
             FeMessage::Sync => {
-                self.write_message(&BeMessage::ReadyForQuery)?;
+                self.write_message(&BeMessage::ReadyForQuery(self.transaction_status))?;
+            }
+
+            FeMessage::Flush => {
+                self.flush().await?;
+            }
+
+            FeMessage::FunctionCall(m) => {
+                let error = PgError::builder(
+                    SqlState::FeatureNotSupported,
+                    "fast-path function calls are not supported",
+                )
+                .detail(format!("function OID {}", m.function_oid))
+                .build();
+                self.fail_transaction();
+                self.write_message(&BeMessage::PgError(&error))?;
+                self.write_message(&BeMessage::ReadyForQuery(self.transaction_status))?;
             }
 
             FeMessage::Terminate => {
@@ -538,7 +1327,7 @@ impl PostgresBackend {
 
             // We prefer explicit pattern matching to wildcards, because
             // this helps us spot the places where new variants are missing
-            FeMessage::CopyData(_) | FeMessage::CopyDone | FeMessage::CopyFail => {
+            FeMessage::CopyData(_) | FeMessage::CopyDone | FeMessage::CopyFail(_) => {
                 return Err(QueryError::Other(anyhow::anyhow!(
                     "unexpected message type: {:?}",
                     msg
@@ -550,13 +1339,66 @@ impl PostgresBackend {
     }
 }
 
+/// Messages are written into the output buffer and sent on flush, like with
+/// [`PostgresBackend::write_message`] and [`PostgresBackend::flush`]. Sending
+/// waits for the buffer to be written out once it has grown above the flush
+/// threshold.
+impl<'a> Sink<BeMessage<'a>> for PostgresBackend {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.needs_flush() {
+            ready!(this.poll_write_buf(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: BeMessage<'a>) -> io::Result<()> {
+        self.get_mut().write_message(&item)?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        PostgresBackend::poll_flush(this, cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
 ///
 /// A futures::AsyncWrite implementation that wraps all data written to it in CopyData
-/// messages.
+/// messages of at most `max_chunk_size` bytes each, see
+/// [`PostgresBackend::copyout_writer`].
 ///
-
+/// Buffered messages are flushed before a new one is written, so at most one
+/// chunk is buffered. Messages are always written whole: the caller can drop
+/// the writer between writes to send other messages, e.g. keepalives, and
+/// take a new one.
 pub struct CopyDataWriter<'a> {
     pgb: &'a mut PostgresBackend,
+    max_chunk_size: usize,
+}
+
+impl CopyDataWriter<'_> {
+    /// Set the max size of the data in a single CopyData message.
+    pub fn with_max_chunk_size(mut self, max_chunk_size: usize) -> Self {
+        assert!(max_chunk_size > 0, "chunk size must be positive");
+        self.max_chunk_size = max_chunk_size;
+        self
+    }
+
+    /// Send a notice between CopyData messages, see
+    /// [`PostgresBackend::send_notice`].
+    pub async fn send_notice(&mut self, message: &str) -> io::Result<()> {
+        self.pgb.send_notice(message).await
+    }
 }
 
 impl<'a> AsyncWrite for CopyDataWriter<'a> {
@@ -575,13 +1417,12 @@ impl<'a> AsyncWrite for CopyDataWriter<'a> {
             Err(err) => return Poll::Ready(Err(err)),
         }
 
-        // CopyData
-        // XXX: if the input is large, we should split it into multiple messages.
-        // Not sure what the threshold should be, but the ultimate hard limit is that
-        // the length cannot exceed u32.
-        this.pgb.write_message(&BeMessage::CopyData(buf))?;
+        // CopyData, reporting a short write if the input doesn't fit into a
+        // single chunk; write_all() will pass the rest in the next call.
+        let chunk = &buf[..buf.len().min(this.max_chunk_size)];
+        this.pgb.write_message(&BeMessage::CopyData(chunk))?;
 
-        Poll::Ready(Ok(buf.len()))
+        Poll::Ready(Ok(chunk.len()))
     }
 
     fn poll_flush(
@@ -611,6 +1452,7 @@ impl<'a> AsyncWrite for CopyDataWriter<'a> {
 pub fn short_error(e: &QueryError) -> String {
     match e {
         QueryError::Disconnected(connection_error) => connection_error.to_string(),
+        QueryError::Cancelled => e.to_string(),
         QueryError::Other(e) => format!("{e:#}"),
     }
 }
@@ -627,8 +1469,443 @@ pub(super) fn log_query_error(query: &str, e: &QueryError) {
         QueryError::Disconnected(other_connection_error) => {
             error!("query handler for '{query}' failed with connection error: {other_connection_error:?}")
         }
+        QueryError::Cancelled => {
+            info!("query handler for '{query}' was cancelled");
+        }
         QueryError::Other(e) => {
             error!("query handler for '{query}' failed: {e:?}");
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer_pool::DEFAULT_READ_BUFFER_CAPACITY;
+    use bytes::BufMut;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn test_to_pg_error() {
+        let e = QueryError::Other(anyhow::anyhow!("disk full").context("failed to write"));
+        let pg_error = e.to_pg_error();
+        assert_eq!(&pg_error.code, b"XX000");
+        assert_eq!(pg_error.message, "failed to write: disk full");
+        assert_eq!(pg_error.detail, None);
+
+        let e = QueryError::Other(
+            anyhow::Error::new(
+                PgError::builder(SqlState::FeatureNotSupported, "not supported").build(),
+            )
+            .context("failed to run query"),
+        );
+        assert_eq!(e.pg_error_code(), b"0A000");
+        let pg_error = e.to_pg_error();
+        assert_eq!(pg_error.message, "not supported");
+        assert_eq!(pg_error.detail.as_deref(), Some("failed to run query"));
+
+        let e = QueryError::Other(anyhow::Error::new(
+            PgError::builder(SqlState::FeatureNotSupported, "not supported")
+                .detail("only in v15")
+                .build(),
+        ));
+        assert_eq!(e.to_pg_error().detail.as_deref(), Some("only in v15"));
+
+        assert_eq!(&QueryError::Cancelled.to_pg_error().code, b"57014");
+    }
+
+    #[tokio::test]
+    async fn test_flush_threshold() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        pgb.set_flush_threshold(Some(10));
+
+        let row = BeMessage::DataRow(&[Some(b"0123456789")]);
+        pgb.feed_message(&row).await.unwrap();
+        assert!(pgb.needs_flush());
+        // flushes the first row
+        pgb.feed_message(&row).await.unwrap();
+        let mut response = [0; 21];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"D\0\0\0\x14\0\x01\0\0\0\x0a0123456789");
+    }
+
+    #[tokio::test]
+    async fn test_write_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+
+        pgb.write_messages(&[
+            BeMessage::DataRow(&[Some(b"1")]),
+            BeMessage::CommandComplete(b"SELECT 1"),
+            BeMessage::READY_FOR_QUERY,
+        ])
+        .await
+        .unwrap();
+        let mut response = [0; 32];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(
+            &response,
+            b"D\0\0\0\x0b\0\x01\0\0\0\x011C\0\0\0\x0dSELECT 1\0Z\0\0\0\x05I"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffer_config() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        let (read, write) = pgb.buffer_capacities();
+        assert_eq!(read, DEFAULT_READ_BUFFER_CAPACITY);
+        assert!(write >= DEFAULT_BUFFER_CAPACITY);
+
+        pgb.set_buffer_config(BufferConfig::SMALL);
+        let (read, write) = pgb.buffer_capacities();
+        assert_eq!(read, BufferConfig::SMALL.read_capacity);
+        assert!(write >= BufferConfig::SMALL.write_capacity && write < DEFAULT_BUFFER_CAPACITY);
+
+        // buffers are returned to the pool when flushed
+        pgb.set_buffer_pool(Arc::new(BufferPool::new(1)));
+        assert_eq!(pgb.buffer_capacities().1, 0);
+    }
+
+    #[tokio::test]
+    async fn test_stream_and_sink() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        pgb.state = ProtoState::Established;
+
+        client.write_all(b"Q\0\0\0\x0dselect 1\0").await.unwrap();
+        client.write_all(b"X\0\0\0\x04").await.unwrap();
+        client.shutdown().await.unwrap();
+        let messages: Vec<_> = pgb
+            .messages()
+            .map(|msg| msg.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+        assert!(matches!(
+            messages[..],
+            [FeMessage::Query(_), FeMessage::Terminate]
+        ));
+
+        pgb.send(BeMessage::CommandComplete(b"SELECT 1"))
+            .await
+            .unwrap();
+        pgb.close().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"C\0\0\0\x0dSELECT 1\0");
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        struct Noop;
+        #[async_trait::async_trait]
+        impl Handler for Noop {
+            async fn process_query(
+                &mut self,
+                _pgb: &mut PostgresBackend,
+                _query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                Ok(())
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        pgb.set_idle_timeout(Some(Duration::from_millis(10)));
+        let res = pgb.run(&mut Noop, futures::future::pending::<()>).await;
+        match res {
+            Err(QueryError::Disconnected(ConnectionError::Socket(e))) => {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut)
+            }
+            res => panic!("unexpected result {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_error() {
+        struct Noop;
+        #[async_trait::async_trait]
+        impl Handler for Noop {
+            async fn process_query(
+                &mut self,
+                _pgb: &mut PostgresBackend,
+                _query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                Ok(())
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        pgb.run(&mut Noop, || async {}).await.unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response[0], b'E');
+        let body = &response[5..];
+        assert!(body.starts_with(b"SFATAL\0VFATAL\0C57P01\0"));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_status() {
+        struct Transactions;
+        #[async_trait::async_trait]
+        impl Handler for Transactions {
+            async fn process_query(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                match query_string {
+                    "begin" => pgb.set_transaction_status(TransactionStatus::InTransaction),
+                    "rollback" => pgb.set_transaction_status(TransactionStatus::Idle),
+                    _ => return Err(QueryError::Other(anyhow::anyhow!("failed"))),
+                }
+                Ok(())
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        pgb.state = ProtoState::Established;
+
+        for query in ["fail", "begin", "fail", "rollback"] {
+            let mut msg = BytesMut::new();
+            msg.put_u8(b'Q');
+            msg.put_u32(4 + query.len() as u32 + 1);
+            msg.put_slice(query.as_bytes());
+            msg.put_u8(0);
+            client.write_all(&msg).await.unwrap();
+        }
+        client.write_all(b"X\0\0\0\x04").await.unwrap();
+        pgb.run(&mut Transactions, futures::future::pending::<()>)
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let mut statuses = Vec::new();
+        let mut buf = &response[..];
+        while !buf.is_empty() {
+            let len = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+            if buf[0] == b'Z' {
+                statuses.push(buf[5]);
+            }
+            buf = &buf[1 + len..];
+        }
+        assert_eq!(statuses, b"ITEI");
+    }
+
+    #[tokio::test]
+    async fn test_send_notice() {
+        /// Holds the client, to check the notice arrives during the query.
+        struct Notice(TcpStream);
+        #[async_trait::async_trait]
+        impl Handler for Notice {
+            async fn process_query(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                _query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                pgb.start_copy(CopyMode::Out).await?;
+                let mut writer = pgb.copyout_writer();
+                writer.write_all(b"1").await?;
+                writer.send_notice("lagging").await?;
+                // flushed right away, between the CopyData messages
+                let mut response = [0; 52];
+                self.0.read_exact(&mut response).await.unwrap();
+                assert_eq!(&response[..8], b"H\0\0\0\x07\0\0\0");
+                assert_eq!(&response[8..14], b"d\0\0\0\x051");
+                assert_eq!(&response[14..19], b"N\0\0\0\x25");
+                assert_eq!(&response[19..], b"SNOTICE\0VNOTICE\0C00000\0Mlagging\0\0");
+                pgb.finish_copy_out().await?;
+                Ok(())
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        pgb.state = ProtoState::Established;
+
+        client.write_all(b"Q\0\0\0\x0dselect 1\0").await.unwrap();
+        client.write_all(b"X\0\0\0\x04").await.unwrap();
+        let mut handler = Notice(client);
+        pgb.run(&mut handler, futures::future::pending::<()>)
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        handler.0.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"c\0\0\0\x04Z\0\0\0\x05I");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_disconnect_cancels_query() {
+        struct WaitCancel(bool);
+        #[async_trait::async_trait]
+        impl Handler for WaitCancel {
+            async fn process_query(
+                &mut self,
+                _pgb: &mut PostgresBackend,
+                _query_string: &str,
+                cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                tokio::time::timeout(Duration::from_secs(10), cancel.cancelled())
+                    .await
+                    .map_err(|_| QueryError::Other(anyhow::anyhow!("not cancelled")))?;
+                self.0 = true;
+                Err(QueryError::Cancelled)
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+        pgb.state = ProtoState::Established;
+
+        // the client is gone while the query runs
+        client.write_all(b"Q\0\0\0\x0dselect 1\0").await.unwrap();
+        drop(client);
+        let mut handler = WaitCancel(false);
+        let res = pgb.run(&mut handler, futures::future::pending::<()>).await;
+        assert!(handler.0);
+        assert!(
+            matches!(res, Err(QueryError::Disconnected(_))),
+            "unexpected result {res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parameter_status() {
+        struct Params;
+        #[async_trait::async_trait]
+        impl Handler for Params {
+            async fn process_query(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                query_string: &str,
+                _cancel: &CancellationToken,
+            ) -> Result<(), QueryError> {
+                pgb.set_parameter_status("application_name", query_string)?;
+                Ok(())
+            }
+
+            fn startup(
+                &mut self,
+                pgb: &mut PostgresBackend,
+                _sm: &FeStartupPacket,
+            ) -> Result<(), QueryError> {
+                pgb.set_parameter_status("server_version", "15.2")?;
+                pgb.set_parameter_status("application_name", "test")?;
+                Ok(())
+            }
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+
+        let params = b"user\0test\0\0";
+        let mut msg = BytesMut::new();
+        msg.put_u32(8 + params.len() as u32);
+        msg.put_u32(196608); // protocol 3.0
+        msg.put_slice(params);
+        msg.put_slice(b"Q\0\0\0\x08app\0");
+        msg.put_slice(b"X\0\0\0\x04");
+        client.write_all(&msg).await.unwrap();
+        pgb.run(&mut Params, futures::future::pending::<()>)
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        let mut statuses = Vec::new();
+        let mut buf = &response[..];
+        while !buf.is_empty() {
+            let len = u32::from_be_bytes(buf[1..5].try_into().unwrap()) as usize;
+            if buf[0] == b'S' {
+                statuses.push(&buf[5..1 + len]);
+            }
+            buf = &buf[1 + len..];
+        }
+        // overridden standard parameters are reported with the set ones,
+        // and changes while the connection is established right away
+        assert_eq!(
+            statuses,
+            [
+                &b"client_encoding\0UTF8\0"[..],
+                b"DateStyle\0ISO, MDY\0",
+                b"integer_datetimes\0on\0",
+                b"standard_conforming_strings\0on\0",
+                b"server_version\x0015.2\0",
+                b"application_name\0test\0",
+                b"application_name\0app\0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy_data_chunking() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut pgb = PostgresBackend::new(socket, AuthType::Trust, None).unwrap();
+
+        let mut writer = pgb.copyout_writer().with_max_chunk_size(4);
+        writer.write_all(b"0123456789").await.unwrap();
+        writer.flush().await.unwrap();
+        drop(pgb);
+
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"d\0\0\0\x080123d\0\0\0\x084567d\0\0\0\x0689");
+    }
+}
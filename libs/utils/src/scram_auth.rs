@@ -0,0 +1,187 @@
+//! Server side of SCRAM-SHA-256 authentication, with which the password is
+//! never sent over the wire, not even hashed. The exchange is the one the
+//! proxy uses, from the `scram` crate; this only adapts it to the messages
+//! the postgres backends receive.
+//!
+//! Like in Postgres, the user name in the SCRAM messages is ignored in favor
+//! of the one from the startup packet. Channel binding is not supported.
+
+use anyhow::{bail, ensure, Context};
+use bytes::Bytes;
+use pq_proto::FeSaslInitialResponse;
+use scram::sasl::{Mechanism, Step};
+use scram::Exchange;
+
+pub use scram::{ServerSecret, METHODS};
+
+/// Result of a successful exchange step.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ScramStep {
+    /// Send the message in AuthenticationSASLContinue and wait for the next
+    /// response.
+    Continue(String),
+    /// Client is authenticated; send the message in AuthenticationSASLFinal.
+    Success(String),
+}
+
+/// Server side of the exchange with a single client. Feed it the bodies of
+/// PasswordMessage's the client sends after AuthenticationSASL; any error
+/// means authentication failed.
+pub struct ScramExchange {
+    /// None once the exchange is finished.
+    exchange: Option<Exchange<'static>>,
+    /// Whether SASLInitialResponse has been received.
+    started: bool,
+}
+
+impl ScramExchange {
+    /// Start the exchange with the secret of the user; None means the user
+    /// doesn't exist, but the exchange still goes on till the end.
+    pub fn new(user: &str, secret: Option<ServerSecret>) -> Self {
+        let secret = secret.unwrap_or_else(|| ServerSecret::mock(user, rand::random()));
+        ScramExchange {
+            exchange: Some(Exchange::new(secret, rand::random, None)),
+            started: false,
+        }
+    }
+
+    /// Process the next message of the client.
+    pub fn step(&mut self, msg: Bytes) -> anyhow::Result<ScramStep> {
+        let exchange = self
+            .exchange
+            .take()
+            .context("SCRAM exchange is already finished")?;
+
+        let data = if self.started {
+            msg
+        } else {
+            let initial = FeSaslInitialResponse::parse(msg)?;
+            ensure!(
+                METHODS
+                    .iter()
+                    .any(|m| m.as_bytes() == &initial.mechanism[..]),
+                "unsupported SASL mechanism {:?}",
+                String::from_utf8_lossy(&initial.mechanism)
+            );
+            self.started = true;
+            initial.data.context("missing client-first-message")?
+        };
+        let input = std::str::from_utf8(&data).context("invalid UTF-8")?;
+
+        match exchange.exchange(input)? {
+            Step::Continue(exchange, msg) => {
+                self.exchange = Some(exchange);
+                Ok(ScramStep::Continue(msg))
+            }
+            Step::Success(_client_key, msg) => Ok(ScramStep::Success(msg)),
+            Step::Failure(reason) => bail!(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+    use scram::password::SaltedPassword;
+    use scram::signature::SignatureBuilder;
+
+    const CLIENT_FIRST: &str = "n,,n=user,r=rOprNGfwEbeRWgbNEkqO";
+    const SALT: &[u8] = b"salt";
+
+    fn initial_response(message: &str) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_slice(METHODS[0].as_bytes());
+        buf.put_u8(0);
+        buf.put_i32(message.len() as i32);
+        buf.put_slice(message.as_bytes());
+        buf.freeze()
+    }
+
+    /// Client side of the final step: client-final-message, and the
+    /// server-final-message the client expects in return.
+    fn client_final(password: &str, server_first: &str) -> (String, String) {
+        let mut attrs = server_first.split(',');
+        let nonce = attrs.next().unwrap().strip_prefix("r=").unwrap();
+        let salt = base64::decode(attrs.next().unwrap().strip_prefix("s=").unwrap()).unwrap();
+        let iterations = attrs.next().unwrap().strip_prefix("i=").unwrap();
+
+        let salted = SaltedPassword::new(password.as_bytes(), &salt, iterations.parse().unwrap());
+        let client_key = salted.client_key();
+        let without_proof = format!("c=biws,r={nonce}");
+        let signature_builder = SignatureBuilder {
+            client_first_message_bare: CLIENT_FIRST.strip_prefix("n,,").unwrap(),
+            server_first_message: server_first,
+            client_final_message_without_proof: &without_proof,
+        };
+        let proof = signature_builder
+            .build(&client_key.sha256())
+            .derive_client_key(&client_key.as_bytes());
+        let server_signature = signature_builder.build(&salted.server_key());
+        (
+            format!("{without_proof},p={}", base64::encode(proof)),
+            format!("v={}", base64::encode(server_signature)),
+        )
+    }
+
+    fn start(exchange: &mut ScramExchange) -> String {
+        match exchange.step(initial_response(CLIENT_FIRST)).unwrap() {
+            ScramStep::Continue(server_first) => server_first,
+            step => panic!("expected server-first-message, got {step:?}"),
+        }
+    }
+
+    #[test]
+    fn test_exchange() {
+        let secret = ServerSecret::build("pencil", SALT, 4096).unwrap();
+        let mut exchange = ScramExchange::new("user", Some(secret));
+        let server_first = start(&mut exchange);
+        assert!(server_first.starts_with("r=rOprNGfwEbeRWgbNEkqO"));
+
+        let (client_final, server_final) = client_final("pencil", &server_first);
+        assert_eq!(
+            exchange.step(Bytes::from(client_final)).unwrap(),
+            ScramStep::Success(server_final)
+        );
+        assert!(exchange.step(Bytes::new()).is_err());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let secret = ServerSecret::build("pencil", SALT, 4096).unwrap();
+        let mut exchange = ScramExchange::new("user", Some(secret));
+        let server_first = start(&mut exchange);
+        let (client_final, _) = client_final("pencil2", &server_first);
+        assert!(exchange.step(Bytes::from(client_final)).is_err());
+    }
+
+    #[test]
+    fn test_unknown_user() {
+        let mut exchange = ScramExchange::new("user", None);
+        // looks like a normal exchange until the end
+        let server_first = start(&mut exchange);
+        let (client_final, _) = client_final("pencil", &server_first);
+        assert!(exchange.step(Bytes::from(client_final)).is_err());
+    }
+
+    #[test]
+    fn test_bad_messages() {
+        let secret = ServerSecret::build("pencil", SALT, 4096).unwrap();
+
+        let mut exchange = ScramExchange::new("user", Some(secret.clone()));
+        let mut msg = BytesMut::from(&b"SCRAM-SHA-1\0"[..]);
+        msg.put_i32(CLIENT_FIRST.len() as i32);
+        msg.put_slice(CLIENT_FIRST.as_bytes());
+        assert!(exchange.step(msg.freeze()).is_err());
+
+        let mut exchange = ScramExchange::new("user", Some(secret.clone()));
+        assert!(exchange.step(initial_response("p=foo,,n=,r=abc")).is_err());
+
+        let mut exchange = ScramExchange::new("user", Some(secret));
+        let server_first = start(&mut exchange);
+        // nonce of another exchange
+        let (client_final, _) = client_final("pencil", &server_first);
+        let client_final = client_final.replacen(",r=rOpr", ",r=xOpr", 1);
+        assert!(exchange.step(Bytes::from(client_final)).is_err());
+    }
+}
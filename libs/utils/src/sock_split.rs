@@ -1,17 +1,38 @@
 use std::{
-    io::{self, BufReader, Write},
+    io::{self, BufRead, BufReader, Write},
     net::{Shutdown, TcpStream},
     sync::Arc,
 };
 
+use bytes::{Buf, Bytes, BytesMut};
 use rustls::Connection;
 
 /// Wrapper supporting reads of a shared TcpStream.
-pub struct ArcTcpRead(Arc<TcpStream>);
+pub struct ArcTcpRead {
+    stream: Arc<TcpStream>,
+    /// Data read from the socket into a previous read buffer, returned
+    /// before reading the socket again.
+    pending: Bytes,
+}
+
+impl ArcTcpRead {
+    fn new(stream: Arc<TcpStream>) -> Self {
+        ArcTcpRead {
+            stream,
+            pending: Bytes::new(),
+        }
+    }
+}
 
 impl io::Read for ArcTcpRead {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        (&*self.0).read(buf)
+        if !self.pending.is_empty() {
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.advance(n);
+            return Ok(n);
+        }
+        (&*self.stream).read(buf)
     }
 }
 
@@ -19,7 +40,7 @@ impl std::ops::Deref for ArcTcpRead {
     type Target = TcpStream;
 
     fn deref(&self) -> &Self::Target {
-        self.0.deref()
+        self.stream.deref()
     }
 }
 
@@ -50,7 +71,7 @@ impl BufStream {
 
     /// Returns a reference to the underlying TcpStream.
     fn get_ref(&self) -> &TcpStream {
-        &self.0.get_ref().0
+        &self.0.get_ref().stream
     }
 }
 
@@ -117,7 +138,26 @@ pub enum BidiStream {
 
 impl BidiStream {
     pub fn from_tcp(stream: TcpStream) -> Self {
-        Self::Tcp(BufStream(BufReader::new(ArcTcpRead(Arc::new(stream)))))
+        Self::Tcp(BufStream(BufReader::new(ArcTcpRead::new(Arc::new(stream)))))
+    }
+
+    /// Replace the read buffer with one of `capacity`. Data already buffered,
+    /// e.g. pipelined by the client, is read first. Only possible before TLS
+    /// is started; returns whether it was done.
+    pub fn set_read_capacity(&mut self, capacity: usize) -> bool {
+        match self {
+            Self::Tcp(BufStream(reader)) => {
+                let mut pending = BytesMut::from(reader.buffer());
+                pending.extend_from_slice(&reader.get_ref().pending);
+                let inner = ArcTcpRead {
+                    stream: Arc::clone(&reader.get_ref().stream),
+                    pending: pending.freeze(),
+                };
+                *reader = BufReader::with_capacity(capacity, inner);
+                true
+            }
+            Self::Tls(_) => false,
+        }
     }
 
     pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
@@ -141,18 +181,19 @@ impl BidiStream {
         match self {
             Self::Tcp(stream) => {
                 let reader = stream.into_reader();
-                let stream: Arc<TcpStream> = reader.get_ref().0.clone();
+                let stream: Arc<TcpStream> = reader.get_ref().stream.clone();
 
                 (ReadStream::Tcp(reader), WriteStream::Tcp(stream))
             }
             Self::Tls(tls_boxed) => {
                 let reader = tls_boxed.sock.into_reader();
-                let buffer_data = reader.buffer().to_owned();
+                let mut buffer_data = reader.buffer().to_owned();
+                buffer_data.extend_from_slice(&reader.get_ref().pending);
                 let read_buf_cfg = rustls_split::BufCfg::with_data(buffer_data, 8192);
                 let write_buf_cfg = rustls_split::BufCfg::with_capacity(8192);
 
                 // TODO would be nice to avoid the Arc here
-                let socket = Arc::try_unwrap(reader.into_inner().0).unwrap();
+                let socket = Arc::try_unwrap(reader.into_inner().stream).unwrap();
 
                 let (read_half, write_half) = rustls_split::split(
                     socket,
@@ -165,6 +206,45 @@ impl BidiStream {
         }
     }
 
+    /// Look at the next byte to be read without consuming it, None on EOF.
+    /// Only possible before TLS is started.
+    pub fn peek_byte(&mut self) -> io::Result<Option<u8>> {
+        match self {
+            Self::Tcp(stream) => Ok(stream.0.fill_buf()?.first().copied()),
+            Self::Tls { .. } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't peek into TLS stream",
+            )),
+        }
+    }
+
+    /// Wait until there is data to read, without consuming it. With a socket
+    /// read timeout set, fails with `WouldBlock` when it expires, leaving the
+    /// stream intact unlike a read timing out in the middle of a message.
+    pub fn wait_readable(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.0.fill_buf().map(|_| ()),
+            Self::Tls(tls_boxed) => {
+                let state = tls_boxed
+                    .conn
+                    .process_new_packets()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                if state.plaintext_bytes_to_read() > 0 {
+                    return Ok(());
+                }
+                tls_boxed.sock.0.fill_buf().map(|_| ())
+            }
+        }
+    }
+
+    /// ALPN protocol negotiated during TLS handshake.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match self {
+            Self::Tcp(_) => None,
+            Self::Tls(tls_boxed) => tls_boxed.conn.alpn_protocol(),
+        }
+    }
+
     pub fn start_tls(self, mut conn: rustls::ServerConnection) -> io::Result<Self> {
         match self {
             Self::Tcp(mut stream) => {
@@ -2,9 +2,11 @@ use std::{
     io,
     net::{TcpListener, ToSocketAddrs},
     os::unix::prelude::AsRawFd,
+    time::Duration,
 };
 
 use nix::sys::socket::{setsockopt, sockopt::ReuseAddr};
+use socket2::{SockRef, TcpKeepalive};
 
 /// Bind a [`TcpListener`] to addr with `SO_REUSEADDR` set to true.
 pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
@@ -14,3 +16,61 @@ pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<TcpListener> {
 
     Ok(listener)
 }
+
+/// Socket options of accepted connections, set with [`Self::apply`].
+/// Keepalive and user timeout make the kernel drop half-open connections
+/// sooner than its defaults would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send small messages right away, `TCP_NODELAY`.
+    pub nodelay: bool,
+    /// Enable keepalive, sending the first probe after the connection is
+    /// idle for this long.
+    pub keepalive_time: Option<Duration>,
+    /// Interval between keepalive probes, the kernel default if None.
+    pub keepalive_interval: Option<Duration>,
+    /// Drop the connection after this many unanswered keepalive probes, the
+    /// kernel default if None.
+    pub keepalive_retries: Option<u32>,
+    /// Drop the connection when sent data stays unacknowledged for this
+    /// long, `TCP_USER_TIMEOUT`.
+    pub user_timeout: Option<Duration>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            nodelay: true,
+            keepalive_time: None,
+            keepalive_interval: None,
+            keepalive_retries: None,
+            user_timeout: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    /// Set the options on the socket. User timeout is only supported on
+    /// Linux and ignored elsewhere.
+    pub fn apply(&self, socket: &impl AsRawFd) -> io::Result<()> {
+        let sock = SockRef::from(socket);
+        sock.set_nodelay(self.nodelay)?;
+        if let Some(time) = self.keepalive_time {
+            let mut keepalive = TcpKeepalive::new().with_time(time);
+            if let Some(interval) = self.keepalive_interval {
+                keepalive = keepalive.with_interval(interval);
+            }
+            if let Some(retries) = self.keepalive_retries {
+                keepalive = keepalive.with_retries(retries);
+            }
+            sock.set_tcp_keepalive(&keepalive)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(timeout) = self.user_timeout {
+            use nix::sys::socket::sockopt::TcpUserTimeout;
+            let millis = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+            setsockopt(socket.as_raw_fd(), TcpUserTimeout, &millis)?;
+        }
+        Ok(())
+    }
+}
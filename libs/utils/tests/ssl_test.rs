@@ -10,7 +10,8 @@ use bytes::{Buf, BufMut, Bytes, BytesMut};
 use once_cell::sync::Lazy;
 
 use utils::{
-    postgres_backend::{AuthType, Handler, PostgresBackend},
+    cancel_registry::CancellationToken,
+    postgres_backend::{AuthType, Handler, PostgresBackend, PG_ALPN_PROTOCOL},
     postgres_backend_async::QueryError,
 };
 
@@ -108,6 +109,7 @@ fn ssl() {
             &mut self,
             _pgb: &mut PostgresBackend,
             query_string: &str,
+            _cancel: &CancellationToken,
         ) -> Result<(), QueryError> {
             self.got_query = query_string == QUERY;
             Ok(())
@@ -155,6 +157,7 @@ fn no_ssl() {
             &mut self,
             _pgb: &mut PostgresBackend,
             _query_string: &str,
+            _cancel: &CancellationToken,
         ) -> Result<(), QueryError> {
             panic!()
         }
@@ -215,6 +218,7 @@ fn server_forces_ssl() {
             &mut self,
             _pgb: &mut PostgresBackend,
             _query_string: &str,
+            _cancel: &CancellationToken,
         ) -> Result<(), QueryError> {
             panic!()
         }
@@ -236,3 +240,178 @@ fn server_forces_ssl() {
 
     // TODO consider shutdown behavior
 }
+
+fn tls_client_config(alpn: Option<&[u8]>) -> Arc<rustls::ClientConfig> {
+    let mut cfg = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates({
+            let mut store = rustls::RootCertStore::empty();
+            store.add(&CERT).unwrap();
+            store
+        })
+        .with_no_client_auth();
+    cfg.alpn_protocols = alpn.into_iter().map(|p| p.to_vec()).collect();
+    Arc::new(cfg)
+}
+
+fn tls_server_config() -> Option<Arc<rustls::ServerConfig>> {
+    let cfg = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(vec![CERT.clone()], KEY.clone())
+        .unwrap();
+    Some(Arc::new(cfg))
+}
+
+#[test]
+fn direct_ssl() {
+    let (mut client_sock, server_sock) = make_tcp_pair();
+
+    let client_jh = std::thread::spawn(move || {
+        // ClientHello right away, without SSLRequest
+        let dns_name = "localhost".try_into().unwrap();
+        let mut conn =
+            rustls::ClientConnection::new(tls_client_config(Some(PG_ALPN_PROTOCOL)), dns_name)
+                .unwrap();
+        conn.complete_io(&mut client_sock).unwrap();
+        assert!(!conn.is_handshaking());
+        assert_eq!(conn.alpn_protocol(), Some(PG_ALPN_PROTOCOL));
+
+        let mut stream = rustls::Stream::new(&mut conn, &mut client_sock);
+
+        // StartupMessage
+        stream.write_u32::<BigEndian>(9).unwrap();
+        stream.write_u32::<BigEndian>(196608).unwrap();
+        stream.write_u8(0).unwrap();
+        stream.flush().unwrap();
+
+        // wait for ReadyForQuery
+        let mut msg_buf = Vec::new();
+        loop {
+            let msg = stream.read_u8().unwrap();
+            let size = stream.read_u32::<BigEndian>().unwrap() - 4;
+            msg_buf.resize(size as usize, 0);
+            stream.read_exact(&mut msg_buf).unwrap();
+
+            if msg == b'Z' {
+                // ReadyForQuery
+                break;
+            }
+        }
+
+        // Terminate
+        stream.write_u8(b'X').unwrap();
+        stream.write_u32::<BigEndian>(4).unwrap();
+        stream.flush().unwrap();
+    });
+
+    struct TestHandler;
+    impl Handler for TestHandler {
+        fn process_query(
+            &mut self,
+            _pgb: &mut PostgresBackend,
+            _query_string: &str,
+            _cancel: &CancellationToken,
+        ) -> Result<(), QueryError> {
+            panic!()
+        }
+    }
+    let mut handler = TestHandler;
+
+    let mut pgb =
+        PostgresBackend::new(server_sock, AuthType::Trust, tls_server_config(), true).unwrap();
+    pgb.allow_direct_tls = true;
+    pgb.run(&mut handler).unwrap();
+
+    client_jh.join().unwrap();
+}
+
+#[test]
+fn direct_ssl_requires_alpn() {
+    let (mut client_sock, server_sock) = make_tcp_pair();
+
+    let client_jh = std::thread::spawn(move || {
+        let dns_name = "localhost".try_into().unwrap();
+        let mut conn = rustls::ClientConnection::new(tls_client_config(None), dns_name).unwrap();
+        // the server drops the connection right after the handshake
+        let _ = conn.complete_io(&mut client_sock);
+    });
+
+    struct TestHandler;
+    impl Handler for TestHandler {
+        fn process_query(
+            &mut self,
+            _pgb: &mut PostgresBackend,
+            _query_string: &str,
+            _cancel: &CancellationToken,
+        ) -> Result<(), QueryError> {
+            panic!()
+        }
+    }
+    let mut handler = TestHandler;
+
+    let mut pgb =
+        PostgresBackend::new(server_sock, AuthType::Trust, tls_server_config(), true).unwrap();
+    pgb.allow_direct_tls = true;
+    let res = pgb.run(&mut handler).unwrap_err();
+    assert_eq!(
+        "direct TLS connection did not negotiate postgresql ALPN",
+        format!("{}", res)
+    );
+
+    client_jh.join().unwrap();
+}
+
+#[test]
+fn invalid_replication_parameter() {
+    let (mut client_sock, server_sock) = make_tcp_pair();
+
+    let client_jh = std::thread::spawn(move || {
+        // StartupMessage with replication=walsender
+        let mut buf = BytesMut::new();
+        buf.put_u32(31);
+        buf.put_u32(196608);
+        buf.put_slice(b"replication\0walsender\0\0");
+        client_sock.write_all(&buf).unwrap();
+
+        // ErrorResponse
+        assert_eq!(client_sock.read_u8().unwrap(), b'E');
+        let len = client_sock.read_u32::<BigEndian>().unwrap() - 4;
+        let mut body = vec![0; len as usize];
+        client_sock.read_exact(&mut body).unwrap();
+
+        let fields: HashMap<u8, &[u8]> = body
+            .split(|&b| b == 0u8)
+            .filter(|field| !field.is_empty())
+            .map(|field| (field[0], &field[1..]))
+            .collect();
+        assert_eq!(fields[&b'S'], b"FATAL");
+        assert_eq!(fields[&b'C'], b"22023");
+        assert_eq!(
+            fields[&b'M'],
+            b"invalid value for parameter \"replication\": \"walsender\""
+        );
+    });
+
+    struct TestHandler;
+    impl Handler for TestHandler {
+        fn process_query(
+            &mut self,
+            _pgb: &mut PostgresBackend,
+            _query_string: &str,
+            _cancel: &CancellationToken,
+        ) -> Result<(), QueryError> {
+            panic!()
+        }
+    }
+    let mut handler = TestHandler;
+
+    let pgb = PostgresBackend::new(server_sock, AuthType::Trust, None, true).unwrap();
+    let res = pgb.run(&mut handler).unwrap_err();
+    assert_eq!(
+        "invalid value for parameter \"replication\": \"walsender\"",
+        format!("{}", res)
+    );
+
+    client_jh.join().unwrap();
+}
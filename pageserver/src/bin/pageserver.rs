@@ -278,6 +278,8 @@ fn start_pageserver(
             let key_path = conf.auth_validation_public_key_path.as_ref().unwrap();
             Some(JwtAuth::from_key_path(key_path)?.into())
         }
+        AuthType::Scram => anyhow::bail!("SCRAM auth is not supported by pageserver"),
+        AuthType::Md5 => anyhow::bail!("md5 auth is not supported by pageserver"),
     };
     info!("Using auth: {:#?}", conf.auth_type);
 
@@ -164,6 +164,9 @@ pub struct PageServerConf {
     pub test_remote_failures: u64,
 
     pub ondemand_download_behavior_treat_error_as_warn: bool,
+
+    /// Ask safekeepers to compress the streamed WAL with zstd.
+    pub wal_receiver_compression: bool,
 }
 
 /// We do not want to store this in a PageServerConf because the latter may be logged
@@ -231,6 +234,8 @@ struct PageServerConfigBuilder {
     test_remote_failures: BuilderValue<u64>,
 
     ondemand_download_behavior_treat_error_as_warn: BuilderValue<bool>,
+
+    wal_receiver_compression: BuilderValue<bool>,
 }
 
 impl Default for PageServerConfigBuilder {
@@ -282,6 +287,8 @@ impl Default for PageServerConfigBuilder {
             test_remote_failures: Set(0),
 
             ondemand_download_behavior_treat_error_as_warn: Set(false),
+
+            wal_receiver_compression: Set(false),
         }
     }
 }
@@ -394,6 +401,10 @@ impl PageServerConfigBuilder {
             BuilderValue::Set(ondemand_download_behavior_treat_error_as_warn);
     }
 
+    pub fn wal_receiver_compression(&mut self, wal_receiver_compression: bool) {
+        self.wal_receiver_compression = BuilderValue::Set(wal_receiver_compression);
+    }
+
     pub fn build(self) -> anyhow::Result<PageServerConf> {
         Ok(PageServerConf {
             listen_pg_addr: self
@@ -461,6 +472,9 @@ impl PageServerConfigBuilder {
                 .ok_or(anyhow!(
                     "missing ondemand_download_behavior_treat_error_as_warn"
                 ))?,
+            wal_receiver_compression: self
+                .wal_receiver_compression
+                .ok_or(anyhow!("missing wal_receiver_compression"))?,
         })
     }
 }
@@ -641,6 +655,7 @@ impl PageServerConf {
                     builder.synthetic_size_calculation_interval(parse_toml_duration(key, item)?),
                 "test_remote_failures" => builder.test_remote_failures(parse_toml_u64(key, item)?),
                 "ondemand_download_behavior_treat_error_as_warn" => builder.ondemand_download_behavior_treat_error_as_warn(parse_toml_bool(key, item)?),
+                "wal_receiver_compression" => builder.wal_receiver_compression(parse_toml_bool(key, item)?),
                 _ => bail!("unrecognized pageserver option '{key}'"),
             }
         }
@@ -774,6 +789,7 @@ impl PageServerConf {
             synthetic_size_calculation_interval: Duration::from_secs(60),
             test_remote_failures: 0,
             ondemand_download_behavior_treat_error_as_warn: false,
+            wal_receiver_compression: false,
         }
     }
 }
@@ -967,6 +983,7 @@ log_format = 'json'
                 )?,
                 test_remote_failures: 0,
                 ondemand_download_behavior_treat_error_as_warn: false,
+                wal_receiver_compression: false,
             },
             "Correct defaults should be used when no config values are provided"
         );
@@ -1016,6 +1033,7 @@ log_format = 'json'
                 synthetic_size_calculation_interval: Duration::from_secs(333),
                 test_remote_failures: 0,
                 ondemand_download_behavior_treat_error_as_warn: false,
+                wal_receiver_compression: false,
             },
             "Should be able to parse all basic config values correctly"
         );
@@ -1072,6 +1090,7 @@ broker_endpoint = '{broker_endpoint}'
                     max_sync_errors: NonZeroU32::new(remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS)
                         .unwrap(),
                     storage: RemoteStorageKind::LocalFs(local_storage_path.clone()),
+                    retry: None,
                 },
                 "Remote storage config should correctly parse the local FS config and fill other storage defaults"
             );
@@ -1141,6 +1160,7 @@ broker_endpoint = '{broker_endpoint}'
                         endpoint: Some(endpoint.clone()),
                         concurrency_limit: s3_concurrency_limit,
                     }),
+                    retry: None,
                 },
                 "Remote storage config should correctly parse the S3 config"
             );
@@ -33,10 +33,11 @@ use tracing::*;
 use utils::id::ConnectionId;
 use utils::{
     auth::{Claims, JwtAuth, Scope},
+    cancel_registry::CancellationToken,
     id::{TenantId, TimelineId},
     lsn::Lsn,
     postgres_backend::AuthType,
-    postgres_backend_async::{self, is_expected_io_error, PostgresBackend, QueryError},
+    postgres_backend_async::{self, is_expected_io_error, CopyMode, PostgresBackend, QueryError},
     simple_rcu::RcuReadGuard,
 };
 
@@ -58,56 +59,34 @@ use postgres_ffi::BLCKSZ;
 fn copyin_stream(pgb: &mut PostgresBackend) -> impl Stream<Item = io::Result<Bytes>> + '_ {
     async_stream::try_stream! {
         loop {
-            let msg = tokio::select! {
+            let res = tokio::select! {
                 biased;
 
                 _ = task_mgr::shutdown_watcher() => {
                     // We were requested to shut down.
-                    let msg = format!("pageserver is shutting down");
-                    let _ = pgb.write_message(&BeMessage::ErrorResponse(&msg, None));
-                    Err(QueryError::Other(anyhow::anyhow!(msg)))
+                    Err(QueryError::Other(anyhow::anyhow!("pageserver is shutting down")))
                 }
 
-                msg = pgb.read_message() => { msg }
+                res = pgb.read_copy_data() => { res }
             };
 
-            match msg {
-                Ok(Some(message)) => {
-                    let copy_data_bytes = match message {
-                        FeMessage::CopyData(bytes) => bytes,
-                        FeMessage::CopyDone => { break },
-                        FeMessage::Sync => continue,
-                        FeMessage::Terminate => {
-                            let msg = "client terminated connection with Terminate message during COPY";
-                            let query_error_error = QueryError::Disconnected(ConnectionError::Socket(io::Error::new(io::ErrorKind::ConnectionReset, msg)));
-                            pgb.write_message(&BeMessage::ErrorResponse(msg, Some(query_error_error.pg_error_code())))?;
-                            Err(io::Error::new(io::ErrorKind::ConnectionReset, msg))?;
-                            break;
+            match res {
+                Ok(Some(copy_data_bytes)) => yield copy_data_bytes,
+                Ok(None) => break,
+                Err(e) => {
+                    // Tell the client why COPY was aborted, if it is still there.
+                    let _ = pgb.write_message(&BeMessage::PgError(&e.to_pg_error()));
+                    let _ = pgb.flush().await;
+                    match e {
+                        QueryError::Disconnected(ConnectionError::Socket(io_error)) => {
+                            Err(io_error)?;
                         }
-                        m => {
-                            let msg = format!("unexpected message {m:?}");
-                            pgb.write_message(&BeMessage::ErrorResponse(&msg, None))?;
-                            Err(io::Error::new(io::ErrorKind::Other, msg))?;
-                            break;
+                        other => {
+                            Err(io::Error::new(io::ErrorKind::Other, other))?;
                         }
-                    };
-
-                    yield copy_data_bytes;
+                    }
                 }
-                Ok(None) => {
-                    let msg = "client closed connection during COPY";
-                    let query_error_error = QueryError::Disconnected(ConnectionError::Socket(io::Error::new(io::ErrorKind::ConnectionReset, msg)));
-                    pgb.write_message(&BeMessage::ErrorResponse(msg, Some(query_error_error.pg_error_code())))?;
-                    pgb.flush().await?;
-                    Err(io::Error::new(io::ErrorKind::ConnectionReset, msg))?;
-                }
-                Err(QueryError::Disconnected(ConnectionError::Socket(io_error))) => {
-                    Err(io_error)?;
-                }
-                Err(other) => {
-                    Err(io::Error::new(io::ErrorKind::Other, other))?;
-                }
-            };
+            }
         }
     }
 }
@@ -283,12 +262,13 @@ impl PageServerHandler {
         }
     }
 
-    #[instrument(skip(self, pgb, ctx))]
+    #[instrument(skip(self, pgb, cancel, ctx))]
     async fn handle_pagerequests(
         &self,
         pgb: &mut PostgresBackend,
         tenant_id: TenantId,
         timeline_id: TimelineId,
+        cancel: &CancellationToken,
         ctx: RequestContext,
     ) -> anyhow::Result<()> {
         // NOTE: pagerequests handler exits when connection is closed,
@@ -311,8 +291,7 @@ impl PageServerHandler {
         let timeline = tenant.get_timeline(timeline_id, true)?;
 
         // switch client to COPYBOTH
-        pgb.write_message(&BeMessage::CopyBothResponse)?;
-        pgb.flush().await?;
+        pgb.start_copy(CopyMode::Both).await?;
 
         let metrics = PageRequestMetrics::new(&tenant_id, &timeline_id);
 
@@ -326,6 +305,11 @@ impl PageServerHandler {
                     break;
                 }
 
+                _ = cancel.cancelled() => {
+                    info!("page requests cancelled");
+                    break;
+                }
+
                 msg = pgb.read_message() => { msg }
             };
 
@@ -416,8 +400,7 @@ impl PageServerHandler {
 
         // Import basebackup provided via CopyData
         info!("importing basebackup");
-        pgb.write_message(&BeMessage::CopyInResponse)?;
-        pgb.flush().await?;
+        pgb.start_copy(CopyMode::In).await?;
 
         let mut copyin_stream = Box::pin(copyin_stream(pgb));
         timeline
@@ -468,8 +451,7 @@ impl PageServerHandler {
 
         // Import wal provided via CopyData
         info!("importing wal");
-        pgb.write_message(&BeMessage::CopyInResponse)?;
-        pgb.flush().await?;
+        pgb.start_copy(CopyMode::In).await?;
         let mut copyin_stream = Box::pin(copyin_stream(pgb));
         let mut reader = tokio_util::io::StreamReader::new(&mut copyin_stream);
         import_wal_from_tar(&timeline, &mut reader, start_lsn, end_lsn, &ctx).await?;
@@ -654,7 +636,7 @@ impl PageServerHandler {
     }
 
     #[allow(clippy::too_many_arguments)]
-    #[instrument(skip(self, pgb, ctx))]
+    #[instrument(skip(self, pgb, cancel, ctx))]
     async fn handle_basebackup_request(
         &mut self,
         pgb: &mut PostgresBackend,
@@ -663,8 +645,9 @@ impl PageServerHandler {
         lsn: Option<Lsn>,
         prev_lsn: Option<Lsn>,
         full_backup: bool,
+        cancel: &CancellationToken,
         ctx: RequestContext,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), QueryError> {
         // check that the timeline exists
         let timeline = get_active_tenant_timeline(tenant_id, timeline_id, &ctx).await?;
         let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
@@ -678,25 +661,29 @@ impl PageServerHandler {
         }
 
         // switch client to COPYOUT
-        pgb.write_message(&BeMessage::CopyOutResponse)?;
-        pgb.flush().await?;
+        pgb.start_copy(CopyMode::Out).await?;
 
-        // Send a tarball of the latest layer on the timeline
+        // Send a tarball of the latest layer on the timeline, unless the
+        // query is cancelled midway
         {
             let mut writer = pgb.copyout_writer();
-            basebackup::send_basebackup_tarball(
-                &mut writer,
-                &timeline,
-                lsn,
-                prev_lsn,
-                full_backup,
-                &ctx,
-            )
-            .await?;
+            tokio::select! {
+                res = basebackup::send_basebackup_tarball(
+                    &mut writer,
+                    &timeline,
+                    lsn,
+                    prev_lsn,
+                    full_backup,
+                    &ctx,
+                ) => res?,
+                _ = cancel.cancelled() => {
+                    info!("basebackup cancelled");
+                    return Err(QueryError::Cancelled);
+                }
+            }
         }
 
-        pgb.write_message(&BeMessage::CopyDone)?;
-        pgb.flush().await?;
+        pgb.finish_copy_out().await?;
         info!("basebackup complete");
 
         Ok(())
@@ -762,6 +749,7 @@ impl postgres_backend_async::Handler for PageServerHandler {
         &mut self,
         pgb: &mut PostgresBackend,
         query_string: &str,
+        cancel: &CancellationToken,
     ) -> Result<(), QueryError> {
         let ctx = self.connection_ctx.attached_child();
         debug!("process query {query_string:?}");
@@ -781,7 +769,7 @@ impl postgres_backend_async::Handler for PageServerHandler {
 
             self.check_permission(Some(tenant_id))?;
 
-            self.handle_pagerequests(pgb, tenant_id, timeline_id, ctx)
+            self.handle_pagerequests(pgb, tenant_id, timeline_id, cancel, ctx)
                 .await?;
         } else if query_string.starts_with("basebackup ") {
             let (_, params_raw) = query_string.split_at("basebackup ".len());
@@ -810,8 +798,17 @@ impl postgres_backend_async::Handler for PageServerHandler {
             };
 
             // Check that the timeline exists
-            self.handle_basebackup_request(pgb, tenant_id, timeline_id, lsn, None, false, ctx)
-                .await?;
+            self.handle_basebackup_request(
+                pgb,
+                tenant_id,
+                timeline_id,
+                lsn,
+                None,
+                false,
+                cancel,
+                ctx,
+            )
+            .await?;
             pgb.write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
         }
         // return pair of prev_lsn and last_lsn
@@ -882,8 +879,17 @@ impl postgres_backend_async::Handler for PageServerHandler {
             self.check_permission(Some(tenant_id))?;
 
             // Check that the timeline exists
-            self.handle_basebackup_request(pgb, tenant_id, timeline_id, lsn, prev_lsn, true, ctx)
-                .await?;
+            self.handle_basebackup_request(
+                pgb,
+                tenant_id,
+                timeline_id,
+                lsn,
+                prev_lsn,
+                true,
+                cancel,
+                ctx,
+            )
+            .await?;
             pgb.write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
         } else if query_string.starts_with("import basebackup ") {
             // Import the `base` section (everything but the wal) of a basebackup.
@@ -932,10 +938,7 @@ impl postgres_backend_async::Handler for PageServerHandler {
                 Ok(()) => pgb.write_message(&BeMessage::CommandComplete(b"SELECT 1"))?,
                 Err(e) => {
                     error!("error importing base backup between {base_lsn} and {end_lsn}: {e:?}");
-                    pgb.write_message(&BeMessage::ErrorResponse(
-                        &e.to_string(),
-                        Some(e.pg_error_code()),
-                    ))?
+                    pgb.write_message(&BeMessage::PgError(&e.to_pg_error()))?
                 }
             };
         } else if query_string.starts_with("import wal ") {
@@ -968,10 +971,7 @@ impl postgres_backend_async::Handler for PageServerHandler {
                 Ok(()) => pgb.write_message(&BeMessage::CommandComplete(b"SELECT 1"))?,
                 Err(e) => {
                     error!("error importing WAL between {start_lsn} and {end_lsn}: {e:?}");
-                    pgb.write_message(&BeMessage::ErrorResponse(
-                        &e.to_string(),
-                        Some(e.pg_error_code()),
-                    ))?
+                    pgb.write_message(&BeMessage::PgError(&e.to_pg_error()))?
                 }
             };
         } else if query_string.to_ascii_lowercase().starts_with("set ") {
@@ -1096,6 +1096,7 @@ mod tests {
             )
             .unwrap(),
             storage: RemoteStorageKind::LocalFs(remote_fs_dir.clone()),
+            retry: None,
         };
 
         // Test outline:
@@ -740,6 +740,7 @@ impl WalreceiverState {
                         None => None,
                         Some(x) => Some(x),
                     },
+                    self.timeline.conf.wal_receiver_compression,
                 ) {
                     Ok(connstr) => Some((*sk_id, info, connstr)),
                     Err(e) => {
@@ -824,17 +825,20 @@ fn wal_stream_connection_config(
     }: TenantTimelineId,
     listen_pg_addr_str: &str,
     auth_token: Option<&str>,
+    compression: bool,
 ) -> anyhow::Result<PgConnectionConfig> {
     let (host, port) =
         parse_host_port(listen_pg_addr_str).context("Unable to parse listen_pg_addr_str")?;
     let port = port.unwrap_or(5432);
-    Ok(PgConnectionConfig::new_host_port(host, port)
-        .extend_options([
-            "-c".to_owned(),
-            format!("timeline_id={}", timeline_id),
-            format!("tenant_id={}", tenant_id),
-        ])
-        .set_password(auth_token.map(|s| s.to_owned())))
+    let mut connconf = PgConnectionConfig::new_host_port(host, port).extend_options([
+        "-c".to_owned(),
+        format!("timeline_id={}", timeline_id),
+        format!("tenant_id={}", tenant_id),
+    ]);
+    if compression {
+        connconf = connconf.extend_options(["compression=zstd".to_owned()]);
+    }
+    Ok(connconf.set_password(auth_token.map(|s| s.to_owned())))
 }
 
 #[cfg(test)]
@@ -852,6 +856,7 @@ mod tests {
             timeline: SafekeeperTimelineInfo {
                 safekeeper_id: 0,
                 tenant_timeline_id: None,
+                term: 0,
                 last_log_term: 0,
                 flush_lsn: 0,
                 commit_lsn,
@@ -859,6 +864,7 @@ mod tests {
                 remote_consistent_lsn: 0,
                 peer_horizon_lsn: 0,
                 local_start_lsn: 0,
+                last_record_time: 0,
                 safekeeper_connstr: safekeeper_connstr.to_owned(),
             },
             latest_update,
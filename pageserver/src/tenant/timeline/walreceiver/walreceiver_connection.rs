@@ -14,7 +14,7 @@ use fail::fail_point;
 use futures::StreamExt;
 use postgres::{error::SqlState, SimpleQueryMessage, SimpleQueryRow};
 use postgres_ffi::v14::xlog_utils::normalize_lsn;
-use postgres_ffi::WAL_SEGMENT_SIZE;
+use postgres_ffi::{MAX_SEND_SIZE, WAL_SEGMENT_SIZE};
 use postgres_protocol::message::backend::ReplicationMessage;
 use postgres_types::PgLsn;
 use tokio::{pin, select, sync::watch, time};
@@ -147,6 +147,14 @@ pub async fn handle_walreceiver_connection(
     let identify = identify_system(&mut replication_client).await?;
     info!("{identify:?}");
 
+    // The safekeeper confirms compression in IDENTIFY_SYSTEM; older ones
+    // ignore the option and send uncompressed WAL.
+    let mut decompressor = match identify.compression.as_deref() {
+        None => None,
+        Some("zstd") => Some(zstd::bulk::Decompressor::new()?),
+        Some(other) => bail!("unsupported WAL compression {other}"),
+    };
+
     let end_of_wal = Lsn::from(u64::from(identify.xlogpos));
     let mut caught_up = false;
 
@@ -210,6 +218,16 @@ pub async fn handle_walreceiver_connection(
                 return Ok(());
             }
         };
+        let wal_data = match (&replication_message, decompressor.as_mut()) {
+            (ReplicationMessage::XLogData(xlog_data), Some(decompressor)) => Some(
+                decompressor
+                    .decompress(xlog_data.data(), MAX_SEND_SIZE)
+                    .context("failed to decompress WAL")?
+                    .into(),
+            ),
+            (ReplicationMessage::XLogData(xlog_data), None) => Some(xlog_data.data().clone()),
+            _ => None,
+        };
 
         let now = Utc::now().naive_utc();
         let last_rec_lsn_before_msg = last_rec_lsn;
@@ -220,10 +238,10 @@ pub async fn handle_walreceiver_connection(
             ReplicationMessage::XLogData(xlog_data) => {
                 connection_status.latest_connection_update = now;
                 connection_status.commit_lsn = Some(Lsn::from(xlog_data.wal_end()));
-                connection_status.streaming_lsn = Some(Lsn::from(
-                    xlog_data.wal_start() + xlog_data.data().len() as u64,
-                ));
-                if !xlog_data.data().is_empty() {
+                let data = wal_data.as_ref().unwrap();
+                connection_status.streaming_lsn =
+                    Some(Lsn::from(xlog_data.wal_start() + data.len() as u64));
+                if !data.is_empty() {
                     connection_status.latest_wal_update = now;
                 }
             }
@@ -242,13 +260,13 @@ pub async fn handle_walreceiver_connection(
             ReplicationMessage::XLogData(xlog_data) => {
                 // Pass the WAL data to the decoder, and see if we can decode
                 // more records as a result.
-                let data = xlog_data.data();
+                let data = wal_data.unwrap();
                 let startlsn = Lsn::from(xlog_data.wal_start());
                 let endlsn = startlsn + data.len() as u64;
 
                 trace!("received XLogData between {startlsn} and {endlsn}");
 
-                waldecoder.feed_bytes(data);
+                waldecoder.feed_bytes(&data);
 
                 {
                     let mut decoded = DecodedWALRecord::default();
@@ -378,6 +396,7 @@ struct IdentifySystem {
     timeline: u32,
     xlogpos: PgLsn,
     dbname: Option<String>,
+    compression: Option<String>,
 }
 
 /// There was a problem parsing the response to
@@ -408,6 +427,7 @@ async fn identify_system(client: &mut Client) -> anyhow::Result<IdentifySystem>
             timeline: get_parse(first_row, 1)?,
             xlogpos: get_parse(first_row, 2)?,
             dbname: get_parse(first_row, 3).ok(),
+            compression: first_row.try_get(4).ok().flatten().map(str::to_owned),
         })
     } else {
         Err(IdentifyError.into())
@@ -5,6 +5,7 @@
 use anyhow::Result;
 use bytes::{Buf, Bytes};
 use postgres_ffi::pg_constants;
+use postgres_ffi::relfile_utils::RelTag;
 use postgres_ffi::BLCKSZ;
 use postgres_ffi::{BlockNumber, OffsetNumber, TimestampTz};
 use postgres_ffi::{MultiXactId, MultiXactOffset, MultiXactStatus, Oid, TransactionId};
@@ -95,6 +96,16 @@ impl DecodedBkpBlock {
     pub fn new() -> DecodedBkpBlock {
         Default::default()
     }
+
+    /// Relation fork this block belongs to
+    pub fn rel_tag(&self) -> RelTag {
+        RelTag {
+            forknum: self.forknum,
+            spcnode: self.rnode_spcnode,
+            dbnode: self.rnode_dbnode,
+            relnode: self.rnode_relnode,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -56,6 +56,7 @@ use postgres_ffi::v14::nonrelfile_utils::{
     mx_offset_to_flags_bitshift, mx_offset_to_flags_offset, mx_offset_to_member_offset,
     transaction_id_set_status,
 };
+use postgres_ffi::vm_fsm_utils::vm_clear_flags;
 use postgres_ffi::BLCKSZ;
 
 ///
@@ -399,31 +400,17 @@ impl PostgresRedoManager {
                     rel
                 );
                 if let Some(heap_blkno) = *new_heap_blkno {
-                    // Calculate the VM block and offset that corresponds to the heap block.
-                    let map_block = pg_constants::HEAPBLK_TO_MAPBLOCK(heap_blkno);
-                    let map_byte = pg_constants::HEAPBLK_TO_MAPBYTE(heap_blkno);
-                    let map_offset = pg_constants::HEAPBLK_TO_OFFSET(heap_blkno);
-
                     // Check that we're modifying the correct VM block.
-                    assert!(map_block == blknum);
-
-                    // equivalent to PageGetContents(page)
-                    let map = &mut page[pg_constants::MAXALIGN_SIZE_OF_PAGE_HEADER_DATA..];
+                    assert!(pg_constants::HEAPBLK_TO_MAPBLOCK(heap_blkno) == blknum);
 
-                    map[map_byte as usize] &= !(flags << map_offset);
+                    vm_clear_flags(page, heap_blkno, *flags);
                 }
 
                 // Repeat for 'old_heap_blkno', if any
                 if let Some(heap_blkno) = *old_heap_blkno {
-                    let map_block = pg_constants::HEAPBLK_TO_MAPBLOCK(heap_blkno);
-                    let map_byte = pg_constants::HEAPBLK_TO_MAPBYTE(heap_blkno);
-                    let map_offset = pg_constants::HEAPBLK_TO_OFFSET(heap_blkno);
-
-                    assert!(map_block == blknum);
-
-                    let map = &mut page[pg_constants::MAXALIGN_SIZE_OF_PAGE_HEADER_DATA..];
+                    assert!(pg_constants::HEAPBLK_TO_MAPBLOCK(heap_blkno) == blknum);
 
-                    map[map_byte as usize] &= !(flags << map_offset);
+                    vm_clear_flags(page, heap_blkno, *flags);
                 }
             }
             // Non-relational WAL records are handled here, with custom code that has the
@@ -31,7 +31,7 @@ pub enum AuthErrorImpl {
     #[error(transparent)]
     WakeCompute(#[from] console::errors::WakeComputeError),
 
-    /// SASL protocol errors (includes [SCRAM](crate::scram)).
+    /// SASL protocol errors (includes [SCRAM](scram)).
     #[error(transparent)]
     Sasl(#[from] crate::sasl::Error),
 
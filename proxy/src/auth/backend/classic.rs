@@ -3,7 +3,7 @@ use crate::{
     auth::{self, AuthFlow, ClientCredentials},
     compute,
     console::{self, AuthInfo, CachedNodeInfo, ConsoleReqExtra},
-    sasl, scram,
+    sasl,
     stream::PqStream,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
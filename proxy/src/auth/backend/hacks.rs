@@ -10,7 +10,7 @@ use crate::{
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{info, warn};
 
-/// Compared to [SCRAM](crate::scram), cleartext password auth saves
+/// Compared to [SCRAM](scram), cleartext password auth saves
 /// one round trip and *expensive* computations (>= 4096 HMAC iterations).
 /// These properties are benefical for serverless JS workers, so we
 /// use this mechanism for websocket connections.
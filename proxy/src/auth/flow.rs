@@ -1,7 +1,7 @@
 //! Main authentication flow.
 
 use super::{AuthErrorImpl, PasswordHackPayload};
-use crate::{sasl, scram, stream::PqStream};
+use crate::{sasl, stream::PqStream};
 use pq_proto::{BeAuthenticationSaslMessage, BeMessage, BeMessage as Be};
 use std::io;
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -16,7 +16,7 @@ pub trait AuthMethod {
 /// Initial state of [`AuthFlow`].
 pub struct Begin;
 
-/// Use [SCRAM](crate::scram)-based auth in [`AuthFlow`].
+/// Use [SCRAM](scram)-based auth in [`AuthFlow`].
 pub struct Scram<'a>(pub &'a scram::ServerSecret);
 
 impl AuthMethod for Scram<'_> {
@@ -109,7 +109,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AuthFlow<'_, S, CleartextPassword> {
     }
 }
 
-/// Stream wrapper for handling [SCRAM](crate::scram) auth.
+/// Stream wrapper for handling [SCRAM](scram) auth.
 impl<S: AsyncRead + AsyncWrite + Unpin> AuthFlow<'_, S, Scram<'_>> {
     /// Perform user authentication. Raise an error in case authentication failed.
     pub async fn authenticate(self) -> super::Result<sasl::Outcome<scram::ScramKey>> {
@@ -125,7 +125,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AuthFlow<'_, S, Scram<'_>> {
 
         let secret = self.state.0;
         let outcome = sasl::SaslStream::new(self.stream, sasl.message)
-            .authenticate(scram::Exchange::new(secret, rand::random, None))
+            .authenticate(scram::Exchange::new(secret.clone(), rand::random, None))
             .await?;
 
         Ok(outcome)
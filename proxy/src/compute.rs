@@ -87,17 +87,17 @@ impl ConnCfg {
             self.application_name(app_name);
         }
 
-        // TODO: This is especially ugly...
-        if let Some(replication) = params.get("replication") {
+        // An invalid value has been rejected during the handshake.
+        if let Ok(replication) = params.replication() {
             use tokio_postgres::config::ReplicationMode;
             match replication {
-                "true" | "on" | "yes" | "1" => {
+                pq_proto::ReplicationMode::Off => {}
+                pq_proto::ReplicationMode::Physical => {
                     self.replication_mode(ReplicationMode::Physical);
                 }
-                "database" => {
+                pq_proto::ReplicationMode::Database => {
                     self.replication_mode(ReplicationMode::Logical);
                 }
-                _other => {}
             }
         }
 
@@ -8,7 +8,7 @@ pub struct ConsoleError {
     pub error: Box<str>,
 }
 
-/// Response which holds client's auth secret, e.g. [`crate::scram::ServerSecret`].
+/// Response which holds client's auth secret, e.g. [`scram::ServerSecret`].
 /// Returned by the `/proxy_get_role_secret` API method.
 #[derive(Deserialize)]
 pub struct GetRoleSecret {
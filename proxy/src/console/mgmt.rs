@@ -8,6 +8,7 @@ use pq_proto::{BeMessage, SINGLE_COL_ROWDESC};
 use std::{net::TcpStream, thread};
 use tracing::{error, info, info_span};
 use utils::{
+    cancel_registry::CancellationToken,
     postgres_backend::{self, AuthType, PostgresBackend},
     postgres_backend_async::QueryError,
 };
@@ -79,7 +80,12 @@ pub type ComputeReady = Result<DatabaseInfo, String>;
 // TODO: replace with an http-based protocol.
 struct MgmtHandler;
 impl postgres_backend::Handler for MgmtHandler {
-    fn process_query(&mut self, pgb: &mut PostgresBackend, query: &str) -> Result<(), QueryError> {
+    fn process_query(
+        &mut self,
+        pgb: &mut PostgresBackend,
+        query: &str,
+        _cancel: &CancellationToken,
+    ) -> Result<(), QueryError> {
         try_process_query(pgb, query).map_err(|e| {
             error!("failed to process response: {e:?}");
             e
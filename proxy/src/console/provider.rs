@@ -5,7 +5,7 @@ use super::messages::MetricsAuxInfo;
 use crate::{
     auth::ClientCredentials,
     cache::{timed_lru, TimedLru},
-    compute, scram,
+    compute,
 };
 use async_trait::async_trait;
 use std::sync::Arc;
@@ -155,7 +155,7 @@ pub enum AuthInfo {
     /// Md5 hash of user's password.
     Md5([u8; 16]),
 
-    /// [SCRAM](crate::scram) authentication info.
+    /// [SCRAM](scram) authentication info.
     Scram(scram::ServerSecret),
 }
 
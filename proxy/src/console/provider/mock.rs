@@ -4,11 +4,12 @@ use super::{
     errors::{ApiError, GetAuthInfoError, WakeComputeError},
     AuthInfo, CachedNodeInfo, ConsoleReqExtra, NodeInfo,
 };
-use crate::{auth::ClientCredentials, compute, error::io_error, scram, url::ApiUrl};
+use crate::{auth::ClientCredentials, compute, error::io_error, url::ApiUrl};
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use thiserror::Error;
 use tracing::{error, info, info_span, warn, Instrument};
+use utils::md5_auth::parse_md5;
 
 #[derive(Debug, Error)]
 enum MockApiError {
@@ -119,12 +120,3 @@ impl super::Api for Api {
             .await
     }
 }
-
-fn parse_md5(input: &str) -> Option<[u8; 16]> {
-    let text = input.strip_prefix("md5")?;
-
-    let mut bytes = [0u8; 16];
-    hex::decode_to_slice(text, &mut bytes).ok()?;
-
-    Some(bytes)
-}
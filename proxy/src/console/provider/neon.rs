@@ -5,7 +5,7 @@ use super::{
     errors::{ApiError, GetAuthInfoError, WakeComputeError},
     ApiCaches, AuthInfo, CachedNodeInfo, ConsoleReqExtra, NodeInfo,
 };
-use crate::{auth::ClientCredentials, compute, http, scram};
+use crate::{auth::ClientCredentials, compute, http};
 use async_trait::async_trait;
 use futures::TryFutureExt;
 use tracing::{error, info, info_span, warn, Instrument};
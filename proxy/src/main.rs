@@ -17,7 +17,6 @@ mod metrics;
 mod parse;
 mod proxy;
 mod sasl;
-mod scram;
 mod stream;
 mod url;
 mod waiters;
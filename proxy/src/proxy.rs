@@ -14,7 +14,10 @@ use anyhow::{bail, Context};
 use futures::TryFutureExt;
 use metrics::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
 use once_cell::sync::Lazy;
-use pq_proto::{BeMessage as Be, FeStartupPacket, StartupMessageParams};
+use pq_proto::{
+    pg_error::{PgError, Severity, SqlState},
+    BeMessage as Be, FeStartupPacket, StartupMessageParams,
+};
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::{error, info, warn};
@@ -231,6 +234,17 @@ async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
                     stream.throw_error_str(ERR_INSECURE_CONNECTION).await?;
                 }
 
+                // Reject an invalid replication mode like the compute would,
+                // as it can't be passed on to it.
+                if let Err(e) = params.replication() {
+                    info!("forwarding error to user: {e}");
+                    let error = PgError::builder(SqlState::InvalidParameterValue, e.to_string())
+                        .severity(Severity::Fatal)
+                        .build();
+                    stream.write_message(&Be::PgError(&error)).await?;
+                    return Err(e);
+                }
+
                 info!(session_type = "normal", "successful handshake");
                 break Ok(Some((stream, params)));
             }
@@ -339,7 +353,7 @@ async fn prepare_client_connection(
 
     stream
         .write_message_noflush(&Be::BackendKeyData(cancel_key_data))?
-        .write_message(&Be::ReadyForQuery)
+        .write_message(&Be::READY_FOR_QUERY)
         .await?;
 
     Ok(())
@@ -1,6 +1,6 @@
 ///! A group of high-level tests for connection establishing logic and auth.
 use super::*;
-use crate::{auth, sasl, scram};
+use crate::{auth, sasl};
 use async_trait::async_trait;
 use rstest::rstest;
 use tokio_postgres::config::SslMode;
@@ -140,7 +140,7 @@ async fn dummy_proxy(
     stream
         .write_message_noflush(&Be::AuthenticationOk)?
         .write_message_noflush(&Be::CLIENT_ENCODING)?
-        .write_message(&Be::ReadyForQuery)
+        .write_message(&Be::READY_FOR_QUERY)
         .await?;
 
     Ok(())
@@ -174,6 +174,35 @@ async fn handshake_tls_is_enforced_by_proxy() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn handshake_invalid_replication_is_rejected() -> anyhow::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (client, mut server) = tokio::io::duplex(1024);
+    let proxy = tokio::spawn(dummy_proxy(client, None, NoAuth));
+
+    // tokio-postgres can't send an invalid value, so write the startup message by hand
+    let params = b"user\0john_doe\0replication\0maybe\0\0";
+    let mut msg = Vec::new();
+    msg.extend_from_slice(&(8 + params.len() as u32).to_be_bytes());
+    msg.extend_from_slice(&196608u32.to_be_bytes()); // protocol 3.0
+    msg.extend_from_slice(params);
+    server.write_all(&msg).await?;
+
+    let server_err = proxy
+        .await?
+        .err() // -> Option<E>
+        .context("server shouldn't accept client")?;
+    assert!(server_err.to_string().contains("replication"));
+
+    let mut response = Vec::new();
+    server.read_to_end(&mut response).await?;
+    assert_eq!(response[0], b'E');
+    assert!(response[5..].starts_with(b"SFATAL\0VFATAL\0C22023\0"));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn handshake_tls() -> anyhow::Result<()> {
     let (client, server) = tokio::io::duplex(1024);
@@ -0,0 +1,6 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Generate rust code of the gRPC service from .proto protobuf.
+    tonic_build::compile_protos("proto/safekeeper.proto")
+        .unwrap_or_else(|e| panic!("failed to compile protos {:?}", e));
+    Ok(())
+}
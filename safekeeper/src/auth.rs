@@ -1,19 +1,123 @@
-use anyhow::{bail, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
 use utils::auth::{Claims, Scope};
 use utils::id::TenantId;
+use utils::md5_auth::parse_md5_secret;
 
-pub fn check_permission(claims: &Claims, tenant_id: Option<TenantId>) -> Result<()> {
-    match (&claims.scope, tenant_id) {
-        (Scope::Tenant, None) => {
-            bail!("Attempt to access management api with tenant scope. Permission denied")
-        }
-        (Scope::Tenant, Some(tenant_id)) => {
-            if claims.tenant_id.unwrap() != tenant_id {
-                bail!("Tenant id mismatch. Permission denied")
+/// md5 secrets of the users allowed to connect with a password, for clients
+/// which can't use JWT. Password authenticated users have full access, as
+/// with auth disabled.
+#[derive(Debug, Default)]
+pub struct PasswordAuth {
+    secrets: HashMap<String, String>,
+}
+
+impl PasswordAuth {
+    /// Load the file with `user:md5secret` lines, secrets as in `pg_authid`.
+    /// Empty lines and lines starting with '#' are skipped.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut secrets = HashMap::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
             }
-            Ok(())
+            let (user, secret) = line
+                .split_once(':')
+                .with_context(|| format!("line {}: expected user:secret", i + 1))?;
+            let secret = parse_md5_secret(secret).with_context(|| format!("line {}", i + 1))?;
+            secrets.insert(user.to_owned(), secret.to_owned());
         }
-        (Scope::PageServerApi, _) => bail!("PageServerApi scope makes no sense for Safekeeper"),
-        (Scope::SafekeeperData, _) => Ok(()),
+        Ok(PasswordAuth { secrets })
+    }
+
+    /// md5 secret of the user, None if there is no such user.
+    pub fn secret(&self, user: &str) -> Option<&str> {
+        self.secrets.get(user).map(String::as_str)
+    }
+}
+
+/// Checks that `claims` allow the operation. `tenant_id` is the tenant the
+/// operation is bound to; pass None for node-level operations (listing all
+/// timelines, pulling timelines from peers, etc).
+///
+/// Tenant scoped tokens are allowed to operate only on timelines of their own
+/// tenant; node-level operations require `SafekeeperData` scope.
+pub fn check_permission(claims: &Claims, tenant_id: Option<TenantId>) -> Result<()> {
+    match tenant_id {
+        Some(tenant_id) => check_tenant_permission(claims, tenant_id),
+        None => check_node_permission(claims),
+    }
+}
+
+/// Checks that `claims` allow access to timelines of the given tenant.
+pub fn check_tenant_permission(claims: &Claims, tenant_id: TenantId) -> Result<()> {
+    match claims.scope {
+        Scope::Tenant => match claims.tenant_id {
+            Some(claims_tenant_id) if claims_tenant_id == tenant_id => Ok(()),
+            Some(_) => bail!("Tenant id mismatch. Permission denied"),
+            None => bail!("Tenant scope token without tenant id. Permission denied"),
+        },
+        Scope::PageServerApi => bail!("PageServerApi scope makes no sense for Safekeeper"),
+        Scope::SafekeeperData => Ok(()),
+    }
+}
+
+/// Checks that `claims` allow node-level operations, i.e. ones not bound to
+/// a single tenant.
+pub fn check_node_permission(claims: &Claims) -> Result<()> {
+    match claims.scope {
+        Scope::Tenant => {
+            bail!("Attempt to access safekeeper-wide api with tenant scope. Permission denied")
+        }
+        Scope::PageServerApi => bail!("PageServerApi scope makes no sense for Safekeeper"),
+        Scope::SafekeeperData => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_scope() {
+        let tenant_id = TenantId::generate();
+        let claims = Claims::new(Some(tenant_id), Scope::Tenant);
+        assert!(check_permission(&claims, Some(tenant_id)).is_ok());
+        assert!(check_permission(&claims, Some(TenantId::generate())).is_err());
+        assert!(check_permission(&claims, None).is_err());
+
+        let claims = Claims::new(None, Scope::Tenant);
+        assert!(check_permission(&claims, Some(tenant_id)).is_err());
+    }
+
+    #[test]
+    fn test_safekeeper_data_scope() {
+        let claims = Claims::new(None, Scope::SafekeeperData);
+        assert!(check_permission(&claims, Some(TenantId::generate())).is_ok());
+        assert!(check_permission(&claims, None).is_ok());
+
+        let claims = Claims::new(None, Scope::PageServerApi);
+        assert!(check_permission(&claims, Some(TenantId::generate())).is_err());
+        assert!(check_permission(&claims, None).is_err());
+    }
+
+    #[test]
+    fn test_password_auth_parse() {
+        let secret = utils::md5_auth::md5_secret("walproposer", "secret");
+        let auth = PasswordAuth::parse(&format!("# comment\n\nwalproposer:{secret}\n")).unwrap();
+        assert_eq!(auth.secret("walproposer"), Some(secret.as_str()));
+        assert_eq!(auth.secret("postgres"), None);
+
+        assert!(PasswordAuth::parse("walproposer").is_err());
+        assert!(PasswordAuth::parse("walproposer:secret").is_err());
     }
 }
@@ -3,9 +3,12 @@
 //
 use anyhow::{bail, Context, Result};
 use clap::Parser;
+use fail::FailScenario;
 use remote_storage::RemoteStorageConfig;
 use toml_edit::Document;
 
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
 use std::fs::{self, File};
 use std::io::{ErrorKind, Write};
 use std::path::{Path, PathBuf};
@@ -19,16 +22,24 @@ use tracing::*;
 use utils::pid_file;
 
 use metrics::set_build_info_metric;
+use safekeeper::auth::PasswordAuth;
 use safekeeper::broker;
 use safekeeper::control_file;
 use safekeeper::defaults::{
-    DEFAULT_HEARTBEAT_TIMEOUT, DEFAULT_HTTP_LISTEN_ADDR, DEFAULT_MAX_OFFLOADER_LAG_BYTES,
-    DEFAULT_PG_LISTEN_ADDR,
+    DEFAULT_HEARTBEAT_TIMEOUT, DEFAULT_HTTP_LISTEN_ADDR, DEFAULT_MAX_APPEND_BATCH_BYTES,
+    DEFAULT_MAX_APPEND_BATCH_DELAY, DEFAULT_MAX_OFFLOADER_LAG_BYTES, DEFAULT_PG_LISTEN_ADDR,
+    DEFAULT_WAL_BACKUP_PARALLEL_UPLOADS,
 };
+use safekeeper::drain;
+use safekeeper::grpc;
 use safekeeper::http;
+use safekeeper::recovery;
 use safekeeper::remove_wal;
+use safekeeper::timeline_eviction;
+use safekeeper::tls::{ServerTls, TlsPaths};
 use safekeeper::wal_backup;
 use safekeeper::wal_service;
+use safekeeper::wal_storage::FsyncMethod;
 use safekeeper::GlobalTimelines;
 use safekeeper::SafeKeeperConf;
 use storage_broker::DEFAULT_ENDPOINT;
@@ -39,7 +50,8 @@ use utils::{
     logging::{self, LogFormat},
     project_git_version,
     sentry_init::init_sentry,
-    signals, tcp_listener,
+    signals::{self, Signal},
+    tcp_listener,
 };
 
 const PID_FILE_NAME: &str = "safekeeper.pid";
@@ -71,6 +83,11 @@ struct Args {
     /// Listen http endpoint for management and metrics in the form host:port.
     #[arg(long, default_value = DEFAULT_HTTP_LISTEN_ADDR)]
     listen_http: String,
+    /// Listen endpoint of the gRPC service streaming WAL to consumers not
+    /// speaking the Postgres replication protocol, in the form host:port.
+    /// Served in plaintext; disabled if not set.
+    #[arg(long)]
+    listen_grpc: Option<String>,
     /// Do not wait for changes to be written safely to disk. Unsafe.
     #[arg(short, long)]
     no_sync: bool,
@@ -92,6 +109,8 @@ struct Args {
     /// Remote storage configuration for WAL backup (offloading to s3) as TOML
     /// inline table, e.g.
     ///   {"max_concurrent_syncs" = 17, "max_sync_errors": 13, "bucket_name": "<BUCKETNAME>", "bucket_region":"<REGION>", "concurrency_limit": 119}
+    /// or {"gcs_bucket_name": "<BUCKETNAME>", "retry_max_backoff_ms": 60000} for GCS, see
+    /// docs/settings.md for all options.
     /// Safekeeper offloads WAL to
     ///   [prefix_in_bucket/]<tenant_id>/<timeline_id>/<segment_file>, mirroring
     /// structure on the file system.
@@ -108,9 +127,149 @@ struct Args {
     /// WAL backup horizon.
     #[arg(long)]
     disable_wal_backup: bool,
+    /// Number of segments of a single timeline offloaded concurrently.
+    #[arg(long, default_value_t = DEFAULT_WAL_BACKUP_PARALLEL_UPLOADS)]
+    wal_backup_parallel_uploads: usize,
+    /// Limit on WAL uploads in flight on the safekeeper, so that busy
+    /// timelines don't hog remote storage rate limits.
+    #[arg(long)]
+    wal_backup_max_concurrent_uploads: Option<usize>,
+    /// Limit on WAL upload bandwidth of the safekeeper, in bytes per second.
+    #[arg(long)]
+    wal_backup_max_upload_rate: Option<u64>,
+    /// Remove WAL offloaded to s3 from disk even if pageserver hasn't consumed
+    /// it yet; the pageserver then gets it from s3. Keeps disk usage bounded
+    /// on busy tenants when pageservers lag.
+    #[arg(long)]
+    remove_offloaded_wal: bool,
+    /// Keep at least this many bytes of WAL before the end of WAL on disk,
+    /// even if it is not needed anymore. Can be overridden per tenant.
+    #[arg(long)]
+    wal_retention_keep_bytes: Option<u64>,
+    /// Keep at least this many WAL segments before the last one on disk,
+    /// even if they are not needed anymore. Can be overridden per tenant.
+    #[arg(long)]
+    wal_retention_keep_segments: Option<u64>,
+    /// Compress WAL segments kept on disk after they are offloaded to s3;
+    /// they are decompressed on the fly when a replica needs them.
+    #[arg(long)]
+    compress_cold_wal: bool,
+    /// Periodically check that WAL offloaded by this safekeeper is complete
+    /// and matches the local copy, uploading missing and corrupted segments
+    /// again, as a human readable duration. Disabled by default.
+    #[arg(long, value_parser= humantime::parse_duration)]
+    wal_backup_scrub_interval: Option<Duration>,
     /// Path to an RSA .pem public key which is used to check JWT tokens.
     #[arg(long)]
     auth_validation_public_key_path: Option<PathBuf>,
+    /// Path to a file with `user:md5secret` lines, enabling md5 password
+    /// authentication of the postgres protocol for clients which can't use
+    /// JWT.
+    #[arg(long, conflicts_with = "auth_validation_public_key_path")]
+    auth_password_file: Option<PathBuf>,
+    /// Limit on the WAL streamed to all replicas, in bytes per second.
+    #[arg(long)]
+    max_send_rate: Option<u64>,
+    /// Limit on the WAL streamed to the replicas of a single tenant, in bytes
+    /// per second.
+    #[arg(long)]
+    max_tenant_send_rate: Option<u64>,
+    /// Limit on the WAL streamed over a single replication connection, in
+    /// bytes per second.
+    #[arg(long)]
+    max_connection_send_rate: Option<u64>,
+    /// Refuse START_WAL_PUSH of a tenant which already has this many
+    /// concurrent connections.
+    #[arg(long)]
+    max_tenant_connections: Option<usize>,
+    /// Refuse START_WAL_PUSH which would create a timeline of a tenant which
+    /// already has this many of them.
+    #[arg(long)]
+    max_tenant_timelines: Option<usize>,
+    /// Refuse START_WAL_PUSH of a tenant which uses this many bytes of disk.
+    #[arg(long)]
+    max_tenant_disk_bytes: Option<u64>,
+    /// Flush received WAL once this many bytes are written but not yet
+    /// flushed, instead of waiting for walproposer to pause.
+    #[arg(long)]
+    max_unflushed_wal: Option<u64>,
+    /// Delay replies to walproposer, slowing down commits, while this many
+    /// bytes of WAL are not offloaded to remote storage yet.
+    #[arg(long)]
+    max_unbacked_wal: Option<u64>,
+    /// Method to sync WAL segments to disk, 'fdatasync' or 'fsync'.
+    #[arg(long, default_value = "fdatasync")]
+    wal_fsync_method: FsyncMethod,
+    /// Wait up to this long for more WAL from compute before flushing it, so
+    /// that appends arriving within the window share one flush. Increases
+    /// commit latency; zero flushes immediately.
+    #[arg(long, value_parser= humantime::parse_duration, default_value = "0ms")]
+    wal_flush_max_delay: Duration,
+    /// Acknowledge appends streamed back to back with a single reply once
+    /// this many bytes of WAL are received, even if more is readily available.
+    #[arg(long, default_value_t = DEFAULT_MAX_APPEND_BATCH_BYTES)]
+    max_append_batch: u64,
+    /// Acknowledge appends streamed back to back with a single reply at least
+    /// this often, as a human readable duration.
+    #[arg(long, value_parser= humantime::parse_duration, default_value = DEFAULT_MAX_APPEND_BATCH_DELAY)]
+    max_append_batch_delay: Duration,
+    /// Enable TCP keepalive on Postgres protocol connections, probing after
+    /// this much idle time and then at this interval, as a human readable
+    /// duration.
+    #[arg(long, value_parser= humantime::parse_duration)]
+    tcp_keepalive: Option<Duration>,
+    /// Drop Postgres protocol connections after this many unanswered TCP
+    /// keepalive probes. The kernel default is used if not set.
+    #[arg(long, requires = "tcp_keepalive")]
+    tcp_keepalive_retries: Option<u32>,
+    /// Drop Postgres protocol connections whose sent data stays
+    /// unacknowledged for this long (TCP_USER_TIMEOUT), as a human readable
+    /// duration.
+    #[arg(long, value_parser= humantime::parse_duration)]
+    tcp_user_timeout: Option<Duration>,
+    /// Drop Postgres protocol connections which send no query, and
+    /// walproposer connections which send no AppendRequest, for this long,
+    /// as a human readable duration. Other walproposer messages don't count,
+    /// so a proposer stuck before election or a half-dead connection doesn't
+    /// keep the timeline active. By default they are kept until TCP notices.
+    #[arg(long, value_parser= humantime::parse_duration)]
+    wal_receive_idle_timeout: Option<Duration>,
+    /// Drop replication connections which don't reply to keepalive requests
+    /// for this long, as a human readable duration, so that they stop
+    /// holding WAL.
+    #[arg(long, value_parser= humantime::parse_duration)]
+    replication_reply_timeout: Option<Duration>,
+    /// Drop replication connections which don't read sent WAL for this
+    /// long, as a human readable duration, instead of blocking the sender
+    /// until they do.
+    #[arg(long, value_parser= humantime::parse_duration)]
+    replication_write_timeout: Option<Duration>,
+    /// Unload timelines from memory after they are idle for this long, as a
+    /// human readable duration; they are loaded back on the next access. By
+    /// default timelines are never unloaded.
+    #[arg(long, value_parser= humantime::parse_duration)]
+    timeline_eviction_timeout: Option<Duration>,
+    /// HTTP API address of a peer safekeeper as `<id>=<url>`, e.g.
+    /// `2=http://sk-2:7676`; may be repeated. Timelines can be relocated
+    /// only between the listed safekeepers, including this one.
+    #[arg(long = "peer-http-addr", value_parser = parse_peer_http_addr)]
+    peer_http_addrs: Vec<(NodeId, String)>,
+    /// Path to a PEM certificate chain; if set together with --tls-key-path,
+    /// the Postgres and HTTP listeners accept only TLS connections.
+    #[arg(long, requires = "tls_key_path")]
+    tls_cert_path: Option<PathBuf>,
+    /// Path to the PEM (PKCS#8) private key of the TLS certificate.
+    #[arg(long, requires = "tls_cert_path")]
+    tls_key_path: Option<PathBuf>,
+    /// Path to a PEM CA bundle; if set, clients must present a certificate
+    /// signed by it. Certificate, key and CA are reloaded on SIGHUP.
+    #[arg(long, requires = "tls_cert_path")]
+    tls_ca_path: Option<PathBuf>,
+    /// Also accept Postgres connections which start TLS right away with
+    /// ALPN "postgresql" (Postgres 17 sslnegotiation=direct), not only
+    /// after SSLRequest.
+    #[arg(long, requires = "tls_cert_path")]
+    allow_direct_tls: bool,
     /// Format for logging, either 'plain' or 'json'.
     #[arg(long, default_value = "plain")]
     log_format: String,
@@ -132,7 +291,25 @@ fn main() -> anyhow::Result<()> {
     // 3. sentry
     logging::init(LogFormat::from_config(&args.log_format)?)?;
     logging::replace_panic_hook_with_tracing_panic_hook().forget();
-    info!("version: {GIT_VERSION}");
+    info!(
+        "version: {GIT_VERSION}, failpoints: {}",
+        fail::has_failpoints()
+    );
+
+    // Initialize failpoints support; failpoints set from FAILPOINTS
+    // environment variable are logged for debugging purposes
+    let _scenario = FailScenario::setup();
+    let failpoints = fail::list();
+    if !failpoints.is_empty() {
+        info!(
+            "started with failpoints: {}",
+            failpoints
+                .iter()
+                .map(|(name, actions)| format!("{name}={actions}"))
+                .collect::<Vec<String>>()
+                .join(";")
+        )
+    }
 
     let args_workdir = &args.datadir;
     let workdir = args_workdir.canonicalize().with_context(|| {
@@ -161,11 +338,47 @@ fn main() -> anyhow::Result<()> {
         }
     };
 
+    let password_auth = match args.auth_password_file.as_ref() {
+        None => None,
+        Some(path) => {
+            info!("loading password auth secrets from {}", path.display());
+            Some(Arc::new(PasswordAuth::from_file(path)?))
+        }
+    };
+
+    let auth_token = match std::env::var("NEON_AUTH_TOKEN") {
+        Ok(token) => {
+            info!("loaded JWT token for authentication with peer safekeepers");
+            Some(token)
+        }
+        Err(std::env::VarError::NotPresent) => None,
+        Err(e) => return Err(e).context("failed to read NEON_AUTH_TOKEN"),
+    };
+
+    let tls = match (args.tls_cert_path, args.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            info!("loading TLS certificate from {}", cert_path.display());
+            let paths = TlsPaths {
+                cert_path,
+                key_path,
+                ca_path: args.tls_ca_path,
+            };
+            Some(Arc::new(
+                ServerTls::load(paths).context("failed to load TLS configuration")?,
+            ))
+        }
+        _ => {
+            info!("TLS is disabled");
+            None
+        }
+    };
+
     let conf = SafeKeeperConf {
         workdir,
         my_id: id,
         listen_pg_addr: args.listen_pg,
         listen_http_addr: args.listen_http,
+        listen_grpc_addr: args.listen_grpc,
         no_sync: args.no_sync,
         broker_endpoint: args.broker_endpoint,
         broker_keepalive_interval: args.broker_keepalive_interval,
@@ -174,7 +387,39 @@ fn main() -> anyhow::Result<()> {
         max_offloader_lag_bytes: args.max_offloader_lag,
         backup_runtime_threads: args.wal_backup_threads,
         wal_backup_enabled: !args.disable_wal_backup,
+        wal_backup_parallel_uploads: args.wal_backup_parallel_uploads,
+        wal_backup_max_concurrent_uploads: args.wal_backup_max_concurrent_uploads,
+        wal_backup_max_upload_rate: args.wal_backup_max_upload_rate,
+        remove_offloaded_wal: args.remove_offloaded_wal,
+        wal_retention_keep_bytes: args.wal_retention_keep_bytes,
+        wal_retention_keep_segments: args.wal_retention_keep_segments,
+        compress_cold_wal: args.compress_cold_wal,
+        wal_backup_scrub_interval: args.wal_backup_scrub_interval,
         auth,
+        password_auth,
+        auth_token,
+        peer_http_addrs: args.peer_http_addrs.into_iter().collect(),
+        max_send_rate: args.max_send_rate,
+        max_tenant_send_rate: args.max_tenant_send_rate,
+        max_connection_send_rate: args.max_connection_send_rate,
+        max_tenant_connections: args.max_tenant_connections,
+        max_tenant_timelines: args.max_tenant_timelines,
+        max_tenant_disk_bytes: args.max_tenant_disk_bytes,
+        max_unflushed_wal_bytes: args.max_unflushed_wal,
+        max_unbacked_wal_bytes: args.max_unbacked_wal,
+        wal_fsync_method: args.wal_fsync_method,
+        wal_flush_max_delay: args.wal_flush_max_delay,
+        max_append_batch_bytes: args.max_append_batch,
+        max_append_batch_delay: args.max_append_batch_delay,
+        tcp_keepalive: args.tcp_keepalive,
+        tcp_keepalive_retries: args.tcp_keepalive_retries,
+        tcp_user_timeout: args.tcp_user_timeout,
+        wal_receive_idle_timeout: args.wal_receive_idle_timeout,
+        replication_reply_timeout: args.replication_reply_timeout,
+        replication_write_timeout: args.replication_write_timeout,
+        timeline_eviction_timeout: args.timeline_eviction_timeout,
+        tls,
+        allow_direct_tls: args.allow_direct_tls,
     };
 
     // initialize sentry if SENTRY_DSN is provided
@@ -218,22 +463,42 @@ fn start_safekeeper(conf: SafeKeeperConf) -> Result<()> {
 
     // Load all timelines from disk to memory.
     GlobalTimelines::init(conf.clone(), wal_backup_launcher_tx)?;
+    remove_wal::load_tenant_policies(&conf)?;
 
     let conf_ = conf.clone();
     threads.push(
         thread::Builder::new()
             .name("http_endpoint_thread".into())
             .spawn(|| {
+                let tls = conf_.tls.clone();
                 let router = http::make_router(conf_);
-                endpoint::serve_thread_main(
-                    router,
-                    http_listener,
-                    std::future::pending(), // never shut down
-                )
+                match tls {
+                    Some(tls) => http::serve_thread_main_tls(router, http_listener, tls),
+                    None => endpoint::serve_thread_main(
+                        router,
+                        http_listener,
+                        std::future::pending(), // never shut down
+                    ),
+                }
                 .unwrap();
             })?,
     );
 
+    if let Some(tls) = conf.tls.clone() {
+        threads.push(
+            thread::Builder::new()
+                .name("TLS reload thread".into())
+                .spawn(move || {
+                    let mut signals = Signals::new([SIGHUP]).expect("failed to register SIGHUP");
+                    for _ in signals.forever() {
+                        if let Err(e) = tls.reload() {
+                            error!("failed to reload TLS certificate: {e:#}");
+                        }
+                    }
+                })?,
+        );
+    }
+
     let conf_cloned = conf.clone();
     let safekeeper_thread = thread::Builder::new()
         .name("safekeeper thread".into())
@@ -242,6 +507,17 @@ fn start_safekeeper(conf: SafeKeeperConf) -> Result<()> {
 
     threads.push(safekeeper_thread);
 
+    if let Some(listen_grpc_addr) = conf.listen_grpc_addr.clone() {
+        let conf_ = conf.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("gRPC thread".into())
+                .spawn(|| {
+                    grpc::thread_main(conf_, listen_grpc_addr);
+                })?,
+        );
+    }
+
     let conf_ = conf.clone();
     threads.push(
         thread::Builder::new()
@@ -251,6 +527,15 @@ fn start_safekeeper(conf: SafeKeeperConf) -> Result<()> {
             })?,
     );
 
+    let conf_ = conf.clone();
+    threads.push(
+        thread::Builder::new()
+            .name("recovery thread".into())
+            .spawn(|| {
+                recovery::thread_main(conf_);
+            })?,
+    );
+
     let conf_ = conf.clone();
     threads.push(
         thread::Builder::new()
@@ -260,6 +545,18 @@ fn start_safekeeper(conf: SafeKeeperConf) -> Result<()> {
             })?,
     );
 
+    if conf.timeline_eviction_timeout.is_some() {
+        let conf_ = conf.clone();
+        threads.push(
+            thread::Builder::new()
+                .name("timeline eviction thread".into())
+                .spawn(|| {
+                    timeline_eviction::thread_main(conf_);
+                })?,
+        );
+    }
+
+    let drain_conf = conf.clone();
     threads.push(
         thread::Builder::new()
             .name("WAL backup launcher thread".into())
@@ -274,6 +571,10 @@ fn start_safekeeper(conf: SafeKeeperConf) -> Result<()> {
 
     // NOTE: we still have to handle signals like SIGQUIT to prevent coredumps
     signals.handle(|signal| {
+        if let Signal::Terminate = signal {
+            info!("received {}, draining", signal.name());
+            drain::drain_and_exit(&drain_conf);
+        }
         // TODO: implement graceful shutdown with joining threads etc
         info!(
             "received {}, terminating in immediate shutdown mode",
@@ -341,6 +642,16 @@ fn parse_remote_storage(storage_conf: &str) -> anyhow::Result<RemoteStorageConfi
     })
 }
 
+fn parse_peer_http_addr(arg: &str) -> anyhow::Result<(NodeId, String)> {
+    let (id, addr) = arg
+        .split_once('=')
+        .with_context(|| format!("expected <id>=<url>, got '{arg}'"))?;
+    let id = id
+        .parse()
+        .with_context(|| format!("invalid safekeeper id in '{arg}'"))?;
+    Ok((NodeId(id), addr.trim_end_matches('/').to_owned()))
+}
+
 #[test]
 fn verify_cli() {
     use clap::CommandFactory;
@@ -0,0 +1,199 @@
+//! Registry of active Postgres protocol connections to timelines, for
+//! inspection through LIST_CONNECTIONS and the HTTP API.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use utils::id::{TenantId, TenantTimelineId};
+use utils::lsn::Lsn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionKind {
+    /// walproposer pushing WAL to us.
+    Push,
+    /// Client streaming WAL from us.
+    Replication,
+}
+
+impl ConnectionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionKind::Push => "push",
+            ConnectionKind::Replication => "replication",
+        }
+    }
+}
+
+/// Active connection of a timeline.
+#[derive(Debug)]
+pub struct Connection {
+    id: u64,
+    pub ttid: TenantTimelineId,
+    pub kind: ConnectionKind,
+    pub appname: Option<String>,
+    pub remote_addr: SocketAddr,
+    /// Options negotiated in startup packet and the command, e.g.
+    /// compression.
+    pub options: BTreeMap<String, String>,
+    /// Unix timestamp in seconds.
+    pub connected_at: u64,
+    pub start_lsn: Lsn,
+    /// End of WAL received (push) or sent (replication) so far.
+    lsn: AtomicU64,
+    /// WAL bytes received or sent so far, before compression.
+    bytes: AtomicU64,
+}
+
+impl Connection {
+    /// Account WAL received or sent up to `lsn`.
+    pub fn observe(&self, lsn: Lsn, bytes: u64) {
+        self.lsn.store(lsn.0, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            kind: self.kind,
+            appname: self.appname.clone(),
+            remote_addr: self.remote_addr.to_string(),
+            options: self.options.clone(),
+            connected_at: self.connected_at,
+            start_lsn: self.start_lsn.to_string(),
+            lsn: Lsn(self.lsn.load(Ordering::Relaxed)).to_string(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of the connection state for reporting.
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub kind: ConnectionKind,
+    pub appname: Option<String>,
+    pub remote_addr: String,
+    pub options: BTreeMap<String, String>,
+    pub connected_at: u64,
+    pub start_lsn: String,
+    pub lsn: String,
+    pub bytes: u64,
+}
+
+static CONNECTIONS: Lazy<Mutex<HashMap<u64, Arc<Connection>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Scope guard unregistering the connection.
+pub struct ConnectionGuard(Arc<Connection>);
+
+impl Deref for ConnectionGuard {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        CONNECTIONS.lock().unwrap().remove(&self.0.id);
+    }
+}
+
+/// Register the connection until the returned guard is dropped.
+pub fn register(
+    ttid: TenantTimelineId,
+    kind: ConnectionKind,
+    appname: Option<String>,
+    remote_addr: SocketAddr,
+    options: BTreeMap<String, String>,
+    start_lsn: Lsn,
+) -> ConnectionGuard {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed);
+    let connected_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let conn = Arc::new(Connection {
+        id,
+        ttid,
+        kind,
+        appname,
+        remote_addr,
+        options,
+        connected_at,
+        start_lsn,
+        lsn: AtomicU64::new(start_lsn.0),
+        bytes: AtomicU64::new(0),
+    });
+    CONNECTIONS.lock().unwrap().insert(id, Arc::clone(&conn));
+    ConnectionGuard(conn)
+}
+
+/// Active connections of the timeline, oldest first.
+pub fn list(ttid: &TenantTimelineId) -> Vec<ConnectionInfo> {
+    let mut conns: Vec<Arc<Connection>> = CONNECTIONS
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|conn| conn.ttid == *ttid)
+        .cloned()
+        .collect();
+    conns.sort_by_key(|conn| conn.id);
+    conns.iter().map(|conn| conn.info()).collect()
+}
+
+/// Number of active connections of the tenant.
+pub fn count_tenant(tenant_id: &TenantId) -> usize {
+    CONNECTIONS
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|conn| conn.ttid.tenant_id == *tenant_id)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::id::TimelineId;
+
+    #[test]
+    fn test_register() {
+        let ttid = TenantTimelineId::new(TenantId::generate(), TimelineId::generate());
+        let addr: SocketAddr = "127.0.0.1:5432".parse().unwrap();
+        let push = register(
+            ttid,
+            ConnectionKind::Push,
+            None,
+            addr,
+            BTreeMap::new(),
+            Lsn(0x100),
+        );
+        let replication = register(
+            ttid,
+            ConnectionKind::Replication,
+            Some("pageserver".to_owned()),
+            addr,
+            BTreeMap::from([("compression".to_owned(), "zstd".to_owned())]),
+            Lsn(0x200),
+        );
+        replication.observe(Lsn(0x300), 0x100);
+
+        let conns = list(&ttid);
+        assert_eq!(conns.len(), 2);
+        assert_eq!(conns[0].kind, ConnectionKind::Push);
+        assert_eq!(conns[1].lsn, "0/300");
+        assert_eq!(conns[1].bytes, 0x100);
+        assert_eq!(count_tenant(&ttid.tenant_id), 2);
+
+        drop(push);
+        drop(replication);
+        assert!(list(&ttid).is_empty());
+    }
+}
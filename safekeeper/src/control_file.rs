@@ -4,7 +4,7 @@ use anyhow::{bail, ensure, Context, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
 
@@ -16,11 +16,15 @@ use utils::{bin_ser::LeSer, id::TenantTimelineId};
 use crate::SafeKeeperConf;
 
 use std::convert::TryInto;
+use tracing::*;
 
 // contains persistent metadata for safekeeper
-const CONTROL_FILE_NAME: &str = "safekeeper.control";
+pub const CONTROL_FILE_NAME: &str = "safekeeper.control";
 // needed to atomically update the state using `rename`
 const CONTROL_FILE_NAME_PARTIAL: &str = "safekeeper.control.partial";
+// previous version of the control file, for manual recovery if the current
+// one is damaged
+pub const CONTROL_FILE_NAME_PREV: &str = "safekeeper.control.prev";
 pub const CHECKSUM_SIZE: usize = std::mem::size_of::<u32>();
 
 /// Storage should keep actual state inside of it. It should implement Deref
@@ -91,20 +95,49 @@ impl FileStorage {
         upgrade_control_file(buf, version)
     }
 
-    /// Load control file for given ttid at path specified by conf.
+    /// Serialize the state into the control file contents: magic, version,
+    /// the state itself and the checksum.
+    pub fn serialize(s: &SafeKeeperState) -> Result<Vec<u8>> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.write_u32::<LittleEndian>(SK_MAGIC)?;
+        buf.write_u32::<LittleEndian>(SK_FORMAT_VERSION)?;
+        s.ser_into(&mut buf)?;
+
+        // calculate checksum before resize
+        let checksum = crc32c::crc32c(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        Ok(buf)
+    }
+
+    /// Load control file for given ttid at path specified by conf. A damaged
+    /// control file is a hard error: the previous version kept by `persist`
+    /// may lack acknowledged state, so only an operator may decide to put it
+    /// in place.
     pub fn load_control_file_conf(
         conf: &SafeKeeperConf,
         ttid: &TenantTimelineId,
     ) -> Result<SafeKeeperState> {
-        let path = conf.timeline_dir(ttid).join(CONTROL_FILE_NAME);
-        Self::load_control_file(path)
+        let timeline_dir = conf.timeline_dir(ttid);
+        let path = timeline_dir.join(CONTROL_FILE_NAME);
+        let err = match Self::load_control_file(&path) {
+            Ok(state) => return Ok(state),
+            Err(err) => err,
+        };
+
+        let prev_path = timeline_dir.join(CONTROL_FILE_NAME_PREV);
+        if prev_path.exists() {
+            return Err(err.context(format!(
+                "previous version of the control file is kept at {}, it can be restored manually",
+                prev_path.display()
+            )));
+        }
+        Err(err)
     }
 
     /// Read in the control file.
     pub fn load_control_file<P: AsRef<Path>>(control_file_path: P) -> Result<SafeKeeperState> {
         let mut control_file = OpenOptions::new()
             .read(true)
-            .write(true)
             .open(&control_file_path)
             .with_context(|| {
                 format!(
@@ -118,6 +151,13 @@ impl FileStorage {
             .read_to_end(&mut buf)
             .context("failed to read control file")?;
 
+        // magic, version and checksum at least
+        ensure!(
+            buf.len() >= 2 * std::mem::size_of::<u32>() + CHECKSUM_SIZE,
+            "safekeeper control file is truncated to {} bytes",
+            buf.len()
+        );
+
         let calculated_checksum = crc32c::crc32c(&buf[..buf.len() - CHECKSUM_SIZE]);
 
         let expected_checksum_bytes: &[u8; CHECKSUM_SIZE] =
@@ -155,6 +195,9 @@ impl Storage for FileStorage {
     /// persists state durably to underlying storage
     /// for description see https://lwn.net/Articles/457667/
     fn persist(&mut self, s: &SafeKeeperState) -> Result<()> {
+        fail::fail_point!("sk-persist-control-file", |_| bail!(
+            "failpoint sk-persist-control-file"
+        ));
         let _timer = PERSIST_CONTROL_FILE_SECONDS.start_timer();
 
         // write data to safekeeper.control.partial
@@ -165,14 +208,7 @@ impl Storage for FileStorage {
                 &control_partial_path.display()
             )
         })?;
-        let mut buf: Vec<u8> = Vec::new();
-        buf.write_u32::<LittleEndian>(SK_MAGIC)?;
-        buf.write_u32::<LittleEndian>(SK_FORMAT_VERSION)?;
-        s.ser_into(&mut buf)?;
-
-        // calculate checksum before resize
-        let checksum = crc32c::crc32c(&buf);
-        buf.extend_from_slice(&checksum.to_le_bytes());
+        let buf = FileStorage::serialize(s)?;
 
         control_partial.write_all(&buf).with_context(|| {
             format!(
@@ -193,6 +229,29 @@ impl Storage for FileStorage {
 
         let control_path = self.timeline_dir.join(CONTROL_FILE_NAME);
 
+        // keep the current version as the previous one, so that there is a
+        // valid copy to restore by hand if the new one gets damaged
+        let control_prev_path = self.timeline_dir.join(CONTROL_FILE_NAME_PREV);
+        match fs::remove_file(&control_prev_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "failed to remove previous control file at {}",
+                        control_prev_path.display()
+                    )
+                })
+            }
+            _ => {}
+        }
+        if control_path.exists() {
+            fs::hard_link(&control_path, &control_prev_path).with_context(|| {
+                format!(
+                    "failed to keep previous control file at {}",
+                    control_prev_path.display()
+                )
+            })?;
+        }
+
         // rename should be atomic
         fs::rename(&control_partial_path, &control_path)?;
         // this sync is not required by any standard but postgres does this (see durable_rename)
@@ -294,4 +353,34 @@ mod test {
             Ok(_) => panic!("expected error"),
         }
     }
+
+    #[test]
+    fn test_safekeeper_state_damaged_keeps_prev() {
+        let conf = stub_conf();
+        let ttid = TenantTimelineId::generate();
+        {
+            let (mut storage, mut state) = create(&conf, &ttid).expect("failed to create state");
+            state.commit_lsn = Lsn(42);
+            storage.persist(&state).expect("failed to persist state");
+            state.commit_lsn = Lsn(43);
+            storage.persist(&state).expect("failed to persist state");
+        }
+        // tear the current control file
+        let control_path = conf.timeline_dir(&ttid).join(CONTROL_FILE_NAME);
+        let data = fs::read(&control_path).unwrap();
+        fs::write(&control_path, &data[..data.len() / 2]).expect("failed to write control file");
+
+        // no silent fallback to the previous version
+        match FileStorage::load_control_file_conf(&conf, &ttid) {
+            Err(err) => assert!(format!("{err:#}").contains(CONTROL_FILE_NAME_PREV)),
+            Ok(_) => panic!("expected error"),
+        }
+
+        // but it can be restored by hand
+        let prev_path = conf.timeline_dir(&ttid).join(CONTROL_FILE_NAME_PREV);
+        fs::rename(&prev_path, &control_path).unwrap();
+        let state =
+            FileStorage::load_control_file_conf(&conf, &ttid).expect("failed to read state");
+        assert_eq!(state.commit_lsn, Lsn(42));
+    }
 }
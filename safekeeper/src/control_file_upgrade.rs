@@ -1,9 +1,19 @@
-//! Code to deal with safekeeper control file upgrades
+//! Code to deal with safekeeper control file upgrades.
+//!
+//! Every format version of the control file has its own frozen struct here,
+//! and an upgrade step converting its body into the body of the next version.
+//! Old files are upgraded by running the steps one by one up to
+//! `SK_FORMAT_VERSION`; files of newer versions are refused, as they might
+//! hold state this safekeeper doesn't know about.
+//!
+//! To change `SafeKeeperState`, copy its current definition here as
+//! `SafeKeeperStateV<N>`, add a step from version N to the new one to
+//! `UPGRADES` and bump `SK_FORMAT_VERSION`.
 use crate::safekeeper::{
-    AcceptorState, PersistedPeers, PgUuid, SafeKeeperState, ServerInfo, Term, TermHistory,
-    TermSwitchEntry,
+    AcceptorState, Configuration, PersistedPeers, PgUuid, SafeKeeperState, ServerInfo, Term,
+    TermHistory, TermSwitchEntry, SK_FORMAT_VERSION,
 };
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use pq_proto::SystemId;
 use serde::{Deserialize, Serialize};
 use tracing::*;
@@ -138,130 +148,289 @@ pub struct SafeKeeperStateV4 {
     pub peers: PersistedPeers,
 }
 
-pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState> {
-    // migrate to storing full term history
-    if version == 1 {
-        info!("reading safekeeper control file version {}", version);
-        let oldstate = SafeKeeperStateV1::des(&buf[..buf.len()])?;
-        let ac = AcceptorState {
-            term: oldstate.acceptor_state.term,
-            term_history: TermHistory(vec![TermSwitchEntry {
-                term: oldstate.acceptor_state.epoch,
-                lsn: Lsn(0),
-            }]),
-        };
-        return Ok(SafeKeeperState {
-            tenant_id: oldstate.server.tenant_id,
-            timeline_id: oldstate.server.timeline_id,
-            acceptor_state: ac,
-            server: ServerInfo {
-                pg_version: oldstate.server.pg_version,
-                system_id: oldstate.server.system_id,
-                wal_seg_size: oldstate.server.wal_seg_size,
-            },
-            proposer_uuid: oldstate.proposer_uuid,
-            timeline_start_lsn: Lsn(0),
-            local_start_lsn: Lsn(0),
-            commit_lsn: oldstate.commit_lsn,
-            backup_lsn: Lsn(0),
-            peer_horizon_lsn: oldstate.truncate_lsn,
-            remote_consistent_lsn: Lsn(0),
-            peers: PersistedPeers(vec![]),
-        });
-    // migrate to hexing some ids
-    } else if version == 2 {
-        info!("reading safekeeper control file version {}", version);
-        let oldstate = SafeKeeperStateV2::des(&buf[..buf.len()])?;
-        let server = ServerInfo {
-            pg_version: oldstate.server.pg_version,
-            system_id: oldstate.server.system_id,
-            wal_seg_size: oldstate.server.wal_seg_size,
-        };
-        return Ok(SafeKeeperState {
-            tenant_id: oldstate.server.tenant_id,
-            timeline_id: oldstate.server.timeline_id,
+/// Versions 5 to 7 differ only in how fields are filled.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafeKeeperStateV7 {
+    #[serde(with = "hex")]
+    pub tenant_id: TenantId,
+    #[serde(with = "hex")]
+    pub timeline_id: TimelineId,
+    pub acceptor_state: AcceptorState,
+    pub server: ServerInfo,
+    #[serde(with = "hex")]
+    pub proposer_uuid: PgUuid,
+    pub timeline_start_lsn: Lsn,
+    pub local_start_lsn: Lsn,
+    pub commit_lsn: Lsn,
+    pub backup_lsn: Lsn,
+    pub peer_horizon_lsn: Lsn,
+    pub remote_consistent_lsn: Lsn,
+    pub peers: PersistedPeers,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SafeKeeperStateV8 {
+    #[serde(with = "hex")]
+    pub tenant_id: TenantId,
+    #[serde(with = "hex")]
+    pub timeline_id: TimelineId,
+    pub acceptor_state: AcceptorState,
+    pub server: ServerInfo,
+    #[serde(with = "hex")]
+    pub proposer_uuid: PgUuid,
+    pub timeline_start_lsn: Lsn,
+    pub local_start_lsn: Lsn,
+    pub commit_lsn: Lsn,
+    pub backup_lsn: Lsn,
+    pub peer_horizon_lsn: Lsn,
+    pub remote_consistent_lsn: Lsn,
+    pub peers: PersistedPeers,
+    pub configuration: Configuration,
+}
+
+impl From<SafeKeeperStateV7> for SafeKeeperStateV8 {
+    fn from(oldstate: SafeKeeperStateV7) -> Self {
+        SafeKeeperStateV8 {
+            tenant_id: oldstate.tenant_id,
+            timeline_id: oldstate.timeline_id,
             acceptor_state: oldstate.acceptor_state,
-            server,
+            server: oldstate.server,
             proposer_uuid: oldstate.proposer_uuid,
-            timeline_start_lsn: Lsn(0),
-            local_start_lsn: Lsn(0),
+            timeline_start_lsn: oldstate.timeline_start_lsn,
+            local_start_lsn: oldstate.local_start_lsn,
             commit_lsn: oldstate.commit_lsn,
-            backup_lsn: Lsn(0),
-            peer_horizon_lsn: oldstate.truncate_lsn,
-            remote_consistent_lsn: Lsn(0),
-            peers: PersistedPeers(vec![]),
-        });
-    // migrate to moving tenant_id/timeline_id to the top and adding some lsns
-    } else if version == 3 {
-        info!("reading safekeeper control file version {version}");
-        let oldstate = SafeKeeperStateV3::des(&buf[..buf.len()])?;
-        let server = ServerInfo {
+            backup_lsn: oldstate.backup_lsn,
+            peer_horizon_lsn: oldstate.peer_horizon_lsn,
+            remote_consistent_lsn: oldstate.remote_consistent_lsn,
+            peers: oldstate.peers,
+            configuration: Configuration::default(),
+        }
+    }
+}
+
+/// Upgrade step from the version in the first element to the next one,
+/// converting the serialized body.
+type UpgradeStep = (u32, fn(&[u8]) -> Result<Vec<u8>>);
+
+/// All upgrade steps, ordered by version.
+const UPGRADES: &[UpgradeStep] = &[
+    (1, upgrade_v1),
+    (2, upgrade_v2),
+    (3, upgrade_v3),
+    (4, upgrade_v4),
+    (5, upgrade_v5),
+    (6, upgrade_v6),
+    (7, upgrade_v7),
+    (8, upgrade_v8),
+];
+
+/// Upgrade the body of the control file of the given version to the current
+/// format and deserialize it.
+pub fn upgrade_control_file(buf: &[u8], version: u32) -> Result<SafeKeeperState> {
+    if version > SK_FORMAT_VERSION {
+        bail!(
+            "safekeeper control file version {} is newer than supported {}, refusing to downgrade",
+            version,
+            SK_FORMAT_VERSION
+        );
+    }
+    if version < UPGRADES[0].0 {
+        bail!("unsupported safekeeper control file version {}", version);
+    }
+
+    info!("reading safekeeper control file version {}", version);
+    let mut body = buf.to_vec();
+    for (from, step) in UPGRADES.iter().filter(|(from, _)| *from >= version) {
+        body = step(&body)
+            .with_context(|| format!("failed to upgrade control file from version {from}"))?;
+    }
+    Ok(SafeKeeperState::des(&body)?)
+}
+
+// migrate to storing full term history
+fn upgrade_v1(buf: &[u8]) -> Result<Vec<u8>> {
+    let oldstate = SafeKeeperStateV1::des(buf)?;
+    let ac = AcceptorState {
+        term: oldstate.acceptor_state.term,
+        term_history: TermHistory(vec![TermSwitchEntry {
+            term: oldstate.acceptor_state.epoch,
+            lsn: Lsn(0),
+        }]),
+    };
+    Ok(SafeKeeperStateV2 {
+        acceptor_state: ac,
+        server: oldstate.server,
+        proposer_uuid: oldstate.proposer_uuid,
+        commit_lsn: oldstate.commit_lsn,
+        truncate_lsn: oldstate.truncate_lsn,
+        wal_start_lsn: oldstate.wal_start_lsn,
+    }
+    .ser()?)
+}
+
+// migrate to hexing some ids
+fn upgrade_v2(buf: &[u8]) -> Result<Vec<u8>> {
+    let oldstate = SafeKeeperStateV2::des(buf)?;
+    Ok(SafeKeeperStateV3 {
+        acceptor_state: oldstate.acceptor_state,
+        server: ServerInfoV3 {
             pg_version: oldstate.server.pg_version,
             system_id: oldstate.server.system_id,
-            wal_seg_size: oldstate.server.wal_seg_size,
-        };
-        return Ok(SafeKeeperState {
             tenant_id: oldstate.server.tenant_id,
             timeline_id: oldstate.server.timeline_id,
-            acceptor_state: oldstate.acceptor_state,
-            server,
-            proposer_uuid: oldstate.proposer_uuid,
-            timeline_start_lsn: Lsn(0),
-            local_start_lsn: Lsn(0),
-            commit_lsn: oldstate.commit_lsn,
-            backup_lsn: Lsn(0),
-            peer_horizon_lsn: oldstate.truncate_lsn,
-            remote_consistent_lsn: Lsn(0),
-            peers: PersistedPeers(vec![]),
-        });
-    // migrate to having timeline_start_lsn
-    } else if version == 4 {
-        info!("reading safekeeper control file version {}", version);
-        let oldstate = SafeKeeperStateV4::des(&buf[..buf.len()])?;
-        let server = ServerInfo {
+            wal_seg_size: oldstate.server.wal_seg_size,
+        },
+        proposer_uuid: oldstate.proposer_uuid,
+        commit_lsn: oldstate.commit_lsn,
+        truncate_lsn: oldstate.truncate_lsn,
+        wal_start_lsn: oldstate.wal_start_lsn,
+    }
+    .ser()?)
+}
+
+// migrate to moving tenant_id/timeline_id to the top and adding some lsns
+fn upgrade_v3(buf: &[u8]) -> Result<Vec<u8>> {
+    let oldstate = SafeKeeperStateV3::des(buf)?;
+    Ok(SafeKeeperStateV4 {
+        tenant_id: oldstate.server.tenant_id,
+        timeline_id: oldstate.server.timeline_id,
+        acceptor_state: oldstate.acceptor_state,
+        server: ServerInfo {
             pg_version: oldstate.server.pg_version,
             system_id: oldstate.server.system_id,
             wal_seg_size: oldstate.server.wal_seg_size,
-        };
-        return Ok(SafeKeeperState {
-            tenant_id: oldstate.tenant_id,
-            timeline_id: oldstate.timeline_id,
-            acceptor_state: oldstate.acceptor_state,
-            server,
-            proposer_uuid: oldstate.proposer_uuid,
-            timeline_start_lsn: Lsn(0),
-            local_start_lsn: Lsn(0),
-            commit_lsn: oldstate.commit_lsn,
-            backup_lsn: Lsn::INVALID,
-            peer_horizon_lsn: oldstate.peer_horizon_lsn,
-            remote_consistent_lsn: Lsn(0),
-            peers: PersistedPeers(vec![]),
-        });
-    } else if version == 5 {
-        info!("reading safekeeper control file version {}", version);
-        let mut oldstate = SafeKeeperState::des(&buf[..buf.len()])?;
-        if oldstate.timeline_start_lsn != Lsn(0) {
-            return Ok(oldstate);
-        }
+        },
+        proposer_uuid: oldstate.proposer_uuid,
+        commit_lsn: oldstate.commit_lsn,
+        s3_wal_lsn: Lsn(0),
+        peer_horizon_lsn: oldstate.truncate_lsn,
+        remote_consistent_lsn: Lsn(0),
+        peers: PersistedPeers(vec![]),
+    }
+    .ser()?)
+}
 
+// migrate to having timeline_start_lsn
+fn upgrade_v4(buf: &[u8]) -> Result<Vec<u8>> {
+    let oldstate = SafeKeeperStateV4::des(buf)?;
+    Ok(SafeKeeperStateV7 {
+        tenant_id: oldstate.tenant_id,
+        timeline_id: oldstate.timeline_id,
+        acceptor_state: oldstate.acceptor_state,
+        server: oldstate.server,
+        proposer_uuid: oldstate.proposer_uuid,
+        timeline_start_lsn: Lsn(0),
+        local_start_lsn: Lsn(0),
+        commit_lsn: oldstate.commit_lsn,
+        backup_lsn: Lsn::INVALID,
+        peer_horizon_lsn: oldstate.peer_horizon_lsn,
+        remote_consistent_lsn: Lsn(0),
+        peers: PersistedPeers(vec![]),
+    }
+    .ser()?)
+}
+
+// fill in timeline_start_lsn if it is unknown
+fn upgrade_v5(buf: &[u8]) -> Result<Vec<u8>> {
+    let mut oldstate = SafeKeeperStateV7::des(buf)?;
+    if oldstate.timeline_start_lsn == Lsn(0) {
         // set special timeline_start_lsn because we don't know the real one
         info!("setting timeline_start_lsn and local_start_lsn to Lsn(1)");
         oldstate.timeline_start_lsn = Lsn(1);
         oldstate.local_start_lsn = Lsn(1);
+    }
+    Ok(oldstate.ser()?)
+}
 
-        return Ok(oldstate);
-    } else if version == 6 {
-        info!("reading safekeeper control file version {}", version);
-        let mut oldstate = SafeKeeperState::des(&buf[..buf.len()])?;
-        if oldstate.server.pg_version != 0 {
-            return Ok(oldstate);
-        }
-
+// fill in pg_version if it is unknown
+fn upgrade_v6(buf: &[u8]) -> Result<Vec<u8>> {
+    let mut oldstate = SafeKeeperStateV7::des(buf)?;
+    if oldstate.server.pg_version == 0 {
         // set pg_version to the default v14
         info!("setting pg_version to 140005");
         oldstate.server.pg_version = 140005;
+    }
+    Ok(oldstate.ser()?)
+}
+
+// migrate to having configuration
+fn upgrade_v7(buf: &[u8]) -> Result<Vec<u8>> {
+    let oldstate = SafeKeeperStateV7::des(buf)?;
+    Ok(SafeKeeperStateV8::from(oldstate).ser()?)
+}
+
+// migrate to having retention pins
+fn upgrade_v8(buf: &[u8]) -> Result<Vec<u8>> {
+    let oldstate = SafeKeeperStateV8::des(buf)?;
+    Ok(SafeKeeperState {
+        tenant_id: oldstate.tenant_id,
+        timeline_id: oldstate.timeline_id,
+        acceptor_state: oldstate.acceptor_state,
+        server: oldstate.server,
+        proposer_uuid: oldstate.proposer_uuid,
+        timeline_start_lsn: oldstate.timeline_start_lsn,
+        local_start_lsn: oldstate.local_start_lsn,
+        commit_lsn: oldstate.commit_lsn,
+        backup_lsn: oldstate.backup_lsn,
+        peer_horizon_lsn: oldstate.peer_horizon_lsn,
+        remote_consistent_lsn: oldstate.remote_consistent_lsn,
+        peers: oldstate.peers,
+        configuration: oldstate.configuration,
+        retention_pins: Vec::new(),
+    }
+    .ser()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::id::TenantTimelineId;
+
+    #[test]
+    fn test_upgrades_reach_current_version() {
+        for (i, (from, _)) in UPGRADES.iter().enumerate() {
+            assert_eq!(*from, UPGRADES[0].0 + i as u32);
+        }
+        assert_eq!(UPGRADES.last().unwrap().0 + 1, SK_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn test_upgrade_from_v1() {
+        let ttid = TenantTimelineId::generate();
+        let oldstate = SafeKeeperStateV1 {
+            acceptor_state: AcceptorStateV1 { term: 5, epoch: 4 },
+            server: ServerInfoV2 {
+                pg_version: 0,
+                system_id: 42,
+                tenant_id: ttid.tenant_id,
+                timeline_id: ttid.timeline_id,
+                wal_seg_size: 16 * 1024 * 1024,
+            },
+            proposer_uuid: [0; 16],
+            commit_lsn: Lsn(0x1000),
+            truncate_lsn: Lsn(0x800),
+            wal_start_lsn: Lsn(0x100),
+        };
+
+        let state = upgrade_control_file(&oldstate.ser().unwrap(), 1).unwrap();
+        assert_eq!(state.tenant_id, ttid.tenant_id);
+        assert_eq!(state.timeline_id, ttid.timeline_id);
+        assert_eq!(state.acceptor_state.term, 5);
+        assert_eq!(state.acceptor_state.term_history.0[0].term, 4);
+        assert_eq!(state.server.system_id, 42);
+        assert_eq!(state.server.pg_version, 140005);
+        assert_eq!(state.commit_lsn, Lsn(0x1000));
+        assert_eq!(state.peer_horizon_lsn, Lsn(0x800));
+        assert_eq!(state.timeline_start_lsn, Lsn(1));
+        assert_eq!(state.configuration, Configuration::default());
+        assert!(state.retention_pins.is_empty());
+    }
 
-        return Ok(oldstate);
+    #[test]
+    fn test_refuse_downgrade() {
+        let buf = SafeKeeperState::empty().ser().unwrap();
+        let err = upgrade_control_file(&buf, SK_FORMAT_VERSION + 1).unwrap_err();
+        assert!(err.to_string().contains("refusing to downgrade"));
+        assert!(upgrade_control_file(&buf, 0).is_err());
     }
-    bail!("unsupported safekeeper control file version {}", version)
 }
@@ -0,0 +1,263 @@
+//! Create a timeline as a copy of another one up to some LSN, so that a
+//! branch can be created without replaying its WAL through a compute.
+//!
+//! Only committed WAL can be copied: it is never truncated, so complete
+//! segments can be shared with the source timeline by hardlinks. The segment
+//! with the copy end is copied with everything after the end zeroed, and the
+//! end is checked to be a record boundary.
+
+use anyhow::{bail, ensure, Context, Result};
+use postgres_ffi::XLogFileName;
+use postgres_ffi::PG_TLI;
+use serde::Deserialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::cmp::{max, min};
+use std::fs;
+use std::path::Path;
+use tracing::*;
+use utils::id::{TenantTimelineId, TimelineId};
+use utils::lsn::Lsn;
+
+use crate::control_file::{self, FileStorage};
+use crate::safekeeper::SafeKeeperState;
+use crate::timeline::Timeline;
+use crate::wal_storage::{compressed_wal_file_path, read_segment};
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    #[serde_as(as = "DisplayFromStr")]
+    pub target_timeline_id: TimelineId,
+    /// End of the copied WAL, must be a record boundary
+    #[serde_as(as = "DisplayFromStr")]
+    pub until_lsn: Lsn,
+}
+
+/// Copy timeline `source` of the request's tenant to the target timeline and
+/// load it.
+pub fn handle_request(
+    conf: &SafeKeeperConf,
+    source: TenantTimelineId,
+    request: Request,
+) -> Result<std::sync::Arc<Timeline>> {
+    let target = TenantTimelineId::new(source.tenant_id, request.target_timeline_id);
+    let until_lsn = request.until_lsn;
+    if GlobalTimelines::get(target).is_ok() {
+        bail!("timeline {} already exists", target);
+    }
+
+    let tli = GlobalTimelines::get(source)?;
+    let (inmem, state) = tli.get_state();
+    ensure!(
+        until_lsn <= inmem.commit_lsn,
+        "can't copy WAL up to {}, only up to commit_lsn {}",
+        until_lsn,
+        inmem.commit_lsn
+    );
+    ensure!(
+        until_lsn >= state.local_start_lsn,
+        "WAL before local_start_lsn {} is not present locally",
+        state.local_start_lsn
+    );
+
+    let target_dir = conf.timeline_dir(&target);
+    ensure!(
+        !target_dir.exists(),
+        "timeline directory {} already exists",
+        target_dir.display()
+    );
+    fs::create_dir_all(&target_dir)?;
+    info!(
+        "copying timeline {} to {} up to {}",
+        source, target, until_lsn
+    );
+
+    let res = copy_wal(conf, &source, &target, &state, until_lsn).and_then(|local_start_lsn| {
+        let new_state = copy_state(&state, &target, local_start_lsn, until_lsn);
+        // Write the control file last, so that an incomplete copy is not
+        // recognized as a valid timeline.
+        let mut store = FileStorage::create_new(&target, conf, new_state.clone())?;
+        control_file::Storage::persist(&mut store, &new_state)
+    });
+    if let Err(e) = res {
+        let _ = fs::remove_dir_all(&target_dir);
+        return Err(e);
+    }
+
+    GlobalTimelines::load_timeline(target)
+}
+
+/// Copy WAL of the source timeline to the target one, returning the LSN the
+/// target WAL starts at.
+fn copy_wal(
+    conf: &SafeKeeperConf,
+    source: &TenantTimelineId,
+    target: &TenantTimelineId,
+    state: &SafeKeeperState,
+    until_lsn: Lsn,
+) -> Result<Lsn> {
+    let source_dir = conf.timeline_dir(source);
+    let target_dir = conf.timeline_dir(target);
+    let wal_seg_size = state.server.wal_seg_size as usize;
+
+    // Old segments might have been removed already, start with the first
+    // one present.
+    let first_segno = state.local_start_lsn.segment_number(wal_seg_size);
+    let end_segno = until_lsn.segment_number(wal_seg_size);
+    let mut local_start_lsn = None;
+    for segno in first_segno..end_segno {
+        let name = XLogFileName(PG_TLI, segno, wal_seg_size);
+        let source_path = source_dir.join(&name);
+        let compressed_path = compressed_wal_file_path(&source_path);
+        if compressed_path.exists() {
+            link_or_copy(
+                &compressed_path,
+                &compressed_wal_file_path(&target_dir.join(&name)),
+            )?;
+        } else if !source_path.exists() && local_start_lsn.is_none() {
+            continue;
+        } else {
+            link_or_copy(&source_path, &target_dir.join(&name))?;
+        }
+        if local_start_lsn.is_none() {
+            local_start_lsn = Some(Lsn(segno * wal_seg_size as u64));
+        }
+    }
+
+    let end_offset = until_lsn.segment_offset(wal_seg_size);
+    if end_offset != 0 {
+        let name = XLogFileName(PG_TLI, end_segno, wal_seg_size);
+        let mut buf = read_segment(&source_dir, end_segno, wal_seg_size)
+            .with_context(|| format!("failed to read segment {name}"))?;
+        ensure!(
+            buf.len() == wal_seg_size,
+            "unexpected size {} of segment {}",
+            buf.len(),
+            name
+        );
+        buf[end_offset..].fill(0);
+        fs::write(target_dir.join(name + ".partial"), &buf)?;
+        if local_start_lsn.is_none() {
+            local_start_lsn = Some(Lsn(end_segno * wal_seg_size as u64));
+        }
+    }
+    let local_start_lsn = max(
+        local_start_lsn.context("no WAL to copy")?,
+        state.local_start_lsn,
+    );
+
+    check_record_boundary(state, &target_dir, local_start_lsn, until_lsn)?;
+    Ok(local_start_lsn)
+}
+
+/// Segments are shared with the source timeline when possible.
+fn link_or_copy(source: &Path, target: &Path) -> Result<()> {
+    if let Err(e) = fs::hard_link(source, target) {
+        debug!("failed to hardlink {}, copying: {}", source.display(), e);
+        fs::copy(source, target).with_context(|| format!("failed to copy {}", source.display()))?;
+    }
+    Ok(())
+}
+
+/// Check that `until_lsn` is the end of a record by decoding WAL up to it,
+/// starting from the closest LSN which is known to be a record boundary.
+fn check_record_boundary(
+    state: &SafeKeeperState,
+    dir: &Path,
+    local_start_lsn: Lsn,
+    until_lsn: Lsn,
+) -> Result<()> {
+    let start_lsn = state
+        .acceptor_state
+        .term_history
+        .0
+        .iter()
+        .map(|e| e.lsn)
+        .chain([
+            state.timeline_start_lsn,
+            state.local_start_lsn,
+            state.peer_horizon_lsn,
+        ])
+        .filter(|lsn| *lsn >= local_start_lsn && *lsn <= until_lsn)
+        .max()
+        .context("no known record boundary to decode WAL from")?;
+
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    let end_of_wal = postgres_ffi::find_end_of_wal(
+        dir,
+        wal_seg_size,
+        start_lsn,
+        state.server.pg_version / 10000,
+    )?;
+    ensure!(
+        end_of_wal == until_lsn,
+        "{} is not a record boundary, the previous one is {}",
+        until_lsn,
+        end_of_wal
+    );
+    Ok(())
+}
+
+/// Control state of the copy: the source one cut at `until_lsn`.
+fn copy_state(
+    state: &SafeKeeperState,
+    target: &TenantTimelineId,
+    local_start_lsn: Lsn,
+    until_lsn: Lsn,
+) -> SafeKeeperState {
+    let mut new_state = state.clone();
+    new_state.tenant_id = target.tenant_id;
+    new_state.timeline_id = target.timeline_id;
+    new_state.acceptor_state.term_history = state.acceptor_state.term_history.up_to(until_lsn);
+    new_state.local_start_lsn = local_start_lsn;
+    new_state.commit_lsn = until_lsn;
+    // Nothing of the copy is offloaded yet; remote WAL of the source
+    // timeline is stored under its own path.
+    new_state.backup_lsn = local_start_lsn.segment_lsn(state.server.wal_seg_size as usize);
+    new_state.peer_horizon_lsn = min(state.peer_horizon_lsn, until_lsn);
+    new_state.remote_consistent_lsn = min(state.remote_consistent_lsn, until_lsn);
+    new_state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safekeeper::{TermHistory, TermSwitchEntry};
+
+    #[test]
+    fn test_copy_state() {
+        let source = TenantTimelineId::generate();
+        let target = TenantTimelineId::new(source.tenant_id, TimelineId::generate());
+        let mut state = SafeKeeperState::empty();
+        state.tenant_id = source.tenant_id;
+        state.timeline_id = source.timeline_id;
+        state.server.wal_seg_size = 16 * 1024 * 1024;
+        state.acceptor_state.term = 3;
+        state.acceptor_state.term_history = TermHistory(vec![
+            TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(0x1000028),
+            },
+            TermSwitchEntry {
+                term: 3,
+                lsn: Lsn(0x3000028),
+            },
+        ]);
+        state.local_start_lsn = Lsn(0x1000028);
+        state.commit_lsn = Lsn(0x4000000);
+        state.backup_lsn = Lsn(0x3000000);
+        state.peer_horizon_lsn = Lsn(0x3800000);
+        state.remote_consistent_lsn = Lsn(0x1800000);
+
+        let new_state = copy_state(&state, &target, Lsn(0x1000028), Lsn(0x2000100));
+        assert_eq!(new_state.tenant_id, target.tenant_id);
+        assert_eq!(new_state.timeline_id, target.timeline_id);
+        assert_eq!(new_state.acceptor_state.term, 3);
+        assert_eq!(new_state.acceptor_state.term_history.0.len(), 1);
+        assert_eq!(new_state.commit_lsn, Lsn(0x2000100));
+        assert_eq!(new_state.backup_lsn, Lsn(0x1000000));
+        assert_eq!(new_state.peer_horizon_lsn, Lsn(0x2000100));
+        assert_eq!(new_state.remote_consistent_lsn, Lsn(0x1800000));
+    }
+}
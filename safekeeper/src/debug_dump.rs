@@ -0,0 +1,116 @@
+//! Dump of the in-memory and persisted state of timelines, for debugging
+//! stuck replication. Exposed through the HTTP API and the DEBUG_DUMP psql
+//! command.
+
+use std::time::SystemTime;
+
+use anyhow::Result;
+use postgres_ffi::XLogSegNo;
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use utils::id::{NodeId, TenantId, TimelineId};
+use utils::lsn::Lsn;
+
+use crate::safekeeper::{SafeKeeperState, SafekeeperMemState, Term};
+use crate::timeline::{BackpressureState, ReplicaState};
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// Which timelines to dump.
+#[derive(Debug, Clone, Default)]
+pub struct Args {
+    pub tenant_id: Option<TenantId>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Response {
+    pub start_time: SystemTime,
+    pub finish_time: SystemTime,
+    #[serde(serialize_with = "serialize_node_id")]
+    pub node_id: NodeId,
+    pub timelines_count: usize,
+    pub timelines: Vec<Timeline>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct Timeline {
+    #[serde_as(as = "DisplayFromStr")]
+    pub tenant_id: TenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: TimelineId,
+    /// None if the timeline was cancelled, e.g. deleted, while dumping.
+    pub memory: Option<Memory>,
+    /// Persisted state, including the term history.
+    pub control_file: Option<SafeKeeperState>,
+}
+
+/// In-memory state of the timeline.
+#[derive(Debug, Serialize)]
+pub struct Memory {
+    pub active: bool,
+    pub wal_backup_active: bool,
+    pub num_computes: u32,
+    pub last_removed_segno: XLogSegNo,
+    pub epoch_start_lsn: Lsn,
+    pub write_lsn: Lsn,
+    pub flush_lsn: Lsn,
+    pub mem_state: SafekeeperMemState,
+    pub backpressure: BackpressureState,
+    /// Connected replicas with their feedback.
+    pub replicas: Vec<ReplicaState>,
+    pub peers: Vec<Peer>,
+}
+
+/// Peer safekeeper as we last heard of it from the broker.
+#[derive(Debug, Serialize)]
+pub struct Peer {
+    #[serde(serialize_with = "serialize_node_id")]
+    pub sk_id: NodeId,
+    pub term: Term,
+    pub last_log_term: Term,
+    pub flush_lsn: Lsn,
+    pub commit_lsn: Lsn,
+    pub backup_lsn: Lsn,
+    pub local_start_lsn: Lsn,
+    pub last_heard_seconds_ago: f64,
+}
+
+fn serialize_node_id<S: serde::Serializer>(id: &NodeId, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(id.0)
+}
+
+/// Dump the requested timelines.
+pub fn build(conf: &SafeKeeperConf, args: Args) -> Result<Response> {
+    let start_time = SystemTime::now();
+
+    let mut timelines: Vec<_> = GlobalTimelines::get_all()
+        .into_iter()
+        .filter(|tli| {
+            args.tenant_id
+                .map_or(true, |tenant_id| tli.ttid.tenant_id == tenant_id)
+        })
+        .collect();
+    timelines.sort_by_key(|tli| tli.ttid);
+
+    let timelines = timelines
+        .into_iter()
+        .map(|tli| {
+            let memory = tli.memory_dump(conf);
+            let control_file = memory.as_ref().map(|_| tli.get_state().1);
+            Timeline {
+                tenant_id: tli.ttid.tenant_id,
+                timeline_id: tli.ttid.timeline_id,
+                memory,
+                control_file,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Response {
+        start_time,
+        finish_time: SystemTime::now(),
+        node_id: conf.my_id,
+        timelines_count: timelines.len(),
+        timelines,
+    })
+}
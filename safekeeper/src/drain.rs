@@ -0,0 +1,88 @@
+//! Graceful shutdown of the safekeeper, triggered by SIGTERM or the HTTP API.
+//!
+//! While draining, new timelines are refused, walproposers get an
+//! ErrorResponse and replicas a CopyDone instead of a dropped connection.
+//! Once connections are gone and WAL backup has caught up (or the timeout
+//! expires), WAL and control files of all timelines are flushed and closed
+//! and the process exits.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::*;
+
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+/// How long to wait for connections to go away and WAL backup to finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for background tasks of all timelines to stop.
+const TASKS_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often connections and background activities check for draining.
+pub const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Whether the safekeeper is shutting down.
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Start draining in a background thread, unless it is already in progress.
+pub fn spawn(conf: SafeKeeperConf) -> anyhow::Result<()> {
+    if is_draining() {
+        return Ok(());
+    }
+    thread::Builder::new()
+        .name("drain thread".into())
+        .spawn(move || drain_and_exit(&conf))?;
+    Ok(())
+}
+
+/// Drain the safekeeper and exit the process.
+pub fn drain_and_exit(conf: &SafeKeeperConf) -> ! {
+    DRAINING.store(true, Ordering::Relaxed);
+    info!("draining safekeeper");
+
+    let started_at = Instant::now();
+    loop {
+        let busy = GlobalTimelines::get_all()
+            .iter()
+            .filter(|tli| !tli.is_drained(conf))
+            .count();
+        if busy == 0 {
+            break;
+        }
+        if started_at.elapsed() >= DRAIN_TIMEOUT {
+            warn!(
+                "{} timelines are still busy after {:?}, exiting anyway",
+                busy, DRAIN_TIMEOUT
+            );
+            break;
+        }
+        thread::sleep(DRAIN_CHECK_INTERVAL);
+    }
+
+    // Cancel all timelines first, so that their tasks stop in parallel
+    // instead of one timeline after another.
+    let mut timelines = Vec::new();
+    for tli in GlobalTimelines::get_all() {
+        match tli.start_shutdown() {
+            Ok(true) => timelines.push(tli),
+            Ok(false) => {}
+            Err(e) => error!("failed to shut down timeline {}: {:#}", tli.ttid, e),
+        }
+    }
+    let deadline = Instant::now() + TASKS_STOP_TIMEOUT;
+    for tli in timelines {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if let Err(e) = tli.finish_shutdown(timeout) {
+            error!("failed to shut down timeline {}: {:#}", tli.ttid, e);
+        }
+    }
+
+    info!("drained in {:?}, exiting", started_at.elapsed());
+    std::process::exit(0);
+}
@@ -0,0 +1,370 @@
+//! gRPC service giving read access to timelines' WAL, for consumers which
+//! don't speak the Postgres replication protocol (backup tooling, analytics
+//! ingest). WAL is read and cut into chunks by the same machinery as in
+//! START_REPLICATION, see `send_wal::WalChunker`.
+
+use std::collections::BTreeMap;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use futures::{Future, Stream};
+use tokio::runtime;
+use tokio::sync::mpsc;
+use tonic::transport::Server;
+use tonic::{Code, Request, Response, Status};
+use tracing::*;
+use utils::id::{TenantId, TenantTimelineId, TimelineId};
+use utils::lsn::Lsn;
+
+use crate::auth::check_tenant_permission;
+use crate::connections::{self, ConnectionKind};
+use crate::drain;
+use crate::rate_limit::WalSendLimiter;
+use crate::send_wal::{wait_for_lsn, WalChunker};
+use crate::timeline::Timeline;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+use proto::safekeeper_wal_service_server::{SafekeeperWalService, SafekeeperWalServiceServer};
+use proto::{StreamWalRequest, TimelineStatus, TimelineStatusRequest, WalChunk};
+
+// Code generated by protobuf.
+pub mod proto {
+    #![allow(clippy::derive_partial_eq_without_eq)]
+    tonic::include_proto!("safekeeper");
+}
+
+/// Chunks read ahead of the client.
+const CHUNK_CHANNEL_SIZE: usize = 16;
+
+pub fn thread_main(conf: SafeKeeperConf, listen_addr: String) {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let _enter = info_span!("gRPC").entered();
+    info!("starting gRPC service on {}", listen_addr);
+
+    if let Err(e) = runtime.block_on(serve(conf, &listen_addr)) {
+        error!("gRPC service failed: {:#}", e);
+    }
+}
+
+async fn serve(conf: SafeKeeperConf, listen_addr: &str) -> anyhow::Result<()> {
+    let addr: SocketAddr = listen_addr
+        .to_socket_addrs()?
+        .next()
+        .with_context(|| format!("failed to resolve {listen_addr}"))?;
+    Server::builder()
+        .add_service(SafekeeperWalServiceServer::new(WalService { conf }))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+struct WalService {
+    conf: SafeKeeperConf,
+}
+
+impl WalService {
+    /// Check the token in `authorization` metadata allows access to the
+    /// tenant, if auth is enabled.
+    fn check_permission<T>(&self, request: &Request<T>, tenant_id: TenantId) -> Result<(), Status> {
+        let Some(auth) = self.conf.auth.as_ref() else {
+            return Ok(());
+        };
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or_else(|| Status::unauthenticated("missing bearer token"))?;
+        let data = auth
+            .decode(token)
+            .map_err(|e| Status::unauthenticated(e.to_string()))?;
+        check_tenant_permission(&data.claims, tenant_id)
+            .map_err(|e| Status::permission_denied(e.to_string()))
+    }
+}
+
+fn parse_ttid(proto_ttid: Option<proto::TenantTimelineId>) -> Result<TenantTimelineId, Status> {
+    let proto_ttid =
+        proto_ttid.ok_or_else(|| Status::invalid_argument("missing tenant_timeline_id"))?;
+    let tenant_id = TenantId::from_slice(&proto_ttid.tenant_id)
+        .map_err(|e| Status::invalid_argument(format!("malformed tenant_id: {}", e)))?;
+    let timeline_id = TimelineId::from_slice(&proto_ttid.timeline_id)
+        .map_err(|e| Status::invalid_argument(format!("malformed timeline_id: {}", e)))?;
+    Ok(TenantTimelineId::new(tenant_id, timeline_id))
+}
+
+fn get_timeline(ttid: TenantTimelineId) -> Result<Arc<Timeline>, Status> {
+    GlobalTimelines::get(ttid).map_err(|e| Status::new(Code::NotFound, e.to_string()))
+}
+
+type WalChunkStream = Pin<Box<dyn Stream<Item = Result<WalChunk, Status>> + Send + 'static>>;
+
+#[tonic::async_trait]
+impl SafekeeperWalService for WalService {
+    type StreamWalStream = WalChunkStream;
+
+    async fn stream_wal(
+        &self,
+        request: Request<StreamWalRequest>,
+    ) -> Result<Response<Self::StreamWalStream>, Status> {
+        let remote_addr = request
+            .remote_addr()
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)));
+        let req = request.get_ref().clone();
+        let ttid = parse_ttid(req.tenant_timeline_id)?;
+        self.check_permission(&request, ttid.tenant_id)?;
+        let tli = get_timeline(ttid)?;
+
+        let start_lsn = Lsn(req.start_lsn);
+        let end_lsn = (req.end_lsn != 0).then_some(Lsn(req.end_lsn));
+        if matches!(end_lsn, Some(end_lsn) if end_lsn < start_lsn) {
+            return Err(Status::invalid_argument(format!(
+                "end_lsn {} is before start_lsn {}",
+                Lsn(req.end_lsn),
+                start_lsn
+            )));
+        }
+
+        let conf = self.conf.clone();
+        let span = info_span!("gRPC WAL sender", ttid = %ttid);
+        let output = spawn_stream(
+            move |tx| async move {
+                let mut options = BTreeMap::new();
+                options.insert("protocol".to_owned(), "grpc".to_owned());
+                if req.compress {
+                    options.insert("compression".to_owned(), "zstd".to_owned());
+                }
+                let conn = connections::register(
+                    ttid,
+                    ConnectionKind::Replication,
+                    None,
+                    remote_addr,
+                    options,
+                    start_lsn,
+                );
+                send_wal_chunks(
+                    &conf,
+                    tli,
+                    start_lsn,
+                    end_lsn,
+                    req.compress,
+                    &tx,
+                    |lsn, bytes| conn.observe(lsn, bytes),
+                )
+                .await
+            },
+            span,
+        );
+        Ok(Response::new(output))
+    }
+
+    async fn get_timeline_status(
+        &self,
+        request: Request<TimelineStatusRequest>,
+    ) -> Result<Response<TimelineStatus>, Status> {
+        let ttid = parse_ttid(request.get_ref().tenant_timeline_id.clone())?;
+        self.check_permission(&request, ttid.tenant_id)?;
+        let tli = get_timeline(ttid)?;
+
+        let (inmem, state) = tli.get_state();
+        let (last_record_time, _) = tli.get_record_time();
+        Ok(Response::new(TimelineStatus {
+            term: state.acceptor_state.term,
+            timeline_start_lsn: state.timeline_start_lsn.0,
+            local_start_lsn: state.local_start_lsn.0,
+            flush_lsn: tli.get_flush_lsn().0,
+            commit_lsn: inmem.commit_lsn.0,
+            backup_lsn: inmem.backup_lsn.0,
+            remote_consistent_lsn: inmem.remote_consistent_lsn.0,
+            last_record_time: last_record_time.unwrap_or(0),
+        }))
+    }
+}
+
+/// Run the sender producing chunks on the shared runtime, returning the
+/// stream of them to the client. An error of the sender ends the stream.
+fn spawn_stream<F, Fut>(sender: F, span: Span) -> WalChunkStream
+where
+    F: FnOnce(mpsc::Sender<Result<WalChunk, Status>>) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let (tx, mut rx) = mpsc::channel(CHUNK_CHANNEL_SIZE);
+    let fut = sender(tx.clone());
+    crate::BACKGROUND_RUNTIME.spawn(
+        async move {
+            if let Err(e) = fut.await {
+                info!("WAL streaming failed: {:#}", e);
+                let _ = tx.send(Err(Status::internal(format!("{e:#}")))).await;
+            }
+        }
+        .instrument(span),
+    );
+
+    Box::pin(async_stream::stream! {
+        while let Some(chunk) = rx.recv().await {
+            yield chunk;
+        }
+    })
+}
+
+/// Send committed WAL from `start_pos` up to `end_pos`, or until the client
+/// is gone if there is no end.
+async fn send_wal_chunks(
+    conf: &SafeKeeperConf,
+    tli: Arc<Timeline>,
+    mut start_pos: Lsn,
+    end_pos: Option<Lsn>,
+    compress: bool,
+    tx: &mpsc::Sender<Result<WalChunk, Status>>,
+    observe: impl Fn(Lsn, u64),
+) -> anyhow::Result<()> {
+    let (_, state) = tli.get_state();
+    if !state.is_initialized() {
+        bail!("timeline {} is not initialized yet", tli.ttid);
+    }
+    let mut chunker = WalChunker::new(conf, &tli, &state, start_pos, compress)?;
+    let limiter = WalSendLimiter::new(conf, tli.ttid.tenant_id);
+    let mut commit_lsn_watch_rx = tli.get_commit_lsn_watch_rx();
+    info!("start streaming from {} till {:?}", start_pos, end_pos);
+
+    loop {
+        if drain::is_draining() {
+            bail!("safekeeper is shutting down, stopping at {}", start_pos);
+        }
+        if tli.is_cancelled() {
+            bail!("timeline {} was deleted", tli.ttid);
+        }
+        if tx.is_closed() {
+            info!("client is gone, stopping at {}", start_pos);
+            return Ok(());
+        }
+        if let Some(end_pos) = end_pos {
+            if start_pos >= end_pos {
+                info!("reached requested end of streaming {}", start_pos);
+                return Ok(());
+            }
+        }
+
+        // Wait until we have some data to stream
+        let Some(commit_lsn) = wait_for_lsn(&mut commit_lsn_watch_rx, start_pos).await? else {
+            continue;
+        };
+        let chunk_end = end_pos.map_or(commit_lsn, |end_pos| end_pos.min(commit_lsn));
+        let send_size = chunker.fill(chunk_end).await?;
+
+        let throttle = limiter.throttle(send_size as u64);
+        if !throttle.is_zero() {
+            tokio::time::sleep(throttle).await;
+        }
+
+        let chunk = WalChunk {
+            start_lsn: start_pos.0,
+            end_lsn: (start_pos + send_size as u64).0,
+            commit_lsn: commit_lsn.0,
+            data: chunker.take_chunk(send_size)?.to_vec(),
+        };
+        if tx.send(Ok(chunk)).await.is_err() {
+            info!("client is gone, stopping at {}", start_pos);
+            return Ok(());
+        }
+
+        start_pos += send_size as u64;
+        tli.observe_wal_sent(send_size as u64);
+        observe(start_pos, send_size as u64);
+        trace!("sent WAL up to {}", start_pos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_parse_ttid() {
+        let ttid = TenantTimelineId::generate();
+        let parsed = parse_ttid(Some(proto::TenantTimelineId {
+            tenant_id: ttid.tenant_id.as_ref().to_vec(),
+            timeline_id: ttid.timeline_id.as_ref().to_vec(),
+        }))
+        .unwrap();
+        assert_eq!(parsed, ttid);
+
+        let status = parse_ttid(None).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let status = parse_ttid(Some(proto::TenantTimelineId {
+            tenant_id: vec![1, 2, 3],
+            timeline_id: ttid.timeline_id.as_ref().to_vec(),
+        }))
+        .unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_timeline() {
+        let service = WalService {
+            conf: SafeKeeperConf::dummy(),
+        };
+        let ttid = TenantTimelineId::generate();
+        let request = Request::new(TimelineStatusRequest {
+            tenant_timeline_id: Some(proto::TenantTimelineId {
+                tenant_id: ttid.tenant_id.as_ref().to_vec(),
+                timeline_id: ttid.timeline_id.as_ref().to_vec(),
+            }),
+        });
+        let status = service.get_timeline_status(request).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    fn test_chunk(start_lsn: u64) -> WalChunk {
+        WalChunk {
+            start_lsn,
+            end_lsn: start_lsn + 8,
+            commit_lsn: start_lsn + 8,
+            data: vec![0; 8],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spawn_stream() {
+        let stream = spawn_stream(
+            |tx| async move {
+                tx.send(Ok(test_chunk(0x10))).await?;
+                tx.send(Ok(test_chunk(0x18))).await?;
+                anyhow::bail!("read failed")
+            },
+            info_span!("test"),
+        );
+        let chunks: Vec<_> = stream.collect().await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].as_ref().unwrap().start_lsn, 0x10);
+        assert_eq!(chunks[1].as_ref().unwrap().start_lsn, 0x18);
+        let status = chunks[2].as_ref().unwrap_err();
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(status.message(), "read failed");
+    }
+
+    #[tokio::test]
+    async fn test_spawn_stream_client_gone() {
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let mut stream = spawn_stream(
+            |tx| async move {
+                tx.send(Ok(test_chunk(0x10))).await?;
+                // the sender notices the client is gone
+                tx.closed().await;
+                let _ = done_tx.send(());
+                Ok(())
+            },
+            info_span!("test"),
+        );
+        assert!(stream.next().await.unwrap().is_ok());
+        drop(stream);
+        done_rx.await.unwrap();
+    }
+}
@@ -2,21 +2,32 @@
 //! protocol commands.
 
 use crate::auth::check_permission;
-use crate::json_ctrl::{handle_json_ctrl, AppendLogicalMessage};
+use crate::connections;
+use crate::debug_dump;
+use crate::drain;
+use crate::json_ctrl::{handle_json_ctrl, JsonCtrlRequest};
+use crate::quota;
 use crate::receive_wal::ReceiveWalConn;
+use crate::remove_wal;
+use crate::safekeeper::Term;
 
 use crate::send_wal::ReplicationConn;
+use crate::snapshot;
+use crate::wal_check;
 
 use crate::{GlobalTimelines, SafeKeeperConf};
 use anyhow::Context;
 
-use postgres_ffi::PG_TLI;
+use postgres_ffi::{from_pg_timestamp, XLogFileName, PG_TLI};
 use regex::Regex;
 
-use pq_proto::{BeMessage, FeStartupPacket, RowDescriptor, INT4_OID, TEXT_OID};
+use pq_proto::cell::{self, Cell};
+use pq_proto::{BeMessage, FeStartupPacket, RowDescriptor};
+use std::collections::BTreeMap;
 use std::str;
-use tracing::info;
+use tracing::{debug, info};
 use utils::auth::{Claims, Scope};
+use utils::cancel_registry::CancellationToken;
 use utils::postgres_backend_async::QueryError;
 use utils::{
     id::{TenantId, TenantTimelineId, TimelineId},
@@ -32,31 +43,114 @@ pub struct SafekeeperPostgresHandler {
     pub tenant_id: Option<TenantId>,
     pub timeline_id: Option<TimelineId>,
     pub ttid: TenantTimelineId,
+    /// compress WAL sent to the client with zstd
+    pub compress_wal: bool,
+    /// term the peer recovering from us expects, see `recovery`
+    pub recovery_term: Option<Term>,
     claims: Option<Claims>,
 }
 
 /// Parsed Postgres command.
 enum SafekeeperPostgresCommand {
     StartWalPush,
-    StartReplication { start_lsn: Lsn },
+    StartReplication {
+        start_lsn: Lsn,
+        until_lsn: Option<Lsn>,
+        timeline: Option<u32>,
+    },
     IdentifySystem,
-    JSONCtrl { cmd: AppendLogicalMessage },
+    TimelineStatus,
+    TimelineDelete {
+        only_local: bool,
+    },
+    TimelineExport,
+    TimelineImport,
+    TimelineTermBump {
+        term: Option<Term>,
+    },
+    DebugDump,
+    CheckWal,
+    GcWal,
+    ListConnections,
+    /// SET or RESET of a session parameter, `guc` is None for RESET ALL.
+    Set {
+        guc: Option<String>,
+        reset: bool,
+    },
+    Show {
+        guc: String,
+    },
+    JSONCtrl {
+        cmd: JsonCtrlRequest,
+    },
 }
 
 fn parse_cmd(cmd: &str) -> anyhow::Result<SafekeeperPostgresCommand> {
     if cmd.starts_with("START_WAL_PUSH") {
         Ok(SafekeeperPostgresCommand::StartWalPush)
     } else if cmd.starts_with("START_REPLICATION") {
-        let re =
-            Regex::new(r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)").unwrap();
-        let mut caps = re.captures_iter(cmd);
-        let start_lsn = caps
-            .next()
-            .map(|cap| cap[1].parse::<Lsn>())
-            .context("failed to parse start LSN from START_REPLICATION command")??;
-        Ok(SafekeeperPostgresCommand::StartReplication { start_lsn })
+        let re = Regex::new(
+            r"START_REPLICATION(?: PHYSICAL)? ([[:xdigit:]]+/[[:xdigit:]]+)(?: TIMELINE (\d+))?(?: UNTIL ([[:xdigit:]]+/[[:xdigit:]]+))?",
+        )
+        .unwrap();
+        let caps = re
+            .captures(cmd)
+            .context("failed to parse start LSN from START_REPLICATION command")?;
+        let start_lsn = caps[1].parse::<Lsn>()?;
+        let timeline = caps.get(2).map(|m| m.as_str().parse::<u32>()).transpose()?;
+        let until_lsn = caps.get(3).map(|m| m.as_str().parse::<Lsn>()).transpose()?;
+        if let Some(until_lsn) = until_lsn {
+            if until_lsn < start_lsn {
+                anyhow::bail!("UNTIL LSN {until_lsn} is before start LSN {start_lsn}");
+            }
+        }
+        Ok(SafekeeperPostgresCommand::StartReplication {
+            start_lsn,
+            until_lsn,
+            timeline,
+        })
     } else if cmd.starts_with("IDENTIFY_SYSTEM") {
         Ok(SafekeeperPostgresCommand::IdentifySystem)
+    } else if cmd.starts_with("TIMELINE_STATUS") {
+        Ok(SafekeeperPostgresCommand::TimelineStatus)
+    } else if cmd.starts_with("TIMELINE_DELETE") {
+        let only_local = match cmd["TIMELINE_DELETE".len()..].trim().trim_end_matches(';') {
+            "" => false,
+            "LOCAL" => true,
+            arg => anyhow::bail!("invalid TIMELINE_DELETE argument {arg:?}"),
+        };
+        Ok(SafekeeperPostgresCommand::TimelineDelete { only_local })
+    } else if cmd.starts_with("TIMELINE_TERM_BUMP") {
+        let term = match cmd["TIMELINE_TERM_BUMP".len()..]
+            .trim()
+            .trim_end_matches(';')
+        {
+            "" => None,
+            arg => Some(
+                arg.parse()
+                    .with_context(|| format!("invalid TIMELINE_TERM_BUMP term {arg:?}"))?,
+            ),
+        };
+        Ok(SafekeeperPostgresCommand::TimelineTermBump { term })
+    } else if cmd.starts_with("TIMELINE_EXPORT") {
+        Ok(SafekeeperPostgresCommand::TimelineExport)
+    } else if cmd.starts_with("TIMELINE_IMPORT") {
+        Ok(SafekeeperPostgresCommand::TimelineImport)
+    } else if cmd.starts_with("DEBUG_DUMP") {
+        Ok(SafekeeperPostgresCommand::DebugDump)
+    } else if cmd.starts_with("CHECK_WAL") {
+        Ok(SafekeeperPostgresCommand::CheckWal)
+    } else if cmd.starts_with("GC_WAL") {
+        Ok(SafekeeperPostgresCommand::GcWal)
+    } else if cmd.starts_with("LIST_CONNECTIONS") {
+        Ok(SafekeeperPostgresCommand::ListConnections)
+    } else if let Some(set) = parse_set_cmd(cmd) {
+        set
+    } else if cmd.to_ascii_lowercase().starts_with("show ") {
+        let guc = cmd[5..].trim().trim_end_matches(';').trim_matches('"');
+        Ok(SafekeeperPostgresCommand::Show {
+            guc: guc.to_ascii_lowercase(),
+        })
     } else if cmd.starts_with("JSON_CTRL") {
         let cmd = cmd.strip_prefix("JSON_CTRL").context("invalid prefix")?;
         Ok(SafekeeperPostgresCommand::JSONCtrl {
@@ -67,6 +161,63 @@ fn parse_cmd(cmd: &str) -> anyhow::Result<SafekeeperPostgresCommand> {
     }
 }
 
+/// Session parameters standard clients (psycopg2, JDBC, psql) set on connect.
+/// They don't affect anything here, so SET and RESET of them succeed without
+/// effect.
+const SETTABLE_GUCS: &[&str] = &[
+    "application_name",
+    "client_encoding",
+    "client_min_messages",
+    "datestyle",
+    "extra_float_digits",
+    "intervalstyle",
+    "search_path",
+    "statement_timeout",
+    "timezone",
+];
+
+/// Parse `SET [SESSION | LOCAL] guc {TO | =} value` and `RESET {guc | ALL}`,
+/// None if `cmd` is neither.
+fn parse_set_cmd(cmd: &str) -> Option<anyhow::Result<SafekeeperPostgresCommand>> {
+    let re = Regex::new(
+        r"(?i)^\s*(SET|RESET)\s+(?:(?:SESSION|LOCAL)\s+)?([[:word:].]+)\s*(?:(?:TO|=)\s*(.*?))?\s*;?\s*$",
+    )
+    .unwrap();
+    let caps = re.captures(cmd)?;
+    let reset = caps[1].eq_ignore_ascii_case("RESET");
+    let guc = caps[2].to_ascii_lowercase();
+    let has_value = caps.get(3).map_or(false, |m| !m.as_str().is_empty());
+    Some(if reset && has_value {
+        Err(anyhow::anyhow!("RESET doesn't take a value: {cmd}"))
+    } else if !reset && !has_value {
+        Err(anyhow::anyhow!("SET requires a value: {cmd}"))
+    } else if reset && guc == "all" {
+        Ok(SafekeeperPostgresCommand::Set { guc: None, reset })
+    } else if !SETTABLE_GUCS.contains(&guc.as_str()) {
+        Err(anyhow::anyhow!(
+            "unrecognized configuration parameter \"{guc}\""
+        ))
+    } else {
+        Ok(SafekeeperPostgresCommand::Set {
+            guc: Some(guc),
+            reset,
+        })
+    })
+}
+
+/// Check the TIMELINE clause of START_REPLICATION. All WAL on safekeepers
+/// belongs to PG_TLI, which has no ancestors, so there is never a timeline
+/// switch to stream through; any other timeline is unknown to us, and Postgres
+/// walsender errors out in the same way for timelines not in its history.
+fn check_replication_timeline(timeline: Option<u32>) -> anyhow::Result<()> {
+    match timeline {
+        Some(tli) if tli != PG_TLI => {
+            anyhow::bail!("requested timeline {tli} is not in this server's history")
+        }
+        _ => Ok(()),
+    }
+}
+
 impl postgres_backend::Handler for SafekeeperPostgresHandler {
     // tenant_id and timeline_id are passed in connection string params
     fn startup(
@@ -75,24 +226,23 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
         sm: &FeStartupPacket,
     ) -> Result<(), QueryError> {
         if let FeStartupPacket::StartupMessage { params, .. } = sm {
-            if let Some(options) = params.options_raw() {
-                for opt in options {
-                    // FIXME `ztenantid` and `ztimelineid` left for compatibility during deploy,
-                    // remove these after the PR gets deployed:
-                    // https://github.com/neondatabase/neon/pull/2433#discussion_r970005064
-                    match opt.split_once('=') {
-                        Some(("ztenantid", value)) | Some(("tenant_id", value)) => {
-                            self.tenant_id = Some(value.parse().with_context(|| {
-                                format!("Failed to parse {value} as tenant id")
-                            })?);
-                        }
-                        Some(("ztimelineid", value)) | Some(("timeline_id", value)) => {
-                            self.timeline_id = Some(value.parse().with_context(|| {
-                                format!("Failed to parse {value} as timeline id")
-                            })?);
-                        }
-                        _ => continue,
-                    }
+            let options = params.options();
+            if let Some(tenant_id) = options.tenant_id()? {
+                self.tenant_id = Some(tenant_id);
+            }
+            if let Some(timeline_id) = options.timeline_id()? {
+                self.timeline_id = Some(timeline_id);
+            }
+            if let Some(recovery_term) = options.get_parsed("recovery_term")? {
+                self.recovery_term = Some(recovery_term);
+            }
+            match options.get("compression") {
+                Some("zstd") => self.compress_wal = true,
+                Some("none") | None => {}
+                Some(value) => {
+                    return Err(QueryError::Other(anyhow::anyhow!(
+                        "Unsupported WAL compression {value}"
+                    )))
                 }
             }
 
@@ -137,20 +287,38 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
         Ok(())
     }
 
+    fn is_shutdown_requested(&self) -> bool {
+        drain::is_draining()
+    }
+
+    fn get_md5_secret(
+        &mut self,
+        _pgb: &mut PostgresBackend,
+        user: &str,
+    ) -> Result<Option<String>, QueryError> {
+        // only called when auth_type is Md5, which requires password_auth
+        let password_auth = self.conf.password_auth.as_ref().unwrap();
+        Ok(password_auth.secret(user).map(str::to_owned))
+    }
+
     fn process_query(
         &mut self,
         pgb: &mut PostgresBackend,
         query_string: &str,
+        cancel: &CancellationToken,
     ) -> Result<(), QueryError> {
-        if query_string
-            .to_ascii_lowercase()
-            .starts_with("set datestyle to ")
-        {
-            // important for debug because psycopg2 executes "SET datestyle TO 'ISO'" on connect
-            pgb.write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        let cmd = parse_cmd(query_string)?;
+        if let SafekeeperPostgresCommand::Set { ref guc, reset } = cmd {
+            // doesn't need a timeline, clients send these right after connect
+            debug!(
+                "accepted {} of {:?}",
+                if reset { "RESET" } else { "SET" },
+                guc
+            );
+            let tag: &[u8] = if reset { b"RESET" } else { b"SET" };
+            pgb.write_message(&BeMessage::CommandComplete(tag))?;
             return Ok(());
         }
-        let cmd = parse_cmd(query_string)?;
 
         info!(
             "got query {:?} in timeline {:?}",
@@ -163,12 +331,43 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
         self.ttid = TenantTimelineId::new(tenant_id, timeline_id);
 
         let res = match cmd {
-            SafekeeperPostgresCommand::StartWalPush => ReceiveWalConn::new(pgb).run(self),
-            SafekeeperPostgresCommand::StartReplication { start_lsn } => {
-                ReplicationConn::new(pgb).run(self, pgb, start_lsn)
+            SafekeeperPostgresCommand::StartWalPush => {
+                match quota::check_wal_push(&self.conf, &self.ttid) {
+                    Ok(()) => ReceiveWalConn::new(pgb).run(self, cancel),
+                    Err(e) => Err(e.into()),
+                }
             }
+            SafekeeperPostgresCommand::StartReplication {
+                start_lsn,
+                until_lsn,
+                timeline,
+            } => match check_replication_timeline(timeline) {
+                Ok(()) => ReplicationConn::new(pgb).run(self, pgb, start_lsn, until_lsn, cancel),
+                Err(e) => Err(e.into()),
+            },
             SafekeeperPostgresCommand::IdentifySystem => self.handle_identify_system(pgb),
-            SafekeeperPostgresCommand::JSONCtrl { ref cmd } => handle_json_ctrl(self, pgb, cmd),
+            SafekeeperPostgresCommand::TimelineStatus => self.handle_timeline_status(pgb),
+            SafekeeperPostgresCommand::TimelineDelete { only_local } => {
+                self.handle_timeline_delete(only_local, pgb)
+            }
+            SafekeeperPostgresCommand::TimelineTermBump { term } => {
+                self.handle_timeline_term_bump(term, pgb)
+            }
+            SafekeeperPostgresCommand::TimelineExport => {
+                snapshot::handle_export(&self.conf, self.ttid, pgb)
+            }
+            SafekeeperPostgresCommand::TimelineImport => {
+                snapshot::handle_import(&self.conf, self.ttid, pgb)
+            }
+            SafekeeperPostgresCommand::DebugDump => self.handle_debug_dump(pgb),
+            SafekeeperPostgresCommand::CheckWal => self.handle_check_wal(pgb),
+            SafekeeperPostgresCommand::GcWal => self.handle_gc_wal(pgb),
+            SafekeeperPostgresCommand::ListConnections => self.handle_list_connections(pgb),
+            SafekeeperPostgresCommand::Set { .. } => unreachable!("handled above"),
+            SafekeeperPostgresCommand::Show { ref guc } => self.handle_show(guc, pgb),
+            SafekeeperPostgresCommand::JSONCtrl { ref cmd } => {
+                handle_json_ctrl(self, pgb, cmd, cancel)
+            }
         };
 
         match res {
@@ -177,6 +376,7 @@ impl postgres_backend::Handler for SafekeeperPostgresHandler {
                 info!("Timeline {tenant_id}/{timeline_id} query failed with connection error: {connection_error}");
                 Err(QueryError::Disconnected(connection_error))
             }
+            Err(QueryError::Cancelled) => Err(QueryError::Cancelled),
             Err(QueryError::Other(e)) => Err(QueryError::Other(e.context(format!(
                 "Failed to process query for timeline {}",
                 self.ttid
@@ -193,6 +393,8 @@ impl SafekeeperPostgresHandler {
             tenant_id: None,
             timeline_id: None,
             ttid: TenantTimelineId::empty(),
+            compress_wal: false,
+            recovery_term: None,
             claims: None,
         }
     }
@@ -219,6 +421,12 @@ impl SafekeeperPostgresHandler {
     ///
     fn handle_identify_system(&mut self, pgb: &mut PostgresBackend) -> Result<(), QueryError> {
         let tli = GlobalTimelines::get(self.ttid)?;
+        if !tli.get_state().1.is_initialized() {
+            return Err(QueryError::Other(anyhow::anyhow!(
+                "timeline {} is not initialized yet",
+                self.ttid
+            )));
+        }
 
         let lsn = if self.is_walproposer_recovery() {
             // walproposer should get all local WAL until flush_lsn
@@ -235,46 +443,487 @@ impl SafekeeperPostgresHandler {
         let tli_bytes = tli.as_bytes();
         let sysid_bytes = sysid.as_bytes();
 
+        // Confirm WAL compression with an extra column, so that clients which
+        // asked for it can tell whether the safekeeper is able to compress.
+        let mut columns = vec![
+            RowDescriptor::text_col(b"systemid"),
+            RowDescriptor::int4_col(b"timeline"),
+            RowDescriptor::lsn_col(b"xlogpos"),
+            RowDescriptor::text_col(b"dbname"),
+        ];
+        let mut values = vec![Some(sysid_bytes), Some(tli_bytes), Some(lsn_bytes), None];
+        if self.compress_wal {
+            columns.push(RowDescriptor::text_col(b"compression"));
+            values.push(Some(b"zstd".as_slice()));
+        }
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&columns))?
+            .write_message_noflush(&BeMessage::DataRow(&values))?
+            .write_message(&BeMessage::CommandComplete(b"IDENTIFY_SYSTEM"))?;
+        Ok(())
+    }
+
+    ///
+    /// Handle TIMELINE_STATUS command: the subset of the HTTP timeline status
+    /// interesting to the clients of the timeline.
+    ///
+    fn handle_timeline_status(&mut self, pgb: &mut PostgresBackend) -> Result<(), QueryError> {
+        let tli = GlobalTimelines::get(self.ttid)?;
+        let (inmem, state) = tli.get_state();
+        let flush_lsn = tli.get_flush_lsn();
+        let peers = tli.get_peers(&self.conf);
+
+        let epoch = state.acceptor_state.get_epoch(flush_lsn);
+        let epoch_start_lsn = state.acceptor_state.get_epoch_start_lsn(flush_lsn);
+        let term_history = serde_json::to_string(
+            &state
+                .acceptor_state
+                .term_history
+                .0
+                .iter()
+                .map(|e| serde_json::json!({"term": e.term, "lsn": e.lsn.to_string()}))
+                .collect::<Vec<_>>(),
+        )
+        .map_err(anyhow::Error::from)?;
+        let backpressure = tli.get_backpressure_state(&self.conf);
+        let throttling = backpressure.throttling.to_string();
+        let (consumers, replication_horizon_lsn) = tli.get_consumers();
+        let consumers = serde_json::to_string(
+            &consumers
+                .iter()
+                .map(|c| {
+                    serde_json::json!({
+                        "kind": c.kind,
+                        "start_lsn": c.start_lsn.to_string(),
+                        "flush_lsn": c.standby_reply.map(|r| r.flush_lsn.to_string()),
+                        "apply_lsn": c.standby_reply.map(|r| r.apply_lsn.to_string()),
+                        "horizon_lsn": c.horizon_lsn().map(|lsn| lsn.to_string()),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        )
+        .map_err(anyhow::Error::from)?;
+        let (last_record_time, record_lag) = tli.get_record_time();
+        let lag_seconds = record_lag.map(|lag| lag.to_string());
+
+        let row = cell::encode_row(
+            &[
+                Cell::Int8(state.acceptor_state.term as i64),
+                Cell::Lsn(flush_lsn.0),
+                Cell::Lsn(inmem.commit_lsn.0),
+                Cell::Lsn(inmem.backup_lsn.0),
+                Cell::Lsn(inmem.remote_consistent_lsn.0),
+                Cell::Int4(peers.len() as i32),
+                Cell::Int8(backpressure.unflushed_wal_bytes as i64),
+                Cell::Int8(backpressure.unbacked_wal_bytes as i64),
+                Cell::Text(&throttling),
+                replication_horizon_lsn.map_or(Cell::Null, |lsn| Cell::Lsn(lsn.0)),
+                Cell::Text(&consumers),
+                Cell::Int8(epoch as i64),
+                Cell::Lsn(epoch_start_lsn.0),
+                Cell::Text(&term_history),
+                last_record_time.map_or(Cell::Null, |ts| Cell::TimestampTz(from_pg_timestamp(ts))),
+                lag_seconds.as_deref().map_or(Cell::Null, Cell::Text),
+            ],
+            &[],
+        );
+
         pgb.write_message_noflush(&BeMessage::RowDescription(&[
-            RowDescriptor {
-                name: b"systemid",
-                typoid: TEXT_OID,
-                typlen: -1,
-                ..Default::default()
-            },
-            RowDescriptor {
-                name: b"timeline",
-                typoid: INT4_OID,
-                typlen: 4,
-                ..Default::default()
-            },
-            RowDescriptor {
-                name: b"xlogpos",
-                typoid: TEXT_OID,
-                typlen: -1,
-                ..Default::default()
-            },
-            RowDescriptor {
-                name: b"dbname",
-                typoid: TEXT_OID,
-                typlen: -1,
-                ..Default::default()
-            },
+            RowDescriptor::int8_col(b"term"),
+            RowDescriptor::lsn_col(b"flush_lsn"),
+            RowDescriptor::lsn_col(b"commit_lsn"),
+            RowDescriptor::lsn_col(b"backup_lsn"),
+            RowDescriptor::lsn_col(b"remote_consistent_lsn"),
+            RowDescriptor::int4_col(b"peer_count"),
+            RowDescriptor::int8_col(b"unflushed_wal_bytes"),
+            RowDescriptor::int8_col(b"unbacked_wal_bytes"),
+            RowDescriptor::text_col(b"throttling"),
+            RowDescriptor::lsn_col(b"replication_horizon_lsn"),
+            RowDescriptor::text_col(b"consumers"),
+            RowDescriptor::int8_col(b"epoch"),
+            RowDescriptor::lsn_col(b"epoch_start_lsn"),
+            RowDescriptor::text_col(b"term_history"),
+            RowDescriptor::timestamptz_col(b"last_record_time"),
+            RowDescriptor::text_col(b"lag_seconds"),
+        ]))?
+        .write_message_noflush(&BeMessage::DataRowBytes(&row))?
+        .write_message(&BeMessage::CommandComplete(b"TIMELINE_STATUS"))?;
+        Ok(())
+    }
+
+    ///
+    /// Delete the timeline together with its WAL in remote storage, or only
+    /// the local data with LOCAL.
+    ///
+    fn handle_timeline_delete(
+        &mut self,
+        only_local: bool,
+        pgb: &mut PostgresBackend,
+    ) -> Result<(), QueryError> {
+        let result =
+            crate::BACKGROUND_RUNTIME.block_on(GlobalTimelines::delete(&self.ttid, only_local))?;
+
+        let dir_existed = result.dir_existed.to_string();
+        let was_active = result.was_active.to_string();
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor::text_col(b"dir_existed"),
+            RowDescriptor::text_col(b"was_active"),
+        ]))?
+        .write_message_noflush(&BeMessage::DataRow(&[
+            Some(dir_existed.as_bytes()),
+            Some(was_active.as_bytes()),
+        ]))?
+        .write_message(&BeMessage::CommandComplete(b"TIMELINE_DELETE"))?;
+        Ok(())
+    }
+
+    ///
+    /// Fence the timeline by bumping its term, so that walproposers of older
+    /// terms can't append anymore.
+    ///
+    fn handle_timeline_term_bump(
+        &mut self,
+        term: Option<Term>,
+        pgb: &mut PostgresBackend,
+    ) -> Result<(), QueryError> {
+        let tli = GlobalTimelines::get(self.ttid)?;
+        let resp = tli.term_bump(term)?;
+
+        let previous_term = resp.previous_term.to_string();
+        let current_term = resp.current_term.to_string();
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor::text_col(b"previous_term"),
+            RowDescriptor::text_col(b"current_term"),
         ]))?
         .write_message_noflush(&BeMessage::DataRow(&[
-            Some(sysid_bytes),
-            Some(tli_bytes),
-            Some(lsn_bytes),
-            None,
+            Some(previous_term.as_bytes()),
+            Some(current_term.as_bytes()),
         ]))?
-        .write_message(&BeMessage::CommandComplete(b"IDENTIFY_SYSTEM"))?;
+        .write_message(&BeMessage::CommandComplete(b"TIMELINE_TERM_BUMP"))?;
         Ok(())
     }
 
+    ///
+    /// Dump state of all timelines of the tenant as JSON.
+    ///
+    fn handle_debug_dump(&mut self, pgb: &mut PostgresBackend) -> Result<(), QueryError> {
+        let args = debug_dump::Args {
+            tenant_id: Some(self.ttid.tenant_id),
+        };
+        let dump = debug_dump::build(&self.conf, args)?;
+        let dump = serde_json::to_vec(&dump).context("failed to serialize debug dump")?;
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[RowDescriptor::text_col(
+            b"json",
+        )]))?
+        .write_message_noflush(&BeMessage::DataRow(&[Some(&dump)]))?
+        .write_message(&BeMessage::CommandComplete(b"DEBUG_DUMP"))?;
+        Ok(())
+    }
+
+    ///
+    /// Validate local WAL of the timeline without modifying it, reporting
+    /// the first bad LSN if any.
+    ///
+    fn handle_check_wal(&mut self, pgb: &mut PostgresBackend) -> Result<(), QueryError> {
+        let result = wal_check::check_timeline_wal(&self.conf, self.ttid)?;
+
+        let start_lsn = result.start_lsn.to_string();
+        let end_lsn = result.end_lsn.to_string();
+        let records = result.records.to_string();
+        let bad_lsn = result.bad_lsn.map(|lsn| lsn.to_string());
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor::text_col(b"start_lsn"),
+            RowDescriptor::text_col(b"end_lsn"),
+            RowDescriptor::text_col(b"records"),
+            RowDescriptor::text_col(b"bad_lsn"),
+            RowDescriptor::text_col(b"error"),
+        ]))?
+        .write_message_noflush(&BeMessage::DataRow(&[
+            Some(start_lsn.as_bytes()),
+            Some(end_lsn.as_bytes()),
+            Some(records.as_bytes()),
+            bad_lsn.as_ref().map(|lsn| lsn.as_bytes()),
+            result.error.as_ref().map(|err| err.as_bytes()),
+        ]))?
+        .write_message(&BeMessage::CommandComplete(b"CHECK_WAL"))?;
+        Ok(())
+    }
+
+    ///
+    /// Remove WAL of the timeline which is not needed anymore right away,
+    /// reporting the removed segments and what holds the remaining WAL.
+    ///
+    fn handle_gc_wal(&mut self, pgb: &mut PostgresBackend) -> Result<(), QueryError> {
+        let tli = GlobalTimelines::get(self.ttid)?;
+        let policy = remove_wal::get_policy(&self.conf, &self.ttid.tenant_id);
+        let removal = tli.remove_old_wal(self.conf.wal_backup_enabled, &policy)?;
+        info!(
+            "removed {} WAL segments, held by {}",
+            removal.removed_segments(),
+            removal.reason
+        );
+
+        let wal_seg_size = tli.get_wal_seg_size();
+        let removed_segments = removal.removed_segments().to_string();
+        let (first_removed, last_removed) = if removal.removed_segments() > 0 {
+            (
+                Some(XLogFileName(PG_TLI, removal.from_segno, wal_seg_size)),
+                Some(XLogFileName(PG_TLI, removal.to_segno - 1, wal_seg_size)),
+            )
+        } else {
+            (None, None)
+        };
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor::text_col(b"removed_segments"),
+            RowDescriptor::text_col(b"first_removed"),
+            RowDescriptor::text_col(b"last_removed"),
+            RowDescriptor::text_col(b"held_by"),
+        ]))?
+        .write_message_noflush(&BeMessage::DataRow(&[
+            Some(removed_segments.as_bytes()),
+            first_removed.as_ref().map(|name| name.as_bytes()),
+            last_removed.as_ref().map(|name| name.as_bytes()),
+            Some(removal.reason.as_bytes()),
+        ]))?
+        .write_message(&BeMessage::CommandComplete(b"GC_WAL"))?;
+        Ok(())
+    }
+
+    ///
+    /// List active connections of the timeline, one row per connection.
+    ///
+    fn handle_list_connections(&mut self, pgb: &mut PostgresBackend) -> Result<(), QueryError> {
+        let conns = connections::list(&self.ttid);
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor::text_col(b"kind"),
+            RowDescriptor::text_col(b"appname"),
+            RowDescriptor::text_col(b"remote_addr"),
+            RowDescriptor::text_col(b"options"),
+            RowDescriptor::text_col(b"connected_at"),
+            RowDescriptor::text_col(b"start_lsn"),
+            RowDescriptor::text_col(b"lsn"),
+            RowDescriptor::text_col(b"bytes"),
+        ]))?;
+        for conn in &conns {
+            let options = conn
+                .options
+                .iter()
+                .map(|(k, v)| format!("{k}={v}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let connected_at = conn.connected_at.to_string();
+            let bytes = conn.bytes.to_string();
+            pgb.write_message_noflush(&BeMessage::DataRow(&[
+                Some(conn.kind.as_str().as_bytes()),
+                conn.appname.as_ref().map(|name| name.as_bytes()),
+                Some(conn.remote_addr.as_bytes()),
+                Some(options.as_bytes()),
+                Some(connected_at.as_bytes()),
+                Some(conn.start_lsn.as_bytes()),
+                Some(conn.lsn.as_bytes()),
+                Some(bytes.as_bytes()),
+            ]))?;
+        }
+        pgb.write_message(&BeMessage::CommandComplete(b"LIST_CONNECTIONS"))?;
+        Ok(())
+    }
+
+    ///
+    /// Handle SHOW command for the settings which replication tools like
+    /// pg_receivewal and pg_basebackup query. Unknown settings are reported
+    /// as empty rather than failing the connection.
+    ///
+    fn handle_show(&mut self, guc: &str, pgb: &mut PostgresBackend) -> Result<(), QueryError> {
+        let tli = GlobalTimelines::get(self.ttid)?;
+        let pg_version = tli.get_state().1.server.pg_version;
+
+        let value = match guc {
+            // pg_receivewal checks that it can write files with these permissions
+            "data_directory_mode" => "0700".to_string(),
+            "wal_segment_size" => format!("{}MB", tli.get_wal_seg_size() / (1024 * 1024)),
+            // e.g. 140005 is 14.5
+            "server_version" => format!("{}.{}", pg_version / 10000, pg_version % 10000),
+            "server_version_num" => pg_version.to_string(),
+            "synchronous_commit" => "on".to_string(),
+            "integer_datetimes" => "on".to_string(),
+            _ => String::new(),
+        };
+
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[RowDescriptor::text_col(
+            guc.as_bytes(),
+        )]))?
+        .write_message_noflush(&BeMessage::DataRow(&[Some(value.as_bytes())]))?
+        .write_message(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        Ok(())
+    }
+
+    /// Connection options negotiated in the startup packet, as reported by
+    /// LIST_CONNECTIONS.
+    pub fn negotiated_options(&self) -> BTreeMap<String, String> {
+        let mut options = BTreeMap::new();
+        if self.compress_wal {
+            options.insert("compression".to_owned(), "zstd".to_owned());
+        }
+        if let Some(term) = self.recovery_term {
+            options.insert("recovery_term".to_owned(), term.to_string());
+        }
+        options
+    }
+
     /// Returns true if current connection is a replication connection, originating
     /// from a walproposer recovery function. This connection gets a special handling:
     /// safekeeper must stream all local WAL till the flush_lsn, whether committed or not.
     pub fn is_walproposer_recovery(&self) -> bool {
         self.appname == Some("wal_proposer_recovery".to_string())
     }
+
+    /// Peer safekeeper fetching WAL it misses, see `recovery`.
+    pub fn is_peer_recovery(&self) -> bool {
+        self.appname == Some(crate::recovery::RECOVERY_APPNAME.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timeline_delete() {
+        assert!(matches!(
+            parse_cmd("TIMELINE_DELETE").unwrap(),
+            SafekeeperPostgresCommand::TimelineDelete { only_local: false }
+        ));
+        assert!(matches!(
+            parse_cmd("TIMELINE_DELETE LOCAL;").unwrap(),
+            SafekeeperPostgresCommand::TimelineDelete { only_local: true }
+        ));
+        assert!(parse_cmd("TIMELINE_DELETE EVERYTHING").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeline_snapshot() {
+        assert!(matches!(
+            parse_cmd("TIMELINE_EXPORT").unwrap(),
+            SafekeeperPostgresCommand::TimelineExport
+        ));
+        assert!(matches!(
+            parse_cmd("TIMELINE_IMPORT;").unwrap(),
+            SafekeeperPostgresCommand::TimelineImport
+        ));
+    }
+
+    #[test]
+    fn test_parse_set() {
+        for cmd in [
+            "SET datestyle TO 'ISO'",
+            "SET extra_float_digits = 3",
+            "set client_encoding to 'UTF8';",
+            "SET SESSION application_name = 'psql'",
+            "RESET statement_timeout",
+        ] {
+            assert!(
+                matches!(
+                    parse_cmd(cmd).unwrap(),
+                    SafekeeperPostgresCommand::Set { guc: Some(_), .. }
+                ),
+                "{cmd}"
+            );
+        }
+        assert!(matches!(
+            parse_cmd("RESET ALL").unwrap(),
+            SafekeeperPostgresCommand::Set {
+                guc: None,
+                reset: true
+            }
+        ));
+        assert!(parse_cmd("SET work_mem = '1GB'").is_err());
+        assert!(parse_cmd("SET datestyle").is_err());
+        assert!(parse_cmd("RESET datestyle TO 'ISO'").is_err());
+    }
+
+    #[test]
+    fn test_parse_timeline_term_bump() {
+        assert!(matches!(
+            parse_cmd("TIMELINE_TERM_BUMP").unwrap(),
+            SafekeeperPostgresCommand::TimelineTermBump { term: None }
+        ));
+        assert!(matches!(
+            parse_cmd("TIMELINE_TERM_BUMP 42;").unwrap(),
+            SafekeeperPostgresCommand::TimelineTermBump { term: Some(42) }
+        ));
+        assert!(parse_cmd("TIMELINE_TERM_BUMP next").is_err());
+    }
+
+    #[test]
+    fn test_parse_debug_dump() {
+        assert!(matches!(
+            parse_cmd("DEBUG_DUMP").unwrap(),
+            SafekeeperPostgresCommand::DebugDump
+        ));
+    }
+
+    #[test]
+    fn test_parse_check_wal() {
+        assert!(matches!(
+            parse_cmd("CHECK_WAL;").unwrap(),
+            SafekeeperPostgresCommand::CheckWal
+        ));
+    }
+
+    #[test]
+    fn test_parse_list_connections() {
+        assert!(matches!(
+            parse_cmd("LIST_CONNECTIONS").unwrap(),
+            SafekeeperPostgresCommand::ListConnections
+        ));
+    }
+
+    #[test]
+    fn test_parse_gc_wal() {
+        assert!(matches!(
+            parse_cmd("GC_WAL;").unwrap(),
+            SafekeeperPostgresCommand::GcWal
+        ));
+    }
+
+    #[test]
+    fn test_parse_start_replication() {
+        match parse_cmd("START_REPLICATION PHYSICAL 0/16B9188").unwrap() {
+            SafekeeperPostgresCommand::StartReplication {
+                start_lsn,
+                until_lsn,
+                timeline,
+            } => {
+                assert_eq!(start_lsn, Lsn(0x16B9188));
+                assert_eq!(until_lsn, None);
+                assert_eq!(timeline, None);
+            }
+            _ => panic!("unexpected command"),
+        }
+
+        match parse_cmd("START_REPLICATION 1/0 TIMELINE 1 UNTIL 1/2000").unwrap() {
+            SafekeeperPostgresCommand::StartReplication {
+                start_lsn,
+                until_lsn,
+                timeline,
+            } => {
+                assert_eq!(start_lsn, Lsn(0x1_0000_0000));
+                assert_eq!(until_lsn, Some(Lsn(0x1_0000_2000)));
+                assert_eq!(timeline, Some(1));
+            }
+            _ => panic!("unexpected command"),
+        }
+
+        assert!(parse_cmd("START_REPLICATION 1/0 UNTIL 0/2000").is_err());
+        assert!(parse_cmd("START_REPLICATION").is_err());
+    }
+
+    #[test]
+    fn test_check_replication_timeline() {
+        assert!(check_replication_timeline(None).is_ok());
+        assert!(check_replication_timeline(Some(PG_TLI)).is_ok());
+        assert!(check_replication_timeline(Some(PG_TLI + 1)).is_err());
+    }
 }
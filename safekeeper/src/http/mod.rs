@@ -1,4 +1,87 @@
 pub mod routes;
+
+use std::convert::Infallible;
+use std::future::ready;
+use std::io;
+use std::net::TcpListener;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures::{stream, StreamExt};
+use hyper::server::accept::{self, Accept};
+use hyper::server::conn::{AddrIncoming, AddrStream};
+use routerify::RequestServiceBuilder;
+use tokio_rustls::server::TlsStream;
+use tracing::*;
+use utils::http::{error::ApiError, RouterBuilder};
+
+use crate::tls::ServerTls;
+
 pub use routes::make_router;
 
 pub use safekeeper_api::models;
+
+/// Connections in TLS handshake at once.
+const MAX_HANDSHAKES: usize = 64;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Same as `endpoint::serve_thread_main`, but accepts only TLS connections
+/// on the listener. Each handshake uses the current config of `tls`, so that
+/// reloaded certificates are picked up.
+pub fn serve_thread_main_tls(
+    router_builder: RouterBuilder<hyper::Body, ApiError>,
+    listener: TcpListener,
+    tls: Arc<ServerTls>,
+) -> anyhow::Result<()> {
+    info!("Starting an HTTPS endpoint at {}", listener.local_addr()?);
+
+    let router = router_builder.build().map_err(|err| anyhow!(err))?;
+    let mut service_builder = RequestServiceBuilder::new(router).map_err(|err| anyhow!(err))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    runtime.block_on(async move {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let mut addr_incoming = AddrIncoming::from_listener(listener)?;
+        let _ = addr_incoming.set_nodelay(true);
+
+        let tls_incoming = stream::poll_fn(move |cx| Pin::new(&mut addr_incoming).poll_accept(cx))
+            .map(move |conn| {
+                let acceptor = tokio_rustls::TlsAcceptor::from(tls.server_config());
+                async move {
+                    let conn = conn?;
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(conn)).await {
+                        Ok(res) => res,
+                        Err(_) => Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "TLS handshake timed out",
+                        )),
+                    }
+                }
+            })
+            .buffer_unordered(MAX_HANDSHAKES)
+            .filter(|conn| {
+                if let Err(err) = conn {
+                    warn!("failed to accept TLS connection: {err:?}");
+                    ready(false)
+                } else {
+                    ready(true)
+                }
+            });
+
+        hyper::Server::builder(accept::from_stream(tls_incoming))
+            .serve(hyper::service::make_service_fn(
+                move |stream: &TlsStream<AddrStream>| {
+                    let service = service_builder.build(stream.get_ref().0.remote_addr());
+                    async move { Ok::<_, Infallible>(service) }
+                },
+            ))
+            .await?;
+        Ok(())
+    })
+}
@@ -12,11 +12,25 @@ use std::sync::Arc;
 use storage_broker::proto::SafekeeperTimelineInfo;
 use storage_broker::proto::TenantTimelineId as ProtoTenantTimelineId;
 use tokio::task::JoinError;
+use tracing::info;
 
+use crate::connections;
+use crate::copy_timeline;
+use crate::debug_dump;
+use crate::drain;
+use crate::pull_timeline;
+use crate::relocate;
+use crate::remove_wal;
+use crate::safekeeper::Configuration;
+use crate::safekeeper::RetentionPin;
 use crate::safekeeper::ServerInfo;
 use crate::safekeeper::Term;
+use crate::safekeeper::TimelineCreateParams;
 
 use crate::timelines_global_map::TimelineDeleteForceResult;
+use crate::wal_backup;
+use crate::wal_backup_scrub;
+use crate::wal_check;
 use crate::GlobalTimelines;
 use crate::SafeKeeperConf;
 use utils::{
@@ -25,14 +39,17 @@ use utils::{
         endpoint::{self, auth_middleware, check_permission_with},
         error::ApiError,
         json::{json_request, json_response},
-        request::{ensure_no_body, parse_request_param},
+        request::{ensure_no_body, parse_query_param, parse_request_param},
         RequestExt, RouterBuilder,
     },
     id::{NodeId, TenantId, TenantTimelineId, TimelineId},
     lsn::Lsn,
 };
 
-use super::models::TimelineCreateRequest;
+use super::models::{
+    ConfigureFailpointsRequest, TimelineCreateRequest, TimelineRetentionPinRequest,
+    TimelineTermBumpRequest, TimelineTermBumpResponse,
+};
 
 #[derive(Debug, Serialize)]
 struct SafekeeperStatus {
@@ -77,6 +94,9 @@ struct TermSwitchApiEntry {
 struct AcceptorStateStatus {
     term: Term,
     epoch: Term,
+    /// Where the epoch term starts in the local WAL.
+    #[serde(serialize_with = "display_serialize")]
+    epoch_start_lsn: Lsn,
     term_history: Vec<TermSwitchApiEntry>,
 }
 
@@ -103,6 +123,27 @@ struct TimelineStatus {
     peer_horizon_lsn: Lsn,
     #[serde(serialize_with = "display_serialize")]
     remote_consistent_lsn: Lsn,
+    configuration: Configuration,
+    retention_pins: Vec<RetentionPinStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct RetentionPinStatus {
+    name: String,
+    #[serde(serialize_with = "display_serialize")]
+    lsn: Lsn,
+    /// Unix timestamp in seconds.
+    expires_at: Option<u64>,
+}
+
+impl From<RetentionPin> for RetentionPinStatus {
+    fn from(pin: RetentionPin) -> Self {
+        RetentionPinStatus {
+            name: pin.name,
+            lsn: pin.lsn,
+            expires_at: pin.expires_at,
+        }
+    }
 }
 
 fn check_permission(request: &Request<Body>, tenant_id: Option<TenantId>) -> Result<(), ApiError> {
@@ -127,8 +168,14 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
         .map_err(ApiError::InternalServerError)?;
     let (inmem, state) = tli.get_state();
     let flush_lsn = tli.get_flush_lsn();
+    let retention_pins = tli
+        .get_retention_pins()
+        .into_iter()
+        .map(RetentionPinStatus::from)
+        .collect();
 
     let epoch = state.acceptor_state.get_epoch(flush_lsn);
+    let epoch_start_lsn = state.acceptor_state.get_epoch_start_lsn(flush_lsn);
     let term_history = state
         .acceptor_state
         .term_history
@@ -142,6 +189,7 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
     let acc_state = AcceptorStateStatus {
         term: state.acceptor_state.term,
         epoch,
+        epoch_start_lsn,
         term_history,
     };
 
@@ -158,6 +206,8 @@ async fn timeline_status_handler(request: Request<Body>) -> Result<Response<Body
         backup_lsn: inmem.backup_lsn,
         peer_horizon_lsn: inmem.peer_horizon_lsn,
         remote_consistent_lsn: inmem.remote_consistent_lsn,
+        configuration: state.configuration,
+        retention_pins,
     };
     json_response(StatusCode::OK, status)
 }
@@ -181,17 +231,24 @@ async fn timeline_create_handler(mut request: Request<Body>) -> Result<Response<
             .commit_lsn
             .segment_lsn(server_info.wal_seg_size as usize)
     });
-    tokio::task::spawn_blocking(move || {
-        GlobalTimelines::create(ttid, server_info, request_data.commit_lsn, local_start_lsn)
-    })
-    .await
-    .map_err(|e| ApiError::InternalServerError(e.into()))?
-    .map_err(ApiError::InternalServerError)?;
+    let params = TimelineCreateParams::new(
+        server_info,
+        request_data.timeline_start_lsn.unwrap_or(Lsn::INVALID),
+        local_start_lsn,
+        request_data.commit_lsn,
+    )
+    .map_err(ApiError::BadRequest)?;
+    tokio::task::spawn_blocking(move || GlobalTimelines::create(ttid, params))
+        .await
+        .map_err(|e| ApiError::InternalServerError(e.into()))?
+        .map_err(ApiError::InternalServerError)?;
 
     json_response(StatusCode::OK, ())
 }
 
-/// Deactivates the timeline and removes its data directory.
+/// Deactivates the timeline and removes its data directory together with WAL
+/// offloaded to remote storage. With `only_local=true` query parameter, remote
+/// WAL is kept.
 async fn timeline_delete_force_handler(
     mut request: Request<Body>,
 ) -> Result<Response<Body>, ApiError> {
@@ -199,16 +256,403 @@ async fn timeline_delete_force_handler(
         parse_request_param(&request, "tenant_id")?,
         parse_request_param(&request, "timeline_id")?,
     );
+    let only_local = parse_query_param(&request, "only_local")?.unwrap_or(false);
     check_permission(&request, Some(ttid.tenant_id))?;
     ensure_no_body(&mut request).await?;
-    let resp = tokio::task::spawn_blocking(move || {
-        // FIXME: `delete_force` can fail from both internal errors and bad requests. Add better
-        // error handling here when we're able to.
-        GlobalTimelines::delete_force(&ttid).map_err(ApiError::InternalServerError)
+    // FIXME: `delete` can fail from both internal errors and bad requests. Add better
+    // error handling here when we're able to.
+    let resp = GlobalTimelines::delete(&ttid, only_local)
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, resp)
+}
+
+/// List files of the timeline to be copied by a peer pulling it.
+async fn timeline_files_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let timeline_dir = get_conf(&request).timeline_dir(&tli.ttid);
+    let files =
+        tokio::task::spawn_blocking(move || pull_timeline::list_timeline_files(&timeline_dir))
+            .await
+            .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+            .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, files)
+}
+
+/// Download a file of the timeline.
+async fn timeline_file_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+    let filename: String = parse_request_param(&request, "filename")?;
+    if !pull_timeline::is_timeline_file(&filename) {
+        return Err(ApiError::BadRequest(anyhow::anyhow!(
+            "invalid file name {filename:?}"
+        )));
+    }
+
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let path = get_conf(&request).timeline_dir(&tli.ttid).join(&filename);
+    let data = tokio::fs::read(&path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))
+        .map_err(ApiError::NotFound)?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(hyper::header::CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from(data))
+        .map_err(|e| ApiError::InternalServerError(e.into()))
+}
+
+/// Create a new timeline of the tenant as a copy of this one up to some LSN.
+async fn timeline_copy_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let source = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(source.tenant_id))?;
+
+    let data: copy_timeline::Request = json_request(&mut request).await?;
+    let conf = get_conf(&request).clone();
+    tokio::task::spawn_blocking(move || copy_timeline::handle_request(&conf, source, data))
+        .await
+        .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, ())
+}
+
+/// Fence the timeline by bumping its term, so that walproposers of older
+/// terms can't append anymore. Control plane calls it on a quorum of
+/// safekeepers of the timeline.
+async fn timeline_term_bump_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let data: TimelineTermBumpRequest = json_request(&mut request).await?;
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let bump = tokio::task::spawn_blocking(move || tli.term_bump(data.term))
+        .await
+        .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+        .map_err(ApiError::InternalServerError)?;
+    json_response(
+        StatusCode::OK,
+        TimelineTermBumpResponse {
+            previous_term: bump.previous_term,
+            current_term: bump.current_term,
+        },
+    )
+}
+
+/// List unexpired retention pins of the timeline.
+async fn timeline_retention_pins_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let pins: Vec<RetentionPinStatus> = tli
+        .get_retention_pins()
+        .into_iter()
+        .map(RetentionPinStatus::from)
+        .collect();
+    json_response(StatusCode::OK, pins)
+}
+
+/// Hold local WAL of the timeline starting at the given LSN under a name,
+/// e.g. while a branch is being created or a backup is taken.
+async fn timeline_retention_pin_hold_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+    let name: String = parse_request_param(&request, "name")?;
+
+    let data: TimelineRetentionPinRequest = json_request(&mut request).await?;
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let ttl = data.ttl_secs.map(std::time::Duration::from_secs);
+    let pin = tokio::task::spawn_blocking(move || tli.hold_wal(name, data.lsn, ttl))
+        .await
+        .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+        .map_err(|e| ApiError::Conflict(format!("{e:#}")))?;
+    json_response(StatusCode::OK, RetentionPinStatus::from(pin))
+}
+
+/// Release the retention pin of the timeline.
+async fn timeline_retention_pin_release_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+    let name: String = parse_request_param(&request, "name")?;
+    ensure_no_body(&mut request).await?;
+
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let released = tokio::task::spawn_blocking(move || tli.release_wal(&name))
+        .await
+        .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+        .map_err(ApiError::InternalServerError)?;
+    if !released {
+        return Err(ApiError::NotFound(anyhow::anyhow!(
+            "retention pin not found"
+        )));
+    }
+    json_response(StatusCode::OK, ())
+}
+
+/// List active connections of the timeline.
+async fn timeline_connections_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    json_response(StatusCode::OK, connections::list(&ttid))
+}
+
+/// List running and recently finished background tasks of the timeline.
+async fn timeline_tasks_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    json_response(StatusCode::OK, tli.tasks().list())
+}
+
+/// Validate local WAL of the timeline, reporting the first bad LSN.
+async fn timeline_check_wal_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let conf = get_conf(&request).clone();
+    let result = tokio::task::spawn_blocking(move || wal_check::check_timeline_wal(&conf, ttid))
+        .await
+        .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, result)
+}
+
+/// Verify WAL of the timeline offloaded to remote storage, uploading missing
+/// and mismatched segments again with `repair=true`.
+async fn timeline_scrub_backup_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    let repair = parse_query_param(&request, "repair")?.unwrap_or(false);
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let conf = get_conf(&request);
+    if conf.remote_storage.is_none() {
+        return Err(ApiError::BadRequest(anyhow::anyhow!(
+            "remote storage is not configured"
+        )));
+    }
+    let tli = GlobalTimelines::get(ttid).map_err(ApiError::NotFound)?;
+    let report = wal_backup_scrub::scrub_timeline(conf, &tli, repair)
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, report)
+}
+
+/// Copy the timeline from a peer safekeeper.
+async fn timeline_pull_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    let data: pull_timeline::Request = json_request(&mut request).await?;
+    let conf = get_conf(&request);
+    let resp = pull_timeline::handle_request(conf, data)
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, resp)
+}
+
+/// Start moving the timeline to another safekeeper, see `relocate` module.
+async fn timeline_relocate_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, None)?;
+
+    let data: relocate::Request = json_request(&mut request).await?;
+    let relocation =
+        relocate::Relocation::new(get_conf(&request), ttid, data).map_err(ApiError::BadRequest)?;
+    let status = relocate::start(relocation).map_err(|e| ApiError::Conflict(e.to_string()))?;
+    json_response(StatusCode::ACCEPTED, status)
+}
+
+/// Report progress of the last relocation of the timeline.
+async fn timeline_relocate_status_handler(
+    request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let ttid = TenantTimelineId::new(
+        parse_request_param(&request, "tenant_id")?,
+        parse_request_param(&request, "timeline_id")?,
+    );
+    check_permission(&request, Some(ttid.tenant_id))?;
+
+    let status = relocate::get_status(&ttid)
+        .ok_or_else(|| ApiError::NotFound(anyhow::anyhow!("no relocation of {}", ttid)))?;
+    json_response(StatusCode::OK, status)
+}
+
+/// Start graceful shutdown of the safekeeper, see `drain` module. Returns
+/// immediately; the process exits once draining is finished.
+async fn drain_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    let conf = get_conf(&request).clone();
+    drain::spawn(conf).map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::ACCEPTED, ())
+}
+
+/// Re-read TLS certificate, key and CA, applied to new connections; the
+/// same as SIGHUP.
+async fn tls_reload_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+
+    let tls = get_conf(&request)
+        .tls
+        .clone()
+        .ok_or_else(|| ApiError::BadRequest(anyhow::anyhow!("TLS is not configured")))?;
+    tokio::task::spawn_blocking(move || tls.reload())
+        .await
+        .map_err(|e| ApiError::InternalServerError(e.into()))?
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, ())
+}
+
+/// Dump state of all timelines, or timelines of the tenant if `tenant_id`
+/// query parameter is given.
+async fn debug_dump_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id: Option<TenantId> = parse_query_param(&request, "tenant_id")?;
+    check_permission(&request, tenant_id)?;
+
+    let conf = get_conf(&request).clone();
+    let args = debug_dump::Args { tenant_id };
+    let dump = tokio::task::spawn_blocking(move || debug_dump::build(&conf, args))
+        .await
+        .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, dump)
+}
+
+/// Info about tenant on safekeeper ready for reporting.
+#[derive(Debug, Serialize)]
+struct TenantStatus {
+    #[serde(serialize_with = "display_serialize")]
+    tenant_id: TenantId,
+    timelines: Vec<String>,
+    disk_usage_bytes: u64,
+}
+
+/// Report timelines of the tenant and the disk space it uses.
+async fn tenant_status_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timelines = GlobalTimelines::get_tenant_timelines(&tenant_id);
+    let conf = get_conf(&request).clone();
+    let disk_usage_bytes = tokio::task::spawn_blocking(move || {
+        GlobalTimelines::get_tenant_disk_usage(&conf, &tenant_id)
     })
     .await
-    .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))??;
-    json_response(StatusCode::OK, resp)
+    .map_err(|e: JoinError| ApiError::InternalServerError(e.into()))?
+    .map_err(ApiError::InternalServerError)?;
+
+    let status = TenantStatus {
+        tenant_id,
+        timelines: timelines.iter().map(|id| id.to_string()).collect(),
+        disk_usage_bytes,
+    };
+    json_response(StatusCode::OK, status)
+}
+
+/// Get current limits on WAL uploads.
+async fn wal_backup_limits_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+    json_response(StatusCode::OK, wal_backup::get_limits())
+}
+
+/// Change limits on WAL uploads, applied to uploads started afterwards.
+async fn wal_backup_limits_set_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+    let limits: wal_backup::BackupLimits = json_request(&mut request).await?;
+    wal_backup::set_limits(limits).map_err(ApiError::BadRequest)?;
+    json_response(StatusCode::OK, wal_backup::get_limits())
+}
+
+/// Get the WAL retention policy in effect for the tenant.
+async fn tenant_wal_retention_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let policy = remove_wal::get_policy(get_conf(&request), &tenant_id);
+    json_response(StatusCode::OK, policy)
+}
+
+/// Override the safekeeper-wide WAL retention policy for the tenant. As it
+/// affects disk usage of the whole node, safekeeper scope is required.
+async fn tenant_wal_retention_set_handler(
+    mut request: Request<Body>,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, None)?;
+
+    let overrides: remove_wal::WalRetentionPolicy = json_request(&mut request).await?;
+    let conf = get_conf(&request);
+    remove_wal::set_tenant_policy(conf, tenant_id, overrides)
+        .map_err(ApiError::InternalServerError)?;
+    let policy = remove_wal::get_policy(conf, &tenant_id);
+    json_response(StatusCode::OK, policy)
+}
+
+/// List timelines of the tenant.
+async fn tenant_timelines_list_handler(request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    let tenant_id = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let timelines = GlobalTimelines::get_tenant_timelines(&tenant_id);
+    json_response(
+        StatusCode::OK,
+        timelines
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<String>>(),
+    )
 }
 
 /// Deactivates all timelines for the tenant and removes its data directory.
@@ -250,6 +694,7 @@ async fn record_safekeeper_info(mut request: Request<Body>) -> Result<Response<B
             tenant_id: ttid.tenant_id.as_ref().to_owned(),
             timeline_id: ttid.timeline_id.as_ref().to_owned(),
         }),
+        term: sk_info.term.unwrap_or(0),
         last_log_term: sk_info.last_log_term.unwrap_or(0),
         flush_lsn: sk_info.flush_lsn.0,
         commit_lsn: sk_info.commit_lsn.0,
@@ -258,6 +703,7 @@ async fn record_safekeeper_info(mut request: Request<Body>) -> Result<Response<B
         safekeeper_connstr: sk_info.safekeeper_connstr.unwrap_or_else(|| "".to_owned()),
         backup_lsn: sk_info.backup_lsn.0,
         local_start_lsn: sk_info.local_start_lsn.0,
+        last_record_time: 0,
     };
 
     let tli = GlobalTimelines::get(ttid)
@@ -276,6 +722,40 @@ async fn record_safekeeper_info(mut request: Request<Body>) -> Result<Response<B
     json_response(StatusCode::OK, ())
 }
 
+/// Configure failpoints, available if built with the `testing` feature.
+async fn failpoints_handler(mut request: Request<Body>) -> Result<Response<Body>, ApiError> {
+    check_permission(&request, None)?;
+    if !fail::has_failpoints() {
+        return Err(ApiError::BadRequest(anyhow::anyhow!(
+            "Cannot manage failpoints because safekeeper was compiled without failpoints support"
+        )));
+    }
+
+    let failpoints: ConfigureFailpointsRequest = json_request(&mut request).await?;
+    for fp in failpoints {
+        info!("cfg failpoint: {} {}", fp.name, fp.actions);
+
+        // We recognize one extra "action" that's not natively recognized
+        // by the failpoints crate: exit, to immediately kill the process
+        let cfg_result = if fp.actions == "exit" {
+            fail::cfg_callback(fp.name, || {
+                info!("Exit requested by failpoint");
+                std::process::exit(1);
+            })
+        } else {
+            fail::cfg(fp.name, &fp.actions)
+        };
+
+        if let Err(err_msg) = cfg_result {
+            return Err(ApiError::BadRequest(anyhow::anyhow!(
+                "Failed to configure failpoints: {err_msg}"
+            )));
+        }
+    }
+
+    json_response(StatusCode::OK, ())
+}
+
 /// Safekeeper http router.
 pub fn make_router(conf: SafeKeeperConf) -> RouterBuilder<hyper::Body, ApiError> {
     let mut router = endpoint::make_router();
@@ -302,6 +782,19 @@ pub fn make_router(conf: SafeKeeperConf) -> RouterBuilder<hyper::Body, ApiError>
         .get("/v1/status", status_handler)
         // Will be used in the future instead of implicit timeline creation
         .post("/v1/tenant/timeline", timeline_create_handler)
+        .get("/v1/tenant/:tenant_id", tenant_status_handler)
+        .get(
+            "/v1/tenant/:tenant_id/wal_retention",
+            tenant_wal_retention_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/wal_retention",
+            tenant_wal_retention_set_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline",
+            tenant_timelines_list_handler,
+        )
         .get(
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_status_handler,
@@ -310,23 +803,159 @@ pub fn make_router(conf: SafeKeeperConf) -> RouterBuilder<hyper::Body, ApiError>
             "/v1/tenant/:tenant_id/timeline/:timeline_id",
             timeline_delete_force_handler,
         )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/file",
+            timeline_files_list_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/file/:filename",
+            timeline_file_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/copy",
+            timeline_copy_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/term_bump",
+            timeline_term_bump_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/check_wal",
+            timeline_check_wal_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/scrub_backup",
+            timeline_scrub_backup_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/connections",
+            timeline_connections_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/tasks",
+            timeline_tasks_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/retention_pins",
+            timeline_retention_pins_handler,
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/retention_pins/:name",
+            timeline_retention_pin_hold_handler,
+        )
+        .delete(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/retention_pins/:name",
+            timeline_retention_pin_release_handler,
+        )
+        .post(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/relocate",
+            timeline_relocate_handler,
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/relocate",
+            timeline_relocate_status_handler,
+        )
+        .post("/v1/pull_timeline", timeline_pull_handler)
+        .post("/v1/drain", drain_handler)
+        .post("/v1/tls/reload", tls_reload_handler)
+        .get("/v1/wal_backup/limits", wal_backup_limits_handler)
+        .put("/v1/wal_backup/limits", wal_backup_limits_set_handler)
+        .get("/v1/debug_dump", debug_dump_handler)
         .delete("/v1/tenant/:tenant_id", tenant_delete_force_handler)
         // for tests
         .post(
             "/v1/record_safekeeper_info/:tenant_id/:timeline_id",
             record_safekeeper_info,
         )
+        .put("/v1/failpoints", failpoints_handler)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tls::{tests::generate_tls_files, ServerTls};
+    use hyper::service::Service;
+    use routerify::RequestServiceBuilder;
+
+    async fn get(conf: &SafeKeeperConf, uri: &str) -> (StatusCode, serde_json::Value) {
+        call(conf, Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+
+    async fn call(
+        conf: &SafeKeeperConf,
+        request: Request<Body>,
+    ) -> (StatusCode, serde_json::Value) {
+        let router = make_router(conf.clone()).build().unwrap();
+        let mut service = RequestServiceBuilder::new(router)
+            .unwrap()
+            .build("127.0.0.1:0".parse().unwrap());
+        let response = service.call(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_tenant_routes() {
+        let workdir = tempfile::tempdir().unwrap();
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            ..SafeKeeperConf::dummy()
+        };
+        let tenant_id = TenantId::generate();
+        let timeline_dir = conf.tenant_dir(&tenant_id).join("broken_timeline");
+        std::fs::create_dir_all(&timeline_dir).unwrap();
+        std::fs::write(timeline_dir.join("file"), [0u8; 100]).unwrap();
+
+        let (status, body) = get(&conf, &format!("/v1/tenant/{tenant_id}/timeline")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, serde_json::json!([]));
+
+        // Disk usage includes timelines which failed to load.
+        let (status, body) = get(&conf, &format!("/v1/tenant/{tenant_id}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "tenant_id": tenant_id.to_string(),
+                "timelines": [],
+                "disk_usage_bytes": 100,
+            })
+        );
+
+        let (status, _) = get(&conf, "/v1/tenant/not_a_tenant_id").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_tls_reload() {
+        let reload = || Request::post("/v1/tls/reload").body(Body::empty()).unwrap();
+
+        let (status, _) = call(&SafeKeeperConf::dummy(), reload()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let dir = tempfile::tempdir().unwrap();
+        let paths = generate_tls_files(dir.path());
+        let conf = SafeKeeperConf {
+            tls: Some(Arc::new(ServerTls::load(paths.clone()).unwrap())),
+            ..SafeKeeperConf::dummy()
+        };
+        generate_tls_files(dir.path());
+        let (status, _) = call(&conf, reload()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // failed reload is reported
+        std::fs::write(&paths.key_path, "garbage").unwrap();
+        let (status, _) = call(&conf, reload()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
     #[test]
     fn test_term_switch_entry_api_serialize() {
         let state = AcceptorStateStatus {
             term: 1,
             epoch: 1,
+            epoch_start_lsn: Lsn(0x16FFDDDD),
             term_history: vec![TermSwitchApiEntry {
                 term: 1,
                 lsn: Lsn(0x16FFDDDD),
@@ -335,7 +964,7 @@ mod tests {
         let json = serde_json::to_string(&state).unwrap();
         assert_eq!(
             json,
-            "{\"term\":1,\"epoch\":1,\"term_history\":[{\"term\":1,\"lsn\":\"0/16FFDDDD\"}]}"
+            "{\"term\":1,\"epoch\":1,\"epoch_start_lsn\":\"0/16FFDDDD\",\"term_history\":[{\"term\":1,\"lsn\":\"0/16FFDDDD\"}]}"
         );
     }
 }
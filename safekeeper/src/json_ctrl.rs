@@ -2,8 +2,8 @@
 //! This module implements JSON_CTRL protocol, which allows exchange
 //! JSON messages over psql for testing purposes.
 //!
-//! Currently supports AppendLogicalMessage, which is used for WAL
-//! modifications in tests.
+//! Supports AppendLogicalMessage, which is used for WAL modifications in
+//! tests, and requests to read back the WAL and the safekeeper state.
 //!
 
 use std::sync::Arc;
@@ -12,22 +12,104 @@ use anyhow::Context;
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use tracing::*;
+use utils::cancel_registry::CancellationToken;
 use utils::id::TenantTimelineId;
 use utils::postgres_backend_async::QueryError;
 
 use crate::handler::SafekeeperPostgresHandler;
-use crate::safekeeper::{AcceptorProposerMessage, AppendResponse, ServerInfo};
+use crate::safekeeper::{
+    AcceptorProposerMessage, AppendResponse, ServerInfo, TimelineCreateParams,
+};
 use crate::safekeeper::{
     AppendRequest, AppendRequestHeader, ProposerAcceptorMessage, ProposerElected,
 };
 use crate::safekeeper::{SafeKeeperState, Term, TermHistory, TermSwitchEntry};
 use crate::timeline::Timeline;
+use crate::wal_storage::WalReader;
 use crate::GlobalTimelines;
-use postgres_ffi::encode_logical_message;
+use postgres_ffi::v14::xlog_utils::XLOG_RECORD_CRC_OFFS;
+use postgres_ffi::waldecoder::WalStreamDecoder;
 use postgres_ffi::WAL_SEGMENT_SIZE;
-use pq_proto::{BeMessage, RowDescriptor, TEXT_OID};
+use postgres_ffi::{decode_logical_message, encode_logical_message_for_version, XLogRecord};
+use pq_proto::{BeMessage, RowDescriptor, SystemId};
 use utils::{lsn::Lsn, postgres_backend::PostgresBackend};
 
+/// Request of JSON_CTRL command. A plain AppendLogicalMessage object is
+/// accepted as is, other requests are tagged with their name, e.g.
+/// `{"ReadWal": {"start_lsn": 0, "end_lsn": 0}}` or `"GetState"`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum JsonCtrlRequest {
+    Command(JsonCtrlCommand),
+    AppendLogicalMessage(AppendLogicalMessage),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum JsonCtrlCommand {
+    /// Decode WAL records in [start_lsn, end_lsn), start_lsn must be a
+    /// record boundary.
+    ReadWal { start_lsn: Lsn, end_lsn: Lsn },
+    /// Get persisted state of the timeline.
+    GetState,
+    /// Append several crafted records in one go.
+    AppendBatch(AppendBatch),
+}
+
+/// Batch of logical messages appended one by one, each with a separate
+/// AppendRequest. Unless overridden, a record is appended with the batch
+/// term right after the previous one.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppendBatch {
+    records: Vec<BatchRecord>,
+
+    // if true, commit_lsn will match flush_lsn after each append
+    set_commit_lsn: bool,
+
+    // if true, ProposerElected will be sent before the first append
+    send_proposer_elected: bool,
+
+    term: Term,
+    epoch_start_lsn: Lsn,
+    begin_lsn: Lsn,
+    truncate_lsn: Lsn,
+    pg_version: u32,
+    #[serde(flatten)]
+    server: ServerOverrides,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BatchRecord {
+    lm_prefix: String,
+    lm_message: String,
+
+    // append with this term instead of the batch one
+    #[serde(default)]
+    term: Option<Term>,
+    // if true, ProposerElected for the record term and LSN will be sent
+    // before append
+    #[serde(default)]
+    send_proposer_elected: bool,
+    // append at this LSN instead of the end of the previous record
+    #[serde(default)]
+    begin_lsn: Option<Lsn>,
+    // number of bytes cut from the end of the record, leaving it incomplete
+    #[serde(default)]
+    missing_bytes: usize,
+    // if true, the record CRC will be broken
+    #[serde(default)]
+    corrupt_crc: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AppendBatchResult {
+    // safekeeper state after the batch
+    state: SafeKeeperState,
+    // records appended before the error, if any
+    inserted_wal: Vec<InsertedWAL>,
+    // error of the first failed append, the rest of the batch is skipped
+    error: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AppendLogicalMessage {
     // prefix and message to build LogicalMessage
@@ -46,6 +128,24 @@ pub struct AppendLogicalMessage {
     begin_lsn: Lsn,
     truncate_lsn: Lsn,
     pg_version: u32,
+    #[serde(flatten)]
+    server: ServerOverrides,
+}
+
+/// Parameters of the timeline created by the request, for reproducing
+/// clusters initialized with non-default settings. Ignored if the timeline
+/// already exists, except that wal_seg_size must match.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ServerOverrides {
+    /// Defaults to WAL_SEGMENT_SIZE.
+    #[serde(default)]
+    wal_seg_size: Option<u32>,
+    /// Defaults to 0.
+    #[serde(default)]
+    system_id: Option<SystemId>,
+    /// LSN the local WAL starts at, defaults to the first appended record.
+    #[serde(default)]
+    start_lsn: Option<Lsn>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,18 +156,49 @@ struct AppendResult {
     inserted_wal: InsertedWAL,
 }
 
-/// Handles command to craft logical message WAL record with given
-/// content, and then append it with specified term and lsn. This
-/// function is used to test safekeepers in different scenarios.
+/// Handles JSON_CTRL command and sends its JSON encoded result as a single
+/// row.
 pub fn handle_json_ctrl(
     spg: &SafekeeperPostgresHandler,
     pgb: &mut PostgresBackend,
-    append_request: &AppendLogicalMessage,
+    request: &JsonCtrlRequest,
+    cancel: &CancellationToken,
 ) -> Result<(), QueryError> {
-    info!("JSON_CTRL request: {append_request:?}");
+    info!("JSON_CTRL request: {request:?}");
+
+    let response_data = match request {
+        JsonCtrlRequest::AppendLogicalMessage(append_request) => {
+            serde_json::to_vec(&handle_append(spg, append_request)?)
+        }
+        JsonCtrlRequest::Command(JsonCtrlCommand::ReadWal { start_lsn, end_lsn }) => {
+            serde_json::to_vec(&read_wal(spg, *start_lsn, *end_lsn)?)
+        }
+        JsonCtrlRequest::Command(JsonCtrlCommand::GetState) => {
+            serde_json::to_vec(&GlobalTimelines::get(spg.ttid)?.get_state().1)
+        }
+        JsonCtrlRequest::Command(JsonCtrlCommand::AppendBatch(batch)) => {
+            serde_json::to_vec(&handle_append_batch(spg, batch, cancel)?)
+        }
+    }
+    .context("failed to serialize JSON_CTRL response")?;
 
+    pgb.write_message_noflush(&BeMessage::RowDescription(&[RowDescriptor::text_col(
+        b"json",
+    )]))?
+    .write_message_noflush(&BeMessage::DataRow(&[Some(&response_data)]))?
+    .write_message(&BeMessage::CommandComplete(b"JSON_CTRL"))?;
+    Ok(())
+}
+
+/// Handles command to craft logical message WAL record with given
+/// content, and then append it with specified term and lsn. This
+/// function is used to test safekeepers in different scenarios.
+fn handle_append(
+    spg: &SafekeeperPostgresHandler,
+    append_request: &AppendLogicalMessage,
+) -> anyhow::Result<AppendResult> {
     // need to init safekeeper state before AppendRequest
-    let tli = prepare_safekeeper(spg.ttid, append_request.pg_version)?;
+    let tli = prepare_safekeeper(spg.ttid, append_request.pg_version, &append_request.server)?;
 
     // if send_proposer_elected is true, we need to update local history
     if append_request.send_proposer_elected {
@@ -75,42 +206,233 @@ pub fn handle_json_ctrl(
     }
 
     let inserted_wal = append_logical_message(&tli, append_request)?;
-    let response = AppendResult {
+    Ok(AppendResult {
         state: tli.get_state().1,
         inserted_wal,
-    };
-    let response_data = serde_json::to_vec(&response)
-        .with_context(|| format!("Response {response:?} is not a json array"))?;
-
-    pgb.write_message_noflush(&BeMessage::RowDescription(&[RowDescriptor {
-        name: b"json",
-        typoid: TEXT_OID,
-        typlen: -1,
-        ..Default::default()
-    }]))?
-    .write_message_noflush(&BeMessage::DataRow(&[Some(&response_data)]))?
-    .write_message(&BeMessage::CommandComplete(b"JSON_CTRL"))?;
-    Ok(())
+    })
+}
+
+fn handle_append_batch(
+    spg: &SafekeeperPostgresHandler,
+    batch: &AppendBatch,
+    cancel: &CancellationToken,
+) -> Result<AppendBatchResult, QueryError> {
+    let tli = prepare_safekeeper(spg.ttid, batch.pg_version, &batch.server)?;
+    if batch.send_proposer_elected {
+        send_proposer_elected(&tli, batch.term, batch.epoch_start_lsn)?;
+    }
+
+    // the timeline might exist already, records are in its WAL format
+    let pg_version = tli.get_state().1.server.pg_version;
+    let _accept_invalid_wal = batch
+        .records
+        .iter()
+        .any(|record| record.corrupt_crc || record.missing_bytes > 0)
+        .then(|| AcceptInvalidWal::new(&tli));
+    let mut inserted_wal = Vec::new();
+    let mut error = None;
+    let mut begin_lsn = batch.begin_lsn;
+    for record in batch.records.iter() {
+        if cancel.is_cancelled() {
+            return Err(QueryError::Cancelled);
+        }
+        let term = record.term.unwrap_or(batch.term);
+        begin_lsn = record.begin_lsn.unwrap_or(begin_lsn);
+        let res = (|| {
+            if record.send_proposer_elected {
+                send_proposer_elected(&tli, term, begin_lsn)?;
+            }
+            let wal_data = craft_record(record, pg_version)?;
+            append_wal(
+                &tli,
+                term,
+                begin_lsn,
+                wal_data,
+                batch.set_commit_lsn,
+                batch.truncate_lsn,
+            )
+        })();
+        match res {
+            Ok(inserted) => {
+                begin_lsn = inserted.end_lsn;
+                inserted_wal.push(inserted);
+            }
+            Err(e) => {
+                error = Some(format!("{e:#}"));
+                break;
+            }
+        }
+    }
+
+    Ok(AppendBatchResult {
+        state: tli.get_state().1,
+        inserted_wal,
+        error,
+    })
+}
+
+/// Encode logical message of the batch record for the postgres version,
+/// damaged as requested.
+fn craft_record(record: &BatchRecord, pg_version: u32) -> anyhow::Result<Vec<u8>> {
+    let mut wal_data = encode_logical_message_for_version(
+        &record.lm_prefix,
+        &record.lm_message,
+        pg_version / 10000,
+    )?;
+    if record.corrupt_crc {
+        wal_data[XLOG_RECORD_CRC_OFFS] ^= 0xFF;
+    }
+    if record.missing_bytes >= wal_data.len() {
+        anyhow::bail!(
+            "can't cut {} bytes from record of {} bytes",
+            record.missing_bytes,
+            wal_data.len()
+        );
+    }
+    wal_data.truncate(wal_data.len() - record.missing_bytes);
+    Ok(wal_data)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DecodedRecord {
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+    xl_tot_len: u32,
+    xl_xid: u32,
+    xl_info: u8,
+    xl_rmid: u8,
+    /// hex encoded record, including the header
+    data: String,
+    /// prefix and content of logical message records
+    lm_prefix: Option<String>,
+    lm_message: Option<String>,
 }
 
-/// Prepare safekeeper to process append requests without crashes,
-/// by sending ProposerGreeting with default server.wal_seg_size.
-fn prepare_safekeeper(ttid: TenantTimelineId, pg_version: u32) -> anyhow::Result<Arc<Timeline>> {
-    GlobalTimelines::create(
-        ttid,
+/// Max amount of WAL ReadWal reads at once.
+const MAX_READ_WAL_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Read local WAL in [start_lsn, end_lsn) and decode records in it.
+fn read_wal(
+    spg: &SafekeeperPostgresHandler,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+) -> anyhow::Result<Vec<DecodedRecord>> {
+    let tli = GlobalTimelines::get(spg.ttid)?;
+    let state = tli.get_state().1;
+    let flush_lsn = tli.get_flush_lsn();
+    if start_lsn > end_lsn || end_lsn > flush_lsn {
+        anyhow::bail!("can't read WAL in {start_lsn}..{end_lsn}, flush_lsn is {flush_lsn}");
+    }
+
+    let mut wal_reader = WalReader::new(
+        spg.conf.workdir.clone(),
+        spg.conf.timeline_dir(&spg.ttid),
+        &state,
+        start_lsn,
+        spg.conf.wal_backup_enabled,
+    )?;
+    let len = u64::from(end_lsn) - u64::from(start_lsn);
+    if len > MAX_READ_WAL_SIZE {
+        anyhow::bail!("can't read {len} bytes of WAL at once, the limit is {MAX_READ_WAL_SIZE}");
+    }
+    let mut wal = vec![0u8; len as usize];
+    let read = crate::BACKGROUND_RUNTIME.block_on(async {
+        let mut pos = 0;
+        while pos < wal.len() {
+            let n = wal_reader.read(&mut wal[pos..]).await?;
+            if n == 0 {
+                break;
+            }
+            pos += n;
+        }
+        anyhow::Ok(pos)
+    })?;
+    wal.truncate(read);
+
+    let mut decoder = WalStreamDecoder::with_seg_size(
+        start_lsn,
+        state.server.pg_version / 10000,
+        state.server.wal_seg_size as usize,
+    );
+    decoder.feed_bytes(&wal);
+    let mut records = Vec::new();
+    while let Some((lsn, rec)) = decoder.poll_decode()? {
+        let header = XLogRecord::from_slice(&rec)?;
+        let logical_message = decode_logical_message(&rec)
+            .with_context(|| format!("failed to decode record at {}", decoder.record_start_lsn))?;
+        records.push(DecodedRecord {
+            start_lsn: decoder.record_start_lsn,
+            end_lsn: lsn,
+            xl_tot_len: header.xl_tot_len,
+            xl_xid: header.xl_xid,
+            xl_info: header.xl_info,
+            xl_rmid: header.xl_rmid,
+            data: hex::encode(&rec),
+            lm_prefix: logical_message.as_ref().map(|m| m.prefix.clone()),
+            lm_message: logical_message
+                .as_ref()
+                .map(|m| String::from_utf8_lossy(&m.message).into_owned()),
+        });
+    }
+    Ok(records)
+}
+
+/// Prepare safekeeper to process append requests without crashes, by
+/// creating the timeline with the requested or default server parameters.
+fn prepare_safekeeper(
+    ttid: TenantTimelineId,
+    pg_version: u32,
+    server: &ServerOverrides,
+) -> anyhow::Result<Arc<Timeline>> {
+    let wal_seg_size = server.wal_seg_size.unwrap_or(WAL_SEGMENT_SIZE as u32);
+    let params = TimelineCreateParams::new(
         ServerInfo {
             pg_version,
-            wal_seg_size: WAL_SEGMENT_SIZE as u32,
-            system_id: 0,
+            wal_seg_size,
+            system_id: server.system_id.unwrap_or(0),
         },
         Lsn::INVALID,
+        server.start_lsn.unwrap_or(Lsn::INVALID),
         Lsn::INVALID,
-    )
+    )?;
+    let tli = GlobalTimelines::create(ttid, params)?;
+    if tli.get_wal_seg_size() != wal_seg_size as usize {
+        anyhow::bail!(
+            "timeline {} already exists with wal_seg_size {}",
+            ttid,
+            tli.get_wal_seg_size()
+        );
+    }
+    Ok(tli)
+}
+
+/// Makes the timeline accept WAL failing validation until dropped, for
+/// appending records damaged on purpose, see BatchRecord.
+struct AcceptInvalidWal<'a>(&'a Timeline);
+
+impl<'a> AcceptInvalidWal<'a> {
+    fn new(tli: &'a Timeline) -> Self {
+        tli.set_validate_wal(false);
+        AcceptInvalidWal(tli)
+    }
+}
+
+impl Drop for AcceptInvalidWal<'_> {
+    fn drop(&mut self) {
+        self.0.set_validate_wal(true);
+    }
 }
 
 fn send_proposer_elected(tli: &Arc<Timeline>, term: Term, lsn: Lsn) -> anyhow::Result<()> {
+    let state = tli.get_state().1;
+    // timeline start can't be changed once set
+    let timeline_start_lsn = if state.timeline_start_lsn != Lsn(0) {
+        state.timeline_start_lsn
+    } else {
+        lsn
+    };
     // add new term to existing history
-    let history = tli.get_state().1.acceptor_state.term_history;
+    let history = state.acceptor_state.term_history;
     let history = history.up_to(lsn.checked_sub(1u64).unwrap());
     let mut history_entries = history.0;
     history_entries.push(TermSwitchEntry { term, lsn });
@@ -120,7 +442,7 @@ fn send_proposer_elected(tli: &Arc<Timeline>, term: Term, lsn: Lsn) -> anyhow::R
         term,
         start_streaming_at: lsn,
         term_history: history,
-        timeline_start_lsn: lsn,
+        timeline_start_lsn,
     });
 
     tli.process_msg(&proposer_elected_request)?;
@@ -140,13 +462,33 @@ fn append_logical_message(
     tli: &Arc<Timeline>,
     msg: &AppendLogicalMessage,
 ) -> anyhow::Result<InsertedWAL> {
-    let wal_data = encode_logical_message(&msg.lm_prefix, &msg.lm_message);
+    let pg_version = tli.get_state().1.server.pg_version;
+    let wal_data =
+        encode_logical_message_for_version(&msg.lm_prefix, &msg.lm_message, pg_version / 10000)?;
+    append_wal(
+        tli,
+        msg.term,
+        msg.begin_lsn,
+        wal_data,
+        msg.set_commit_lsn,
+        msg.truncate_lsn,
+    )
+}
+
+/// Append raw WAL at begin_lsn with a single AppendRequest.
+fn append_wal(
+    tli: &Arc<Timeline>,
+    term: Term,
+    begin_lsn: Lsn,
+    wal_data: Vec<u8>,
+    set_commit_lsn: bool,
+    truncate_lsn: Lsn,
+) -> anyhow::Result<InsertedWAL> {
     let sk_state = tli.get_state().1;
 
-    let begin_lsn = msg.begin_lsn;
     let end_lsn = begin_lsn + wal_data.len() as u64;
 
-    let commit_lsn = if msg.set_commit_lsn {
+    let commit_lsn = if set_commit_lsn {
         end_lsn
     } else {
         sk_state.commit_lsn
@@ -154,12 +496,12 @@ fn append_logical_message(
 
     let append_request = ProposerAcceptorMessage::AppendRequest(AppendRequest {
         h: AppendRequestHeader {
-            term: msg.term,
+            term,
             epoch_start_lsn: begin_lsn,
             begin_lsn,
             end_lsn,
             commit_lsn,
-            truncate_lsn: msg.truncate_lsn,
+            truncate_lsn,
             proposer_uuid: [0u8; 16],
         },
         wal_data: Bytes::from(wal_data),
@@ -178,3 +520,102 @@ fn append_logical_message(
         append_response,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        let request: JsonCtrlRequest = serde_json::from_str(
+            r#"{"lm_prefix": "prefix", "lm_message": "message", "set_commit_lsn": true,
+                "send_proposer_elected": true, "term": 1, "epoch_start_lsn": 0,
+                "begin_lsn": 0, "truncate_lsn": 0, "pg_version": 140000}"#,
+        )
+        .unwrap();
+        assert!(matches!(request, JsonCtrlRequest::AppendLogicalMessage(_)));
+
+        let request: JsonCtrlRequest =
+            serde_json::from_str(r#"{"ReadWal": {"start_lsn": 40, "end_lsn": 100}}"#).unwrap();
+        assert!(matches!(
+            request,
+            JsonCtrlRequest::Command(JsonCtrlCommand::ReadWal {
+                start_lsn: Lsn(40),
+                end_lsn: Lsn(100)
+            })
+        ));
+
+        let request: JsonCtrlRequest = serde_json::from_str(
+            r#"{"AppendBatch": {"set_commit_lsn": false, "send_proposer_elected": true,
+                "term": 1, "epoch_start_lsn": 0, "begin_lsn": 0, "truncate_lsn": 0,
+                "pg_version": 140000, "records": [
+                    {"lm_prefix": "p", "lm_message": "m1"},
+                    {"lm_prefix": "p", "lm_message": "m2", "term": 2, "missing_bytes": 8}
+                ]}}"#,
+        )
+        .unwrap();
+        match &request {
+            JsonCtrlRequest::Command(JsonCtrlCommand::AppendBatch(batch)) => {
+                assert_eq!(batch.records.len(), 2);
+                assert_eq!(batch.records[0].term, None);
+                assert_eq!(batch.records[1].term, Some(2));
+                assert_eq!(batch.records[1].missing_bytes, 8);
+            }
+            _ => panic!("unexpected request {request:?}"),
+        }
+
+        let request: JsonCtrlRequest = serde_json::from_str(
+            r#"{"lm_prefix": "prefix", "lm_message": "message", "set_commit_lsn": true,
+                "send_proposer_elected": true, "term": 1, "epoch_start_lsn": 0,
+                "begin_lsn": 0, "truncate_lsn": 0, "pg_version": 140000,
+                "wal_seg_size": 1048576, "system_id": 42, "start_lsn": 1048576}"#,
+        )
+        .unwrap();
+        match &request {
+            JsonCtrlRequest::AppendLogicalMessage(msg) => {
+                assert_eq!(msg.server.wal_seg_size, Some(1 << 20));
+                assert_eq!(msg.server.system_id, Some(42));
+                assert_eq!(msg.server.start_lsn, Some(Lsn(1 << 20)));
+            }
+            _ => panic!("unexpected request {request:?}"),
+        }
+
+        let request: JsonCtrlRequest = serde_json::from_str(r#""GetState""#).unwrap();
+        assert!(matches!(
+            request,
+            JsonCtrlRequest::Command(JsonCtrlCommand::GetState)
+        ));
+    }
+
+    #[test]
+    fn test_craft_record() {
+        let mut record = BatchRecord {
+            lm_prefix: "prefix".to_string(),
+            lm_message: "message".to_string(),
+            term: None,
+            send_proposer_elected: false,
+            begin_lsn: None,
+            missing_bytes: 0,
+            corrupt_crc: false,
+        };
+        let full = craft_record(&record, 150000).unwrap();
+        assert_eq!(
+            full,
+            postgres_ffi::encode_logical_message("prefix", "message")
+        );
+        assert!(craft_record(&record, 130000).is_err());
+
+        record.missing_bytes = 3;
+        record.corrupt_crc = true;
+        let damaged = craft_record(&record, 150000).unwrap();
+        assert_eq!(damaged.len(), full.len() - 3);
+        assert_ne!(damaged[XLOG_RECORD_CRC_OFFS], full[XLOG_RECORD_CRC_OFFS]);
+        assert_eq!(
+            damaged[..XLOG_RECORD_CRC_OFFS],
+            full[..XLOG_RECORD_CRC_OFFS]
+        );
+
+        record.missing_bytes = full.len();
+        assert!(craft_record(&record, 150000).is_err());
+    }
+}
@@ -1,31 +1,54 @@
 use storage_broker::Uri;
 //
 use remote_storage::RemoteStorageConfig;
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::Duration;
 
 use utils::id::{NodeId, TenantId, TenantTimelineId};
+use wal_storage::FsyncMethod;
 
-mod auth;
+pub mod auth;
 pub mod broker;
+pub mod connections;
 pub mod control_file;
 pub mod control_file_upgrade;
+pub mod copy_timeline;
+pub mod debug_dump;
+pub mod drain;
+pub mod grpc;
 pub mod handler;
 pub mod http;
 pub mod json_ctrl;
 pub mod metrics;
+pub mod pull_timeline;
+pub mod quota;
+pub mod rate_limit;
 pub mod receive_wal;
+pub mod record_time;
+pub mod recovery;
+pub mod relocate;
 pub mod remove_wal;
 pub mod safekeeper;
 pub mod send_wal;
+pub mod snapshot;
 pub mod timeline;
+pub mod timeline_eviction;
+pub mod timeline_tasks;
+pub mod tls;
 pub mod wal_backup;
+pub mod wal_backup_scrub;
+pub mod wal_check;
 pub mod wal_service;
 pub mod wal_storage;
 
 mod timelines_global_map;
+use auth::PasswordAuth;
+use once_cell::sync::Lazy;
 use std::sync::Arc;
 pub use timelines_global_map::GlobalTimelines;
+use tls::ServerTls;
+use tokio::runtime::Runtime;
 use utils::auth::JwtAuth;
 
 pub mod defaults {
@@ -37,8 +60,22 @@ pub mod defaults {
     pub const DEFAULT_WAL_BACKUP_RUNTIME_THREADS: usize = 8;
     pub const DEFAULT_HEARTBEAT_TIMEOUT: &str = "5000ms";
     pub const DEFAULT_MAX_OFFLOADER_LAG_BYTES: u64 = 128 * (1 << 20);
+    pub const DEFAULT_MAX_APPEND_BATCH_BYTES: u64 = 16 * (1 << 20);
+    pub const DEFAULT_MAX_APPEND_BATCH_DELAY: &str = "100ms";
+    pub const DEFAULT_WAL_BACKUP_PARALLEL_UPLOADS: usize = 1;
 }
 
+/// Runtime for the async parts of requests served by the synchronous
+/// connection threads, so that they don't build a runtime of their own each
+/// time.
+pub static BACKGROUND_RUNTIME: Lazy<Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .thread_name("background op worker")
+        .enable_all()
+        .build()
+        .expect("Failed to create background op runtime")
+});
+
 #[derive(Debug, Clone)]
 pub struct SafeKeeperConf {
     // Repository directory, relative to current working directory.
@@ -51,6 +88,8 @@ pub struct SafeKeeperConf {
     pub my_id: NodeId,
     pub listen_pg_addr: String,
     pub listen_http_addr: String,
+    /// Where to serve the gRPC WAL service, None disables it.
+    pub listen_grpc_addr: Option<String>,
     pub no_sync: bool,
     pub broker_endpoint: Uri,
     pub broker_keepalive_interval: Duration,
@@ -59,7 +98,88 @@ pub struct SafeKeeperConf {
     pub max_offloader_lag_bytes: u64,
     pub backup_runtime_threads: Option<usize>,
     pub wal_backup_enabled: bool,
+    /// Segments of a single timeline offloaded concurrently.
+    pub wal_backup_parallel_uploads: usize,
+    /// Limit on WAL uploads in flight on the safekeeper.
+    pub wal_backup_max_concurrent_uploads: Option<usize>,
+    /// Limit on WAL upload bandwidth of the safekeeper, in bytes per second.
+    pub wal_backup_max_upload_rate: Option<u64>,
+    /// Remove WAL offloaded to s3 without waiting for pageserver to consume
+    /// it, a lagging pageserver is then served from s3.
+    pub remove_offloaded_wal: bool,
+    /// Keep at least this many bytes of WAL before flush_lsn on disk, see
+    /// `remove_wal::WalRetentionPolicy`.
+    pub wal_retention_keep_bytes: Option<u64>,
+    /// Keep at least this many WAL segments before the last one on disk.
+    pub wal_retention_keep_segments: Option<u64>,
+    /// Compress offloaded WAL segments on disk with zstd.
+    pub compress_cold_wal: bool,
+    /// Verify WAL offloaded by this safekeeper with this interval, uploading
+    /// missing and corrupted segments again.
+    pub wal_backup_scrub_interval: Option<Duration>,
     pub auth: Option<Arc<JwtAuth>>,
+    /// md5 password authentication, used if JWT auth is disabled.
+    pub password_auth: Option<Arc<PasswordAuth>>,
+    /// JWT presented to peer safekeepers, e.g. when recovering WAL from them.
+    pub auth_token: Option<String>,
+    /// HTTP API addresses of peer safekeepers; timelines are relocated only
+    /// between the ones listed here.
+    pub peer_http_addrs: HashMap<NodeId, String>,
+    /// Limit on WAL sent to all replicas together, in bytes per second.
+    pub max_send_rate: Option<u64>,
+    /// Limit on WAL sent to the replicas of a single tenant, in bytes per second.
+    pub max_tenant_send_rate: Option<u64>,
+    /// Limit on WAL sent over a single replication connection, in bytes per second.
+    pub max_connection_send_rate: Option<u64>,
+    /// Max number of concurrent connections of a single tenant.
+    pub max_tenant_connections: Option<usize>,
+    /// Max number of timelines of a single tenant.
+    pub max_tenant_timelines: Option<usize>,
+    /// Max disk space used by a single tenant, in bytes.
+    pub max_tenant_disk_bytes: Option<u64>,
+    /// Flush received WAL once this many bytes are written but not flushed,
+    /// even if walproposer keeps sending more.
+    pub max_unflushed_wal_bytes: Option<u64>,
+    /// Delay replies to walproposer while this many bytes of flushed WAL are
+    /// not offloaded to remote storage yet.
+    pub max_unbacked_wal_bytes: Option<u64>,
+    /// How WAL segments are synced to disk.
+    pub wal_fsync_method: FsyncMethod,
+    /// Wait this long for more WAL from walproposer before flushing, so that
+    /// appends arriving within the window share one flush. Zero flushes as
+    /// soon as no more WAL is readily available.
+    pub wal_flush_max_delay: Duration,
+    /// Appends received back to back are written and acknowledged with a
+    /// single AppendResponse; the batch is cut once it has this many bytes
+    /// of WAL.
+    pub max_append_batch_bytes: u64,
+    /// Cut the batch of appends after it has been collected for this long.
+    pub max_append_batch_delay: Duration,
+    /// Enable TCP keepalive on Postgres protocol connections, probing after
+    /// this much idle time and then at this interval.
+    pub tcp_keepalive: Option<Duration>,
+    /// Drop connections after this many unanswered keepalive probes.
+    pub tcp_keepalive_retries: Option<u32>,
+    /// Drop connections whose sent data stays unacknowledged for this long.
+    pub tcp_user_timeout: Option<Duration>,
+    /// Drop Postgres protocol connections which send no query, and
+    /// walproposer connections which send no AppendRequest, for this long,
+    /// letting the timeline become inactive.
+    pub wal_receive_idle_timeout: Option<Duration>,
+    /// Drop replication connections which don't reply to keepalive requests
+    /// for this long, releasing WAL they hold.
+    pub replication_reply_timeout: Option<Duration>,
+    /// Drop replication connections which don't read sent WAL for this
+    /// long, so a stuck consumer doesn't block the sender forever.
+    pub replication_write_timeout: Option<Duration>,
+    /// Unload timelines from memory after they are idle for this long,
+    /// None disables eviction.
+    pub timeline_eviction_timeout: Option<Duration>,
+    /// Serve Postgres and HTTP listeners over TLS, None means plaintext.
+    pub tls: Option<Arc<ServerTls>>,
+    /// Also accept Postgres connections starting TLS directly, without
+    /// SSLRequest.
+    pub allow_direct_tls: bool,
 }
 
 impl SafeKeeperConf {
@@ -81,6 +201,7 @@ impl SafeKeeperConf {
             no_sync: false,
             listen_pg_addr: defaults::DEFAULT_PG_LISTEN_ADDR.to_string(),
             listen_http_addr: defaults::DEFAULT_HTTP_LISTEN_ADDR.to_string(),
+            listen_grpc_addr: None,
             remote_storage: None,
             my_id: NodeId(0),
             broker_endpoint: storage_broker::DEFAULT_ENDPOINT
@@ -89,9 +210,44 @@ impl SafeKeeperConf {
             broker_keepalive_interval: Duration::from_secs(5),
             backup_runtime_threads: None,
             wal_backup_enabled: true,
+            wal_backup_parallel_uploads: defaults::DEFAULT_WAL_BACKUP_PARALLEL_UPLOADS,
+            wal_backup_max_concurrent_uploads: None,
+            wal_backup_max_upload_rate: None,
+            remove_offloaded_wal: false,
+            wal_retention_keep_bytes: None,
+            wal_retention_keep_segments: None,
+            compress_cold_wal: false,
+            wal_backup_scrub_interval: None,
             auth: None,
+            password_auth: None,
+            auth_token: None,
+            peer_http_addrs: HashMap::new(),
             heartbeat_timeout: Duration::new(5, 0),
             max_offloader_lag_bytes: defaults::DEFAULT_MAX_OFFLOADER_LAG_BYTES,
+            max_send_rate: None,
+            max_tenant_send_rate: None,
+            max_connection_send_rate: None,
+            max_tenant_connections: None,
+            max_tenant_timelines: None,
+            max_tenant_disk_bytes: None,
+            max_unflushed_wal_bytes: None,
+            max_unbacked_wal_bytes: None,
+            wal_fsync_method: FsyncMethod::default(),
+            wal_flush_max_delay: Duration::ZERO,
+            max_append_batch_bytes: defaults::DEFAULT_MAX_APPEND_BATCH_BYTES,
+            max_append_batch_delay: humantime::parse_duration(
+                defaults::DEFAULT_MAX_APPEND_BATCH_DELAY,
+            )
+            .unwrap(),
+            tcp_keepalive: None,
+            tcp_keepalive_retries: None,
+            tcp_user_timeout: None,
+            wal_receive_idle_timeout: None,
+            replication_reply_timeout: None,
+            replication_write_timeout: None,
+            timeline_eviction_timeout: None,
+            tls: None,
+            allow_direct_tls: false,
         }
     }
 }
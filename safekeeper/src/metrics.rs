@@ -1,16 +1,21 @@
 //! Global safekeeper mertics and per-timeline safekeeper metrics.
 
+use std::collections::HashMap;
 use std::time::{Instant, SystemTime};
 
-use ::metrics::{register_histogram, GaugeVec, Histogram, IntGauge, DISK_WRITE_SECONDS_BUCKETS};
+use ::metrics::{
+    register_counter, register_counter_vec, register_histogram, register_int_counter,
+    register_int_counter_vec, register_int_gauge_vec, Counter, CounterVec, GaugeVec, Histogram,
+    IntCounter, IntCounterVec, IntGauge, DISK_WRITE_SECONDS_BUCKETS,
+};
 use anyhow::Result;
 use metrics::{
     core::{AtomicU64, Collector, Desc, GenericGaugeVec, Opts},
-    proto::MetricFamily,
+    proto::{self, LabelPair, Metric, MetricFamily, MetricType},
     Gauge, IntGaugeVec,
 };
 use once_cell::sync::Lazy;
-use postgres_ffi::XLogSegNo;
+use postgres_ffi::{from_pg_timestamp, TimestampTz, XLogSegNo};
 use utils::{id::TenantTimelineId, lsn::Lsn};
 
 use crate::{
@@ -37,6 +42,14 @@ pub static WRITE_WAL_BYTES: Lazy<Histogram> = Lazy::new(|| {
     )
     .expect("Failed to register safekeeper_write_wal_bytes histogram")
 });
+pub static WAL_RECEIVE_APPEND_BATCH_SIZE: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "safekeeper_wal_receive_append_batch_size",
+        "Number of AppendRequests acknowledged with a single AppendResponse",
+        vec![1.0, 2.0, 4.0, 8.0, 16.0, 64.0, 256.0, 1024.0]
+    )
+    .expect("Failed to register safekeeper_wal_receive_append_batch_size histogram")
+});
 pub static WRITE_WAL_SECONDS: Lazy<Histogram> = Lazy::new(|| {
     register_histogram!(
         "safekeeper_write_wal_seconds",
@@ -62,6 +75,85 @@ pub static PERSIST_CONTROL_FILE_SECONDS: Lazy<Histogram> = Lazy::new(|| {
     .expect("Failed to register safekeeper_persist_control_file_seconds histogram vec")
 });
 
+pub static WAL_SEND_THROTTLED_SECONDS: Lazy<CounterVec> = Lazy::new(|| {
+    register_counter_vec!(
+        "safekeeper_wal_send_throttled_seconds_total",
+        "Seconds WAL senders were delayed by the send rate limits",
+        &["limit"]
+    )
+    .expect("Failed to register safekeeper_wal_send_throttled_seconds_total counter vec")
+});
+
+pub static WAL_RECEIVE_INACTIVE_DISCONNECTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_wal_receive_inactive_disconnects_total",
+        "Walproposer connections closed because they sent no AppendRequest in time"
+    )
+    .expect("Failed to register safekeeper_wal_receive_inactive_disconnects_total counter")
+});
+
+pub static WAL_RECEIVE_THROTTLED_SECONDS: Lazy<Counter> = Lazy::new(|| {
+    register_counter!(
+        "safekeeper_wal_receive_throttled_seconds_total",
+        "Seconds replies to walproposer were delayed because of the safekeeper lag"
+    )
+    .expect("Failed to register safekeeper_wal_receive_throttled_seconds_total counter")
+});
+
+pub static WAL_BACKUP_THROTTLED_SECONDS: Lazy<Counter> = Lazy::new(|| {
+    register_counter!(
+        "safekeeper_wal_backup_throttled_seconds_total",
+        "Seconds WAL uploads were delayed by the upload bandwidth limit"
+    )
+    .expect("Failed to register safekeeper_wal_backup_throttled_seconds_total counter")
+});
+pub static WAL_BACKUP_SCRUB_MISSING_SEGMENTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_wal_backup_scrub_missing_segments_total",
+        "Offloaded WAL segments found missing in remote storage by the scrubber"
+    )
+    .expect("Failed to register safekeeper_wal_backup_scrub_missing_segments_total counter")
+});
+pub static WAL_BACKUP_SCRUB_MISMATCHED_SEGMENTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_wal_backup_scrub_mismatched_segments_total",
+        "Offloaded WAL segments found different from the local copy by the scrubber"
+    )
+    .expect("Failed to register safekeeper_wal_backup_scrub_mismatched_segments_total counter")
+});
+pub static WAL_BACKUP_SCRUB_REPAIRED_SEGMENTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_wal_backup_scrub_repaired_segments_total",
+        "Offloaded WAL segments uploaded again by the scrubber"
+    )
+    .expect("Failed to register safekeeper_wal_backup_scrub_repaired_segments_total counter")
+});
+pub static TENANT_QUOTA_USAGE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "safekeeper_tenant_quota_usage",
+        "Tenant resources limited by quotas, as of the last START_WAL_PUSH",
+        &["tenant_id", "quota"]
+    )
+    .expect("Failed to register safekeeper_tenant_quota_usage gauge vec")
+});
+pub static TENANT_QUOTA_REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "safekeeper_tenant_quota_rejections_total",
+        "START_WAL_PUSH commands refused because the tenant exceeded a quota",
+        &["quota"]
+    )
+    .expect("Failed to register safekeeper_tenant_quota_rejections_total counter")
+});
+
+pub static PG_IO_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "safekeeper_pg_io_bytes_total",
+        "Bytes read from and written to postgres protocol connections",
+        &["direction"]
+    )
+    .expect("Failed to register safekeeper_pg_io_bytes_total counter")
+});
+
 /// Metrics for WalStorage in a single timeline.
 #[derive(Clone, Default)]
 pub struct WalStorageMetrics {
@@ -71,6 +163,10 @@ pub struct WalStorageMetrics {
     write_wal_seconds: f64,
     /// How much time spent syncing WAL to disk, waiting for fsync(2).
     flush_wal_seconds: f64,
+    /// Number of fsync(2) calls in each of DISK_WRITE_SECONDS_BUCKETS, not
+    /// cumulative; the ones slower than all buckets are only in the count.
+    flush_wal_buckets: [u64; DISK_WRITE_SECONDS_BUCKETS.len()],
+    flush_wal_count: u64,
 }
 
 impl WalStorageMetrics {
@@ -86,6 +182,13 @@ impl WalStorageMetrics {
 
     pub fn observe_flush_seconds(&mut self, seconds: f64) {
         self.flush_wal_seconds += seconds;
+        if let Some(i) = DISK_WRITE_SECONDS_BUCKETS
+            .iter()
+            .position(|upper_bound| seconds <= *upper_bound)
+        {
+            self.flush_wal_buckets[i] += 1;
+        }
+        self.flush_wal_count += 1;
         FLUSH_WAL_SECONDS.observe(seconds);
     }
 }
@@ -113,6 +216,12 @@ pub struct FullTimelineInfo {
     pub flush_lsn: Lsn,
 
     pub wal_storage: WalStorageMetrics,
+    pub wal_received_bytes: u64,
+    pub wal_sent_bytes: u64,
+
+    /// Commit time of the newest received transaction and lag to it in
+    /// seconds, see `RecordTimeTracker`.
+    pub record_time: (Option<TimestampTz>, Option<f64>),
 }
 
 /// Collects metrics for all active timelines.
@@ -126,6 +235,8 @@ pub struct TimelineCollector {
     remote_consistent_lsn: GenericGaugeVec<AtomicU64>,
     feedback_ps_write_lsn: GenericGaugeVec<AtomicU64>,
     feedback_last_time_seconds: GenericGaugeVec<AtomicU64>,
+    last_record_time_seconds: GaugeVec,
+    record_lag_seconds: GaugeVec,
     timeline_active: GenericGaugeVec<AtomicU64>,
     wal_backup_active: GenericGaugeVec<AtomicU64>,
     connected_computes: IntGaugeVec,
@@ -134,6 +245,10 @@ pub struct TimelineCollector {
     written_wal_bytes: GenericGaugeVec<AtomicU64>,
     written_wal_seconds: GaugeVec,
     flushed_wal_seconds: GaugeVec,
+    flush_wal_seconds_desc: Desc,
+    received_wal_bytes: GenericGaugeVec<AtomicU64>,
+    sent_wal_bytes: GenericGaugeVec<AtomicU64>,
+    connected_replicas: IntGaugeVec,
     collect_timeline_metrics: Gauge,
     timelines_count: IntGauge,
 }
@@ -228,6 +343,26 @@ impl TimelineCollector {
         .unwrap();
         descs.extend(feedback_last_time_seconds.desc().into_iter().cloned());
 
+        let last_record_time_seconds = GaugeVec::new(
+            Opts::new(
+                "safekeeper_last_record_time_seconds",
+                "Commit time of the newest received transaction, as unix timestamp",
+            ),
+            &["tenant_id", "timeline_id"],
+        )
+        .unwrap();
+        descs.extend(last_record_time_seconds.desc().into_iter().cloned());
+
+        let record_lag_seconds = GaugeVec::new(
+            Opts::new(
+                "safekeeper_record_lag_seconds",
+                "Difference between commit times of the newest received transaction and the newest one persisted by pageserver",
+            ),
+            &["tenant_id", "timeline_id"],
+        )
+        .unwrap();
+        descs.extend(record_lag_seconds.desc().into_iter().cloned());
+
         let timeline_active = GenericGaugeVec::new(
             Opts::new(
                 "safekeeper_timeline_active",
@@ -305,6 +440,45 @@ impl TimelineCollector {
         .unwrap();
         descs.extend(flushed_wal_seconds.desc().into_iter().cloned());
 
+        let flush_wal_seconds_desc = Desc::new(
+            "safekeeper_timeline_flush_wal_seconds".to_string(),
+            "Seconds spent in fsync(2) flushing WAL to disk, grouped by timeline".to_string(),
+            vec!["tenant_id".to_string(), "timeline_id".to_string()],
+            HashMap::new(),
+        )
+        .unwrap();
+        descs.push(flush_wal_seconds_desc.clone());
+
+        let received_wal_bytes = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_received_wal_bytes_total",
+                "Number of WAL bytes received from walproposers, grouped by timeline",
+            ),
+            &["tenant_id", "timeline_id"],
+        )
+        .unwrap();
+        descs.extend(received_wal_bytes.desc().into_iter().cloned());
+
+        let sent_wal_bytes = GenericGaugeVec::new(
+            Opts::new(
+                "safekeeper_sent_wal_bytes_total",
+                "Number of WAL bytes sent to replicas before compression, grouped by timeline",
+            ),
+            &["tenant_id", "timeline_id"],
+        )
+        .unwrap();
+        descs.extend(sent_wal_bytes.desc().into_iter().cloned());
+
+        let connected_replicas = IntGaugeVec::new(
+            Opts::new(
+                "safekeeper_connected_replicas",
+                "Number of active replication connections, e.g. from pageservers",
+            ),
+            &["tenant_id", "timeline_id"],
+        )
+        .unwrap();
+        descs.extend(connected_replicas.desc().into_iter().cloned());
+
         let collect_timeline_metrics = Gauge::new(
             "safekeeper_collect_timeline_metrics_seconds",
             "Time spent collecting timeline metrics, including obtaining mutex lock for all timelines",
@@ -329,6 +503,8 @@ impl TimelineCollector {
             remote_consistent_lsn,
             feedback_ps_write_lsn,
             feedback_last_time_seconds,
+            last_record_time_seconds,
+            record_lag_seconds,
             timeline_active,
             wal_backup_active,
             connected_computes,
@@ -337,6 +513,10 @@ impl TimelineCollector {
             written_wal_bytes,
             written_wal_seconds,
             flushed_wal_seconds,
+            flush_wal_seconds_desc,
+            received_wal_bytes,
+            sent_wal_bytes,
+            connected_replicas,
             collect_timeline_metrics,
             timelines_count,
         }
@@ -360,6 +540,8 @@ impl Collector for TimelineCollector {
         self.remote_consistent_lsn.reset();
         self.feedback_ps_write_lsn.reset();
         self.feedback_last_time_seconds.reset();
+        self.last_record_time_seconds.reset();
+        self.record_lag_seconds.reset();
         self.timeline_active.reset();
         self.wal_backup_active.reset();
         self.connected_computes.reset();
@@ -368,6 +550,14 @@ impl Collector for TimelineCollector {
         self.written_wal_bytes.reset();
         self.written_wal_seconds.reset();
         self.flushed_wal_seconds.reset();
+        self.received_wal_bytes.reset();
+        self.sent_wal_bytes.reset();
+        self.connected_replicas.reset();
+
+        let mut flush_wal_seconds = MetricFamily::default();
+        flush_wal_seconds.set_name(self.flush_wal_seconds_desc.fq_name.clone());
+        flush_wal_seconds.set_help(self.flush_wal_seconds_desc.help.clone());
+        flush_wal_seconds.set_field_type(MetricType::HISTOGRAM);
 
         let timelines = GlobalTimelines::get_all();
         let timelines_count = timelines.len();
@@ -435,6 +625,18 @@ impl Collector for TimelineCollector {
             self.flushed_wal_seconds
                 .with_label_values(labels)
                 .set(tli.wal_storage.flush_wal_seconds);
+            self.received_wal_bytes
+                .with_label_values(labels)
+                .set(tli.wal_received_bytes);
+            self.sent_wal_bytes
+                .with_label_values(labels)
+                .set(tli.wal_sent_bytes);
+            self.connected_replicas
+                .with_label_values(labels)
+                .set(tli.replicas.len() as i64);
+            flush_wal_seconds
+                .mut_metric()
+                .push(flush_wal_histogram(labels, &tli.wal_storage));
 
             if let Some(feedback) = most_advanced {
                 self.feedback_ps_write_lsn
@@ -448,6 +650,19 @@ impl Collector for TimelineCollector {
                 }
             }
 
+            let (last_record_time, record_lag) = tli.record_time;
+            if let Some(ts) = last_record_time {
+                if let Ok(unix_time) = from_pg_timestamp(ts).duration_since(SystemTime::UNIX_EPOCH)
+                {
+                    self.last_record_time_seconds
+                        .with_label_values(labels)
+                        .set(unix_time.as_secs_f64());
+                }
+            }
+            if let Some(lag) = record_lag {
+                self.record_lag_seconds.with_label_values(labels).set(lag);
+            }
+
             if tli.last_removed_segno != 0 {
                 let segno_count = tli
                     .flush_lsn
@@ -470,6 +685,8 @@ impl Collector for TimelineCollector {
         mfs.extend(self.remote_consistent_lsn.collect());
         mfs.extend(self.feedback_ps_write_lsn.collect());
         mfs.extend(self.feedback_last_time_seconds.collect());
+        mfs.extend(self.last_record_time_seconds.collect());
+        mfs.extend(self.record_lag_seconds.collect());
         mfs.extend(self.timeline_active.collect());
         mfs.extend(self.wal_backup_active.collect());
         mfs.extend(self.connected_computes.collect());
@@ -478,6 +695,12 @@ impl Collector for TimelineCollector {
         mfs.extend(self.written_wal_bytes.collect());
         mfs.extend(self.written_wal_seconds.collect());
         mfs.extend(self.flushed_wal_seconds.collect());
+        mfs.extend(self.received_wal_bytes.collect());
+        mfs.extend(self.sent_wal_bytes.collect());
+        mfs.extend(self.connected_replicas.collect());
+        if !flush_wal_seconds.get_metric().is_empty() {
+            mfs.push(flush_wal_seconds);
+        }
 
         // report time it took to collect all info
         let elapsed = start_collecting.elapsed().as_secs_f64();
@@ -491,3 +714,58 @@ impl Collector for TimelineCollector {
         mfs
     }
 }
+
+/// Build histogram of timeline fsync(2) durations; they are accumulated in the
+/// timeline, so the histogram can't be kept in a vec reset on each collection.
+fn flush_wal_histogram(labels: &[&str; 2], metrics: &WalStorageMetrics) -> Metric {
+    let mut histogram = proto::Histogram::default();
+    histogram.set_sample_count(metrics.flush_wal_count);
+    histogram.set_sample_sum(metrics.flush_wal_seconds);
+    let mut cumulative_count = 0;
+    for (upper_bound, count) in DISK_WRITE_SECONDS_BUCKETS
+        .iter()
+        .zip(metrics.flush_wal_buckets.iter())
+    {
+        cumulative_count += count;
+        let mut bucket = proto::Bucket::default();
+        bucket.set_upper_bound(*upper_bound);
+        bucket.set_cumulative_count(cumulative_count);
+        histogram.mut_bucket().push(bucket);
+    }
+
+    let mut metric = Metric::default();
+    for (name, value) in ["tenant_id", "timeline_id"].iter().zip(labels.iter()) {
+        let mut label = LabelPair::default();
+        label.set_name(name.to_string());
+        label.set_value(value.to_string());
+        metric.mut_label().push(label);
+    }
+    metric.set_histogram(histogram);
+    metric
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flush_wal_histogram() {
+        let mut metrics = WalStorageMetrics::default();
+        metrics.observe_flush_seconds(0.000_010);
+        metrics.observe_flush_seconds(0.002);
+        metrics.observe_flush_seconds(0.004);
+        metrics.observe_flush_seconds(10.0);
+
+        let metric = flush_wal_histogram(&["tenant", "timeline"], &metrics);
+        assert_eq!(metric.get_label().len(), 2);
+        let histogram = metric.get_histogram();
+        assert_eq!(histogram.get_sample_count(), 4);
+        let counts: Vec<u64> = histogram
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_cumulative_count())
+            .collect();
+        // buckets are 50us, 100us, 500us, 1ms, 3ms, 5ms, ...
+        assert_eq!(counts, vec![1, 1, 1, 1, 2, 3, 3, 3, 3, 3, 3]);
+    }
+}
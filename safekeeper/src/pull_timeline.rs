@@ -0,0 +1,415 @@
+//! Bootstrap a timeline on this safekeeper by copying it from a peer, e.g.
+//! when replacing a dead safekeeper node.
+//!
+//! The donor lists the files of the timeline directory and serves them over
+//! HTTP. The control file is fetched first, so that the WAL fetched after it
+//! covers everything the control file refers to. The pulled timeline is
+//! accepted only if the control file CRC and term history are fine, and WAL
+//! records pass CRC checks up to commit_lsn of the control file. After that
+//! the timeline is loaded as usual and joins the quorum once compute
+//! connects to it.
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tracing::*;
+use utils::id::{TenantId, TenantTimelineId, TimelineId};
+use utils::lsn::Lsn;
+
+use crate::control_file::{FileStorage, CONTROL_FILE_NAME};
+use crate::safekeeper::{SafeKeeperState, Term};
+use crate::wal_storage::is_wal_segment_file;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// Suffix of the directory where the timeline is downloaded to before it is
+/// verified. Such directories are not recognized as timelines on startup.
+const PULL_TMP_SUFFIX: &str = ".pull";
+
+/// Request to pull the timeline from one of the given safekeepers.
+#[serde_as]
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    #[serde_as(as = "DisplayFromStr")]
+    pub tenant_id: TenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: TimelineId,
+    /// HTTP API addresses of the safekeepers, e.g. "http://sk-1:7676"
+    pub http_hosts: Vec<String>,
+}
+
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct Response {
+    /// Safekeeper the timeline was pulled from
+    pub safekeeper_host: String,
+    #[serde_as(as = "DisplayFromStr")]
+    pub flush_lsn: Lsn,
+}
+
+/// Part of the donor's timeline status we are interested in.
+#[serde_as]
+#[derive(Debug, Deserialize)]
+struct DonorStatus {
+    acceptor_state: DonorAcceptorState,
+    #[serde_as(as = "DisplayFromStr")]
+    flush_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    commit_lsn: Lsn,
+}
+
+#[derive(Debug, Deserialize)]
+struct DonorAcceptorState {
+    term: Term,
+    /// Term of the last WAL record, aka last_log_term.
+    epoch: Term,
+}
+
+/// Whether the file is part of the timeline state to be copied to a peer.
+pub fn is_timeline_file(name: &str) -> bool {
+    name == CONTROL_FILE_NAME || is_wal_segment_file(name)
+}
+
+/// List the files to be copied to a peer pulling the timeline, control file
+/// first.
+pub fn list_timeline_files(timeline_dir: &Path) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(timeline_dir)
+        .with_context(|| format!("failed to list {}", timeline_dir.display()))?
+    {
+        let name = entry?.file_name();
+        match name.to_str() {
+            Some(name) if name != CONTROL_FILE_NAME && is_timeline_file(name) => {
+                files.push(name.to_owned())
+            }
+            _ => {}
+        }
+    }
+    files.sort();
+    files.insert(0, CONTROL_FILE_NAME.to_owned());
+    Ok(files)
+}
+
+/// Pull the timeline from the most advanced of the given safekeepers.
+pub async fn handle_request(conf: &SafeKeeperConf, request: Request) -> Result<Response> {
+    let ttid = TenantTimelineId::new(request.tenant_id, request.timeline_id);
+    if GlobalTimelines::get(ttid).is_ok() {
+        bail!("timeline {} already exists", ttid);
+    }
+
+    let client = reqwest::Client::new();
+    let mut donor: Option<(&str, DonorStatus)> = None;
+    for host in &request.http_hosts {
+        let url = format!(
+            "{}/v1/tenant/{}/timeline/{}",
+            host, ttid.tenant_id, ttid.timeline_id
+        );
+        let status = match fetch_status(&client, &url).await {
+            Ok(status) => status,
+            Err(e) => {
+                warn!(
+                    "failed to get timeline {} status from {}: {:?}",
+                    ttid, host, e
+                );
+                continue;
+            }
+        };
+        // Like in elections, the most advanced log is the one with the
+        // highest (last_log_term, flush_lsn); the term voted for doesn't
+        // tell anything about the WAL.
+        let better = match &donor {
+            Some((_, best)) => {
+                (status.acceptor_state.epoch, status.flush_lsn)
+                    > (best.acceptor_state.epoch, best.flush_lsn)
+            }
+            None => true,
+        };
+        if better {
+            donor = Some((host, status));
+        }
+    }
+    let (host, status) = donor.context("no safekeeper to pull the timeline from")?;
+    info!(
+        "pulling timeline {} from {}, last_log_term {}, flush_lsn {}, commit_lsn {}",
+        ttid, host, status.acceptor_state.epoch, status.flush_lsn, status.commit_lsn
+    );
+
+    let timeline_dir = conf.timeline_dir(&ttid);
+    ensure!(
+        !timeline_dir.exists(),
+        "timeline directory {} already exists",
+        timeline_dir.display()
+    );
+    let tmp_dir = tmp_timeline_dir(&timeline_dir);
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).await?;
+    }
+    fs::create_dir_all(&tmp_dir).await?;
+
+    let res = download_timeline(&client, host, &ttid, &tmp_dir, &status).await;
+    if let Err(e) = res {
+        let _ = fs::remove_dir_all(&tmp_dir).await;
+        return Err(e);
+    }
+    fs::rename(&tmp_dir, &timeline_dir).await?;
+    if !conf.no_sync {
+        fs::File::open(conf.tenant_dir(&ttid.tenant_id))
+            .await?
+            .sync_all()
+            .await?;
+    }
+
+    let tli = tokio::task::spawn_blocking(move || GlobalTimelines::load_timeline(ttid))
+        .await
+        .context("timeline load task panicked")?;
+    let tli = match tli {
+        Ok(tli) => tli,
+        Err(e) => {
+            // Don't leave behind a timeline we failed to load, so that pull
+            // can be retried.
+            let _ = fs::remove_dir_all(&timeline_dir).await;
+            return Err(e.context("failed to load pulled timeline"));
+        }
+    };
+    let flush_lsn = tli.get_flush_lsn();
+
+    info!("pulled timeline {} up to {}", ttid, flush_lsn);
+    Ok(Response {
+        safekeeper_host: host.to_owned(),
+        flush_lsn,
+    })
+}
+
+fn tmp_timeline_dir(timeline_dir: &Path) -> PathBuf {
+    let mut name = timeline_dir.as_os_str().to_owned();
+    name.push(PULL_TMP_SUFFIX);
+    PathBuf::from(name)
+}
+
+async fn fetch_status(client: &reqwest::Client, url: &str) -> Result<DonorStatus> {
+    let status = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<DonorStatus>()
+        .await?;
+    Ok(status)
+}
+
+/// Download the files of the timeline into `dir`, and check the control file.
+async fn download_timeline(
+    client: &reqwest::Client,
+    host: &str,
+    ttid: &TenantTimelineId,
+    dir: &Path,
+    status: &DonorStatus,
+) -> Result<()> {
+    let base_url = format!(
+        "{}/v1/tenant/{}/timeline/{}/file",
+        host, ttid.tenant_id, ttid.timeline_id
+    );
+    let files = client
+        .get(&base_url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Vec<String>>()
+        .await
+        .context("failed to list timeline files")?;
+    ensure!(
+        files.first().map(String::as_str) == Some(CONTROL_FILE_NAME),
+        "donor didn't provide the control file first"
+    );
+
+    for name in &files {
+        // don't let the donor write outside of the timeline directory
+        ensure!(is_timeline_file(name), "unexpected file {:?}", name);
+
+        let mut response = client
+            .get(format!("{}/{}", base_url, name))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("failed to download {}", name))?;
+        let mut file = fs::File::create(dir.join(name)).await?;
+        while let Some(chunk) = response.chunk().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.sync_all().await?;
+    }
+    info!("downloaded {} files of timeline {}", files.len(), ttid);
+
+    let state = FileStorage::load_control_file(dir.join(CONTROL_FILE_NAME))?;
+    check_control_file(&state, ttid, status)?;
+    check_end_of_wal(&state, dir)
+}
+
+/// Check that the WAL is intact up to commit_lsn of the control file. This
+/// validates the CRCs of all records after it as well.
+pub fn check_end_of_wal(state: &SafeKeeperState, dir: &Path) -> Result<()> {
+    if state.commit_lsn == Lsn(0) {
+        return Ok(());
+    }
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    let pg_version = state.server.pg_version / 10000;
+    let start_lsn = find_record_start(state, dir)?;
+    let end_of_wal = postgres_ffi::find_end_of_wal(dir, wal_seg_size, start_lsn, pg_version)?;
+    ensure!(
+        end_of_wal >= state.commit_lsn,
+        "pulled WAL ends at {}, before commit_lsn {}",
+        end_of_wal,
+        state.commit_lsn
+    );
+    Ok(())
+}
+
+/// Find a record start at or before commit_lsn to decode the WAL from: the
+/// first record of the segment holding commit_lsn, or of an earlier one if no
+/// record starts in it before commit_lsn. local_start_lsn is a known record
+/// start in the first segment of the local WAL.
+fn find_record_start(state: &SafeKeeperState, dir: &Path) -> Result<Lsn> {
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    let first_segno = state.local_start_lsn.segment_number(wal_seg_size);
+    // commit_lsn may point exactly at the start of a segment not written yet.
+    let commit_segno = Lsn(state.commit_lsn.0 - 1).segment_number(wal_seg_size);
+    for segno in (first_segno + 1..=commit_segno).rev() {
+        let record_start = postgres_ffi::find_first_record_in_segment(
+            dir,
+            wal_seg_size,
+            segno,
+            state.server.pg_version / 10000,
+        )?;
+        match record_start {
+            Some(lsn) if lsn <= state.commit_lsn => return Ok(lsn),
+            _ => {}
+        }
+    }
+    Ok(state.local_start_lsn)
+}
+
+/// Check that the control file belongs to the timeline and is sane.
+fn check_control_file(
+    state: &SafeKeeperState,
+    ttid: &TenantTimelineId,
+    status: &DonorStatus,
+) -> Result<()> {
+    ensure!(
+        state.tenant_id == ttid.tenant_id && state.timeline_id == ttid.timeline_id,
+        "control file belongs to timeline {}/{}",
+        state.tenant_id,
+        state.timeline_id
+    );
+    ensure!(
+        state.acceptor_state.term <= status.acceptor_state.term,
+        "control file term {} is ahead of the reported term {}",
+        state.acceptor_state.term,
+        status.acceptor_state.term
+    );
+
+    // Terms in the history must grow and their start LSNs must not go back,
+    // and the last one can't be ahead of the term voted for.
+    let history = &state.acceptor_state.term_history.0;
+    for pair in history.windows(2) {
+        ensure!(
+            pair[0].term < pair[1].term && pair[0].lsn <= pair[1].lsn,
+            "invalid term history: {:?} followed by {:?}",
+            pair[0],
+            pair[1]
+        );
+    }
+    if let Some(last) = history.last() {
+        ensure!(
+            last.term <= state.acceptor_state.term,
+            "last term {} in the history is ahead of the acceptor term {}",
+            last.term,
+            state.acceptor_state.term
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safekeeper::{TermHistory, TermSwitchEntry};
+    use postgres_ffi::{PG_TLI, WAL_SEGMENT_SIZE};
+
+    #[test]
+    fn test_is_timeline_file() {
+        assert!(is_timeline_file("safekeeper.control"));
+        assert!(is_timeline_file("000000010000000000000001"));
+        assert!(is_timeline_file("000000010000000000000002.partial"));
+        assert!(!is_timeline_file("safekeeper.control.partial"));
+        assert!(!is_timeline_file("../safekeeper.control"));
+        assert!(!is_timeline_file("../../000000010000000000000001"));
+    }
+
+    #[test]
+    fn test_check_control_file() {
+        let ttid = TenantTimelineId::generate();
+        let mut state = SafeKeeperState::empty();
+        state.tenant_id = ttid.tenant_id;
+        state.timeline_id = ttid.timeline_id;
+        state.acceptor_state.term = 3;
+        state.acceptor_state.term_history = TermHistory(vec![
+            TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(0x100),
+            },
+            TermSwitchEntry {
+                term: 3,
+                lsn: Lsn(0x200),
+            },
+        ]);
+        let status = DonorStatus {
+            acceptor_state: DonorAcceptorState { term: 3, epoch: 3 },
+            flush_lsn: Lsn(0x300),
+            commit_lsn: Lsn(0x300),
+        };
+        check_control_file(&state, &ttid, &status).unwrap();
+
+        // wrong timeline
+        assert!(check_control_file(&state, &TenantTimelineId::generate(), &status).is_err());
+
+        // terms don't grow
+        let mut bad = state.clone();
+        bad.acceptor_state.term_history.0[1].term = 1;
+        assert!(check_control_file(&bad, &ttid, &status).is_err());
+
+        // history goes back in LSN
+        let mut bad = state.clone();
+        bad.acceptor_state.term_history.0[1].lsn = Lsn(0x50);
+        assert!(check_control_file(&bad, &ttid, &status).is_err());
+
+        // history is ahead of the acceptor term
+        let mut bad = state;
+        bad.acceptor_state.term = 2;
+        assert!(check_control_file(&bad, &ttid, &status).is_err());
+    }
+
+    #[test]
+    fn test_check_end_of_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let seg_start = Lsn(WAL_SEGMENT_SIZE as u64);
+        let mut state = SafeKeeperState::empty();
+        state.server.wal_seg_size = WAL_SEGMENT_SIZE as u32;
+        state.server.pg_version = 150000;
+        state.local_start_lsn = seg_start;
+        // Valid page header, but no records after it.
+        let (name, segment) = postgres_ffi::generate_wal_segment(seg_start, 0, PG_TLI, 15).unwrap();
+        std::fs::write(dir.path().join(name), segment).unwrap();
+
+        state.commit_lsn = Lsn(0);
+        check_end_of_wal(&state, dir.path()).unwrap();
+
+        // Nothing decodes up to commit_lsn, so the check must not pass even
+        // though commit_lsn lies within the segment.
+        state.commit_lsn = seg_start + 0x1000;
+        assert!(check_end_of_wal(&state, dir.path()).is_err());
+        state.local_start_lsn = Lsn(0);
+        assert!(check_end_of_wal(&state, dir.path()).is_err());
+    }
+}
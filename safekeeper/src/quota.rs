@@ -0,0 +1,166 @@
+//! Per-tenant limits on resources of the safekeeper. Without them a single
+//! misbehaving tenant can exhaust file descriptors or disk space of the whole
+//! node.
+//!
+//! Quotas are checked when walproposer starts pushing WAL (START_WAL_PUSH),
+//! so a tenant over the limit can't connect more computes or create more
+//! timelines, while the already connected ones keep working.
+
+use anyhow::Result;
+use utils::id::{TenantId, TenantTimelineId};
+
+use crate::connections;
+use crate::metrics::{TENANT_QUOTA_REJECTIONS, TENANT_QUOTA_USAGE};
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// Limits applied to each tenant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TenantQuotas {
+    /// Max number of concurrent Postgres protocol connections.
+    pub max_connections: Option<usize>,
+    /// Max number of timelines.
+    pub max_timelines: Option<usize>,
+    /// Max size of the tenant directory, in bytes.
+    pub max_disk_bytes: Option<u64>,
+}
+
+impl TenantQuotas {
+    pub fn from_conf(conf: &SafeKeeperConf) -> Self {
+        TenantQuotas {
+            max_connections: conf.max_tenant_connections,
+            max_timelines: conf.max_tenant_timelines,
+            max_disk_bytes: conf.max_tenant_disk_bytes,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == TenantQuotas::default()
+    }
+
+    /// Check the usage against the limits, returning the name of the
+    /// exceeded quota along with the error message.
+    fn check(&self, usage: &TenantUsage) -> Result<(), (&'static str, String)> {
+        if let Some(max) = self.max_connections {
+            if usage.connections >= max {
+                return Err((
+                    "connections",
+                    format!(
+                        "tenant has {} connections, limit is {}",
+                        usage.connections, max
+                    ),
+                ));
+            }
+        }
+        if let Some(max) = self.max_timelines {
+            if usage.new_timeline && usage.timelines >= max {
+                return Err((
+                    "timelines",
+                    format!("tenant has {} timelines, limit is {}", usage.timelines, max),
+                ));
+            }
+        }
+        if let Some(max) = self.max_disk_bytes {
+            if usage.disk_bytes >= max {
+                return Err((
+                    "disk_bytes",
+                    format!(
+                        "tenant uses {} bytes of disk, limit is {}",
+                        usage.disk_bytes, max
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Resources used by the tenant.
+#[derive(Debug, Default)]
+struct TenantUsage {
+    connections: usize,
+    timelines: usize,
+    disk_bytes: u64,
+    /// Whether the connection would create a new timeline.
+    new_timeline: bool,
+}
+
+impl TenantUsage {
+    fn get(conf: &SafeKeeperConf, ttid: &TenantTimelineId) -> Result<Self> {
+        let timelines = GlobalTimelines::get_tenant_timelines(&ttid.tenant_id);
+        Ok(TenantUsage {
+            connections: connections::count_tenant(&ttid.tenant_id),
+            new_timeline: !timelines.contains(&ttid.timeline_id),
+            timelines: timelines.len(),
+            disk_bytes: GlobalTimelines::get_tenant_disk_usage(conf, &ttid.tenant_id)?,
+        })
+    }
+
+    fn report(&self, tenant_id: &TenantId) {
+        let tenant_id = tenant_id.to_string();
+        for (quota, value) in [
+            ("connections", self.connections as i64),
+            ("timelines", self.timelines as i64),
+            ("disk_bytes", self.disk_bytes as i64),
+        ] {
+            TENANT_QUOTA_USAGE
+                .with_label_values(&[&tenant_id, quota])
+                .set(value);
+        }
+    }
+}
+
+/// Check that the tenant may start pushing WAL to the timeline.
+pub fn check_wal_push(conf: &SafeKeeperConf, ttid: &TenantTimelineId) -> Result<()> {
+    let quotas = TenantQuotas::from_conf(conf);
+    if quotas.is_empty() {
+        return Ok(());
+    }
+    let usage = TenantUsage::get(conf, ttid)?;
+    usage.report(&ttid.tenant_id);
+    if let Err((quota, msg)) = quotas.check(&usage) {
+        TENANT_QUOTA_REJECTIONS.with_label_values(&[quota]).inc();
+        anyhow::bail!("tenant {} exceeded its quota: {}", ttid.tenant_id, msg);
+    }
+    Ok(())
+}
+
+/// Stop reporting usage of the deleted tenant.
+pub fn forget_tenant(tenant_id: &TenantId) {
+    let tenant_id = tenant_id.to_string();
+    for quota in ["connections", "timelines", "disk_bytes"] {
+        let _ = TENANT_QUOTA_USAGE.remove_label_values(&[&tenant_id, quota]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_quotas() {
+        let quotas = TenantQuotas {
+            max_connections: Some(2),
+            max_timelines: Some(1),
+            max_disk_bytes: None,
+        };
+        let usage = TenantUsage {
+            connections: 1,
+            timelines: 1,
+            disk_bytes: u64::MAX,
+            new_timeline: false,
+        };
+        assert!(quotas.check(&usage).is_ok());
+
+        let usage = TenantUsage {
+            new_timeline: true,
+            ..usage
+        };
+        assert_eq!(quotas.check(&usage).unwrap_err().0, "timelines");
+
+        let usage = TenantUsage {
+            connections: 2,
+            ..usage
+        };
+        assert_eq!(quotas.check(&usage).unwrap_err().0, "connections");
+    }
+}
@@ -0,0 +1,187 @@
+//! Bandwidth limits on WAL sent to replicas, e.g. pageservers. Without them a
+//! single replica catching up on a lot of WAL can saturate the disk and the
+//! network, starving the live compute connections.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::{Lazy, OnceCell};
+use utils::id::TenantId;
+
+use crate::metrics::WAL_SEND_THROTTLED_SECONDS;
+use crate::SafeKeeperConf;
+
+/// Token bucket holding up to one second worth of bytes.
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: u64,
+    state: Mutex<TokenBucketState>,
+}
+
+struct TokenBucketState {
+    /// Negative when the bucket is in debt.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "rate limit must be positive");
+        TokenBucket {
+            rate,
+            state: Mutex::new(TokenBucketState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `amount` bytes from the bucket. The bucket may go into debt, so
+    /// that sends larger than the rate are possible; returns how long the
+    /// caller should wait for the debt to be repaid before sending.
+    pub fn take(&self, amount: u64) -> Duration {
+        self.take_at(amount, Instant::now())
+    }
+
+    fn take_at(&self, amount: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens = f64::min(
+            state.tokens + elapsed.as_secs_f64() * self.rate as f64,
+            self.rate as f64,
+        );
+        state.last_refill = std::cmp::max(state.last_refill, now);
+
+        state.tokens -= amount as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate as f64)
+        }
+    }
+}
+
+static GLOBAL_LIMIT: OnceCell<Option<TokenBucket>> = OnceCell::new();
+/// Buckets of the tenants with WAL senders running, see [`WalSendLimiter`].
+static TENANT_LIMITS: Lazy<Mutex<HashMap<TenantId, Arc<TokenBucket>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Configured global, per-tenant and per-connection limits of one WAL
+/// sender. The tenant bucket is shared by all senders of the tenant, and
+/// dropped along with the last of them.
+pub struct WalSendLimiter {
+    tenant_id: TenantId,
+    tenant: Option<Arc<TokenBucket>>,
+    connection: Option<TokenBucket>,
+}
+
+impl WalSendLimiter {
+    pub fn new(conf: &SafeKeeperConf, tenant_id: TenantId) -> Self {
+        let tenant = conf.max_tenant_send_rate.map(|rate| {
+            TENANT_LIMITS
+                .lock()
+                .unwrap()
+                .entry(tenant_id)
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate)))
+                .clone()
+        });
+        GLOBAL_LIMIT.get_or_init(|| conf.max_send_rate.map(TokenBucket::new));
+        WalSendLimiter {
+            tenant_id,
+            tenant,
+            connection: conf.max_connection_send_rate.map(TokenBucket::new),
+        }
+    }
+
+    /// Account `amount` bytes of WAL about to be sent against the limits, and
+    /// return how long the sender should sleep before sending them.
+    pub fn throttle(&self, amount: u64) -> Duration {
+        let limits = [
+            ("global", GLOBAL_LIMIT.get().and_then(Option::as_ref)),
+            ("tenant", self.tenant.as_deref()),
+            ("connection", self.connection.as_ref()),
+        ];
+        let mut delay = Duration::ZERO;
+        for (limit, bucket) in limits {
+            let Some(bucket) = bucket else {
+                continue;
+            };
+            let limit_delay = bucket.take(amount);
+            if !limit_delay.is_zero() {
+                WAL_SEND_THROTTLED_SECONDS
+                    .with_label_values(&[limit])
+                    .inc_by(limit_delay.as_secs_f64());
+            }
+            delay = std::cmp::max(delay, limit_delay);
+        }
+        delay
+    }
+}
+
+impl Drop for WalSendLimiter {
+    fn drop(&mut self) {
+        let Some(bucket) = self.tenant.take() else {
+            return;
+        };
+        let mut limits = TENANT_LIMITS.lock().unwrap();
+        // New senders clone the bucket under the lock, so nobody else can
+        // pick it up once only the map and this sender hold it.
+        if let Some(shared) = limits.get(&self.tenant_id) {
+            if Arc::ptr_eq(shared, &bucket) && Arc::strong_count(&bucket) == 2 {
+                limits.remove(&self.tenant_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().unwrap().last_refill;
+
+        // Full bucket allows a burst of one second worth of bytes
+        assert_eq!(bucket.take_at(600, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(400, start), Duration::ZERO);
+        // then goes into debt
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+
+        // Debt is repaid over time
+        let later = start + Duration::from_millis(500);
+        assert_eq!(bucket.take_at(0, later), Duration::ZERO);
+        assert_eq!(bucket.take_at(100, later), Duration::from_millis(100));
+
+        // Idle time doesn't accumulate more than the bucket holds
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(bucket.take_at(1000, much_later), Duration::ZERO);
+        assert_eq!(bucket.take_at(1, much_later), Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_tenant_limits_pruned() {
+        let mut conf = SafeKeeperConf::dummy();
+        conf.max_tenant_send_rate = Some(1000);
+        conf.max_connection_send_rate = Some(100);
+        let tenant_id = TenantId::generate();
+
+        let first = WalSendLimiter::new(&conf, tenant_id);
+        let second = WalSendLimiter::new(&conf, tenant_id);
+        assert!(Arc::ptr_eq(
+            first.tenant.as_ref().unwrap(),
+            second.tenant.as_ref().unwrap()
+        ));
+
+        // The connection limit applies to each sender on its own
+        assert!(first.throttle(150) > Duration::from_millis(400));
+        assert_eq!(second.throttle(50), Duration::ZERO);
+
+        drop(first);
+        assert!(TENANT_LIMITS.lock().unwrap().contains_key(&tenant_id));
+        drop(second);
+        assert!(!TENANT_LIMITS.lock().unwrap().contains_key(&tenant_id));
+    }
+}
@@ -7,32 +7,50 @@ use anyhow::Context;
 
 use bytes::BytesMut;
 use tracing::*;
-use utils::lsn::Lsn;
-use utils::postgres_backend_async::QueryError;
+use utils::cancel_registry::CancellationToken;
+use utils::postgres_backend_async::{CopyMode, QueryError};
 
-use crate::safekeeper::ServerInfo;
+use crate::safekeeper::{ServerInfo, TimelineCreateParams};
 use crate::timeline::Timeline;
 use crate::GlobalTimelines;
 
 use std::net::SocketAddr;
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::safekeeper::AcceptorProposerMessage;
 use crate::safekeeper::ProposerAcceptorMessage;
+use crate::safekeeper::{needs_flush_reply, negotiate_features, negotiate_protocol_version};
 
+use crate::connections::{self, ConnectionKind};
+use crate::drain;
 use crate::handler::SafekeeperPostgresHandler;
+use crate::metrics::{
+    WAL_RECEIVE_APPEND_BATCH_SIZE, WAL_RECEIVE_INACTIVE_DISCONNECTS, WAL_RECEIVE_THROTTLED_SECONDS,
+};
+use crate::SafeKeeperConf;
 use pq_proto::{BeMessage, FeMessage};
-use utils::{postgres_backend::PostgresBackend, sock_split::ReadStream};
+use utils::{buffer_pool::BufferConfig, postgres_backend::PostgresBackend, sock_split::ReadStream};
+
+/// While the safekeeper lags, replies to walproposer are delayed, re-checking
+/// the lag at this interval.
+const BACKPRESSURE_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+/// Maximum delay of a single reply, so that walproposer doesn't consider the
+/// connection dead.
+const BACKPRESSURE_MAX_DELAY: Duration = Duration::from_secs(1);
 
 pub struct ReceiveWalConn<'pg> {
     /// Postgres connection
     pg_backend: &'pg mut PostgresBackend,
     /// The cached result of `pg_backend.socket().peer_addr()` (roughly)
     peer_addr: SocketAddr,
+    /// Protocol features negotiated in the greeting.
+    features: u64,
 }
 
 impl<'pg> ReceiveWalConn<'pg> {
@@ -41,30 +59,45 @@ impl<'pg> ReceiveWalConn<'pg> {
         ReceiveWalConn {
             pg_backend: pg,
             peer_addr,
+            features: 0,
         }
     }
 
     // Send message to the postgres
     fn write_msg(&mut self, msg: &AcceptorProposerMessage) -> anyhow::Result<()> {
+        fail::fail_point!("sk-send-response", |_| anyhow::bail!(
+            "failpoint sk-send-response"
+        ));
         let mut buf = BytesMut::with_capacity(128);
-        msg.serialize(&mut buf)?;
+        msg.serialize(&mut buf, self.features)?;
+        if let AcceptorProposerMessage::Greeting(greeting) = msg {
+            self.features = greeting.features;
+        }
         self.pg_backend.write_message(&BeMessage::CopyData(&buf))?;
         Ok(())
     }
 
     /// Receive WAL from wal_proposer
-    pub fn run(&mut self, spg: &mut SafekeeperPostgresHandler) -> Result<(), QueryError> {
+    pub fn run(
+        &mut self,
+        spg: &mut SafekeeperPostgresHandler,
+        cancel: &CancellationToken,
+    ) -> Result<(), QueryError> {
         let _enter = info_span!("WAL acceptor", ttid = %spg.ttid).entered();
 
+        // The connection streams WAL from now on. The walproposer greeting
+        // may already be buffered, it is kept by the resize.
+        self.pg_backend.set_buffer_config(BufferConfig::LARGE);
+
         // Notify the libpq client that it's allowed to send `CopyData` messages
-        self.pg_backend
-            .write_message(&BeMessage::CopyBothResponse)?;
+        self.pg_backend.start_copy(CopyMode::Both)?;
 
         let r = self
             .pg_backend
             .take_stream_in()
             .ok_or_else(|| anyhow!("failed to take read stream from pgbackend"))?;
-        let mut poll_reader = ProposerPollStream::new(r)?;
+        let max_message_len = self.pg_backend.message_limits.max_message_len;
+        let mut poll_reader = ProposerPollStream::new(r, max_message_len)?;
 
         // Receive information about server
         let next_msg = poll_reader.recv_msg()?;
@@ -79,7 +112,8 @@ impl<'pg> ReceiveWalConn<'pg> {
                     system_id: greeting.system_id,
                     wal_seg_size: greeting.wal_seg_size,
                 };
-                GlobalTimelines::create(spg.ttid, server_info, Lsn::INVALID, Lsn::INVALID)?
+                let params = TimelineCreateParams::uninitialized(server_info)?;
+                GlobalTimelines::create(spg.ttid, params)?
             }
             _ => {
                 return Err(QueryError::Other(anyhow::anyhow!(
@@ -89,14 +123,36 @@ impl<'pg> ReceiveWalConn<'pg> {
         };
 
         let mut next_msg = Some(next_msg);
+        let conn = connections::register(
+            spg.ttid,
+            ConnectionKind::Push,
+            spg.appname.clone(),
+            self.peer_addr,
+            spg.negotiated_options(),
+            tli.get_flush_lsn(),
+        );
 
         let mut first_time_through = true;
         let mut _guard: Option<ComputeConnectionGuard> = None;
+        // idle timeout is counted from the last AppendRequest
+        let mut last_append_at = Instant::now();
         loop {
             if matches!(next_msg, Some(ProposerAcceptorMessage::AppendRequest(_))) {
-                // poll AppendRequest's without blocking and write WAL to disk without flushing,
-                // while it's readily available
+                last_append_at = Instant::now();
+                // Pipeline AppendRequest's: while WAL is readily available or arrives before the
+                // flush deadline, write it to disk without flushing and without replying. The
+                // whole batch is then flushed and acknowledged with a single AppendResponse.
+                let batch_start = Instant::now();
+                let flush_deadline = batch_start + spg.conf.wal_flush_max_delay;
+                let mut batch_bytes = 0;
+                let mut batch_size = 0;
                 while let Some(ProposerAcceptorMessage::AppendRequest(append_request)) = next_msg {
+                    batch_bytes += append_request.wal_data.len() as u64;
+                    batch_size += 1;
+                    conn.observe(
+                        append_request.h.end_lsn,
+                        append_request.wal_data.len() as u64,
+                    );
                     let msg = ProposerAcceptorMessage::NoFlushAppendRequest(append_request);
 
                     let reply = tli.process_msg(&msg)?;
@@ -104,12 +160,28 @@ impl<'pg> ReceiveWalConn<'pg> {
                         self.write_msg(&reply)?;
                     }
 
+                    // ack regularly even if walproposer streams WAL continuously,
+                    // and don't let it grow unflushed WAL indefinitely
+                    if batch_bytes >= spg.conf.max_append_batch_bytes
+                        || batch_start.elapsed() >= spg.conf.max_append_batch_delay
+                        || tli.get_backpressure_state(&spg.conf).unflushed_wal_bytes
+                            > spg.conf.max_unflushed_wal_bytes.unwrap_or(u64::MAX)
+                    {
+                        next_msg = None;
+                        break;
+                    }
                     next_msg = poll_reader.poll_msg();
+                    if next_msg.is_none() {
+                        // group commit: let more WAL share the flush
+                        next_msg = poll_reader.poll_msg_until(flush_deadline);
+                    }
                 }
 
                 // flush all written WAL to the disk
+                WAL_RECEIVE_APPEND_BATCH_SIZE.observe(batch_size as f64);
                 let reply = tli.process_msg(&ProposerAcceptorMessage::FlushWAL)?;
                 if let Some(reply) = reply {
+                    wait_for_backpressure(&tli, &spg.conf);
                     self.write_msg(&reply)?;
                 }
             } else if let Some(msg) = next_msg.take() {
@@ -118,6 +190,12 @@ impl<'pg> ReceiveWalConn<'pg> {
                 if let Some(reply) = reply {
                     self.write_msg(&reply)?;
                 }
+                if needs_flush_reply(&msg, self.features) {
+                    let reply = tli.process_msg(&ProposerAcceptorMessage::FlushWAL)?;
+                    if let Some(reply) = reply {
+                        self.write_msg(&reply)?;
+                    }
+                }
             }
             if first_time_through {
                 // Register the connection and defer unregister. Do that only
@@ -130,28 +208,71 @@ impl<'pg> ReceiveWalConn<'pg> {
                 first_time_through = false;
             }
 
-            // blocking wait for the next message
-            if next_msg.is_none() {
-                next_msg = Some(poll_reader.recv_msg()?);
+            // blocking wait for the next message, unless we are shutting down
+            // or walproposer sends no WAL for too long
+            while next_msg.is_none() {
+                if drain::is_draining() {
+                    return Err(QueryError::Other(anyhow!("safekeeper is shutting down")));
+                }
+                if cancel.is_cancelled() {
+                    return Err(QueryError::Cancelled);
+                }
+                if let Some(timeout) = spg.conf.wal_receive_idle_timeout {
+                    if last_append_at.elapsed() >= timeout {
+                        // dropping the connection guard lets the timeline
+                        // become inactive
+                        WAL_RECEIVE_INACTIVE_DISCONNECTS.inc();
+                        warn!(
+                            "closing connection from {}: no AppendRequest for {:?}",
+                            self.peer_addr, timeout
+                        );
+                        return Err(QueryError::from(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            format!("walproposer sent no AppendRequest for {timeout:?}"),
+                        )));
+                    }
+                }
+                next_msg = poll_reader.recv_msg_timeout(drain::DRAIN_CHECK_INTERVAL)?;
             }
         }
     }
 }
 
+/// Delay the reply to walproposer while the timeline lags too much, which
+/// makes walproposer slow down commits.
+fn wait_for_backpressure(tli: &Timeline, conf: &SafeKeeperConf) {
+    let started_at = Instant::now();
+    loop {
+        let state = tli.get_backpressure_state(conf);
+        if !state.throttling || started_at.elapsed() >= BACKPRESSURE_MAX_DELAY {
+            break;
+        }
+        trace!("delaying reply to walproposer: {:?}", state);
+        thread::sleep(BACKPRESSURE_CHECK_INTERVAL);
+    }
+    let delay = started_at.elapsed();
+    if delay >= BACKPRESSURE_CHECK_INTERVAL {
+        WAL_RECEIVE_THROTTLED_SECONDS.inc_by(delay.as_secs_f64());
+    }
+}
+
 struct ProposerPollStream {
     msg_rx: Receiver<ProposerAcceptorMessage>,
     read_thread: Option<thread::JoinHandle<Result<(), QueryError>>>,
 }
 
 impl ProposerPollStream {
-    fn new(mut r: ReadStream) -> anyhow::Result<Self> {
+    fn new(mut r: ReadStream, max_message_len: usize) -> anyhow::Result<Self> {
         let (msg_tx, msg_rx) = channel();
 
         let read_thread = thread::Builder::new()
             .name("Read WAL thread".into())
             .spawn(move || -> Result<(), QueryError> {
+                // messages are parsed with the features negotiated in the
+                // greeting
+                let mut features = 0;
                 loop {
-                    let copy_data = match FeMessage::read(&mut r)? {
+                    let copy_data = match FeMessage::read_limited(&mut r, max_message_len)? {
                         Some(FeMessage::CopyData(bytes)) => Ok(bytes),
                         Some(msg) => Err(QueryError::Other(anyhow::anyhow!(
                             "expected `CopyData` message, found {msg:?}"
@@ -162,7 +283,11 @@ impl ProposerPollStream {
                         ))),
                     }?;
 
-                    let msg = ProposerAcceptorMessage::parse(copy_data)?;
+                    let msg = ProposerAcceptorMessage::parse(copy_data, features)?;
+                    if let ProposerAcceptorMessage::Greeting(ref greeting) = msg {
+                        negotiate_protocol_version(greeting.protocol_version)?;
+                        features = negotiate_features(greeting.features);
+                    }
                     msg_tx
                         .send(msg)
                         .context("Failed to send the proposer message")?;
@@ -177,21 +302,33 @@ impl ProposerPollStream {
     }
 
     fn recv_msg(&mut self) -> Result<ProposerAcceptorMessage, QueryError> {
-        self.msg_rx.recv().map_err(|_| {
-            // return error from the read thread
-            let res = match self.read_thread.take() {
-                Some(thread) => thread.join(),
-                None => return QueryError::Other(anyhow::anyhow!("read thread is gone")),
-            };
-
-            match res {
-                Ok(Ok(())) => {
-                    QueryError::Other(anyhow::anyhow!("unexpected result from read thread"))
-                }
-                Err(err) => QueryError::Other(anyhow::anyhow!("read thread panicked: {err:?}")),
-                Ok(Err(err)) => err,
-            }
-        })
+        self.msg_rx.recv().map_err(|_| self.read_thread_error())
+    }
+
+    /// Like recv_msg, but returns None if no message arrives within timeout.
+    fn recv_msg_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<ProposerAcceptorMessage>, QueryError> {
+        match self.msg_rx.recv_timeout(timeout) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(self.read_thread_error()),
+        }
+    }
+
+    /// Get the error the read thread exited with.
+    fn read_thread_error(&mut self) -> QueryError {
+        let res = match self.read_thread.take() {
+            Some(thread) => thread.join(),
+            None => return QueryError::Other(anyhow::anyhow!("read thread is gone")),
+        };
+
+        match res {
+            Ok(Ok(())) => QueryError::Other(anyhow::anyhow!("unexpected result from read thread")),
+            Err(err) => QueryError::Other(anyhow::anyhow!("read thread panicked: {err:?}")),
+            Ok(Err(err)) => err,
+        }
     }
 
     fn poll_msg(&mut self) -> Option<ProposerAcceptorMessage> {
@@ -202,6 +339,15 @@ impl ProposerPollStream {
             Ok(msg) => Some(msg),
         }
     }
+
+    /// Like poll_msg, but waits for the message until the deadline.
+    fn poll_msg_until(&mut self, deadline: Instant) -> Option<ProposerAcceptorMessage> {
+        let now = Instant::now();
+        if now >= deadline {
+            return None;
+        }
+        self.msg_rx.recv_timeout(deadline - now).ok()
+    }
 }
 
 struct ComputeConnectionGuard {
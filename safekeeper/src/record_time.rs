@@ -0,0 +1,105 @@
+//! Tracking of commit timestamps in the received WAL, to report lag in
+//! seconds rather than bytes which are meaningless to most users.
+
+use std::collections::VecDeque;
+
+use postgres_ffi::TimestampTz;
+use utils::lsn::Lsn;
+
+/// Max number of remembered (lsn, commit time) points used to compute lag.
+const MAX_SAMPLES: usize = 1024;
+/// Min distance between remembered points in commit time, microseconds.
+const SAMPLE_INTERVAL_USECS: i64 = 1_000_000;
+
+/// Remembers commit time of the newest transaction and a sparse history of
+/// commit times to tell how far behind it a consumer at some LSN is. Commits
+/// are found by the WAL storage while it decodes received WAL, see
+/// `Storage::take_commit_times`.
+#[derive(Default)]
+pub struct RecordTimeTracker {
+    /// End LSN and commit time of commit records, oldest first.
+    samples: VecDeque<(Lsn, TimestampTz)>,
+    last_record: Option<(Lsn, TimestampTz)>,
+}
+
+impl RecordTimeTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget commits past `lsn` after WAL is truncated there.
+    pub fn reset(&mut self, lsn: Lsn) {
+        while matches!(self.samples.back(), Some((end_lsn, _)) if *end_lsn > lsn) {
+            self.samples.pop_back();
+        }
+        if matches!(self.last_record, Some((end_lsn, _)) if end_lsn > lsn) {
+            self.last_record = self.samples.back().copied();
+        }
+    }
+
+    /// Remember commit of the transaction ending at `end_lsn`.
+    pub fn observe_commit(&mut self, end_lsn: Lsn, ts: TimestampTz) {
+        self.last_record = Some((end_lsn, ts));
+        let sample = match self.samples.back() {
+            Some((_, last_ts)) => ts - last_ts >= SAMPLE_INTERVAL_USECS,
+            None => true,
+        };
+        if sample {
+            if self.samples.len() == MAX_SAMPLES {
+                self.samples.pop_front();
+            }
+            self.samples.push_back((end_lsn, ts));
+        }
+    }
+
+    /// Commit time of the newest transaction received.
+    pub fn last_record_time(&self) -> Option<TimestampTz> {
+        self.last_record.map(|(_, ts)| ts)
+    }
+
+    /// How far behind the newest commit a consumer which has processed WAL
+    /// up to `lsn` is, in seconds. If `lsn` is older than all remembered
+    /// commits, the result is a lower bound.
+    pub fn lag_seconds(&self, lsn: Lsn) -> Option<f64> {
+        let (last_lsn, last_ts) = self.last_record?;
+        if lsn >= last_lsn {
+            return Some(0.0);
+        }
+        let consumed_ts = self
+            .samples
+            .iter()
+            .rev()
+            .find(|(end_lsn, _)| *end_lsn <= lsn)
+            .or_else(|| self.samples.front())
+            .map_or(last_ts, |(_, ts)| *ts);
+        Some((last_ts - consumed_ts).max(0) as f64 / 1_000_000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lag_seconds() {
+        let mut tracker = RecordTimeTracker::new();
+        assert_eq!(tracker.last_record_time(), None);
+        assert_eq!(tracker.lag_seconds(Lsn(0x100)), None);
+
+        tracker.observe_commit(Lsn(0x100), 1_000_000);
+        // too close to the previous one to be sampled
+        tracker.observe_commit(Lsn(0x200), 1_500_000);
+        tracker.observe_commit(Lsn(0x300), 3_000_000);
+        assert_eq!(tracker.last_record_time(), Some(3_000_000));
+
+        assert_eq!(tracker.lag_seconds(Lsn(0x300)), Some(0.0));
+        assert_eq!(tracker.lag_seconds(Lsn(0x250)), Some(2.0));
+        assert_eq!(tracker.lag_seconds(Lsn(0x100)), Some(2.0));
+        // before all commits, lower bound
+        assert_eq!(tracker.lag_seconds(Lsn(0x50)), Some(2.0));
+
+        tracker.reset(Lsn(0x250));
+        assert_eq!(tracker.last_record_time(), Some(1_000_000));
+        assert_eq!(tracker.lag_seconds(Lsn(0x100)), Some(0.0));
+    }
+}
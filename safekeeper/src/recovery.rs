@@ -0,0 +1,157 @@
+//! Fetch WAL this safekeeper misses (e.g. appends it missed while being down)
+//! from a peer safekeeper which is ahead, instead of waiting for walproposer
+//! to resend all of it.
+//!
+//! Positions of peers are learnt from the broker. WAL is fetched with
+//! START_REPLICATION only from a peer whose current term equals its last log
+//! term and ours: then both have WAL of the single leader of that term, and
+//! the peer's WAL is just longer. The peer stops streaming once its term
+//! changes, as the WAL might be overwritten after that.
+
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use futures::StreamExt;
+use postgres_connection::{parse_host_port, PgConnectionConfig};
+use postgres_protocol::message::backend::ReplicationMessage;
+use tokio::runtime;
+use tokio::sync::mpsc;
+use tokio_postgres::replication::ReplicationStream;
+use tokio_postgres::NoTls;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::*;
+use utils::id::TenantTimelineId;
+use utils::lsn::Lsn;
+
+use crate::safekeeper::Term;
+use crate::timeline::{PeerInfo, Timeline};
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// application_name safekeepers use when fetching WAL from peers.
+pub const RECOVERY_APPNAME: &str = "safekeeper_recovery";
+
+const RECOVERY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn thread_main(conf: SafeKeeperConf) {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let _enter = info_span!("recovery").entered();
+    runtime.block_on(main_loop(conf));
+}
+
+/// Check once in a while whether some timeline lags behind its peers and
+/// launch recovery for it, one at a time per timeline.
+async fn main_loop(conf: SafeKeeperConf) {
+    let mut in_progress: HashSet<TenantTimelineId> = HashSet::new();
+    let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+    let mut ticker = tokio::time::interval(RECOVERY_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                for tli in GlobalTimelines::get_all() {
+                    if in_progress.contains(&tli.ttid) {
+                        continue;
+                    }
+                    if let Some((term, donor)) = tli.recovery_donor(&conf) {
+                        in_progress.insert(tli.ttid);
+                        let ttid = tli.ttid;
+                        let span = info_span!("timeline", %ttid, donor = %donor.sk_id);
+                        let tasks = Arc::clone(tli.tasks());
+                        let conf = conf.clone();
+                        let handle = tasks.spawn("recovery", async move {
+                            recover(&conf, tli, term, &donor).instrument(span).await
+                        });
+                        // Task is dropped if the timeline is cancelled, so
+                        // report completion from outside of it.
+                        let done_tx = done_tx.clone();
+                        tokio::spawn(async move {
+                            let _ = handle.await;
+                            let _ = done_tx.send(ttid);
+                        });
+                    }
+                }
+            }
+            Some(ttid) = done_rx.recv() => {
+                in_progress.remove(&ttid);
+            }
+        }
+    }
+}
+
+/// Stream WAL from `donor` starting at the end of our WAL up to donor's
+/// flush_lsn, appending it locally.
+async fn recover(
+    conf: &SafeKeeperConf,
+    tli: Arc<Timeline>,
+    term: Term,
+    donor: &PeerInfo,
+) -> Result<()> {
+    let start_lsn = tli.get_write_lsn();
+    let end_lsn = donor.flush_lsn;
+    info!(
+        "recovering WAL {}..{} of term {} from safekeeper {} at {}",
+        start_lsn, end_lsn, term, donor.sk_id, donor.pg_connstr
+    );
+
+    let (host, port) = parse_host_port(&donor.pg_connstr)
+        .with_context(|| format!("invalid peer address {}", donor.pg_connstr))?;
+    let connconf = PgConnectionConfig::new_host_port(host, port.unwrap_or(5432))
+        .extend_options([
+            "-c".to_owned(),
+            format!("timeline_id={}", tli.ttid.timeline_id),
+            format!("tenant_id={}", tli.ttid.tenant_id),
+            format!("recovery_term={term}"),
+        ])
+        .set_password(conf.auth_token.clone());
+    let mut config = connconf.to_tokio_postgres_config();
+    config.application_name(RECOVERY_APPNAME);
+    config.replication_mode(tokio_postgres::config::ReplicationMode::Physical);
+    config.connect_timeout(CONNECT_TIMEOUT);
+    let client = match &conf.tls {
+        Some(tls) => {
+            let tls = MakeRustlsConnect::new(tls.client_config()?);
+            let (client, connection) = config.connect(tls).await?;
+            tokio::spawn(log_connection_end(connection));
+            client
+        }
+        None => {
+            let (client, connection) = config.connect(NoTls).await?;
+            tokio::spawn(log_connection_end(connection));
+            client
+        }
+    };
+
+    let query = format!("START_REPLICATION PHYSICAL {start_lsn} UNTIL {end_lsn}");
+    let copy_stream = client.copy_both_simple(&query).await?;
+    let stream = ReplicationStream::new(copy_stream);
+    tokio::pin!(stream);
+
+    let mut lsn = start_lsn;
+    while let Some(msg) = stream.next().await {
+        if let ReplicationMessage::XLogData(xlog_data) = msg? {
+            let begin_lsn = Lsn(xlog_data.wal_start());
+            if begin_lsn != lsn {
+                bail!("peer sent WAL at {begin_lsn}, expected {lsn}");
+            }
+            let data = xlog_data.data();
+            tli.append_peer_wal(term, begin_lsn, data, donor.commit_lsn)?;
+            lsn += data.len() as u64;
+        }
+    }
+
+    info!("recovered WAL up to {lsn}");
+    Ok(())
+}
+
+async fn log_connection_end(connection: impl Future<Output = Result<(), tokio_postgres::Error>>) {
+    if let Err(e) = connection.await {
+        info!("recovery connection closed: {e}");
+    }
+}
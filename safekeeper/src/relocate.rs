@@ -0,0 +1,378 @@
+//! Move a timeline from one safekeeper to another.
+//!
+//! Relocation is driven by the safekeeper which received the request, talking
+//! to both nodes over HTTP at the addresses configured with
+//! `--peer-http-addr` and authenticating with its own token:
+//! 1. the target pulls the timeline from the source (see `pull_timeline`);
+//! 2. we wait until the proposer switches the configuration of the timeline
+//!    to one which includes the target and excludes the source. Safekeepers
+//!    can't change membership themselves, so it must be requested from
+//!    compute separately, e.g. by the control plane;
+//! 3. the timeline is deleted on the source.
+//!
+//! If the configuration doesn't change in time, the pulled copy is deleted
+//! from the target, unless the target has become a member meanwhile. Progress
+//! of the last relocation of each timeline is kept in memory and reported by
+//! the HTTP API.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::*;
+use utils::id::{NodeId, TenantTimelineId};
+
+use crate::safekeeper::Configuration;
+use crate::SafeKeeperConf;
+
+/// How often the target is polled for the configuration change.
+const MEMBERSHIP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_MEMBERSHIP_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Request to move the timeline between safekeepers.
+#[derive(Debug, Deserialize)]
+pub struct Request {
+    /// Id of the safekeeper the timeline is moved from.
+    pub from: NodeId,
+    /// Id of the safekeeper the timeline is moved to.
+    pub to: NodeId,
+    /// How long to wait for the configuration change, in seconds.
+    pub membership_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    Pulling,
+    WaitingForMembership,
+    Deleting,
+    Done,
+    /// Failed and the pulled copy was removed from the target.
+    RolledBack,
+    /// Failed, both copies are left in place.
+    Failed,
+}
+
+impl Step {
+    fn is_finished(&self) -> bool {
+        matches!(self, Step::Done | Step::RolledBack | Step::Failed)
+    }
+}
+
+/// Progress of the relocation of a timeline.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub from: NodeId,
+    pub to: NodeId,
+    pub step: Step,
+    pub error: Option<String>,
+}
+
+static RELOCATIONS: Lazy<Mutex<HashMap<TenantTimelineId, Status>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Progress of the last relocation of the timeline started on this node.
+pub fn get_status(ttid: &TenantTimelineId) -> Option<Status> {
+    RELOCATIONS.lock().unwrap().get(ttid).cloned()
+}
+
+fn set_step(ttid: &TenantTimelineId, step: Step, error: Option<String>) {
+    if let Some(status) = RELOCATIONS.lock().unwrap().get_mut(ttid) {
+        info!("relocation of {} is {:?}", ttid, step);
+        status.step = step;
+        status.error = error;
+    }
+}
+
+/// Start relocation in the background.
+pub fn start(relocation: Relocation) -> Result<Status> {
+    let ttid = relocation.ttid;
+    let status = Status {
+        from: relocation.request.from,
+        to: relocation.request.to,
+        step: Step::Pulling,
+        error: None,
+    };
+    {
+        let mut relocations = RELOCATIONS.lock().unwrap();
+        if let Some(status) = relocations.get(&ttid) {
+            if !status.step.is_finished() {
+                bail!("timeline {} is already being relocated", ttid);
+            }
+        }
+        relocations.insert(ttid, status.clone());
+    }
+
+    tokio::spawn(
+        async move {
+            let (step, error) = match relocation.run().await {
+                Ok(()) => (Step::Done, None),
+                Err((step, e)) => {
+                    error!("relocation of {} failed: {:#}", ttid, e);
+                    (step, Some(format!("{e:#}")))
+                }
+            };
+            set_step(&ttid, step, error);
+        }
+        .instrument(info_span!("relocate", %ttid)),
+    );
+    Ok(status)
+}
+
+/// Part of the target's timeline status we are interested in.
+#[derive(Debug, Deserialize)]
+struct TimelineStatus {
+    configuration: Configuration,
+}
+
+#[derive(Debug, Deserialize)]
+struct SafekeeperStatus {
+    id: NodeId,
+}
+
+pub struct Relocation {
+    ttid: TenantTimelineId,
+    request: Request,
+    /// HTTP API addresses of the source and the target.
+    from_host: String,
+    to_host: String,
+    client: reqwest::Client,
+    auth_header: Option<String>,
+}
+
+impl Relocation {
+    /// Prepare relocation between safekeepers known from the configuration;
+    /// addresses are never taken from the request, so that it can't make us
+    /// send our token elsewhere.
+    pub fn new(conf: &SafeKeeperConf, ttid: TenantTimelineId, request: Request) -> Result<Self> {
+        let host = |id: NodeId| {
+            conf.peer_http_addrs
+                .get(&id)
+                .cloned()
+                .with_context(|| format!("HTTP address of safekeeper {id} is not configured"))
+        };
+        if request.from == request.to {
+            bail!("source and target are the same safekeeper {}", request.from);
+        }
+        Ok(Relocation {
+            ttid,
+            from_host: host(request.from)?,
+            to_host: host(request.to)?,
+            request,
+            client: reqwest::Client::new(),
+            auth_header: conf
+                .auth_token
+                .as_ref()
+                .map(|token| format!("Bearer {token}")),
+        })
+    }
+
+    /// Run the relocation, on failure returning the final step along with
+    /// the error.
+    async fn run(&self) -> Result<(), (Step, anyhow::Error)> {
+        let from_id = self.request.from;
+        let to_id = self.request.to;
+        self.check_node_id(&self.from_host, from_id)
+            .await
+            .map_err(failed)?;
+        self.check_node_id(&self.to_host, to_id)
+            .await
+            .map_err(failed)?;
+        info!(
+            "relocating from safekeeper {} at {} to {} at {}",
+            from_id, self.from_host, to_id, self.to_host
+        );
+
+        // nothing to roll back if pull fails, it cleans up after itself
+        self.pull().await.map_err(failed)?;
+
+        set_step(&self.ttid, Step::WaitingForMembership, None);
+        if let Err(e) = self.wait_for_membership(from_id, to_id).await {
+            return Err(self.rollback(to_id, e).await);
+        }
+
+        set_step(&self.ttid, Step::Deleting, None);
+        self.delete(&self.from_host)
+            .await
+            .context("configuration is switched, but timeline is left on the source")
+            .map_err(failed)
+    }
+
+    fn with_auth(&self, builder: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.auth_header {
+            Some(header) => builder.header(reqwest::header::AUTHORIZATION, header),
+            None => builder,
+        }
+    }
+
+    fn timeline_url(&self, host: &str) -> String {
+        format!(
+            "{}/v1/tenant/{}/timeline/{}",
+            host, self.ttid.tenant_id, self.ttid.timeline_id
+        )
+    }
+
+    /// Check that the configured address is of the expected node.
+    async fn check_node_id(&self, host: &str, expected: NodeId) -> Result<()> {
+        let status = self
+            .with_auth(self.client.get(format!("{host}/v1/status")))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<SafekeeperStatus>()
+            .await
+            .with_context(|| format!("failed to get status of {host}"))?;
+        if status.id != expected {
+            bail!(
+                "safekeeper at {} is {}, expected {}",
+                host,
+                status.id,
+                expected
+            );
+        }
+        Ok(())
+    }
+
+    async fn pull(&self) -> Result<()> {
+        let body = json!({
+            "tenant_id": self.ttid.tenant_id.to_string(),
+            "timeline_id": self.ttid.timeline_id.to_string(),
+            "http_hosts": [&self.from_host],
+        });
+        self.with_auth(
+            self.client
+                .post(format!("{}/v1/pull_timeline", self.to_host))
+                .json(&body),
+        )
+        .send()
+        .await?
+        .error_for_status()
+        .context("failed to pull timeline to the target")?;
+        Ok(())
+    }
+
+    async fn configuration(&self) -> Result<Configuration> {
+        let status = self
+            .with_auth(self.client.get(self.timeline_url(&self.to_host)))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TimelineStatus>()
+            .await?;
+        Ok(status.configuration)
+    }
+
+    /// Wait until the configuration on the target includes it and excludes
+    /// the source.
+    async fn wait_for_membership(&self, from_id: NodeId, to_id: NodeId) -> Result<()> {
+        let timeout = self
+            .request
+            .membership_timeout_secs
+            .map_or(DEFAULT_MEMBERSHIP_TIMEOUT, Duration::from_secs);
+        let started_at = Instant::now();
+        loop {
+            match self.configuration().await {
+                Ok(conf) => {
+                    if conf.generation != 0
+                        && !conf.is_joint()
+                        && conf.members.contains(&to_id)
+                        && !conf.members.contains(&from_id)
+                    {
+                        info!("switched to configuration {:?}", conf);
+                        return Ok(());
+                    }
+                }
+                Err(e) => warn!("failed to get configuration from the target: {:#}", e),
+            }
+            if started_at.elapsed() >= timeout {
+                bail!("configuration didn't change in {:?}", timeout);
+            }
+            tokio::time::sleep(MEMBERSHIP_CHECK_INTERVAL).await;
+        }
+    }
+
+    /// Delete the pulled copy from the target, unless it is a member of the
+    /// configuration: then it may hold WAL acknowledged to the proposer.
+    async fn rollback(&self, to_id: NodeId, err: anyhow::Error) -> (Step, anyhow::Error) {
+        match self.configuration().await {
+            Ok(conf)
+                if !conf.members.contains(&to_id)
+                    && !conf
+                        .new_members
+                        .as_ref()
+                        .map_or(false, |m| m.contains(&to_id)) => {}
+            Ok(_) => {
+                return (
+                    Step::Failed,
+                    err.context("target is already a member, not rolling back"),
+                )
+            }
+            Err(e) => {
+                return (
+                    Step::Failed,
+                    err.context(format!(
+                        "not rolling back, failed to get configuration: {e:#}"
+                    )),
+                )
+            }
+        }
+        match self.delete(&self.to_host).await {
+            Ok(()) => (Step::RolledBack, err),
+            Err(e) => (
+                Step::Failed,
+                err.context(format!("failed to remove the pulled copy: {e:#}")),
+            ),
+        }
+    }
+
+    async fn delete(&self, host: &str) -> Result<()> {
+        self.with_auth(self.client.delete(self.timeline_url(host)))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("failed to delete timeline on {host}"))?;
+        Ok(())
+    }
+}
+
+fn failed(e: anyhow::Error) -> (Step, anyhow::Error) {
+    (Step::Failed, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(from: u64, to: u64) -> Request {
+        Request {
+            from: NodeId(from),
+            to: NodeId(to),
+            membership_timeout_secs: None,
+        }
+    }
+
+    #[test]
+    fn test_hosts_from_conf() {
+        let conf = SafeKeeperConf {
+            peer_http_addrs: HashMap::from([
+                (NodeId(1), "http://sk-1:7676".to_owned()),
+                (NodeId(2), "http://sk-2:7676".to_owned()),
+            ]),
+            auth_token: Some("token".to_owned()),
+            ..SafeKeeperConf::dummy()
+        };
+        let ttid = TenantTimelineId::generate();
+
+        let relocation = Relocation::new(&conf, ttid, request(1, 2)).unwrap();
+        assert_eq!(relocation.from_host, "http://sk-1:7676");
+        assert_eq!(relocation.to_host, "http://sk-2:7676");
+        assert_eq!(relocation.auth_header.as_deref(), Some("Bearer token"));
+
+        assert!(Relocation::new(&conf, ttid, request(1, 3)).is_err());
+        assert!(Relocation::new(&conf, ttid, request(1, 1)).is_err());
+    }
+}
@@ -1,11 +1,137 @@
-//! Thread removing old WAL.
+//! Thread removing old WAL (and compressing offloaded one, if enabled), and
+//! the policy of how much WAL to keep.
 
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
 use std::{thread, time::Duration};
 
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use tracing::*;
+use utils::crashsafe;
+use utils::id::TenantId;
 
 use crate::{GlobalTimelines, SafeKeeperConf};
 
+/// How much WAL to keep on disk in addition to what is required for safety:
+/// WAL is never removed before it is replicated to peers, offloaded (with
+/// backup enabled), consumed by connected replicas or while it is pinned.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalRetentionPolicy {
+    /// Keep at least this many bytes of WAL before flush_lsn.
+    pub keep_bytes: Option<u64>,
+    /// Keep at least this many segments before the one of flush_lsn.
+    pub keep_segments: Option<u64>,
+    /// Keep WAL only until it is offloaded, without waiting for pageserver
+    /// to consume it, see `SafeKeeperConf::remove_offloaded_wal`.
+    pub until_backup: Option<bool>,
+}
+
+impl WalRetentionPolicy {
+    /// Safekeeper-wide policy.
+    pub fn from_conf(conf: &SafeKeeperConf) -> Self {
+        WalRetentionPolicy {
+            keep_bytes: conf.wal_retention_keep_bytes,
+            keep_segments: conf.wal_retention_keep_segments,
+            until_backup: Some(conf.remove_offloaded_wal),
+        }
+    }
+
+    /// Policy with fields set in `overrides` replaced.
+    fn merge(self, overrides: &WalRetentionPolicy) -> Self {
+        WalRetentionPolicy {
+            keep_bytes: overrides.keep_bytes.or(self.keep_bytes),
+            keep_segments: overrides.keep_segments.or(self.keep_segments),
+            until_backup: overrides.until_backup.or(self.until_backup),
+        }
+    }
+
+    pub fn until_backup(&self) -> bool {
+        self.until_backup.unwrap_or(false)
+    }
+}
+
+/// Overrides are persisted in this file in the tenant directory.
+const TENANT_POLICY_FILE_NAME: &str = "wal_retention.json";
+
+/// Per-tenant overrides of the safekeeper-wide policy, loaded from disk on
+/// startup by `load_tenant_policies`.
+static TENANT_POLICIES: Lazy<Mutex<HashMap<TenantId, WalRetentionPolicy>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Set overrides of the retention policy for the tenant; all fields unset
+/// removes them.
+pub fn set_tenant_policy(
+    conf: &SafeKeeperConf,
+    tenant_id: TenantId,
+    overrides: WalRetentionPolicy,
+) -> Result<()> {
+    let path = conf.tenant_dir(&tenant_id).join(TENANT_POLICY_FILE_NAME);
+    // the lock serializes writers of the file as well
+    let mut policies = TENANT_POLICIES.lock().unwrap();
+    if overrides == WalRetentionPolicy::default() {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("failed to remove {}", path.display()))
+            }
+            _ => {}
+        }
+        policies.remove(&tenant_id);
+    } else {
+        persist_policy(&path, &overrides)
+            .with_context(|| format!("failed to persist {}", path.display()))?;
+        policies.insert(tenant_id, overrides);
+    }
+    Ok(())
+}
+
+fn persist_policy(path: &Path, overrides: &WalRetentionPolicy) -> Result<()> {
+    crashsafe::create_dir_all(path.parent().unwrap())?;
+    let tmp_path = crashsafe::path_with_suffix_extension(path, "tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&serde_json::to_vec(overrides)?)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    crashsafe::fsync_file_and_parent(path)?;
+    Ok(())
+}
+
+/// Load overrides persisted by `set_tenant_policy`.
+pub fn load_tenant_policies(conf: &SafeKeeperConf) -> Result<()> {
+    let mut policies = TENANT_POLICIES.lock().unwrap();
+    for entry in fs::read_dir(&conf.workdir)? {
+        let entry = entry?;
+        let tenant_id = match TenantId::from_str(entry.file_name().to_str().unwrap_or("")) {
+            Ok(tenant_id) if entry.path().is_dir() => tenant_id,
+            _ => continue,
+        };
+        let path = entry.path().join(TENANT_POLICY_FILE_NAME);
+        let overrides = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .with_context(|| format!("failed to parse {}", path.display()))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        info!("loaded WAL retention overrides of tenant {}", tenant_id);
+        policies.insert(tenant_id, overrides);
+    }
+    Ok(())
+}
+
+/// Retention policy in effect for the tenant.
+pub fn get_policy(conf: &SafeKeeperConf, tenant_id: &TenantId) -> WalRetentionPolicy {
+    let policy = WalRetentionPolicy::from_conf(conf);
+    match TENANT_POLICIES.lock().unwrap().get(tenant_id) {
+        Some(overrides) => policy.merge(overrides),
+        None => policy,
+    }
+}
+
 pub fn thread_main(conf: SafeKeeperConf) {
     let wal_removal_interval = Duration::from_millis(5000);
     loop {
@@ -17,10 +143,72 @@ pub fn thread_main(conf: SafeKeeperConf) {
             let ttid = tli.ttid;
             let _enter =
                 info_span!("", tenant = %ttid.tenant_id, timeline = %ttid.timeline_id).entered();
-            if let Err(e) = tli.remove_old_wal(conf.wal_backup_enabled) {
-                warn!("failed to remove WAL: {}", e);
+            let policy = get_policy(&conf, &ttid.tenant_id);
+            match tli.remove_old_wal(conf.wal_backup_enabled, &policy) {
+                Ok(removal) if removal.removed_segments() > 0 => debug!(
+                    "removed WAL segments {}..{}, held by {}",
+                    removal.from_segno, removal.to_segno, removal.reason
+                ),
+                Ok(_) => {}
+                Err(e) => warn!("failed to remove WAL: {}", e),
+            }
+            if conf.compress_cold_wal && conf.wal_backup_enabled {
+                if let Err(e) = tli.compress_cold_wal() {
+                    warn!("failed to compress WAL: {}", e);
+                }
             }
         }
         thread::sleep(wal_removal_interval)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tenant_policy_persisted() {
+        let workdir = tempfile::tempdir().unwrap();
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            ..SafeKeeperConf::dummy()
+        };
+        let tenant_id = TenantId::generate();
+        let overrides = WalRetentionPolicy {
+            keep_segments: Some(4),
+            ..Default::default()
+        };
+        set_tenant_policy(&conf, tenant_id, overrides.clone()).unwrap();
+
+        // as if restarted
+        TENANT_POLICIES.lock().unwrap().remove(&tenant_id);
+        load_tenant_policies(&conf).unwrap();
+        assert_eq!(get_policy(&conf, &tenant_id).keep_segments, Some(4));
+
+        set_tenant_policy(&conf, tenant_id, WalRetentionPolicy::default()).unwrap();
+        load_tenant_policies(&conf).unwrap();
+        assert_eq!(get_policy(&conf, &tenant_id).keep_segments, None);
+    }
+
+    #[test]
+    fn test_policy_merge() {
+        let node = WalRetentionPolicy {
+            keep_bytes: Some(1024),
+            keep_segments: None,
+            until_backup: Some(false),
+        };
+        let overrides = WalRetentionPolicy {
+            keep_segments: Some(4),
+            until_backup: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            node.merge(&overrides),
+            WalRetentionPolicy {
+                keep_bytes: Some(1024),
+                keep_segments: Some(4),
+                until_backup: Some(true),
+            }
+        );
+    }
+}
@@ -10,11 +10,13 @@ use std::cmp::max;
 use std::cmp::min;
 use std::fmt;
 use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
 use storage_broker::proto::SafekeeperTimelineInfo;
 
 use tracing::*;
 
 use crate::control_file;
+use crate::record_time::RecordTimeTracker;
 use crate::send_wal::HotStandbyFeedback;
 
 use crate::wal_storage;
@@ -26,8 +28,30 @@ use utils::{
 };
 
 pub const SK_MAGIC: u32 = 0xcafeceefu32;
-pub const SK_FORMAT_VERSION: u32 = 7;
-const SK_PROTOCOL_VERSION: u32 = 2;
+pub const SK_FORMAT_VERSION: u32 = 9;
+/// Latest proposer-acceptor protocol version we speak.
+pub const SK_PROTOCOL_VERSION: u32 = 3;
+/// Oldest protocol version still accepted from proposers, to allow upgrading
+/// safekeepers before computes.
+pub const MIN_SK_PROTOCOL_VERSION: u32 = 2;
+
+/// Optional protocol features, since version 3 negotiated in the greeting as
+/// the ones both proposer and acceptor support.
+pub mod proto_features {
+    /// Proposer may change the set of safekeepers with ConfigurationChange.
+    pub const CONFIGURATION_CHANGE: u64 = 1 << 0;
+    /// AppendRequest and AppendResponse end with crc32c of the message
+    /// preceding it, catching corruption on the way between compute and
+    /// safekeeper before the WAL is written.
+    pub const FRAME_CRC: u64 = 1 << 1;
+    /// Proposer runs in sync-safekeepers mode, so ProposerElected is
+    /// answered with AppendResponse right away instead of after the first
+    /// (empty) AppendRequest.
+    pub const SYNC_SAFEKEEPERS: u64 = 1 << 2;
+
+    /// Features this safekeeper supports.
+    pub const SUPPORTED: u64 = CONFIGURATION_CHANGE | FRAME_CRC | SYNC_SAFEKEEPERS;
+}
 pub const UNKNOWN_SERVER_VERSION: u32 = 0;
 
 /// Consensus logical timestamp.
@@ -121,6 +145,16 @@ impl AcceptorState {
             None => 0,
         }
     }
+
+    /// LSN at which the epoch started, i.e. the first LSN of the log written
+    /// in the epoch term.
+    pub fn get_epoch_start_lsn(&self, flush_lsn: Lsn) -> Lsn {
+        let th = self.term_history.up_to(flush_lsn);
+        match th.0.last() {
+            Some(e) => e.lsn,
+            None => Lsn::INVALID,
+        }
+    }
 }
 
 /// Information about Postgres. Safekeeper gets it once and then verifies
@@ -133,6 +167,65 @@ pub struct ServerInfo {
     pub wal_seg_size: u32,
 }
 
+/// Validated parameters of a new timeline.
+#[derive(Debug, Clone)]
+pub struct TimelineCreateParams {
+    pub server_info: ServerInfo,
+    /// Where WAL of the timeline begins globally. Lsn::INVALID creates
+    /// uninitialized timeline which learns it from the first ProposerElected.
+    pub timeline_start_lsn: Lsn,
+    /// Since which LSN this safekeeper has WAL.
+    pub local_start_lsn: Lsn,
+    pub commit_lsn: Lsn,
+}
+
+impl TimelineCreateParams {
+    /// Parameters of uninitialized timeline, with all LSNs to be learnt from
+    /// the first ProposerElected.
+    pub fn uninitialized(server_info: ServerInfo) -> Result<Self> {
+        Self::new(server_info, Lsn::INVALID, Lsn::INVALID, Lsn::INVALID)
+    }
+
+    pub fn new(
+        server_info: ServerInfo,
+        timeline_start_lsn: Lsn,
+        local_start_lsn: Lsn,
+        commit_lsn: Lsn,
+    ) -> Result<Self> {
+        let wal_seg_size = server_info.wal_seg_size;
+        if !wal_seg_size.is_power_of_two() || !(1 << 20..=1 << 30).contains(&wal_seg_size) {
+            bail!(
+                "invalid wal_seg_size {}, must be a power of two between 1MB and 1GB",
+                wal_seg_size
+            );
+        }
+        if local_start_lsn == Lsn::INVALID {
+            if timeline_start_lsn != Lsn::INVALID || commit_lsn != Lsn::INVALID {
+                bail!("local_start_lsn must be set along with timeline_start_lsn and commit_lsn");
+            }
+        } else if local_start_lsn < timeline_start_lsn {
+            bail!(
+                "local_start_lsn {} is below timeline_start_lsn {}",
+                local_start_lsn,
+                timeline_start_lsn
+            );
+        }
+        if commit_lsn != Lsn::INVALID && commit_lsn < local_start_lsn {
+            bail!(
+                "commit_lsn {} is below local_start_lsn {}",
+                commit_lsn,
+                local_start_lsn
+            );
+        }
+        Ok(TimelineCreateParams {
+            server_info,
+            timeline_start_lsn,
+            local_start_lsn,
+            commit_lsn,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersistedPeerInfo {
     /// LSN up to which safekeeper offloaded WAL to s3.
@@ -159,6 +252,63 @@ impl PersistedPeerInfo {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PersistedPeers(pub Vec<(NodeId, PersistedPeerInfo)>);
 
+/// Set of safekeepers hosting the timeline. Membership is changed in two
+/// steps through a joint configuration containing both the old and the new
+/// set. Safekeepers only persist the configuration and validate changes of
+/// it; counting votes and acknowledgements against it is up to the proposer.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Configuration {
+    /// Incremented on each change, 0 means configuration was never set and
+    /// members are defined by the proposer.
+    pub generation: u32,
+    pub members: Vec<NodeId>,
+    /// Members of the configuration being switched to, if any.
+    pub new_members: Option<Vec<NodeId>>,
+}
+
+impl Configuration {
+    pub fn is_joint(&self) -> bool {
+        self.new_members.is_some()
+    }
+
+    /// Check that `new` may replace this configuration: the first
+    /// configuration can be anything, then the joint one must be entered and
+    /// left, either to the new members or back to the old ones.
+    pub fn validate_change(&self, new: &Configuration) -> Result<()> {
+        if new.generation != self.generation + 1 {
+            bail!(
+                "configuration generation {} doesn't follow current {}",
+                new.generation,
+                self.generation
+            );
+        }
+        for members in std::iter::once(&new.members).chain(new.new_members.iter()) {
+            if members.is_empty() {
+                bail!("configuration with no members");
+            }
+            let mut sorted = members.clone();
+            sorted.sort();
+            sorted.dedup();
+            if sorted.len() != members.len() {
+                bail!("configuration with duplicate members {:?}", members);
+            }
+        }
+        let valid = match (&self.new_members, &new.new_members) {
+            // initial configuration
+            _ if self.generation == 0 => !new.is_joint(),
+            // entering joint configuration
+            (None, Some(_)) => new.members == self.members,
+            // leaving joint configuration
+            (Some(new_members), None) => new.members == *new_members || new.members == self.members,
+            _ => false,
+        };
+        if !valid {
+            bail!("invalid configuration change from {:?} to {:?}", self, new);
+        }
+        Ok(())
+    }
+}
+
 /// Persistent information stored on safekeeper node
 /// On disk data is prefixed by magic and format version and followed by checksum.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -202,9 +352,45 @@ pub struct SafeKeeperState {
     // obviously can be stale. (Currently not saved at all, but let's provision
     // place to have less file version upgrades).
     pub peers: PersistedPeers,
+    /// Safekeepers hosting the timeline, set by the proposer.
+    pub configuration: Configuration,
+    /// Named holds on local WAL, see `RetentionPin`.
+    pub retention_pins: Vec<RetentionPin>,
 }
 
-#[derive(Debug, Clone)]
+/// Outcome of `SafeKeeper::term_bump`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TermBump {
+    pub previous_term: Term,
+    pub current_term: Term,
+}
+
+/// Named hold on local WAL, e.g. for branch creation or a backup in
+/// progress: WAL at and after `lsn` is not removed while the pin exists.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionPin {
+    pub name: String,
+    pub lsn: Lsn,
+    /// Unix timestamp in seconds after which the pin is ignored and dropped,
+    /// None if it is held until released.
+    pub expires_at: Option<u64>,
+}
+
+impl RetentionPin {
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at
+            .map_or(false, |expires_at| expires_at <= now)
+    }
+}
+
+/// Current time for pin expiration.
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, Clone, Serialize)]
 // In memory safekeeper state. Fields mirror ones in `SafeKeeperState`; values
 // are not flushed yet.
 pub struct SafekeeperMemState {
@@ -218,11 +404,15 @@ pub struct SafekeeperMemState {
 impl SafeKeeperState {
     pub fn new(
         ttid: &TenantTimelineId,
-        server_info: ServerInfo,
+        params: TimelineCreateParams,
         peers: Vec<NodeId>,
-        commit_lsn: Lsn,
-        local_start_lsn: Lsn,
     ) -> SafeKeeperState {
+        let TimelineCreateParams {
+            server_info,
+            timeline_start_lsn,
+            local_start_lsn,
+            commit_lsn,
+        } = params;
         SafeKeeperState {
             tenant_id: ttid.tenant_id,
             timeline_id: ttid.timeline_id,
@@ -232,7 +422,7 @@ impl SafeKeeperState {
             },
             server: server_info,
             proposer_uuid: [0; 16],
-            timeline_start_lsn: Lsn(0),
+            timeline_start_lsn,
             local_start_lsn,
             commit_lsn,
             backup_lsn: local_start_lsn,
@@ -244,21 +434,33 @@ impl SafeKeeperState {
                     .map(|p| (*p, PersistedPeerInfo::new()))
                     .collect(),
             ),
+            configuration: Configuration::default(),
+            retention_pins: Vec::new(),
         }
     }
 
+    /// Whether the timeline knows where its WAL begins. Uninitialized
+    /// timeline is created by the first walproposer connection and learns
+    /// it from ProposerElected; until then it has no WAL to serve.
+    pub fn is_initialized(&self) -> bool {
+        self.timeline_start_lsn != Lsn(0) || !self.acceptor_state.term_history.0.is_empty()
+    }
+
     #[cfg(test)]
     pub fn empty() -> Self {
         SafeKeeperState::new(
             &TenantTimelineId::empty(),
-            ServerInfo {
-                pg_version: UNKNOWN_SERVER_VERSION, /* Postgres server version */
-                system_id: 0,                       /* Postgres system identifier */
-                wal_seg_size: 0,
+            TimelineCreateParams {
+                server_info: ServerInfo {
+                    pg_version: UNKNOWN_SERVER_VERSION, /* Postgres server version */
+                    system_id: 0,                       /* Postgres system identifier */
+                    wal_seg_size: 0,
+                },
+                timeline_start_lsn: Lsn::INVALID,
+                local_start_lsn: Lsn::INVALID,
+                commit_lsn: Lsn::INVALID,
             },
             vec![],
-            Lsn::INVALID,
-            Lsn::INVALID,
         )
     }
 }
@@ -278,14 +480,21 @@ pub struct ProposerGreeting {
     pub tenant_id: TenantId,
     pub tli: TimeLineID,
     pub wal_seg_size: u32,
+    /// Features proposer supports, see `proto_features`; sent since
+    /// protocol version 3.
+    #[serde(skip)]
+    pub features: u64,
 }
 
 /// Acceptor -> Proposer initial response: the highest term known to me
-/// (acceptor voted for).
+/// (acceptor voted for). Since protocol version 3 it also carries the
+/// negotiated version and features.
 #[derive(Debug, Serialize)]
 pub struct AcceptorGreeting {
     term: u64,
     node_id: NodeId,
+    protocol_version: u32,
+    pub features: u64,
 }
 
 /// Vote request sent from proposer to safekeepers
@@ -319,6 +528,22 @@ pub struct ProposerElected {
     pub timeline_start_lsn: Lsn,
 }
 
+/// Proposer -> Acceptor request to change the set of safekeepers, sent by
+/// the elected proposer.
+#[derive(Debug)]
+pub struct ConfigurationChangeRequest {
+    pub term: Term,
+    pub configuration: Configuration,
+}
+
+/// Acceptor -> Proposer response with the configuration in effect, which is
+/// the requested one if the change was accepted.
+#[derive(Debug)]
+pub struct ConfigurationChangeResponse {
+    pub term: Term,
+    pub configuration: Configuration,
+}
+
 /// Request with WAL message sent from proposer to safekeeper. Along the way it
 /// communicates commit_lsn.
 #[derive(Debug)]
@@ -382,18 +607,54 @@ pub enum ProposerAcceptorMessage {
     AppendRequest(AppendRequest),
     NoFlushAppendRequest(AppendRequest),
     FlushWAL,
+    ConfigurationChange(ConfigurationChangeRequest),
+}
+
+/// Choose protocol version to speak with the proposer: the lower of the
+/// proposer's and ours, if we still support it.
+pub fn negotiate_protocol_version(proposer_version: u32) -> Result<u32> {
+    if proposer_version < MIN_SK_PROTOCOL_VERSION {
+        bail!(
+            "incompatible protocol version {}, expected at least {}",
+            proposer_version,
+            MIN_SK_PROTOCOL_VERSION
+        );
+    }
+    Ok(min(proposer_version, SK_PROTOCOL_VERSION))
 }
 
+/// Features to use with the proposer: the ones both sides support.
+pub fn negotiate_features(proposer_features: u64) -> u64 {
+    proposer_features & proto_features::SUPPORTED
+}
+
+/// Whether `msg` must be followed by an AppendResponse right away, see
+/// `proto_features::SYNC_SAFEKEEPERS`: sync-safekeepers waits only for
+/// flush_lsn and commit_lsn, so they are reported after ProposerElected
+/// without waiting for an AppendRequest.
+pub fn needs_flush_reply(msg: &ProposerAcceptorMessage, features: u64) -> bool {
+    matches!(msg, ProposerAcceptorMessage::Elected(_))
+        && features & proto_features::SYNC_SAFEKEEPERS != 0
+}
+
+/// Size of the frame checksum, see `proto_features::FRAME_CRC`.
+const FRAME_CRC_SIZE: usize = 4;
+
 impl ProposerAcceptorMessage {
-    /// Parse proposer message.
-    pub fn parse(msg_bytes: Bytes) -> Result<ProposerAcceptorMessage> {
+    /// Parse proposer message. `features` are the ones negotiated in the
+    /// greeting; the greeting itself is parsed regardless of them.
+    pub fn parse(msg_bytes: Bytes, features: u64) -> Result<ProposerAcceptorMessage> {
+        let raw = msg_bytes.clone();
         // xxx using Reader is inefficient but easy to work with bincode
         let mut stream = msg_bytes.reader();
         // u64 is here to avoid padding; it will be removed once we stop packing C structs into the wire as is
         let tag = stream.read_u64::<LittleEndian>()? as u8 as char;
         match tag {
             'g' => {
-                let msg = ProposerGreeting::des_from(&mut stream)?;
+                let mut msg = ProposerGreeting::des_from(&mut stream)?;
+                if msg.protocol_version >= 3 {
+                    msg.features = stream.read_u64::<LittleEndian>()?;
+                }
                 Ok(ProposerAcceptorMessage::Greeting(msg))
             }
             'v' => {
@@ -437,32 +698,96 @@ impl ProposerAcceptorMessage {
 
                 let mut wal_data_vec: Vec<u8> = vec![0; rec_size];
                 stream.read_exact(&mut wal_data_vec)?;
+                if features & proto_features::FRAME_CRC != 0 {
+                    let covered = raw.len() - stream.get_ref().remaining();
+                    let expected = stream.read_u32::<LittleEndian>()?;
+                    let actual = crc32c::crc32c(&raw[..covered]);
+                    if actual != expected {
+                        bail!(
+                            "AppendRequest {}-{} is corrupted: crc {:#010x}, expected {:#010x}",
+                            hdr.begin_lsn,
+                            hdr.end_lsn,
+                            actual,
+                            expected
+                        );
+                    }
+                }
                 let wal_data = Bytes::from(wal_data_vec);
                 let msg = AppendRequest { h: hdr, wal_data };
 
                 Ok(ProposerAcceptorMessage::AppendRequest(msg))
             }
+            'c' => {
+                if features & proto_features::CONFIGURATION_CHANGE == 0 {
+                    bail!("ConfigurationChangeRequest is sent, but the feature is not negotiated");
+                }
+                let mut msg_bytes = stream.into_inner();
+                if msg_bytes.remaining() < 12 {
+                    bail!("ConfigurationChangeRequest message is not complete");
+                }
+                let term = msg_bytes.get_u64_le();
+                let generation = msg_bytes.get_u32_le();
+                let members = parse_members(&mut msg_bytes)?;
+                let new_members = parse_members(&mut msg_bytes)?;
+                let msg = ConfigurationChangeRequest {
+                    term,
+                    configuration: Configuration {
+                        generation,
+                        members,
+                        // joint configuration always has new members
+                        new_members: Some(new_members).filter(|m| !m.is_empty()),
+                    },
+                };
+                Ok(ProposerAcceptorMessage::ConfigurationChange(msg))
+            }
             _ => bail!("unknown proposer-acceptor message tag: {}", tag,),
         }
     }
 }
 
+/// Parse list of node ids as n_members followed by the ids.
+fn parse_members(bytes: &mut Bytes) -> Result<Vec<NodeId>> {
+    if bytes.remaining() < 4 {
+        bail!("member list misses len");
+    }
+    let n_members = bytes.get_u32_le() as usize;
+    if bytes.remaining() < n_members * 8 {
+        bail!("member list is incomplete");
+    }
+    Ok((0..n_members).map(|_| NodeId(bytes.get_u64_le())).collect())
+}
+
+fn serialize_members(buf: &mut BytesMut, members: &[NodeId]) {
+    buf.put_u32_le(members.len() as u32);
+    for m in members {
+        buf.put_u64_le(m.0);
+    }
+}
+
 /// Acceptor -> Proposer messages
 #[derive(Debug)]
 pub enum AcceptorProposerMessage {
     Greeting(AcceptorGreeting),
     VoteResponse(VoteResponse),
     AppendResponse(AppendResponse),
+    ConfigurationChangeResponse(ConfigurationChangeResponse),
 }
 
 impl AcceptorProposerMessage {
-    /// Serialize acceptor -> proposer message.
-    pub fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    /// Serialize acceptor -> proposer message with the features negotiated
+    /// in the greeting.
+    pub fn serialize(&self, buf: &mut BytesMut, features: u64) -> Result<()> {
+        let start = buf.len();
         match self {
             AcceptorProposerMessage::Greeting(msg) => {
                 buf.put_u64_le('g' as u64);
                 buf.put_u64_le(msg.term);
                 buf.put_u64_le(msg.node_id.0);
+                if msg.protocol_version >= 3 {
+                    // u64 to keep the C struct free of padding
+                    buf.put_u64_le(msg.protocol_version as u64);
+                    buf.put_u64_le(msg.features);
+                }
             }
             AcceptorProposerMessage::VoteResponse(msg) => {
                 buf.put_u64_le('v' as u64);
@@ -486,7 +811,21 @@ impl AcceptorProposerMessage {
                 buf.put_u64_le(msg.hs_feedback.xmin);
                 buf.put_u64_le(msg.hs_feedback.catalog_xmin);
 
-                msg.pageserver_feedback.serialize(buf)?
+                msg.pageserver_feedback.serialize(buf)?;
+                if features & proto_features::FRAME_CRC != 0 {
+                    let crc = crc32c::crc32c(&buf[start..]);
+                    buf.put_u32_le(crc);
+                }
+            }
+            AcceptorProposerMessage::ConfigurationChangeResponse(msg) => {
+                buf.put_u64_le('c' as u64);
+                buf.put_u64_le(msg.term);
+                buf.put_u32_le(msg.configuration.generation);
+                serialize_members(buf, &msg.configuration.members);
+                serialize_members(
+                    buf,
+                    msg.configuration.new_members.as_deref().unwrap_or_default(),
+                );
             }
         }
 
@@ -510,6 +849,9 @@ pub struct SafeKeeper<CTRL: control_file::Storage, WAL: wal_storage::Storage> {
 
     pub wal_store: WAL,
 
+    /// Commit times of the received WAL.
+    pub record_time: RecordTimeTracker,
+
     node_id: NodeId, // safekeeper's node id
 }
 
@@ -543,6 +885,7 @@ where
             },
             state,
             wal_store,
+            record_time: RecordTimeTracker::new(),
             node_id,
         })
     }
@@ -579,6 +922,9 @@ where
                 self.handle_append_request(msg, false)
             }
             ProposerAcceptorMessage::FlushWAL => self.handle_flush(),
+            ProposerAcceptorMessage::ConfigurationChange(msg) => {
+                self.handle_configuration_change(msg)
+            }
         }
     }
 
@@ -589,13 +935,7 @@ where
         msg: &ProposerGreeting,
     ) -> Result<Option<AcceptorProposerMessage>> {
         // Check protocol compatibility
-        if msg.protocol_version != SK_PROTOCOL_VERSION {
-            bail!(
-                "incompatible protocol version {}, expected {}",
-                msg.protocol_version,
-                SK_PROTOCOL_VERSION
-            );
-        }
+        let protocol_version = negotiate_protocol_version(msg.protocol_version)?;
         /* Postgres major version mismatch is treated as fatal error
          * because safekeepers parse WAL headers and the format
          * may change between versions.
@@ -649,14 +989,19 @@ where
             self.state.persist(&state)?;
         }
 
+        let features = negotiate_features(msg.features);
         info!(
-            "processed greeting from walproposer {}, sending term {:?}",
+            "processed greeting from walproposer {}, sending term {:?}, protocol version {}, features {:#x}",
             msg.proposer_id.map(|b| format!("{:X}", b)).join(""),
-            self.state.acceptor_state.term
+            self.state.acceptor_state.term,
+            protocol_version,
+            features,
         );
         Ok(Some(AcceptorProposerMessage::Greeting(AcceptorGreeting {
             term: self.state.acceptor_state.term,
             node_id: self.node_id,
+            protocol_version,
+            features,
         })))
     }
 
@@ -698,6 +1043,114 @@ where
         Ok(Some(AcceptorProposerMessage::VoteResponse(resp)))
     }
 
+    /// Bump term to `to`, or to the next one if not given, so that
+    /// proposers of older terms are rejected. Does nothing if the term is
+    /// already at least that high.
+    pub fn term_bump(&mut self, to: Option<Term>) -> Result<TermBump> {
+        let previous_term = self.state.acceptor_state.term;
+        let new_term = to.unwrap_or(previous_term + 1);
+        if new_term > previous_term {
+            // Like when voting, flush what we've already received so that
+            // the next proposer starts streaming at end of our WAL.
+            self.wal_store.flush_wal()?;
+            let mut state = self.state.clone();
+            state.acceptor_state.term = new_term;
+            self.state.persist(&state)?;
+            info!("bumped term from {} to {}", previous_term, new_term);
+        }
+        Ok(TermBump {
+            previous_term,
+            current_term: self.state.acceptor_state.term,
+        })
+    }
+
+    /// Add or replace the retention pin with the same name. Expired pins are
+    /// dropped along the way.
+    pub fn hold_wal(&mut self, pin: RetentionPin) -> Result<()> {
+        let now = unix_now();
+        let mut state = self.state.clone();
+        state
+            .retention_pins
+            .retain(|p| p.name != pin.name && !p.is_expired(now));
+        info!("holding WAL from {} for {:?}", pin.lsn, pin.name);
+        state.retention_pins.push(pin);
+        self.state.persist(&state)
+    }
+
+    /// Remove the retention pin, returns whether it existed. Expired pins
+    /// are dropped along the way.
+    pub fn release_wal(&mut self, name: &str) -> Result<bool> {
+        let now = unix_now();
+        let mut state = self.state.clone();
+        let existed = state.retention_pins.iter().any(|p| p.name == name);
+        state
+            .retention_pins
+            .retain(|p| p.name != name && !p.is_expired(now));
+        if state.retention_pins != self.state.retention_pins {
+            info!("released WAL held for {:?}", name);
+            self.state.persist(&state)?;
+        }
+        Ok(existed)
+    }
+
+    /// Append WAL fetched from a peer safekeeper, see `recovery`. The peer
+    /// streams only WAL of its current term, so it is accepted only if we
+    /// are in the epoch of that term as well: then both have WAL from the
+    /// single leader of the term, and the peer's is just longer.
+    pub fn handle_peer_wal(
+        &mut self,
+        term: Term,
+        begin_lsn: Lsn,
+        wal_data: &[u8],
+        commit_lsn: Lsn,
+    ) -> Result<()> {
+        if self.state.acceptor_state.term != term || self.get_epoch() != term {
+            bail!(
+                "peer WAL of term {} doesn't match term {} epoch {}",
+                term,
+                self.state.acceptor_state.term,
+                self.get_epoch()
+            );
+        }
+        self.check_not_before_start(begin_lsn)?;
+        if begin_lsn != self.wal_store.write_lsn() {
+            bail!(
+                "peer WAL starts at {}, but local WAL ends at {}",
+                begin_lsn,
+                self.wal_store.write_lsn()
+            );
+        }
+
+        self.write_wal(begin_lsn, wal_data)?;
+        self.wal_store.flush_wal()?;
+        if commit_lsn > self.inmem.commit_lsn {
+            self.update_commit_lsn(commit_lsn)?;
+        }
+        Ok(())
+    }
+
+    /// Write WAL, remembering commit times the storage found in it.
+    fn write_wal(&mut self, begin_lsn: Lsn, wal_data: &[u8]) -> Result<()> {
+        self.wal_store.write_wal(begin_lsn, wal_data)?;
+        for (end_lsn, ts) in self.wal_store.take_commit_times() {
+            self.record_time.observe_commit(end_lsn, ts);
+        }
+        Ok(())
+    }
+
+    /// WAL below timeline_start_lsn doesn't belong to the timeline, writing it
+    /// would make the state inconsistent.
+    fn check_not_before_start(&self, begin_lsn: Lsn) -> Result<()> {
+        if begin_lsn < self.state.timeline_start_lsn {
+            bail!(
+                "refusing WAL at {} below timeline_start_lsn {}",
+                begin_lsn,
+                self.state.timeline_start_lsn
+            );
+        }
+        Ok(())
+    }
+
     /// Form AppendResponse from current state.
     fn append_response(&self) -> AppendResponse {
         let ar = AppendResponse {
@@ -725,6 +1178,19 @@ where
             return Ok(None);
         }
 
+        // Timeline start is fixed once known, a proposer disagreeing with it
+        // would make us mix WAL of different histories. Lsn(1) is set by
+        // control file upgrade when the real start is unknown.
+        if self.state.timeline_start_lsn > Lsn(1)
+            && self.state.timeline_start_lsn != msg.timeline_start_lsn
+        {
+            bail!(
+                "refusing ProposerElected with timeline_start_lsn {}, timeline starts at {}",
+                msg.timeline_start_lsn,
+                self.state.timeline_start_lsn
+            );
+        }
+
         // This might happen in a rare race when another (old) connection from
         // the same walproposer writes + flushes WAL after this connection
         // already sent flush_lsn in VoteRequest. It is generally safe to
@@ -748,6 +1214,7 @@ where
 
         // truncate wal, update the LSNs
         self.wal_store.truncate_wal(msg.start_streaming_at)?;
+        self.record_time.reset(msg.start_streaming_at);
 
         // and now adopt term history from proposer
         {
@@ -825,7 +1292,8 @@ where
         Ok(())
     }
 
-    /// Persist control file to disk, called only after timeline creation (bootstrap).
+    /// Persist control file to disk, called after timeline creation
+    /// (bootstrap) and before it is evicted from memory.
     pub fn persist(&mut self) -> Result<()> {
         self.persist_control_file(self.state.clone())
     }
@@ -860,12 +1328,13 @@ where
         // Now we know that we are in the same term as the proposer,
         // processing the message.
 
+        self.check_not_before_start(msg.h.begin_lsn)?;
         self.epoch_start_lsn = msg.h.epoch_start_lsn;
         self.inmem.proposer_uuid = msg.h.proposer_uuid;
 
         // do the job
         if !msg.wal_data.is_empty() {
-            self.wal_store.write_wal(msg.h.begin_lsn, &msg.wal_data)?;
+            self.write_wal(msg.h.begin_lsn, &msg.wal_data)?;
         }
 
         // flush wal to the disk, if required
@@ -914,6 +1383,41 @@ where
         )))
     }
 
+    /// Switch to the configuration proposed by the elected proposer, if the
+    /// change is valid. Response carries the configuration in effect.
+    fn handle_configuration_change(
+        &mut self,
+        msg: &ConfigurationChangeRequest,
+    ) -> Result<Option<AcceptorProposerMessage>> {
+        if self.state.acceptor_state.term < msg.term {
+            bail!("got ConfigurationChangeRequest before ProposerElected");
+        }
+
+        // Change only if our term is not higher; otherwise the response
+        // informs the proposer it is outdated.
+        if self.state.acceptor_state.term == msg.term
+            && self.state.configuration != msg.configuration
+        {
+            self.state
+                .configuration
+                .validate_change(&msg.configuration)?;
+            let mut state = self.state.clone();
+            state.configuration = msg.configuration.clone();
+            self.state.persist(&state)?;
+            info!(
+                "switched to configuration {:?} in term {}",
+                self.state.configuration, msg.term
+            );
+        }
+
+        Ok(Some(AcceptorProposerMessage::ConfigurationChangeResponse(
+            ConfigurationChangeResponse {
+                term: self.state.acceptor_state.term,
+                configuration: self.state.configuration.clone(),
+            },
+        )))
+    }
+
     /// Update timeline state with peer safekeeper data.
     pub fn record_safekeeper_info(&mut self, sk_info: &SafekeeperTimelineInfo) -> Result<()> {
         let mut sync_control_file = false;
@@ -954,18 +1458,51 @@ where
 
     /// Get oldest segno we still need to keep. We hold WAL till it is consumed
     /// by all of 1) pageserver (remote_consistent_lsn) 2) peers 3) s3
-    /// offloading.
+    /// offloading, and while it is pinned by unexpired retention pins. With
+    /// remove_offloaded_wal, pageserver is not waited for, as it can read
+    /// offloaded WAL from s3.
     /// While it is safe to use inmem values for determining horizon,
     /// we use persistent to make possible normal states less surprising.
-    pub fn get_horizon_segno(&self, wal_backup_enabled: bool) -> XLogSegNo {
-        let mut horizon_lsn = min(
-            self.state.remote_consistent_lsn,
-            self.state.peer_horizon_lsn,
-        );
+    pub fn get_horizon_segno(
+        &self,
+        wal_backup_enabled: bool,
+        remove_offloaded_wal: bool,
+    ) -> XLogSegNo {
+        let (horizon_lsn, _) = self.get_horizon(wal_backup_enabled, remove_offloaded_wal);
+        horizon_lsn.segment_number(self.state.server.wal_seg_size as usize)
+    }
+
+    /// Same as `get_horizon_segno`, but returns the horizon LSN along with
+    /// the name of what holds it.
+    pub fn get_horizon(
+        &self,
+        wal_backup_enabled: bool,
+        remove_offloaded_wal: bool,
+    ) -> (Lsn, String) {
+        let mut horizon = (self.state.peer_horizon_lsn, "peer_horizon_lsn".to_owned());
+        let mut hold = |lsn: Lsn, reason: &dyn Fn() -> String| {
+            if lsn < horizon.0 {
+                horizon = (lsn, reason());
+            }
+        };
+        if !(wal_backup_enabled && remove_offloaded_wal) {
+            hold(self.state.remote_consistent_lsn, &|| {
+                "remote_consistent_lsn".to_owned()
+            });
+        }
         if wal_backup_enabled {
-            horizon_lsn = min(horizon_lsn, self.state.backup_lsn);
+            hold(self.state.backup_lsn, &|| "backup_lsn".to_owned());
         }
-        horizon_lsn.segment_number(self.state.server.wal_seg_size as usize)
+        let now = unix_now();
+        for pin in self
+            .state
+            .retention_pins
+            .iter()
+            .filter(|p| !p.is_expired(now))
+        {
+            hold(pin.lsn, &|| format!("retention pin {:?}", pin.name));
+        }
+        horizon
     }
 }
 
@@ -1014,6 +1551,10 @@ mod tests {
             self.lsn
         }
 
+        fn write_lsn(&self) -> Lsn {
+            self.lsn
+        }
+
         fn write_wal(&mut self, startpos: Lsn, buf: &[u8]) -> Result<()> {
             self.lsn = startpos + buf.len() as u64;
             Ok(())
@@ -1037,6 +1578,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_epoch_start_lsn() {
+        let state = AcceptorState {
+            term: 3,
+            term_history: TermHistory(vec![
+                TermSwitchEntry {
+                    term: 1,
+                    lsn: Lsn(0x10),
+                },
+                TermSwitchEntry {
+                    term: 3,
+                    lsn: Lsn(0x30),
+                },
+            ]),
+        };
+        assert_eq!(state.get_epoch(Lsn(0x20)), 1);
+        assert_eq!(state.get_epoch_start_lsn(Lsn(0x20)), Lsn(0x10));
+        assert_eq!(state.get_epoch(Lsn(0x30)), 3);
+        assert_eq!(state.get_epoch_start_lsn(Lsn(0x30)), Lsn(0x30));
+        assert_eq!(state.get_epoch_start_lsn(Lsn(0x5)), Lsn::INVALID);
+    }
+
     #[test]
     fn test_voting() {
         let storage = InMemoryState {
@@ -1121,4 +1684,430 @@ mod tests {
         sk.wal_store.truncate_wal(Lsn(3)).unwrap(); // imitate the complete record at 3 %)
         assert_eq!(sk.get_epoch(), 1);
     }
+
+    #[test]
+    fn test_timeline_start_lsn() {
+        let mut state = test_sk_state();
+        state.timeline_start_lsn = Lsn(100);
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+
+        let elected = |timeline_start_lsn| {
+            ProposerAcceptorMessage::Elected(ProposerElected {
+                term: 1,
+                start_streaming_at: Lsn(200),
+                term_history: TermHistory(vec![TermSwitchEntry {
+                    term: 1,
+                    lsn: Lsn(200),
+                }]),
+                timeline_start_lsn,
+            })
+        };
+        // proposer disagreeing on the timeline start is refused
+        assert!(sk.process_msg(&elected(Lsn(200))).is_err());
+        sk.process_msg(&elected(Lsn(100))).unwrap();
+
+        // as well as WAL below the timeline start
+        let append_request = AppendRequest {
+            h: AppendRequestHeader {
+                term: 1,
+                epoch_start_lsn: Lsn(200),
+                begin_lsn: Lsn(50),
+                end_lsn: Lsn(60),
+                commit_lsn: Lsn(0),
+                truncate_lsn: Lsn(0),
+                proposer_uuid: [0; 16],
+            },
+            wal_data: Bytes::from_static(b"b"),
+        };
+        assert!(sk
+            .process_msg(&ProposerAcceptorMessage::AppendRequest(append_request))
+            .is_err());
+    }
+
+    #[test]
+    fn test_timeline_create_params() {
+        let server_info = ServerInfo {
+            pg_version: 150000,
+            system_id: 0,
+            wal_seg_size: WAL_SEGMENT_SIZE as u32,
+        };
+        assert!(TimelineCreateParams::uninitialized(server_info.clone()).is_ok());
+        assert!(
+            TimelineCreateParams::new(server_info.clone(), Lsn(100), Lsn(100), Lsn(200)).is_ok()
+        );
+        // local WAL can't start before the timeline
+        assert!(
+            TimelineCreateParams::new(server_info.clone(), Lsn(100), Lsn(50), Lsn(200)).is_err()
+        );
+        // nor commit_lsn be below it
+        assert!(
+            TimelineCreateParams::new(server_info.clone(), Lsn(100), Lsn(100), Lsn(50)).is_err()
+        );
+        assert!(TimelineCreateParams::new(
+            server_info.clone(),
+            Lsn(100),
+            Lsn::INVALID,
+            Lsn::INVALID
+        )
+        .is_err());
+        let server_info = ServerInfo {
+            wal_seg_size: 1000,
+            ..server_info
+        };
+        assert!(TimelineCreateParams::uninitialized(server_info).is_err());
+    }
+
+    #[test]
+    fn test_horizon_segno() {
+        let seg = WAL_SEGMENT_SIZE as u64;
+        let mut state = test_sk_state();
+        state.peer_horizon_lsn = Lsn(10 * seg);
+        state.backup_lsn = Lsn(5 * seg);
+        state.remote_consistent_lsn = Lsn(2 * seg);
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+
+        assert_eq!(sk.get_horizon_segno(false, false), 2);
+        assert_eq!(sk.get_horizon_segno(true, false), 2);
+        // pageserver lagging doesn't hold offloaded WAL
+        assert_eq!(sk.get_horizon_segno(true, true), 5);
+        // but without offloading it is the only copy
+        assert_eq!(sk.get_horizon_segno(false, true), 2);
+        assert_eq!(
+            sk.get_horizon(true, true),
+            (Lsn(5 * seg), "backup_lsn".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_retention_pins() {
+        let seg = WAL_SEGMENT_SIZE as u64;
+        let mut state = test_sk_state();
+        state.peer_horizon_lsn = Lsn(10 * seg);
+        state.backup_lsn = Lsn(10 * seg);
+        state.remote_consistent_lsn = Lsn(10 * seg);
+        let storage = InMemoryState {
+            persisted_state: state,
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+        assert_eq!(sk.get_horizon_segno(true, false), 10);
+
+        let pin = |name: &str, segno: u64, expires_at| RetentionPin {
+            name: name.to_owned(),
+            lsn: Lsn(segno * seg),
+            expires_at,
+        };
+        sk.hold_wal(pin("branch", 3, None)).unwrap();
+        sk.hold_wal(pin("expired", 1, Some(unix_now() - 1)))
+            .unwrap();
+        assert_eq!(sk.get_horizon_segno(true, false), 3);
+        // re-pinning replaces the pin
+        sk.hold_wal(pin("branch", 4, None)).unwrap();
+        assert_eq!(sk.state.retention_pins.len(), 1);
+        assert_eq!(sk.get_horizon_segno(true, false), 4);
+
+        assert!(sk.release_wal("branch").unwrap());
+        assert!(!sk.release_wal("branch").unwrap());
+        assert_eq!(sk.get_horizon_segno(true, false), 10);
+    }
+
+    #[test]
+    fn test_term_bump() {
+        let storage = InMemoryState {
+            persisted_state: test_sk_state(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+        sk.process_msg(&ProposerAcceptorMessage::Elected(ProposerElected {
+            term: 1,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(1),
+        }))
+        .unwrap();
+
+        let resp = sk.term_bump(None).unwrap();
+        assert_eq!((resp.previous_term, resp.current_term), (1, 2));
+        let resp = sk.term_bump(Some(5)).unwrap();
+        assert_eq!((resp.previous_term, resp.current_term), (2, 5));
+        // never goes down
+        let resp = sk.term_bump(Some(3)).unwrap();
+        assert_eq!((resp.previous_term, resp.current_term), (5, 5));
+        assert_eq!(sk.state.persisted_state.acceptor_state.term, 5);
+
+        // the old proposer is refused
+        let append_request = AppendRequest {
+            h: AppendRequestHeader {
+                term: 1,
+                epoch_start_lsn: Lsn(1),
+                begin_lsn: Lsn(1),
+                end_lsn: Lsn(2),
+                commit_lsn: Lsn(0),
+                truncate_lsn: Lsn(0),
+                proposer_uuid: [0; 16],
+            },
+            wal_data: Bytes::from_static(b"b"),
+        };
+        match sk
+            .process_msg(&ProposerAcceptorMessage::AppendRequest(append_request))
+            .unwrap()
+        {
+            Some(AcceptorProposerMessage::AppendResponse(resp)) => {
+                assert_eq!(resp.term, 5);
+                assert_eq!(resp.flush_lsn, Lsn(0));
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    fn greeting_bytes(protocol_version: u32, features: Option<u64>) -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u64_le('g' as u64);
+        buf.put_u32_le(protocol_version);
+        buf.put_u32_le(150000); // pg_version
+        buf.put_slice(&[0; 16]); // proposer_id
+        buf.put_u64_le(0); // system_id
+        buf.put_slice(&[1; 16]); // timeline_id
+        buf.put_slice(&[1; 16]); // tenant_id
+        buf.put_u32_le(1); // tli
+        buf.put_u32_le(WAL_SEGMENT_SIZE as u32);
+        if let Some(features) = features {
+            buf.put_u64_le(features);
+        }
+        buf.freeze()
+    }
+
+    #[test]
+    fn test_protocol_negotiation() {
+        assert!(negotiate_protocol_version(1).is_err());
+        assert_eq!(negotiate_protocol_version(2).unwrap(), 2);
+        assert_eq!(negotiate_protocol_version(3).unwrap(), 3);
+        assert_eq!(
+            negotiate_protocol_version(SK_PROTOCOL_VERSION + 1).unwrap(),
+            SK_PROTOCOL_VERSION
+        );
+
+        let storage = InMemoryState {
+            persisted_state: test_sk_state(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+
+        // old proposer gets the old greeting
+        let msg = ProposerAcceptorMessage::parse(greeting_bytes(2, None), 0).unwrap();
+        let resp = sk.process_msg(&msg).unwrap().unwrap();
+        let mut buf = BytesMut::new();
+        resp.serialize(&mut buf, 0).unwrap();
+        assert_eq!(buf.len(), 24);
+
+        // new one learns the version and common features
+        let msg = ProposerAcceptorMessage::parse(greeting_bytes(3, Some(0b1001)), 0).unwrap();
+        match sk.process_msg(&msg).unwrap() {
+            Some(AcceptorProposerMessage::Greeting(greeting)) => {
+                assert_eq!(greeting.protocol_version, 3);
+                assert_eq!(greeting.features, proto_features::CONFIGURATION_CHANGE);
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+
+        // configuration change needs the feature negotiated, whatever the
+        // version is
+        let mut buf = BytesMut::new();
+        buf.put_u64_le('c' as u64);
+        buf.put_u64_le(1);
+        buf.put_u32_le(1);
+        buf.put_u32_le(0);
+        buf.put_u32_le(0);
+        let msg = buf.freeze();
+        assert!(ProposerAcceptorMessage::parse(msg.clone(), 0).is_err());
+        assert!(ProposerAcceptorMessage::parse(msg, proto_features::CONFIGURATION_CHANGE).is_ok());
+    }
+
+    #[test]
+    fn test_frame_crc() {
+        let mut buf = BytesMut::new();
+        buf.put_u64_le('a' as u64);
+        buf.put_u64_le(1); // term
+        buf.put_u64_le(0x10); // epoch_start_lsn
+        buf.put_u64_le(0x10); // begin_lsn
+        buf.put_u64_le(0x14); // end_lsn
+        buf.put_u64_le(0x10); // commit_lsn
+        buf.put_u64_le(0); // truncate_lsn
+        buf.put_slice(&[0; 16]); // proposer_uuid
+        buf.put_slice(b"wal!");
+        let crc = crc32c::crc32c(&buf);
+        buf.put_u32_le(crc);
+        let msg = buf.freeze();
+
+        let features = proto_features::FRAME_CRC;
+        match ProposerAcceptorMessage::parse(msg.clone(), features).unwrap() {
+            ProposerAcceptorMessage::AppendRequest(req) => {
+                assert_eq!(&req.wal_data[..], b"wal!")
+            }
+            m => panic!("unexpected message: {:?}", m),
+        }
+        let mut corrupted = msg.to_vec();
+        corrupted[60] ^= 1;
+        assert!(ProposerAcceptorMessage::parse(corrupted.into(), features).is_err());
+
+        let resp = AcceptorProposerMessage::AppendResponse(AppendResponse::term_only(1));
+        let mut plain = BytesMut::new();
+        resp.serialize(&mut plain, 0).unwrap();
+        let mut buf = BytesMut::from(&b"previous"[..]);
+        resp.serialize(&mut buf, features).unwrap();
+        assert_eq!(&buf[8..8 + plain.len()], &plain[..]);
+        assert_eq!(buf[8 + plain.len()..], crc32c::crc32c(&plain).to_le_bytes());
+    }
+
+    #[test]
+    fn test_peer_wal() {
+        let storage = InMemoryState {
+            persisted_state: test_sk_state(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+        sk.process_msg(&ProposerAcceptorMessage::Elected(ProposerElected {
+            term: 1,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(1),
+        }))
+        .unwrap();
+
+        // WAL of another term or not continuing ours is refused
+        assert!(sk.handle_peer_wal(2, Lsn(1), b"abc", Lsn(0)).is_err());
+        assert!(sk.handle_peer_wal(1, Lsn(2), b"abc", Lsn(0)).is_err());
+
+        sk.handle_peer_wal(1, Lsn(1), b"abc", Lsn(3)).unwrap();
+        assert_eq!(sk.wal_store.flush_lsn(), Lsn(4));
+        assert_eq!(sk.inmem.commit_lsn, Lsn(3));
+        // commit_lsn doesn't go beyond our WAL
+        sk.handle_peer_wal(1, Lsn(4), b"d", Lsn(10)).unwrap();
+        assert_eq!(sk.inmem.commit_lsn, Lsn(5));
+
+        // once we voted in a higher term, the WAL may be outdated
+        sk.term_bump(None).unwrap();
+        assert!(sk.handle_peer_wal(1, Lsn(5), b"e", Lsn(0)).is_err());
+    }
+
+    fn nodes(ids: &[u64]) -> Vec<NodeId> {
+        ids.iter().map(|id| NodeId(*id)).collect()
+    }
+
+    #[test]
+    fn test_sync_safekeepers_reply() {
+        let storage = InMemoryState {
+            persisted_state: test_sk_state(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0x40) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(0)).unwrap();
+
+        let features = match sk
+            .process_msg(
+                &ProposerAcceptorMessage::parse(
+                    greeting_bytes(3, Some(proto_features::SYNC_SAFEKEEPERS)),
+                    0,
+                )
+                .unwrap(),
+            )
+            .unwrap()
+        {
+            Some(AcceptorProposerMessage::Greeting(greeting)) => greeting.features,
+            r => panic!("unexpected response: {:?}", r),
+        };
+        assert_eq!(features, proto_features::SYNC_SAFEKEEPERS);
+        sk.process_msg(&ProposerAcceptorMessage::VoteRequest(VoteRequest {
+            term: 1,
+        }))
+        .unwrap();
+
+        let elected = ProposerAcceptorMessage::Elected(ProposerElected {
+            term: 1,
+            start_streaming_at: Lsn(0x40),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(0x40),
+            }]),
+            timeline_start_lsn: Lsn(0x10),
+        });
+        assert!(!needs_flush_reply(&elected, 0));
+        assert!(needs_flush_reply(&elected, features));
+        assert!(!needs_flush_reply(
+            &ProposerAcceptorMessage::VoteRequest(VoteRequest { term: 1 }),
+            features
+        ));
+
+        // the fast path: flush_lsn is reported right after ProposerElected
+        assert!(sk.process_msg(&elected).unwrap().is_none());
+        match sk.process_msg(&ProposerAcceptorMessage::FlushWAL).unwrap() {
+            Some(AcceptorProposerMessage::AppendResponse(resp)) => {
+                assert_eq!(resp.term, 1);
+                assert_eq!(resp.flush_lsn, Lsn(0x40));
+                assert_eq!(resp.commit_lsn, Lsn(0x10));
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_configuration_change() {
+        let storage = InMemoryState {
+            persisted_state: test_sk_state(),
+        };
+        let wal_store = DummyWalStore { lsn: Lsn(0) };
+        let mut sk = SafeKeeper::new(storage, wal_store, NodeId(1)).unwrap();
+        sk.process_msg(&ProposerAcceptorMessage::Elected(ProposerElected {
+            term: 1,
+            start_streaming_at: Lsn(1),
+            term_history: TermHistory(vec![TermSwitchEntry {
+                term: 1,
+                lsn: Lsn(1),
+            }]),
+            timeline_start_lsn: Lsn(1),
+        }))
+        .unwrap();
+
+        let mut change = |generation, members: &[u64], new_members: Option<&[u64]>| {
+            sk.process_msg(&ProposerAcceptorMessage::ConfigurationChange(
+                ConfigurationChangeRequest {
+                    term: 1,
+                    configuration: Configuration {
+                        generation,
+                        members: nodes(members),
+                        new_members: new_members.map(nodes),
+                    },
+                },
+            ))
+        };
+        change(1, &[1, 2, 3], None).unwrap();
+        // must go through joint configuration
+        assert!(change(2, &[1, 2, 4], None).is_err());
+        change(2, &[1, 2, 3], Some(&[1, 2, 4])).unwrap();
+        // stale generation
+        assert!(change(2, &[1, 2, 4], None).is_err());
+        let resp = change(3, &[1, 2, 4], None).unwrap();
+        match resp {
+            Some(AcceptorProposerMessage::ConfigurationChangeResponse(resp)) => {
+                assert_eq!(resp.configuration.generation, 3);
+                assert_eq!(resp.configuration.members, nodes(&[1, 2, 4]));
+                assert!(!resp.configuration.is_joint());
+            }
+            r => panic!("unexpected response: {:?}", r),
+        }
+        assert_eq!(sk.state.configuration.generation, 3);
+    }
 }
@@ -1,24 +1,31 @@
 //! This module implements the streaming side of replication protocol, starting
 //! with the "START_REPLICATION" message.
 
+use crate::connections::{self, ConnectionKind};
+use crate::drain;
 use crate::handler::SafekeeperPostgresHandler;
-use crate::timeline::{ReplicaState, Timeline};
+use crate::rate_limit::WalSendLimiter;
+use crate::safekeeper::SafeKeeperState;
+use crate::timeline::{ConsumerKind, ReplicaState, Timeline};
+use crate::wal_check::record_start_lsn;
 use crate::wal_storage::WalReader;
-use crate::GlobalTimelines;
+use crate::{GlobalTimelines, SafeKeeperConf};
 use anyhow::Context;
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use postgres_ffi::get_current_timestamp;
+use postgres_ffi::waldecoder::split_on_record_boundaries;
 use postgres_ffi::{TimestampTz, MAX_SEND_SIZE};
 use serde::{Deserialize, Serialize};
 use std::cmp::min;
 use std::net::Shutdown;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{io, str, thread};
-use utils::postgres_backend_async::QueryError;
+use utils::cancel_registry::CancellationToken;
+use utils::postgres_backend_async::{CopyMode, QueryError};
 
-use pq_proto::{BeMessage, FeMessage, ReplicationFeedback, WalSndKeepAlive, XLogDataBody};
+use pq_proto::{BeMessage, FeMessage, ReplicationFeedback, WalSndKeepAlive, XLogDataBytes};
 use tokio::sync::watch::Receiver;
 use tokio::time::timeout;
 use tracing::*;
@@ -48,10 +55,35 @@ impl HotStandbyFeedback {
             catalog_xmin: 0,
         }
     }
+
+    /// Parse hot standby feedback message body (without the tag byte), as
+    /// sent by postgres: 32-bit xids along with their epochs. Invalid xmin
+    /// means the standby doesn't hold back the horizon, so it is turned into
+    /// u64::MAX to not affect the minimum over all replicas.
+    pub fn parse(mut buf: &[u8]) -> anyhow::Result<HotStandbyFeedback> {
+        use bytes::Buf;
+        anyhow::ensure!(
+            buf.len() >= 24,
+            "hot standby feedback is too short: {}",
+            buf.len()
+        );
+        let ts = buf.get_i64();
+        let full_xid = |xid: u32, epoch: u32| match xid {
+            0 => u64::MAX,
+            xid => (epoch as u64) << 32 | xid as u64,
+        };
+        let (xmin, xmin_epoch) = (buf.get_u32(), buf.get_u32());
+        let (catalog_xmin, catalog_xmin_epoch) = (buf.get_u32(), buf.get_u32());
+        Ok(HotStandbyFeedback {
+            ts,
+            xmin: full_xid(xmin, xmin_epoch),
+            catalog_xmin: full_xid(catalog_xmin, catalog_xmin_epoch),
+        })
+    }
 }
 
 /// Standby status update
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct StandbyReply {
     pub write_lsn: Lsn, // last lsn received by pageserver
     pub flush_lsn: Lsn, // pageserver's disk consistent lSN
@@ -65,12 +97,15 @@ pub struct ReplicationConn {
     /// This is an `Option` because we will spawn a background thread that will
     /// `take` it from us.
     stream_in: Option<ReadStream>,
+    max_message_len: usize,
 }
 
 /// Scope guard to unregister replication connection from timeline
 struct ReplicationConnGuard {
     replica: usize, // replica internal ID assigned by timeline
     timeline: Arc<Timeline>,
+    /// When the replica sent anything last time.
+    last_feedback_at: Mutex<Instant>,
 }
 
 impl Drop for ReplicationConnGuard {
@@ -84,6 +119,7 @@ impl ReplicationConn {
     pub fn new(pgb: &mut PostgresBackend) -> Self {
         Self {
             stream_in: pgb.take_stream_in(),
+            max_message_len: pgb.message_limits.max_message_len,
         }
     }
 
@@ -91,14 +127,16 @@ impl ReplicationConn {
     /// This is spawned into the background by `handle_start_replication`.
     fn background_thread(
         mut stream_in: ReadStream,
+        max_message_len: usize,
         replica_guard: Arc<ReplicationConnGuard>,
+        mut state: ReplicaState,
     ) -> anyhow::Result<()> {
         let replica_id = replica_guard.replica;
         let timeline = &replica_guard.timeline;
 
-        let mut state = ReplicaState::new();
         // Wait for replica's feedback.
-        while let Some(msg) = FeMessage::read(&mut stream_in)? {
+        while let Some(msg) = FeMessage::read_limited(&mut stream_in, max_message_len)? {
+            *replica_guard.last_feedback_at.lock().unwrap() = Instant::now();
             match &msg {
                 FeMessage::CopyData(m) => {
                     // There's three possible data messages that the client is supposed to send here:
@@ -106,20 +144,24 @@ impl ReplicationConn {
 
                     match m.first().cloned() {
                         Some(HOT_STANDBY_FEEDBACK_TAG_BYTE) => {
-                            // Note: deserializing is on m[1..] because we skip the tag byte.
-                            state.hs_feedback = HotStandbyFeedback::des(&m[1..])
-                                .context("failed to deserialize HotStandbyFeedback")?;
+                            // Note: parsing is on m[1..] because we skip the tag byte.
+                            state.hs_feedback = HotStandbyFeedback::parse(&m[1..])
+                                .context("failed to parse HotStandbyFeedback")?;
+                            trace!("HotStandbyFeedback is {:?}", state.hs_feedback);
                             timeline.update_replica_state(replica_id, state);
                         }
                         Some(STANDBY_STATUS_UPDATE_TAG_BYTE) => {
-                            let _reply = StandbyReply::des(&m[1..])
-                                .context("failed to deserialize StandbyReply")?;
                             // This must be a regular postgres replica,
                             // because pageserver doesn't send this type of messages to safekeeper.
-                            // Currently this is not implemented, so this message is ignored.
-
-                            warn!("unexpected StandbyReply. Read-only postgres replicas are not supported in safekeepers yet.");
-                            // timeline.update_replica_state(replica_id, Some(state));
+                            // WAL it hasn't flushed yet is kept while it is connected.
+                            let reply = StandbyReply::des(&m[1..])
+                                .context("failed to deserialize StandbyReply")?;
+                            trace!("StandbyReply is {:?}", reply);
+                            state.standby_reply = Some(reply);
+                            if state.kind == ConsumerKind::Unknown {
+                                state.kind = ConsumerKind::Standby;
+                            }
+                            timeline.update_replica_state(replica_id, state);
                         }
                         Some(NEON_STATUS_UPDATE_TAG_BYTE) => {
                             // Note: deserializing is on m[9..] because we skip the tag byte and len bytes.
@@ -130,6 +172,7 @@ impl ReplicationConn {
                             // Only pageserver sends ReplicationFeedback, so set the flag.
                             // This replica is the source of information to resend to compute.
                             state.pageserver_feedback = Some(reply);
+                            state.kind = ConsumerKind::Pageserver;
 
                             timeline.update_replica_state(replica_id, state);
                         }
@@ -137,7 +180,7 @@ impl ReplicationConn {
                     }
                 }
                 FeMessage::Sync => {}
-                FeMessage::CopyFail => {
+                FeMessage::CopyFail(_) => {
                     // Shutdown the connection, because rust-postgres client cannot be dropped
                     // when connection is alive.
                     let _ = stream_in.shutdown(Shutdown::Both);
@@ -161,6 +204,8 @@ impl ReplicationConn {
         spg: &mut SafekeeperPostgresHandler,
         pgb: &mut PostgresBackend,
         mut start_pos: Lsn,
+        until_pos: Option<Lsn>,
+        cancel: &CancellationToken,
     ) -> Result<(), QueryError> {
         let _enter = info_span!("WAL sender", ttid = %spg.ttid).entered();
 
@@ -169,9 +214,29 @@ impl ReplicationConn {
         // spawn the background thread which receives HotStandbyFeedback messages.
         let bg_timeline = Arc::clone(&tli);
         let bg_stream_in = self.stream_in.take().unwrap();
+        let max_message_len = self.max_message_len;
         let bg_timeline_id = spg.timeline_id.unwrap();
 
-        let state = ReplicaState::new();
+        let kind = if spg.is_walproposer_recovery() || spg.is_peer_recovery() {
+            ConsumerKind::Recovery
+        } else if spg.appname.as_deref() == Some("pageserver") {
+            ConsumerKind::Pageserver
+        } else {
+            ConsumerKind::Unknown
+        };
+        let mut options = spg.negotiated_options();
+        if let Some(until_pos) = until_pos {
+            options.insert("until_lsn".to_owned(), until_pos.to_string());
+        }
+        let conn = connections::register(
+            spg.ttid,
+            ConnectionKind::Replication,
+            spg.appname.clone(),
+            *pgb.get_peer_addr(),
+            options,
+            start_pos,
+        );
+        let state = ReplicaState::for_consumer(kind, start_pos);
         // This replica_id is used below to check if it's time to stop replication.
         let replica_id = bg_timeline.add_replica(state);
 
@@ -180,6 +245,7 @@ impl ReplicationConn {
         let replica_guard = Arc::new(ReplicationConnGuard {
             replica: replica_id,
             timeline: bg_timeline,
+            last_feedback_at: Mutex::new(Instant::now()),
         });
         let bg_replica_guard = Arc::clone(&replica_guard);
 
@@ -190,18 +256,27 @@ impl ReplicationConn {
             .spawn(move || {
                 let _enter =
                     info_span!("HotStandbyFeedback thread", timeline = %bg_timeline_id).entered();
-                if let Err(err) = Self::background_thread(bg_stream_in, bg_replica_guard) {
+                if let Err(err) =
+                    Self::background_thread(bg_stream_in, max_message_len, bg_replica_guard, state)
+                {
                     error!("Replication background thread failed: {}", err);
                 }
             })?;
 
+        let cancellation = cancel.clone();
+
         let runtime = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()?;
 
         runtime.block_on(async move {
             let (inmem_state, persisted_state) = tli.get_state();
-            // add persisted_state.timeline_start_lsn == Lsn(0) check
+            if !persisted_state.is_initialized() {
+                return Err(QueryError::Other(anyhow::anyhow!(
+                    "timeline {} is not initialized yet",
+                    tli.ttid
+                )));
+            }
 
             // Walproposer gets special handling: safekeeper must give proposer all
             // local WAL till the end, whether committed or not (walproposer will
@@ -212,46 +287,85 @@ impl ReplicationConn {
             // another compute rises which collects majority and starts fixing log
             // on this safekeeper itself. That's ok as (old) proposer will never be
             // able to commit such WAL.
-            let stop_pos: Option<Lsn> = if spg.is_walproposer_recovery() {
+            //
+            // Peer safekeepers recovering from us get uncommitted WAL as well,
+            // but only of the term they expect, see `recovery`.
+            //
+            // Other clients may ask to stop at some LSN, but never get uncommitted WAL.
+            let recovery = spg.is_walproposer_recovery() || spg.is_peer_recovery();
+            let stop_pos: Option<Lsn> = if recovery {
                 let wal_end = tli.get_flush_lsn();
-                Some(wal_end)
+                Some(until_pos.map_or(wal_end, |until_pos| min(until_pos, wal_end)))
             } else {
-                None
+                until_pos
             };
 
             info!("Start replication from {:?} till {:?}", start_pos, stop_pos);
 
             // switch to copy
-            pgb.write_message(&BeMessage::CopyBothResponse)?;
+            pgb.start_copy(CopyMode::Both)?;
+            pgb.set_write_stall_timeout(spg.conf.replication_write_timeout)?;
 
             let mut end_pos = stop_pos.unwrap_or(inmem_state.commit_lsn);
 
-            let mut wal_reader = WalReader::new(
-                spg.conf.workdir.clone(),
-                spg.conf.timeline_dir(&tli.ttid),
+            // if the client asked for compression, each XLogData message
+            // carries a separate zstd frame, wal_start and wal_end still refer
+            // to the uncompressed WAL.
+            let mut chunker = WalChunker::new(
+                &spg.conf,
+                &tli,
                 &persisted_state,
                 start_pos,
-                spg.conf.wal_backup_enabled,
+                spg.compress_wal,
             )?;
-
-            // buffer for wal sending, limited by MAX_SEND_SIZE
-            let mut send_buf = vec![0u8; MAX_SEND_SIZE];
+            let limiter = WalSendLimiter::new(&spg.conf, tli.ttid.tenant_id);
 
             // watcher for commit_lsn updates
             let mut commit_lsn_watch_rx = tli.get_commit_lsn_watch_rx();
 
             loop {
+                if drain::is_draining() {
+                    info!("safekeeper is shutting down, stopping at {}", start_pos);
+                    pgb.finish_copy_out()?
+                        .write_message(&BeMessage::CommandComplete(b"START_REPLICATION"))?;
+                    return Ok(());
+                }
+                if cancellation.is_cancelled() {
+                    info!("replication cancelled by client at {}", start_pos);
+                    return Err(QueryError::Cancelled);
+                }
+                if tli.is_cancelled() {
+                    return Err(QueryError::from(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        format!("timeline {} was deleted", tli.ttid),
+                    )));
+                }
+                if let Some(timeout) = spg.conf.replication_reply_timeout {
+                    let silent_for = replica_guard.last_feedback_at.lock().unwrap().elapsed();
+                    if !recovery && silent_for >= timeout {
+                        // dropping the connection releases WAL the replica holds
+                        return Err(QueryError::from(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!(
+                                "{:?} didn't reply to keepalive requests for {:?}",
+                                spg.appname, silent_for
+                            ),
+                        )));
+                    }
+                }
                 if let Some(stop_pos) = stop_pos {
                     if start_pos >= stop_pos {
-                        break; /* recovery finished */
+                        break; /* recovery finished or requested end reached */
                     }
-                    end_pos = stop_pos;
+                }
+                if recovery {
+                    end_pos = stop_pos.unwrap();
                 } else {
                     /* Wait until we have some data to stream */
                     let lsn = wait_for_lsn(&mut commit_lsn_watch_rx, start_pos).await?;
 
                     if let Some(lsn) = lsn {
-                        end_pos = lsn;
+                        end_pos = stop_pos.map_or(lsn, |stop_pos| min(lsn, stop_pos));
                     } else {
                         // TODO: also check once in a while whether we are walsender
                         // to right pageserver.
@@ -273,37 +387,227 @@ impl ReplicationConn {
                     }
                 }
 
-                let send_size = end_pos.checked_sub(start_pos).unwrap().0 as usize;
-                let send_size = min(send_size, send_buf.len());
+                let send_size = chunker.fill(end_pos).await?;
 
-                let send_buf = &mut send_buf[..send_size];
+                let throttle = limiter.throttle(send_size as u64);
+                if !throttle.is_zero() {
+                    tokio::time::sleep(throttle).await;
+                }
 
-                // read wal into buffer
-                let send_size = wal_reader.read(send_buf).await?;
-                let send_buf = &send_buf[..send_size];
+                // WAL is overwritten only after term change, so if the term is
+                // still the same, what we've read belongs to it.
+                if let Some(recovery_term) = spg.recovery_term {
+                    let term = tli.get_term();
+                    if term != recovery_term {
+                        return Err(QueryError::Other(anyhow::anyhow!(
+                            "term changed from {recovery_term} to {term}, stopping recovery"
+                        )));
+                    }
+                }
 
                 // Write some data to the network socket.
-                pgb.write_message(&BeMessage::XLogData(XLogDataBody {
+                pgb.write_message(&BeMessage::XLogDataBytes(XLogDataBytes {
                     wal_start: start_pos.0,
                     wal_end: end_pos.0,
                     timestamp: get_current_timestamp(),
-                    data: send_buf,
+                    data: chunker.take_chunk(send_size)?,
                 }))
                 .context("Failed to send XLogData")?;
 
                 start_pos += send_size as u64;
+                tli.observe_wal_sent(send_size as u64);
+                conn.observe(start_pos, send_size as u64);
                 trace!("sent WAL up to {}", start_pos);
             }
 
+            if !spg.is_walproposer_recovery() && stop_pos.is_some() {
+                // Like walsender at the end of timeline, finish the COPY and
+                // complete the command.
+                info!("reached requested end of streaming {}", start_pos);
+                pgb.finish_copy_out()?
+                    .write_message(&BeMessage::CommandComplete(b"START_REPLICATION"))?;
+            }
+
             Ok(())
         })
     }
 }
 
+/// Reads WAL of the timeline sequentially and cuts it into chunks to send,
+/// ending on record boundaries when possible and, if asked, compressed into
+/// separate zstd frames. Shared by the replication protocol and the gRPC
+/// service.
+pub(crate) struct WalChunker {
+    wal_reader: WalReader,
+    /// Position of the first byte of `pending`.
+    start_pos: Lsn,
+    /// WAL read but not sent yet, limited by MAX_SEND_SIZE.
+    pending: BytesMut,
+    /// Start of the record `pending` begins with or continues, so that
+    /// chunks end on record boundaries and the client doesn't need to buffer
+    /// partial records. None if alignment is given up: decoding must start
+    /// at a record boundary, and if the client asked for another position
+    /// (e.g. segment start) or WAL can't be decoded, chunks are cut at
+    /// arbitrary positions.
+    record_start: Option<Lsn>,
+    /// Already sent beginning of the record at `record_start` which didn't
+    /// fit into a single chunk, to decode the record once it is read fully.
+    record_sent: BytesMut,
+    /// End of the last complete record in `pending`, if any.
+    record_end: Option<Lsn>,
+    pg_version: u32,
+    wal_seg_size: usize,
+    compressor: Option<zstd::bulk::Compressor<'static>>,
+}
+
+impl WalChunker {
+    pub fn new(
+        conf: &SafeKeeperConf,
+        tli: &Timeline,
+        state: &SafeKeeperState,
+        start_pos: Lsn,
+        compress: bool,
+    ) -> anyhow::Result<Self> {
+        let wal_reader = WalReader::new(
+            conf.workdir.clone(),
+            conf.timeline_dir(&tli.ttid),
+            state,
+            start_pos,
+            conf.wal_backup_enabled,
+        )?;
+        let compressor = if compress {
+            Some(
+                zstd::bulk::Compressor::new(WAL_COMPRESSION_LEVEL)
+                    .context("Failed to create WAL compressor")?,
+            )
+        } else {
+            None
+        };
+        Ok(WalChunker {
+            wal_reader,
+            start_pos,
+            pending: BytesMut::with_capacity(MAX_SEND_SIZE),
+            record_start: Some(start_pos),
+            record_sent: BytesMut::new(),
+            record_end: None,
+            pg_version: state.server.pg_version / 10000,
+            wal_seg_size: state.server.wal_seg_size as usize,
+            compressor,
+        })
+    }
+
+    /// Read WAL up to `end_pos` until the buffer has a complete record, is
+    /// full, or there is no more WAL, and return size of the next chunk.
+    pub async fn fill(&mut self, end_pos: Lsn) -> anyhow::Result<usize> {
+        self.find_record_end();
+        while self.pending.len() < MAX_SEND_SIZE && self.record_end.is_none() {
+            let read_pos = self.start_pos + self.pending.len() as u64;
+            if read_pos >= end_pos {
+                break;
+            }
+            let read_size = min(
+                (end_pos.0 - read_pos.0) as usize,
+                MAX_SEND_SIZE - self.pending.len(),
+            );
+            let filled = self.pending.len();
+            self.pending.resize(filled + read_size, 0);
+            let nread = self.wal_reader.read(&mut self.pending[filled..]).await?;
+            self.pending.truncate(filled + nread);
+            self.find_record_end();
+        }
+
+        // Send whole records, unless a record doesn't fit into the buffer or
+        // the boundaries are unknown.
+        Ok(self.record_end.map_or(self.pending.len(), |end| {
+            (end.0 - self.start_pos.0) as usize
+        }))
+    }
+
+    /// Find the end of the last complete record in `pending`.
+    fn find_record_end(&mut self) {
+        self.record_end = None;
+        let Some(record_start) = self.record_start else {
+            return;
+        };
+        let pending_end = self.start_pos + self.pending.len() as u64;
+        if !self.record_sent.is_empty() {
+            // Don't decode a large record over and over while it is read:
+            // it can't end before its xl_tot_len.
+            let tot_len_pos =
+                (record_start_lsn(record_start, self.wal_seg_size) - record_start) as usize;
+            if let Some(tot_len) = self.record_sent.get(tot_len_pos..tot_len_pos + 4) {
+                let tot_len = u32::from_le_bytes(tot_len.try_into().unwrap());
+                if pending_end < record_start + tot_len_pos as u64 + tot_len as u64 {
+                    return;
+                }
+            }
+        }
+
+        let res = if self.record_sent.is_empty() {
+            split_on_record_boundaries(
+                &self.pending,
+                record_start,
+                self.pg_version,
+                self.wal_seg_size,
+            )
+        } else {
+            let mut buf = BytesMut::with_capacity(self.record_sent.len() + self.pending.len());
+            buf.extend_from_slice(&self.record_sent);
+            buf.extend_from_slice(&self.pending);
+            split_on_record_boundaries(&buf, record_start, self.pg_version, self.wal_seg_size)
+        };
+        match res {
+            Ok(ranges) => {
+                self.record_end = ranges
+                    .last()
+                    .map(|range| record_start + range.end as u64)
+                    .filter(|end| *end > self.start_pos);
+            }
+            Err(e) => {
+                debug!("not aligning WAL messages to records: {}", e);
+                self.record_start = None;
+                self.record_sent = BytesMut::new();
+            }
+        }
+    }
+
+    /// Take data of the next chunk of `size` bytes, compressed if asked.
+    /// Uncompressed data is split off the read buffer without copying.
+    pub fn take_chunk(&mut self, size: usize) -> anyhow::Result<Bytes> {
+        let buf = self.pending.split_to(size).freeze();
+        let chunk_start = self.start_pos;
+        self.start_pos += size as u64;
+        if self.record_start.is_some() {
+            // keep the part of the record which doesn't end in the chunk
+            match self.record_end.take() {
+                Some(end) if end <= self.start_pos => {
+                    self.record_start = Some(end);
+                    self.record_sent.clear();
+                    self.record_sent
+                        .extend_from_slice(&buf[(end.0 - chunk_start.0) as usize..]);
+                }
+                _ => self.record_sent.extend_from_slice(&buf),
+            }
+        }
+        Ok(match self.compressor.as_mut() {
+            Some(compressor) => Bytes::from(
+                compressor
+                    .compress(&buf)
+                    .context("Failed to compress WAL")?,
+            ),
+            None => buf,
+        })
+    }
+}
+
 const POLL_STATE_TIMEOUT: Duration = Duration::from_secs(1);
 
+// Fast compression: WAL compresses well even at the lowest levels, and we
+// don't want the sender to become CPU-bound.
+const WAL_COMPRESSION_LEVEL: i32 = 1;
+
 // Wait until we have commit_lsn > lsn or timeout expires. Returns latest commit_lsn.
-async fn wait_for_lsn(rx: &mut Receiver<Lsn>, lsn: Lsn) -> anyhow::Result<Option<Lsn>> {
+pub(crate) async fn wait_for_lsn(rx: &mut Receiver<Lsn>, lsn: Lsn) -> anyhow::Result<Option<Lsn>> {
     let commit_lsn: Lsn = *rx.borrow();
     if commit_lsn > lsn {
         return Ok(Some(commit_lsn));
@@ -332,3 +636,25 @@ async fn wait_for_lsn(rx: &mut Receiver<Lsn>, lsn: Lsn) -> anyhow::Result<Option
         Err(_) => Ok(None),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn test_parse_hot_standby_feedback() {
+        let mut buf = Vec::new();
+        buf.put_i64(12345);
+        buf.put_u32(1000); // xmin
+        buf.put_u32(2); // xmin epoch
+        buf.put_u32(0); // catalog_xmin, not set
+        buf.put_u32(0); // catalog_xmin epoch
+        let feedback = HotStandbyFeedback::parse(&buf).unwrap();
+        assert_eq!(feedback.ts, 12345);
+        assert_eq!(feedback.xmin, (2 << 32) | 1000);
+        assert_eq!(feedback.catalog_xmin, u64::MAX);
+
+        assert!(HotStandbyFeedback::parse(&buf[..20]).is_err());
+    }
+}
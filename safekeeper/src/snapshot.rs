@@ -0,0 +1,279 @@
+//! Export of a timeline snapshot as a tar archive and its import, both
+//! streamed over the COPY protocol. Used for offline debugging of timelines
+//! and to seed test data.
+//!
+//! The archive contains the control file and WAL segments up to flush_lsn
+//! as of the start of the export; the last segment is zeroed after it. The
+//! control file is produced from the in-memory persisted state, so it is
+//! never behind the WAL.
+
+use anyhow::{bail, ensure, Context, Result};
+use bytes::Bytes;
+use postgres_ffi::{XLogFileName, PG_TLI};
+use pq_proto::BeMessage;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tracing::*;
+use utils::id::TenantTimelineId;
+use utils::lsn::Lsn;
+use utils::postgres_backend::PostgresBackend;
+use utils::postgres_backend_async::{CopyMode, QueryError};
+
+use crate::control_file::{self, FileStorage, CONTROL_FILE_NAME};
+use crate::pull_timeline;
+use crate::wal_storage::read_segment;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// Suffix of the directory where the snapshot is unpacked to before it is
+/// verified.
+const IMPORT_TMP_SUFFIX: &str = ".import";
+
+/// Writes the data it gets as CopyData messages.
+struct CopyOutWriter<'a> {
+    pgb: &'a mut PostgresBackend,
+}
+
+impl Write for CopyOutWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pgb.write_message(&BeMessage::CopyData(buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.pgb.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads contents of CopyData messages until CopyDone.
+struct CopyInReader<'a> {
+    pgb: &'a mut PostgresBackend,
+    buf: Bytes,
+    done: bool,
+}
+
+impl Read for CopyInReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.buf.is_empty() && !self.done {
+            match self
+                .pgb
+                .read_copy_data()
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?
+            {
+                Some(data) => self.buf = data,
+                None => self.done = true,
+            }
+        }
+        let n = std::cmp::min(out.len(), self.buf.len());
+        out[..n].copy_from_slice(&self.buf.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Stream the snapshot of the timeline as a tar archive.
+pub fn handle_export(
+    conf: &SafeKeeperConf,
+    ttid: TenantTimelineId,
+    pgb: &mut PostgresBackend,
+) -> Result<(), QueryError> {
+    let tli = GlobalTimelines::get(ttid)?;
+    let (_, state) = tli.get_state();
+    let flush_lsn = tli.get_flush_lsn();
+    let timeline_dir = conf.timeline_dir(&ttid);
+    let wal_seg_size = state.server.wal_seg_size as usize;
+    info!("exporting timeline {} up to {}", ttid, flush_lsn);
+
+    pgb.start_copy(CopyMode::Out)?;
+    let mut builder = tar::Builder::new(CopyOutWriter { pgb });
+    append_file(
+        &mut builder,
+        CONTROL_FILE_NAME,
+        &FileStorage::serialize(&state)?,
+    )?;
+
+    if wal_seg_size > 0 && flush_lsn != Lsn(0) {
+        let mut started = false;
+        let first_segno = state.local_start_lsn.segment_number(wal_seg_size);
+        let last_segno = flush_lsn.segment_number(wal_seg_size);
+        for segno in first_segno..=last_segno {
+            let name = XLogFileName(PG_TLI, segno, wal_seg_size);
+            let mut data = match read_segment(&timeline_dir, segno, wal_seg_size) {
+                Ok(data) => data,
+                // segments in the beginning might have been removed
+                Err(e) if e.kind() == io::ErrorKind::NotFound && !started => continue,
+                Err(e) => {
+                    return Err(anyhow::Error::new(e)
+                        .context(format!("failed to read segment {name}"))
+                        .into())
+                }
+            };
+            started = true;
+            if segno == last_segno {
+                let end = flush_lsn.segment_offset(wal_seg_size);
+                if end == 0 {
+                    break;
+                }
+                data[end..].fill(0);
+                append_file(&mut builder, &(name + ".partial"), &data)?;
+            } else {
+                append_file(&mut builder, &name, &data)?;
+            }
+        }
+    }
+
+    let writer = builder
+        .into_inner()
+        .context("failed to finish tar archive")?;
+    writer
+        .pgb
+        .finish_copy_out()?
+        .write_message(&BeMessage::CommandComplete(b"TIMELINE_EXPORT"))?;
+    Ok(())
+}
+
+fn append_file<W: Write>(builder: &mut tar::Builder<W>, name: &str, data: &[u8]) -> Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o600);
+    header.set_cksum();
+    builder
+        .append_data(&mut header, name, data)
+        .with_context(|| format!("failed to add {name} to the archive"))
+}
+
+/// Create the timeline from a snapshot streamed as a tar archive. The
+/// snapshot may come from another timeline, ids in the control file are
+/// replaced with the given ones.
+pub fn handle_import(
+    conf: &SafeKeeperConf,
+    ttid: TenantTimelineId,
+    pgb: &mut PostgresBackend,
+) -> Result<(), QueryError> {
+    if GlobalTimelines::get(ttid).is_ok() {
+        return Err(anyhow::anyhow!("timeline {} already exists", ttid).into());
+    }
+    let timeline_dir = conf.timeline_dir(&ttid);
+    if timeline_dir.exists() {
+        return Err(anyhow::anyhow!(
+            "timeline directory {} already exists",
+            timeline_dir.display()
+        )
+        .into());
+    }
+    let mut tmp_dir = timeline_dir.clone().into_os_string();
+    tmp_dir.push(IMPORT_TMP_SUFFIX);
+    let tmp_dir = PathBuf::from(tmp_dir);
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).context("failed to remove leftover import directory")?;
+    }
+    fs::create_dir_all(&tmp_dir).context("failed to create import directory")?;
+
+    pgb.start_copy(CopyMode::In)?;
+    let mut reader = CopyInReader {
+        pgb,
+        buf: Bytes::new(),
+        done: false,
+    };
+    let res = unpack(&mut reader, &tmp_dir).and_then(|_| {
+        // drain the rest of the stream, e.g. tar padding
+        io::copy(&mut reader, &mut io::sink())?;
+        Ok(())
+    });
+    let res = res.and_then(|_| install(conf, &ttid, &tmp_dir, &timeline_dir));
+    if let Err(e) = res {
+        let _ = fs::remove_dir_all(&tmp_dir);
+        return Err(e
+            .context(format!("failed to import timeline {ttid}"))
+            .into());
+    }
+
+    let tli = GlobalTimelines::load_timeline(ttid)?;
+    info!("imported timeline {} up to {}", ttid, tli.get_flush_lsn());
+    pgb.write_message(&BeMessage::CommandComplete(b"TIMELINE_IMPORT"))?;
+    Ok(())
+}
+
+fn unpack(reader: &mut impl Read, dir: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let name = path
+            .to_str()
+            .filter(|name| pull_timeline::is_timeline_file(name))
+            .with_context(|| format!("unexpected file {} in the archive", path.display()))?
+            .to_owned();
+        let mut file = fs::File::create(dir.join(&name))?;
+        io::copy(&mut entry, &mut file)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Verify the unpacked snapshot and move it in place under the new ids.
+fn install(
+    conf: &SafeKeeperConf,
+    ttid: &TenantTimelineId,
+    tmp_dir: &Path,
+    timeline_dir: &Path,
+) -> Result<()> {
+    let mut state = FileStorage::load_control_file(tmp_dir.join(CONTROL_FILE_NAME))
+        .context("no valid control file in the archive")?;
+    if state.server.wal_seg_size == 0 {
+        bail!("wal_seg_size is not set in the control file");
+    }
+    pull_timeline::check_end_of_wal(&state, tmp_dir)?;
+    if state.tenant_id != ttid.tenant_id || state.timeline_id != ttid.timeline_id {
+        info!(
+            "importing snapshot of timeline {}/{} as {}",
+            state.tenant_id, state.timeline_id, ttid
+        );
+    }
+    state.tenant_id = ttid.tenant_id;
+    state.timeline_id = ttid.timeline_id;
+
+    ensure!(
+        !timeline_dir.exists(),
+        "timeline directory {} already exists",
+        timeline_dir.display()
+    );
+    fs::rename(tmp_dir, timeline_dir)?;
+    let res = FileStorage::create_new(ttid, conf, state.clone())
+        .and_then(|mut store| control_file::Storage::persist(&mut store, &state));
+    if res.is_err() {
+        let _ = fs::remove_dir_all(timeline_dir);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unpack() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append_file(&mut builder, CONTROL_FILE_NAME, b"control").unwrap();
+        append_file(&mut builder, "000000010000000000000001.partial", b"wal").unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        unpack(&mut archive.as_slice(), dir.path()).unwrap();
+        assert_eq!(
+            fs::read(dir.path().join(CONTROL_FILE_NAME)).unwrap(),
+            b"control"
+        );
+        assert_eq!(
+            fs::read(dir.path().join("000000010000000000000001.partial")).unwrap(),
+            b"wal"
+        );
+
+        // files other than control file and WAL are rejected
+        let mut builder = tar::Builder::new(Vec::new());
+        append_file(&mut builder, "../../etc/passwd", b"x").unwrap_err();
+        append_file(&mut builder, "somefile", b"x").unwrap();
+        let archive = builder.into_inner().unwrap();
+        assert!(unpack(&mut archive.as_slice(), dir.path()).is_err());
+    }
+}
@@ -3,10 +3,14 @@
 
 use anyhow::{bail, Result};
 use parking_lot::{Mutex, MutexGuard};
-use postgres_ffi::XLogSegNo;
+use postgres_ffi::{TimestampTz, XLogSegNo};
 use pq_proto::ReplicationFeedback;
+use serde::Serialize;
 use std::cmp::{max, min};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::{
     sync::{mpsc::Sender, watch},
     time::Instant,
@@ -21,29 +25,42 @@ use storage_broker::proto::SafekeeperTimelineInfo;
 use storage_broker::proto::TenantTimelineId as ProtoTenantTimelineId;
 
 use crate::safekeeper::{
-    AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
-    SafekeeperMemState, ServerInfo, Term,
+    unix_now, AcceptorProposerMessage, ProposerAcceptorMessage, RetentionPin, SafeKeeper,
+    SafeKeeperState, SafekeeperMemState, Term, TermBump, TimelineCreateParams,
 };
-use crate::send_wal::HotStandbyFeedback;
+use crate::send_wal::{HotStandbyFeedback, StandbyReply};
 use crate::{control_file, safekeeper::UNKNOWN_SERVER_VERSION};
 
+use crate::debug_dump;
 use crate::metrics::FullTimelineInfo;
+use crate::remove_wal::WalRetentionPolicy;
+use crate::timeline_tasks::TimelineTasks;
 use crate::wal_storage;
 use crate::wal_storage::Storage as wal_storage_iface;
 use crate::SafeKeeperConf;
 
+/// How long deletion and eviction wait for background tasks of the timeline
+/// to stop.
+const TASKS_STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// Things safekeeper should know about timeline state on peers.
 #[derive(Debug, Clone)]
 pub struct PeerInfo {
     pub sk_id: NodeId,
+    /// Current term of the peer.
+    pub term: Term,
     /// Term of the last entry.
-    _last_log_term: Term,
+    pub last_log_term: Term,
     /// LSN of the last record.
-    _flush_lsn: Lsn,
+    pub flush_lsn: Lsn,
     pub commit_lsn: Lsn,
+    /// LSN up to which peer has offloaded WAL.
+    pub backup_lsn: Lsn,
     /// Since which LSN safekeeper has WAL. TODO: remove this once we fill new
     /// sk since backup_lsn.
     pub local_start_lsn: Lsn,
+    /// Where peer accepts Postgres protocol connections.
+    pub pg_connstr: String,
     /// When info was received.
     ts: Instant,
 }
@@ -52,10 +69,13 @@ impl PeerInfo {
     fn from_sk_info(sk_info: &SafekeeperTimelineInfo, ts: Instant) -> PeerInfo {
         PeerInfo {
             sk_id: NodeId(sk_info.safekeeper_id),
-            _last_log_term: sk_info.last_log_term,
-            _flush_lsn: Lsn(sk_info.flush_lsn),
+            term: sk_info.term,
+            last_log_term: sk_info.last_log_term,
+            flush_lsn: Lsn(sk_info.flush_lsn),
             commit_lsn: Lsn(sk_info.commit_lsn),
+            backup_lsn: Lsn(sk_info.backup_lsn),
             local_start_lsn: Lsn(sk_info.local_start_lsn),
+            pg_connstr: sk_info.safekeeper_connstr.clone(),
             ts,
         }
     }
@@ -79,9 +99,26 @@ impl PeersInfo {
     }
 }
 
+/// Kind of the replication consumer, learnt from its application_name and
+/// the feedback it sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsumerKind {
+    /// Nothing is known about the consumer yet.
+    Unknown,
+    Pageserver,
+    /// Postgres standby or pg_receivewal, sending standby status updates.
+    Standby,
+    /// walproposer or peer safekeeper fetching WAL for recovery.
+    Recovery,
+}
+
 /// Replica status update + hot standby feedback
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct ReplicaState {
+    pub kind: ConsumerKind,
+    /// LSN streaming started at
+    pub start_lsn: Lsn,
     /// last known lsn received by replica
     pub last_received_lsn: Lsn, // None means we don't know
     /// combined remote consistent lsn of pageservers
@@ -90,6 +127,9 @@ pub struct ReplicaState {
     pub hs_feedback: HotStandbyFeedback,
     /// Replication specific feedback received from pageserver, if any
     pub pageserver_feedback: Option<ReplicationFeedback>,
+    /// Status update received from postgres replica, if any. In the combined
+    /// state, minimal LSNs of all replicas which reported them.
+    pub standby_reply: Option<StandbyReply>,
 }
 
 impl Default for ReplicaState {
@@ -100,7 +140,13 @@ impl Default for ReplicaState {
 
 impl ReplicaState {
     pub fn new() -> ReplicaState {
+        Self::for_consumer(ConsumerKind::Unknown, Lsn::INVALID)
+    }
+
+    pub fn for_consumer(kind: ConsumerKind, start_lsn: Lsn) -> ReplicaState {
         ReplicaState {
+            kind,
+            start_lsn,
             last_received_lsn: Lsn::MAX,
             remote_consistent_lsn: Lsn(0),
             hs_feedback: HotStandbyFeedback {
@@ -109,10 +155,41 @@ impl ReplicaState {
                 catalog_xmin: u64::MAX,
             },
             pageserver_feedback: None,
+            standby_reply: None,
+        }
+    }
+
+    /// LSN from which WAL must be kept for this consumer while it is
+    /// connected, if any.
+    ///
+    /// Standbys need WAL from their flush position, or from where streaming
+    /// started until they report it. Consumers not identified yet are treated
+    /// the same way. Pageservers hold WAL through remote_consistent_lsn
+    /// instead, and recovery is short lived and may just be restarted.
+    pub fn horizon_lsn(&self) -> Option<Lsn> {
+        match self.kind {
+            ConsumerKind::Standby | ConsumerKind::Unknown => Some(
+                self.standby_reply
+                    .map(|r| r.flush_lsn)
+                    .filter(|lsn| *lsn != Lsn::INVALID)
+                    .unwrap_or(self.start_lsn),
+            ),
+            ConsumerKind::Pageserver | ConsumerKind::Recovery => None,
         }
     }
 }
 
+/// Lag of the safekeeper which it signals to walproposer by delaying replies.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct BackpressureState {
+    /// WAL written, but not flushed to disk yet
+    pub unflushed_wal_bytes: u64,
+    /// WAL flushed, but not offloaded to remote storage yet
+    pub unbacked_wal_bytes: u64,
+    /// Whether any of the configured limits is exceeded
+    pub throttling: bool,
+}
+
 /// Shared state associated with database instance
 pub struct SharedState {
     /// Safekeeper object
@@ -134,7 +211,13 @@ pub struct SharedState {
     /// when tli is inactive instead of having this flag.
     active: bool,
     num_computes: u32,
+    /// Segments before this one are removed from disk.
     last_removed_segno: XLogSegNo,
+    /// Segments before this one are compressed on disk, see
+    /// `Timeline::compress_cold_wal`.
+    last_compressed_segno: XLogSegNo,
+    /// When the timeline became inactive, None while it is active.
+    inactive_since: Option<Instant>,
 }
 
 impl SharedState {
@@ -174,6 +257,8 @@ impl SharedState {
             active: false,
             num_computes: 0,
             last_removed_segno: 0,
+            last_compressed_segno: 0,
+            inactive_since: Some(Instant::now()),
         })
     }
 
@@ -185,6 +270,12 @@ impl SharedState {
         }
 
         let wal_store = wal_storage::PhysicalStorage::new(ttid, conf, &control_store)?;
+        // segments before the oldest one on disk were removed before restart
+        let last_removed_segno = wal_storage::oldest_segment_on_disk(
+            &conf.timeline_dir(ttid),
+            control_store.server.wal_seg_size as usize,
+        )?
+        .unwrap_or(0);
 
         Ok(Self {
             sk: SafeKeeper::new(control_store, wal_store, conf.my_id)?,
@@ -193,7 +284,9 @@ impl SharedState {
             wal_backup_active: false,
             active: false,
             num_computes: 0,
-            last_removed_segno: 0,
+            last_removed_segno,
+            last_compressed_segno: 0,
+            inactive_since: Some(Instant::now()),
         })
     }
 
@@ -212,9 +305,26 @@ impl SharedState {
             info!("timeline {} active={} now", ttid, is_active);
         }
         self.active = is_active;
+        if is_active {
+            self.inactive_since = None;
+        } else if self.inactive_since.is_none() {
+            self.inactive_since = Some(Instant::now());
+        }
         self.is_wal_backup_action_pending()
     }
 
+    /// Whether nothing has used the timeline for at least `timeout`, so it
+    /// can be unloaded from memory.
+    fn is_idle_for(&self, timeout: Duration) -> bool {
+        !self.is_active()
+            && self.num_computes == 0
+            && !self.wal_backup_active
+            && self.replicas.iter().all(|r| r.is_none())
+            && self
+                .inactive_since
+                .map_or(false, |since| since.elapsed() >= timeout)
+    }
+
     /// Should we run s3 offloading in current state?
     fn is_wal_backup_required(&self) -> bool {
         let seg_size = self.get_wal_seg_size();
@@ -272,6 +382,21 @@ impl SharedState {
             // we need to know which pageserver compute node considers to be main.
             // See https://github.com/neondatabase/neon/issues/1171
             //
+            // Postgres replicas need WAL from their flush position, so take
+            // the minimum. Replies without flush position don't count.
+            if let Some(reply) = state.standby_reply.filter(|r| r.flush_lsn != Lsn::INVALID) {
+                acc.standby_reply = Some(match acc.standby_reply {
+                    Some(acc_reply) => StandbyReply {
+                        write_lsn: min(acc_reply.write_lsn, reply.write_lsn),
+                        flush_lsn: min(acc_reply.flush_lsn, reply.flush_lsn),
+                        apply_lsn: min(acc_reply.apply_lsn, reply.apply_lsn),
+                        reply_ts: max(acc_reply.reply_ts, reply.reply_ts),
+                        reply_requested: acc_reply.reply_requested || reply.reply_requested,
+                    },
+                    None => reply,
+                });
+            }
+
             if let Some(pageserver_feedback) = state.pageserver_feedback {
                 if let Some(acc_feedback) = acc.pageserver_feedback {
                     if acc_feedback.ps_writelsn < pageserver_feedback.ps_writelsn {
@@ -298,6 +423,51 @@ impl SharedState {
         acc
     }
 
+    /// Minimal horizon of all connected replication consumers, see
+    /// `ReplicaState::horizon_lsn`.
+    fn get_replication_horizon_lsn(&self) -> Option<Lsn> {
+        self.replicas
+            .iter()
+            .flatten()
+            .filter_map(|r| r.horizon_lsn())
+            .filter(|lsn| *lsn != Lsn::INVALID)
+            .min()
+    }
+
+    /// Segment number before which WAL can be removed along with what holds
+    /// it: in addition to the safekeeper horizon (which includes backup_lsn),
+    /// WAL still needed by connected replication consumers and WAL the
+    /// retention policy asks for is kept.
+    fn get_horizon_segno(
+        &self,
+        wal_backup_enabled: bool,
+        policy: &WalRetentionPolicy,
+    ) -> (XLogSegNo, String) {
+        let seg_size = self.get_wal_seg_size();
+        let (horizon_lsn, reason) = self
+            .sk
+            .get_horizon(wal_backup_enabled, policy.until_backup());
+        let mut horizon = (horizon_lsn.segment_number(seg_size), reason);
+        let mut hold = |segno: XLogSegNo, reason: &str| {
+            if segno < horizon.0 {
+                horizon = (segno, reason.to_owned());
+            }
+        };
+        if let Some(lsn) = self.get_replication_horizon_lsn() {
+            hold(lsn.segment_number(seg_size), "replication consumer");
+        }
+        let flush_lsn = self.sk.wal_store.flush_lsn();
+        if let Some(keep_bytes) = policy.keep_bytes {
+            let lsn = Lsn(flush_lsn.0.saturating_sub(keep_bytes));
+            hold(lsn.segment_number(seg_size), "keep_bytes");
+        }
+        if let Some(keep_segments) = policy.keep_segments {
+            let segno = flush_lsn.segment_number(seg_size);
+            hold(segno.saturating_sub(keep_segments), "keep_segments");
+        }
+        horizon
+    }
+
     /// Assign new replica ID. We choose first empty cell in the replicas vector
     /// or extend the vector if there are no free slots.
     pub fn add_replica(&mut self, state: ReplicaState) -> usize {
@@ -310,6 +480,26 @@ impl SharedState {
         pos
     }
 
+    /// Max of remote_consistent_lsn reported by pageservers and the one we
+    /// know from peers.
+    fn remote_consistent_lsn(&self) -> Lsn {
+        // TODO: rework feedbacks to avoid max here
+        max(
+            self.get_replicas_state().remote_consistent_lsn,
+            self.sk.inmem.remote_consistent_lsn,
+        )
+    }
+
+    /// Commit time of the newest received transaction and how far behind it
+    /// pageservers are, in seconds.
+    fn get_record_time(&self) -> (Option<TimestampTz>, Option<f64>) {
+        let record_time = &self.sk.record_time;
+        (
+            record_time.last_record_time(),
+            record_time.lag_seconds(self.remote_consistent_lsn()),
+        )
+    }
+
     fn get_safekeeper_info(
         &self,
         ttid: &TenantTimelineId,
@@ -321,20 +511,17 @@ impl SharedState {
                 tenant_id: ttid.tenant_id.as_ref().to_owned(),
                 timeline_id: ttid.timeline_id.as_ref().to_owned(),
             }),
+            term: self.sk.state.acceptor_state.term,
             last_log_term: self.sk.get_epoch(),
             flush_lsn: self.sk.wal_store.flush_lsn().0,
             // note: this value is not flushed to control file yet and can be lost
             commit_lsn: self.sk.inmem.commit_lsn.0,
-            // TODO: rework feedbacks to avoid max here
-            remote_consistent_lsn: max(
-                self.get_replicas_state().remote_consistent_lsn,
-                self.sk.inmem.remote_consistent_lsn,
-            )
-            .0,
+            remote_consistent_lsn: self.remote_consistent_lsn().0,
             peer_horizon_lsn: self.sk.inmem.peer_horizon_lsn.0,
             safekeeper_connstr: conf.listen_pg_addr.clone(),
             backup_lsn: self.sk.inmem.backup_lsn.0,
             local_start_lsn: self.sk.state.local_start_lsn.0,
+            last_record_time: self.sk.record_time.last_record_time().unwrap_or(0),
         }
     }
 }
@@ -380,8 +567,16 @@ pub struct Timeline {
     /// monitor this channel and stop eventually after receiving `true` from this channel.
     cancellation_rx: watch::Receiver<bool>,
 
+    /// Background tasks working with the timeline, stopped on cancellation.
+    tasks: Arc<TimelineTasks>,
+
     /// Directory where timeline state is stored.
     timeline_dir: PathBuf,
+
+    /// WAL bytes received from walproposers, for metrics.
+    wal_received_bytes: AtomicU64,
+    /// WAL bytes sent to replicas, for metrics.
+    wal_sent_bytes: AtomicU64,
 }
 
 impl Timeline {
@@ -397,6 +592,7 @@ impl Timeline {
         let (commit_lsn_watch_tx, commit_lsn_watch_rx) =
             watch::channel(shared_state.sk.state.commit_lsn);
         let (cancellation_tx, cancellation_rx) = watch::channel(false);
+        let tasks = Arc::new(TimelineTasks::new(ttid, cancellation_rx.clone()));
 
         Ok(Timeline {
             ttid,
@@ -406,7 +602,10 @@ impl Timeline {
             mutex: Mutex::new(shared_state),
             cancellation_rx,
             cancellation_tx,
+            tasks,
             timeline_dir: conf.timeline_dir(&ttid),
+            wal_received_bytes: AtomicU64::new(0),
+            wal_sent_bytes: AtomicU64::new(0),
         })
     }
 
@@ -415,13 +614,12 @@ impl Timeline {
         conf: SafeKeeperConf,
         ttid: TenantTimelineId,
         wal_backup_launcher_tx: Sender<TenantTimelineId>,
-        server_info: ServerInfo,
-        commit_lsn: Lsn,
-        local_start_lsn: Lsn,
+        params: TimelineCreateParams,
     ) -> Result<Timeline> {
         let (commit_lsn_watch_tx, commit_lsn_watch_rx) = watch::channel(Lsn::INVALID);
         let (cancellation_tx, cancellation_rx) = watch::channel(false);
-        let state = SafeKeeperState::new(&ttid, server_info, vec![], commit_lsn, local_start_lsn);
+        let tasks = Arc::new(TimelineTasks::new(ttid, cancellation_rx.clone()));
+        let state = SafeKeeperState::new(&ttid, params, vec![]);
 
         Ok(Timeline {
             ttid,
@@ -431,7 +629,10 @@ impl Timeline {
             mutex: Mutex::new(SharedState::create_new(&conf, &ttid, state)?),
             cancellation_rx,
             cancellation_tx,
+            tasks,
             timeline_dir: conf.timeline_dir(&ttid),
+            wal_received_bytes: AtomicU64::new(0),
+            wal_sent_bytes: AtomicU64::new(0),
         })
     }
 
@@ -480,18 +681,38 @@ impl Timeline {
         }
     }
 
-    /// Delete timeline from disk completely, by removing timeline directory. Background
-    /// timeline activities will stop eventually.
+    /// Delete timeline from disk completely, by removing timeline directory.
+    /// Background tasks of the timeline are stopped first; if they don't stop
+    /// in time, the timeline is left cancelled and deletion can be retried.
     pub fn delete_from_disk(
         &self,
         shared_state: &mut MutexGuard<SharedState>,
     ) -> Result<(bool, bool)> {
         let was_active = shared_state.active;
         self.cancel(shared_state);
+        self.wait_tasks_stopped(shared_state, TASKS_STOP_TIMEOUT)?;
         let dir_existed = delete_dir(&self.timeline_dir)?;
         Ok((dir_existed, was_active))
     }
 
+    /// Wait for background tasks to stop after cancellation. The lock is
+    /// released meanwhile, as tasks might need it to proceed.
+    fn wait_tasks_stopped(
+        &self,
+        shared_state: &mut MutexGuard<SharedState>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let stopped = MutexGuard::unlocked(shared_state, || self.tasks.wait_stopped(timeout));
+        if !stopped {
+            bail!(
+                "background tasks of timeline {} didn't stop in {:?}",
+                self.ttid,
+                timeout
+            );
+        }
+        Ok(())
+    }
+
     /// Cancel timeline to prevent further usage. Background tasks will stop
     /// eventually after receiving cancellation signal.
     fn cancel(&self, shared_state: &mut MutexGuard<SharedState>) {
@@ -506,11 +727,86 @@ impl Timeline {
         shared_state.sk.wal_store.close();
     }
 
+    /// Unload the timeline if it has been idle for `timeout`: persist the
+    /// in-memory state and cancel it, closing its files. The caller must
+    /// remove it from the global map. Returns whether it was evicted.
+    pub fn evict_if_idle(
+        &self,
+        shared_state: &mut MutexGuard<SharedState>,
+        timeout: Duration,
+    ) -> Result<bool> {
+        if self.is_cancelled() || !shared_state.is_idle_for(timeout) || self.tasks.has_running() {
+            return Ok(false);
+        }
+        info!("evicting idle timeline {}", self.ttid);
+        shared_state.sk.persist()?;
+        // Unlike cancel(), don't bother WAL backup launcher: offloading of
+        // an idle timeline is not running.
+        let _ = self.cancellation_tx.send(true);
+        if self.tasks.has_running() {
+            // A task started since the check above and needs the lock to
+            // stop. Files are kept on eviction, so go on even if it is stuck.
+            if let Err(e) = self.wait_tasks_stopped(shared_state, TASKS_STOP_TIMEOUT) {
+                warn!("{:#}", e);
+            }
+            // The timeline might have been used while the lock was released,
+            // keep it then.
+            if !shared_state.is_idle_for(timeout) {
+                info!("timeline {} became active, not evicting it", self.ttid);
+                let _ = self.cancellation_tx.send(false);
+                return Ok(false);
+            }
+            shared_state.sk.persist()?;
+        }
+        shared_state.sk.wal_store.close();
+        Ok(true)
+    }
+
+    /// Whether nothing is connected to the timeline and WAL backup doesn't
+    /// need to run, so safekeeper can shut down.
+    pub fn is_drained(&self, conf: &SafeKeeperConf) -> bool {
+        let shared_state = self.write_shared_state();
+        let backup_pending = conf.wal_backup_enabled
+            && conf.remote_storage.is_some()
+            && shared_state.is_wal_backup_required();
+        shared_state.num_computes == 0
+            && shared_state.replicas.iter().all(|r| r.is_none())
+            && !backup_pending
+    }
+
+    /// Flush WAL and in-memory state to disk and cancel the timeline, as
+    /// part of safekeeper shutdown; `finish_shutdown` completes it. Returns
+    /// false if the timeline is already cancelled.
+    pub fn start_shutdown(&self) -> Result<bool> {
+        let mut shared_state = self.write_shared_state();
+        if self.is_cancelled() {
+            return Ok(false);
+        }
+        shared_state.sk.wal_store.flush_wal()?;
+        shared_state.sk.persist()?;
+        let _ = self.cancellation_tx.send(true);
+        Ok(true)
+    }
+
+    /// Wait for background tasks to stop after `start_shutdown` and close
+    /// the files.
+    pub fn finish_shutdown(&self, timeout: Duration) -> Result<()> {
+        let mut shared_state = self.write_shared_state();
+        self.wait_tasks_stopped(&mut shared_state, timeout)?;
+        shared_state.sk.wal_store.close();
+        Ok(())
+    }
+
     /// Returns if timeline is cancelled.
     pub fn is_cancelled(&self) -> bool {
         *self.cancellation_rx.borrow()
     }
 
+    /// Background tasks of the timeline.
+    pub fn tasks(&self) -> &Arc<TimelineTasks> {
+        &self.tasks
+    }
+
     /// Take a writing mutual exclusive lock on timeline shared_state.
     pub fn write_shared_state(&self) -> MutexGuard<SharedState> {
         self.mutex.lock()
@@ -614,17 +910,114 @@ impl Timeline {
                 persisted_state: state.sk.state.clone(),
                 flush_lsn: state.sk.wal_store.flush_lsn(),
                 wal_storage: state.sk.wal_store.get_metrics(),
+                wal_received_bytes: self.wal_received_bytes.load(Ordering::Relaxed),
+                wal_sent_bytes: self.wal_sent_bytes.load(Ordering::Relaxed),
+                record_time: state.get_record_time(),
             })
         } else {
             None
         }
     }
 
+    /// Dump in-memory state of the timeline for debugging, None if it is
+    /// cancelled.
+    pub fn memory_dump(&self, conf: &SafeKeeperConf) -> Option<debug_dump::Memory> {
+        if self.is_cancelled() {
+            return None;
+        }
+
+        let backpressure = self.get_backpressure_state(conf);
+        let state = self.write_shared_state();
+        let now = Instant::now();
+        Some(debug_dump::Memory {
+            active: state.active,
+            wal_backup_active: state.wal_backup_active,
+            num_computes: state.num_computes,
+            last_removed_segno: state.last_removed_segno,
+            epoch_start_lsn: state.sk.epoch_start_lsn,
+            write_lsn: state.sk.wal_store.write_lsn(),
+            flush_lsn: state.sk.wal_store.flush_lsn(),
+            mem_state: state.sk.inmem.clone(),
+            backpressure,
+            replicas: state.replicas.iter().flatten().copied().collect(),
+            peers: state
+                .peers_info
+                .0
+                .iter()
+                .map(|p| debug_dump::Peer {
+                    sk_id: p.sk_id,
+                    term: p.term,
+                    last_log_term: p.last_log_term,
+                    flush_lsn: p.flush_lsn,
+                    commit_lsn: p.commit_lsn,
+                    backup_lsn: p.backup_lsn,
+                    local_start_lsn: p.local_start_lsn,
+                    last_heard_seconds_ago: now.duration_since(p.ts).as_secs_f64(),
+                })
+                .collect(),
+        })
+    }
+
+    /// Account WAL sent to a replica, uncompressed.
+    pub fn observe_wal_sent(&self, bytes: u64) {
+        self.wal_sent_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
     /// Returns commit_lsn watch channel.
     pub fn get_commit_lsn_watch_rx(&self) -> watch::Receiver<Lsn> {
         self.commit_lsn_watch_rx.clone()
     }
 
+    /// Fence the timeline by bumping its term, see `SafeKeeper::term_bump`.
+    pub fn term_bump(&self, to: Option<Term>) -> Result<TermBump> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+        self.write_shared_state().sk.term_bump(to)
+    }
+
+    /// Pin WAL starting at `lsn` under `name` so that it isn't removed
+    /// locally, optionally until `ttl` passes. Replaces the pin with the same
+    /// name.
+    pub fn hold_wal(&self, name: String, lsn: Lsn, ttl: Option<Duration>) -> Result<RetentionPin> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+        let mut shared_state = self.write_shared_state();
+        let segno = lsn.segment_number(shared_state.get_wal_seg_size());
+        if segno < shared_state.last_removed_segno {
+            bail!("WAL at {} is already removed", lsn);
+        }
+        let pin = RetentionPin {
+            name,
+            lsn,
+            expires_at: ttl.map(|ttl| unix_now() + ttl.as_secs()),
+        };
+        shared_state.sk.hold_wal(pin.clone())?;
+        Ok(pin)
+    }
+
+    /// Remove the retention pin, returns whether it existed.
+    pub fn release_wal(&self, name: &str) -> Result<bool> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+        self.write_shared_state().sk.release_wal(name)
+    }
+
+    /// Get unexpired retention pins of the timeline.
+    pub fn get_retention_pins(&self) -> Vec<RetentionPin> {
+        let now = unix_now();
+        self.write_shared_state()
+            .sk
+            .state
+            .retention_pins
+            .iter()
+            .filter(|p| !p.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
     /// Pass arrived message to the safekeeper.
     pub fn process_msg(
         &self,
@@ -634,6 +1027,13 @@ impl Timeline {
             bail!(TimelineError::Cancelled(self.ttid));
         }
 
+        if let ProposerAcceptorMessage::AppendRequest(req)
+        | ProposerAcceptorMessage::NoFlushAppendRequest(req) = msg
+        {
+            self.wal_received_bytes
+                .fetch_add(req.wal_data.len() as u64, Ordering::Relaxed);
+        }
+
         let mut rmsg: Option<AcceptorProposerMessage>;
         let commit_lsn: Lsn;
         {
@@ -655,6 +1055,24 @@ impl Timeline {
         Ok(rmsg)
     }
 
+    /// Write received WAL even if it fails validation, see
+    /// `PhysicalStorage::set_validate_wal`.
+    pub fn set_validate_wal(&self, validate: bool) {
+        self.write_shared_state()
+            .sk
+            .wal_store
+            .set_validate_wal(validate);
+    }
+
+    /// Returns the disk space taken by WAL of the timeline.
+    pub fn get_wal_disk_usage(&self) -> u64 {
+        self.write_shared_state()
+            .sk
+            .wal_store
+            .disk_usage()
+            .load(Ordering::Relaxed)
+    }
+
     /// Returns wal_seg_size.
     pub fn get_wal_seg_size(&self) -> usize {
         self.write_shared_state().get_wal_seg_size()
@@ -675,6 +1093,12 @@ impl Timeline {
         (state.sk.inmem.clone(), state.sk.state.clone())
     }
 
+    /// Returns commit time of the newest received transaction and
+    /// replication lag to pageservers in seconds, if known.
+    pub fn get_record_time(&self) -> (Option<TimestampTz>, Option<f64>) {
+        self.write_shared_state().get_record_time()
+    }
+
     /// Returns latest backup_lsn.
     pub fn get_wal_backup_lsn(&self) -> Lsn {
         self.write_shared_state().sk.inmem.backup_lsn
@@ -704,6 +1128,11 @@ impl Timeline {
         let commit_lsn: Lsn;
         {
             let mut shared_state = self.write_shared_state();
+            // Files of the timeline are gone or about to be closed; that's
+            // not an error for the broker loop, which raced with deletion.
+            if self.is_cancelled() {
+                return Ok(());
+            }
             shared_state.sk.record_safekeeper_info(sk_info)?;
             let peer_info = PeerInfo::from_sk_info(sk_info, Instant::now());
             shared_state.peers_info.upsert(&peer_info);
@@ -734,6 +1163,68 @@ impl Timeline {
             .collect()
     }
 
+    /// Choose a peer to fetch WAL we miss from, see `recovery`. That is the
+    /// most advanced peer in our epoch, which must be the current term on both
+    /// sides. Walproposer does the recovery itself while connected, so None
+    /// is returned in this case.
+    pub fn recovery_donor(&self, conf: &SafeKeeperConf) -> Option<(Term, PeerInfo)> {
+        let shared_state = self.write_shared_state();
+        let term = shared_state.sk.state.acceptor_state.term;
+        if shared_state.num_computes > 0 || shared_state.sk.get_epoch() != term {
+            return None;
+        }
+        let write_lsn = shared_state.sk.wal_store.write_lsn();
+        if write_lsn == Lsn::INVALID {
+            return None;
+        }
+        let now = Instant::now();
+        shared_state
+            .peers_info
+            .0
+            .iter()
+            .filter(|p| now.duration_since(p.ts) <= conf.heartbeat_timeout)
+            .filter(|p| p.sk_id != conf.my_id && p.term == term && p.last_log_term == term)
+            .filter(|p| p.flush_lsn > write_lsn)
+            .max_by_key(|p| p.flush_lsn)
+            .map(|p| (term, p.clone()))
+    }
+
+    /// Append WAL fetched from a peer, see `SafeKeeper::handle_peer_wal`.
+    pub fn append_peer_wal(
+        &self,
+        term: Term,
+        begin_lsn: Lsn,
+        wal_data: &[u8],
+        commit_lsn: Lsn,
+    ) -> Result<()> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        let commit_lsn = {
+            let mut shared_state = self.write_shared_state();
+            if shared_state.num_computes > 0 {
+                bail!("walproposer connected, stopping recovery from peer");
+            }
+            shared_state
+                .sk
+                .handle_peer_wal(term, begin_lsn, wal_data, commit_lsn)?;
+            shared_state.sk.inmem.commit_lsn
+        };
+        self.commit_lsn_watch_tx.send(commit_lsn)?;
+        Ok(())
+    }
+
+    /// Returns the current term.
+    pub fn get_term(&self) -> Term {
+        self.write_shared_state().sk.state.acceptor_state.term
+    }
+
+    /// Returns end of written, but maybe not flushed WAL.
+    pub fn get_write_lsn(&self) -> Lsn {
+        self.write_shared_state().sk.wal_store.write_lsn()
+    }
+
     /// Add send_wal replica to the in-memory vector of replicas.
     pub fn add_replica(&self, state: ReplicaState) -> usize {
         self.write_shared_state().add_replica(state)
@@ -745,6 +1236,16 @@ impl Timeline {
         shared_state.replicas[id] = Some(state);
     }
 
+    /// Get states of connected replication consumers along with the minimal
+    /// LSN they need WAL from, if any.
+    pub fn get_consumers(&self) -> (Vec<ReplicaState>, Option<Lsn>) {
+        let shared_state = self.write_shared_state();
+        (
+            shared_state.replicas.iter().flatten().copied().collect(),
+            shared_state.get_replication_horizon_lsn(),
+        )
+    }
+
     /// Remove send_wal replica from the in-memory vector of replicas.
     pub fn remove_replica(&self, id: usize) {
         let mut shared_state = self.write_shared_state();
@@ -752,29 +1253,80 @@ impl Timeline {
         shared_state.replicas[id] = None;
     }
 
+    /// Get the lag of the timeline against the limits in conf.
+    pub fn get_backpressure_state(&self, conf: &SafeKeeperConf) -> BackpressureState {
+        let shared_state = self.write_shared_state();
+        let flush_lsn = shared_state.sk.wal_store.flush_lsn();
+        let write_lsn = shared_state.sk.wal_store.write_lsn();
+        let unflushed_wal_bytes = u64::from(write_lsn).saturating_sub(u64::from(flush_lsn));
+        let unbacked_wal_bytes = if conf.wal_backup_enabled {
+            u64::from(flush_lsn).saturating_sub(u64::from(shared_state.sk.inmem.backup_lsn))
+        } else {
+            0
+        };
+        let exceeds = |lag: u64, limit: Option<u64>| limit.map_or(false, |limit| lag > limit);
+        BackpressureState {
+            unflushed_wal_bytes,
+            unbacked_wal_bytes,
+            throttling: exceeds(unflushed_wal_bytes, conf.max_unflushed_wal_bytes)
+                || exceeds(unbacked_wal_bytes, conf.max_unbacked_wal_bytes),
+        }
+    }
+
     /// Returns flush_lsn.
     pub fn get_flush_lsn(&self) -> Lsn {
         self.write_shared_state().sk.wal_store.flush_lsn()
     }
 
+    /// Append WAL restored from elsewhere (e.g. remote storage) at the end of
+    /// local WAL and flush it. `startpos` must be the current flush_lsn.
+    pub fn restore_wal_tail(&self, startpos: Lsn, buf: &[u8]) -> Result<()> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        let mut shared_state = self.write_shared_state();
+        let flush_lsn = shared_state.sk.wal_store.flush_lsn();
+        if flush_lsn != startpos {
+            bail!(
+                "flush_lsn moved to {} while restoring WAL from {}",
+                flush_lsn,
+                startpos
+            );
+        }
+        shared_state.sk.wal_store.write_wal(startpos, buf)?;
+        shared_state.sk.wal_store.flush_wal()?;
+        Ok(())
+    }
+
     /// Delete WAL segments from disk that are no longer needed. This is determined
-    /// based on pageserver's remote_consistent_lsn and local backup_lsn/peer_lsn.
-    pub fn remove_old_wal(&self, wal_backup_enabled: bool) -> Result<()> {
+    /// based on pageserver's remote_consistent_lsn, local backup_lsn/peer_lsn
+    /// and the retention policy.
+    pub fn remove_old_wal(
+        &self,
+        wal_backup_enabled: bool,
+        policy: &WalRetentionPolicy,
+    ) -> Result<WalRemoval> {
         if self.is_cancelled() {
             bail!(TimelineError::Cancelled(self.ttid));
         }
 
-        let horizon_segno: XLogSegNo;
-        let remover: Box<dyn Fn(u64) -> Result<(), anyhow::Error>>;
-        {
+        let (horizon_segno, reason, from_segno, remover) = {
             let shared_state = self.write_shared_state();
-            horizon_segno = shared_state.sk.get_horizon_segno(wal_backup_enabled);
-            remover = shared_state.sk.wal_store.remove_up_to();
-            if horizon_segno <= 1 || horizon_segno <= shared_state.last_removed_segno {
-                return Ok(());
+            let (horizon_segno, reason) =
+                shared_state.get_horizon_segno(wal_backup_enabled, policy);
+            let from_segno = shared_state.last_removed_segno;
+            if horizon_segno <= 1 || horizon_segno <= from_segno {
+                return Ok(WalRemoval {
+                    from_segno,
+                    to_segno: from_segno,
+                    reason,
+                });
             }
+            let remover = shared_state.sk.wal_store.remove_up_to();
             // release the lock before removing
-        }
+            (horizon_segno, reason, from_segno, remover)
+        };
 
         // delete old WAL files
         remover(horizon_segno - 1)?;
@@ -782,7 +1334,81 @@ impl Timeline {
         // update last_removed_segno
         let mut shared_state = self.write_shared_state();
         shared_state.last_removed_segno = horizon_segno;
-        Ok(())
+        Ok(WalRemoval {
+            from_segno,
+            to_segno: horizon_segno,
+            reason,
+        })
+    }
+
+    /// Compress full segments which are already offloaded, see
+    /// `wal_storage::compress_segments`. Returns the number of segments
+    /// compressed.
+    pub fn compress_cold_wal(&self) -> Result<u64> {
+        if self.is_cancelled() {
+            bail!(TimelineError::Cancelled(self.ttid));
+        }
+
+        let (from_segno, to_segno, wal_seg_size, disk_usage) = {
+            let shared_state = self.write_shared_state();
+            let wal_seg_size = shared_state.get_wal_seg_size();
+            // the segment of backup_lsn is not offloaded fully yet, the one
+            // of flush_lsn is still being written
+            let to_segno = min(
+                shared_state
+                    .sk
+                    .inmem
+                    .backup_lsn
+                    .segment_number(wal_seg_size),
+                shared_state
+                    .sk
+                    .wal_store
+                    .flush_lsn()
+                    .segment_number(wal_seg_size),
+            );
+            let from_segno = max(
+                shared_state.last_compressed_segno,
+                shared_state.last_removed_segno,
+            );
+            if to_segno <= from_segno {
+                return Ok(0);
+            }
+            (
+                from_segno,
+                to_segno,
+                wal_seg_size,
+                shared_state.sk.wal_store.disk_usage(),
+            )
+        };
+
+        let n_compressed = wal_storage::compress_segments(
+            &self.timeline_dir,
+            wal_seg_size,
+            &disk_usage,
+            from_segno,
+            to_segno,
+        )?;
+
+        let mut shared_state = self.write_shared_state();
+        shared_state.last_compressed_segno = to_segno;
+        Ok(n_compressed)
+    }
+}
+
+/// Result of `Timeline::remove_old_wal`.
+#[derive(Debug, Clone, Serialize)]
+pub struct WalRemoval {
+    /// Segments in `from_segno..to_segno` were removed; segments before
+    /// `from_segno` had already been removed (or never existed).
+    pub from_segno: XLogSegNo,
+    pub to_segno: XLogSegNo,
+    /// What holds WAL at `to_segno`, e.g. "backup_lsn".
+    pub reason: String,
+}
+
+impl WalRemoval {
+    pub fn removed_segments(&self) -> u64 {
+        self.to_segno - self.from_segno
     }
 }
 
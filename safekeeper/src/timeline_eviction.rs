@@ -0,0 +1,32 @@
+//! Thread unloading timelines idle for a long time from memory, so that
+//! safekeepers hosting many idle timelines don't keep their state and files
+//! open. Evicted timelines are loaded back on the next access.
+
+use std::{cmp::min, thread, time::Duration};
+
+use tracing::*;
+
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+pub fn thread_main(conf: SafeKeeperConf) {
+    let timeout = match conf.timeline_eviction_timeout {
+        Some(timeout) => timeout,
+        None => return,
+    };
+    let eviction_interval = min(timeout, Duration::from_secs(10));
+    loop {
+        let tlis = GlobalTimelines::get_all();
+        for tli in &tlis {
+            if tli.is_active() {
+                continue;
+            }
+            let ttid = tli.ttid;
+            let _enter =
+                info_span!("", tenant = %ttid.tenant_id, timeline = %ttid.timeline_id).entered();
+            if let Err(e) = GlobalTimelines::evict_if_idle(ttid, timeout) {
+                warn!("failed to evict timeline: {}", e);
+            }
+        }
+        thread::sleep(eviction_interval)
+    }
+}
@@ -0,0 +1,267 @@
+//! Registry of background tasks working with a timeline (WAL backup, recovery
+//! from peers). Tasks are stopped once the timeline is cancelled, and
+//! deletion and eviction wait for them to finish before the timeline files
+//! are closed and removed. Task status is reported through the HTTP API.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use parking_lot::{Condvar, Mutex};
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::*;
+use utils::id::TenantTimelineId;
+
+/// Number of finished tasks remembered for inspection.
+const MAX_FINISHED_TASKS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    Running,
+    /// Task completed successfully.
+    Finished,
+    /// Task returned an error or panicked.
+    Failed,
+    /// Task was stopped because the timeline was cancelled.
+    Cancelled,
+}
+
+/// Snapshot of the task state for reporting.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskInfo {
+    pub name: String,
+    pub status: TaskStatus,
+    /// Unix timestamp in seconds.
+    pub started_at: u64,
+    /// Unix timestamp in seconds.
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Default)]
+struct Tasks {
+    next_id: u64,
+    running: BTreeMap<u64, TaskInfo>,
+    /// Oldest first.
+    finished: VecDeque<TaskInfo>,
+}
+
+pub struct TimelineTasks {
+    ttid: TenantTimelineId,
+    cancellation_rx: watch::Receiver<bool>,
+    tasks: Mutex<Tasks>,
+    /// Notified whenever a task finishes.
+    task_finished: Condvar,
+}
+
+impl TimelineTasks {
+    pub fn new(ttid: TenantTimelineId, cancellation_rx: watch::Receiver<bool>) -> Self {
+        TimelineTasks {
+            ttid,
+            cancellation_rx,
+            tasks: Mutex::new(Tasks::default()),
+            task_finished: Condvar::new(),
+        }
+    }
+
+    /// Spawn the task on the current runtime. The task future is dropped as
+    /// soon as the timeline is cancelled; if it is cancelled already, the
+    /// task doesn't start at all.
+    pub fn spawn<F>(self: &Arc<Self>, name: &str, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let Some(id) = self.register(name) else {
+            info!("not starting {} task of cancelled timeline {}", name, self.ttid);
+            return tokio::spawn(async {});
+        };
+        let mut guard = TaskGuard {
+            tasks: Arc::clone(self),
+            id,
+            outcome: None,
+        };
+        let mut cancellation_rx = self.cancellation_rx.clone();
+        tokio::spawn(async move {
+            let outcome = tokio::select! {
+                res = task => match res {
+                    Ok(()) => (TaskStatus::Finished, None),
+                    Err(e) => (TaskStatus::Failed, Some(format!("{e:#}"))),
+                },
+                _ = wait_cancelled(&mut cancellation_rx) => (TaskStatus::Cancelled, None),
+            };
+            guard.outcome = Some(outcome);
+        })
+    }
+
+    /// Add a running task, unless the timeline is cancelled. Checking for
+    /// cancellation under the lock guarantees that `wait_stopped` called
+    /// after cancellation sees every task which is going to run.
+    fn register(&self, name: &str) -> Option<u64> {
+        let mut tasks = self.tasks.lock();
+        if *self.cancellation_rx.borrow() {
+            return None;
+        }
+        let id = tasks.next_id;
+        tasks.next_id += 1;
+        tasks.running.insert(
+            id,
+            TaskInfo {
+                name: name.to_owned(),
+                status: TaskStatus::Running,
+                started_at: now_secs(),
+                finished_at: None,
+                error: None,
+            },
+        );
+        Some(id)
+    }
+
+    fn unregister(&self, id: u64, status: TaskStatus, error: Option<String>) {
+        let mut tasks = self.tasks.lock();
+        if let Some(mut info) = tasks.running.remove(&id) {
+            if let Some(error) = &error {
+                warn!(
+                    "{} task of timeline {} failed: {}",
+                    info.name, self.ttid, error
+                );
+            }
+            info.status = status;
+            info.finished_at = Some(now_secs());
+            info.error = error;
+            if tasks.finished.len() == MAX_FINISHED_TASKS {
+                tasks.finished.pop_front();
+            }
+            tasks.finished.push_back(info);
+        }
+        self.task_finished.notify_all();
+    }
+
+    /// Whether some task is running.
+    pub fn has_running(&self) -> bool {
+        !self.tasks.lock().running.is_empty()
+    }
+
+    /// Block until all tasks have finished, for at most `timeout`. Should be
+    /// called after the timeline is cancelled, otherwise new tasks can be
+    /// started meanwhile. Returns whether all tasks have finished.
+    pub fn wait_stopped(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut tasks = self.tasks.lock();
+        while !tasks.running.is_empty() {
+            if self
+                .task_finished
+                .wait_until(&mut tasks, deadline)
+                .timed_out()
+            {
+                return tasks.running.is_empty();
+            }
+        }
+        true
+    }
+
+    /// Running and recently finished tasks, oldest first.
+    pub fn list(&self) -> Vec<TaskInfo> {
+        let tasks = self.tasks.lock();
+        let mut list: Vec<TaskInfo> = tasks
+            .finished
+            .iter()
+            .chain(tasks.running.values())
+            .cloned()
+            .collect();
+        list.sort_by_key(|info| info.started_at);
+        list
+    }
+}
+
+/// Unregisters the task when its future is gone, whichever way it ended:
+/// completed, cancelled, panicked or dropped with the runtime.
+struct TaskGuard {
+    tasks: Arc<TimelineTasks>,
+    id: u64,
+    outcome: Option<(TaskStatus, Option<String>)>,
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let (status, error) = self.outcome.take().unwrap_or_else(|| {
+            if std::thread::panicking() {
+                (TaskStatus::Failed, Some("task panicked".to_owned()))
+            } else {
+                (TaskStatus::Cancelled, None)
+            }
+        });
+        self.tasks.unregister(self.id, status, error);
+    }
+}
+
+/// Resolves once the timeline is cancelled or dropped.
+async fn wait_cancelled(cancellation_rx: &mut watch::Receiver<bool>) {
+    while !*cancellation_rx.borrow() {
+        if cancellation_rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::id::{TenantId, TimelineId};
+
+    fn ttid() -> TenantTimelineId {
+        TenantTimelineId::new(TenantId::generate(), TimelineId::generate())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_cancel_and_wait() {
+        let (cancellation_tx, cancellation_rx) = watch::channel(false);
+        let tasks = Arc::new(TimelineTasks::new(ttid(), cancellation_rx));
+
+        let done = tasks.spawn("done", async { Ok(()) });
+        done.await.unwrap();
+        let failed = tasks.spawn("failed", async { anyhow::bail!("oops") });
+        failed.await.unwrap();
+        let stuck = tasks.spawn("stuck", std::future::pending());
+        assert!(tasks.has_running());
+
+        let tasks_ = Arc::clone(&tasks);
+        let timed_out =
+            tokio::task::spawn_blocking(move || tasks_.wait_stopped(Duration::from_millis(10)));
+        assert!(!timed_out.await.unwrap());
+
+        cancellation_tx.send(true).unwrap();
+        let tasks_ = Arc::clone(&tasks);
+        let stopped =
+            tokio::task::spawn_blocking(move || tasks_.wait_stopped(Duration::from_secs(10)));
+        assert!(stopped.await.unwrap());
+        stuck.await.unwrap();
+
+        // no new tasks after cancellation
+        tasks.spawn("late", std::future::pending()).await.unwrap();
+        assert!(!tasks.has_running());
+
+        let statuses: Vec<(String, TaskStatus)> = tasks
+            .list()
+            .into_iter()
+            .map(|info| (info.name, info.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("done".to_owned(), TaskStatus::Finished),
+                ("failed".to_owned(), TaskStatus::Failed),
+                ("stuck".to_owned(), TaskStatus::Cancelled),
+            ]
+        );
+    }
+}
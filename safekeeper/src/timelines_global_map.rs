@@ -1,24 +1,38 @@
 //! This module contains global (tenant_id, timeline_id) -> Arc<Timeline> mapping.
-//! All timelines should always be present in this map, this is done by loading them
-//! all from the disk on startup and keeping them in memory.
+//! All timelines are loaded from the disk on startup and kept in memory, except
+//! the ones evicted because of inactivity, which are loaded back on access.
 
-use crate::safekeeper::ServerInfo;
+use crate::drain;
+use crate::quota;
+use crate::safekeeper::TimelineCreateParams;
 use crate::timeline::{Timeline, TimelineError};
+use crate::wal_backup;
 use crate::SafeKeeperConf;
 use anyhow::{anyhow, bail, Context, Result};
 use once_cell::sync::Lazy;
 use serde::Serialize;
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
 use tokio::sync::mpsc::Sender;
 use tracing::*;
+use utils::crashsafe;
 use utils::id::{TenantId, TenantTimelineId, TimelineId};
-use utils::lsn::Lsn;
 
 struct GlobalTimelinesState {
     timelines: HashMap<TenantTimelineId, Arc<Timeline>>,
+    // Deleted timelines. Their ids must not be reused, otherwise a compute
+    // which is not aware of the deletion would recreate the timeline from
+    // scratch on connect. Persisted as marker files in the tenant directory,
+    // see `tombstone_path`.
+    tombstones: HashSet<TenantTimelineId>,
+    // Tenants deleted as a whole, no new timelines can be created for them.
+    // Persisted as marker files in the workdir, see `tenant_tombstone_path`.
+    deleted_tenants: HashSet<TenantId>,
+    // Timelines unloaded from memory because of inactivity.
+    evicted: HashSet<TenantTimelineId>,
     wal_backup_launcher_tx: Option<Sender<TenantTimelineId>>,
     conf: Option<SafeKeeperConf>,
 }
@@ -45,10 +59,19 @@ impl GlobalTimelinesState {
         if self.timelines.contains_key(&ttid) {
             bail!(TimelineError::AlreadyExists(ttid));
         }
+        if self.is_deleted(&ttid) {
+            bail!(TimelineError::Cancelled(ttid));
+        }
         self.timelines.insert(ttid, timeline);
+        self.evicted.remove(&ttid);
         Ok(())
     }
 
+    /// Whether the timeline or its whole tenant was deleted.
+    fn is_deleted(&self, ttid: &TenantTimelineId) -> bool {
+        self.tombstones.contains(ttid) || self.deleted_tenants.contains(&ttid.tenant_id)
+    }
+
     /// Get timeline from the map. Returns error if timeline doesn't exist.
     fn get(&self, ttid: &TenantTimelineId) -> Result<Arc<Timeline>> {
         self.timelines
@@ -61,6 +84,9 @@ impl GlobalTimelinesState {
 static TIMELINES_STATE: Lazy<Mutex<GlobalTimelinesState>> = Lazy::new(|| {
     Mutex::new(GlobalTimelinesState {
         timelines: HashMap::new(),
+        tombstones: HashSet::new(),
+        deleted_tenants: HashSet::new(),
+        evicted: HashSet::new(),
         wal_backup_launcher_tx: None,
         conf: None,
     })
@@ -89,9 +115,20 @@ impl GlobalTimelines {
         {
             match &tenants_dir_entry {
                 Ok(tenants_dir_entry) => {
-                    if let Ok(tenant_id) =
-                        TenantId::from_str(tenants_dir_entry.file_name().to_str().unwrap_or(""))
+                    let file_name = tenants_dir_entry.file_name();
+                    let file_name = file_name.to_str().unwrap_or("");
+                    if let Some(tenant_id) = file_name
+                        .strip_suffix(TOMBSTONE_SUFFIX)
+                        .and_then(|id| TenantId::from_str(id).ok())
                     {
+                        state.deleted_tenants.insert(tenant_id);
+                    } else if let Ok(tenant_id) = TenantId::from_str(file_name) {
+                        if tenant_tombstone_path(state.get_conf(), &tenant_id).exists() {
+                            // Deletion was interrupted by a crash, finish it.
+                            info!("removing leftovers of deleted tenant {}", tenant_id);
+                            delete_dir(tenants_dir_entry.path())?;
+                            continue;
+                        }
                         tenant_count += 1;
                         GlobalTimelines::load_tenant_timelines(&mut state, tenant_id)?;
                     }
@@ -124,10 +161,23 @@ impl GlobalTimelines {
         {
             match &timelines_dir_entry {
                 Ok(timeline_dir_entry) => {
-                    if let Ok(timeline_id) =
-                        TimelineId::from_str(timeline_dir_entry.file_name().to_str().unwrap_or(""))
+                    let file_name = timeline_dir_entry.file_name();
+                    let file_name = file_name.to_str().unwrap_or("");
+                    if let Some(timeline_id) = file_name
+                        .strip_suffix(TOMBSTONE_SUFFIX)
+                        .and_then(|id| TimelineId::from_str(id).ok())
                     {
+                        state
+                            .tombstones
+                            .insert(TenantTimelineId::new(tenant_id, timeline_id));
+                    } else if let Ok(timeline_id) = TimelineId::from_str(file_name) {
                         let ttid = TenantTimelineId::new(tenant_id, timeline_id);
+                        if tombstone_path(state.get_conf(), &ttid).exists() {
+                            // Deletion was interrupted by a crash, finish it.
+                            info!("removing leftovers of deleted timeline {}", ttid);
+                            delete_dir(timeline_dir_entry.path())?;
+                            continue;
+                        }
                         match Timeline::load_timeline(
                             state.get_conf().clone(),
                             ttid,
@@ -161,18 +211,23 @@ impl GlobalTimelines {
 
     /// Create a new timeline with the given id. If the timeline already exists, returns
     /// an existing timeline.
-    pub fn create(
-        ttid: TenantTimelineId,
-        server_info: ServerInfo,
-        commit_lsn: Lsn,
-        local_start_lsn: Lsn,
-    ) -> Result<Arc<Timeline>> {
+    pub fn create(ttid: TenantTimelineId, params: TimelineCreateParams) -> Result<Arc<Timeline>> {
         let (conf, wal_backup_launcher_tx) = {
             let state = TIMELINES_STATE.lock().unwrap();
             if let Ok(timeline) = state.get(&ttid) {
                 // Timeline already exists, return it.
                 return Ok(timeline);
             }
+            if state.evicted.contains(&ttid) {
+                drop(state);
+                return Self::load_evicted(ttid);
+            }
+            if state.is_deleted(&ttid) {
+                bail!(TimelineError::Cancelled(ttid));
+            }
+            if drain::is_draining() {
+                bail!("safekeeper is shutting down, refusing to create {}", ttid);
+            }
             state.get_dependencies()
         };
 
@@ -182,9 +237,7 @@ impl GlobalTimelines {
             conf,
             ttid,
             wal_backup_launcher_tx,
-            server_info,
-            commit_lsn,
-            local_start_lsn,
+            params,
         )?);
 
         // Take a lock and finish the initialization holding this mutex. No other threads
@@ -227,11 +280,36 @@ impl GlobalTimelines {
         }
     }
 
+    /// Load a timeline which appeared on disk after startup, e.g. pulled
+    /// from a peer, and start its background activity.
+    pub fn load_timeline(ttid: TenantTimelineId) -> Result<Arc<Timeline>> {
+        let (conf, wal_backup_launcher_tx) = {
+            let state = TIMELINES_STATE.lock().unwrap();
+            if state.is_deleted(&ttid) {
+                bail!(TimelineError::Cancelled(ttid));
+            }
+            state.get_dependencies()
+        };
+
+        let timeline = Arc::new(Timeline::load_timeline(conf, ttid, wal_backup_launcher_tx)?);
+        TIMELINES_STATE
+            .lock()
+            .unwrap()
+            .try_insert(timeline.clone())?;
+        timeline
+            .wal_backup_launcher_tx
+            .blocking_send(timeline.ttid)?;
+        Ok(timeline)
+    }
+
     /// Get a timeline from the global map. If it's not present, it doesn't exist on disk,
     /// or was corrupted and couldn't be loaded on startup. Returned timeline is always valid,
     /// i.e. loaded in memory and not cancelled.
     pub fn get(ttid: TenantTimelineId) -> Result<Arc<Timeline>> {
-        let res = TIMELINES_STATE.lock().unwrap().get(&ttid);
+        let (res, evicted) = {
+            let state = TIMELINES_STATE.lock().unwrap();
+            (state.get(&ttid), state.evicted.contains(&ttid))
+        };
 
         match res {
             Ok(tli) => {
@@ -240,10 +318,43 @@ impl GlobalTimelines {
                 }
                 Ok(tli)
             }
+            Err(_) if evicted => Self::load_evicted(ttid),
             Err(e) => Err(e),
         }
     }
 
+    /// Load back a timeline evicted from memory because of inactivity.
+    fn load_evicted(ttid: TenantTimelineId) -> Result<Arc<Timeline>> {
+        let (conf, wal_backup_launcher_tx) = TIMELINES_STATE.lock().unwrap().get_dependencies();
+        let timeline = Arc::new(Timeline::load_timeline(conf, ttid, wal_backup_launcher_tx)?);
+
+        let mut state = TIMELINES_STATE.lock().unwrap();
+        if !state.evicted.contains(&ttid) {
+            // Loaded concurrently or deleted meanwhile.
+            return state.get(&ttid);
+        }
+        state.try_insert(timeline.clone())?;
+        info!("loaded evicted timeline {}", ttid);
+        Ok(timeline)
+    }
+
+    /// Unload the timeline from memory if it has been idle for `timeout`; it
+    /// is loaded back on the next access. Returns whether it was evicted.
+    pub fn evict_if_idle(ttid: TenantTimelineId, timeout: Duration) -> Result<bool> {
+        let timeline = TIMELINES_STATE.lock().unwrap().get(&ttid)?;
+        // Take a lock and finish the eviction holding this mutex, so that
+        // the timeline can't become active meanwhile.
+        let mut shared_state = timeline.write_shared_state();
+        if !timeline.evict_if_idle(&mut shared_state, timeout)? {
+            return Ok(false);
+        }
+
+        let mut state = TIMELINES_STATE.lock().unwrap();
+        state.timelines.remove(&ttid);
+        state.evicted.insert(ttid);
+        Ok(true)
+    }
+
     /// Returns all timelines. This is used for background timeline proccesses.
     pub fn get_all() -> Vec<Arc<Timeline>> {
         let global_lock = TIMELINES_STATE.lock().unwrap();
@@ -267,21 +378,80 @@ impl GlobalTimelines {
             .collect()
     }
 
-    /// Cancels timeline, then deletes the corresponding data directory.
+    /// Returns ids of all timelines of the tenant, including evicted ones.
+    pub fn get_tenant_timelines(tenant_id: &TenantId) -> Vec<TimelineId> {
+        let mut timelines: Vec<TimelineId> = Self::get_all_for_tenant(*tenant_id)
+            .into_iter()
+            .filter(|t| !t.is_cancelled())
+            .map(|t| t.ttid.timeline_id)
+            .collect();
+        timelines.extend(
+            TIMELINES_STATE
+                .lock()
+                .unwrap()
+                .evicted
+                .iter()
+                .filter(|ttid| ttid.tenant_id == *tenant_id)
+                .map(|ttid| ttid.timeline_id),
+        );
+        timelines.sort();
+        timelines
+    }
+
+    /// Returns the disk space used by the tenant: WAL of loaded timelines,
+    /// which is tracked by their storage, plus the size of everything else
+    /// in the tenant directory, e.g. evicted timelines or the ones which
+    /// failed to load.
+    pub fn get_tenant_disk_usage(conf: &SafeKeeperConf, tenant_id: &TenantId) -> Result<u64> {
+        let loaded: HashMap<TimelineId, Arc<Timeline>> = Self::get_all_for_tenant(*tenant_id)
+            .into_iter()
+            .map(|tli| (tli.ttid.timeline_id, tli))
+            .collect();
+        let tenant_dir = conf.tenant_dir(tenant_id);
+        let entries = match std::fs::read_dir(&tenant_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to read {}", tenant_dir.display()))
+            }
+        };
+        let mut size = 0;
+        for entry in entries {
+            let entry = entry?;
+            let tli = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| TimelineId::from_str(name).ok())
+                .and_then(|timeline_id| loaded.get(&timeline_id));
+            size += match tli {
+                Some(tli) => tli.get_wal_disk_usage(),
+                None => path_size(&entry.path())
+                    .with_context(|| format!("failed to get size of {}", entry.path().display()))?,
+            };
+        }
+        Ok(size)
+    }
+
+    /// Cancels timeline, then deletes the corresponding data directory. The
+    /// timeline is removed from the map, leaving a tombstone which prevents
+    /// its recreation.
     pub fn delete_force(ttid: &TenantTimelineId) -> Result<TimelineDeleteForceResult> {
-        let tli_res = TIMELINES_STATE.lock().unwrap().get(ttid);
+        let (tli_res, conf) = {
+            let state = TIMELINES_STATE.lock().unwrap();
+            (state.get(ttid), state.get_conf().clone())
+        };
         match tli_res {
             Ok(timeline) => {
                 // Take a lock and finish the deletion holding this mutex.
                 let mut shared_state = timeline.write_shared_state();
 
                 info!("deleting timeline {}", ttid);
+                persist_tombstone(&conf, ttid)?;
                 let (dir_existed, was_active) = timeline.delete_from_disk(&mut shared_state)?;
 
-                // Remove timeline from the map.
-                // FIXME: re-enable it once we fix the issue with recreation of deleted timelines
-                // https://github.com/neondatabase/neon/issues/3146
-                // TIMELINES_STATE.lock().unwrap().timelines.remove(ttid);
+                let mut state = TIMELINES_STATE.lock().unwrap();
+                state.tombstones.insert(*ttid);
+                state.timelines.remove(ttid);
 
                 Ok(TimelineDeleteForceResult {
                     dir_existed,
@@ -290,12 +460,13 @@ impl GlobalTimelines {
             }
             Err(_) => {
                 // Timeline is not memory, but it may still exist on disk in broken state.
-                let dir_path = TIMELINES_STATE
-                    .lock()
-                    .unwrap()
-                    .get_conf()
-                    .timeline_dir(ttid);
-                let dir_existed = delete_dir(dir_path)?;
+                persist_tombstone(&conf, ttid)?;
+                {
+                    let mut state = TIMELINES_STATE.lock().unwrap();
+                    state.tombstones.insert(*ttid);
+                    state.evicted.remove(ttid);
+                }
+                let dir_existed = delete_dir(conf.timeline_dir(ttid))?;
 
                 Ok(TimelineDeleteForceResult {
                     dir_existed,
@@ -305,6 +476,41 @@ impl GlobalTimelines {
         }
     }
 
+    /// Deletes the timeline: stops background tasks and connections working
+    /// with it, removes its WAL and control file, and remembers that it was
+    /// deleted. Unless `only_local` is set, WAL offloaded to remote storage
+    /// is removed as well; remote WAL is shared by all safekeepers of the
+    /// timeline, so this should be requested only when deleting the timeline
+    /// everywhere.
+    pub async fn delete(
+        ttid: &TenantTimelineId,
+        only_local: bool,
+    ) -> Result<TimelineDeleteForceResult> {
+        // Remember what was offloaded before the control file is gone.
+        let remote_state = match Self::get(*ttid) {
+            Ok(tli) if !only_local => {
+                let (inmem, state) = tli.get_state();
+                Some((
+                    state.timeline_start_lsn,
+                    inmem.backup_lsn,
+                    state.server.wal_seg_size as usize,
+                ))
+            }
+            _ => None,
+        };
+
+        let ttid_ = *ttid;
+        let result = tokio::task::spawn_blocking(move || Self::delete_force(&ttid_))
+            .await
+            .context("timeline deletion task panicked")??;
+
+        if let Some((start_lsn, backup_lsn, wal_seg_size)) = remote_state {
+            let conf = TIMELINES_STATE.lock().unwrap().get_conf().clone();
+            wal_backup::delete_timeline(&conf, ttid, start_lsn, backup_lsn, wal_seg_size).await?;
+        }
+        Ok(result)
+    }
+
     /// Deactivates and deletes all timelines for the tenant. Returns map of all timelines which
     /// the tenant had, `true` if a timeline was active. There may be a race if new timelines are
     /// created simultaneously. In that case the function will return error and the caller should
@@ -313,6 +519,16 @@ impl GlobalTimelines {
         tenant_id: &TenantId,
     ) -> Result<HashMap<TenantTimelineId, TimelineDeleteForceResult>> {
         info!("deleting all timelines for tenant {}", tenant_id);
+        // Forbid creation of new timelines first, so that none can appear
+        // behind our back while we are deleting the existing ones.
+        let conf = TIMELINES_STATE.lock().unwrap().get_conf().clone();
+        create_tombstone(&tenant_tombstone_path(&conf, tenant_id))?;
+        {
+            let mut state = TIMELINES_STATE.lock().unwrap();
+            state.deleted_tenants.insert(*tenant_id);
+            // Evicted timelines are removed with the tenant directory below.
+            state.evicted.retain(|ttid| ttid.tenant_id != *tenant_id);
+        }
         let to_delete = Self::get_all_for_tenant(*tenant_id);
 
         let mut err = None;
@@ -336,28 +552,22 @@ impl GlobalTimelines {
             return Err(e);
         }
 
+        quota::forget_tenant(tenant_id);
+
         // There may be broken timelines on disk, so delete the whole tenant dir as well.
-        // Note that we could concurrently create new timelines while we were deleting them,
-        // so the directory may be not empty. In this case timelines will have bad state
-        // and timeline background jobs can panic.
-        delete_dir(
-            TIMELINES_STATE
-                .lock()
-                .unwrap()
-                .get_conf()
-                .tenant_dir(tenant_id),
-        )?;
-
-        // FIXME: we temporarily disabled removing timelines from the map, see `delete_force`
-        // let tlis_after_delete = Self::get_all_for_tenant(*tenant_id);
-        // if !tlis_after_delete.is_empty() {
-        //     // Some timelines were created while we were deleting them, returning error
-        //     // to the caller, so it can retry later.
-        //     bail!(
-        //         "failed to delete all timelines for tenant {}: some timelines were created while we were deleting them",
-        //         tenant_id
-        //     );
-        // }
+        delete_dir(conf.tenant_dir(tenant_id))?;
+
+        // Timeline creation could have been in progress when the tenant was
+        // marked as deleted.
+        let tlis_after_delete = Self::get_all_for_tenant(*tenant_id);
+        if !tlis_after_delete.is_empty() {
+            // Some timelines were created while we were deleting them, returning error
+            // to the caller, so it can retry later.
+            bail!(
+                "failed to delete all timelines for tenant {}: some timelines were created while we were deleting them",
+                tenant_id
+            );
+        }
 
         Ok(deleted)
     }
@@ -369,6 +579,59 @@ pub struct TimelineDeleteForceResult {
     pub was_active: bool,
 }
 
+/// Returns total size of the file, or of files in the directory and its
+/// subdirectories. Files removed during the scan count as empty.
+fn path_size(path: &Path) -> Result<u64> {
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut size = 0;
+    for entry in entries {
+        size += path_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+/// Suffix of the marker file which remembers a deleted tenant or timeline.
+const TOMBSTONE_SUFFIX: &str = ".deleted";
+
+/// Path of the marker file for the deleted timeline, in the tenant directory.
+fn tombstone_path(conf: &SafeKeeperConf, ttid: &TenantTimelineId) -> PathBuf {
+    conf.tenant_dir(&ttid.tenant_id)
+        .join(format!("{}{}", ttid.timeline_id, TOMBSTONE_SUFFIX))
+}
+
+/// Path of the marker file for the deleted tenant, in the workdir.
+fn tenant_tombstone_path(conf: &SafeKeeperConf, tenant_id: &TenantId) -> PathBuf {
+    conf.workdir
+        .join(format!("{}{}", tenant_id, TOMBSTONE_SUFFIX))
+}
+
+/// Durably remembers that the timeline was deleted, so that it isn't
+/// recreated after restart. Must be done before the data is removed.
+fn persist_tombstone(conf: &SafeKeeperConf, ttid: &TenantTimelineId) -> Result<()> {
+    std::fs::create_dir_all(conf.tenant_dir(&ttid.tenant_id))?;
+    create_tombstone(&tombstone_path(conf, ttid))
+}
+
+/// Creates the marker file and makes sure it survives a crash.
+fn create_tombstone(path: &Path) -> Result<()> {
+    std::fs::File::create(path)
+        .with_context(|| format!("failed to create tombstone {}", path.display()))?;
+    crashsafe::fsync_file_and_parent(path)?;
+    Ok(())
+}
+
 /// Deletes directory and it's contents. Returns false if directory does not exist.
 fn delete_dir(path: PathBuf) -> Result<bool> {
     match std::fs::remove_dir_all(path) {
@@ -377,3 +640,30 @@ fn delete_dir(path: PathBuf) -> Result<bool> {
         Err(e) => Err(e.into()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tombstone_persisted() {
+        let workdir = tempfile::tempdir().unwrap();
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            ..SafeKeeperConf::dummy()
+        };
+        let ttid = TenantTimelineId::generate();
+
+        persist_tombstone(&conf, &ttid).unwrap();
+
+        let names = std::fs::read_dir(conf.tenant_dir(&ttid.tenant_id))
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 1);
+        // Tombstone must not be taken for a timeline directory on load.
+        assert!(TimelineId::from_str(&names[0]).is_err());
+        let timeline_id = names[0].strip_suffix(TOMBSTONE_SUFFIX).unwrap();
+        assert_eq!(TimelineId::from_str(timeline_id).unwrap(), ttid.timeline_id);
+    }
+}
@@ -0,0 +1,270 @@
+//!
+//! Server-side TLS for the safekeeper Postgres and HTTP listeners.
+//!
+//! Certificate and key are served through a resolver, and client
+//! certificates, if a CA is configured, are checked by a verifier, both of
+//! which can swap their state on the fly. So rotated certificates and CA are
+//! picked up by `reload` (on SIGHUP or via the HTTP API) without restarting
+//! the safekeeper. Established connections keep the old ones.
+//!
+use anyhow::{ensure, Context, Result};
+use parking_lot::RwLock;
+use rustls::server::{
+    AllowAnyAuthenticatedClient, ClientCertVerified, ClientCertVerifier, ClientHello,
+    ResolvesServerCert,
+};
+use rustls::sign::CertifiedKey;
+use rustls::{
+    Certificate, ClientConfig, DistinguishedNames, PrivateKey, RootCertStore, ServerConfig,
+};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::*;
+
+/// Paths to the PEM files TLS is configured from.
+#[derive(Debug, Clone)]
+pub struct TlsPaths {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// If set, clients must present a certificate signed by this CA.
+    pub ca_path: Option<PathBuf>,
+}
+
+/// TLS state shared by all listeners.
+pub struct ServerTls {
+    paths: TlsPaths,
+    resolver: Arc<ReloadableCertResolver>,
+    verifier: Option<Arc<ReloadableClientVerifier>>,
+    config: Arc<ServerConfig>,
+}
+
+impl ServerTls {
+    pub fn load(paths: TlsPaths) -> Result<Self> {
+        let resolver = Arc::new(ReloadableCertResolver {
+            key: RwLock::new(load_certified_key(&paths.cert_path, &paths.key_path)?),
+        });
+        let verifier = match &paths.ca_path {
+            Some(ca_path) => Some(Arc::new(ReloadableClientVerifier {
+                inner: RwLock::new(load_client_verifier(ca_path)?),
+            })),
+            None => None,
+        };
+
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let config = match &verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier.clone()),
+            None => builder.with_no_client_auth(),
+        }
+        .with_cert_resolver(resolver.clone());
+
+        Ok(Self {
+            paths,
+            resolver,
+            verifier,
+            config: Arc::new(config),
+        })
+    }
+
+    /// Config to be passed to the listeners; it picks up reloaded
+    /// certificates and CA for new connections.
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.config.clone()
+    }
+
+    /// Re-read certificate, key and CA from disk. On failure the previous
+    /// ones are kept.
+    pub fn reload(&self) -> Result<()> {
+        let key = load_certified_key(&self.paths.cert_path, &self.paths.key_path)?;
+        let client_verifier = match &self.paths.ca_path {
+            Some(ca_path) => Some(load_client_verifier(ca_path)?),
+            None => None,
+        };
+
+        *self.resolver.key.write() = key;
+        if let (Some(verifier), Some(client_verifier)) = (&self.verifier, client_verifier) {
+            *verifier.inner.write() = client_verifier;
+        }
+        info!(
+            "reloaded TLS certificate from {}",
+            self.paths.cert_path.display()
+        );
+        Ok(())
+    }
+
+    /// Config for connections to peer safekeepers: their certificate is
+    /// verified against our CA and ours is presented as the client one.
+    /// Read from disk on each call, so it follows reloads.
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        let ca_path = self
+            .paths
+            .ca_path
+            .as_ref()
+            .context("TLS CA is not configured, can't verify peer certificates")?;
+        let roots = load_root_store(ca_path)?;
+        let (certs, key) = load_cert_and_key(&self.paths.cert_path, &self.paths.key_path)?;
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_single_cert(certs, key)
+            .with_context(|| format!("invalid TLS key in {}", self.paths.key_path.display()))
+    }
+}
+
+impl fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerTls")
+            .field("paths", &self.paths)
+            .finish()
+    }
+}
+
+struct ReloadableCertResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadableCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().clone())
+    }
+}
+
+struct ReloadableClientVerifier {
+    inner: RwLock<Arc<dyn ClientCertVerifier>>,
+}
+
+impl ClientCertVerifier for ReloadableClientVerifier {
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.inner.read().client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.inner
+            .read()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<Arc<CertifiedKey>> {
+    let (certs, key) = load_cert_and_key(cert_path, key_path)?;
+    let signing_key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| anyhow::anyhow!("unsupported TLS key in {}", key_path.display()))?;
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+fn load_cert_and_key(cert_path: &Path, key_path: &Path) -> Result<(Vec<Certificate>, PrivateKey)> {
+    let certs = load_certs(cert_path)?;
+    ensure!(
+        !certs.is_empty(),
+        "no certificates found in {}",
+        cert_path.display()
+    );
+
+    let key_bytes = std::fs::read(key_path)
+        .with_context(|| format!("failed to read TLS key file {}", key_path.display()))?;
+    let mut keys = rustls_pemfile::pkcs8_private_keys(&mut &key_bytes[..])
+        .with_context(|| format!("failed to parse TLS keys in {}", key_path.display()))?;
+    ensure!(keys.len() == 1, "keys.len() = {} (should be 1)", keys.len());
+    Ok((certs, PrivateKey(keys.pop().unwrap())))
+}
+
+fn load_client_verifier(ca_path: &Path) -> Result<Arc<dyn ClientCertVerifier>> {
+    Ok(AllowAnyAuthenticatedClient::new(load_root_store(ca_path)?))
+}
+
+fn load_root_store(ca_path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots
+            .add(&cert)
+            .with_context(|| format!("invalid CA certificate in {}", ca_path.display()))?;
+    }
+    ensure!(
+        !roots.is_empty(),
+        "no CA certificates found in {}",
+        ca_path.display()
+    );
+    Ok(roots)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let bytes = std::fs::read(path)
+        .with_context(|| format!("failed to read TLS certificate file {}", path.display()))?;
+    Ok(rustls_pemfile::certs(&mut &bytes[..])
+        .with_context(|| format!("failed to parse TLS certificates in {}", path.display()))?
+        .into_iter()
+        .map(Certificate)
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Generate a CA and a certificate signed by it into `dir`, overwriting
+    /// the previous ones.
+    pub(crate) fn generate_tls_files(dir: &Path) -> TlsPaths {
+        let ca = rcgen::Certificate::from_params({
+            let mut params = rcgen::CertificateParams::default();
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            params
+        })
+        .unwrap();
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+        let paths = TlsPaths {
+            cert_path: dir.join("server.crt"),
+            key_path: dir.join("server.key"),
+            ca_path: Some(dir.join("ca.crt")),
+        };
+        let cert_pem = cert.serialize_pem_with_signer(&ca).unwrap();
+        std::fs::write(&paths.cert_path, cert_pem).unwrap();
+        std::fs::write(&paths.key_path, cert.serialize_private_key_pem()).unwrap();
+        std::fs::write(paths.ca_path.as_ref().unwrap(), ca.serialize_pem().unwrap()).unwrap();
+        paths
+    }
+
+    fn served_cert(tls: &ServerTls) -> Certificate {
+        tls.resolver.key.read().cert[0].clone()
+    }
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = generate_tls_files(dir.path());
+        let tls = ServerTls::load(paths.clone()).unwrap();
+        let config = tls.server_config();
+        let old_cert = load_certs(&paths.cert_path).unwrap().remove(0);
+        assert_eq!(served_cert(&tls), old_cert);
+        // the server certificate is signed by the CA
+        let verifier = tls.verifier.clone().unwrap();
+        assert!(verifier
+            .verify_client_cert(&old_cert, &[], SystemTime::now())
+            .is_ok());
+
+        generate_tls_files(dir.path());
+        tls.reload().unwrap();
+        let new_cert = load_certs(&paths.cert_path).unwrap().remove(0);
+        assert_ne!(new_cert, old_cert);
+        assert_eq!(served_cert(&tls), new_cert);
+        assert!(verifier
+            .verify_client_cert(&new_cert, &[], SystemTime::now())
+            .is_ok());
+        assert!(verifier
+            .verify_client_cert(&old_cert, &[], SystemTime::now())
+            .is_err());
+        // listeners keep the same config, which serves the new state
+        assert!(Arc::ptr_eq(&config, &tls.server_config()));
+
+        // failed reload keeps the previous state
+        std::fs::write(&paths.key_path, "garbage").unwrap();
+        assert!(tls.reload().is_err());
+        assert_eq!(served_cert(&tls), new_cert);
+    }
+}
@@ -1,37 +1,43 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context as _, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 
 use tokio::task::JoinHandle;
 use utils::id::NodeId;
 
-use std::cmp::min;
 use std::collections::HashMap;
+use std::future::Future;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
 use std::time::Duration;
 
-use postgres_ffi::v14::xlog_utils::XLogSegNoOffsetToRecPtr;
+use postgres_ffi::v14::xlog_utils::{IsXLogFileName, XLogFromFileName, XLogSegNoOffsetToRecPtr};
 use postgres_ffi::XLogFileName;
 use postgres_ffi::{XLogSegNo, PG_TLI};
-use remote_storage::{GenericRemoteStorage, RemotePath};
+use remote_storage::{GenericRemoteStorage, RemotePath, RetryConfig};
 use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 use tokio::runtime::Builder;
 
 use tokio::select;
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::sync::watch;
-use tokio::time::sleep;
+use tokio::sync::Semaphore;
+use tokio::time::{sleep, Sleep};
 use tracing::*;
 
 use utils::{id::TenantTimelineId, lsn::Lsn};
 
+use crate::defaults::DEFAULT_WAL_BACKUP_PARALLEL_UPLOADS;
+use crate::metrics::WAL_BACKUP_THROTTLED_SECONDS;
+use crate::rate_limit::TokenBucket;
+use crate::safekeeper::Term;
 use crate::timeline::{PeerInfo, Timeline};
-use crate::{GlobalTimelines, SafeKeeperConf};
+use crate::{wal_backup_scrub, GlobalTimelines, SafeKeeperConf};
 
-use once_cell::sync::OnceCell;
-
-const UPLOAD_FAILURE_RETRY_MIN_MS: u64 = 10;
-const UPLOAD_FAILURE_RETRY_MAX_MS: u64 = 5000;
+use once_cell::sync::{Lazy, OnceCell};
 
 pub fn wal_backup_launcher_thread_main(
     conf: SafeKeeperConf,
@@ -132,6 +138,17 @@ fn determine_offloader(
     }
 }
 
+/// Whether this safekeeper is elected to offload WAL of the timeline.
+pub(crate) fn is_elected_offloader(conf: &SafeKeeperConf, tli: &Timeline) -> bool {
+    let (offloader, _) = determine_offloader(
+        &tli.get_peers(conf),
+        tli.get_wal_backup_lsn(),
+        tli.ttid,
+        conf,
+    );
+    offloader == Some(conf.my_id)
+}
+
 /// Based on peer information determine which safekeeper should offload; if it
 /// is me, run (per timeline) task, if not yet. OTOH, if it is not me and task
 /// is running, kill it.
@@ -152,11 +169,19 @@ async fn update_task(
 
             let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
             let timeline_dir = conf.timeline_dir(&ttid);
-
-            let handle = tokio::spawn(
-                backup_task_main(ttid, timeline_dir, conf.workdir.clone(), shutdown_rx)
-                    .instrument(info_span!("WAL backup task", ttid = %ttid)),
-            );
+            let retry = conf
+                .remote_storage
+                .as_ref()
+                .expect("backup is started only with remote storage configured")
+                .retry_config();
+
+            let task =
+                backup_task_main(ttid, timeline_dir, conf.workdir.clone(), retry, shutdown_rx)
+                    .instrument(info_span!("WAL backup task", ttid = %ttid));
+            let handle = entry.timeline.tasks().spawn("WAL backup", async move {
+                task.await;
+                Ok(())
+            });
 
             entry.handle = Some(WalBackupTaskHandle {
                 shutdown_tx,
@@ -183,6 +208,10 @@ async fn wal_backup_launcher_main_loop(
         conf.remote_storage
     );
 
+    if let Err(e) = set_limits(BackupLimits::from_conf(&conf)) {
+        error!("invalid WAL backup limits, keeping defaults: {:#}", e);
+    }
+
     let conf_ = conf.clone();
     REMOTE_STORAGE.get_or_init(|| {
         conf_
@@ -191,6 +220,12 @@ async fn wal_backup_launcher_main_loop(
             .map(|c| GenericRemoteStorage::from_config(c).expect("failed to create remote storage"))
     });
 
+    if let Some(interval) = conf.wal_backup_scrub_interval {
+        if conf.remote_storage.is_some() && conf.wal_backup_enabled {
+            tokio::spawn(wal_backup_scrub::scrub_main_loop(conf.clone(), interval));
+        }
+    }
+
     // Presense in this map means launcher is aware s3 offloading is needed for
     // the timeline, but task is started only if it makes sense for to offload
     // from this safekeeper.
@@ -209,6 +244,21 @@ async fn wal_backup_launcher_main_loop(
                 // do we need to do anything at all?
                 if timeline.is_some() != tasks.contains_key(&ttid) {
                     if let Some(timeline) = timeline {
+                        // Timeline woke up; if we lost the tail of WAL,
+                        // try to get it back from remote storage. Done in
+                        // the background not to hold up other timelines.
+                        let restore_conf = conf.clone();
+                        let restore_timeline = Arc::clone(&timeline);
+                        tokio::spawn(
+                            async move {
+                                if let Err(e) =
+                                    restore_partial_segment(&restore_conf, &restore_timeline).await
+                                {
+                                    warn!("failed to restore partial segment: {:?}", e);
+                                }
+                            }
+                            .instrument(info_span!("restore partial segment", ttid = %ttid)),
+                        );
                         // need to start the task
                         let entry = tasks.entry(ttid).or_insert(WalBackupTimelineEntry {
                             timeline,
@@ -219,7 +269,15 @@ async fn wal_backup_launcher_main_loop(
                         // need to stop the task
                         info!("stopping WAL backup task for {}", ttid);
                         let mut entry = tasks.remove(&ttid).unwrap();
+                        let was_offloading = entry.handle.is_some();
                         shut_down_task(ttid, &mut entry).await;
+                        // Timeline went idle; if we were the offloader, also
+                        // save the tail of WAL which is not in a full segment.
+                        if was_offloading && !entry.timeline.is_cancelled() {
+                            if let Err(e) = upload_partial_segment(&conf, &entry.timeline).await {
+                                warn!("failed to upload partial segment for {}: {:?}", ttid, e);
+                            }
+                        }
                     }
                 }
             }
@@ -240,6 +298,7 @@ struct WalBackupTask {
     workspace_dir: PathBuf,
     wal_seg_size: usize,
     commit_lsn_watch_rx: watch::Receiver<Lsn>,
+    retry: RetryConfig,
 }
 
 /// Offload single timeline.
@@ -247,6 +306,7 @@ async fn backup_task_main(
     ttid: TenantTimelineId,
     timeline_dir: PathBuf,
     workspace_dir: PathBuf,
+    retry: RetryConfig,
     mut shutdown_rx: Receiver<()>,
 ) {
     info!("started");
@@ -263,6 +323,7 @@ async fn backup_task_main(
         timeline: tli,
         timeline_dir,
         workspace_dir,
+        retry,
     };
 
     // task is spinned up only when wal_seg_size already initialized
@@ -294,12 +355,7 @@ impl WalBackupTask {
                 }
             } else {
                 // or just sleep if we errored previously
-                let mut retry_delay = UPLOAD_FAILURE_RETRY_MAX_MS;
-                if let Some(backoff_delay) = UPLOAD_FAILURE_RETRY_MIN_MS.checked_shl(retry_attempt)
-                {
-                    retry_delay = min(retry_delay, backoff_delay);
-                }
-                sleep(Duration::from_millis(retry_delay)).await;
+                sleep(self.retry.backoff(retry_attempt)).await;
             }
 
             let commit_lsn = *self.commit_lsn_watch_rx.borrow();
@@ -353,6 +409,9 @@ impl WalBackupTask {
     }
 }
 
+/// Offload segments between the LSNs, up to `parallel_uploads_per_timeline`
+/// of them at a time. Returns the end of the last segment offloaded along
+/// with all previous ones.
 pub async fn backup_lsn_range(
     start_lsn: Lsn,
     end_lsn: Lsn,
@@ -362,12 +421,17 @@ pub async fn backup_lsn_range(
 ) -> Result<Lsn> {
     let mut res = start_lsn;
     let segments = get_segments(start_lsn, end_lsn, wal_seg_size);
-    for s in &segments {
+    let parallel_uploads = get_limits().parallel_uploads_per_timeline;
+    let mut uploads = futures::stream::iter(segments.iter().map(|s| async move {
         backup_single_segment(s, timeline_dir, workspace_dir)
             .await
-            .with_context(|| format!("offloading segno {}", s.seg_no))?;
-
-        res = s.end_lsn;
+            .with_context(|| format!("offloading segno {}", s.seg_no))
+            .map(|()| s)
+    }))
+    .buffered(parallel_uploads);
+    // results come in order, so backup_lsn never skips a failed segment
+    while let Some(s) = uploads.next().await {
+        res = s?.end_lsn;
     }
     info!(
         "offloaded segnos {:?} up to {}, previous backup_lsn {}",
@@ -383,6 +447,9 @@ async fn backup_single_segment(
     timeline_dir: &Path,
     workspace_dir: &Path,
 ) -> Result<()> {
+    fail::fail_point!("sk-upload-wal-segment", |_| bail!(
+        "failpoint sk-upload-wal-segment"
+    ));
     let segment_file_path = seg.file_path(timeline_dir)?;
     let remote_segment_path = segment_file_path
         .strip_prefix(workspace_dir)
@@ -443,31 +510,376 @@ fn get_segments(start: Lsn, end: Lsn, seg_size: usize) -> Vec<Segment> {
     res
 }
 
-static REMOTE_STORAGE: OnceCell<Option<GenericRemoteStorage>> = OnceCell::new();
+/// Directory in remote storage where partial segments of the timeline are
+/// uploaded.
+const PARTIAL_SEGMENTS_DIR: &str = "partial";
+
+/// Partial (last, not yet filled) segment uploaded when timeline becomes
+/// idle. Several safekeepers, or the same one in different terms, may have
+/// different contents of the segment, so the upload is qualified with the
+/// term of the last record in it (the epoch) and flush_lsn. Each one is
+/// stored as `partial/<segment>_<term>_<flush_lsn>/<segment>.partial`, which
+/// allows to find them with a cheap prefix listing.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct PartialSegment {
+    seg_no: XLogSegNo,
+    /// Term which wrote the WAL at flush_lsn.
+    term: Term,
+    flush_lsn: Lsn,
+}
+
+impl PartialSegment {
+    fn dir_name(self, wal_seg_size: usize) -> String {
+        format!(
+            "{}_{}_{:016X}",
+            XLogFileName(PG_TLI, self.seg_no, wal_seg_size),
+            self.term,
+            u64::from(self.flush_lsn)
+        )
+    }
+
+    fn parse_dir_name(name: &str, wal_seg_size: usize) -> Option<PartialSegment> {
+        let mut parts = name.split('_');
+        let (segment, term, flush_lsn) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() || !IsXLogFileName(segment) {
+            return None;
+        }
+        let (seg_no, _) = XLogFromFileName(segment, wal_seg_size);
+        Some(PartialSegment {
+            seg_no,
+            term: term.parse().ok()?,
+            flush_lsn: Lsn(u64::from_str_radix(flush_lsn, 16).ok()?),
+        })
+    }
+
+    fn remote_path(self, partial_dir: &Path, wal_seg_size: usize) -> Result<RemotePath> {
+        let object_name = XLogFileName(PG_TLI, self.seg_no, wal_seg_size) + ".partial";
+        RemotePath::new(
+            &partial_dir
+                .join(self.dir_name(wal_seg_size))
+                .join(object_name),
+        )
+    }
+}
+
+/// Remote directory with offloaded segments of the timeline, relative to the
+/// storage root.
+pub(crate) fn remote_timeline_dir(
+    conf: &SafeKeeperConf,
+    ttid: &TenantTimelineId,
+) -> Result<PathBuf> {
+    let timeline_dir = conf.timeline_dir(ttid);
+    let relative = timeline_dir
+        .strip_prefix(&conf.workdir)
+        .context("Failed to strip workspace dir prefix")?;
+    Ok(relative.to_owned())
+}
+
+/// Remote directory with partial segments of the timeline, relative to the
+/// storage root.
+fn remote_partial_dir(conf: &SafeKeeperConf, ttid: &TenantTimelineId) -> Result<PathBuf> {
+    Ok(remote_timeline_dir(conf, ttid)?.join(PARTIAL_SEGMENTS_DIR))
+}
+
+/// Upload the segment containing flush_lsn, so that the tail of WAL survives
+/// loss of this safekeeper. Full segments are offloaded by the backup task.
+async fn upload_partial_segment(conf: &SafeKeeperConf, tli: &Timeline) -> Result<()> {
+    let wal_seg_size = tli.get_wal_seg_size();
+    let flush_lsn = tli.get_flush_lsn();
+    if flush_lsn == Lsn(0) || flush_lsn.segment_offset(wal_seg_size) == 0 {
+        // nothing beyond full segments
+        return Ok(());
+    }
+    let (_, state) = tli.get_state();
+    let seg = PartialSegment {
+        seg_no: flush_lsn.segment_number(wal_seg_size),
+        term: state.acceptor_state.get_epoch(flush_lsn),
+        flush_lsn,
+    };
+
+    let local_path = conf
+        .timeline_dir(&tli.ttid)
+        .join(XLogFileName(PG_TLI, seg.seg_no, wal_seg_size) + ".partial");
+    let remote_path = seg.remote_path(&remote_partial_dir(conf, &tli.ttid)?, wal_seg_size)?;
+    backup_object(&local_path, &remote_path, wal_seg_size).await?;
+    info!(
+        "uploaded partial segment {:?} at term {}, flush_lsn {}",
+        remote_path, seg.term, flush_lsn
+    );
+    Ok(())
+}
+
+/// If local WAL ends before commit_lsn, e.g. because the partial segment got
+/// lost, look for an upload of it consistent with the local term history and
+/// append the missing part to the local WAL. Like in the election, logs which
+/// have the same term at the same LSN are the same up to it, so an upload is
+/// usable if the term history has its term at its flush_lsn.
+async fn restore_partial_segment(conf: &SafeKeeperConf, tli: &Timeline) -> Result<()> {
+    let wal_seg_size = tli.get_wal_seg_size();
+    let flush_lsn = tli.get_flush_lsn();
+    let (inmem, state) = tli.get_state();
+    if wal_seg_size == 0 || flush_lsn >= inmem.commit_lsn {
+        return Ok(());
+    }
 
-async fn backup_object(source_file: &Path, target_file: &RemotePath, size: usize) -> Result<()> {
     let storage = REMOTE_STORAGE
         .get()
-        .expect("failed to get remote storage")
+        .context("Failed to get remote storage")?
         .as_ref()
-        .unwrap();
+        .context("No remote storage configured")?;
+    let partial_dir = remote_partial_dir(conf, &tli.ttid)?;
+    let uploaded = storage
+        .list_prefixes(Some(&RemotePath::new(&partial_dir)?))
+        .await
+        .context("Failed to list uploaded partial segments")?;
+
+    let best = uploaded
+        .iter()
+        .filter_map(|path| PartialSegment::parse_dir_name(path.object_name()?, wal_seg_size))
+        .filter(|seg| {
+            seg.seg_no == flush_lsn.segment_number(wal_seg_size)
+                && seg.term == state.acceptor_state.get_epoch(seg.flush_lsn)
+                && seg.flush_lsn > flush_lsn
+        })
+        .max_by_key(|seg| seg.flush_lsn);
+    let seg = match best {
+        Some(seg) => seg,
+        None => {
+            warn!(
+                "flush_lsn {} is behind commit_lsn {}, but no suitable partial segment uploaded",
+                flush_lsn, inmem.commit_lsn
+            );
+            return Ok(());
+        }
+    };
+
+    let remote_path = seg.remote_path(&partial_dir, wal_seg_size)?;
+    let mut reader =
+        read_object(&remote_path, flush_lsn.segment_offset(wal_seg_size) as u64).await?;
+    let mut buf = vec![0u8; (u64::from(seg.flush_lsn) - u64::from(flush_lsn)) as usize];
+    reader
+        .read_exact(&mut buf)
+        .await
+        .with_context(|| format!("Failed to read partial segment {remote_path:?}"))?;
+
+    tli.restore_wal_tail(flush_lsn, &buf)?;
+    info!(
+        "restored WAL {}-{} from partial segment {:?}",
+        flush_lsn, seg.flush_lsn, remote_path
+    );
+    Ok(())
+}
+
+/// Remove WAL of the timeline from remote storage: offloaded segments from
+/// `start_lsn` up to `backup_lsn` and uploaded partial segments. Failures to
+/// delete single objects are logged and skipped, e.g. segments offloaded
+/// only partially by other safekeepers might be absent.
+pub async fn delete_timeline(
+    conf: &SafeKeeperConf,
+    ttid: &TenantTimelineId,
+    start_lsn: Lsn,
+    backup_lsn: Lsn,
+    wal_seg_size: usize,
+) -> Result<()> {
+    let storage = match REMOTE_STORAGE.get().and_then(|s| s.as_ref()) {
+        Some(storage) => storage,
+        None => return Ok(()),
+    };
+    let timeline_dir = conf.timeline_dir(ttid);
+    let remote_timeline_dir = timeline_dir
+        .strip_prefix(&conf.workdir)
+        .context("Failed to strip workspace dir prefix")?;
+
+    let mut objects = Vec::new();
+    if wal_seg_size > 0 {
+        for seg in get_segments(start_lsn, backup_lsn, wal_seg_size) {
+            objects.push(RemotePath::new(&remote_timeline_dir.join(XLogFileName(
+                PG_TLI,
+                seg.seg_no,
+                wal_seg_size,
+            )))?);
+        }
+        let partial_dir = remote_partial_dir(conf, ttid)?;
+        let uploaded = storage
+            .list_prefixes(Some(&RemotePath::new(&partial_dir)?))
+            .await
+            .context("Failed to list uploaded partial segments")?;
+        for path in uploaded {
+            if let Some(seg) = path
+                .object_name()
+                .and_then(|name| PartialSegment::parse_dir_name(name, wal_seg_size))
+            {
+                objects.push(seg.remote_path(&partial_dir, wal_seg_size)?);
+            }
+        }
+    }
+
+    info!(
+        "deleting {} objects of timeline {} from remote storage",
+        objects.len(),
+        ttid
+    );
+    for path in &objects {
+        if let Err(e) = storage.delete(path).await {
+            warn!("failed to delete {:?} from remote storage: {:?}", path, e);
+        }
+    }
+    Ok(())
+}
+
+static REMOTE_STORAGE: OnceCell<Option<GenericRemoteStorage>> = OnceCell::new();
+
+/// Remote storage, if configured and initialized by the launcher.
+pub(crate) fn remote_storage() -> Option<&'static GenericRemoteStorage> {
+    REMOTE_STORAGE.get().and_then(|s| s.as_ref())
+}
+
+/// Limits on WAL uploads, adjustable at runtime through the HTTP API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupLimits {
+    /// Segments of a single timeline uploaded concurrently.
+    pub parallel_uploads_per_timeline: usize,
+    /// Uploads in flight on the safekeeper, None means unlimited.
+    pub max_concurrent_uploads: Option<usize>,
+    /// Upload bandwidth of the safekeeper in bytes per second, None means
+    /// unlimited.
+    pub max_upload_rate: Option<u64>,
+}
+
+impl BackupLimits {
+    pub fn from_conf(conf: &SafeKeeperConf) -> Self {
+        BackupLimits {
+            parallel_uploads_per_timeline: conf.wal_backup_parallel_uploads,
+            max_concurrent_uploads: conf.wal_backup_max_concurrent_uploads,
+            max_upload_rate: conf.wal_backup_max_upload_rate,
+        }
+    }
+}
 
+/// Current limits along with the primitives enforcing them. They are
+/// replaced as a whole on change, so new limits apply to uploads started
+/// afterwards.
+struct BackupLimiter {
+    limits: BackupLimits,
+    upload_slots: Option<Arc<Semaphore>>,
+    bandwidth: Option<Arc<TokenBucket>>,
+}
+
+impl BackupLimiter {
+    fn new(limits: BackupLimits) -> Self {
+        BackupLimiter {
+            upload_slots: limits
+                .max_concurrent_uploads
+                .map(|n| Arc::new(Semaphore::new(n))),
+            bandwidth: limits
+                .max_upload_rate
+                .map(|rate| Arc::new(TokenBucket::new(rate))),
+            limits,
+        }
+    }
+}
+
+static BACKUP_LIMITER: Lazy<Mutex<BackupLimiter>> = Lazy::new(|| {
+    Mutex::new(BackupLimiter::new(BackupLimits {
+        parallel_uploads_per_timeline: DEFAULT_WAL_BACKUP_PARALLEL_UPLOADS,
+        max_concurrent_uploads: None,
+        max_upload_rate: None,
+    }))
+});
+
+pub fn get_limits() -> BackupLimits {
+    BACKUP_LIMITER.lock().unwrap().limits.clone()
+}
+
+pub fn set_limits(limits: BackupLimits) -> Result<()> {
+    if limits.parallel_uploads_per_timeline == 0
+        || limits.max_concurrent_uploads == Some(0)
+        || limits.max_upload_rate == Some(0)
+    {
+        bail!("WAL backup limits must be positive: {:?}", limits);
+    }
+    info!("setting WAL backup limits to {:?}", limits);
+    *BACKUP_LIMITER.lock().unwrap() = BackupLimiter::new(limits);
+    Ok(())
+}
+
+/// Reader accounting everything read against the bandwidth limit, pausing
+/// while the limit is exceeded.
+struct ThrottledReader<R> {
+    inner: R,
+    bandwidth: Arc<TokenBucket>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if let Some(delay) = self.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        let filled_before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - filled_before;
+        let delay = self.bandwidth.take(read as u64);
+        if !delay.is_zero() {
+            WAL_BACKUP_THROTTLED_SECONDS.inc_by(delay.as_secs_f64());
+            self.delay = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn backup_object(source_file: &Path, target_file: &RemotePath, size: usize) -> Result<()> {
     let file = tokio::io::BufReader::new(File::open(&source_file).await.with_context(|| {
         format!(
             "Failed to open file {} for wal backup",
             source_file.display()
         )
     })?);
+    upload_object(file, size, target_file).await
+}
 
-    storage
-        .upload_storage_object(Box::new(file), size, target_file)
-        .await
+/// Upload `data` of `size` bytes to `target_file` within the backup limits.
+pub(crate) async fn upload_object(
+    data: impl AsyncRead + Unpin + Send + Sync + 'static,
+    size: usize,
+    target_file: &RemotePath,
+) -> Result<()> {
+    let storage = REMOTE_STORAGE
+        .get()
+        .expect("failed to get remote storage")
+        .as_ref()
+        .unwrap();
+
+    let (upload_slots, bandwidth) = {
+        let limiter = BACKUP_LIMITER.lock().unwrap();
+        (limiter.upload_slots.clone(), limiter.bandwidth.clone())
+    };
+    let _permit = match upload_slots {
+        Some(slots) => Some(slots.acquire_owned().await?),
+        None => None,
+    };
+
+    let data: Box<dyn AsyncRead + Unpin + Send + Sync> = match bandwidth {
+        Some(bandwidth) => Box::new(ThrottledReader {
+            inner: data,
+            bandwidth,
+            delay: None,
+        }),
+        None => Box::new(data),
+    };
+
+    storage.upload_storage_object(data, size, target_file).await
 }
 
 pub async fn read_object(
     file_path: &RemotePath,
     offset: u64,
-) -> anyhow::Result<Pin<Box<dyn tokio::io::AsyncRead>>> {
+) -> anyhow::Result<Pin<Box<dyn tokio::io::AsyncRead + Send>>> {
     let storage = REMOTE_STORAGE
         .get()
         .context("Failed to get remote storage")?
@@ -485,3 +897,61 @@ pub async fn read_object(
 
     Ok(download.download_stream)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zero_limits_rejected() {
+        let limits = BackupLimits {
+            parallel_uploads_per_timeline: 1,
+            max_concurrent_uploads: Some(0),
+            max_upload_rate: None,
+        };
+        assert!(set_limits(limits).is_err());
+        let limits = BackupLimits {
+            parallel_uploads_per_timeline: 0,
+            max_concurrent_uploads: None,
+            max_upload_rate: None,
+        };
+        assert!(set_limits(limits).is_err());
+    }
+
+    #[test]
+    fn test_partial_segment_name() {
+        let wal_seg_size = 16 * 1024 * 1024;
+        let seg = PartialSegment {
+            seg_no: 2,
+            term: 5,
+            flush_lsn: Lsn(0x2A3B4C8),
+        };
+        let name = seg.dir_name(wal_seg_size);
+        assert_eq!(name, "000000010000000000000002_5_0000000002A3B4C8");
+        assert_eq!(
+            PartialSegment::parse_dir_name(&name, wal_seg_size),
+            Some(seg)
+        );
+        assert_eq!(
+            seg.remote_path(Path::new("tenant/timeline/partial"), wal_seg_size)
+                .unwrap()
+                .with_base(Path::new("/")),
+            Path::new(
+                "/tenant/timeline/partial/000000010000000000000002_5_0000000002A3B4C8/000000010000000000000002.partial"
+            )
+        );
+
+        assert_eq!(
+            PartialSegment::parse_dir_name("000000010000000000000002", wal_seg_size),
+            None
+        );
+        assert_eq!(
+            PartialSegment::parse_dir_name("000000010000000000000002_x_0", wal_seg_size),
+            None
+        );
+        assert_eq!(
+            PartialSegment::parse_dir_name("garbage_5_0000000002A3B4C8", wal_seg_size),
+            None
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Verification of WAL offloaded to remote storage.
+//!
+//! Upload failures which go unnoticed, e.g. objects lost or damaged by the
+//! storage, otherwise surface only when WAL is restored. The scrubber lists
+//! offloaded segments of a timeline, checks that they cover everything from
+//! timeline_start_lsn up to backup_lsn, compares a few of them with the local
+//! copy and, if asked, uploads missing and mismatched segments again while
+//! they are still on disk.
+//!
+//! It runs periodically for timelines offloaded by this safekeeper (see
+//! `SafeKeeperConf::wal_backup_scrub_interval`) and on demand through the
+//! HTTP API.
+
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use postgres_ffi::v14::xlog_utils::IsXLogFileName;
+use postgres_ffi::{XLogFileName, XLogSegNo, PG_TLI};
+use remote_storage::RemotePath;
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+use tracing::*;
+use utils::lsn::Lsn;
+
+use crate::metrics::{
+    WAL_BACKUP_SCRUB_MISMATCHED_SEGMENTS, WAL_BACKUP_SCRUB_MISSING_SEGMENTS,
+    WAL_BACKUP_SCRUB_REPAIRED_SEGMENTS,
+};
+use crate::timeline::Timeline;
+use crate::wal_backup::{is_elected_offloader, remote_storage, remote_timeline_dir, upload_object};
+use crate::wal_storage::read_segment;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// How many offloaded segments are downloaded and compared with the local
+/// copy on each run.
+const SPOT_CHECKED_SEGMENTS: usize = 4;
+
+/// Outcome of scrubbing offloaded WAL of a timeline. Segments are reported
+/// by their file names.
+#[derive(Debug, Default, Serialize)]
+pub struct ScrubReport {
+    /// Number of segments expected in remote storage.
+    pub expected_segments: u64,
+    /// Expected segments absent in remote storage.
+    pub missing: Vec<String>,
+    /// Segments downloaded and compared with the local copy.
+    pub spot_checked: Vec<String>,
+    /// Segments whose remote contents differ from the local copy.
+    pub mismatched: Vec<String>,
+    /// Missing and mismatched segments uploaded again.
+    pub repaired: Vec<String>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.mismatched.is_empty()
+    }
+}
+
+/// Scrub offloaded WAL of the timeline; with `repair`, upload missing and
+/// mismatched segments again if they are still present locally.
+pub async fn scrub_timeline(
+    conf: &SafeKeeperConf,
+    tli: &Timeline,
+    repair: bool,
+) -> Result<ScrubReport> {
+    let storage = remote_storage().context("No remote storage configured")?;
+    let wal_seg_size = tli.get_wal_seg_size();
+    let mut report = ScrubReport::default();
+    if wal_seg_size == 0 {
+        return Ok(report);
+    }
+    let (_, state) = tli.get_state();
+    if state.timeline_start_lsn == Lsn(0) {
+        // timeline created before start LSN was tracked, can't tell where
+        // offloaded WAL should begin
+        return Ok(report);
+    }
+    let start_segno = state.timeline_start_lsn.segment_number(wal_seg_size);
+    let end_segno = tli.get_wal_backup_lsn().segment_number(wal_seg_size);
+    if end_segno <= start_segno {
+        return Ok(report);
+    }
+    report.expected_segments = end_segno - start_segno;
+
+    let remote_dir = remote_timeline_dir(conf, &tli.ttid)?;
+    let present: HashSet<String> = storage
+        .list_files(&RemotePath::new(&remote_dir)?)
+        .await
+        .context("Failed to list offloaded segments")?
+        .iter()
+        .filter_map(|path| path.object_name())
+        .filter(|name| IsXLogFileName(name))
+        .map(str::to_owned)
+        .collect();
+
+    let missing = missing_segments(start_segno, end_segno, wal_seg_size, &present);
+    let present_segnos: Vec<XLogSegNo> = (start_segno..end_segno)
+        .filter(|segno| !missing.contains(segno))
+        .collect();
+
+    let timeline_dir = conf.timeline_dir(&tli.ttid);
+    let mut to_repair = missing.clone();
+    for i in spot_check_indices(present_segnos.len(), SPOT_CHECKED_SEGMENTS) {
+        let segno = present_segnos[i];
+        let name = XLogFileName(PG_TLI, segno, wal_seg_size);
+        let local = match read_local_segment(&timeline_dir, segno, wal_seg_size).await? {
+            Some(local) => local,
+            // already removed locally, nothing to compare with
+            None => continue,
+        };
+        let path = RemotePath::new(&remote_dir.join(&name))?;
+        let mut remote = Vec::with_capacity(wal_seg_size);
+        storage
+            .download(&path)
+            .await
+            .with_context(|| format!("Failed to download segment {path:?}"))?
+            .download_stream
+            .read_to_end(&mut remote)
+            .await
+            .with_context(|| format!("Failed to read segment {path:?}"))?;
+        if crc32c::crc32c(&remote) != crc32c::crc32c(&local) || remote.len() != local.len() {
+            warn!("offloaded segment {} differs from the local copy", name);
+            report.mismatched.push(name.clone());
+            to_repair.push(segno);
+        }
+        report.spot_checked.push(name);
+    }
+
+    for &segno in &missing {
+        warn!(
+            "segment {} is missing in remote storage",
+            XLogFileName(PG_TLI, segno, wal_seg_size)
+        );
+    }
+    report.missing = missing
+        .iter()
+        .map(|&segno| XLogFileName(PG_TLI, segno, wal_seg_size))
+        .collect();
+    WAL_BACKUP_SCRUB_MISSING_SEGMENTS.inc_by(report.missing.len() as u64);
+    WAL_BACKUP_SCRUB_MISMATCHED_SEGMENTS.inc_by(report.mismatched.len() as u64);
+
+    if repair {
+        for segno in to_repair {
+            let name = XLogFileName(PG_TLI, segno, wal_seg_size);
+            let local = match read_local_segment(&timeline_dir, segno, wal_seg_size).await? {
+                Some(local) => local,
+                None => {
+                    warn!("can't repair segment {}, it is removed locally", name);
+                    continue;
+                }
+            };
+            let path = RemotePath::new(&remote_dir.join(&name))?;
+            let size = local.len();
+            upload_object(Cursor::new(local), size, &path).await?;
+            info!("uploaded segment {} again", name);
+            WAL_BACKUP_SCRUB_REPAIRED_SEGMENTS.inc();
+            report.repaired.push(name);
+        }
+    }
+    Ok(report)
+}
+
+/// Periodically scrub timelines offloaded by this safekeeper, repairing
+/// what can be repaired.
+pub async fn scrub_main_loop(conf: SafeKeeperConf, interval: Duration) {
+    info!("WAL backup scrubber started with interval {:?}", interval);
+    loop {
+        tokio::time::sleep(interval).await;
+        for tli in GlobalTimelines::get_all() {
+            if !tli.is_active() || !is_elected_offloader(&conf, &tli) {
+                continue;
+            }
+            let span = info_span!("WAL backup scrub", ttid = %tli.ttid);
+            match scrub_timeline(&conf, &tli, true).instrument(span).await {
+                Ok(report) if !report.is_clean() => {
+                    warn!("scrubbed offloaded WAL of {}: {:?}", tli.ttid, report)
+                }
+                Ok(_) => {}
+                Err(e) => warn!("failed to scrub offloaded WAL of {}: {:#}", tli.ttid, e),
+            }
+        }
+    }
+}
+
+/// Contents of the local segment, or None if it is already removed.
+async fn read_local_segment(
+    timeline_dir: &Path,
+    segno: XLogSegNo,
+    wal_seg_size: usize,
+) -> Result<Option<Vec<u8>>> {
+    let timeline_dir = timeline_dir.to_owned();
+    let res = tokio::task::spawn_blocking(move || read_segment(&timeline_dir, segno, wal_seg_size))
+        .await?;
+    match res {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read local segment {segno}")),
+    }
+}
+
+/// Segments in `start_segno..end_segno` absent among the `present` file names.
+fn missing_segments(
+    start_segno: XLogSegNo,
+    end_segno: XLogSegNo,
+    wal_seg_size: usize,
+    present: &HashSet<String>,
+) -> Vec<XLogSegNo> {
+    (start_segno..end_segno)
+        .filter(|&segno| !present.contains(&XLogFileName(PG_TLI, segno, wal_seg_size)))
+        .collect()
+}
+
+/// Up to `count` indices evenly spread over `0..len`, including the last one,
+/// which is the most recently offloaded.
+fn spot_check_indices(len: usize, count: usize) -> Vec<usize> {
+    if len <= count {
+        return (0..len).collect();
+    }
+    if count == 1 {
+        return vec![len - 1];
+    }
+    (0..count).map(|i| i * (len - 1) / (count - 1)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_segments() {
+        let wal_seg_size = 16 * 1024 * 1024;
+        let present: HashSet<String> = [1, 2, 4]
+            .iter()
+            .map(|&segno| XLogFileName(PG_TLI, segno, wal_seg_size))
+            .collect();
+        assert_eq!(missing_segments(1, 6, wal_seg_size, &present), vec![3, 5]);
+        assert!(missing_segments(1, 3, wal_seg_size, &present).is_empty());
+    }
+
+    #[test]
+    fn test_spot_check_indices() {
+        assert_eq!(spot_check_indices(0, 4), Vec::<usize>::new());
+        assert_eq!(spot_check_indices(3, 4), vec![0, 1, 2]);
+        assert_eq!(spot_check_indices(10, 4), vec![0, 3, 6, 9]);
+        assert_eq!(spot_check_indices(10, 1), vec![9]);
+    }
+}
@@ -0,0 +1,232 @@
+//! Non-destructive consistency check of the local WAL of a timeline: decodes
+//! WAL between peer_horizon_lsn and flush_lsn, validating page headers,
+//! record CRCs and xl_prev links, and reports the first bad LSN.
+
+use anyhow::{Context, Result};
+use postgres_ffi::v14::xlog_utils::XLOG_SIZE_OF_XLOG_LONG_PHD;
+use postgres_ffi::waldecoder::WalStreamDecoder;
+use postgres_ffi::{XLogRecord, XLOG_SIZE_OF_XLOG_SHORT_PHD};
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
+use std::cmp::{max, min};
+use std::io;
+use std::path::Path;
+use tracing::*;
+use utils::id::TenantTimelineId;
+use utils::lsn::Lsn;
+
+use crate::wal_storage::read_segment;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+#[serde_as]
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct CheckWalResult {
+    #[serde_as(as = "DisplayFromStr")]
+    pub start_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub end_lsn: Lsn,
+    /// Number of records successfully validated.
+    pub records: u64,
+    /// First LSN at which WAL is invalid, None if the whole range is valid.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub bad_lsn: Option<Lsn>,
+    pub error: Option<String>,
+}
+
+/// Check local WAL of the timeline up to its flush_lsn.
+pub fn check_timeline_wal(conf: &SafeKeeperConf, ttid: TenantTimelineId) -> Result<CheckWalResult> {
+    let tli = GlobalTimelines::get(ttid)?;
+    let (inmem, state) = tli.get_state();
+    let flush_lsn = tli.get_flush_lsn();
+    // peer_horizon_lsn is a record boundary; WAL before local_start_lsn
+    // isn't present locally.
+    let start_lsn = max(inmem.peer_horizon_lsn, state.local_start_lsn);
+
+    info!(
+        "checking WAL of {} from {} to {}",
+        ttid, start_lsn, flush_lsn
+    );
+    let result = check_wal(
+        &conf.timeline_dir(&ttid),
+        state.server.wal_seg_size as usize,
+        state.server.pg_version / 10000,
+        start_lsn,
+        flush_lsn,
+    )?;
+    match &result.error {
+        Some(err) => warn!("WAL of {} is invalid: {}", ttid, err),
+        None => info!(
+            "WAL of {} is valid, {} records checked",
+            ttid, result.records
+        ),
+    }
+    Ok(result)
+}
+
+/// Decode WAL in `dir` from `start_lsn`, which must be a record boundary, to
+/// `end_lsn`, stopping at the first invalid record.
+pub fn check_wal(
+    dir: &Path,
+    wal_seg_size: usize,
+    pg_version: u32,
+    start_lsn: Lsn,
+    end_lsn: Lsn,
+) -> Result<CheckWalResult> {
+    let mut result = CheckWalResult {
+        start_lsn,
+        end_lsn,
+        records: 0,
+        bad_lsn: None,
+        error: None,
+    };
+    if start_lsn >= end_lsn {
+        return Ok(result);
+    }
+
+    let mut decoder = WalStreamDecoder::with_seg_size(start_lsn, pg_version, wal_seg_size);
+    // start of the last decoded record, unknown for the first one
+    let mut prev_record_lsn: Option<Lsn> = None;
+    let mut record_start = start_lsn;
+    while decoder.available() < end_lsn {
+        // segments may be compressed, so read them whole
+        let pos = decoder.available();
+        let segno = pos.segment_number(wal_seg_size);
+        let segment = match read_segment(dir, segno, wal_seg_size) {
+            Ok(segment) => segment,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read WAL at {}", pos)),
+        };
+        let offset = pos.segment_offset(wal_seg_size);
+        let len = min(
+            segment.len().saturating_sub(offset) as u64,
+            end_lsn.0 - pos.0,
+        ) as usize;
+        if len == 0 {
+            result.bad_lsn = Some(pos);
+            result.error = Some(format!("WAL is missing at {}", pos));
+            return Ok(result);
+        }
+        decoder.feed_bytes(&segment[offset..offset + len]);
+
+        loop {
+            match decoder.poll_decode() {
+                Ok(Some((end, rec))) => {
+                    let rec_lsn = record_start_lsn(record_start, wal_seg_size);
+                    let xl_prev = XLogRecord::from_slice(&rec)?.xl_prev;
+                    // Records crafted by tests have zero xl_prev, tolerate it
+                    // like the rest of the safekeeper does.
+                    if let Some(prev) = prev_record_lsn {
+                        if xl_prev != 0 && Lsn(xl_prev) != prev {
+                            result.bad_lsn = Some(rec_lsn);
+                            result.error = Some(format!(
+                                "record at {} has xl_prev {}, expected {}",
+                                rec_lsn,
+                                Lsn(xl_prev),
+                                prev
+                            ));
+                            return Ok(result);
+                        }
+                    }
+                    result.records += 1;
+                    prev_record_lsn = Some(rec_lsn);
+                    record_start = end;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    result.bad_lsn = Some(e.lsn);
+                    result.error = Some(e.to_string());
+                    return Ok(result);
+                }
+            }
+        }
+    }
+
+    // the decoder reports aligned record ends, so the last one may be past
+    // end_lsn
+    if record_start < end_lsn {
+        result.bad_lsn = Some(record_start);
+        result.error = Some(format!(
+            "WAL ends in the middle of the record starting at {}",
+            record_start
+        ));
+    }
+    Ok(result)
+}
+
+/// The decoder reports record ends, the next record starts there or, at the
+/// page boundary, after the page header.
+pub(crate) fn record_start_lsn(lsn: Lsn, wal_seg_size: usize) -> Lsn {
+    if lsn.segment_offset(wal_seg_size) == 0 {
+        lsn + XLOG_SIZE_OF_XLOG_LONG_PHD as u64
+    } else if lsn.block_offset() == 0 {
+        lsn + XLOG_SIZE_OF_XLOG_SHORT_PHD as u64
+    } else {
+        lsn
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wal_storage::compressed_wal_file_path;
+    use postgres_ffi::{XLogFileName, PG_TLI, WAL_SEGMENT_SIZE, XLOG_BLCKSZ};
+
+    #[test]
+    fn test_check_compressed_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let start_lsn = Lsn(0x1000000);
+        let (_, seg) =
+            postgres_ffi::v14::xlog_utils::generate_wal_segment(start_lsn, 42, 1).unwrap();
+        let record = postgres_ffi::encode_logical_message("prefix", "message");
+        let mut seg = seg.to_vec();
+        let mut end = XLOG_SIZE_OF_XLOG_LONG_PHD;
+        for _ in 0..2 {
+            seg[end..end + record.len()].copy_from_slice(&record);
+            // records are MAXALIGN'ed
+            end = (end + record.len() + 7) & !7;
+        }
+        let path = dir.path().join(XLogFileName(PG_TLI, 1, WAL_SEGMENT_SIZE));
+        std::fs::write(
+            compressed_wal_file_path(&path),
+            zstd::stream::encode_all(seg.as_slice(), 1).unwrap(),
+        )
+        .unwrap();
+
+        let end_lsn = start_lsn + end as u64;
+        let result = check_wal(dir.path(), WAL_SEGMENT_SIZE, 14, start_lsn, end_lsn).unwrap();
+        assert_eq!(result.error, None);
+        assert_eq!(result.records, 2);
+
+        // the rest of the segment is zeroed, so decoding stops after the
+        // last record
+        let result =
+            check_wal(dir.path(), WAL_SEGMENT_SIZE, 14, start_lsn, Lsn(0x2000100)).unwrap();
+        assert_eq!(result.records, 2);
+        assert_eq!(result.bad_lsn, Some(end_lsn));
+
+        // the next segment is missing
+        let result = check_wal(
+            dir.path(),
+            WAL_SEGMENT_SIZE,
+            14,
+            Lsn(0x2000000),
+            Lsn(0x2000100),
+        )
+        .unwrap();
+        assert_eq!(result.bad_lsn, Some(Lsn(0x2000000)));
+    }
+
+    #[test]
+    fn test_record_start_lsn() {
+        let seg_size = 16 * 1024 * 1024;
+        assert_eq!(
+            record_start_lsn(Lsn(0x1000000), seg_size),
+            Lsn(0x1000000 + XLOG_SIZE_OF_XLOG_LONG_PHD as u64)
+        );
+        assert_eq!(
+            record_start_lsn(Lsn(0x1000000 + XLOG_BLCKSZ as u64), seg_size),
+            Lsn(0x1000000 + (XLOG_BLCKSZ + XLOG_SIZE_OF_XLOG_SHORT_PHD) as u64)
+        );
+        assert_eq!(record_start_lsn(Lsn(0x1000028), seg_size), Lsn(0x1000028));
+    }
+}
@@ -2,15 +2,50 @@
 //!   WAL service listens for client connections and
 //!   receive WAL from wal_proposer and send it to WAL receivers
 //!
+use ::metrics::IntCounter;
+use once_cell::sync::Lazy;
 use regex::Regex;
 use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
 use std::thread;
 use tracing::*;
 use utils::postgres_backend_async::QueryError;
 
+use crate::drain;
 use crate::handler::SafekeeperPostgresHandler;
+use crate::metrics::PG_IO_BYTES;
 use crate::SafeKeeperConf;
+use utils::buffer_pool::{BufferConfig, BufferPool};
+use utils::io_observer::IoObserver;
 use utils::postgres_backend::{AuthType, PostgresBackend};
+use utils::tcp_listener::SocketOptions;
+
+/// Most connections are idle most of the time, so they share output buffers
+/// instead of each keeping its own.
+static BUFFER_POOL: Lazy<Arc<BufferPool>> = Lazy::new(|| Arc::new(BufferPool::new(256)));
+
+/// Counts traffic of all connections in [`PG_IO_BYTES`].
+struct TrafficMetrics {
+    read: IntCounter,
+    written: IntCounter,
+}
+
+impl IoObserver for TrafficMetrics {
+    fn bytes_read(&self, n: usize) {
+        self.read.inc_by(n as u64);
+    }
+
+    fn bytes_written(&self, n: usize) {
+        self.written.inc_by(n as u64);
+    }
+}
+
+static TRAFFIC_METRICS: Lazy<Arc<TrafficMetrics>> = Lazy::new(|| {
+    Arc::new(TrafficMetrics {
+        read: PG_IO_BYTES.with_label_values(&["read"]),
+        written: PG_IO_BYTES.with_label_values(&["write"]),
+    })
+});
 
 /// Accept incoming TCP connections and spawn them into a background thread.
 pub fn thread_main(conf: SafeKeeperConf, listener: TcpListener) -> ! {
@@ -47,14 +82,34 @@ fn get_tid() -> u64 {
 fn handle_socket(socket: TcpStream, conf: SafeKeeperConf) -> Result<(), QueryError> {
     let _enter = info_span!("", tid = ?get_tid()).entered();
 
-    socket.set_nodelay(true)?;
+    SocketOptions {
+        nodelay: true,
+        keepalive_time: conf.tcp_keepalive,
+        keepalive_interval: conf.tcp_keepalive,
+        keepalive_retries: conf.tcp_keepalive_retries,
+        user_timeout: conf.tcp_user_timeout,
+    }
+    .apply(&socket)?;
 
-    let auth_type = match conf.auth {
-        None => AuthType::Trust,
-        Some(_) => AuthType::NeonJWT,
+    let auth_type = match (&conf.auth, &conf.password_auth) {
+        (Some(_), _) => AuthType::NeonJWT,
+        (None, Some(_)) => AuthType::Md5,
+        (None, None) => AuthType::Trust,
     };
+    let tls_config = conf.tls.as_ref().map(|tls| tls.server_config());
+    let allow_direct_tls = conf.allow_direct_tls;
+    let idle_timeout = conf.wal_receive_idle_timeout;
     let mut conn_handler = SafekeeperPostgresHandler::new(conf);
-    let pgbackend = PostgresBackend::new(socket, auth_type, None, false)?;
+    let mut pgbackend = PostgresBackend::new(socket, auth_type, tls_config, false)?;
+    // most connections are short control ones, WAL streaming ones resize
+    pgbackend.set_buffer_config(BufferConfig::SMALL);
+    pgbackend.set_buffer_pool(Arc::clone(&BUFFER_POOL));
+    pgbackend.set_io_observer(TRAFFIC_METRICS.clone());
+    pgbackend.allow_direct_tls = allow_direct_tls;
+    pgbackend.set_idle_timeout(idle_timeout);
+    // on drain, idle connections get an admin shutdown error
+    pgbackend.set_shutdown_poll_interval(Some(drain::DRAIN_CHECK_INTERVAL));
+    pgbackend.set_shutdown_check(Some(drain::is_draining));
     // libpq replication protocol between safekeeper and replicas/pagers
     pgbackend.run(&mut conn_handler)?;
 
@@ -6,21 +6,26 @@
 //! - 000000010000000000000002.partial
 //!
 //! Note that last file has `.partial` suffix, that's different from postgres.
+//! Cold segments may be compressed, see `compress_segments`; they have `.zst`
+//! suffix and are decompressed transparently by `WalReader`.
 
 use anyhow::{bail, Context, Result};
 use remote_storage::RemotePath;
 
-use std::io::{self, Seek, SeekFrom};
+use std::io::{self, Cursor, Seek, SeekFrom};
 use std::pin::Pin;
 use tokio::io::AsyncRead;
 
 use postgres_ffi::v14::xlog_utils::{IsPartialXLogFileName, IsXLogFileName, XLogFromFileName};
-use postgres_ffi::{XLogSegNo, PG_TLI};
+use postgres_ffi::{decode_commit_timestamp, TimestampTz, XLogRecord, XLogSegNo, PG_TLI};
 use std::cmp::{max, min};
 
 use std::fs::{self, remove_file, File, OpenOptions};
 use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use tracing::*;
 
@@ -30,6 +35,7 @@ use crate::metrics::{time_io_closure, WalStorageMetrics};
 use crate::safekeeper::SafeKeeperState;
 
 use crate::wal_backup::read_object;
+use crate::wal_check::record_start_lsn;
 use crate::SafeKeeperConf;
 use postgres_ffi::XLogFileName;
 use postgres_ffi::XLOG_BLCKSZ;
@@ -38,10 +44,37 @@ use postgres_ffi::waldecoder::WalStreamDecoder;
 
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
+/// How WAL files are synced to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncMethod {
+    /// Sync only the data and the metadata required to read it back, which
+    /// is enough as segments are preallocated.
+    #[default]
+    Fdatasync,
+    /// Sync all metadata as well, for filesystems where fdatasync isn't
+    /// reliable.
+    Fsync,
+}
+
+impl FromStr for FsyncMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fdatasync" => Ok(FsyncMethod::Fdatasync),
+            "fsync" => Ok(FsyncMethod::Fsync),
+            _ => bail!("invalid fsync method {s:?}, expected 'fdatasync' or 'fsync'"),
+        }
+    }
+}
+
 pub trait Storage {
     /// LSN of last durably stored WAL record.
     fn flush_lsn(&self) -> Lsn;
 
+    /// LSN of the end of written WAL, which might be not flushed yet.
+    fn write_lsn(&self) -> Lsn;
+
     /// Write piece of WAL from buf to disk, but not necessarily sync it.
     fn write_wal(&mut self, startpos: Lsn, buf: &[u8]) -> Result<()>;
 
@@ -63,6 +96,12 @@ pub trait Storage {
 
     /// Get metrics for this timeline.
     fn get_metrics(&self) -> WalStorageMetrics;
+
+    /// Take end LSNs and commit times of transactions committed in WAL
+    /// written since the previous call.
+    fn take_commit_times(&mut self) -> Vec<(Lsn, TimestampTz)> {
+        Vec::new()
+    }
 }
 
 /// PhysicalStorage is a storage that stores WAL on disk. Writes are separated from flushes
@@ -93,9 +132,31 @@ pub struct PhysicalStorage {
     /// The LSN of the last WAL record flushed to disk.
     flush_record_lsn: Lsn,
 
-    /// Decoder is required for detecting boundaries of WAL records.
+    /// Decoder is required for detecting boundaries of WAL records. It also
+    /// validates received WAL before it is written.
     decoder: WalStreamDecoder,
 
+    /// If false, invalid WAL is written as is instead of being rejected,
+    /// see `set_validate_wal`.
+    validate_wal: bool,
+
+    /// Start of the last decoded record, to check xl_prev of the next one.
+    /// None after the decoder is restarted.
+    prev_record_lsn: Option<Lsn>,
+
+    /// End of the last decoded record, where the decoder started the
+    /// one it is decoding now.
+    decoded_lsn: Lsn,
+
+    /// Size of WAL files of the timeline on disk, kept up to date on
+    /// segment creation, compression and removal instead of scanning the
+    /// directory each time.
+    disk_usage: Arc<AtomicU64>,
+
+    /// Commits found by the decoder, not taken yet, see
+    /// `Storage::take_commit_times`.
+    commit_times: Vec<(Lsn, TimestampTz)>,
+
     /// Cached open file for the last segment.
     ///
     /// If Some(file) is open, then it always:
@@ -125,19 +186,18 @@ impl PhysicalStorage {
         let write_lsn = if state.commit_lsn == Lsn(0) {
             Lsn(0)
         } else {
-            match state.server.pg_version / 10000 {
-                14 => postgres_ffi::v14::xlog_utils::find_end_of_wal(
-                    &timeline_dir,
-                    wal_seg_size,
-                    state.commit_lsn,
-                )?,
-                15 => postgres_ffi::v15::xlog_utils::find_end_of_wal(
-                    &timeline_dir,
-                    wal_seg_size,
-                    state.commit_lsn,
-                )?,
-                _ => bail!("unsupported postgres version: {}", state.server.pg_version),
-            }
+            postgres_ffi::find_end_of_wal(
+                &timeline_dir,
+                wal_seg_size,
+                state.commit_lsn,
+                state.server.pg_version / 10000,
+            )
+            .with_context(|| {
+                format!(
+                    "failed to find end of WAL of postgres {} timeline",
+                    state.server.pg_version
+                )
+            })?
         };
 
         // TODO: do we really know that write_lsn is fully flushed to disk?
@@ -152,6 +212,9 @@ impl PhysicalStorage {
             warn!("timeline {} potential data loss: flush_lsn by find_end_of_wal is less than either commit_lsn or peer_horizon_lsn from control file", ttid.timeline_id);
         }
 
+        let disk_usage = wal_files_size(&timeline_dir)
+            .with_context(|| format!("failed to get size of WAL in {}", timeline_dir.display()))?;
+
         Ok(PhysicalStorage {
             metrics: WalStorageMetrics::default(),
             timeline_dir,
@@ -160,16 +223,36 @@ impl PhysicalStorage {
             write_lsn,
             write_record_lsn: write_lsn,
             flush_record_lsn: flush_lsn,
-            decoder: WalStreamDecoder::new(write_lsn, state.server.pg_version / 10000),
+            decoder: WalStreamDecoder::with_seg_size(
+                write_lsn,
+                state.server.pg_version / 10000,
+                wal_seg_size,
+            ),
+            validate_wal: true,
+            prev_record_lsn: None,
+            decoded_lsn: write_lsn,
+            disk_usage: Arc::new(AtomicU64::new(disk_usage)),
+            commit_times: Vec::new(),
             file: None,
         })
     }
 
-    /// Call fdatasync if config requires so.
-    fn fdatasync_file(&mut self, file: &mut File) -> Result<()> {
+    /// Counter of the disk space taken by WAL of the timeline, shared with
+    /// WAL removal and compression.
+    pub fn disk_usage(&self) -> Arc<AtomicU64> {
+        self.disk_usage.clone()
+    }
+
+    /// Sync written WAL with the configured method, if config requires so.
+    fn sync_wal_file(&mut self, file: &mut File) -> Result<()> {
+        fail::fail_point!("sk-fsync-wal", |_| bail!("failpoint sk-fsync-wal"));
         if !self.conf.no_sync {
+            let method = self.conf.wal_fsync_method;
             self.metrics
-                .observe_flush_seconds(time_io_closure(|| Ok(file.sync_data()?))?);
+                .observe_flush_seconds(time_io_closure(|| match method {
+                    FsyncMethod::Fdatasync => Ok(file.sync_data()?),
+                    FsyncMethod::Fsync => Ok(file.sync_all()?),
+                })?);
         }
         Ok(())
     }
@@ -205,6 +288,8 @@ impl PhysicalStorage {
 
             write_zeroes(&mut file, self.wal_seg_size)?;
             self.fsync_file(&mut file)?;
+            self.disk_usage
+                .fetch_add(self.wal_seg_size as u64, Ordering::Relaxed);
             Ok((file, true))
         }
     }
@@ -224,7 +309,7 @@ impl PhysicalStorage {
 
         if xlogoff + buf.len() == self.wal_seg_size {
             // If we reached the end of a WAL segment, flush and close it.
-            self.fdatasync_file(&mut file)?;
+            self.sync_wal_file(&mut file)?;
 
             // Rename partial file to completed file
             let (wal_file_path, wal_file_partial_path) =
@@ -247,7 +332,7 @@ impl PhysicalStorage {
         if self.write_lsn != pos {
             // need to flush the file before discarding it
             if let Some(mut file) = self.file.take() {
-                self.fdatasync_file(&mut file)?;
+                self.sync_wal_file(&mut file)?;
             }
 
             self.write_lsn = pos;
@@ -272,6 +357,69 @@ impl PhysicalStorage {
 
         Ok(())
     }
+
+    /// Allow writing WAL the decoder can't make sense of, for tests crafting
+    /// broken WAL on purpose. Not persisted, so has to be set again after the
+    /// timeline is loaded.
+    pub fn set_validate_wal(&mut self, validate: bool) {
+        self.validate_wal = validate;
+    }
+
+    fn reset_decoder(&mut self, lsn: Lsn) {
+        let pg_version = self.decoder.pg_version;
+        self.decoder = WalStreamDecoder::with_seg_size(lsn, pg_version, self.wal_seg_size);
+        self.prev_record_lsn = None;
+        self.decoded_lsn = lsn;
+        self.commit_times.clear();
+    }
+
+    /// Feed WAL starting at `startpos` to the decoder, validating page
+    /// headers, record CRCs and xl_prev links. Returns the end of the last
+    /// complete record in it, if any.
+    fn decode_wal(&mut self, startpos: Lsn, buf: &[u8]) -> Result<Option<Lsn>> {
+        if self.decoder.available() != startpos {
+            info!(
+                "restart decoder from {} to {}",
+                self.decoder.available(),
+                startpos,
+            );
+            self.reset_decoder(startpos);
+        }
+
+        let mut last_record_end = None;
+        self.decoder.feed_bytes(buf);
+        loop {
+            let (end_lsn, rec) = match self.decoder.poll_decode() {
+                Ok(Some(record)) => record,
+                Ok(None) => break, // no full record yet
+                Err(e) => bail!("received invalid WAL: {}", e),
+            };
+            let rec_lsn = record_start_lsn(self.decoded_lsn, self.wal_seg_size);
+            let xl_prev = XLogRecord::from_slice(&rec)
+                .with_context(|| format!("received invalid WAL record at {}", rec_lsn))?
+                .xl_prev;
+            // Records crafted by tests have zero xl_prev, tolerate it.
+            if let Some(prev) = self.prev_record_lsn {
+                if xl_prev != 0 && Lsn(xl_prev) != prev {
+                    bail!(
+                        "received invalid WAL: record at {} has xl_prev {}, expected {}",
+                        rec_lsn,
+                        Lsn(xl_prev),
+                        prev
+                    );
+                }
+            }
+            match decode_commit_timestamp(&rec) {
+                Ok(Some(ts)) => self.commit_times.push((end_lsn, ts)),
+                Ok(None) => {}
+                Err(e) => debug!("failed to decode commit record at {}: {}", rec_lsn, e),
+            }
+            self.prev_record_lsn = Some(rec_lsn);
+            self.decoded_lsn = end_lsn;
+            last_record_end = Some(end_lsn);
+        }
+        Ok(last_record_end)
+    }
 }
 
 impl Storage for PhysicalStorage {
@@ -280,8 +428,13 @@ impl Storage for PhysicalStorage {
         self.flush_record_lsn
     }
 
+    fn write_lsn(&self) -> Lsn {
+        self.write_lsn
+    }
+
     /// Write WAL to disk.
     fn write_wal(&mut self, startpos: Lsn, buf: &[u8]) -> Result<()> {
+        fail::fail_point!("sk-write-wal", |_| bail!("failpoint sk-write-wal"));
         // Disallow any non-sequential writes, which can result in gaps or overwrites.
         // If we need to move the pointer, use truncate_wal() instead.
         if self.write_lsn > startpos {
@@ -299,32 +452,31 @@ impl Storage for PhysicalStorage {
             );
         }
 
+        // Decode WAL before writing it, figuring out last record's end lsn
+        // for reporting (if we got the whole record) and rejecting malformed
+        // WAL instead of persisting it.
+        let last_record_end = match self.decode_wal(startpos, buf) {
+            Ok(lsn) => lsn,
+            Err(e) if self.validate_wal => {
+                // the decoder has consumed the rejected WAL, reset it
+                self.reset_decoder(startpos);
+                return Err(e);
+            }
+            Err(e) => {
+                warn!("writing WAL which failed validation: {:#}", e);
+                self.reset_decoder(startpos + buf.len() as u64);
+                None
+            }
+        };
+
         let write_seconds = time_io_closure(|| self.write_exact(startpos, buf))?;
         // WAL is written, updating write metrics
         self.metrics.observe_write_seconds(write_seconds);
         self.metrics.observe_write_bytes(buf.len());
 
-        // figure out last record's end lsn for reporting (if we got the
-        // whole record)
-        if self.decoder.available() != startpos {
-            info!(
-                "restart decoder from {} to {}",
-                self.decoder.available(),
-                startpos,
-            );
-            let pg_version = self.decoder.pg_version;
-            self.decoder = WalStreamDecoder::new(startpos, pg_version);
+        if let Some(lsn) = last_record_end {
+            self.write_record_lsn = lsn;
         }
-        self.decoder.feed_bytes(buf);
-        loop {
-            match self.decoder.poll_decode()? {
-                None => break, // no full record yet
-                Some((lsn, _rec)) => {
-                    self.write_record_lsn = lsn;
-                }
-            }
-        }
-
         Ok(())
     }
 
@@ -335,7 +487,7 @@ impl Storage for PhysicalStorage {
         }
 
         if let Some(mut unflushed_file) = self.file.take() {
-            self.fdatasync_file(&mut unflushed_file)?;
+            self.sync_wal_file(&mut unflushed_file)?;
             self.file = Some(unflushed_file);
         } else {
             // We have unflushed data (write_lsn != flush_lsn), but no file.
@@ -369,21 +521,26 @@ impl Storage for PhysicalStorage {
 
         // Close previously opened file, if any
         if let Some(mut unflushed_file) = self.file.take() {
-            self.fdatasync_file(&mut unflushed_file)?;
+            self.sync_wal_file(&mut unflushed_file)?;
         }
 
         let xlogoff = end_pos.segment_offset(self.wal_seg_size);
         let segno = end_pos.segment_number(self.wal_seg_size);
 
         // Remove all segments after the given LSN.
-        remove_segments_from_disk(&self.timeline_dir, self.wal_seg_size, |x| x > segno)?;
+        remove_segments_from_disk(
+            &self.timeline_dir,
+            self.wal_seg_size,
+            &self.disk_usage,
+            |x| x > segno,
+        )?;
 
         let (mut file, is_partial) = self.open_or_create(segno)?;
 
         // Fill end with zeroes
         file.seek(SeekFrom::Start(xlogoff as u64))?;
         write_zeroes(&mut file, self.wal_seg_size - xlogoff)?;
-        self.fdatasync_file(&mut file)?;
+        self.sync_wal_file(&mut file)?;
 
         if !is_partial {
             // Make segment partial once again
@@ -402,8 +559,11 @@ impl Storage for PhysicalStorage {
     fn remove_up_to(&self) -> Box<dyn Fn(XLogSegNo) -> Result<()>> {
         let timeline_dir = self.timeline_dir.clone();
         let wal_seg_size = self.wal_seg_size;
+        let disk_usage = self.disk_usage.clone();
         Box::new(move |segno_up_to: XLogSegNo| {
-            remove_segments_from_disk(&timeline_dir, wal_seg_size, |x| x <= segno_up_to)
+            remove_segments_from_disk(&timeline_dir, wal_seg_size, &disk_usage, |x| {
+                x <= segno_up_to
+            })
         })
     }
 
@@ -415,12 +575,157 @@ impl Storage for PhysicalStorage {
     fn get_metrics(&self) -> WalStorageMetrics {
         self.metrics.clone()
     }
+
+    fn take_commit_times(&mut self) -> Vec<(Lsn, TimestampTz)> {
+        std::mem::take(&mut self.commit_times)
+    }
 }
 
-/// Remove all WAL segments in timeline_dir that match the given predicate.
+/// Suffix of compressed WAL segments.
+pub const COMPRESSED_SUFFIX: &str = ".zst";
+
+/// zstd level for cold segments; they are written once and read rarely, so
+/// favour the ratio a bit.
+const SEGMENT_COMPRESSION_LEVEL: i32 = 9;
+
+/// Compress full segments in `from_segno..to_segno` present on disk,
+/// replacing them with `<segment>.zst`. Segments already compressed or
+/// missing are skipped. Returns the number of segments compressed.
+///
+/// The caller must ensure the segments are fully flushed and won't be
+/// written anymore. `disk_usage` is updated accordingly.
+pub fn compress_segments(
+    timeline_dir: &Path,
+    wal_seg_size: usize,
+    disk_usage: &AtomicU64,
+    from_segno: XLogSegNo,
+    to_segno: XLogSegNo,
+) -> Result<u64> {
+    let mut n_compressed = 0;
+    for segno in from_segno..to_segno {
+        let (wal_file_path, _) = wal_file_paths(timeline_dir, segno, wal_seg_size)?;
+        let data = match fs::read(&wal_file_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("failed to read {:?}", wal_file_path)),
+        };
+        let compressed = zstd::bulk::compress(&data, SEGMENT_COMPRESSION_LEVEL)?;
+
+        let compressed_path = compressed_wal_file_path(&wal_file_path);
+        let mut tmp_path = compressed_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&compressed)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &compressed_path)?;
+        // the compressed copy must be durable before the original is gone
+        File::open(timeline_dir)?.sync_all()?;
+        remove_file(&wal_file_path)?;
+        disk_usage.fetch_add(compressed.len() as u64, Ordering::Relaxed);
+        sub_disk_usage(disk_usage, data.len() as u64);
+        n_compressed += 1;
+    }
+
+    if n_compressed > 0 {
+        info!(
+            "compressed {} WAL segments in [{}; {})",
+            n_compressed, from_segno, to_segno
+        );
+    }
+    Ok(n_compressed)
+}
+
+/// Path of the compressed version of the segment.
+pub fn compressed_wal_file_path(wal_file_path: &Path) -> PathBuf {
+    let mut path = wal_file_path.to_owned().into_os_string();
+    path.push(COMPRESSED_SUFFIX);
+    PathBuf::from(path)
+}
+
+/// Read the whole segment from disk, whether it is full, `.partial` or
+/// compressed.
+pub fn read_segment(
+    timeline_dir: &Path,
+    segno: XLogSegNo,
+    wal_seg_size: usize,
+) -> io::Result<Vec<u8>> {
+    let (wal_file_path, wal_file_partial_path) = wal_file_paths(timeline_dir, segno, wal_seg_size)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    for path in [&wal_file_path, &wal_file_partial_path] {
+        match fs::read(path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => return res,
+        }
+    }
+    let compressed = fs::read(compressed_wal_file_path(&wal_file_path))?;
+    zstd::stream::decode_all(compressed.as_slice())
+}
+
+/// Whether the file is a WAL segment, possibly `.partial` or compressed.
+pub fn is_wal_segment_file(fname: &str) -> bool {
+    let fname = fname.strip_suffix(COMPRESSED_SUFFIX).unwrap_or(fname);
+    IsXLogFileName(fname) || IsPartialXLogFileName(fname)
+}
+
+/// Number of the oldest WAL segment in timeline_dir, if there is any.
+pub fn oldest_segment_on_disk(
+    timeline_dir: &Path,
+    wal_seg_size: usize,
+) -> Result<Option<XLogSegNo>> {
+    let mut oldest = None;
+    for entry in fs::read_dir(timeline_dir)? {
+        let fname = entry?.file_name();
+        if let Some(fname_str) = fname.to_str() {
+            if is_wal_segment_file(fname_str) {
+                let (segno, _) = XLogFromFileName(fname_str, wal_seg_size);
+                oldest = Some(oldest.map_or(segno, |oldest: XLogSegNo| min(oldest, segno)));
+            }
+        }
+    }
+    Ok(oldest)
+}
+
+/// Size of WAL segment files in timeline_dir. Files removed concurrently
+/// count as empty.
+fn wal_files_size(timeline_dir: &Path) -> Result<u64> {
+    let entries = match fs::read_dir(timeline_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        if !entry
+            .file_name()
+            .to_str()
+            .map_or(false, is_wal_segment_file)
+        {
+            continue;
+        }
+        match entry.metadata() {
+            Ok(metadata) => size += metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(size)
+}
+
+/// Subtract freed space from the usage counter, which is approximate and
+/// must not wrap around.
+fn sub_disk_usage(disk_usage: &AtomicU64, freed: u64) {
+    let _ = disk_usage.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |usage| {
+        Some(usage.saturating_sub(freed))
+    });
+}
+
+/// Remove all WAL segments in timeline_dir that match the given predicate,
+/// subtracting their size from `disk_usage`.
 fn remove_segments_from_disk(
     timeline_dir: &Path,
     wal_seg_size: usize,
+    disk_usage: &AtomicU64,
     remove_predicate: impl Fn(XLogSegNo) -> bool,
 ) -> Result<()> {
     let mut n_removed = 0;
@@ -434,12 +739,18 @@ fn remove_segments_from_disk(
 
         if let Some(fname_str) = fname.to_str() {
             /* Ignore files that are not XLOG segments */
-            if !IsXLogFileName(fname_str) && !IsPartialXLogFileName(fname_str) {
+            if !is_wal_segment_file(fname_str) {
                 continue;
             }
             let (segno, _) = XLogFromFileName(fname_str, wal_seg_size);
             if remove_predicate(segno) {
+                let size = match entry.metadata() {
+                    Ok(metadata) => metadata.len(),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e.into()),
+                };
                 remove_file(entry_path)?;
+                sub_disk_usage(disk_usage, size);
                 n_removed += 1;
                 min_removed = min(min_removed, segno);
                 max_removed = max(max_removed, segno);
@@ -461,7 +772,7 @@ pub struct WalReader {
     timeline_dir: PathBuf,
     wal_seg_size: usize,
     pos: Lsn,
-    wal_segment: Option<Pin<Box<dyn AsyncRead>>>,
+    wal_segment: Option<Pin<Box<dyn AsyncRead + Send>>>,
 
     // S3 will be used to read WAL if LSN is not available locally
     enable_remote_read: bool,
@@ -486,8 +797,10 @@ impl WalReader {
             );
         }
 
-        // TODO: add state.timeline_start_lsn == Lsn(0) check
-        if state.server.wal_seg_size == 0 || state.local_start_lsn == Lsn(0) {
+        if !state.is_initialized()
+            || state.server.wal_seg_size == 0
+            || state.local_start_lsn == Lsn(0)
+        {
             bail!("state uninitialized, no data to read");
         }
 
@@ -528,7 +841,7 @@ impl WalReader {
     }
 
     /// Open WAL segment at the current position of the reader.
-    async fn open_segment(&self) -> Result<Pin<Box<dyn AsyncRead>>> {
+    async fn open_segment(&self) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
         let xlogoff = self.pos.segment_offset(self.wal_seg_size);
         let segno = self.pos.segment_number(self.wal_seg_size);
         let wal_file_name = XLogFileName(PG_TLI, segno, self.wal_seg_size);
@@ -553,9 +866,29 @@ impl WalReader {
                     if !is_not_found {
                         return Err(e);
                     }
-                    // NotFound is expected, fall through to remote read
+                    // NotFound is expected, the segment may be compressed
                 }
             };
+
+            let compressed_path = compressed_wal_file_path(&wal_file_path);
+            match tokio::fs::read(&compressed_path).await {
+                Ok(compressed) => {
+                    let data = tokio::task::spawn_blocking(move || {
+                        zstd::stream::decode_all(compressed.as_slice())
+                    })
+                    .await?
+                    .with_context(|| format!("failed to decompress {:?}", compressed_path))?;
+                    let mut cursor = Cursor::new(data);
+                    cursor.set_position(xlogoff as u64);
+                    return Ok(Box::pin(cursor));
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // fall through to remote read
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to read {:?}", compressed_path))
+                }
+            }
         }
 
         // Try to open remote file, if remote reads are enabled
@@ -620,3 +953,183 @@ fn wal_file_paths(
     let wal_file_partial_path = timeline_dir.join(wal_file_name + ".partial");
     Ok((wal_file_path, wal_file_partial_path))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postgres_ffi::v14::xlog_utils::XLOG_RECORD_CRC_OFFS;
+
+    #[test]
+    fn test_compress_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal_seg_size = 16 * XLOG_BLCKSZ;
+        let segment: Vec<u8> = (0..wal_seg_size).map(|i| (i % 7) as u8).collect();
+        for segno in 1..=3 {
+            let name = XLogFileName(PG_TLI, segno, wal_seg_size);
+            fs::write(dir.path().join(name), &segment).unwrap();
+        }
+        fs::write(dir.path().join("safekeeper.control"), b"not WAL").unwrap();
+        let disk_usage = AtomicU64::new(wal_files_size(dir.path()).unwrap());
+        assert_eq!(disk_usage.load(Ordering::Relaxed), 3 * wal_seg_size as u64);
+
+        // segment 3 is not cold yet
+        assert_eq!(
+            compress_segments(dir.path(), wal_seg_size, &disk_usage, 0, 3).unwrap(),
+            2
+        );
+        assert_eq!(
+            compress_segments(dir.path(), wal_seg_size, &disk_usage, 0, 3).unwrap(),
+            0
+        );
+        assert_eq!(
+            disk_usage.load(Ordering::Relaxed),
+            wal_files_size(dir.path()).unwrap()
+        );
+        for segno in 1..=3 {
+            assert_eq!(
+                read_segment(dir.path(), segno, wal_seg_size).unwrap(),
+                segment
+            );
+        }
+
+        assert_eq!(
+            oldest_segment_on_disk(dir.path(), wal_seg_size).unwrap(),
+            Some(1)
+        );
+        remove_segments_from_disk(dir.path(), wal_seg_size, &disk_usage, |segno| segno <= 2)
+            .unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(disk_usage.load(Ordering::Relaxed), wal_seg_size as u64);
+        assert_eq!(
+            oldest_segment_on_disk(dir.path(), wal_seg_size).unwrap(),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn test_write_invalid_wal() {
+        let workdir = tempfile::tempdir().unwrap();
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            ..SafeKeeperConf::dummy()
+        };
+        let mut state = SafeKeeperState::empty();
+        state.server.pg_version = 140000;
+        state.server.wal_seg_size = postgres_ffi::WAL_SEGMENT_SIZE as u32;
+        let ttid = TenantTimelineId::empty();
+        fs::create_dir_all(conf.timeline_dir(&ttid)).unwrap();
+        let mut storage = PhysicalStorage::new(&ttid, &conf, &state).unwrap();
+
+        let mut record = postgres_ffi::encode_logical_message("prefix", "message");
+        record[XLOG_RECORD_CRC_OFFS] ^= 0xFF;
+        let startpos = Lsn(0x1000100);
+        assert!(storage.write_wal(startpos, &record).is_err());
+        assert_eq!(storage.write_lsn(), Lsn(0));
+
+        // tests may write broken WAL on purpose
+        storage.set_validate_wal(false);
+        storage.write_wal(startpos, &record).unwrap();
+        assert_eq!(storage.write_lsn(), startpos + record.len() as u64);
+    }
+
+    #[test]
+    fn test_commit_times() {
+        use postgres_ffi::pg_constants;
+        use postgres_ffi::XLOG_SIZE_OF_XLOG_RECORD;
+
+        let workdir = tempfile::tempdir().unwrap();
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            ..SafeKeeperConf::dummy()
+        };
+        let mut state = SafeKeeperState::empty();
+        state.server.pg_version = 150000;
+        state.server.wal_seg_size = postgres_ffi::WAL_SEGMENT_SIZE as u32;
+        let ttid = TenantTimelineId::empty();
+        fs::create_dir_all(conf.timeline_dir(&ttid)).unwrap();
+        let mut storage = PhysicalStorage::new(&ttid, &conf, &state).unwrap();
+
+        // commit record with xinfo, which isn't looked at
+        let commit_time: TimestampTz = 0x2_9B5E_8D3A_6C10;
+        let mut data = vec![pg_constants::XLR_BLOCK_ID_DATA_SHORT, 12];
+        data.extend_from_slice(&commit_time.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        let mut header = XLogRecord {
+            xl_tot_len: (XLOG_SIZE_OF_XLOG_RECORD + data.len()) as u32,
+            xl_xid: 742,
+            xl_prev: 0,
+            xl_info: pg_constants::XLOG_XACT_COMMIT | pg_constants::XLOG_XACT_HAS_INFO,
+            xl_rmid: pg_constants::RM_XACT_ID,
+            __bindgen_padding_0: [0u8; 2usize],
+            xl_crc: 0,
+        };
+        let crc = crc32c::crc32c_append(0, &data);
+        header.xl_crc =
+            crc32c::crc32c_append(crc, &header.encode().unwrap()[..XLOG_RECORD_CRC_OFFS]);
+        let mut commit = header.encode().unwrap().to_vec();
+        commit.extend_from_slice(&data);
+        commit.resize((commit.len() + 7) & !7, 0);
+
+        let message = postgres_ffi::encode_logical_message("prefix", "message");
+        let startpos = Lsn(0x1000100);
+        let mut wal = message.clone();
+        wal.extend_from_slice(&commit);
+        storage.write_wal(startpos, &wal).unwrap();
+
+        let commit_end = startpos + (message.len() + commit.len()) as u64;
+        assert_eq!(storage.take_commit_times(), vec![(commit_end, commit_time)]);
+        assert!(storage.take_commit_times().is_empty());
+    }
+
+    #[test]
+    fn test_write_wal_small_segments() {
+        use postgres_ffi::v14::bindings::{
+            XLogLongPageHeaderData, XLogPageHeaderData, XLOG_PAGE_MAGIC,
+        };
+
+        let workdir = tempfile::tempdir().unwrap();
+        let conf = SafeKeeperConf {
+            workdir: workdir.path().to_owned(),
+            ..SafeKeeperConf::dummy()
+        };
+        let wal_seg_size = 1024 * 1024;
+        let mut state = SafeKeeperState::empty();
+        state.server.pg_version = 140000;
+        state.server.wal_seg_size = wal_seg_size as u32;
+        let ttid = TenantTimelineId::empty();
+        fs::create_dir_all(conf.timeline_dir(&ttid)).unwrap();
+        let mut storage = PhysicalStorage::new(&ttid, &conf, &state).unwrap();
+
+        // start of the 1MB segment, but not of a 16MB one, so it must begin
+        // with a long page header
+        let startpos = Lsn(0x1100000);
+        let hdr = XLogLongPageHeaderData {
+            std: XLogPageHeaderData {
+                xlp_magic: XLOG_PAGE_MAGIC as u16,
+                xlp_info: postgres_ffi::pg_constants::XLP_LONG_HEADER,
+                xlp_tli: PG_TLI,
+                xlp_pageaddr: startpos.0,
+                xlp_rem_len: 0,
+                ..Default::default()
+            },
+            xlp_sysid: 0,
+            xlp_seg_size: wal_seg_size as u32,
+            xlp_xlog_blcksz: XLOG_BLCKSZ as u32,
+        };
+        let mut wal = hdr.encode().unwrap().to_vec();
+        wal.extend(postgres_ffi::encode_logical_message("prefix", "message"));
+        storage.write_wal(startpos, &wal).unwrap();
+        assert_eq!(storage.write_lsn(), startpos + wal.len() as u64);
+        // the record was decoded
+        assert!(storage.write_record_lsn > startpos);
+        assert!(conf
+            .timeline_dir(&ttid)
+            .join(XLogFileName(PG_TLI, 0x11, wal_seg_size) + ".partial")
+            .exists());
+        // the new segment is accounted for
+        assert_eq!(
+            storage.disk_usage().load(Ordering::Relaxed),
+            wal_seg_size as u64
+        );
+    }
+}
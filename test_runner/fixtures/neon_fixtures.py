@@ -2929,7 +2929,45 @@ class Safekeeper:
         safekeeper state. It will construct LogicalMessage from provided
         prefix and message, and then will write it to WAL.
         """
+        res = self.json_ctrl(tenant_id, timeline_id, request)
+        assert isinstance(res, dict)
+        return res
+
+    def append_batch(
+        self, tenant_id: TenantId, timeline_id: TimelineId, request: Dict[str, Any]
+    ) -> Dict[str, Any]:
+        """
+        Send JSON_CTRL query to append a batch of LogicalMessage records, each
+        of them possibly with its own term and LSN, cut or with broken CRC.
+        Appending stops at the first failed record, its error is returned in
+        the response.
+        """
+        res = self.json_ctrl(tenant_id, timeline_id, {"AppendBatch": request})
+        assert isinstance(res, dict)
+        return res
+
+    def read_wal(
+        self, tenant_id: TenantId, timeline_id: TimelineId, start_lsn: int, end_lsn: int
+    ) -> List[Dict[str, Any]]:
+        """
+        Decode WAL records of the timeline in [start_lsn, end_lsn), start_lsn
+        must be a record boundary.
+        """
+        res = self.json_ctrl(
+            tenant_id, timeline_id, {"ReadWal": {"start_lsn": start_lsn, "end_lsn": end_lsn}}
+        )
+        assert isinstance(res, list)
+        return res
 
+    def get_state(self, tenant_id: TenantId, timeline_id: TimelineId) -> Dict[str, Any]:
+        """
+        Get persisted state (control file contents) of the timeline.
+        """
+        res = self.json_ctrl(tenant_id, timeline_id, "GetState")
+        assert isinstance(res, dict)
+        return res
+
+    def json_ctrl(self, tenant_id: TenantId, timeline_id: TimelineId, request: Any) -> Any:
         # "replication=0" hacks psycopg not to send additional queries
         # on startup, see https://github.com/psycopg/psycopg2/pull/482
         token = self.env.auth_keys.generate_tenant_token(tenant_id)
@@ -2944,9 +2982,7 @@ class Safekeeper:
                 cur.execute("JSON_CTRL " + request_json)
                 all = cur.fetchall()
                 log.info(f"JSON_CTRL response: {all[0][0]}")
-                res = json.loads(all[0][0])
-                assert isinstance(res, dict)
-                return res
+                return json.loads(all[0][0])
 
     def http_client(self, auth_token: Optional[str] = None) -> SafekeeperHttpClient:
         return SafekeeperHttpClient(port=self.port.http, auth_token=auth_token)
@@ -2988,6 +3024,21 @@ class SafekeeperHttpClient(requests.Session):
     def check_status(self):
         self.get(f"http://localhost:{self.port}/v1/status").raise_for_status()
 
+    def configure_failpoints(self, config_strings: Tuple[str, str] | List[Tuple[str, str]]):
+        if isinstance(config_strings, tuple):
+            pairs = [config_strings]
+        else:
+            pairs = config_strings
+
+        log.info(f"Requesting config failpoints: {repr(pairs)}")
+
+        res = self.put(
+            f"http://localhost:{self.port}/v1/failpoints",
+            json=[{"name": name, "actions": actions} for name, actions in pairs],
+        )
+        log.info(f"Got failpoints request response code {res.status_code}")
+        res.raise_for_status()
+
     def timeline_create(
         self, tenant_id: TenantId, timeline_id: TimelineId, pg_version: int, commit_lsn: Lsn
     ):
@@ -3039,6 +3090,35 @@ class SafekeeperHttpClient(requests.Session):
         assert isinstance(res_json, dict)
         return res_json
 
+    def debug_dump(self, tenant_id: Optional[TenantId] = None) -> Dict[str, Any]:
+        params = {} if tenant_id is None else {"tenant_id": str(tenant_id)}
+        res = self.get(f"http://localhost:{self.port}/v1/debug_dump", params=params)
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def term_bump(
+        self, tenant_id: TenantId, timeline_id: TimelineId, term: Optional[int] = None
+    ) -> Dict[str, Any]:
+        res = self.post(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/term_bump",
+            json={"term": term},
+        )
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
+    def check_wal(self, tenant_id: TenantId, timeline_id: TimelineId) -> Dict[str, Any]:
+        res = self.get(
+            f"http://localhost:{self.port}/v1/tenant/{tenant_id}/timeline/{timeline_id}/check_wal"
+        )
+        res.raise_for_status()
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
+
     def get_metrics_str(self) -> str:
         request_result = self.get(f"http://localhost:{self.port}/metrics")
         request_result.raise_for_status()